@@ -6,7 +6,9 @@
 // copied, modified, or distributed except according to those terms.
 
 use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use crate::uname;
 
@@ -32,6 +34,33 @@ pub fn prefix_kernel_headers(headers: &[&str]) -> Option<Vec<String>> {
     Some(ret)
 }
 
+/// Returns the version of the kernel headers as `(major, minor)`.
+///
+/// The version is read from `LINUX_VERSION_CODE` in
+/// `include/generated/uapi/linux/version.h`.
+pub fn kernel_headers_version() -> Option<(u32, u32)> {
+    let KernelHeaders { build, .. } = kernel_headers_path()?;
+    kernel_headers_version_at(&build)
+}
+
+/// Returns the version of the kernel build tree at `build` as
+/// `(major, minor)`, like `kernel_headers_version()`.
+pub fn kernel_headers_version_at(build: &Path) -> Option<(u32, u32)> {
+    let header = fs::read_to_string(build.join("include/generated/uapi/linux/version.h")).ok()?;
+    parse_version_code(&header)
+}
+
+fn parse_version_code(header: &str) -> Option<(u32, u32)> {
+    let code = header.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("#define"), Some("LINUX_VERSION_CODE"), Some(code)) => u32::from_str(code).ok(),
+            _ => None
+        }
+    })?;
+    Some((code >> 16, (code >> 8) & 0xff))
+}
+
 fn kernel_headers_path() -> Option<KernelHeaders> {
     env::var("KERNEL_SOURCE")
     .ok()
@@ -75,4 +104,19 @@ fn kernel_version() -> Option<String> {
             uname::to_str(&u.release).to_string()
         })
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_version_code() {
+        let header = "#define LINUX_VERSION_CODE 328704\n\
+                      #define KERNEL_VERSION(a,b,c) (((a) << 16) + ((b) << 8) + (c))\n";
+        assert_eq!(parse_version_code(header), Some((5, 4)));
+        assert_eq!(parse_version_code("#define LINUX_VERSION_CODE 266002\n"), Some((4, 15)));
+        assert_eq!(parse_version_code("#define KERNEL_VERSION(a,b,c)\n"), None);
+        assert_eq!(parse_version_code(""), None);
+    }
+}
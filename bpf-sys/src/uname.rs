@@ -31,10 +31,19 @@ pub fn get_kernel_internal_version() -> Option<u32> {
     };
 
     parse_version(&version).map(|(major, minor, patch)| {
-        major << 16 | minor << 8 | patch
+        kernel_version_code(major, minor, patch)
     })
 }
 
+/// Encodes a kernel version like the kernel's `KERNEL_VERSION` macro.
+///
+/// Stable kernels can have patch levels above 255, which the kernel clamps
+/// so that they don't overflow into the minor version.
+#[inline]
+pub fn kernel_version_code(major: u32, minor: u32, patch: u32) -> u32 {
+    major << 16 | minor << 8 | patch.min(255)
+}
+
 #[inline]
 pub fn get_fqdn() -> Result<String, ()> {
     let uname = uname()?;
@@ -94,6 +103,12 @@ mod test {
         assert_eq!(parse_version(""), None);
     }
 
+    #[test]
+    fn test_kernel_version_code() {
+        assert_eq!(kernel_version_code(4, 15, 18), 0x040f12);
+        assert_eq!(kernel_version_code(4, 9, 337), 0x0409ff);
+    }
+
     #[test]
     fn test_parse_version_signature() {
         assert_eq!(parse_version_signature("Ubuntu 4.15.0-55.60-generic 4.15.18"), Some("4.15.18".into()));
@@ -0,0 +1,106 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Selects the LLVM cargo-bpf links to, set with one of the llvm-* features.
+// The features are mutually exclusive since each builds its llvm-sys, which
+// needs its own LLVM. Without any, only bindgen can be used, which is enough
+// for the build scripts of probes, and the commands needing LLVM fail with
+// the feature of the installed llvm-config.
+use std::env;
+use std::process::Command;
+
+const VERSIONS: [u32; 6] = [9, 10, 11, 12, 13, 14];
+
+// The variable llvm-sys reads the prefix of LLVM `version` from, eg.
+// LLVM_SYS_140_PREFIX
+fn prefix_var(version: u32) -> String {
+    format!("LLVM_SYS_{}0_PREFIX", version)
+}
+
+// Returns the major version of the llvm-config llvm-sys uses for LLVM
+// `version`, or of the one in the PATH
+fn llvm_config_version(version: Option<u32>) -> Option<u32> {
+    let llvm_config = match version.and_then(|v| env::var(prefix_var(v)).ok()) {
+        Some(prefix) => format!("{}/bin/llvm-config", prefix),
+        None => "llvm-config".into(),
+    };
+    let out = Command::new(llvm_config).arg("--version").output().ok()?;
+    let version = String::from_utf8(out.stdout).ok()?;
+    version.trim().split('.').next()?.parse().ok()
+}
+
+// Suggests the feature of the installed LLVM when there's one
+fn hint(installed: u32) -> String {
+    if VERSIONS.contains(&installed) {
+        format!("enable the llvm-{} feature to build with it", installed)
+    } else {
+        "it's not supported, use an LLVM from llvm-9 to llvm-14".to_string()
+    }
+}
+
+fn main() {
+    for version in VERSIONS.iter() {
+        println!("cargo:rerun-if-env-changed={}", prefix_var(*version));
+    }
+    println!(
+        "cargo:rustc-check-cfg=cfg(llvm, values(none(), {}))",
+        VERSIONS
+            .iter()
+            .map(|v| format!("\"{}\"", v))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    println!("cargo:rustc-check-cfg=cfg(llvm_conflict)");
+
+    let enabled: Vec<u32> = VERSIONS
+        .iter()
+        .cloned()
+        .filter(|v| env::var_os(format!("CARGO_FEATURE_LLVM_{}", v)).is_some())
+        .collect();
+    let version = match enabled.as_slice() {
+        [] if env::var_os("CARGO_FEATURE_COMMAND_LINE").is_none() => return,
+        [] => {
+            let hint = match llvm_config_version(None) {
+                Some(installed) => {
+                    format!("llvm-config is LLVM {}, {}", installed, hint(installed))
+                }
+                None => "enable one of the llvm-9 to llvm-14 features".to_string(),
+            };
+            println!(
+                "cargo:warning=building without LLVM, `cargo bpf build` won't work: {}",
+                hint
+            );
+            println!("cargo:rustc-env=CARGO_BPF_LLVM_HINT={}", hint);
+            return;
+        }
+        [version] => *version,
+        _ => {
+            // reported by a compile_error! in lib.rs, naming the features
+            let features: Vec<String> = enabled.iter().map(|v| format!("llvm-{}", v)).collect();
+            println!("cargo:rustc-cfg=llvm_conflict");
+            println!(
+                "cargo:rustc-env=CARGO_BPF_LLVM_FEATURES={}",
+                features.join(", ")
+            );
+            return;
+        }
+    };
+    if let Some(installed) = llvm_config_version(Some(version)) {
+        if installed != version {
+            println!(
+                "cargo:warning=building with LLVM {}, but llvm-config is LLVM {}: {}",
+                version,
+                installed,
+                hint(installed)
+            );
+        }
+    }
+
+    println!("cargo:rustc-cfg=llvm");
+    println!("cargo:rustc-cfg=llvm=\"{}\"", version);
+    println!("cargo:rustc-env=CARGO_BPF_LLVM_VERSION={}", version);
+}
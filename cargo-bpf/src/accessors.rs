@@ -1,8 +1,10 @@
 use proc_macro2::{Ident, Span};
 use quote::quote;
 use std::collections::{HashMap, HashSet};
-use syn::visit::{visit_item_struct, Visit};
-use syn::{self, parse_str, Field, File, Item, ItemStruct, ItemUnion, Path, Type, TypePath};
+use syn::visit::{visit_item_struct, visit_item_union, Visit};
+use syn::{
+    self, parse_str, Attribute, Field, File, Item, ItemStruct, ItemUnion, Path, Type, TypePath,
+};
 
 // toplevel syn items
 type Items = HashMap<Ident, Item>;
@@ -54,16 +56,16 @@ impl GenerateAccessors {
         }
     }
 
-    fn type_item(&self, ty: &Type) -> &Item {
+    fn type_item(&self, ty: &Type) -> Option<&Item> {
         let ident = match ty {
             Type::Path(TypePath {
                 path: Path { ref segments, .. },
                 ..
-            }) => segments.first().unwrap().ident.clone(),
-            _ => panic!(),
+            }) => segments.first()?.ident.clone(),
+            _ => return None,
         };
 
-        self.items.get(&ident).unwrap()
+        self.items.get(&ident)
     }
 
     fn enter_field(&self, id_s: &str) -> bool {
@@ -79,35 +81,65 @@ impl GenerateAccessors {
     }
 
     fn blacklisted_field(&self, id_s: &str) -> bool {
-        if id_s.starts_with("_bindgen") || id_s.starts_with("_bitfield") {
+        // padding and opaque storage of the bindings generated from BTF
+        if id_s.starts_with("_bindgen")
+            || id_s.starts_with("_bitfield")
+            || id_s.starts_with("_pad")
+            || id_s.starts_with("_opaque")
+            || id_s.starts_with("_unused")
+        {
             return true;
         }
 
         false
     }
-}
 
-impl<'ast> Visit<'ast> for GenerateAccessors {
-    fn visit_item_struct(&mut self, node: &ItemStruct) {
+    fn visit_toplevel(
+        &mut self,
+        ident: &Ident,
+        attrs: &[Attribute],
+        visit: impl FnOnce(&mut Self),
+    ) {
         let toplevel = self.toplevel_visit();
         if toplevel {
-            if !self.is_whitelisted(&node.ident) {
+            // the fields of packed structs can't be borrowed
+            if !self.is_whitelisted(ident) || is_packed(attrs) {
                 return;
             }
             self.prefix.push("self".to_string());
         }
 
-        visit_item_struct(self, node);
+        visit(self);
         // this function is called recursively as types are parsed. We want to
         // generate accessors only when parsing toplevel items.
         if toplevel {
             if !self.accessors.is_empty() {
                 self.type_accessors
-                    .insert(node.ident.to_string(), self.accessors.drain(..).collect());
+                    .insert(ident.to_string(), self.accessors.drain(..).collect());
             }
             self.prefix.pop();
         }
     }
+}
+
+fn is_packed(attrs: &[Attribute]) -> bool {
+    attrs
+        .iter()
+        .any(|attr| attr.path.is_ident("repr") && attr.tokens.to_string().contains("packed"))
+}
+
+impl<'ast> Visit<'ast> for GenerateAccessors {
+    fn visit_item_struct(&mut self, node: &ItemStruct) {
+        self.visit_toplevel(&node.ident, &node.attrs, |this| {
+            visit_item_struct(this, node)
+        });
+    }
+
+    fn visit_item_union(&mut self, node: &ItemUnion) {
+        self.visit_toplevel(&node.ident, &node.attrs, |this| {
+            visit_item_union(this, node)
+        });
+    }
 
     fn visit_field(&mut self, node: &Field) {
         if self.prefix.is_empty() {
@@ -122,8 +154,12 @@ impl<'ast> Visit<'ast> for GenerateAccessors {
         };
 
         if self.enter_field(&id_s) {
+            let item = match self.type_item(&node.ty) {
+                Some(Item::Struct(item)) if is_packed(&item.attrs) => return,
+                Some(item) => item.clone(),
+                None => return,
+            };
             self.prefix.push(id_s);
-            let item = self.type_item(&node.ty).clone();
             self.visit_item(&item);
             self.prefix.pop();
         } else if !self.blacklisted_field(&id_s) {
@@ -141,7 +177,23 @@ fn parse_items(tree: &File) -> Items {
     ci.items
 }
 
+/// Generates getters reading the fields of the `whitelist` structs with
+/// `bpf_probe_read`, `None` when the read fails or the pointer read is null.
+///
+/// Fields of anonymous members are read through the struct containing them,
+/// bitfields and packed structs are skipped. The bindings must be in scope
+/// of `bpf_probe_read`.
 pub fn generate_read_accessors(bindings: &str, whitelist: &[&str]) -> String {
+    generate_accessors(bindings, whitelist, false)
+}
+
+/// Generates getters like `generate_read_accessors()`, returning the error
+/// of `bpf_probe_read` instead of `None`. Pointers are returned even if null.
+pub fn generate_result_accessors(bindings: &str, whitelist: &[&str]) -> String {
+    generate_accessors(bindings, whitelist, true)
+}
+
+fn generate_accessors(bindings: &str, whitelist: &[&str], result: bool) -> String {
     // parse the bindgen generated bindings
     let tree: File = parse_str(&bindings).unwrap();
 
@@ -162,6 +214,13 @@ pub fn generate_read_accessors(bindings: &str, whitelist: &[&str]) -> String {
                 let ty = &acc.field.ty;
                 let prefix = acc.prefix.iter().map(|p| Ident::new(p, Span::call_site()));
                 match ty {
+                    _ if result => {
+                        quote! {
+                            pub fn #ident(&self) -> Result<#ty, i32> {
+                                unsafe { bpf_probe_read(&#(#prefix).*.#ident) }
+                            }
+                        }
+                    }
                     Type::Ptr(_) => {
                         quote! {
                             pub fn #ident(&self) -> Option<#ty> {
@@ -198,3 +257,66 @@ pub fn generate_read_accessors(bindings: &str, whitelist: &[&str]) -> String {
     }
     out
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BINDINGS: &str = r#"
+        #[repr(C)]
+        pub struct task_struct {
+            pub pid: i32,
+            pub _bitfield_1: [u8; 1],
+            pub mm: *mut mm_struct,
+            pub __bindgen_anon_1: task_struct__bindgen_ty_1,
+            _pad1: [u8; 4],
+        }
+        #[repr(C)]
+        pub union task_struct__bindgen_ty_1 {
+            pub flags: u32,
+            pub state: u64,
+        }
+        #[repr(C, packed)]
+        pub struct packed {
+            pub x: u32,
+        }
+    "#;
+
+    fn accessors(result: bool, types: &[&str]) -> Vec<String> {
+        let tree: File = parse_str(&generate_accessors(BINDINGS, types, result)).unwrap();
+        let mut names = Vec::new();
+        for item in tree.items.iter() {
+            if let Item::Impl(item) = item {
+                for item in item.items.iter() {
+                    if let syn::ImplItem::Method(method) = item {
+                        names.push(method.sig.ident.to_string());
+                    }
+                }
+            }
+        }
+        names.sort();
+
+        names
+    }
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(
+            accessors(false, &["task_struct"]),
+            vec!["flags", "mm", "pid", "state"]
+        );
+        assert!(accessors(false, &["packed"]).is_empty());
+        assert!(accessors(false, &["mm_struct"]).is_empty());
+    }
+
+    #[test]
+    fn test_return_types() {
+        let option = generate_read_accessors(BINDINGS, &["task_struct"]);
+        assert!(option.contains("Option < i32 >"));
+        assert!(option.contains("is_null"));
+        let result = generate_result_accessors(BINDINGS, &["task_struct"]);
+        assert!(result.contains("Result < i32 , i32 >"));
+        assert!(result.contains("self . __bindgen_anon_1 . flags"));
+        assert!(!result.contains("Option"));
+    }
+}
@@ -7,24 +7,40 @@
 
 use bindgen::{self, callbacks::ParseCallbacks};
 pub use bindgen::Builder;
+use std::env;
+use std::fmt::Write as _;
 use std::io::{self, Write};
 use std::path::Path;
 use std::process::Command;
 use std::str;
 use tempfile;
 
-pub use crate::accessors::generate_read_accessors;
+mod btf;
+
+pub use self::btf::{cmd_bindgen_btf, generate_btf_bindings};
+pub use crate::accessors::{generate_read_accessors, generate_result_accessors};
 use crate::CommandError;
 
-use redbpf::{self, build::headers::kernel_headers};
+use redbpf::build::{build_flags_for_arch, headers::kernel_headers_for_arch};
+use redbpf::tracepoint::{Field, Format};
 
+/// Returns a builder generating bindings for the target of the current build.
+///
+/// When called from build scripts this is the architecture the crate is
+/// being built for, which is different from the host when cross-building.
 pub fn builder() -> Builder {
-    let kernel_headers = kernel_headers().expect("couldn't find kernel headers");
+    builder_for_arch(&target_arch())
+}
+
+/// Returns a builder generating bindings for `arch`, named like `target_arch`.
+pub fn builder_for_arch(arch: &str) -> Builder {
+    let kernel_headers = kernel_headers_for_arch(arch).expect("couldn't find kernel headers");
+    let build_flags = build_flags_for_arch(arch).expect("unsupported target architecture");
     let mut flags: Vec<String> = kernel_headers
         .iter()
         .map(|dir| format!("-I{}", dir))
         .collect();
-    flags.extend(redbpf::build::BUILD_FLAGS.iter().map(|f| f.to_string()));
+    flags.extend(build_flags.iter().map(|f| f.to_string()));
     flags.push("-Wno-unused-function".to_string());
     flags.push("-Wno-unused-variable".to_string());
     flags.push("-Wno-address-of-packed-member".to_string());
@@ -38,6 +54,57 @@ pub fn builder() -> Builder {
         .parse_callbacks(Box::new(Callbacks))
 }
 
+// Cargo sets CARGO_CFG_TARGET_ARCH for build scripts
+fn target_arch() -> String {
+    env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_else(|_| env::consts::ARCH.to_string())
+}
+
+/// The id of the first BPF helper added by each kernel version since 5.0.
+///
+/// Helper ids are assigned in the order helpers are merged, so a helper
+/// belongs to the last version whose first id isn't greater than its own.
+/// Helpers with lower ids are available in all the supported kernels.
+pub const HELPER_VERSIONS: [(usize, (u32, u32)); 9] = [
+    (91, (5, 0)),
+    (93, (5, 1)),
+    (99, (5, 2)),
+    (109, (5, 3)),
+    (110, (5, 4)),
+    (111, (5, 5)),
+    (119, (5, 6)),
+    (122, (5, 7)),
+    (125, (5, 8)),
+];
+
+// The newest version in HELPER_VERSIONS
+const LATEST_KERNEL: (u32, u32) = (5, 8);
+
+/// Returns the kernel version that introduced the helper `id`, or `None`
+/// if the helper is available in all the supported kernels.
+pub fn helper_version(id: usize) -> Option<(u32, u32)> {
+    HELPER_VERSIONS
+        .iter()
+        .rev()
+        .find(|(first, _)| id >= *first)
+        .map(|(_, version)| *version)
+}
+
+/// Returns the `redbpf-probes` feature enabling the helpers of kernel
+/// `version`, given as `(major, minor)`.
+///
+/// Versions newer than the ones known to redbpf map to the `latest`
+/// feature, versions older than 5.0 don't need any feature.
+pub fn kernel_feature(version: (u32, u32)) -> Option<String> {
+    if version < (5, 0) {
+        return None;
+    }
+    if version > LATEST_KERNEL {
+        return Some("latest".to_string());
+    }
+
+    Some(format!("kernel{}_{}", version.0, version.1))
+}
+
 pub fn generate(builder: &Builder, extra_args: &[&str]) -> Result<String, String> {
     let mut bindgen_flags = builder.command_line_flags();
     let p = bindgen_flags
@@ -59,7 +126,89 @@ pub fn generate(builder: &Builder, extra_args: &[&str]) -> Result<String, String
     Ok(bindings)
 }
 
-pub fn cmd_bindgen(header: &Path, extra_args: &[&str]) -> Result<(), CommandError> {
+/// The traits `BindgenOptions::derives` can add.
+pub const DERIVES: [&str; 8] = [
+    "Copy",
+    "Debug",
+    "Default",
+    "Hash",
+    "PartialEq",
+    "Eq",
+    "PartialOrd",
+    "Ord",
+];
+
+/// Options of `cmd_bindgen()` and `cmd_bindgen_btf()`.
+#[derive(Debug, Clone, Default)]
+pub struct BindgenOptions {
+    /// The types to generate, regexes for headers and names for BTF
+    pub types: Vec<String>,
+    /// The functions to generate from headers, as regexes
+    pub functions: Vec<String>,
+    /// The variables to generate from headers, as regexes
+    pub vars: Vec<String>,
+    /// The traits of `DERIVES` to derive, for headers
+    pub derives: Vec<String>,
+    /// Adds getters reading the fields of `types` with `bpf_probe_read`, see
+    /// `generate_result_accessors()`. Regexes in `types` get no getters.
+    pub accessors: bool,
+}
+
+impl BindgenOptions {
+    fn apply(&self, builder: Builder) -> Result<Builder, String> {
+        let mut builder = builder;
+        for ty in self.types.iter() {
+            builder = builder.whitelist_type(ty);
+        }
+        for function in self.functions.iter() {
+            builder = builder.whitelist_function(function);
+        }
+        for var in self.vars.iter() {
+            builder = builder.whitelist_var(var);
+        }
+        for derive in self.derives.iter() {
+            builder = match derive.as_str() {
+                "Copy" => builder.derive_copy(true),
+                "Debug" => builder.derive_debug(true),
+                "Default" => builder.derive_default(true),
+                "Hash" => builder.derive_hash(true),
+                "PartialEq" => builder.derive_partialeq(true),
+                "Eq" => builder.derive_eq(true),
+                "PartialOrd" => builder.derive_partialord(true),
+                "Ord" => builder.derive_ord(true),
+                _ => {
+                    return Err(format!(
+                        "can't derive `{}', expected one of {}",
+                        derive,
+                        DERIVES.join(", ")
+                    ))
+                }
+            };
+        }
+
+        Ok(builder)
+    }
+
+    fn add_accessors(&self, bindings: &mut String) {
+        if !self.accessors {
+            return;
+        }
+        let types: Vec<&str> = self.types.iter().map(String::as_str).collect();
+        let accessors = generate_result_accessors(bindings, &types);
+        bindings.push_str("\nuse redbpf_probes::helpers::bpf_probe_read;\n");
+        bindings.push_str(&accessors);
+    }
+}
+
+/// Prints the bindings of `header`, a path or a header of the kernel like
+/// `linux/sched.h`, with the types, functions and variables of `options`.
+///
+/// `extra_args` are passed to bindgen.
+pub fn cmd_bindgen(
+    header: &Path,
+    extra_args: &[&str],
+    options: &BindgenOptions,
+) -> Result<(), CommandError> {
     let (_temp, header) = if !header.exists() {
         // try to find find the file in the kernel include path
         let path = header.to_str().unwrap();
@@ -76,8 +225,18 @@ pub fn cmd_bindgen(header: &Path, extra_args: &[&str]) -> Result<(), CommandErro
         (None, header.to_owned())
     };
 
-    let builder = builder().header(header.to_str().unwrap());
-    let bindings = generate(&builder, extra_args).map_err(|e| CommandError(e))?;
+    let builder = options
+        .apply(builder().header(header.to_str().unwrap()))
+        .map_err(CommandError::Message)?;
+    let mut bindings = generate(&builder, extra_args).map_err(CommandError::Message)?;
+    options.add_accessors(&mut bindings);
+    write_bindings(&bindings);
+
+    Ok(())
+}
+
+// Prints bindings wrapped in a module silencing the lints about C names
+fn write_bindings(bindings: &str) {
     let mut out = io::stdout();
     writeln!(
         &mut out,
@@ -85,6 +244,7 @@ pub fn cmd_bindgen(header: &Path, extra_args: &[&str]) -> Result<(), CommandErro
 mod generated_bindings {{
 #![allow(non_camel_case_types)]
 #![allow(non_upper_case_globals)]
+#![allow(non_snake_case)]
 #![allow(clippy::all)]
 {}
 }}
@@ -93,10 +253,186 @@ pub use generated_bindings::*;
         bindings
     )
     .unwrap();
+}
+
+/// Generates the record struct of the `category/name` tracepoint from its
+/// `format` file in tracefs.
+pub fn tracepoint_record(tracepoint: &str) -> Result<String, String> {
+    let mut names = tracepoint.splitn(2, '/');
+    let (category, name) = match (names.next(), names.next()) {
+        (Some(category), Some(name)) => (category, name),
+        _ => return Err(format!("invalid tracepoint name: {}", tracepoint)),
+    };
+    let format = Format::load(category, name).map_err(|e| format!("{}: {:?}", tracepoint, e))?;
+
+    generate_tracepoint_record(tracepoint, &format)
+}
+
+/// Generates the record struct of `tracepoint` from its parsed `format`.
+///
+/// The struct is `#[repr(C, packed)]`, with padding fields placing each
+/// field at the offset given by the format. Fields are read through
+/// accessors, since references to fields of packed structs aren't allowed,
+/// and `__data_loc` fields are returned as
+/// `redbpf_probes::tracepoint::DataLoc`. The `FIELDS` constant lists the
+/// layout like the records provided by `redbpf-probes`, so that it can be
+/// checked with `redbpf::tracepoint::Format::check_layout()`.
+pub fn generate_tracepoint_record(tracepoint: &str, format: &Format) -> Result<String, String> {
+    let ident = camel_case(&format.name);
+    let mut fields = String::new();
+    let mut accessors = String::new();
+    let mut layout = String::new();
+    let mut offset = 0;
+    let mut padding = 0;
+    for field in format.fields.iter() {
+        if field.name.starts_with("common_") {
+            if field.offset + field.size > COMMON_SIZE {
+                return Err(format!("{}: unexpected field {}", tracepoint, field.name));
+            }
+            if offset == 0 {
+                writeln!(fields, "    common: {},", COMMON_TYPE).unwrap();
+                accessors.push_str(&accessor("common", COMMON_TYPE));
+                offset = COMMON_SIZE;
+            }
+            continue;
+        }
+        if field.offset < offset {
+            return Err(format!("{}: overlapping field {}", tracepoint, field.name));
+        }
+        if field.offset > offset {
+            let len = field.offset - offset;
+            writeln!(fields, "    _pad{}: [u8; {}],", padding, len).unwrap();
+            padding += 1;
+        }
+
+        let name = field_ident(&field.name);
+        let ty = field_type(field);
+        writeln!(fields, "    {}: {},", name, ty).unwrap();
+        accessors.push_str(&accessor(&name, &ty));
+        writeln!(
+            layout,
+            "        (\"{}\", {}, {}),",
+            field.name, field.offset, field.size
+        )
+        .unwrap();
+        offset = field.offset + field.size;
+    }
+
+    Ok(format!(
+        r#"/// Record of the `{tracepoint}` tracepoint.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct {ident} {{
+{fields}}}
+
+impl {ident} {{
+    /// The layout of the record as `(name, offset, size)` triples.
+    pub const FIELDS: &'static [(&'static str, usize, usize)] = &[
+{layout}    ];
+{accessors}}}
+"#,
+        tracepoint = tracepoint,
+        ident = ident,
+        fields = fields,
+        layout = layout,
+        accessors = accessors
+    ))
+}
+
+pub fn cmd_tracepoint(tracepoints: &[&str]) -> Result<(), CommandError> {
+    let mut out = io::stdout();
+    writeln!(
+        &mut out,
+        "// Generated by `cargo bpf tracepoint` from the format files of the running kernel"
+    )
+    .unwrap();
+    for tracepoint in tracepoints.iter() {
+        let record = tracepoint_record(tracepoint).map_err(CommandError::Message)?;
+        writeln!(&mut out, "\n{}", record).unwrap();
+    }
 
     Ok(())
 }
 
+// The common_* fields are replaced by TracepointCommon
+const COMMON_TYPE: &str = "::redbpf_probes::tracepoint::TracepointCommon";
+const COMMON_SIZE: usize = 8;
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (first, rest) = part.split_at(1);
+            format!("{}{}", first.to_uppercase(), rest)
+        })
+        .collect()
+}
+
+fn field_ident(name: &str) -> String {
+    // the strict and reserved keywords of the 2018 edition
+    const KEYWORDS: [&str; 51] = [
+        "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+        "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+        "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+        "return", "Self", "self", "static", "struct", "super", "trait", "true", "try", "type",
+        "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+    ];
+    if KEYWORDS.contains(&name) {
+        format!("{}_", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn field_type(field: &Field) -> String {
+    if field.is_data_loc() && field.size == 4 {
+        return "::redbpf_probes::tracepoint::DataLoc".to_string();
+    }
+    if field.is_pointer() && field.size == 8 {
+        return "*const ::cty::c_void".to_string();
+    }
+    let bytes = format!("[u8; {}]", field.size);
+    match field.array_len() {
+        Some(len) if len > 0 && field.size / len * len == field.size => {
+            match int_type(field.size / len, field.signed) {
+                Some(ty) => format!("[{}; {}]", ty, len),
+                None => bytes,
+            }
+        }
+        Some(_) => bytes,
+        None => int_type(field.size, field.signed)
+            .map(String::from)
+            .unwrap_or(bytes),
+    }
+}
+
+fn int_type(size: usize, signed: bool) -> Option<&'static str> {
+    Some(match (size, signed) {
+        (1, false) => "u8",
+        (1, true) => "i8",
+        (2, false) => "u16",
+        (2, true) => "i16",
+        (4, false) => "u32",
+        (4, true) => "i32",
+        (8, false) => "u64",
+        (8, true) => "i64",
+        _ => return None,
+    })
+}
+
+fn accessor(name: &str, ty: &str) -> String {
+    format!(
+        r#"
+    #[inline]
+    pub fn {name}(&self) -> {ty} {{
+        self.{name}
+    }}
+"#,
+        name = name,
+        ty = ty
+    )
+}
+
 #[derive(Debug)]
 struct Callbacks;
 
@@ -110,4 +446,176 @@ impl ParseCallbacks for Callbacks {
             _ => None
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        field_ident, field_type, generate_tracepoint_record, helper_version, kernel_feature,
+        HELPER_VERSIONS,
+    };
+    use redbpf::tracepoint::{Field, Format};
+
+    #[test]
+    fn helper_versions() {
+        assert!(HELPER_VERSIONS.windows(2).all(|w| w[0] < w[1]));
+
+        assert_eq!(helper_version(90), None);
+        assert_eq!(helper_version(91), Some((5, 0)));
+        assert_eq!(helper_version(109), Some((5, 3)));
+        assert_eq!(helper_version(110), Some((5, 4)));
+        assert_eq!(helper_version(111), Some((5, 5)));
+        assert_eq!(helper_version(200), Some((5, 8)));
+
+        assert_eq!(kernel_feature((4, 19)), None);
+        assert_eq!(kernel_feature((5, 4)), Some("kernel5_4".to_string()));
+        assert_eq!(kernel_feature((5, 9)), Some("latest".to_string()));
+    }
+
+    fn field(decl: &str, size: usize, signed: bool) -> Field {
+        Field {
+            name: String::new(),
+            decl: decl.to_string(),
+            offset: 0,
+            size,
+            signed,
+        }
+    }
+
+    #[test]
+    fn field_types() {
+        let data_loc = "::redbpf_probes::tracepoint::DataLoc";
+        assert_eq!(
+            field_type(&field("__data_loc char[] name", 4, true)),
+            data_loc
+        );
+        assert_eq!(
+            field_type(&field("__data_loc u8[] buf", 4, false)),
+            data_loc
+        );
+        assert_eq!(
+            field_type(&field("const void * skaddr", 8, false)),
+            "*const ::cty::c_void"
+        );
+        // 32-bit pointers are read as integers
+        assert_eq!(field_type(&field("void * ptr", 4, false)), "u32");
+
+        assert_eq!(field_type(&field("__u8 saddr[4]", 4, false)), "[u8; 4]");
+        assert_eq!(field_type(&field("__u16 ports[2]", 4, false)), "[u16; 2]");
+        assert_eq!(field_type(&field("char comm[16]", 16, true)), "[i8; 16]");
+        assert_eq!(field_type(&field("__u32 ids[ 3 ]", 12, false)), "[u32; 3]");
+        // elements that aren't integers, or that don't divide the size
+        assert_eq!(field_type(&field("struct x xs[2]", 24, false)), "[u8; 24]");
+        assert_eq!(field_type(&field("__u16 odd[2]", 5, false)), "[u8; 5]");
+        assert_eq!(field_type(&field("__u8 empty[0]", 0, false)), "[u8; 0]");
+
+        assert_eq!(field_type(&field("int oldstate", 4, true)), "i32");
+        assert_eq!(field_type(&field("u64 bytes", 8, false)), "u64");
+        assert_eq!(field_type(&field("__u8 tos", 1, false)), "u8");
+        assert_eq!(
+            field_type(&field("struct in6_addr addr", 16, false)),
+            "[u8; 16]"
+        );
+    }
+
+    #[test]
+    fn field_idents() {
+        let keywords = [
+            "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern",
+            "fn", "for", "if", "impl", "loop", "match", "mod", "move", "return", "struct", "trait",
+            "type", "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final",
+            "macro", "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+        ];
+        for keyword in keywords.iter() {
+            assert_eq!(field_ident(keyword), format!("{}_", keyword));
+        }
+        assert_eq!(field_ident("self"), "self_");
+        assert_eq!(field_ident("Self"), "Self_");
+        assert_eq!(field_ident("union"), "union");
+        assert_eq!(field_ident("types"), "types");
+        assert_eq!(field_ident("saddr"), "saddr");
+    }
+
+    #[test]
+    fn tracepoint_record() {
+        let format = Format::parse(
+            "name: sched_process_exec
+ID: 313
+format:
+	field:unsigned short common_type;	offset:0;	size:2;	signed:0;
+	field:unsigned char common_flags;	offset:2;	size:1;	signed:0;
+	field:unsigned char common_preempt_count;	offset:3;	size:1;	signed:0;
+	field:int common_pid;	offset:4;	size:4;	signed:1;
+
+	field:__data_loc char[] filename;	offset:8;	size:4;	signed:1;
+	field:pid_t pid;	offset:12;	size:4;	signed:1;
+	field:int type;	offset:16;	size:4;	signed:1;
+	field:__u16 ports[2];	offset:24;	size:4;	signed:0;
+	field:const void * ptr;	offset:32;	size:8;	signed:0;
+
+print fmt: \"filename=%s\"",
+        )
+        .unwrap();
+        let record = generate_tracepoint_record("sched/sched_process_exec", &format).unwrap();
+        let expected = r#"/// Record of the `sched/sched_process_exec` tracepoint.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct SchedProcessExec {
+    common: ::redbpf_probes::tracepoint::TracepointCommon,
+    filename: ::redbpf_probes::tracepoint::DataLoc,
+    pid: i32,
+    type_: i32,
+    _pad0: [u8; 4],
+    ports: [u16; 2],
+    _pad1: [u8; 4],
+    ptr: *const ::cty::c_void,
+}
+
+impl SchedProcessExec {
+    /// The layout of the record as `(name, offset, size)` triples.
+    pub const FIELDS: &'static [(&'static str, usize, usize)] = &[
+        ("filename", 8, 4),
+        ("pid", 12, 4),
+        ("type", 16, 4),
+        ("ports", 24, 4),
+        ("ptr", 32, 8),
+    ];
+"#;
+        assert!(record.starts_with(expected), "{}", record);
+        assert!(record.contains(
+            "
+    #[inline]
+    pub fn type_(&self) -> i32 {
+        self.type_
+    }
+"
+        ));
+        assert!(record.contains("pub fn filename(&self) -> ::redbpf_probes::tracepoint::DataLoc {"));
+        assert!(record
+            .contains("pub fn common(&self) -> ::redbpf_probes::tracepoint::TracepointCommon {"));
+        assert!(record.ends_with("    }\n}\n"));
+    }
+
+    #[test]
+    fn tracepoint_record_errors() {
+        let parse = |fields: &str| {
+            Format::parse(&format!(
+                "name: test\nformat:\n\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;\n{}",
+                fields
+            ))
+            .unwrap()
+        };
+        let overlapping = parse(
+            "\tfield:int a;\toffset:8;\tsize:4;\tsigned:1;\n\tfield:int b;\toffset:10;\tsize:4;\tsigned:1;\n",
+        );
+        assert_eq!(
+            generate_tracepoint_record("test/test", &overlapping).unwrap_err(),
+            "test/test: overlapping field b"
+        );
+        let common = parse("\tfield:int common_x;\toffset:8;\tsize:4;\tsigned:1;\n");
+        assert_eq!(
+            generate_tracepoint_record("test/test", &common).unwrap_err(),
+            "test/test: unexpected field common_x"
+        );
+    }
+}
@@ -0,0 +1,1011 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Bindings generated from BTF instead of the kernel headers.
+//
+// The requested types are defined with the layout given by the BTF: members
+// are placed at their offsets with explicit padding, structs that can't be
+// laid out by `repr(C)` are packed, and bitfields are stored in byte arrays
+// read with accessors. Anonymous structs, unions and enums are named after
+// the type containing them, like bindgen does. Types nested deeper than the
+// requested depth keep only their size and alignment.
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use redbpf::btf::{Btf, BtfKind};
+
+use super::{field_ident, int_type, write_bindings, BindgenOptions};
+use crate::CommandError;
+
+// BTF_INT_SIGNED, the signedness bit of the encoding of ints
+const INT_SIGNED: u32 = 1;
+
+const PRIMITIVES: [&str; 16] = [
+    "bool", "char", "f32", "f64", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32",
+    "u64", "u128", "usize",
+];
+
+/// Generates Rust definitions of the structs, unions, enums and typedefs
+/// named `types` from `btf`.
+///
+/// The types the requested ones contain or point to are defined as well,
+/// down to `depth` levels below them. Beyond that, types are opaque: they
+/// keep their size so that the layout of the types containing them is
+/// right, but not their fields. Anonymous members don't count as a level.
+///
+/// The output only depends on `core` and `cty`, so that it can be used in
+/// `no_std` probes.
+pub fn generate_btf_bindings(btf: &Btf, types: &[&str], depth: usize) -> Result<String, String> {
+    let mut generator = Generator::new(btf, depth);
+    for name in types.iter() {
+        generator.add_root(name)?;
+    }
+    generator.walk();
+
+    Ok(generator.generate())
+}
+
+/// Prints the bindings of the types of `options`, generated from the BTF at
+/// `path` or from the BTF of the running kernel. The types are names, and
+/// the functions, variables and derives of `options` are ignored.
+pub fn cmd_bindgen_btf(
+    path: Option<&Path>,
+    depth: usize,
+    options: &BindgenOptions,
+) -> Result<(), CommandError> {
+    let btf = match path {
+        Some(path) => fs::read(path)
+            .map_err(|e| CommandError::Message(format!("{}: {}", path.display(), e)))
+            .and_then(|data| {
+                Btf::parse(&data)
+                    .map_err(|e| CommandError::Message(format!("{}: {:?}", path.display(), e)))
+            })?,
+        None => {
+            Btf::from_kernel().map_err(|e| CommandError::Message(format!("kernel BTF: {:?}", e)))?
+        }
+    };
+    let types: Vec<&str> = options.types.iter().map(String::as_str).collect();
+    let mut bindings = generate_btf_bindings(&btf, &types, depth).map_err(CommandError::Message)?;
+    options.add_accessors(&mut bindings);
+    write_bindings(&bindings);
+
+    Ok(())
+}
+
+struct Generator<'a> {
+    btf: &'a Btf,
+    depth: usize,
+    // the identifiers of the types, and the types using them
+    names: HashMap<u32, String>,
+    taken: HashMap<String, u32>,
+    // the types defined in full, with the shallowest depth they're used at
+    full: HashMap<u32, usize>,
+    order: Vec<u32>,
+    opaque: Vec<u32>,
+    queue: VecDeque<(u32, usize)>,
+    aliases: Vec<(String, u32)>,
+    // the anonymous types named after a typedef
+    typedefs: HashSet<u32>,
+    alignments: RefCell<HashMap<u32, u32>>,
+}
+
+// A field of a struct or a union: a member, or the storage of consecutive
+// bitfields
+enum Field {
+    Member {
+        name: String,
+        offset: u32,
+        type_id: u32,
+    },
+    Bitfields {
+        offset: u32,
+        size: u32,
+        // the name, type, bit offset in the storage and bit size
+        members: Vec<(String, u32, u32, u32)>,
+    },
+}
+
+impl Field {
+    fn offset(&self) -> u32 {
+        match self {
+            Field::Member { offset, .. } | Field::Bitfields { offset, .. } => *offset,
+        }
+    }
+}
+
+impl<'a> Generator<'a> {
+    fn new(btf: &'a Btf, depth: usize) -> Generator<'a> {
+        let mut generator = Generator {
+            btf,
+            depth,
+            names: HashMap::new(),
+            taken: HashMap::new(),
+            full: HashMap::new(),
+            order: Vec::new(),
+            opaque: Vec::new(),
+            queue: VecDeque::new(),
+            aliases: Vec::new(),
+            typedefs: HashSet::new(),
+            alignments: RefCell::new(HashMap::new()),
+        };
+        // anonymous types are often declared with a typedef, like atomic_t
+        for ty in btf.types().iter() {
+            if let BtfKind::Typedef(target) = ty.kind {
+                let target = generator.skip_qualifiers(target);
+                if generator.is_anonymous(target) && !generator.names.contains_key(&target) {
+                    generator.assign(target, &ty.name);
+                    generator.typedefs.insert(target);
+                }
+            }
+        }
+
+        generator
+    }
+
+    fn kind(&self, id: u32) -> &'a BtfKind {
+        self.btf
+            .type_by_id(id)
+            .map(|ty| &ty.kind)
+            .unwrap_or(&BtfKind::Void)
+    }
+
+    fn skip_qualifiers(&self, id: u32) -> u32 {
+        let mut id = id;
+        for _ in 0..self.btf.types().len() {
+            match *self.kind(id) {
+                BtfKind::Volatile(t)
+                | BtfKind::Const(t)
+                | BtfKind::Restrict(t)
+                | BtfKind::TypeTag(t) => id = t,
+                _ => break,
+            }
+        }
+
+        id
+    }
+
+    // Skips typedefs as well, the bindings use the types they refer to
+    fn skip(&self, id: u32) -> u32 {
+        let mut id = id;
+        for _ in 0..self.btf.types().len() {
+            match *self.kind(self.skip_qualifiers(id)) {
+                BtfKind::Typedef(t) => id = t,
+                _ => return self.skip_qualifiers(id),
+            }
+        }
+
+        id
+    }
+
+    fn is_anonymous(&self, id: u32) -> bool {
+        match self.btf.type_by_id(id) {
+            Some(ty) => match ty.kind {
+                BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. } => {
+                    ty.name.is_empty()
+                }
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    fn assign(&mut self, id: u32, name: &str) {
+        let mut ident = field_ident(name);
+        if matches!(self.taken.get(&ident), Some(&other) if other != id) {
+            ident = format!("{}_{}", ident, id);
+        }
+        self.taken.insert(ident.clone(), id);
+        self.names.insert(id, ident);
+    }
+
+    fn ident(&self, id: u32) -> String {
+        match self.names.get(&id) {
+            Some(name) => name.clone(),
+            None => format!("__btf_ty_{}", id),
+        }
+    }
+
+    fn add_root(&mut self, name: &str) -> Result<(), String> {
+        let types = self.btf.types();
+        let find = |pred: &dyn Fn(&BtfKind) -> bool| {
+            types
+                .iter()
+                .position(|ty| ty.name == name && pred(&ty.kind))
+                .map(|id| id as u32)
+        };
+        let id = find(&|kind| {
+            matches!(
+                kind,
+                BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. }
+            )
+        });
+        if let Some(id) = id {
+            self.queue_type(id, 0);
+            return Ok(());
+        }
+        if let Some(id) = find(&|kind| matches!(kind, BtfKind::Typedef(_))) {
+            let target = self.skip(id);
+            match self.kind(target) {
+                BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. } => {
+                    self.queue_type(target, 0)
+                }
+                _ => self.visit(target, name, 0, false, &mut 0),
+            }
+            // typedefs like u32 are replaced by the Rust types
+            if self.names.get(&target).map(String::as_str) != Some(name)
+                && !PRIMITIVES.contains(&name)
+                && !self.taken.contains_key(name)
+            {
+                self.taken.insert(name.to_string(), id);
+                self.aliases.push((name.to_string(), target));
+            }
+            return Ok(());
+        }
+        if find(&|kind| *kind == BtfKind::Fwd).is_some() {
+            return Err(format!("type `{}' is only declared in the BTF", name));
+        }
+
+        Err(format!("type `{}' not found in the BTF", name))
+    }
+
+    fn queue_type(&mut self, id: u32, depth: usize) {
+        if !self.names.contains_key(&id) {
+            let name = self
+                .btf
+                .type_by_id(id)
+                .map(|ty| ty.name.clone())
+                .unwrap_or_default();
+            self.assign(id, &name);
+        }
+        self.queue.push_back((id, depth));
+    }
+
+    fn walk(&mut self) {
+        while let Some((id, depth)) = self.queue.pop_front() {
+            match self.full.get(&id) {
+                Some(&seen) if seen <= depth => continue,
+                Some(_) => (),
+                None => self.order.push(id),
+            }
+            self.full.insert(id, depth);
+
+            let parent = self.ident(id);
+            let mut anonymous = 0;
+            match self.kind(id) {
+                BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => {
+                    for member in members.iter() {
+                        self.visit(member.type_id, &parent, depth, true, &mut anonymous);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Visits the type of a member of `parent`. Anonymous types are named
+    // after the parent and, when they're members themselves, defined at its
+    // depth.
+    fn visit(
+        &mut self,
+        type_id: u32,
+        parent: &str,
+        depth: usize,
+        by_value: bool,
+        anonymous: &mut usize,
+    ) {
+        let id = self.skip(type_id);
+        match *self.kind(id) {
+            BtfKind::Ptr(pointee) => self.visit(pointee, parent, depth, false, anonymous),
+            BtfKind::Array { elem, .. } => self.visit(elem, parent, depth, by_value, anonymous),
+            BtfKind::Struct { .. } | BtfKind::Union { .. } | BtfKind::Enum { .. } => {
+                let inline = self.is_anonymous(id) && !self.typedefs.contains(&id);
+                if inline && !self.names.contains_key(&id) {
+                    *anonymous += 1;
+                    self.assign(id, &format!("{}__bindgen_ty_{}", parent, anonymous));
+                }
+                let depth = if inline && by_value { depth } else { depth + 1 };
+                let is_enum = matches!(self.kind(id), BtfKind::Enum { .. });
+                if is_enum || depth <= self.depth {
+                    self.queue_type(id, depth);
+                } else {
+                    self.add_opaque(id);
+                }
+            }
+            BtfKind::Fwd => self.add_opaque(id),
+            _ => (),
+        }
+    }
+
+    fn add_opaque(&mut self, id: u32) {
+        if !self.names.contains_key(&id) {
+            let name = self
+                .btf
+                .type_by_id(id)
+                .map(|ty| ty.name.clone())
+                .unwrap_or_default();
+            self.assign(id, &name);
+        }
+        if !self.opaque.contains(&id) {
+            self.opaque.push(id);
+        }
+    }
+
+    fn rust_type(&self, type_id: u32) -> String {
+        let id = self.skip(type_id);
+        match *self.kind(id) {
+            BtfKind::Int { size, encoding } => {
+                int_type(size as usize, encoding >> 24 & INT_SIGNED != 0)
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("[u8; {}]", size))
+            }
+            BtfKind::Float { size: 4 } => "f32".to_string(),
+            BtfKind::Float { size: 8 } => "f64".to_string(),
+            BtfKind::Float { size } => format!("[u8; {}]", size),
+            BtfKind::Ptr(pointee) => match *self.kind(self.skip(pointee)) {
+                BtfKind::Void | BtfKind::Func(_) | BtfKind::FuncProto { .. } => {
+                    "*mut ::cty::c_void".to_string()
+                }
+                _ => format!("*mut {}", self.rust_type(pointee)),
+            },
+            BtfKind::Array { elem, nelems, .. } => {
+                format!("[{}; {}]", self.rust_type(elem), nelems)
+            }
+            BtfKind::Struct { .. }
+            | BtfKind::Union { .. }
+            | BtfKind::Enum { .. }
+            | BtfKind::Fwd => self.ident(id),
+            _ => "::cty::c_void".to_string(),
+        }
+    }
+
+    // The alignment of the Rust type of `type_id`
+    fn align_of(&self, type_id: u32) -> u32 {
+        let id = self.skip(type_id);
+        if let Some(&align) = self.alignments.borrow().get(&id) {
+            return align;
+        }
+        let align = match *self.kind(id) {
+            BtfKind::Int { size, .. } | BtfKind::Enum { size, .. } | BtfKind::Float { size } => {
+                match size {
+                    1 | 2 | 4 | 8 => size,
+                    _ => 1,
+                }
+            }
+            BtfKind::Ptr(_) => 8,
+            BtfKind::Array { elem, .. } => self.align_of(elem),
+            BtfKind::Struct { .. } | BtfKind::Union { .. } => match self.layout(id) {
+                Some((_, _, align, false)) => align,
+                _ => 1,
+            },
+            _ => 1,
+        };
+        self.alignments.borrow_mut().insert(id, align);
+
+        align
+    }
+
+    fn field_size(&self, field: &Field) -> u32 {
+        match field {
+            Field::Member { type_id, .. } => self.btf.size_of(*type_id).unwrap_or(0),
+            Field::Bitfields { size, .. } => *size,
+        }
+    }
+
+    fn field_align(&self, field: &Field) -> u32 {
+        match field {
+            Field::Member { type_id, .. } => self.align_of(*type_id),
+            Field::Bitfields { .. } => 1,
+        }
+    }
+
+    // Returns the fields of the struct or union `id`, its size, alignment
+    // and whether it must be packed
+    fn layout(&self, id: u32) -> Option<(Vec<Field>, u32, u32, bool)> {
+        let (size, members) = match self.kind(id) {
+            BtfKind::Struct { size, members } | BtfKind::Union { size, members } => {
+                (*size, members)
+            }
+            _ => return None,
+        };
+        let mut fields: Vec<Field> = Vec::new();
+        let mut anonymous = 0;
+        for member in members.iter() {
+            let mut bit_offset = member.bit_offset;
+            let mut bits = member.bitfield_size;
+            // without the kind flag, bitfields are ints with fewer bits
+            if let BtfKind::Int { size, encoding } = *self.kind(self.skip(member.type_id)) {
+                let int_bits = encoding & 0xff;
+                if bits == 0 && int_bits != size * 8 {
+                    bit_offset += encoding >> 16 & 0xff;
+                    bits = int_bits;
+                }
+            }
+            if bits == 0 && bit_offset % 8 == 0 {
+                let name = if member.name.is_empty() {
+                    anonymous += 1;
+                    format!("__bindgen_anon_{}", anonymous)
+                } else {
+                    field_ident(&member.name)
+                };
+                fields.push(Field::Member {
+                    name,
+                    offset: bit_offset / 8,
+                    type_id: member.type_id,
+                });
+                continue;
+            }
+            let bits = if bits == 0 {
+                self.btf.size_of(member.type_id).unwrap_or(0) * 8
+            } else {
+                bits
+            };
+            let start = bit_offset / 8;
+            let end = (bit_offset + bits - 1) / 8 + 1;
+            if let Some(Field::Bitfields {
+                offset,
+                size,
+                members,
+            }) = fields.last_mut()
+            {
+                if start >= *offset {
+                    *size = cmp::max(*size, end - *offset);
+                    members.push((
+                        member.name.clone(),
+                        member.type_id,
+                        bit_offset - *offset * 8,
+                        bits,
+                    ));
+                    continue;
+                }
+            }
+            fields.push(Field::Bitfields {
+                offset: start,
+                size: end - start,
+                members: vec![(member.name.clone(), member.type_id, bit_offset % 8, bits)],
+            });
+        }
+
+        let align = fields
+            .iter()
+            .map(|field| self.field_align(field))
+            .max()
+            .unwrap_or(1);
+        let packed = size % align != 0
+            || fields
+                .iter()
+                .any(|field| field.offset() % self.field_align(field) != 0);
+
+        Some((fields, size, if packed { 1 } else { align }, packed))
+    }
+
+    fn generate(&self) -> String {
+        let mut out = String::new();
+        let mut consts = HashSet::new();
+        let mut bitfields = false;
+        for (name, target) in self.aliases.iter() {
+            writeln!(out, "pub type {} = {};\n", name, self.rust_type(*target)).unwrap();
+        }
+        for id in self.order.iter() {
+            match self.kind(*id) {
+                BtfKind::Enum { size, values } => {
+                    out.push_str(&self.generate_enum(*id, *size, values, &mut consts))
+                }
+                _ => match self.generate_composite(*id) {
+                    Some((def, has_bitfields)) => {
+                        bitfields |= has_bitfields;
+                        out.push_str(&def);
+                    }
+                    None => out.push_str(&self.generate_opaque(*id)),
+                },
+            }
+        }
+        for id in self.opaque.iter().filter(|id| !self.full.contains_key(id)) {
+            out.push_str(&self.generate_opaque(*id));
+        }
+        if bitfields {
+            out.push_str(BITFIELD_ACCESSOR);
+        }
+
+        out
+    }
+
+    fn generate_enum(
+        &self,
+        id: u32,
+        size: u32,
+        values: &[(String, i64)],
+        consts: &mut HashSet<String>,
+    ) -> String {
+        let ident = self.ident(id);
+        let signed = values.iter().any(|(_, value)| *value < 0);
+        let repr = int_type(size as usize, signed).unwrap_or(if signed { "i32" } else { "u32" });
+        let (min, max) = match (repr, signed) {
+            ("i8", _) => (i64::from(i8::MIN), i64::from(i8::MAX)),
+            ("i16", _) => (i64::from(i16::MIN), i64::from(i16::MAX)),
+            ("i32", _) => (i64::from(i32::MIN), i64::from(i32::MAX)),
+            ("u8", _) => (0, i64::from(u8::MAX)),
+            ("u16", _) => (0, i64::from(u16::MAX)),
+            ("u32", _) => (0, i64::from(u32::MAX)),
+            _ => (i64::MIN, i64::MAX),
+        };
+        let mut out = format!("pub type {} = {};\n", ident, repr);
+        for (name, value) in values.iter() {
+            // enumerators of different compilation units can clash
+            if !consts.insert(name.clone()) {
+                continue;
+            }
+            if *value < min || *value > max {
+                writeln!(
+                    out,
+                    "pub const {}: {} = {}i64 as {};",
+                    name, ident, value, ident
+                )
+                .unwrap();
+            } else {
+                writeln!(out, "pub const {}: {} = {};", name, ident, value).unwrap();
+            }
+        }
+        out.push('\n');
+
+        out
+    }
+
+    // Returns the definition of a struct or union and whether it has
+    // bitfields, or None if its layout can't be represented
+    fn generate_composite(&self, id: u32) -> Option<(String, bool)> {
+        let is_union = matches!(self.kind(id), BtfKind::Union { .. });
+        let (fields, size, _, packed) = self.layout(id)?;
+        let ident = self.ident(id);
+        let mut body = String::new();
+        let mut accessors = String::new();
+        let mut offset = 0;
+        let mut padding = 0;
+        let mut storage = 0;
+        for field in fields.iter() {
+            let field_offset = field.offset();
+            if is_union {
+                if field_offset != 0 {
+                    return None;
+                }
+            } else {
+                if field_offset < offset {
+                    return None;
+                }
+                if field_offset > offset {
+                    padding += 1;
+                    writeln!(
+                        body,
+                        "    _pad{}: [u8; {}],",
+                        padding,
+                        field_offset - offset
+                    )
+                    .unwrap();
+                }
+                offset = field_offset;
+            }
+            match field {
+                Field::Member { name, type_id, .. } => {
+                    writeln!(body, "    pub {}: {},", name, self.rust_type(*type_id)).unwrap();
+                }
+                Field::Bitfields { size, members, .. } => {
+                    storage += 1;
+                    writeln!(body, "    pub _bitfield_{}: [u8; {}],", storage, size).unwrap();
+                    for (name, type_id, bit, bits) in members.iter().filter(|m| !m.0.is_empty()) {
+                        accessors.push_str(
+                            &self.bitfield_accessor(name, *type_id, storage, *bit, *bits, is_union),
+                        );
+                    }
+                }
+            }
+            let field_size = self.field_size(field);
+            offset = if is_union {
+                cmp::max(offset, field_size)
+            } else {
+                offset + field_size
+            };
+        }
+        if offset > size {
+            return None;
+        }
+        if offset < size {
+            if is_union {
+                writeln!(body, "    _pad: [u8; {}],", size).unwrap();
+            } else {
+                writeln!(body, "    _pad{}: [u8; {}],", padding + 1, size - offset).unwrap();
+            }
+        }
+
+        let mut out = format!(
+            "#[repr(C{})]\n#[derive(Copy, Clone)]\npub {} {} {{\n{}}}\n",
+            if packed { ", packed" } else { "" },
+            if is_union { "union" } else { "struct" },
+            ident,
+            body
+        );
+        if !accessors.is_empty() {
+            write!(out, "\nimpl {} {{{}}}\n", ident, accessors).unwrap();
+        }
+        writeln!(
+            out,
+            "const _: [(); {}] = [(); ::core::mem::size_of::<{}>()];\n",
+            size, ident
+        )
+        .unwrap();
+
+        Some((out, storage > 0))
+    }
+
+    fn bitfield_accessor(
+        &self,
+        name: &str,
+        type_id: u32,
+        storage: usize,
+        bit: u32,
+        bits: u32,
+        is_union: bool,
+    ) -> String {
+        let id = self.skip(type_id);
+        let (ty, signed) = match *self.kind(id) {
+            BtfKind::Int { size, encoding } => {
+                let signed = encoding >> 24 & INT_SIGNED != 0;
+                (int_type(size as usize, signed).map(String::from), signed)
+            }
+            BtfKind::Enum { .. } => (Some(self.ident(id)), false),
+            _ => (None, false),
+        };
+        let ty = ty.unwrap_or_else(|| "u64".to_string());
+        let read = format!(
+            "_btf_bitfield(&{{ self._bitfield_{} }}, {}, {}, {}) as {}",
+            storage, bit, bits, signed, ty
+        );
+        format!(
+            r#"
+    #[inline]
+    pub fn {name}(&self) -> {ty} {{
+        {read}
+    }}
+"#,
+            name = field_ident(name),
+            ty = ty,
+            read = if is_union {
+                format!("unsafe {{ {} }}", read)
+            } else {
+                read
+            }
+        )
+    }
+
+    fn generate_opaque(&self, id: u32) -> String {
+        let ident = self.ident(id);
+        let size = match self.kind(id) {
+            BtfKind::Struct { size, .. } | BtfKind::Union { size, .. } => *size,
+            _ => {
+                return format!(
+                    "#[repr(C)]\n#[derive(Copy, Clone)]\npub struct {} {{\n    _unused: [u8; 0],\n}}\n\n",
+                    ident
+                )
+            }
+        };
+        let align = self.align_of(id);
+        format!(
+            "#[repr(C)]\n#[derive(Copy, Clone)]\npub struct {} {{\n    _opaque: [u{}; {}],\n}}\n\n",
+            ident,
+            align * 8,
+            size / align
+        )
+    }
+}
+
+// Reads `size` bits at bit `offset` of a little endian bitfield storage
+const BITFIELD_ACCESSOR: &str = r#"#[inline(always)]
+fn _btf_bitfield(storage: &[u8], offset: usize, size: usize, signed: bool) -> u64 {
+    let mut value = 0u64;
+    for i in 0..size {
+        let bit = offset + i;
+        value |= u64::from(storage[bit / 8] >> (bit % 8) & 1) << i;
+    }
+    if signed && size < 64 && value >> (size - 1) & 1 == 1 {
+        value |= !0u64 << size;
+    }
+    value
+}
+"#;
+
+#[cfg(test)]
+mod test {
+    use super::generate_btf_bindings;
+    use redbpf::btf::{Btf, BtfKind, BtfMember, BtfType};
+    use std::fs;
+    use std::process::Command;
+
+    fn ty(name: &str, kind: BtfKind) -> BtfType {
+        BtfType {
+            name: name.to_string(),
+            kind,
+        }
+    }
+
+    fn int(name: &str, size: u32, signed: bool) -> BtfType {
+        let signed = if signed { 1 << 24 } else { 0 };
+        ty(
+            name,
+            BtfKind::Int {
+                size,
+                encoding: signed | size * 8,
+            },
+        )
+    }
+
+    fn member(name: &str, type_id: u32, bit_offset: u32, bitfield_size: u32) -> BtfMember {
+        BtfMember {
+            name: name.to_string(),
+            type_id,
+            bit_offset,
+            bitfield_size,
+        }
+    }
+
+    fn values(values: &[(&str, i64)]) -> Vec<(String, i64)> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    }
+
+    // The types are numbered from 1
+    fn btf() -> Btf {
+        Btf::from_types(vec![
+            // 1
+            int("unsigned int", 4, false),
+            int("int", 4, true),
+            int("unsigned char", 1, false),
+            int("long long unsigned int", 8, false),
+            // 5
+            ty(
+                "padded",
+                BtfKind::Struct {
+                    size: 16,
+                    members: vec![
+                        member("a", 3, 0, 0),
+                        member("b", 1, 32, 0),
+                        member("c", 4, 64, 0),
+                    ],
+                },
+            ),
+            ty(
+                "bits",
+                BtfKind::Struct {
+                    size: 8,
+                    members: vec![
+                        member("x", 1, 0, 3),
+                        member("y", 2, 3, 5),
+                        member("z", 1, 8, 12),
+                        member("w", 3, 32, 0),
+                    ],
+                },
+            ),
+            ty(
+                "outer",
+                BtfKind::Struct {
+                    size: 16,
+                    members: vec![member("", 8, 0, 0), member("u", 9, 64, 0)],
+                },
+            ),
+            ty(
+                "",
+                BtfKind::Struct {
+                    size: 8,
+                    members: vec![member("lo", 1, 0, 0), member("hi", 1, 32, 0)],
+                },
+            ),
+            ty(
+                "",
+                BtfKind::Union {
+                    size: 8,
+                    members: vec![member("raw", 4, 0, 0), member("half", 1, 0, 0)],
+                },
+            ),
+            // 10
+            ty("task_struct", BtfKind::Fwd),
+            ty("", BtfKind::Ptr(10)),
+            ty(
+                "holder",
+                BtfKind::Struct {
+                    size: 16,
+                    members: vec![member("task", 11, 0, 0), member("inner", 13, 64, 0)],
+                },
+            ),
+            ty(
+                "deep",
+                BtfKind::Struct {
+                    size: 8,
+                    members: vec![member("v", 4, 0, 0)],
+                },
+            ),
+            ty(
+                "e",
+                BtfKind::Enum {
+                    size: 4,
+                    values: values(&[("E_NEG", -1), ("E_BIG", 0x7fff_ffff)]),
+                },
+            ),
+            // 15
+            ty(
+                "wide",
+                BtfKind::Enum {
+                    size: 4,
+                    values: values(&[("W_MAX", 0xffff_ffff), ("W_NEG", -2)]),
+                },
+            ),
+            ty(
+                "flags_e",
+                BtfKind::Enum {
+                    size: 1,
+                    values: values(&[("F_HIGH", 0xff)]),
+                },
+            ),
+            ty("atomic_t", BtfKind::Typedef(18)),
+            ty(
+                "",
+                BtfKind::Struct {
+                    size: 4,
+                    members: vec![member("counter", 2, 0, 0)],
+                },
+            ),
+            ty(
+                "unaligned",
+                BtfKind::Struct {
+                    size: 6,
+                    members: vec![member("a", 20, 0, 0), member("b", 1, 16, 0)],
+                },
+            ),
+            // 20
+            int("short unsigned int", 2, false),
+        ])
+    }
+
+    const ROOTS: [&str; 9] = [
+        "padded",
+        "unaligned",
+        "bits",
+        "outer",
+        "holder",
+        "e",
+        "wide",
+        "flags_e",
+        "atomic_t",
+    ];
+
+    #[test]
+    fn layout_and_padding() {
+        let out = generate_btf_bindings(&btf(), &["padded", "unaligned"], 1).unwrap();
+        assert!(out.contains(
+            "#[repr(C)]\n#[derive(Copy, Clone)]\npub struct padded {\n    pub a: u8,\n    _pad1: [u8; 3],\n    pub b: u32,\n    pub c: u64,\n}\n"
+        ));
+        assert!(out.contains("const _: [(); 16] = [(); ::core::mem::size_of::<padded>()];"));
+        // a u32 at offset 2 can't be laid out by repr(C)
+        assert!(out.contains(
+            "#[repr(C, packed)]\n#[derive(Copy, Clone)]\npub struct unaligned {\n    pub a: u16,\n    pub b: u32,\n}\n"
+        ));
+    }
+
+    #[test]
+    fn bitfields() {
+        let out = generate_btf_bindings(&btf(), &["bits"], 1).unwrap();
+        assert!(out.contains(
+            "pub struct bits {\n    pub _bitfield_1: [u8; 3],\n    _pad1: [u8; 1],\n    pub w: u8,\n    _pad2: [u8; 3],\n}\n"
+        ));
+        assert!(out.contains("_btf_bitfield(&{ self._bitfield_1 }, 0, 3, false) as u32"));
+        assert!(out.contains("_btf_bitfield(&{ self._bitfield_1 }, 3, 5, true) as i32"));
+        assert!(out.contains("_btf_bitfield(&{ self._bitfield_1 }, 8, 12, false) as u32"));
+        assert_eq!(out.matches("fn _btf_bitfield(").count(), 1);
+
+        let out = generate_btf_bindings(&btf(), &["padded"], 1).unwrap();
+        assert!(!out.contains("_btf_bitfield"));
+    }
+
+    #[test]
+    fn anonymous_types() {
+        let out = generate_btf_bindings(&btf(), &["outer", "atomic_t"], 0).unwrap();
+        assert!(out.contains(
+            "pub struct outer {\n    pub __bindgen_anon_1: outer__bindgen_ty_1,\n    pub u: outer__bindgen_ty_2,\n}\n"
+        ));
+        // anonymous members are defined even at depth 0
+        assert!(out
+            .contains("pub struct outer__bindgen_ty_1 {\n    pub lo: u32,\n    pub hi: u32,\n}\n"));
+        assert!(out.contains(
+            "pub union outer__bindgen_ty_2 {\n    pub raw: u64,\n    pub half: u32,\n}\n"
+        ));
+        // named after their typedef
+        assert!(out.contains("pub struct atomic_t {\n    pub counter: i32,\n}\n"));
+        assert!(!out.contains("pub type atomic_t"));
+    }
+
+    #[test]
+    fn opaque_types() {
+        let out = generate_btf_bindings(&btf(), &["holder"], 0).unwrap();
+        assert!(out.contains(
+            "pub struct holder {\n    pub task: *mut task_struct,\n    pub inner: deep,\n}\n"
+        ));
+        assert!(out.contains("pub struct task_struct {\n    _unused: [u8; 0],\n}\n"));
+        assert!(out.contains("pub struct deep {\n    _opaque: [u64; 1],\n}\n"));
+
+        let out = generate_btf_bindings(&btf(), &["holder"], 1).unwrap();
+        assert!(out.contains("pub struct deep {\n    pub v: u64,\n}\n"));
+
+        assert_eq!(
+            generate_btf_bindings(&btf(), &["task_struct"], 1).unwrap_err(),
+            "type `task_struct' is only declared in the BTF"
+        );
+        assert_eq!(
+            generate_btf_bindings(&btf(), &["missing"], 1).unwrap_err(),
+            "type `missing' not found in the BTF"
+        );
+    }
+
+    #[test]
+    fn enum_values() {
+        let out = generate_btf_bindings(&btf(), &["e", "wide", "flags_e"], 1).unwrap();
+        assert!(out.contains(
+            "pub type e = i32;\npub const E_NEG: e = -1;\npub const E_BIG: e = 2147483647;\n"
+        ));
+        assert!(out.contains("pub type wide = i32;\npub const W_MAX: wide = 4294967295i64 as wide;\npub const W_NEG: wide = -2;\n"));
+        assert!(out.contains("pub type flags_e = u8;\npub const F_HIGH: flags_e = 255;\n"));
+    }
+
+    // Compiles the bindings with a main checking the layouts and reading
+    // the bitfields, and runs it
+    #[test]
+    fn bindings_compile() {
+        let mut source = generate_btf_bindings(&btf(), &ROOTS, 0).unwrap();
+        source.push_str(
+            r#"
+fn main() {
+    use std::mem::size_of;
+
+    assert_eq!(size_of::<padded>(), 16);
+    assert_eq!(size_of::<unaligned>(), 6);
+    assert_eq!(size_of::<bits>(), 8);
+    assert_eq!(size_of::<outer>(), 16);
+    assert_eq!(size_of::<holder>(), 16);
+    assert_eq!(size_of::<atomic_t>(), 4);
+    assert_eq!(W_MAX, -1);
+
+    let b: bits = unsafe { std::mem::transmute([0xf5u8, 0xab, 0x0c, 0, 7, 0, 0, 0]) };
+    assert_eq!(b.x(), 5);
+    assert_eq!(b.y(), -2);
+    assert_eq!(b.z(), 0xcab);
+    assert_eq!(b.w, 7);
+}
+"#,
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("bindings.rs");
+        let bin = dir.path().join("bindings");
+        fs::write(&src, source).unwrap();
+        let status = Command::new("rustc")
+            .args(&[
+                "--edition",
+                "2018",
+                "--crate-type",
+                "bin",
+                "-A",
+                "warnings",
+                "-o",
+            ])
+            .arg(&bin)
+            .arg(&src)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(Command::new(&bin).status().unwrap().success());
+    }
+}
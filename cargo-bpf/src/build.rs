@@ -11,20 +11,44 @@ use std::fmt::{self, Display};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 use toml_edit::{Document, Item};
 
-use crate::llvm::process_ir;
+use crate::bindgen;
+#[cfg(llvm)]
+use crate::llvm::{is_newer_bitcode, process_ir, unsupported_code};
 use crate::CommandError;
+use redbpf::build::headers::{kernel_headers_version, kernel_headers_version_at};
+use redbpf::spec::ModuleSpec;
 
 #[derive(Debug)]
 pub enum Error {
     MissingManifest(PathBuf),
+    UnknownPackage(String),
+    SelectPackage(PathBuf),
     NoPrograms,
+    /// `cargo metadata` failed, with its error
+    Metadata(String),
+    NoLLVM,
     NoLLC,
     NoOPT,
+    NewerBitcode(String, Option<String>),
+    /// The ELF file of the program has no BTF, or not for all its programs
+    MissingBtf(String, String),
+    UnsupportedTarget(String),
+    /// Cross-building for the target needs its kernel headers
+    CrossKernelHeaders(String),
+    /// The kernel tree and the `target_arch` it's used for, and the files
+    /// missing from the tree
+    KernelHeaders(PathBuf, String, Vec<String>),
     Compile(String, Option<String>),
+    /// The probe has code the BPF target can't compile, with a message per
+    /// program listing the functions the code is in
+    UnsupportedCode(String, Vec<String>),
     MissingBitcode(String),
     Link(String),
     IOError(io::Error),
@@ -50,13 +74,81 @@ impl Display for Error {
         use Error::*;
         match self {
             MissingManifest(p) => write!(f, "Could not find `Cargo.toml' in {:?}", p),
+            UnknownPackage(p) => write!(f, "package `{}' not found in the workspace", p),
+            SelectPackage(p) => write!(
+                f,
+                "could not find the package with the eBPF programs in the workspace at {:?}, select it with -p",
+                p
+            ),
             NoPrograms => write!(f, "the package doesn't contain any eBPF programs"),
+            Metadata(e) => write!(f, "failed to read the workspace with cargo metadata: {}", e),
             Compile(p, Some(msg)) => write!(f, "failed to compile the `{}' program: {}", p, msg),
             Compile(p, None) => write!(f, "failed to compile the `{}' program", p),
+            UnsupportedCode(p, functions) => write!(
+                f,
+                "the `{}' program has code BPF doesn't support, in the functions:\n  {}",
+                p,
+                functions.join("\n  ")
+            ),
             MissingBitcode(p) => write!(f, "failed to generate bitcode for the `{}' program", p),
             Link(p) => write!(f, "failed to generate bitcode for the `{}' program", p),
-            NoOPT => write!(f, "no usable opt executable found, expecting version 9"),
-            NoLLC => write!(f, "no usable llc executable found, expecting version 9"),
+            NoLLVM => write!(
+                f,
+                "cargo-bpf is built without LLVM: {}",
+                option_env!("CARGO_BPF_LLVM_HINT").unwrap_or("enable one of its llvm-* features")
+            ),
+            NoOPT => write!(
+                f,
+                "no usable opt executable found, expecting version {}",
+                llvm_version_name()
+            ),
+            NoLLC => write!(
+                f,
+                "no usable llc executable found, expecting version {}",
+                llvm_version_name()
+            ),
+            NewerBitcode(p, rustc) => write!(
+                f,
+                "LLVM {} can't read the bitcode of the `{}' program, rustc uses {}: build cargo-bpf with the llvm-* feature of the LLVM of rustc",
+                llvm_version_name(),
+                p,
+                rustc
+                    .as_ref()
+                    .map(|v| format!("LLVM {}", v))
+                    .unwrap_or_else(|| "a newer LLVM".to_string())
+            ),
+            MissingBtf(p, missing) => write!(
+                f,
+                "the ELF file of the `{}' program has {}: LLVM didn't generate BTF from the debug info",
+                p, missing
+            ),
+            UnsupportedTarget(t) => write!(
+                f,
+                "unsupported target `{}', the probes can be built for x86_64 and aarch64",
+                t
+            ),
+            CrossKernelHeaders(t) => write!(
+                f,
+                "building for `{}' needs the kernel headers of the target, set them with --kernel-headers or $KERNEL_SOURCE",
+                t
+            ),
+            KernelHeaders(dir, _, missing) if missing.is_empty() => {
+                write!(f, "the kernel headers {:?} are not a directory", dir)
+            }
+            KernelHeaders(dir, arch, missing) => {
+                write!(
+                    f,
+                    "the kernel headers in {:?} are missing {}: copy the .config of the target kernel in the tree and run `make -C {} ARCH={}",
+                    dir,
+                    missing.join(", "),
+                    dir.display(),
+                    kernel_arch(arch).unwrap_or_default()
+                )?;
+                if arch != env::consts::ARCH {
+                    write!(f, " CROSS_COMPILE={}-linux-gnu-", arch)?;
+                }
+                write!(f, " olddefconfig prepare`")
+            }
             IOError(e) => write!(f, "{}", e),
         }
     }
@@ -64,34 +156,110 @@ impl Display for Error {
 
 impl From<Error> for CommandError {
     fn from(error: Error) -> CommandError {
-        CommandError(error.to_string())
+        CommandError::Build(error)
     }
 }
 
-fn build_probe(cargo: &Path, package: &Path, target_dir: &Path, probe: &str) -> Result<(), Error> {
-    let llc_args = ["-march=bpf", "-filetype=obj", "-o"];
+/// Returns the directory the probes are built in, `target_dir/bpf/programs`.
+///
+/// Each probe is in a subdirectory named after it, which holds its ELF file
+/// and the intermediate bitcode files. The directory can be passed to
+/// `redbpf::load::Loader::load_dir()` to load all the probes.
+pub fn programs_dir(target_dir: &Path) -> PathBuf {
+    target_dir.join("bpf").join("programs")
+}
+
+/// Returns the path of the ELF file of `probe`, eg.
+/// `target/bpf/programs/block_http/block_http.elf`.
+pub fn probe_path(target_dir: &Path, probe: &str) -> PathBuf {
+    programs_dir(target_dir)
+        .join(probe)
+        .join(format!("{}.elf", probe))
+}
+
+// The bitcode of a probe, to be linked into its ELF file
+struct LinkJob {
+    probe: String,
+    package: PathBuf,
+    bc_file: PathBuf,
+    elf_target: PathBuf,
+    fingerprint: String,
+}
+
+// Compiles `probe` to bitcode, and returns None when its ELF file is already
+// built from the same bitcode
+fn compile_bitcode(
+    package: &Path,
+    target_dir: &Path,
+    probe: &str,
+    options: &BuildOptions,
+    capture: bool,
+) -> Result<Option<LinkJob>, Error> {
+    llvm_version().ok_or(Error::NoLLVM)?;
+    let elf_target = probe_path(target_dir, probe);
+    let artifacts_dir = elf_target.parent().unwrap().to_path_buf();
     let target_dir = target_dir.join("bpf");
-    let artifacts_dir = target_dir.join("programs").join(probe);
-    let _ = fs::remove_dir_all(&artifacts_dir);
     fs::create_dir_all(&artifacts_dir)?;
+    // the ELF file and its fingerprint are kept to skip the link when the
+    // bitcode doesn't change
+    for entry in fs::read_dir(&artifacts_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+        if name.contains(".bc") {
+            fs::remove_file(&path)?;
+        }
+    }
 
-    if !Command::new(cargo)
+    let mut features = String::from("probes");
+    if let Some(feature) = kernel_feature(package, options) {
+        features.push_str(&format!(",redbpf-probes/{}", feature));
+    }
+    let mut rustc = Command::new(&options.cargo);
+    rustc
         .current_dir(package)
-        .args("rustc --release --features=probes".split(" "))
+        .args("rustc --release".split(" "))
+        .arg(format!("--features={}", features))
         .arg("--target-dir")
         .arg(target_dir.to_str().unwrap())
         .arg("--bin")
-        .arg(probe)
+        .arg(probe);
+    if let Some(target) = &options.target {
+        rustc.arg("--target").arg(target);
+    }
+    if let Some(dir) = &options.kernel_headers {
+        rustc.env("KERNEL_SOURCE", dir);
+    }
+    rustc
         .arg("--")
-        .args(
-            "--emit=llvm-bc -C panic=abort -C lto -C link-arg=-nostartfiles -C opt-level=3"
-                .split(" "),
-        )
+        .args("--emit=llvm-bc -C panic=abort -C lto -C link-arg=-nostartfiles".split(" "))
+        .arg(format!("-Copt-level={}", options.opt_level))
+        // llc emits .BTF and .BTF.ext from the debug info
+        .args(if options.btf {
+            &["-C", "debuginfo=2"][..]
+        } else {
+            &[]
+        })
+        .args(&options.rustc_flags)
         .arg("-o")
-        .arg(artifacts_dir.join(probe).to_str().unwrap())
-        .status()?
-        .success()
-    {
+        .arg(artifacts_dir.join(probe).to_str().unwrap());
+    if capture {
+        // build scripts only show their output when they fail, so the
+        // warnings are forwarded to cargo and the errors go in the Error
+        let output = rustc.stderr(Stdio::piped()).output()?;
+        let diagnostics = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(Error::Compile(
+                probe.to_string(),
+                Some(diagnostics.trim_end().to_string()),
+            ));
+        }
+        for line in diagnostics.lines() {
+            if line.starts_with("warning: ") {
+                let warning = line.trim_start_matches("warning: ");
+                println!("cargo:warning={}: {}", probe, warning);
+            }
+        }
+    } else if !rustc.status()?.success() {
         return Err(Error::Compile(probe.to_string(), None));
     }
 
@@ -111,10 +279,45 @@ fn build_probe(cargo: &Path, package: &Path, target_dir: &Path, probe: &str) ->
     }
 
     let bc_file = bc_files.drain(..).next().unwrap();
+    let fingerprint = fingerprint(&fs::read(&bc_file)?, options);
+    let fingerprint_file = fingerprint_path(&elf_target);
+    if elf_target.exists()
+        && fs::read_to_string(&fingerprint_file)
+            .map(|f| f == fingerprint)
+            .unwrap_or(false)
+    {
+        println!("{} is up to date", elf_target.display());
+        return Ok(None);
+    }
+    let _ = fs::remove_file(&fingerprint_file);
+
+    Ok(Some(LinkJob {
+        probe: probe.to_string(),
+        package: package.to_path_buf(),
+        bc_file,
+        elf_target,
+        fingerprint,
+    }))
+}
+
+// Processes the bitcode of a probe, optimizes it and generates its ELF file
+fn link_probe(job: &LinkJob, options: &BuildOptions) -> Result<(), Error> {
+    let llvm = llvm_version().ok_or(Error::NoLLVM)?;
+    let llc_args = ["-march=bpf", "-filetype=obj", "-o"];
+    let LinkJob {
+        probe,
+        package,
+        bc_file,
+        elf_target,
+        ..
+    } = job;
     let processed_bc_file = bc_file.with_extension("bc.proc");
     let opt_bc_file = bc_file.with_extension("bc.opt");
 
-    process_ir(&bc_file, &processed_bc_file).map_err(|msg| {
+    process_ir(bc_file, &processed_bc_file, !options.btf).map_err(|msg| {
+        if is_newer_bitcode(&msg) {
+            return Error::NewerBitcode(probe.into(), rustc_llvm_version(package));
+        }
         Error::Compile(
             probe.into(),
             Some(format!("couldn't process IR file: {}", msg)),
@@ -129,15 +332,12 @@ fn build_probe(cargo: &Path, package: &Path, target_dir: &Path, probe: &str) ->
     if !Command::new(opt)
         .args(&[
             "-march=bpf",
-            "-O3",
-            "--loop-unroll",
             &format!("--unroll-threshold={}", std::u32::MAX), // unroll at any cost
             "--unroll-max-upperbound=500", // the max loop iteration count to unroll
-            // enable upperbound unrolling when the exact trip count can't be computed
-            "--passes=always-inline,function(unroll<upperbound>)",
-            "-o",
-            opt_bc_file.to_str().unwrap(),
         ])
+        .args(opt_passes(llvm, options.opt_level))
+        .arg("-o")
+        .arg(opt_bc_file.to_str().unwrap())
         .arg(processed_bc_file.to_str().unwrap())
         .status()?
         .success()
@@ -146,53 +346,476 @@ fn build_probe(cargo: &Path, package: &Path, target_dir: &Path, probe: &str) ->
     }
     println!("IR optimised: {:?}", opt_bc_file);
 
-    let elf_target = artifacts_dir.join(format!("{}.elf", probe));
+    // llc fails on this code with errors that don't say where it comes from
+    let unsupported = unsupported_code(bc_file, &opt_bc_file).map_err(|msg| {
+        Error::Compile(
+            probe.into(),
+            Some(format!("couldn't read the optimized IR: {}", msg)),
+        )
+    })?;
+    if !unsupported.is_empty() {
+        return Err(Error::UnsupportedCode(probe.to_string(), unsupported));
+    }
+
     let llc = get_llc_executable()?;
     if !Command::new(llc)
         .args(&llc_args)
-        .arg(&elf_target)
+        .arg(elf_target)
         .arg(opt_bc_file.to_str().unwrap())
         .status()?
         .success()
     {
         return Err(Error::Link(probe.to_string()));
     }
+    if options.btf {
+        check_btf(probe, elf_target)?;
+    }
+    fs::write(fingerprint_path(elf_target), &job.fingerprint)?;
+
+    Ok(())
+}
+
+// Checks that llc emitted `.BTF`, and `.BTF.ext` records for all the
+// programs, from the debug info
+fn check_btf(probe: &str, elf_target: &Path) -> Result<(), Error> {
+    let spec = ModuleSpec::parse(&fs::read(elf_target)?)
+        .map_err(|e| Error::MissingBtf(probe.to_string(), format!("invalid BTF: {}", e)))?;
+    if !spec.has_btf() {
+        return Err(Error::MissingBtf(
+            probe.to_string(),
+            "no .BTF section".to_string(),
+        ));
+    }
+    let missing: Vec<&str> = spec
+        .programs
+        .iter()
+        .filter(|prog| !prog.has_btf_ext())
+        .map(|prog| prog.name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::MissingBtf(
+            probe.to_string(),
+            format!("no .BTF.ext records for {}", missing.join(", ")),
+        ));
+    }
 
     Ok(())
 }
 
+// Links the probes on a thread each, up to the number of CPUs at a time.
+// Returns the error of the first probe that fails.
+fn link_probes(jobs: Vec<LinkJob>, options: &BuildOptions) -> Result<(), Error> {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as usize;
+    let jobs = Arc::new(jobs);
+    let next = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<_> = (0..cpus.min(jobs.len()))
+        .map(|_| {
+            let jobs = Arc::clone(&jobs);
+            let next = Arc::clone(&next);
+            let options = options.clone();
+            thread::spawn(move || {
+                let mut results = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    match jobs.get(i) {
+                        Some(job) => results.push((i, link_probe(job, &options))),
+                        None => return results,
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut results: Vec<(usize, Result<(), Error>)> = workers
+        .into_iter()
+        .flat_map(|worker| worker.join().expect("link thread panicked"))
+        .collect();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().try_for_each(|(_, result)| result)
+}
+
+// The fingerprint of the ELF file built from `bitcode` with `options`
+fn fingerprint(bitcode: &[u8], options: &BuildOptions) -> String {
+    // FNV-1a, so that it doesn't change across rust releases
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let settings = format!(
+        "{} {:?} {} {}",
+        env!("CARGO_PKG_VERSION"),
+        llvm_version(),
+        options.opt_level,
+        options.btf
+    );
+    for byte in bitcode.iter().chain(settings.as_bytes()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn fingerprint_path(elf_target: &Path) -> PathBuf {
+    elf_target.with_extension("fingerprint")
+}
+
 pub fn build(
     cargo: &Path,
     package: &Path,
     target_dir: &Path,
-    mut probes: Vec<String>,
+    probes: Vec<String>,
 ) -> Result<(), Error> {
+    let mut options = BuildOptions::new(package);
+    options.cargo = cargo.to_path_buf();
+    options.probes = probes;
+    compile_probes(package, target_dir, &options, false).map(|_| ())
+}
+
+/// Builds the probes like `build()`, keeping the debug info so that the
+/// ELF files get `.BTF` and `.BTF.ext` sections.
+///
+/// The programs are then loaded with their source lines, which show in the
+/// verifier logs. Some versions of LLVM crash generating BTF from the debug
+/// info of rustc.
+pub fn build_with_btf(
+    cargo: &Path,
+    package: &Path,
+    target_dir: &Path,
+    probes: Vec<String>,
+) -> Result<(), Error> {
+    let mut options = BuildOptions::new(package);
+    options.cargo = cargo.to_path_buf();
+    options.probes = probes;
+    options.btf = true;
+    compile_probes(package, target_dir, &options, false).map(|_| ())
+}
+
+/// The options of `cargo bpf build`, for `build_package()` and
+/// `build_probes()`.
+///
+/// Build scripts should set `target_dir`, eg. to a directory under
+/// `OUT_DIR`: the target directory of the outer build is locked by cargo
+/// while the build script runs.
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// The cargo executable, `$CARGO` when it's set.
+    pub cargo: PathBuf,
+    /// The directory of the package or of the workspace to build.
+    pub dir: PathBuf,
+    /// The workspace member with the probes, like `-p`.
+    pub package: Option<String>,
+    /// Where the probes are built. Defaults to the target directory of the
+    /// workspace reported by `cargo metadata`, which honors
+    /// `$CARGO_TARGET_DIR`.
+    pub target_dir: Option<PathBuf>,
+    /// A directory the ELF files are copied to, as `<probe>.elf`.
+    pub out_dir: Option<PathBuf>,
+    /// The probes to build, all of them when empty, like `--program`.
+    pub probes: Vec<String>,
+    /// Keeps the debug info, see `build_with_btf()`.
+    pub btf: bool,
+    /// The optimization level of rustc and opt, from 0 to 3. Defaults to 3.
+    pub opt_level: u8,
+    /// Extra flags passed to rustc, eg. `["--cfg", "verbose"]`.
+    pub rustc_flags: Vec<String>,
+    /// The kernel source or build tree the bindings of the probes are
+    /// generated from, like `$KERNEL_SOURCE`. By default the headers are
+    /// found like in `bpf_sys::headers`.
+    pub kernel_headers: Option<PathBuf>,
+    /// The target triple the probes are built for, eg.
+    /// `aarch64-unknown-linux-gnu`. It sets `target_arch` and the headers
+    /// and clang target of the bindings. Defaults to the host.
+    pub target: Option<String>,
+}
+
+impl BuildOptions {
+    pub fn new<P: AsRef<Path>>(dir: P) -> BuildOptions {
+        BuildOptions {
+            cargo: env::var_os("CARGO")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("cargo")),
+            dir: dir.as_ref().to_path_buf(),
+            package: None,
+            target_dir: None,
+            out_dir: None,
+            probes: Vec::new(),
+            btf: false,
+            opt_level: 3,
+            rustc_flags: Vec::new(),
+            kernel_headers: None,
+            target: None,
+        }
+    }
+}
+
+/// A probe built by `build_package()` or `build_probes()`.
+#[derive(Debug, Clone)]
+pub struct BuiltProgram {
+    /// The name of the probe, the name of its binary in `Cargo.toml`.
+    pub name: String,
+    /// The path of the ELF file.
+    pub path: PathBuf,
+}
+
+/// Builds the probes of a package or of a workspace member, and returns the
+/// paths of the ELF files.
+///
+/// The package is found like cargo does: `options.package` is looked up
+/// among the members of the workspace `options.dir` belongs to. When it's
+/// not set, the package in `options.dir` is built, or the only member of
+/// the workspace that depends on `redbpf-probes` and has binaries.
+///
+/// When `options.out_dir` is set, the returned paths are the copies in it.
+pub fn build_package(options: &BuildOptions) -> Result<Vec<BuiltProgram>, Error> {
+    let (package, _, target_dir) = resolve(options)?;
+    let programs = compile_probes(&package, &target_dir, options, false)?;
+    copy_programs(programs, options)
+}
+
+/// Builds the probes from a build script, and returns their ELF files so
+/// that they can be embedded with `include_bytes!` or copied.
+///
+/// The sources of the probes, their `Cargo.toml` and the `Cargo.lock` of the
+/// package and of its workspace are printed as `cargo:rerun-if-changed`, so
+/// the probes are built again when they change. The warnings of rustc are
+/// forwarded as `cargo:warning`, and its errors are in the returned
+/// `BuildError`.
+///
+/// ```no_run
+/// use std::env;
+/// use std::path::PathBuf;
+/// use cargo_bpf_lib as cargo_bpf;
+///
+/// let mut options = cargo_bpf::BuildOptions::new("probes");
+/// // the target directory of the outer build is locked
+/// options.target_dir = Some(PathBuf::from(env::var("OUT_DIR").unwrap()).join("target"));
+/// if let Err(e) = cargo_bpf::build_probes(options) {
+///     panic!("couldn't compile probes: {}", e);
+/// }
+/// ```
+///
+/// The ELF file of the `iotop` probe can then be loaded with:
+///
+/// ```ignore
+/// include_bytes!(concat!(env!("OUT_DIR"), "/target/bpf/programs/iotop/iotop.elf"))
+/// ```
+pub fn build_probes(options: BuildOptions) -> Result<Vec<BuiltProgram>, BuildError> {
+    let (package, root, target_dir) = resolve(&options)?;
+    rerun_if_changed(&package, &root)?;
+    let programs = compile_probes(&package, &target_dir, &options, true)?;
+    copy_programs(programs, &options)
+}
+
+/// The error of `build_probes()`.
+pub type BuildError = Error;
+
+// Returns the directory of the package to build, the root of its workspace
+// and the target directory
+pub(crate) fn resolve(options: &BuildOptions) -> Result<(PathBuf, PathBuf, PathBuf), Error> {
+    let dir = env::current_dir()?.join(&options.dir);
+    let workspace = workspace(&options.cargo, &dir)?;
+    let package = find_package(&dir, &workspace, options.package.as_deref())?;
+    let target_dir = match &options.target_dir {
+        Some(target_dir) => env::current_dir()?.join(target_dir),
+        None => workspace.target_dir,
+    };
+
+    Ok((package, workspace.root, target_dir))
+}
+
+fn copy_programs(
+    programs: Vec<BuiltProgram>,
+    options: &BuildOptions,
+) -> Result<Vec<BuiltProgram>, Error> {
+    let out_dir = match &options.out_dir {
+        Some(out_dir) => env::current_dir()?.join(out_dir),
+        None => return Ok(programs),
+    };
+    fs::create_dir_all(&out_dir)?;
+    programs
+        .into_iter()
+        .map(|program| {
+            let path = out_dir.join(program.path.file_name().unwrap());
+            fs::copy(&program.path, &path)?;
+            Ok(BuiltProgram { path, ..program })
+        })
+        .collect()
+}
+
+fn rerun_if_changed(package: &Path, root: &Path) -> Result<(), Error> {
+    let mut files = vec![package.join("Cargo.toml")];
+    for dir in [package, root].iter() {
+        let lock = dir.join("Cargo.lock");
+        if lock.exists() && !files.contains(&lock) {
+            files.push(lock);
+        }
+    }
+    source_files(&package.join("src"), &mut files)?;
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+
+    Ok(())
+}
+
+fn source_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            source_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn compile_probes(
+    package: &Path,
+    target_dir: &Path,
+    options: &BuildOptions,
+    capture: bool,
+) -> Result<Vec<BuiltProgram>, Error> {
     let path = package.join("Cargo.toml");
     if !path.exists() {
         return Err(Error::MissingManifest(path.clone()));
     }
 
-    if probes.is_empty() {
+    let probes = if options.probes.is_empty() {
         let doc = load_package(package)?;
-        probes = probe_names(&doc)?
+        probe_names(&doc)?
+    } else {
+        options.probes.clone()
     };
 
-    for probe in probes {
-        build_probe(cargo, package, &target_dir, &probe)?;
+    check_kernel_headers(options)?;
+    let mut jobs = Vec::new();
+    for probe in probes.iter() {
+        if let Some(job) = compile_bitcode(package, target_dir, probe, options, capture)? {
+            jobs.push(job);
+        }
     }
+    link_probes(jobs, options)?;
 
+    Ok(probes
+        .into_iter()
+        .map(|probe| BuiltProgram {
+            path: probe_path(target_dir, &probe),
+            name: probe,
+        })
+        .collect())
+}
+
+pub fn cmd_build(options: &BuildOptions) -> Result<(), CommandError> {
+    for program in build_package(options)? {
+        println!("Built {}", program.path.display());
+    }
     Ok(())
 }
 
-pub fn cmd_build(programs: Vec<String>) -> Result<(), CommandError> {
-    let current_dir = std::env::current_dir().unwrap();
-    let ret = build(
-        Path::new("cargo"),
-        &current_dir,
-        &current_dir.join("target"),
-        programs,
-    )?;
-    Ok(ret)
+// The members of the workspace a directory is in, from `cargo metadata`
+#[derive(Debug)]
+struct Workspace {
+    root: PathBuf,
+    target_dir: PathBuf,
+    members: Vec<Member>,
+}
+
+#[derive(Debug)]
+struct Member {
+    name: String,
+    dir: PathBuf,
+    // depends on redbpf-probes and has binaries
+    probes: bool,
+}
+
+// Runs `cargo metadata` in `dir`. Path dependencies of the members are
+// members too, and the target directory honors `$CARGO_TARGET_DIR` and the
+// cargo configuration.
+fn workspace(cargo: &Path, dir: &Path) -> Result<Workspace, Error> {
+    let output = Command::new(cargo)
+        .args(&["metadata", "--no-deps", "--format-version", "1"])
+        .current_dir(dir)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Metadata(stderr.trim().to_string()));
+    }
+
+    parse_metadata(&output.stdout)
+}
+
+fn parse_metadata(json: &[u8]) -> Result<Workspace, Error> {
+    let invalid = |what: &str| Error::Metadata(format!("invalid output, {}", what));
+    let metadata: serde_json::Value =
+        serde_json::from_slice(json).map_err(|e| Error::Metadata(e.to_string()))?;
+    let path = |key: &str| {
+        metadata[key]
+            .as_str()
+            .map(PathBuf::from)
+            .ok_or_else(|| invalid(key))
+    };
+    let root = path("workspace_root")?;
+    let target_dir = path("target_directory")?;
+    let packages = metadata["packages"]
+        .as_array()
+        .ok_or_else(|| invalid("packages"))?;
+    let mut members = Vec::new();
+    for package in packages {
+        let name = package["name"].as_str().ok_or_else(|| invalid("name"))?;
+        let dir = package["manifest_path"]
+            .as_str()
+            .and_then(|path| Path::new(path).parent())
+            .ok_or_else(|| invalid("manifest_path"))?;
+        let redbpf_probes = package["dependencies"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|dep| dep["name"] == "redbpf-probes" && dep["kind"].is_null());
+        let bins = package["targets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|target| target["kind"].as_array())
+            .any(|kinds| kinds.iter().any(|kind| kind == "bin"));
+        members.push(Member {
+            name: name.to_string(),
+            dir: dir.to_path_buf(),
+            probes: redbpf_probes && bins,
+        });
+    }
+
+    Ok(Workspace {
+        root,
+        target_dir,
+        members,
+    })
+}
+
+// Returns the directory of the member named `name`. Without a name, it's
+// the member in `dir`, or the only member with probes.
+fn find_package(dir: &Path, workspace: &Workspace, name: Option<&str>) -> Result<PathBuf, Error> {
+    let members = &workspace.members;
+    let member = match name {
+        Some(name) => members
+            .iter()
+            .find(|member| member.name == name)
+            .ok_or_else(|| Error::UnknownPackage(name.to_string()))?,
+        None => {
+            let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+            let in_dir = members.iter().find(|member| {
+                fs::canonicalize(&member.dir).unwrap_or_else(|_| member.dir.clone()) == dir
+            });
+            let mut probes = members.iter().filter(|member| member.probes);
+            match (in_dir, probes.next(), probes.next()) {
+                (Some(member), _, _) | (None, Some(member), None) => member,
+                _ => return Err(Error::SelectPackage(workspace.root.clone())),
+            }
+        }
+    };
+
+    Ok(member.dir.clone())
 }
 
 pub fn probe_files(package: &Path) -> Result<Vec<String>, Error> {
@@ -211,7 +834,7 @@ pub fn probe_files(package: &Path) -> Result<Vec<String>, Error> {
         .collect())
 }
 
-fn load_package(package: &Path) -> Result<Document, Error> {
+pub(crate) fn load_package(package: &Path) -> Result<Document, Error> {
     let path = package.join("Cargo.toml");
     if !path.exists() {
         return Err(Error::MissingManifest(path.clone()));
@@ -231,36 +854,355 @@ fn probe_names(doc: &Document) -> Result<Vec<String>, Error> {
     }
 }
 
-fn get_opt_executable() -> Result<String, Error> {
-    for llc in vec!["opt".into(), env::var("OPT").unwrap_or("opt-9".into())].drain(..) {
-        if let Ok(out) = Command::new(&llc).arg("--version").output() {
-            match String::from_utf8(out.stdout) {
-                Ok(out) => {
-                    if out.contains("LLVM version 9.") {
-                        return Ok(llc);
-                    }
-                }
-                Err(_) => continue,
-            }
+// Returns the redbpf-probes feature enabling the helpers of the kernel the
+// headers are for, so that probes can use them without listing the feature
+fn kernel_feature(package: &Path, options: &BuildOptions) -> Option<String> {
+    let doc = load_package(package).ok()?;
+    if doc["dependencies"]["redbpf-probes"].is_none() {
+        return None;
+    }
+    let version = match &options.kernel_headers {
+        Some(dir) => kernel_headers_version_at(dir)?,
+        None => kernel_headers_version()?,
+    };
+
+    bindgen::kernel_feature(version)
+}
+
+// The `ARCH` of the kernel build for `target_arch`
+fn kernel_arch(target_arch: &str) -> Option<&'static str> {
+    match target_arch {
+        "x86_64" => Some("x86"),
+        "aarch64" => Some("arm64"),
+        _ => None,
+    }
+}
+
+// Checks that the kernel tree the probes are built with, if one is given,
+// has the files generated by `make prepare` for the target
+fn check_kernel_headers(options: &BuildOptions) -> Result<(), Error> {
+    let arch = match &options.target {
+        Some(target) => target.split('-').next().unwrap().to_string(),
+        None => env::consts::ARCH.to_string(),
+    };
+    let karch = match kernel_arch(&arch) {
+        Some(karch) => karch,
+        None => return Err(Error::UnsupportedTarget(arch)),
+    };
+    let dir = match options
+        .kernel_headers
+        .clone()
+        .or_else(|| env::var_os("KERNEL_SOURCE").map(PathBuf::from))
+    {
+        Some(dir) => dir,
+        // the headers of the host are only good for the host
+        None if arch != env::consts::ARCH => {
+            return Err(Error::CrossKernelHeaders(options.target.clone().unwrap()))
         }
+        None => return Ok(()),
+    };
+    if !dir.is_dir() {
+        return Err(Error::KernelHeaders(dir, arch, Vec::new()));
+    }
+    let missing: Vec<String> = [
+        "include/generated/autoconf.h".to_string(),
+        "include/generated/uapi/linux/version.h".to_string(),
+        format!("arch/{}/include/generated", karch),
+    ]
+    .iter()
+    .filter(|file| !dir.join(file).exists())
+    .cloned()
+    .collect();
+    if !missing.is_empty() {
+        return Err(Error::KernelHeaders(dir, arch, missing));
     }
 
-    return Err(Error::NoOPT);
+    Ok(())
+}
+
+/// Returns the major version of the LLVM cargo-bpf is built with, selected
+/// with the llvm-* features.
+pub fn llvm_version() -> Option<u32> {
+    option_env!("CARGO_BPF_LLVM_VERSION").and_then(|v| v.parse().ok())
+}
+
+fn llvm_version_name() -> String {
+    match llvm_version() {
+        Some(version) => version.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+// Returns the version of the LLVM of the rustc building `package`
+fn rustc_llvm_version(package: &Path) -> Option<String> {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let out = Command::new(rustc)
+        .current_dir(package)
+        .arg("-vV")
+        .output()
+        .ok()?;
+    String::from_utf8(out.stdout)
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("LLVM version: "))
+        .map(|line| line.trim_start_matches("LLVM version: ").to_string())
+}
+
+// Returns the optimization passes of opt. Since LLVM 13 the -O flags can't
+// be combined with --passes, and the unroll pass is named loop-unroll since
+// LLVM 11.
+fn opt_passes(llvm: u32, opt_level: u8) -> Vec<String> {
+    // enable upperbound unrolling when the exact trip count can't be computed
+    match llvm {
+        9 | 10 => vec![
+            format!("-O{}", opt_level),
+            "--loop-unroll".to_string(),
+            "--passes=always-inline,function(unroll<upperbound>)".to_string(),
+        ],
+        11 | 12 => vec![
+            format!("-O{}", opt_level),
+            "--loop-unroll".to_string(),
+            "--passes=always-inline,function(loop-unroll<upperbound>)".to_string(),
+        ],
+        _ => vec![format!(
+            "--passes=default<O{}>,always-inline,function(loop-unroll<upperbound>)",
+            opt_level
+        )],
+    }
+}
+
+fn get_opt_executable() -> Result<String, Error> {
+    find_llvm_tool("opt", "OPT").ok_or(Error::NoOPT)
 }
 
 fn get_llc_executable() -> Result<String, Error> {
-    for llc in vec!["llc".into(), env::var("LLC").unwrap_or("llc-9".into())].drain(..) {
-        if let Ok(out) = Command::new(&llc).arg("--version").output() {
-            match String::from_utf8(out.stdout) {
-                Ok(out) => {
-                    if out.contains("LLVM version 9.") {
-                        return Ok(llc);
-                    }
-                }
-                Err(_) => continue,
+    find_llvm_tool("llc", "LLC").ok_or(Error::NoLLC)
+}
+
+// Looks for the LLVM tool matching the LLVM cargo-bpf is built with, eg.
+// `opt`, `$OPT` or `opt-14`
+fn find_llvm_tool(tool: &str, var: &str) -> Option<String> {
+    let llvm = llvm_version()?;
+    let candidates = vec![
+        tool.to_string(),
+        env::var(var).unwrap_or_else(|_| format!("{}-{}", tool, llvm)),
+    ];
+    candidates.into_iter().find(|candidate| {
+        Command::new(candidate)
+            .arg("--version")
+            .output()
+            .ok()
+            .and_then(|out| String::from_utf8(out.stdout).ok())
+            .map(|out| out.contains(&format!("LLVM version {}.", llvm)))
+            .unwrap_or(false)
+    })
+}
+
+// cargo-bpf is used without LLVM by the build scripts that only need bindgen
+#[cfg(not(llvm))]
+fn process_ir(_input: &Path, _output: &Path, _strip_debug_info: bool) -> Result<(), String> {
+    Err(Error::NoLLVM.to_string())
+}
+
+#[cfg(not(llvm))]
+fn unsupported_code(_original: &Path, _optimized: &Path) -> Result<Vec<String>, String> {
+    Err(Error::NoLLVM.to_string())
+}
+
+#[cfg(not(llvm))]
+fn is_newer_bitcode(_message: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_package, opt_passes, parse_metadata, workspace, Error, Member, Workspace};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn opt_passes_of_llvm_versions() {
+        assert_eq!(
+            opt_passes(9, 3),
+            vec![
+                "-O3",
+                "--loop-unroll",
+                "--passes=always-inline,function(unroll<upperbound>)"
+            ]
+        );
+        assert_eq!(
+            opt_passes(12, 2),
+            vec![
+                "-O2",
+                "--loop-unroll",
+                "--passes=always-inline,function(loop-unroll<upperbound>)"
+            ]
+        );
+        for llvm in [13, 14].iter() {
+            assert_eq!(
+                opt_passes(*llvm, 3),
+                vec!["--passes=default<O3>,always-inline,function(loop-unroll<upperbound>)"]
+            );
+        }
+    }
+
+    const METADATA: &str = r#"{
+        "packages": [
+            {
+                "name": "probes",
+                "manifest_path": "/ws/probes/Cargo.toml",
+                "dependencies": [
+                    {"name": "cty", "kind": null},
+                    {"name": "redbpf-probes", "kind": null}
+                ],
+                "targets": [
+                    {"name": "probes", "kind": ["lib"]},
+                    {"name": "iotop", "kind": ["bin"]}
+                ]
+            },
+            {
+                "name": "app",
+                "manifest_path": "/ws/app/Cargo.toml",
+                "dependencies": [{"name": "redbpf-probes", "kind": "dev"}],
+                "targets": [{"name": "app", "kind": ["bin"]}]
+            },
+            {
+                "name": "helpers",
+                "manifest_path": "/ws/helpers/Cargo.toml",
+                "dependencies": [{"name": "redbpf-probes", "kind": null}],
+                "targets": [{"name": "helpers", "kind": ["lib"]}]
             }
+        ],
+        "workspace_root": "/ws",
+        "target_directory": "/ws/target"
+    }"#;
+
+    fn member(name: &str, probes: bool) -> Member {
+        Member {
+            name: name.to_string(),
+            dir: PathBuf::from(format!("/ws/{}", name)),
+            probes,
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let workspace = parse_metadata(METADATA.as_bytes()).unwrap();
+        assert_eq!(workspace.root, Path::new("/ws"));
+        assert_eq!(workspace.target_dir, Path::new("/ws/target"));
+        let members: Vec<_> = workspace
+            .members
+            .iter()
+            .map(|m| (m.name.as_str(), m.dir.to_str().unwrap(), m.probes))
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                ("probes", "/ws/probes", true),
+                ("app", "/ws/app", false),
+                ("helpers", "/ws/helpers", false)
+            ]
+        );
+
+        match parse_metadata(b"{\"packages\": []}") {
+            Err(Error::Metadata(e)) => assert!(e.contains("workspace_root"), "{}", e),
+            r => panic!("unexpected {:?}", r),
         }
+        assert!(matches!(parse_metadata(b"error"), Err(Error::Metadata(_))));
+    }
+
+    #[test]
+    fn select_package() {
+        let mut workspace = Workspace {
+            root: PathBuf::from("/ws"),
+            target_dir: PathBuf::from("/ws/target"),
+            members: vec![member("probes", true), member("app", false)],
+        };
+        let root = Path::new("/ws");
+        let app = Path::new("/ws/app");
+        // the package in the directory, then the only one with probes
+        assert_eq!(find_package(app, &workspace, None).unwrap(), app);
+        assert_eq!(
+            find_package(root, &workspace, None).unwrap(),
+            Path::new("/ws/probes")
+        );
+        assert_eq!(find_package(root, &workspace, Some("app")).unwrap(), app);
+        assert!(matches!(
+            find_package(root, &workspace, Some("nope")),
+            Err(Error::UnknownPackage(p)) if p == "nope"
+        ));
+
+        workspace.members.push(member("more-probes", true));
+        assert!(matches!(
+            find_package(root, &workspace, None),
+            Err(Error::SelectPackage(p)) if p == root
+        ));
+        assert_eq!(find_package(app, &workspace, None).unwrap(), app);
     }
 
-    return Err(Error::NoLLC);
+    #[test]
+    fn cargo_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let write = |path: &str, data: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        };
+        write("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n");
+        write(
+            "crates/probes/Cargo.toml",
+            r#"[package]
+name = "probes"
+version = "0.1.0"
+
+[dependencies]
+redbpf-probes = "0.9"
+shared = { path = "../../shared" }
+
+[[bin]]
+name = "iotop"
+path = "src/iotop/main.rs"
+"#,
+        );
+        write("crates/probes/src/lib.rs", "");
+        write(
+            "shared/Cargo.toml",
+            "[package]\nname = \"shared\"\nversion = \"0.1.0\"\n",
+        );
+        write("shared/src/lib.rs", "");
+
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let workspace = workspace(Path::new(&cargo), &root.join("shared")).unwrap();
+        assert_eq!(workspace.root, root);
+        let mut members: Vec<_> = workspace
+            .members
+            .iter()
+            .map(|m| {
+                (
+                    m.name.as_str(),
+                    m.dir.strip_prefix(&root).unwrap(),
+                    m.probes,
+                )
+            })
+            .collect();
+        members.sort();
+        assert_eq!(
+            members,
+            vec![
+                ("probes", Path::new("crates/probes"), true),
+                ("shared", Path::new("shared"), false)
+            ]
+        );
+        assert_eq!(
+            find_package(&root, &workspace, None).unwrap(),
+            root.join("crates/probes")
+        );
+
+        fs::remove_file(root.join("Cargo.toml")).unwrap();
+        assert!(matches!(
+            workspace(Path::new(&cargo), &root),
+            Err(Error::Metadata(_))
+        ));
+    }
 }
@@ -0,0 +1,111 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::CommandError;
+
+use redbpf::spec::ModuleSpec;
+use redbpf::uname::kernel_version_code;
+use redbpf::{sys, Error, Module};
+use std::fs;
+use std::path::PathBuf;
+
+// The number of lines of the verifier log printed for rejected programs,
+// where the verifier explains why
+const LOG_EXCERPT_LINES: usize = 20;
+
+/// Runs the programs of the ELF files in `paths` through the verifier of the
+/// running kernel, without attaching them, and prints whether each of them
+/// passed.
+///
+/// The programs are loaded with `kernel_version`, eg. `5.4.0`, instead of
+/// the version of their ELF file when it's set. Fails if any program is
+/// rejected, after all of them are checked.
+pub fn check(paths: &[PathBuf], kernel_version: Option<&str>) -> Result<(), CommandError> {
+    let version = match kernel_version {
+        Some(version) => Some(parse_kernel_version(version).ok_or_else(|| {
+            CommandError::Message(format!("invalid kernel version `{}'", version))
+        })?),
+        None => None,
+    };
+    // without CAP_SYS_RESOURCE the maps may still fit in the limit
+    let _ = sys::bump_memlock_rlimit();
+
+    let mut checked = 0;
+    let mut failed = 0;
+    for path in paths {
+        println!("{}", path.display());
+        let module = fs::read(path)
+            .map_err(Error::from)
+            .and_then(|data| ModuleSpec::parse(&data))
+            .and_then(|mut spec| {
+                if let Some(version) = version {
+                    spec.version = version;
+                }
+                Module::from_spec(&spec)
+            });
+        let mut module = match module {
+            Ok(module) => module,
+            Err(e) => {
+                println!("  FAIL  {}", e);
+                checked += 1;
+                failed += 1;
+                continue;
+            }
+        };
+
+        let results = module.verify(0);
+        for (prog, result) in module.programs.iter().zip(results) {
+            let kind = format!("{:?}", prog.kind);
+            checked += 1;
+            match result {
+                Ok(_) => println!("  PASS  {:<32} {:<14}", prog.name, kind),
+                Err(e) => {
+                    failed += 1;
+                    let (error, log) = match &e {
+                        Error::ProgramLoad(e) => (e.error.to_string(), e.verifier_log()),
+                        e => (e.to_string(), ""),
+                    };
+                    println!("  FAIL  {:<32} {:<14} {}", prog.name, kind, error);
+                    print_log_excerpt(log);
+                }
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(CommandError::Message(format!(
+            "{} of {} programs failed verification",
+            failed, checked
+        )));
+    }
+
+    Ok(())
+}
+
+// Parses `major.minor[.patch]` into a version code like `KERNEL_VERSION()`
+fn parse_kernel_version(version: &str) -> Option<u32> {
+    let parts = version
+        .split('.')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [major, minor] => Some(kernel_version_code(major, minor, 0)),
+        [major, minor, patch] => Some(kernel_version_code(major, minor, patch)),
+        _ => None,
+    }
+}
+
+fn print_log_excerpt(log: &str) {
+    let lines: Vec<&str> = log.lines().collect();
+    let start = lines.len().saturating_sub(LOG_EXCERPT_LINES);
+    if start > 0 {
+        println!("        ... {} lines of the verifier log omitted", start);
+    }
+    for line in &lines[start..] {
+        println!("        {}", line);
+    }
+}
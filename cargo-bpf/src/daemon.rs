@@ -0,0 +1,258 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::load::{load_programs, output, write_events, EventFormat, LoadOptions};
+use crate::CommandError;
+
+use futures::future;
+use futures::stream::StreamExt;
+use redbpf::load::{Loaded, MapEvent};
+use redbpf::{xdp, Module, ProgramKind};
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::Runtime;
+use tokio::signal;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Loads and attaches the programs of the ELF file at `program` like
+/// `load()`, and keeps them running until interrupted or terminated.
+///
+/// The maps and the BPF links of the programs are pinned in the `maps` and
+/// `links` directories of `options.pin_dir`, and unpinned on exit. The
+/// events are sent as JSON lines to the clients of `options.socket` if set,
+/// and written like `load()` does otherwise.
+///
+/// On SIGHUP the ELF file is loaded again and its programs replace the
+/// running ones. The new programs are attached before the old ones are
+/// detached, and XDP programs are swapped atomically on the interface. The
+/// new programs reuse the pinned maps of the old ones named like theirs, so
+/// the state in the maps is kept. The old programs keep running if the file
+/// fails to load.
+pub fn daemon(program: &Path, options: &LoadOptions) -> Result<(), CommandError> {
+    let mut sink = Sink {
+        out: output(options)?,
+        clients: options.socket.as_ref().map(|_| Vec::new()),
+        options: options.clone(),
+        json: LoadOptions {
+            format: EventFormat::Json,
+            ..options.clone()
+        },
+    };
+    let mut pins = Pins {
+        dir: options.pin_dir.clone(),
+        paths: Vec::new(),
+    };
+    let mut runtime = Runtime::new()?;
+    runtime.block_on(async {
+        let mut loaded = load_programs(program, options, true, None).await?;
+        pins.pin(&loaded.module)?;
+        let mut listener = match &options.socket {
+            Some(path) => Some(bind(path)?),
+            None => None,
+        };
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        loop {
+            tokio::select! {
+                Some((name, events)) = loaded.events.next() => sink.send(&name, events).await?,
+                client = accept(&mut listener) => match client {
+                    Ok(client) => sink.add_client(client),
+                    Err(e) => eprintln!("error accepting a client: {}", e),
+                },
+                _ = hangup.recv() => match reload(program, options, &mut loaded).await {
+                    Ok(new) => {
+                        let old = mem::replace(&mut loaded, new);
+                        for (name, events) in old.close().await? {
+                            sink.send(&name, events).await?;
+                        }
+                        pins.pin(&loaded.module)?;
+                        println!("reloaded {}", program.display());
+                    }
+                    Err(e) => eprintln!("error reloading {}: {}", program.display(), e),
+                },
+                _ = terminate.recv() => break,
+                _ = signal::ctrl_c() => break,
+            }
+        }
+        for (name, events) in loaded.close().await? {
+            sink.send(&name, events).await?;
+        }
+        sink.out.flush()?;
+        Ok::<_, CommandError>(())
+    })?;
+    pins.unpin();
+    if let Some(path) = &options.socket {
+        let _ = fs::remove_file(path);
+    }
+
+    println!("exiting");
+
+    Ok(())
+}
+
+// Loads the programs of `program` again, to replace the ones of `old`. The
+// XDP programs and classifiers of `old` replaced on the interface are left
+// attached when `old` is closed, so that closing it doesn't detach the new
+// ones. An interface has a single XDP program, so once a new one is attached
+// none of the XDP programs of `old` is detached.
+async fn reload(
+    program: &Path,
+    options: &LoadOptions,
+    old: &mut Loaded,
+) -> Result<Loaded, CommandError> {
+    let pinned_maps = options.pin_dir.as_ref().map(|dir| dir.join("maps"));
+    let mut new = load_programs(program, options, false, pinned_maps.as_deref()).await?;
+    let mut xdp_attached = false;
+    if let Some(interface) = &options.interface {
+        for prog in new.module.programs.iter_mut() {
+            if prog.kind() != ProgramKind::XDP {
+                continue;
+            }
+            let replaced = old
+                .module
+                .programs
+                .iter_mut()
+                .find(|p| p.kind() == ProgramKind::XDP && p.name() == prog.name());
+            let ret = match replaced {
+                Some(replaced) => prog.replace_xdp(interface, replaced),
+                None => prog.attach_xdp(interface, xdp::Flags::default()),
+            };
+            match ret {
+                Ok(()) => xdp_attached = true,
+                Err(e) => eprintln!("error attaching `{}' to {}: {}", prog.name(), interface, e),
+            }
+        }
+    }
+    for prog in old.module.programs.iter_mut() {
+        // XDP programs attached through netlink have no file descriptor,
+        // detaching one would detach the new program
+        if prog.kind() == ProgramKind::XDP && xdp_attached {
+            for link in prog.take_links() {
+                if link.fd().is_none() {
+                    link.forget();
+                }
+            }
+            continue;
+        }
+        let replaced = prog.kind() == ProgramKind::Classifier
+            && new
+                .module
+                .programs
+                .iter()
+                .any(|p| p.name() == prog.name() && !p.links().is_empty());
+        if replaced {
+            for link in prog.take_links() {
+                link.forget();
+            }
+        }
+    }
+
+    Ok(new)
+}
+
+// Removes the socket left by a previous run, if any
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    UnixListener::bind(path)
+}
+
+async fn accept(listener: &mut Option<UnixListener>) -> io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(client, _)| client),
+        None => future::pending().await,
+    }
+}
+
+// Where the events go: the clients of the socket if there is one, `out`
+// otherwise
+struct Sink {
+    out: Box<dyn Write>,
+    clients: Option<Vec<UnixStream>>,
+    options: LoadOptions,
+    // `options` with the JSON format, for the clients
+    json: LoadOptions,
+}
+
+impl Sink {
+    fn add_client(&mut self, client: UnixStream) {
+        if let Some(clients) = &mut self.clients {
+            clients.push(client);
+        }
+    }
+
+    // Clients that can't be written to are dropped
+    async fn send(&mut self, name: &str, events: Vec<MapEvent>) -> io::Result<()> {
+        let clients = match &mut self.clients {
+            Some(clients) => clients,
+            None => return write_events(&mut self.out, name, events, &self.options),
+        };
+        let mut lines = Vec::new();
+        write_events(&mut lines, name, events, &self.json)?;
+        let mut connected = Vec::with_capacity(clients.len());
+        for mut client in clients.drain(..) {
+            if client.write_all(&lines).await.is_ok() {
+                connected.push(client);
+            }
+        }
+        *clients = connected;
+
+        Ok(())
+    }
+}
+
+// The files pinned in the pin directory
+struct Pins {
+    dir: Option<PathBuf>,
+    paths: Vec<PathBuf>,
+}
+
+impl Pins {
+    // Replaces the pins with the maps and links of `module`, and the ones
+    // left by a previous run
+    fn pin(&mut self, module: &Module) -> Result<(), CommandError> {
+        self.unpin();
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(()),
+        };
+        fs::create_dir_all(dir.join("maps"))?;
+        fs::create_dir_all(dir.join("links"))?;
+        for map in module.maps.iter() {
+            let path = dir.join("maps").join(&map.name);
+            let _ = fs::remove_file(&path);
+            map.pin(&path)?;
+            self.paths.push(path);
+        }
+        for prog in module.programs.iter() {
+            if !prog.links().iter().any(|l| l.is_bpf_link()) {
+                continue;
+            }
+            let path = dir.join("links").join(prog.name());
+            let _ = fs::remove_file(&path);
+            prog.pin_link(&path)?;
+            self.paths.push(path);
+        }
+
+        Ok(())
+    }
+
+    fn unpin(&mut self) {
+        for path in self.paths.drain(..) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::programs::json_string;
+
+use std::cmp;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// The layout of the events of a map, to decode them to JSON.
+///
+/// Layouts are parsed from a list of `name:type` fields separated by commas,
+/// eg. `pid:u32,comm:str[16],ts:u64`. The fields are laid out like in a
+/// `#[repr(C)]` struct. The types are the integers `u8` to `u64` and `i8`
+/// to `i64`, arrays like `[u32; 4]`, and `str[N]` for NUL-terminated strings
+/// of `N` bytes like `[u8; N]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventLayout {
+    fields: Vec<Field>,
+    size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    name: String,
+    ty: FieldType,
+    offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FieldType {
+    Int { size: usize, signed: bool },
+    Array(Box<FieldType>, usize),
+    Str(usize),
+}
+
+impl FieldType {
+    fn parse(ty: &str) -> Option<FieldType> {
+        let ty = ty.trim();
+        let int = |size, signed| Some(FieldType::Int { size, signed });
+        match ty {
+            "u8" => return int(1, false),
+            "u16" => return int(2, false),
+            "u32" => return int(4, false),
+            "u64" => return int(8, false),
+            "i8" => return int(1, true),
+            "i16" => return int(2, true),
+            "i32" => return int(4, true),
+            "i64" => return int(8, true),
+            _ => {}
+        }
+        if ty.starts_with("str[") && ty.ends_with(']') {
+            let len = parse_len(&ty[4..ty.len() - 1])?;
+            return Some(FieldType::Str(len));
+        }
+        if ty.starts_with('[') && ty.ends_with(']') {
+            let inner = &ty[1..ty.len() - 1];
+            let split = inner.rfind(';')?;
+            let len = parse_len(&inner[split + 1..])?;
+            let elem = FieldType::parse(&inner[..split])?;
+            return Some(FieldType::Array(Box::new(elem), len));
+        }
+
+        None
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            FieldType::Int { size, .. } => *size,
+            FieldType::Array(elem, len) => elem.size() * len,
+            FieldType::Str(len) => *len,
+        }
+    }
+
+    fn align(&self) -> usize {
+        match self {
+            FieldType::Int { size, .. } => *size,
+            FieldType::Array(elem, _) => elem.align(),
+            FieldType::Str(_) => 1,
+        }
+    }
+
+    fn write_json(&self, out: &mut String, data: &[u8]) {
+        match self {
+            FieldType::Int { size, signed } => {
+                let value = match size {
+                    1 => data[0] as u64,
+                    2 => u16::from_ne_bytes(data.try_into().unwrap()) as u64,
+                    4 => u32::from_ne_bytes(data.try_into().unwrap()) as u64,
+                    _ => u64::from_ne_bytes(data.try_into().unwrap()),
+                };
+                if *signed {
+                    // sign extend from the width of the field
+                    let shift = 64 - size * 8;
+                    write!(out, "{}", ((value << shift) as i64) >> shift).unwrap();
+                } else {
+                    write!(out, "{}", value).unwrap();
+                }
+            }
+            FieldType::Array(elem, _) => {
+                out.push('[');
+                for (i, data) in data.chunks(elem.size()).enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    elem.write_json(out, data);
+                }
+                out.push(']');
+            }
+            FieldType::Str(_) => {
+                let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+                out.push_str(&json_string(&String::from_utf8_lossy(&data[..len])));
+            }
+        }
+    }
+}
+
+impl FromStr for EventLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventLayout, String> {
+        let mut fields = Vec::new();
+        let mut offset = 0;
+        let mut align = 1;
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let split = field
+                .find(':')
+                .ok_or_else(|| format!("field `{}' has no type, expected `name:type'", field))?;
+            let name = field[..split].trim().to_string();
+            let ty = FieldType::parse(&field[split + 1..])
+                .ok_or_else(|| format!("unknown type of field `{}'", field))?;
+            if name.is_empty() {
+                return Err(format!("field `{}' has no name", field));
+            }
+            offset = round_up(offset, ty.align());
+            align = cmp::max(align, ty.align());
+            let size = ty.size();
+            fields.push(Field { name, ty, offset });
+            offset += size;
+        }
+        if fields.is_empty() {
+            return Err("the layout has no fields".to_string());
+        }
+
+        Ok(EventLayout {
+            fields,
+            size: round_up(offset, align),
+        })
+    }
+}
+
+impl EventLayout {
+    /// Returns the size of the struct described by the layout.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Decodes an event to a JSON object with a member per field. The fields
+    /// that don't fit in `data` are `null`.
+    pub fn to_json(&self, data: &[u8]) -> String {
+        let mut out = String::from("{");
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(out, "{}:", json_string(&field.name)).unwrap();
+            let end = field.offset + field.ty.size();
+            match data.get(field.offset..end) {
+                Some(data) => field.ty.write_json(&mut out, data),
+                None => out.push_str("null"),
+            }
+        }
+        out.push('}');
+
+        out
+    }
+}
+
+fn parse_len(len: &str) -> Option<usize> {
+    len.trim().parse().ok().filter(|len| *len > 0)
+}
+
+fn round_up(offset: usize, align: usize) -> usize {
+    offset + (align - offset % align) % align
+}
+
+#[cfg(test)]
+mod test {
+    use super::EventLayout;
+
+    #[test]
+    fn layout_to_json() {
+        let layout: EventLayout = "pid:u32, ts:u64, comm:str[4], delta:i16, c:[u8; 2]"
+            .parse()
+            .unwrap();
+        assert_eq!(layout.size(), 24);
+
+        let mut data = vec![0u8; 24];
+        data[..4].copy_from_slice(&42u32.to_ne_bytes());
+        data[8..16].copy_from_slice(&7u64.to_ne_bytes());
+        data[16..19].copy_from_slice(b"top");
+        data[20..22].copy_from_slice(&(-3i16).to_ne_bytes());
+        data[22..24].copy_from_slice(&[1, 2]);
+        assert_eq!(
+            layout.to_json(&data),
+            r#"{"pid":42,"ts":7,"comm":"top","delta":-3,"c":[1,2]}"#
+        );
+        assert_eq!(
+            layout.to_json(&data[..12]),
+            r#"{"pid":42,"ts":null,"comm":null,"delta":null,"c":null}"#
+        );
+
+        assert!("pid".parse::<EventLayout>().is_err());
+        assert!("pid:u128".parse::<EventLayout>().is_err());
+    }
+}
@@ -8,26 +8,117 @@
 mod accessors;
 pub mod bindgen;
 mod build;
+#[cfg(feature = "command-line")]
+mod check;
+#[cfg(llvm)]
 mod llvm;
 #[cfg(feature = "command-line")]
+mod daemon;
+#[cfg(feature = "command-line")]
+mod layout;
+#[cfg(feature = "command-line")]
 mod load;
 #[cfg(feature = "command-line")]
 mod new;
 #[cfg(feature = "command-line")]
 mod new_program;
+#[cfg(feature = "command-line")]
+mod programs;
+mod shared_types;
+#[cfg(feature = "command-line")]
+mod templates;
+
+use std::fmt;
+use std::io;
+
+// the llvm-* feature picked by build.rs
+#[cfg(llvm_conflict)]
+compile_error!(concat!(
+    "only one of the llvm-* features of cargo-bpf can be enabled, found: ",
+    env!("CARGO_BPF_LLVM_FEATURES")
+));
+#[cfg(llvm = "10")]
+extern crate llvm_sys_100 as llvm_sys;
+#[cfg(llvm = "11")]
+extern crate llvm_sys_110 as llvm_sys;
+#[cfg(llvm = "12")]
+extern crate llvm_sys_120 as llvm_sys;
+#[cfg(llvm = "13")]
+extern crate llvm_sys_130 as llvm_sys;
+#[cfg(llvm = "14")]
+extern crate llvm_sys_140 as llvm_sys;
+#[cfg(llvm = "9")]
+extern crate llvm_sys_90 as llvm_sys;
+
+/// The error returned by the commands.
+///
+/// Errors of `redbpf` are kept as they are, so their source can be inspected.
+#[derive(Debug)]
+pub enum CommandError {
+    Message(String),
+    IO(io::Error),
+    Build(build::Error),
+    Bpf(redbpf::Error),
+    #[cfg(feature = "command-line")]
+    Load(redbpf::load::LoaderError),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Message(msg) => write!(f, "{}", msg),
+            CommandError::IO(e) => write!(f, "{}", e),
+            CommandError::Build(e) => write!(f, "{}", e),
+            CommandError::Bpf(e) => write!(f, "{}", e),
+            #[cfg(feature = "command-line")]
+            CommandError::Load(e) => write!(f, "{}", e),
+        }
+    }
+}
 
-pub struct CommandError(pub String);
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Message(_) => None,
+            CommandError::IO(e) => Some(e),
+            CommandError::Build(e) => Some(e),
+            CommandError::Bpf(e) => Some(e),
+            #[cfg(feature = "command-line")]
+            CommandError::Load(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> CommandError {
+        CommandError::IO(e)
+    }
+}
+
+impl From<redbpf::Error> for CommandError {
+    fn from(e: redbpf::Error) -> CommandError {
+        CommandError::Bpf(e)
+    }
+}
 
-impl std::convert::From<std::io::Error> for CommandError {
-    fn from(e: std::io::Error) -> CommandError {
-        CommandError(format!("{}", e))
+#[cfg(feature = "command-line")]
+impl From<redbpf::load::LoaderError> for CommandError {
+    fn from(e: redbpf::load::LoaderError) -> CommandError {
+        CommandError::Load(e)
     }
 }
 
 pub use build::*;
 #[cfg(feature = "command-line")]
-pub use load::load;
+pub use check::check;
+#[cfg(feature = "command-line")]
+pub use layout::EventLayout;
+#[cfg(feature = "command-line")]
+pub use load::{load, EventFormat, LoadOptions};
 #[cfg(feature = "command-line")]
 pub use new::new;
 #[cfg(feature = "command-line")]
-pub use new_program::new_program;
+pub use new_program::{new_program, program_templates};
+#[cfg(feature = "command-line")]
+pub use programs::{disasm, inspect, programs};
+pub use shared_types::{cmd_share_types, shared_types};
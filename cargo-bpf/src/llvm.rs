@@ -1,31 +1,44 @@
 use llvm_sys::analysis::{LLVMVerifierFailureAction::*, LLVMVerifyModule};
 use llvm_sys::core::*;
 use llvm_sys::debuginfo::LLVMStripModuleDebugInfo;
+use llvm_sys::disassembler::*;
 use llvm_sys::initialization::*;
 use llvm_sys::ir_reader::LLVMParseIRInContext;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
-use llvm_sys::{LLVMAttributeFunctionIndex, LLVMInlineAsmDialect::*};
+use llvm_sys::{LLVMAttributeFunctionIndex, LLVMInlineAsmDialect::*, LLVMOpcode, LLVMTypeKind};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::{CStr, CString};
+use std::fmt;
 use std::os::raw::c_char;
 use std::path::Path;
 use std::ptr;
+use std::sync::Once;
 
-unsafe fn init_context() -> LLVMContextRef {
-    let context = LLVMGetGlobalContext();
+static INIT: Once = Once::new();
 
-    LLVM_InitializeAllTargets();
-    LLVM_InitializeAllTargetMCs();
-    LLVM_InitializeAllAsmPrinters();
-    LLVM_InitializeAllAsmParsers();
+unsafe fn init() {
+    INIT.call_once(|| {
+        LLVM_InitializeAllTargetInfos();
+        LLVM_InitializeAllTargets();
+        LLVM_InitializeAllTargetMCs();
+        LLVM_InitializeAllAsmPrinters();
+        LLVM_InitializeAllAsmParsers();
+        LLVM_InitializeAllDisassemblers();
 
-    let registry = LLVMGetGlobalPassRegistry();
-    LLVMInitializeCore(registry);
-    LLVMInitializeCodeGen(registry);
-    LLVMInitializeScalarOpts(registry);
-    LLVMInitializeVectorization(registry);
+        let registry = LLVMGetGlobalPassRegistry();
+        LLVMInitializeCore(registry);
+        LLVMInitializeCodeGen(registry);
+        LLVMInitializeScalarOpts(registry);
+        LLVMInitializeVectorization(registry);
+    });
+}
 
-    context
+// Probes are processed in parallel, so each gets its own context
+unsafe fn init_context() -> LLVMContextRef {
+    init();
+
+    LLVMContextCreate()
 }
 
 unsafe fn load_module(context: LLVMContextRef, input: &Path) -> Result<LLVMModuleRef, String> {
@@ -80,25 +93,49 @@ unsafe fn write_module(module: LLVMModuleRef, path: &Path) -> Result<(), String>
     Ok(())
 }
 
-unsafe fn inject_exit_call(context: LLVMContextRef, func: LLVMValueRef, builder: LLVMBuilderRef) {
-    let exit_str = CString::new("exit").unwrap();
-    let exit_sig = LLVMFunctionType(LLVMVoidTypeInContext(context), ptr::null_mut(), 0, 0);
-    let exit = LLVMGetInlineAsm(
-        exit_sig,
-        exit_str.as_ptr() as *mut _,
-        "exit".len(),
+// LLVM 13 added the CanThrow argument
+#[cfg(any(llvm = "9", llvm = "10", llvm = "11", llvm = "12"))]
+unsafe fn inline_asm(ty: LLVMTypeRef, asm: &str) -> LLVMValueRef {
+    let asm_str = CString::new(asm).unwrap();
+    LLVMGetInlineAsm(
+        ty,
+        asm_str.as_ptr() as *mut _,
+        asm.len(),
         ptr::null_mut(),
         0,
         0,
         0,
         LLVMInlineAsmDialectATT,
-    );
+    )
+}
+
+#[cfg(any(llvm = "13", llvm = "14"))]
+unsafe fn inline_asm(ty: LLVMTypeRef, asm: &str) -> LLVMValueRef {
+    let asm_str = CString::new(asm).unwrap();
+    LLVMGetInlineAsm(
+        ty,
+        asm_str.as_ptr() as *mut _,
+        asm.len(),
+        ptr::null_mut(),
+        0,
+        0,
+        0,
+        LLVMInlineAsmDialectATT,
+        0,
+    )
+}
+
+unsafe fn inject_exit_call(context: LLVMContextRef, func: LLVMValueRef, builder: LLVMBuilderRef) {
+    let exit_sig = LLVMFunctionType(LLVMVoidTypeInContext(context), ptr::null_mut(), 0, 0);
+    let exit = inline_asm(exit_sig, "exit");
 
     let block = LLVMGetLastBasicBlock(func);
     let last = LLVMGetLastInstruction(block);
     LLVMPositionBuilderBefore(builder, last);
-    LLVMBuildCall(
+    // LLVMBuildCall is deprecated since LLVM 14
+    LLVMBuildCall2(
         builder,
+        exit_sig,
         exit,
         ptr::null_mut(),
         0,
@@ -106,43 +143,547 @@ unsafe fn inject_exit_call(context: LLVMContextRef, func: LLVMValueRef, builder:
     );
 }
 
-pub fn process_ir(input: &Path, output: &Path) -> Result<(), String> {
+/// Returns whether `message`, an error of `process_ir()`, means that the
+/// bitcode was produced by a newer LLVM than the one cargo-bpf is built with.
+pub fn is_newer_bitcode(message: &str) -> bool {
+    ["Invalid record", "Unknown attribute kind", "Invalid type"]
+        .iter()
+        .any(|error| message.contains(error))
+}
+
+pub fn process_ir(input: &Path, output: &Path, strip_debug_info: bool) -> Result<(), String> {
     unsafe {
         let context = init_context();
-        let module = load_module(context, input)?;
-        let builder = LLVMCreateBuilderInContext(context);
-
-        let no_inline = CString::new("noinline").unwrap();
-        let no_inline_kind = LLVMGetEnumAttributeKindForName(no_inline.as_ptr(), "noinline".len());
-        let always_inline = CString::new("alwaysinline").unwrap();
-        let always_inline_kind =
-            LLVMGetEnumAttributeKindForName(always_inline.as_ptr(), "alwaysinline".len());
-        let always_inline_attr = LLVMCreateEnumAttribute(context, always_inline_kind, 0);
-
-        let mut func = LLVMGetFirstFunction(module);
-        while func != ptr::null_mut() {
-            let mut size: libc::size_t = 0;
-            let name = CStr::from_ptr(LLVMGetValueName2(func, &mut size as *mut _))
-                .to_str()
-                .unwrap();
-            if !name.starts_with("llvm.") {
-                // make sure everything gets inlined as BPF can't do calls to
-                // things other than helpers
-                LLVMRemoveEnumAttributeAtIndex(func, LLVMAttributeFunctionIndex, no_inline_kind);
-                LLVMAddAttributeAtIndex(func, LLVMAttributeFunctionIndex, always_inline_attr);
-
-                if name == "rust_begin_unwind" {
-                    // inject a BPF exit call in the panic handler to make the program terminate
-                    inject_exit_call(context, func, builder);
-                }
+        let ret = process_module(context, input, output, strip_debug_info);
+        LLVMContextDispose(context);
+        ret
+    }
+}
+
+unsafe fn process_module(
+    context: LLVMContextRef,
+    input: &Path,
+    output: &Path,
+    strip_debug_info: bool,
+) -> Result<(), String> {
+    let module = load_module(context, input)?;
+    let builder = LLVMCreateBuilderInContext(context);
+
+    let no_inline = CString::new("noinline").unwrap();
+    let no_inline_kind = LLVMGetEnumAttributeKindForName(no_inline.as_ptr(), "noinline".len());
+    let always_inline = CString::new("alwaysinline").unwrap();
+    let always_inline_kind =
+        LLVMGetEnumAttributeKindForName(always_inline.as_ptr(), "alwaysinline".len());
+    let always_inline_attr = LLVMCreateEnumAttribute(context, always_inline_kind, 0);
+
+    let mut func = LLVMGetFirstFunction(module);
+    while func != ptr::null_mut() {
+        let mut size: libc::size_t = 0;
+        let name = CStr::from_ptr(LLVMGetValueName2(func, &mut size as *mut _))
+            .to_str()
+            .unwrap();
+        if !name.starts_with("llvm.") {
+            // make sure everything gets inlined as BPF can't do calls to
+            // things other than helpers
+            LLVMRemoveEnumAttributeAtIndex(func, LLVMAttributeFunctionIndex, no_inline_kind);
+            LLVMAddAttributeAtIndex(func, LLVMAttributeFunctionIndex, always_inline_attr);
+
+            if name == "rust_begin_unwind" {
+                // inject a BPF exit call in the panic handler to make the program terminate
+                inject_exit_call(context, func, builder);
             }
-            func = LLVMGetNextFunction(func);
         }
+        func = LLVMGetNextFunction(func);
+    }
+    LLVMDisposeBuilder(builder);
 
-        // the debug info generated by rustc seems to trigger a segfault in the
-        // BTF code in llvm, so strip it until that is fixed
+    // the debug info generated by rustc seems to trigger a segfault in the
+    // BTF code in llvm, so strip it unless BTF is asked for
+    if strip_debug_info {
         LLVMStripModuleDebugInfo(module);
+    }
+
+    write_module(module, output)
+}
+
+// The number of functions listed for each program by `unsupported_code()`
+const UNSUPPORTED_FUNCTIONS: usize = 5;
+
+// Code that the BPF target can't compile
+#[derive(Debug, Clone, PartialEq)]
+enum Unsupported {
+    ExternalCall(String),
+    FloatingPoint,
+    DynamicAlloca,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unsupported::ExternalCall(symbol) => {
+                write!(f, "calls the external symbol `{}'", demangle(symbol))
+            }
+            Unsupported::FloatingPoint => write!(f, "uses floating-point instructions"),
+            Unsupported::DynamicAlloca => write!(f, "allocates a variable-sized stack array"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FunctionCode {
+    unsupported: Vec<Unsupported>,
+    // the functions of the module it calls
+    calls: Vec<String>,
+}
+
+impl FunctionCode {
+    fn add(&mut self, unsupported: Unsupported) {
+        if !self.unsupported.contains(&unsupported) {
+            self.unsupported.push(unsupported);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ModuleCode {
+    // the sections and functions of the programs
+    programs: Vec<(String, String)>,
+    functions: HashMap<String, FunctionCode>,
+}
+
+impl ModuleCode {
+    // The functions reachable from `function`, nearest first
+    fn reachable<'a>(&'a self, function: &'a str) -> Vec<&'a str> {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        let mut reachable = Vec::new();
+        seen.insert(function);
+        queue.push_back(function);
+        while let Some(function) = queue.pop_front() {
+            reachable.push(function);
+            let code = match self.functions.get(function) {
+                Some(code) => code,
+                None => continue,
+            };
+            for callee in code.calls.iter() {
+                if seen.insert(callee) {
+                    queue.push_back(callee);
+                }
+            }
+        }
+
+        reachable
+    }
+
+    // The functions reachable from `function` with code in `unsupported`
+    fn unsupported_functions<'a>(
+        &'a self,
+        function: &'a str,
+        unsupported: &[Unsupported],
+    ) -> Vec<(&'a str, &'a Unsupported)> {
+        let mut functions = Vec::new();
+        for function in self.reachable(function) {
+            let code = &self.functions[function];
+            if let Some(u) = code.unsupported.iter().find(|u| unsupported.contains(u)) {
+                functions.push((function, u));
+            }
+        }
+
+        functions
+    }
+}
+
+/// Looks for the code of the programs in the optimized bitcode at
+/// `optimized` that the BPF target can't compile: calls to external
+/// symbols, floating-point instructions and stack arrays of a variable size,
+/// which usually come from `std` or a crate using floats.
+///
+/// Returns a message per program with such code, listing the functions of
+/// the bitcode at `original`, built by rustc before the functions are all
+/// inlined, that it comes from. The functions nearest to the program come
+/// first.
+pub fn unsupported_code(original: &Path, optimized: &Path) -> Result<Vec<String>, String> {
+    unsafe {
+        let context = init_context();
+        let ret = find_unsupported_code(context, original, optimized);
+        LLVMContextDispose(context);
+        ret
+    }
+}
+
+unsafe fn find_unsupported_code(
+    context: LLVMContextRef,
+    original: &Path,
+    optimized: &Path,
+) -> Result<Vec<String>, String> {
+    let optimized = module_code(context, optimized)?;
+    let mut messages = Vec::new();
+    let mut original_code = None;
+    for (section, program) in optimized.programs.iter() {
+        let mut unsupported: Vec<Unsupported> = Vec::new();
+        for function in optimized.reachable(program) {
+            for u in optimized.functions[function].unsupported.iter() {
+                if !unsupported.contains(u) {
+                    unsupported.push(u.clone());
+                }
+            }
+        }
+        if unsupported.is_empty() {
+            continue;
+        }
+
+        if original_code.is_none() {
+            original_code = Some(module_code(context, original)?);
+        }
+        let mut functions = original_code
+            .as_ref()
+            .unwrap()
+            .unsupported_functions(program, &unsupported);
+        // opt can generate the code itself, eg. with memcpy
+        if functions.is_empty() {
+            functions = optimized.unsupported_functions(program, &unsupported);
+        }
+        let mut message = format!("{}:", section);
+        for (function, u) in functions.iter().take(UNSUPPORTED_FUNCTIONS) {
+            message.push_str(&format!("\n    `{}' {}", demangle(function), u));
+        }
+        if functions.len() > UNSUPPORTED_FUNCTIONS {
+            message.push_str(&format!(
+                "\n    and {} more functions",
+                functions.len() - UNSUPPORTED_FUNCTIONS
+            ));
+        }
+        messages.push(message);
+    }
+
+    Ok(messages)
+}
+
+unsafe fn module_code(context: LLVMContextRef, path: &Path) -> Result<ModuleCode, String> {
+    let module = load_module(context, path)?;
+    let mut code = ModuleCode::default();
+    let mut func = LLVMGetFirstFunction(module);
+    while !func.is_null() {
+        if LLVMIsDeclaration(func) == 0 {
+            let name = value_name(func);
+            let section = LLVMGetSection(func);
+            if !section.is_null() && *section != 0 {
+                let section = CStr::from_ptr(section).to_string_lossy().into_owned();
+                code.programs.push((section, name.clone()));
+            }
+            code.functions.insert(name, function_code(func));
+        }
+        func = LLVMGetNextFunction(func);
+    }
+    LLVMDisposeModule(module);
+
+    Ok(code)
+}
+
+unsafe fn function_code(func: LLVMValueRef) -> FunctionCode {
+    let mut code = FunctionCode::default();
+    let mut block = LLVMGetFirstBasicBlock(func);
+    while !block.is_null() {
+        let mut inst = LLVMGetFirstInstruction(block);
+        while !inst.is_null() {
+            if uses_floating_point(inst) {
+                code.add(Unsupported::FloatingPoint);
+            }
+            if !LLVMIsAAllocaInst(inst).is_null() && LLVMIsConstant(LLVMGetOperand(inst, 0)) == 0 {
+                code.add(Unsupported::DynamicAlloca);
+            }
+            if !LLVMIsACallInst(inst).is_null() || !LLVMIsAInvokeInst(inst).is_null() {
+                let callee = called_function(inst);
+                if !callee.is_null() {
+                    let name = value_name(callee);
+                    if LLVMIsDeclaration(callee) == 0 {
+                        if !code.calls.contains(&name) {
+                            code.calls.push(name);
+                        }
+                    } else if !name.starts_with("llvm.") {
+                        code.add(Unsupported::ExternalCall(name));
+                    }
+                }
+            }
+            inst = LLVMGetNextInstruction(inst);
+        }
+        block = LLVMGetNextBasicBlock(block);
+    }
+
+    code
+}
+
+// Returns the function called by `inst`, or null when it's not a function
+// of the module, eg. a BPF helper called by its number
+unsafe fn called_function(inst: LLVMValueRef) -> LLVMValueRef {
+    let mut callee = LLVMGetCalledValue(inst);
+    // the function is cast when its type doesn't match the call
+    while !LLVMIsAConstantExpr(callee).is_null()
+        && LLVMGetConstOpcode(callee) == LLVMOpcode::LLVMBitCast
+    {
+        callee = LLVMGetOperand(callee, 0);
+    }
+
+    LLVMIsAFunction(callee)
+}
 
-        write_module(module, output)
+unsafe fn uses_floating_point(inst: LLVMValueRef) -> bool {
+    use LLVMOpcode::*;
+
+    match LLVMGetInstructionOpcode(inst) {
+        LLVMFAdd | LLVMFSub | LLVMFMul | LLVMFDiv | LLVMFRem | LLVMFNeg | LLVMFCmp | LLVMFPToUI
+        | LLVMFPToSI | LLVMUIToFP | LLVMSIToFP | LLVMFPTrunc | LLVMFPExt => return true,
+        _ => {}
+    }
+    if is_floating_point(LLVMTypeOf(inst)) {
+        return true;
+    }
+    (0..LLVMGetNumOperands(inst))
+        .any(|i| is_floating_point(LLVMTypeOf(LLVMGetOperand(inst, i as u32))))
+}
+
+unsafe fn is_floating_point(ty: LLVMTypeRef) -> bool {
+    use LLVMTypeKind::*;
+
+    match LLVMGetTypeKind(ty) {
+        LLVMHalfTypeKind
+        | LLVMFloatTypeKind
+        | LLVMDoubleTypeKind
+        | LLVMX86_FP80TypeKind
+        | LLVMFP128TypeKind
+        | LLVMPPC_FP128TypeKind => true,
+        LLVMVectorTypeKind => is_floating_point(LLVMGetElementType(ty)),
+        _ => false,
+    }
+}
+
+unsafe fn value_name(value: LLVMValueRef) -> String {
+    let mut size: libc::size_t = 0;
+    let name = LLVMGetValueName2(value, &mut size as *mut _);
+    let name = std::slice::from_raw_parts(name as *const u8, size);
+
+    String::from_utf8_lossy(name).into_owned()
+}
+
+// Demangles the legacy symbols of rustc, eg. `_ZN4core3fmt5write17h0123456789abcdefE`
+// to `core::fmt::write`. Other symbols are returned as they are.
+fn demangle(symbol: &str) -> String {
+    let mangled = symbol.trim_start_matches('_');
+    if !mangled.starts_with("ZN") || !mangled.ends_with('E') {
+        return symbol.to_string();
+    }
+
+    let mut rest = &mangled[2..mangled.len() - 1];
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) if digits + len <= rest.len() => len,
+            _ => return symbol.to_string(),
+        };
+        segments.push(&rest[digits..digits + len]);
+        rest = &rest[digits + len..];
+    }
+    // the hash of the crate and of the signature
+    if let Some(last) = segments.last() {
+        if last.len() == 17
+            && last.starts_with('h')
+            && last[1..].chars().all(|c| c.is_ascii_hexdigit())
+        {
+            segments.pop();
+        }
     }
-}
\ No newline at end of file
+
+    segments
+        .iter()
+        .map(|segment| demangle_segment(segment))
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+fn demangle_segment(segment: &str) -> String {
+    let segment = if segment.starts_with("_$") {
+        &segment[1..]
+    } else {
+        segment
+    };
+    let mut out = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            out.push_str("::");
+            rest = &rest[2..];
+            continue;
+        }
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                let escape = &rest[1..end + 1];
+                let c = match escape {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C" => Some(','),
+                    _ if escape.starts_with('u') => u32::from_str_radix(&escape[1..], 16)
+                        .ok()
+                        .and_then(std::char::from_u32),
+                    _ => None,
+                };
+                if let Some(c) = c {
+                    out.push(c);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+        let c = rest.chars().next().unwrap();
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+/// Disassembles the BPF `code`, returning the offset in bytes and the text
+/// of each instruction. Instructions LLVM can't decode are `<invalid>`.
+pub fn disassemble(code: &[u8]) -> Result<Vec<(usize, String)>, String> {
+    let triple = CString::new("bpfel").unwrap();
+    let mut text = [0 as c_char; 256];
+    let mut insns = Vec::new();
+    unsafe {
+        init();
+        let disasm = LLVMCreateDisasm(triple.as_ptr(), ptr::null_mut(), 0, None, None);
+        if disasm.is_null() {
+            return Err("the BPF target of LLVM has no disassembler".to_string());
+        }
+        let mut offset = 0;
+        while offset < code.len() {
+            let size = LLVMDisasmInstruction(
+                disasm,
+                code[offset..].as_ptr() as *mut u8,
+                (code.len() - offset) as u64,
+                offset as u64,
+                text.as_mut_ptr(),
+                text.len(),
+            );
+            if size == 0 {
+                insns.push((offset, "<invalid>".to_string()));
+                offset += 8;
+                continue;
+            }
+            let insn = CStr::from_ptr(text.as_ptr()).to_string_lossy();
+            insns.push((offset, insn.trim().to_string()));
+            offset += size;
+        }
+        LLVMDisasmDispose(disasm);
+    }
+
+    Ok(insns)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{demangle, is_newer_bitcode, unsupported_code};
+    use std::fs;
+
+    #[test]
+    fn demangle_symbols() {
+        assert_eq!(
+            demangle("_ZN4core3fmt5float29float_to_decimal_common_exact17h6fa2d5bb8c3b4b1aE"),
+            "core::fmt::float::float_to_decimal_common_exact"
+        );
+        assert_eq!(
+            demangle("_ZN61_$LT$probes..iotop..Counter$u20$as$u20$core..clone..Clone$GT$5clone17h0123456789abcdefE"),
+            "<probes::iotop::Counter as core::clone::Clone>::clone"
+        );
+        assert_eq!(demangle("memcpy"), "memcpy");
+    }
+
+    #[test]
+    fn newer_bitcode() {
+        assert!(is_newer_bitcode(
+            "error loading bitcode: Invalid record (Producer: 'LLVM15.0.6-rust-1.69.0-stable' Reader: 'LLVM 14.0.6')"
+        ));
+        assert!(is_newer_bitcode("Unknown attribute kind (86)"));
+        assert!(!is_newer_bitcode("error opening probe.bc: No such file or directory"));
+    }
+
+    #[test]
+    fn unsupported() {
+        let dir = tempfile::tempdir().unwrap();
+        let optimized = dir.path().join("optimized.ll");
+        let original = dir.path().join("original.ll");
+        // ok_prog only calls a helper, by its number, and an intrinsic the
+        // BPF target lowers
+        fs::write(
+            &optimized,
+            r#"target triple = "bpf"
+
+declare i32 @external(i32)
+
+declare void @llvm.memset.p0i8.i64(i8*, i8, i64, i1)
+
+define i32 @float_prog(i64 %x) section "kprobe/float" {
+  %f = uitofp i64 %x to double
+  %g = fmul double %f, 2.0
+  %r = fptoui double %g to i32
+  ret i32 %r
+}
+
+define i32 @alloca_prog(i64 %n) section "kprobe/alloca" {
+  %buf = alloca i8, i64 %n
+  store i8 0, i8* %buf
+  ret i32 0
+}
+
+define i32 @call_prog(i32 %x) section "kprobe/call" {
+  %r = call i32 @external(i32 %x)
+  ret i32 %r
+}
+
+define i32 @ok_prog(i8* %p) section "kprobe/ok" {
+  %buf = alloca i8, i64 8
+  call void @llvm.memset.p0i8.i64(i8* %buf, i8 0, i64 8, i1 false)
+  %pid = call i64 inttoptr (i64 14 to i64 ()*)()
+  ret i32 0
+}
+"#,
+        )
+        .unwrap();
+        // the other programs aren't in the original code, like code
+        // generated by opt, so they are reported from the optimized code
+        fs::write(
+            &original,
+            r#"target triple = "bpf"
+
+declare i32 @external(i32)
+
+define internal i32 @helper(i32 %x) {
+  %r = call i32 @external(i32 %x)
+  ret i32 %r
+}
+
+define i32 @call_prog(i32 %x) section "kprobe/call" {
+  %r = call i32 @helper(i32 %x)
+  ret i32 %r
+}
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            unsupported_code(&original, &optimized).unwrap(),
+            vec![
+                "kprobe/float:\n    `float_prog' uses floating-point instructions",
+                "kprobe/alloca:\n    `alloca_prog' allocates a variable-sized stack array",
+                "kprobe/call:\n    `helper' calls the external symbol `external'",
+            ]
+        );
+        assert!(unsupported_code(&original, &dir.path().join("missing.ll")).is_err());
+    }
+}
@@ -5,38 +5,321 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use crate::daemon::daemon;
+use crate::layout::EventLayout;
+use crate::programs::json_string;
 use crate::CommandError;
 
-use hexdump::hexdump;
-use redbpf::load::Loader;
-use redbpf::xdp;
-use std::path::PathBuf;
+use hexdump::hexdump_iter;
+use redbpf::load::{AttachParams, Loaded, Loader, MapEvent};
+use redbpf::spec::ModuleSpec;
+use redbpf::{enable_stats, tc, xdp, Module, ProgramKind, ProgramStats};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use futures::stream::StreamExt;
 use tokio;
 use tokio::runtime::Runtime;
 use tokio::signal;
 
-pub fn load(program: &PathBuf, interface: Option<&str>) -> Result<(), CommandError> {
-    let mut runtime = Runtime::new().unwrap();
-    let _ = runtime.block_on(async {
-        let mut loader = Loader::new()
-            .xdp(interface.map(String::from), xdp::Flags::default())
-            .load_file(&program)
-            .await
-            .expect("error loading file");
-        tokio::spawn(async move {
-            while let Some((name, events)) = loader.events.next().await {
-                for event in events {
-                    println!("-- Event: {} --", name);
-                    hexdump(&event);
-                }
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the events of the programs are printed by `load()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    /// A hex dump of each event
+    Hex,
+    /// A JSON object per line, decoded with the layout of the map if any
+    Json,
+    /// The bytes of the events, one after the other
+    Raw,
+}
+
+impl FromStr for EventFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventFormat, String> {
+        match s {
+            "hex" => Ok(EventFormat::Hex),
+            "json" => Ok(EventFormat::Json),
+            "raw" => Ok(EventFormat::Raw),
+            _ => Err(format!("unknown event format `{}'", s)),
+        }
+    }
+}
+
+/// Where `load()` attaches the programs, and how it prints their events.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// The interface XDP programs, classifiers and socket filters are
+    /// attached to. Classifiers are attached for ingress packets.
+    pub interface: Option<String>,
+    /// The process uprobes are attached to. Without `uprobe_path`, they are
+    /// attached in its executable.
+    pub pid: Option<libc::pid_t>,
+    /// The executable or library uprobes are attached in.
+    pub uprobe_path: Option<String>,
+    /// The function uprobes are attached to, instead of the function they are
+    /// named after.
+    pub uprobe_symbol: Option<String>,
+    /// Defaults to `EventFormat::Hex`.
+    pub format: EventFormat,
+    /// The layouts the events are decoded with in the JSON format, for the
+    /// named map or for all the maps when the name is `None`.
+    pub layouts: Vec<(Option<String>, EventLayout)>,
+    /// How long the programs stay loaded, until interrupted by default.
+    pub duration: Option<Duration>,
+    /// The file the events are written to instead of stdout.
+    pub output: Option<PathBuf>,
+    /// Prints the run count and run time of each program every second.
+    pub stats: bool,
+    /// Keeps the programs loaded until terminated, and reloads them on
+    /// SIGHUP, see `daemon()`. `duration` and `stats` don't apply.
+    pub daemon: bool,
+    /// The bpffs directory the maps and links are pinned in, in daemon
+    /// mode.
+    pub pin_dir: Option<PathBuf>,
+    /// The unix socket the events are sent to as JSON lines, in daemon
+    /// mode, instead of being written to `output`.
+    pub socket: Option<PathBuf>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions {
+            interface: None,
+            pid: None,
+            uprobe_path: None,
+            uprobe_symbol: None,
+            format: EventFormat::Hex,
+            layouts: Vec::new(),
+            duration: None,
+            output: None,
+            stats: false,
+            daemon: false,
+            pin_dir: None,
+            socket: None,
+        }
+    }
+}
+
+impl LoadOptions {
+    fn attach_params(&self, kind: ProgramKind, name: &str) -> Option<AttachParams> {
+        use ProgramKind::*;
+
+        match kind {
+            Classifier => self
+                .interface
+                .as_ref()
+                .map(|interface| AttachParams::Classifier {
+                    interface: interface.clone(),
+                    direction: tc::Direction::Ingress,
+                    priority: 1,
+                    handle: 1,
+                }),
+            SocketFilter => self
+                .interface
+                .as_ref()
+                .map(|interface| AttachParams::SocketFilter {
+                    interface: interface.clone(),
+                }),
+            Uprobe | Uretprobe => {
+                let path = match (&self.uprobe_path, self.pid) {
+                    (Some(path), _) => path.clone(),
+                    (None, Some(pid)) => format!("/proc/{}/exe", pid),
+                    (None, None) => return None,
+                };
+                Some(AttachParams::Uprobe {
+                    path,
+                    symbol: self
+                        .uprobe_symbol
+                        .clone()
+                        .unwrap_or_else(|| name.to_string()),
+                    pid: self.pid,
+                })
             }
-        });
+            _ => None,
+        }
+    }
 
-        signal::ctrl_c().await
-    });
+    fn layout(&self, map: &str) -> Option<&EventLayout> {
+        self.layouts
+            .iter()
+            .find(|(name, _)| match name {
+                Some(name) => name == map,
+                None => true,
+            })
+            .map(|(_, layout)| layout)
+    }
+}
+
+pub fn load(program: &Path, options: &LoadOptions) -> Result<(), CommandError> {
+    if options.daemon {
+        return daemon(program, options);
+    }
+    let mut out = output(options)?;
+    let mut runtime = Runtime::new()?;
+    runtime.block_on(async {
+        let mut loader = load_programs(program, options, true, None).await?;
+        let stats = if options.stats {
+            Some(enable_stats()?)
+        } else {
+            None
+        };
+        let mut previous = HashMap::new();
+        let mut interval = tokio::time::interval(STATS_INTERVAL);
+        let mut deadline = tokio::time::delay_for(options.duration.unwrap_or_default());
+        loop {
+            tokio::select! {
+                Some((name, events)) = loader.events.next() => {
+                    write_events(&mut out, &name, events, options)?
+                }
+                _ = interval.tick(), if options.stats => print_stats(&loader.module, &mut previous),
+                _ = &mut deadline, if options.duration.is_some() => break,
+                _ = signal::ctrl_c() => break,
+            }
+        }
+        for (name, events) in loader.close().await? {
+            write_events(&mut out, &name, events, options)?;
+        }
+        out.flush()?;
+        if let Some(stats) = stats {
+            stats.restore()?;
+        }
+        Ok::<_, CommandError>(())
+    })?;
 
     println!("exiting");
 
     Ok(())
 }
+
+// Loads the programs of the ELF file at `program` and attaches them as set
+// in `options`, except XDP programs unless `attach_xdp` is set. The maps
+// pinned in `pinned_maps` are used instead of creating them. The programs
+// failing to attach are reported and stay loaded.
+pub(crate) async fn load_programs(
+    program: &Path,
+    options: &LoadOptions,
+    attach_xdp: bool,
+    pinned_maps: Option<&Path>,
+) -> Result<Loaded, CommandError> {
+    let spec = ModuleSpec::parse(&fs::read(program)?)?;
+    let mut loader = Loader::new();
+    if let Some(dir) = pinned_maps {
+        loader.reuse_pinned_maps(dir);
+    }
+    if attach_xdp {
+        loader.xdp(options.interface.clone(), xdp::Flags::default());
+    }
+    for prog in spec.programs.iter() {
+        if let Some(params) = options.attach_params(prog.kind, &prog.name) {
+            loader.attach_params(&prog.name, params);
+        }
+    }
+    let loaded = loader.load_file(program).await?;
+    if let Some(e) = &loaded.module.btf_error {
+        eprintln!("loaded the programs without BTF: {}", e);
+    }
+    for error in loaded.attach_errors.iter() {
+        eprintln!("{}", error);
+    }
+
+    Ok(loaded)
+}
+
+pub(crate) fn output(options: &LoadOptions) -> io::Result<Box<dyn Write>> {
+    Ok(match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    })
+}
+
+pub(crate) fn write_events(
+    out: &mut dyn Write,
+    name: &str,
+    events: Vec<MapEvent>,
+    options: &LoadOptions,
+) -> io::Result<()> {
+    for event in events {
+        match (options.format, event) {
+            (EventFormat::Hex, MapEvent::Sample { data, .. }) => {
+                writeln!(out, "-- Event: {} --", name)?;
+                for line in hexdump_iter(&data) {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            (
+                EventFormat::Json,
+                MapEvent::Sample {
+                    cpu,
+                    timestamp_ns,
+                    data,
+                },
+            ) => {
+                let mut line = format!("{{\"map\":{},\"cpu\":{}", json_string(name), cpu);
+                if let Some(timestamp_ns) = timestamp_ns {
+                    write!(line, ",\"timestamp_ns\":{}", timestamp_ns).unwrap();
+                }
+                match options.layout(name) {
+                    Some(layout) => write!(line, ",\"data\":{}}}", layout.to_json(&data)),
+                    None => write!(line, ",\"hex\":\"{}\"}}", hex(&data)),
+                }
+                .unwrap();
+                writeln!(out, "{}", line)?;
+            }
+            (EventFormat::Raw, MapEvent::Sample { data, .. }) => out.write_all(&data)?,
+            (EventFormat::Json, MapEvent::Lost { count, cpu }) => writeln!(
+                out,
+                "{{\"map\":{},\"cpu\":{},\"lost\":{}}}",
+                json_string(name),
+                cpu,
+                count
+            )?,
+            (EventFormat::Hex, MapEvent::Lost { count, cpu }) => {
+                writeln!(out, "-- Lost {} events: {} on CPU {} --", count, name, cpu)?;
+            }
+            // raw events can't be told apart, so losses go to stderr
+            (EventFormat::Raw, MapEvent::Lost { count, cpu }) => {
+                eprintln!("-- Lost {} events: {} on CPU {} --", count, name, cpu);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn hex(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(data.len() * 2);
+    for byte in data {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+
+    hex
+}
+
+// Prints the runs of each program since the previous call. The CPU usage is
+// relative to a single CPU.
+fn print_stats(module: &Module, previous: &mut HashMap<String, ProgramStats>) {
+    for prog in module.programs() {
+        let stats = match prog.stats() {
+            Ok(stats) => stats,
+            Err(_) => continue,
+        };
+        let earlier = previous.get(prog.name()).cloned().unwrap_or_default();
+        let delta = stats.since(&earlier);
+        let cpu = delta.run_time_ns as f64 * 100.0 / STATS_INTERVAL.as_nanos() as f64;
+        println!(
+            "-- Stats: {} {} runs, {} ns/run, {:.2}% CPU --",
+            prog.name(),
+            delta.run_count,
+            delta.average_ns(),
+            cpu
+        );
+        previous.insert(prog.name().to_string(), stats);
+    }
+}
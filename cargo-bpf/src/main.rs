@@ -22,9 +22,13 @@ bytecode and executed by the linux in-kernel eBPF virtual machine.
 To install `cargo bpf` simply run:
 
 ```
-cargo install cargo-bpf
+cargo install cargo-bpf --features llvm-14
 ```
 
+The `llvm-9` to `llvm-14` features select the LLVM `cargo-bpf` links to. It
+must be the LLVM of rustc, shown by `rustc -vV`, or a newer one. Without any,
+`cargo bpf build` fails naming the feature of the installed `llvm-config`.
+
 # Creating a new project
 
 After installng `cargo bpf`, you can crate a new project with `cargo bpf new`:
@@ -83,6 +87,17 @@ required-features = ["probes"]
 As you can see, running `cargo bpf add` added a new `[bin]` target to the
 crate. This new target will contain the eBPF program code.
 
+By default the program is an empty skeleton. To start from a working program
+instead, pass `--template` with one of `xdp`, `kprobe`, `kretprobe`, `uprobe`,
+`socket-filter` or `tracepoint`:
+
+```
+$ cargo bpf add --template kprobe trace_open
+```
+
+The generated `main.rs` declares a map and a probe function, and ends with an
+example of loading the program from user space.
+
 # Building
 
 Say that you're building an XDP program to block all traffic directed to port 80, and have therefore modified
@@ -118,7 +133,157 @@ $ cargo bpf build block_http
 
 `cargo bpf build` will produce eBPF code compatibile with the format expected
 by `redbpf::Module` and will place it in
-`target/release/bpf-programs/http_block.elf`.
+`target/bpf/programs/block_http/block_http.elf`. `cargo_bpf_lib::probe_path()`
+returns the path of the ELF file of a probe, and
+`redbpf::load::Loader::load_dir()` loads all the probes of
+`target/bpf/programs` at once.
+
+In a workspace, `cargo bpf build` can run from the root, and `-p` selects
+the member with the programs when more than one depends on `redbpf-probes`.
+The programs are built in `$CARGO_TARGET_DIR` when it's set, otherwise in the
+`target` directory of the workspace, and `--target-dir` overrides both.
+`--out-dir` copies the ELF files to a directory, as `<program>.elf`. The
+paths of the ELF files are printed once they're built.
+
+`--program` builds only some of the programs, and can be repeated. The
+programs are linked in parallel, and the link is skipped when the bitcode of
+a program is the same as in its previous build, so that rebuilding a single
+program after a change is quick.
+
+BPF has no floating-point instructions, and programs can only call helpers
+and the functions inlined in them. Such code usually comes from `std` or a
+dependency, and the build fails before it reaches the BPF backend, listing
+the functions each program reaches that use floats, call external symbols
+or allocate stack arrays of a variable size, nearest first.
+
+Build scripts can do the same with `cargo_bpf_lib::build_probes()`, which
+takes the options of the command in a `BuildOptions` and returns the paths
+of the ELF files. It prints `cargo:rerun-if-changed` for the sources of the
+probes, so that they're built again when they change, and returns the
+errors of rustc.
+
+With `--btf`, the debug info is kept and the ELF file gets `.BTF` and
+`.BTF.ext` sections. `redbpf` then loads the programs with their source
+lines, and the verifier logs show the line of each instruction. LLVM
+deduplicates the types as it generates BTF, and the build fails if the ELF
+file lacks the sections, or `.BTF.ext` lacks some of the programs. `cargo bpf
+inspect` shows which sections an ELF file has.
+
+## Kernel headers and cross-building
+
+The bindings of `redbpf-probes` are generated from the headers of the running
+kernel, found in `/lib/modules/$KERNEL_VERSION` where `$KERNEL_VERSION`
+defaults to the release of the running kernel. `--kernel-headers`, or
+`$KERNEL_SOURCE`, points to another kernel source or build tree instead, eg.
+in a container:
+
+```
+$ cargo bpf build --kernel-headers /opt/linux-5.4
+```
+
+The tree must have the files `make prepare` generates, and the error says
+how to generate them when it doesn't. `--target` builds the programs for
+another architecture, eg. `aarch64-unknown-linux-gnu`: the bindings are
+generated for it and the programs are compiled with its `target_arch`, so it
+needs the kernel headers of the target and the Rust target installed with
+`rustup target add`.
+
+# Sharing types with userspace
+
+Userspace reads the maps of the programs with the structs the programs
+write, but it can't depend on the probes crate, which is `no_std` and needs
+the kernel headers. `cargo bpf share-types` generates a module with the
+types of the keys and values of the `#[map]` statics instead, and the types
+and constants they use:
+
+```
+$ cargo bpf share-types -o ../userspace/src/probes_types.rs
+```
+
+The types must be `#[repr(C)]`, or have an integer `repr` for enums, and
+keep their module in the probes crate, eg. `probes::knock::Connection` is
+`probes_types::knock::Connection`. Each of them is followed by assertions of
+its size and alignment in the programs, so that userspace fails to build if
+it lays it out differently. The output only depends on the sources: it can
+be checked in, and `--check` fails when it's out of date. Build scripts can
+generate it in `OUT_DIR` with `cargo_bpf_lib::shared_types()` instead.
+
+# Tracepoint records
+
+The layout of the record passed to tracepoint programs is described by the
+`format` file of the tracepoint. `cargo bpf tracepoint` reads the format
+files of the running kernel and prints a struct for each record, that can be
+added to the probes crate and used as the argument of `#[tracepoint]`
+functions:
+
+```
+$ cargo bpf tracepoint syscalls/sys_enter_openat > src/block_http/records.rs
+```
+
+# Generating bindings
+
+`cargo bpf bindgen` generates bindings from C headers, like a header of the
+kernel:
+
+```
+$ cargo bpf bindgen linux/sched.h -t task_struct --derive Default --accessors > src/block_http/sched.rs
+```
+
+`-t`, `--allowlist-function` and `--allowlist-var` generate only the types,
+functions and variables matching a regex, and `--derive` adds a derive to
+the types that allow it. With `--accessors`, each type given by name gets a
+getter for each field, that reads it with `bpf_probe_read` and returns the
+error it fails with, eg. `task.pid()` returns `Result<i32, i32>`. The
+bindings expect `redbpf_probes` to be a dependency of the crate.
+
+# Kernel types from BTF
+
+The kernel headers must be installed for the running kernel. With `--btf`,
+the structs, unions, enums and typedefs given with `-t` are generated from
+the BTF of the kernel in `/sys/kernel/btf/vmlinux` instead, or from another
+BTF file with `--btf=PATH`:
+
+```
+$ cargo bpf bindgen --btf -t task_struct -t sock > src/block_http/vmlinux.rs
+```
+
+The types keep the layout of the BTF, and the types they use are generated
+too. Those more than `--depth` levels down, 1 by default, are opaque: only
+their size is kept. Without `--accessors`, which skips packed structs here,
+the bindings depend on `cty` only and work in any `no_std` crate.
+
+# Inspecting a program
+
+`cargo bpf inspect` lists the programs, maps and globals of a compiled
+program without loading it, so it works without root and on any kernel:
+
+```
+$ cargo bpf inspect target/bpf/programs/block_http/block_http.elf
+```
+
+Each program is listed with its section, its instruction count and the maps
+it uses. `cargo bpf disasm` prints the instructions of the programs, or of
+those given with `--program`, numbered like in the logs of the verifier:
+
+```
+$ cargo bpf disasm --program block_http target/bpf/programs/block_http/block_http.elf
+```
+
+Both take `--json` to print JSON for scripts instead.
+
+# Checking programs with the verifier
+
+`cargo bpf check` loads the programs of ELF files in the running kernel and
+closes them right away, without attaching them to anything, to tell whether
+the verifier accepts them. It prints the programs that pass and those that
+fail, with the end of the verifier log, and fails if any of them is rejected:
+
+```
+$ sudo cargo bpf check target/bpf/programs/block_http/block_http.elf
+```
+
+Without ELF files, the programs of the package are built first. The kernel
+version the programs are loaded with can be set with `--kernel-version`.
 
 # Loading a program during development
 
@@ -130,12 +295,47 @@ Loading eBPF programs requires admin priviledges, so you'll have to run
 `load` as root or with sudo:
 
 ```
-$ sudo cargo bpf load -i eth0 target/release/bpf-programs/http_block.elf
+$ sudo cargo bpf load -i eth0 target/bpf/programs/block_http/block_http.elf
 ```
 
+XDP programs, classifiers and socket filters are attached to the interface
+given with `-i`, and uprobes to the process given with `--pid` or to
+`--uprobe-target`, eg. `/usr/bin/app:main`. Kprobes and tracepoints are
+attached to the function or the event they are named after.
+
+The events are printed as hex dumps by default. `--format json` prints a JSON
+object per event instead, decoded with the layout of the struct sent by the
+program when it's given with `--layout`:
+
+```
+$ sudo cargo bpf load --pid 1234 --format json --layout 'pid:u32,comm:str[16],ts:u64' \
+    --duration 30s --output events.json target/bpf/programs/trace/trace.elf
+```
+
+`--format raw` writes the bytes of the events only, eg. to pipe them to
+another program.
+
+With `--stats`, the run count and run time of each program are printed every
+second, to measure the overhead of the programs. Statistics require Linux 5.1.
+
+With `--daemon`, `load` keeps the programs running until it gets SIGTERM or
+Ctrl-C. `--pin-dir` pins the maps and links under a bpffs directory, and
+`--socket` sends the events to the clients of a unix socket as JSON lines:
+
+```
+$ sudo cargo bpf load --daemon -i eth0 --pin-dir /sys/fs/bpf/block_http \
+    --socket /run/block_http.sock target/bpf/programs/block_http/block_http.elf &
+$ sudo socat - UNIX-CONNECT:/run/block_http.sock
+```
+
+On SIGHUP the ELF file is loaded again, and the new programs are attached
+before the old ones are detached. XDP programs are swapped atomically, so no
+packet goes unprocessed. The maps of the new programs start empty.
+
 */
-use clap::{self, crate_authors, crate_version, App, AppSettings, Arg, SubCommand};
+use clap::{self, crate_authors, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use cargo_bpf_lib as cargo_bpf;
 
@@ -168,32 +368,164 @@ fn main() {
                     .subcommand(
                         SubCommand::with_name("add")
                             .about("Adds a new eBPF program at src/<NAME>")
+                            .arg(Arg::with_name("TEMPLATE").long("template").value_name("TEMPLATE").possible_values(&cargo_bpf::program_templates()).help(
+                                "Creates a working program of the given type, with a map and an example of loading it from user space",
+                            ))
                             .arg(Arg::with_name("NAME").required(true).help(
                                 "The name of the eBPF program. The code will be created under src/<NAME>",
                             ))
                     )
                     .subcommand(
                         SubCommand::with_name("bindgen")
-                            .about("Generates rust bindings from C headers")
-                            .arg(Arg::with_name("HEADER").required(true).help(
+                            .about("Generates rust bindings from C headers or from BTF")
+                            .arg(Arg::with_name("HEADER").required_unless("BTF").conflicts_with("BTF").help(
                                 "The C header file to generate bindings for",
                             ))
                             .arg(Arg::with_name("BINDGEN_ARGS").required(false).multiple(true).help(
                                 "Extra arguments passed to bindgen",
                             ))
+                            .arg(Arg::with_name("BTF").long("btf").value_name("PATH").min_values(0).require_equals(true).requires("TYPE").help(
+                                "Generates the bindings from the BTF at PATH instead of headers, by default from /sys/kernel/btf/vmlinux",
+                            ))
+                            .arg(Arg::with_name("TYPE").short("t").long("allowlist-type").alias("type").value_name("TYPE").multiple(true).number_of_values(1).help(
+                                "Generates only TYPE and the types it uses, a regex with headers and a struct, union, enum or typedef with --btf",
+                            ))
+                            .arg(Arg::with_name("FUNCTION").long("allowlist-function").value_name("REGEX").multiple(true).number_of_values(1).conflicts_with("BTF").help(
+                                "Generates only the functions matching REGEX",
+                            ))
+                            .arg(Arg::with_name("VAR").long("allowlist-var").value_name("REGEX").multiple(true).number_of_values(1).conflicts_with("BTF").help(
+                                "Generates only the variables matching REGEX",
+                            ))
+                            .arg(Arg::with_name("DERIVE").long("derive").value_name("TRAIT").multiple(true).number_of_values(1).possible_values(&cargo_bpf::bindgen::DERIVES).conflicts_with("BTF").help(
+                                "Derives TRAIT for the types that allow it",
+                            ))
+                            .arg(Arg::with_name("ACCESSORS").long("accessors").requires("TYPE").help(
+                                "Adds getters reading the fields of each TYPE given by name with bpf_probe_read",
+                            ))
+                            .arg(Arg::with_name("DEPTH").long("depth").value_name("DEPTH").default_value("1").requires("BTF").help(
+                                "The levels of types used by TYPE to define as well, deeper ones are opaque",
+                            ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("tracepoint")
+                            .about("Generates rust structs for the records of tracepoints")
+                            .arg(Arg::with_name("NAME").required(true).multiple(true).help(
+                                "The names of the tracepoints in the category/name form, eg. syscalls/sys_enter_openat",
+                            ))
                     )
                     .subcommand(
                         SubCommand::with_name("build")
                             .about("Compiles the eBPF programs in the package")
+                            .arg(Arg::with_name("BTF").long("btf").help(
+                                "Keeps the debug info, so that the programs are loaded with BTF and verifier logs show the source lines",
+                            ))
+                            .arg(Arg::with_name("PACKAGE").value_name("PACKAGE").short("p").long("package").help(
+                                "The workspace member with the programs, found automatically when only one member depends on redbpf-probes",
+                            ))
+                            .arg(Arg::with_name("TARGET_DIR").value_name("DIR").long("target-dir").help(
+                                "Builds in DIR instead of $CARGO_TARGET_DIR or the target directory of the workspace",
+                            ))
+                            .arg(Arg::with_name("OUT_DIR").value_name("DIR").long("out-dir").help(
+                                "Copies the ELF files of the programs to DIR",
+                            ))
+                            .arg(Arg::with_name("KERNEL_HEADERS").value_name("DIR").long("kernel-headers").help(
+                                "Generates the bindings from the kernel source or build tree DIR instead of $KERNEL_SOURCE or the headers of the running kernel",
+                            ))
+                            .arg(Arg::with_name("TARGET").value_name("TRIPLE").long("target").help(
+                                "Builds the programs for the architecture of TRIPLE, eg. aarch64-unknown-linux-gnu",
+                            ))
+                            .arg(Arg::with_name("PROGRAM").value_name("NAME").long("program").multiple(true).number_of_values(1).help(
+                                "Builds the NAME program, can be repeated. When no programs are specified, all the programs are built",
+                            ))
                             .arg(Arg::with_name("NAME").required(false).multiple(true).help(
-                                "The names of the programs to compile. When no names are specified, all the programs are built",
+                                "The names of the programs to compile, like --program",
+                            ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("inspect")
+                            .alias("programs")
+                            .about("Lists the programs and maps of a compiled eBPF program, without loading it")
+                            .arg(Arg::with_name("JSON").long("json").help(
+                                "Prints the programs and maps as JSON",
+                            ))
+                            .arg(Arg::with_name("PROGRAM").required(true).help(
+                                "The ELF file of the eBPF program",
+                            ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("disasm")
+                            .about("Prints the instructions of the programs of a compiled eBPF program")
+                            .arg(Arg::with_name("NAME").value_name("NAME").long("program").multiple(true).number_of_values(1).help(
+                                "Prints the NAME program only, can be repeated",
+                            ))
+                            .arg(Arg::with_name("JSON").long("json").help(
+                                "Prints the instructions as JSON",
+                            ))
+                            .arg(Arg::with_name("PROGRAM").required(true).help(
+                                "The ELF file of the eBPF program",
+                            ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("check")
+                            .about("Runs the eBPF programs through the verifier of the running kernel, without attaching them")
+                            .arg(Arg::with_name("KERNEL_VERSION").value_name("VERSION").long("kernel-version").help(
+                                "Loads the programs with the kernel version VERSION, eg. 5.4.0, instead of the one of the ELF file",
+                            ))
+                            .arg(Arg::with_name("PACKAGE").value_name("PACKAGE").short("p").long("package").help(
+                                "The workspace member to build when no ELF file is given",
+                            ))
+                            .arg(Arg::with_name("PROGRAM").multiple(true).help(
+                                "The ELF files to check. Without them, the programs of the package are built and checked",
+                            ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("share-types")
+                            .about("Generates the types of the maps of the eBPF programs, for userspace to include")
+                            .arg(Arg::with_name("PACKAGE").value_name("PACKAGE").short("p").long("package").help(
+                                "The workspace member with the eBPF programs",
+                            ))
+                            .arg(Arg::with_name("OUTPUT").value_name("FILE").short("o").long("output").help(
+                                "Writes the types to FILE when they changed, instead of stdout",
+                            ))
+                            .arg(Arg::with_name("CHECK").long("check").requires("OUTPUT").help(
+                                "Fails if FILE is out of date instead of writing it",
                             ))
                     )
                     .subcommand(
                         SubCommand::with_name("load")
                             .about("Loads the specifeid eBPF program")
                             .arg(Arg::with_name("INTERFACE").value_name("INTERFACE").short("i").long("interface").help(
-                                "Binds XDP programs to the given interface"
+                                "Binds XDP programs, classifiers and socket filters to the given interface"
+                            ))
+                            .arg(Arg::with_name("PID").value_name("PID").long("pid").help(
+                                "Attaches uprobes to the process PID, in its executable unless --uprobe-target is set"
+                            ))
+                            .arg(Arg::with_name("UPROBE_TARGET").value_name("PATH[:SYMBOL]").long("uprobe-target").help(
+                                "Attaches uprobes to SYMBOL, or to the function they are named after, in the executable or library PATH"
+                            ))
+                            .arg(Arg::with_name("FORMAT").value_name("FORMAT").long("format").possible_values(&["hex", "json", "raw"]).default_value("hex").help(
+                                "Prints the events as a hex dump, as JSON objects or as raw bytes"
+                            ))
+                            .arg(Arg::with_name("LAYOUT").value_name("[MAP=]FIELDS").long("layout").multiple(true).number_of_values(1).help(
+                                "Decodes the JSON events of MAP, or of all the maps, as a C struct of FIELDS, eg. pid:u32,comm:str[16]. Can be repeated"
+                            ))
+                            .arg(Arg::with_name("DURATION").value_name("DURATION").long("duration").help(
+                                "Unloads the programs after DURATION, eg. 30s, 500ms or 5m, instead of on Ctrl-C"
+                            ))
+                            .arg(Arg::with_name("OUTPUT").value_name("FILE").short("o").long("output").help(
+                                "Writes the events to FILE instead of stdout"
+                            ))
+                            .arg(Arg::with_name("STATS").long("stats").help(
+                                "Prints the run count and run time of each program every second"
+                            ))
+                            .arg(Arg::with_name("DAEMON").long("daemon").conflicts_with_all(&["DURATION", "STATS"]).help(
+                                "Keeps the programs loaded until terminated, and reloads PROGRAM on SIGHUP"
+                            ))
+                            .arg(Arg::with_name("PIN_DIR").value_name("DIR").long("pin-dir").requires("DAEMON").help(
+                                "Pins the maps and links in DIR/maps and DIR/links, in a bpffs mount"
+                            ))
+                            .arg(Arg::with_name("SOCKET").value_name("PATH").long("socket").requires("DAEMON").help(
+                                "Sends the events as JSON lines to the clients of the unix socket PATH"
                             ))
                             .arg(Arg::with_name("PROGRAM").required(true).help(
                                 "Loads the specified eBPF program and outputs all the events generated",
@@ -206,38 +538,195 @@ fn main() {
         let path = m.value_of("PATH").map(PathBuf::from).unwrap();
 
         if let Err(e) = cargo_bpf::new(&path, m.value_of("NAME")) {
-            clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
         }
     }
     if let Some(m) = matches.subcommand_matches("add") {
-        if let Err(e) = cargo_bpf::new_program(m.value_of("NAME").unwrap()) {
-            clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+        if let Err(e) = cargo_bpf::new_program(m.value_of("NAME").unwrap(), m.value_of("TEMPLATE"))
+        {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
         }
     }
     if let Some(m) = matches.subcommand_matches("bindgen") {
-        let header = m.value_of("HEADER").map(PathBuf::from).unwrap();
-        let extra_args = m
-            .values_of("BINDGEN_ARGS")
-            .map(|i| i.collect())
-            .unwrap_or_else(Vec::new);
-        if let Err(e) = cargo_bpf::bindgen::cmd_bindgen(&header, &extra_args[..]) {
-            clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+        let values = |name| {
+            m.values_of(name)
+                .map(|v| v.map(String::from).collect())
+                .unwrap_or_else(Vec::new)
+        };
+        let options = cargo_bpf::bindgen::BindgenOptions {
+            types: values("TYPE"),
+            functions: values("FUNCTION"),
+            vars: values("VAR"),
+            derives: values("DERIVE"),
+            accessors: m.is_present("ACCESSORS"),
+        };
+        if m.is_present("BTF") {
+            let depth = match m.value_of("DEPTH").unwrap().parse() {
+                Ok(depth) => depth,
+                Err(_) => clap::Error::with_description(
+                    "the depth must be a number",
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit(),
+            };
+            let btf = m.value_of("BTF").map(PathBuf::from);
+            if let Err(e) = cargo_bpf::bindgen::cmd_bindgen_btf(btf.as_deref(), depth, &options) {
+                clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+            }
+        } else {
+            let header = m.value_of("HEADER").map(PathBuf::from).unwrap();
+            let extra_args = m
+                .values_of("BINDGEN_ARGS")
+                .map(|i| i.collect())
+                .unwrap_or_else(Vec::new);
+            if let Err(e) = cargo_bpf::bindgen::cmd_bindgen(&header, &extra_args[..], &options) {
+                clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+            }
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("tracepoint") {
+        let tracepoints: Vec<&str> = m.values_of("NAME").unwrap().collect();
+        if let Err(e) = cargo_bpf::bindgen::cmd_tracepoint(&tracepoints) {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
         }
     }
     if let Some(m) = matches.subcommand_matches("build") {
-        let programs = m
-            .values_of("NAME")
-            .map(|i| i.map(|s| String::from(s)).collect())
-            .unwrap_or_else(Vec::new);
-        if let Err(e) = cargo_bpf::cmd_build(programs) {
-            clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+        let mut options = cargo_bpf::BuildOptions::new(".");
+        options.package = m.value_of("PACKAGE").map(String::from);
+        options.target_dir = m.value_of("TARGET_DIR").map(PathBuf::from);
+        options.out_dir = m.value_of("OUT_DIR").map(PathBuf::from);
+        options.kernel_headers = m.value_of("KERNEL_HEADERS").map(PathBuf::from);
+        options.target = m.value_of("TARGET").map(String::from);
+        options.probes = m
+            .values_of("PROGRAM")
+            .into_iter()
+            .flatten()
+            .chain(m.values_of("NAME").into_iter().flatten())
+            .map(String::from)
+            .collect();
+        options.btf = m.is_present("BTF");
+        if let Err(e) = cargo_bpf::cmd_build(&options) {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("inspect") {
+        let program = m.value_of("PROGRAM").map(PathBuf::from).unwrap();
+        if let Err(e) = cargo_bpf::inspect(&program, m.is_present("JSON")) {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("disasm") {
+        let program = m.value_of("PROGRAM").map(PathBuf::from).unwrap();
+        let names: Vec<&str> = m.values_of("NAME").into_iter().flatten().collect();
+        if let Err(e) = cargo_bpf::disasm(&program, &names, m.is_present("JSON")) {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("check") {
+        let mut programs: Vec<PathBuf> = m
+            .values_of("PROGRAM")
+            .into_iter()
+            .flatten()
+            .map(PathBuf::from)
+            .collect();
+        if programs.is_empty() {
+            let mut options = cargo_bpf::BuildOptions::new(".");
+            options.package = m.value_of("PACKAGE").map(String::from);
+            match cargo_bpf::build_package(&options) {
+                Ok(built) => programs.extend(built.into_iter().map(|p| p.path)),
+                Err(e) => {
+                    clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue)
+                        .exit()
+                }
+            }
+        }
+        if let Err(e) = cargo_bpf::check(&programs, m.value_of("KERNEL_VERSION")) {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("share-types") {
+        let mut options = cargo_bpf::BuildOptions::new(".");
+        options.package = m.value_of("PACKAGE").map(String::from);
+        let output = m.value_of("OUTPUT").map(PathBuf::from);
+        if let Err(e) =
+            cargo_bpf::cmd_share_types(&options, output.as_deref(), m.is_present("CHECK"))
+        {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
         }
     }
     if let Some(m) = matches.subcommand_matches("load") {
         let program = m.value_of("PROGRAM").map(PathBuf::from).unwrap();
-        let interface = m.value_of("INTERFACE");
-        if let Err(e) = cargo_bpf::load(&program, interface) {
-            clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+        let options = match load_options(m) {
+            Ok(options) => options,
+            Err(e) => clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit(),
+        };
+        if let Err(e) = cargo_bpf::load(&program, &options) {
+            clap::Error::with_description(&e.to_string(), clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+}
+
+fn load_options(m: &ArgMatches) -> Result<cargo_bpf::LoadOptions, String> {
+    let pid = match m.value_of("PID") {
+        Some(pid) => Some(pid.parse().map_err(|_| format!("invalid pid `{}'", pid))?),
+        None => None,
+    };
+    let (uprobe_path, uprobe_symbol) = match m.value_of("UPROBE_TARGET") {
+        Some(target) => {
+            let mut parts = target.rsplitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(symbol), Some(path)) => (Some(path.to_string()), Some(symbol.to_string())),
+                _ => (Some(target.to_string()), None),
+            }
         }
+        None => (None, None),
+    };
+    let mut layouts = Vec::new();
+    for layout in m.values_of("LAYOUT").into_iter().flatten() {
+        // the fields have colons, but not the names of the maps
+        let (map, fields) = match layout.find('=') {
+            Some(i) if !layout[..i].contains(':') => {
+                (Some(layout[..i].to_string()), &layout[i + 1..])
+            }
+            _ => (None, layout),
+        };
+        layouts.push((map, fields.parse()?));
+    }
+    let duration = match m.value_of("DURATION") {
+        Some(duration) => Some(
+            parse_duration(duration).ok_or_else(|| format!("invalid duration `{}'", duration))?,
+        ),
+        None => None,
+    };
+
+    Ok(cargo_bpf::LoadOptions {
+        interface: m.value_of("INTERFACE").map(String::from),
+        pid,
+        uprobe_path,
+        uprobe_symbol,
+        format: m.value_of("FORMAT").unwrap().parse()?,
+        layouts,
+        duration,
+        output: m.value_of("OUTPUT").map(PathBuf::from),
+        stats: m.is_present("STATS"),
+        daemon: m.is_present("DAEMON"),
+        pin_dir: m.value_of("PIN_DIR").map(PathBuf::from),
+        socket: m.value_of("SOCKET").map(PathBuf::from),
+    })
+}
+
+// Parses durations like `30s`, `500ms`, `5m` or `1h`, in seconds without a
+// unit
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let value: u64 = duration[..split].parse().ok()?;
+    match &duration[split..] {
+        "ms" => Some(Duration::from_millis(value)),
+        "" | "s" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_secs(value * 60)),
+        "h" => Some(Duration::from_secs(value * 3600)),
+        _ => None,
     }
 }
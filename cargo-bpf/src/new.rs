@@ -13,7 +13,7 @@ use crate::CommandError;
 
 pub fn new(path: &PathBuf, name: Option<&str>) -> Result<(), CommandError> {
     if path.exists() {
-        return Err(CommandError(format!(
+        return Err(CommandError::Message(format!(
             "destination `{}' already exists",
             path.to_str().unwrap()
         )));
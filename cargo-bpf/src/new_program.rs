@@ -5,20 +5,41 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::fs::{self, File, OpenOptions};
+use std::fs::{self, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
 use toml_edit;
 
+use crate::templates;
 use crate::CommandError;
 
-pub fn new_program(name: &str) -> Result<(), CommandError> {
+/// Returns the names of the templates of `new_program()`.
+pub fn program_templates() -> Vec<&'static str> {
+    templates::names()
+}
+
+/// Adds the program `name` to the package in the current directory.
+///
+/// The program is created from `template`, one of the names listed by
+/// `program_templates()`, or from a bare skeleton when it's None.
+pub fn new_program(name: &str, template: Option<&str>) -> Result<(), CommandError> {
     use toml_edit::{value, Array, ArrayOfTables, Document, Item, Table};
 
+    let template = match template {
+        Some(template) => templates::find(template).ok_or_else(|| {
+            CommandError::Message(format!(
+                "unknown template `{}', expected one of {}",
+                template,
+                templates::names().join(", ")
+            ))
+        })?,
+        None => &templates::DEFAULT,
+    };
+
     let current_dir = std::env::current_dir().unwrap();
     let path = Path::new("Cargo.toml");
     if !path.exists() {
-        return Err(CommandError(format!(
+        return Err(CommandError::Message(format!(
             "Could not find `Cargo.toml' in {:?}",
             current_dir
         )));
@@ -29,19 +50,19 @@ pub fn new_program(name: &str) -> Result<(), CommandError> {
     let crate_name = config["lib"]["name"]
         .as_str()
         .or(config["package"]["name"].as_str())
-        .ok_or(CommandError("invalid manifest syntax".to_string()))
+        .ok_or(CommandError::Message("invalid manifest syntax".to_string()))
         .map(String::from)?;
 
     let mut targets = match &config["bin"] {
         Item::None => ArrayOfTables::new(),
         Item::ArrayOfTables(array) => array.clone(),
-        _ => return Err(CommandError(format!("invalid manifest syntax"))),
+        _ => return Err(CommandError::Message(format!("invalid manifest syntax"))),
     };
     if targets
         .iter()
         .any(|target| target["name"].as_str().map(|s| s == name).unwrap_or(false))
     {
-        return Err(CommandError(format!(
+        return Err(CommandError::Message(format!(
             "a program named `{}' already exists",
             name
         )));
@@ -70,61 +91,15 @@ pub fn new_program(name: &str) -> Result<(), CommandError> {
     let probe_dir = src.join(name);
     fs::create_dir_all(probe_dir.clone())?;
 
-    let mod_rs = probe_dir.join("mod.rs");
+    let lib = crate_name.replace('-', "_");
+    let vars = [("lib", lib.as_str()), ("name", name), ("map", template.map)];
     fs::write(
-        mod_rs,
-        r#"
-use cty::*;
-
-// This is where you should define the types shared by the kernel and user
-// space, eg:
-//
-// #[repr(C)]
-// #[derive(Debug)]
-// pub struct SomeEvent {
-//     pub pid: u64,
-//     ...
-// }
-"#,
+        probe_dir.join("mod.rs"),
+        templates::render(template.module, &vars),
     )?;
-    let main_rs = probe_dir.join("main.rs");
-    let mut main_rs = File::create(main_rs)?;
-    write!(
-        &mut main_rs,
-        r#"
-#![no_std]
-#![no_main]
-use cty::*;
-
-// use one of the preludes
-// use redbpf_probes::kprobe::prelude::*;
-// use redbpf_probes::xdp::prelude::*;
-// use redbpf_probes::socket_filter::prelude::*;
-
-// Use the types you're going to share with userspace, eg:
-// use {lib}::{name}::SomeEvent;
-
-program!(0xFFFFFFFE, "GPL");
-
-// The maps and probe functions go here, eg:
-//
-// #[map("syscall_events")]
-// static mut syscall_events: PerfMap<SomeEvent> = PerfMap::with_max_entries(1024);
-//
-// #[kprobe("__x64_sys_open")]
-// fn syscall_enter(regs: Registers) {{
-//   let pid_tgid = bpf_get_current_pid_tgid();
-//   ...
-//
-//   let event = SomeEvent {{
-//     pid: pid_tgid >> 32,
-//     ...
-//   }};
-//   unsafe {{ syscall_events.insert(regs.ctx, &event) }};
-// }}
-"#,
-        lib = crate_name,
-        name = name
+    fs::write(
+        probe_dir.join("main.rs"),
+        templates::render(template.main, &vars),
     )?;
 
     Ok(())
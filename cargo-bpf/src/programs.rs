@@ -0,0 +1,301 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+#[cfg(llvm)]
+use crate::llvm::disassemble;
+use crate::CommandError;
+
+use redbpf::spec::{MapSpec, ModuleSpec, ProgramSpec};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::slice;
+
+// The size of a BPF instruction, in bytes
+const BPF_INSN_SIZE: usize = 8;
+
+/// Prints the programs, maps and globals of the ELF file at `path`, without
+/// loading it.
+pub fn programs(path: &Path) -> Result<(), CommandError> {
+    inspect(path, false)
+}
+
+/// Prints the license, version, programs, maps and globals of the ELF file
+/// at `path` without loading it, as JSON if `json` is set. The maps of each
+/// program are the maps its instructions refer to.
+pub fn inspect(path: &Path, json: bool) -> Result<(), CommandError> {
+    let spec = ModuleSpec::parse(&fs::read(path)?)?;
+    if json {
+        println!("{}", inspect_json(&spec));
+        return Ok(());
+    }
+
+    println!("license: {}", spec.license);
+    println!("version: {:#x}", spec.version);
+    println!("btf: {}", btf_sections(&spec));
+
+    println!("\nprograms:");
+    for prog in spec.programs.iter() {
+        let maps = program_maps(&spec, prog);
+        println!(
+            "  {:<32} {:<14} {:>6} insns  {}{}",
+            prog.name,
+            format!("{:?}", prog.kind),
+            prog.instruction_count(),
+            prog.section,
+            if maps.is_empty() {
+                String::new()
+            } else {
+                format!("  maps: {}", maps.join(", "))
+            }
+        );
+    }
+
+    println!("\nmaps:");
+    for map in spec.maps.iter() {
+        println!(
+            "  {:<32} {:<18} key {:>4}  value {:>6}  max_entries {:>8}  flags {:#x}",
+            map.name,
+            map_type(map),
+            map.def.key_size,
+            map.def.value_size,
+            map.def.max_entries,
+            map.def.map_flags
+        );
+    }
+
+    if !spec.globals.is_empty() {
+        println!("\nglobals:");
+        for global in spec.globals.iter() {
+            let access = if global.read_only {
+                "read-only"
+            } else {
+                "read-write"
+            };
+            println!(
+                "  {:<32} {:>6} bytes  {}",
+                global.name,
+                global.data.len(),
+                access
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the BPF instructions of the programs of the ELF file at `path`,
+/// or of the programs named in `programs` if it's not empty, as JSON if
+/// `json` is set.
+///
+/// Instructions are numbered like in the logs of the verifier, and the ones
+/// loading maps are followed by the name of the map.
+pub fn disasm(path: &Path, programs: &[&str], json: bool) -> Result<(), CommandError> {
+    let spec = ModuleSpec::parse(&fs::read(path)?)?;
+    if let Some(name) = programs.iter().find(|name| spec.program(name).is_none()) {
+        return Err(CommandError::Message(format!(
+            "program `{}' not found in {}",
+            name,
+            path.display()
+        )));
+    }
+
+    let mut out = String::new();
+    let selected = spec
+        .programs
+        .iter()
+        .filter(|prog| programs.is_empty() || programs.contains(&prog.name.as_str()));
+    for (i, prog) in selected.enumerate() {
+        let maps: HashMap<usize, &str> = spec
+            .map_references(&prog.name)
+            .into_iter()
+            .map(|(insn, map)| (insn, map.name.as_str()))
+            .collect();
+        let insns = disassemble(program_code(prog)).map_err(CommandError::Message)?;
+        if json {
+            if i == 0 {
+                write!(out, "{{\"btf\":{},\"programs\":[", spec.has_btf()).unwrap();
+            } else {
+                out.push(',');
+            }
+            let insns: Vec<String> = insns
+                .iter()
+                .map(|(offset, text)| {
+                    let index = offset / BPF_INSN_SIZE;
+                    format!(
+                        "{{\"index\":{},\"text\":{},\"map\":{}}}",
+                        index,
+                        json_string(text),
+                        maps.get(&index)
+                            .map(|map| json_string(map))
+                            .unwrap_or_else(|| "null".to_string())
+                    )
+                })
+                .collect();
+            write!(
+                out,
+                "{{\"name\":{},\"kind\":{},\"section\":{},\"btf_ext\":{},\"instructions\":[{}]}}",
+                json_string(&prog.name),
+                json_string(&format!("{:?}", prog.kind)),
+                json_string(&prog.section),
+                prog.has_btf_ext(),
+                insns.join(",")
+            )
+            .unwrap();
+            continue;
+        }
+
+        if i > 0 {
+            out.push('\n');
+        }
+        writeln!(
+            out,
+            "{}: {} ({:?}), {} insns{}",
+            prog.section,
+            prog.name,
+            prog.kind,
+            prog.instruction_count(),
+            if prog.has_btf_ext() { ", BTF" } else { "" }
+        )
+        .unwrap();
+        for (offset, text) in insns.iter() {
+            let index = offset / BPF_INSN_SIZE;
+            match maps.get(&index) {
+                Some(map) => writeln!(out, "{:>6}: {}  ; map {}", index, text, map),
+                None => writeln!(out, "{:>6}: {}", index, text),
+            }
+            .unwrap();
+        }
+    }
+    if json {
+        if out.is_empty() {
+            write!(out, "{{\"btf\":{},\"programs\":[", spec.has_btf()).unwrap();
+        }
+        out.push_str("]}\n");
+    }
+    print!("{}", out);
+
+    Ok(())
+}
+
+#[cfg(not(llvm))]
+fn disassemble(_code: &[u8]) -> Result<Vec<(usize, String)>, String> {
+    Err(crate::build::Error::NoLLVM.to_string())
+}
+
+fn program_code(prog: &ProgramSpec) -> &[u8] {
+    let insns = prog.instructions();
+    unsafe { slice::from_raw_parts(insns.as_ptr() as *const u8, mem::size_of_val(insns)) }
+}
+
+// The names of the maps used by `prog`, without duplicates
+fn program_maps<'a>(spec: &'a ModuleSpec, prog: &ProgramSpec) -> Vec<&'a str> {
+    let mut maps = Vec::new();
+    for (_, map) in spec.map_references(&prog.name) {
+        if !maps.contains(&map.name.as_str()) {
+            maps.push(map.name.as_str());
+        }
+    }
+
+    maps
+}
+
+// The BTF sections of the ELF file
+fn btf_sections(spec: &ModuleSpec) -> &'static str {
+    let btf_ext = spec.programs.iter().any(|p| p.has_btf_ext());
+    match (spec.has_btf(), btf_ext) {
+        (true, true) => ".BTF, .BTF.ext",
+        (true, false) => ".BTF",
+        (false, _) => "none",
+    }
+}
+
+fn map_type(map: &MapSpec) -> String {
+    match map.type_name() {
+        Some(name) => name.to_string(),
+        None => map.def.type_.to_string(),
+    }
+}
+
+fn inspect_json(spec: &ModuleSpec) -> String {
+    let programs: Vec<String> = spec
+        .programs
+        .iter()
+        .map(|prog| {
+            let maps: Vec<String> = program_maps(spec, prog)
+                .iter()
+                .map(|map| json_string(map))
+                .collect();
+            format!(
+                "{{\"name\":{},\"kind\":{},\"section\":{},\"instructions\":{},\"btf_ext\":{},\"maps\":[{}]}}",
+                json_string(&prog.name),
+                json_string(&format!("{:?}", prog.kind)),
+                json_string(&prog.section),
+                prog.instruction_count(),
+                prog.has_btf_ext(),
+                maps.join(",")
+            )
+        })
+        .collect();
+    let maps: Vec<String> = spec
+        .maps
+        .iter()
+        .map(|map| {
+            format!(
+                "{{\"name\":{},\"type\":{},\"key_size\":{},\"value_size\":{},\"max_entries\":{},\"flags\":{}}}",
+                json_string(&map.name),
+                json_string(&map_type(map)),
+                map.def.key_size,
+                map.def.value_size,
+                map.def.max_entries,
+                map.def.map_flags
+            )
+        })
+        .collect();
+    let globals: Vec<String> = spec
+        .globals
+        .iter()
+        .map(|global| {
+            format!(
+                "{{\"name\":{},\"size\":{},\"read_only\":{}}}",
+                json_string(&global.name),
+                global.data.len(),
+                global.read_only
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"license\":{},\"version\":{},\"btf\":{},\"programs\":[{}],\"maps\":[{}],\"globals\":[{}]}}",
+        json_string(&spec.license),
+        spec.version,
+        spec.has_btf(),
+        programs.join(","),
+        maps.join(","),
+        globals.join(",")
+    )
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
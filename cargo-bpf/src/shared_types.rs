@@ -0,0 +1,1315 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::build::{load_package, resolve, BuildOptions};
+use crate::CommandError;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use syn::{
+    self, BinOp, Expr, Fields, GenericArgument, Item, ItemStatic, Lit, Meta, NestedMeta,
+    PathArguments, Type, UnOp, UseTree,
+};
+use toml_edit::Item as TomlItem;
+
+// The derives kept in the generated types, the others may need dependencies
+// of the probes
+const DERIVES: [&str; 9] = [
+    "Clone",
+    "Copy",
+    "Debug",
+    "Default",
+    "PartialEq",
+    "Eq",
+    "PartialOrd",
+    "Ord",
+    "Hash",
+];
+
+const INT_TYPES: [(&str, u64); 12] = [
+    ("u8", 1),
+    ("i8", 1),
+    ("u16", 2),
+    ("i16", 2),
+    ("u32", 4),
+    ("i32", 4),
+    ("u64", 8),
+    ("i64", 8),
+    ("usize", 8),
+    ("isize", 8),
+    ("u128", 16),
+    ("i128", 16),
+];
+
+// The C types of `cty`, sized like in the BPF target
+const C_TYPES: [(&str, Option<u64>); 14] = [
+    ("c_char", Some(1)),
+    ("c_schar", Some(1)),
+    ("c_uchar", Some(1)),
+    ("c_short", Some(2)),
+    ("c_ushort", Some(2)),
+    ("c_int", Some(4)),
+    ("c_uint", Some(4)),
+    ("c_long", Some(8)),
+    ("c_ulong", Some(8)),
+    ("c_longlong", Some(8)),
+    ("c_ulonglong", Some(8)),
+    ("c_float", Some(4)),
+    ("c_double", Some(8)),
+    ("c_void", None),
+];
+
+/// Generates the definitions of the types the probes of a package share with
+/// userspace, for userspace to include instead of depending on the probes.
+///
+/// The types are those of the keys and values of the `#[map]` statics, and
+/// the types and constants they use, found in the library and the binaries of
+/// the package like `build_package()`. They must be `#[repr(C)]`, or have an
+/// integer `repr` for enums. The module of each type is kept, the types of a
+/// binary being in the module named after it, and each type is followed by
+/// assertions of its size and alignment in the probes, so that userspace
+/// doesn't build if it would lay it out differently.
+///
+/// The output only depends on the sources, so it can be checked in.
+pub fn shared_types(options: &BuildOptions) -> Result<String, CommandError> {
+    let (package, _, _) = resolve(options)?;
+    let doc = load_package(&package)?;
+    let name = doc["package"]["name"].as_str().unwrap_or_default();
+    let mut crate_ = Crate::new(name);
+    let lib = doc["lib"]["path"].as_str().unwrap_or("src/lib.rs");
+    let lib = package.join(lib);
+    if lib.exists() {
+        crate_.lib = Some(crate_.load_root(&lib, Vec::new())?);
+    }
+    if let TomlItem::ArrayOfTables(bins) = &doc["bin"] {
+        for bin in bins.iter() {
+            let name = match bin["name"].as_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            let path = match bin["path"].as_str() {
+                Some(path) => package.join(path),
+                None => package.join("src").join(name).join("main.rs"),
+            };
+            crate_.load_root(&path, vec![name.to_string()])?;
+        }
+    }
+
+    let mut types = SharedTypes::new(&crate_);
+    types.collect().map_err(CommandError::Message)?;
+    types.generate(name).map_err(CommandError::Message)
+}
+
+/// Writes the types shared by the probes of a package to `output`, or to
+/// stdout, see `shared_types()`.
+///
+/// `output` is only written when the types change. With `check`, it's left
+/// as it is and the command fails if it's out of date.
+pub fn cmd_share_types(
+    options: &BuildOptions,
+    output: Option<&Path>,
+    check: bool,
+) -> Result<(), CommandError> {
+    let types = shared_types(options)?;
+    let output = match output {
+        Some(output) => output,
+        None => {
+            print!("{}", types);
+            return Ok(());
+        }
+    };
+    let current = fs::read_to_string(output).ok();
+    if current.as_deref() == Some(types.as_str()) {
+        return Ok(());
+    }
+    if check {
+        return Err(CommandError::Message(format!(
+            "{} is out of date, run `cargo bpf share-types -o {}'",
+            output.display(),
+            output.display()
+        )));
+    }
+    fs::write(output, types)?;
+
+    Ok(())
+}
+
+// A module of the library or of a binary of the probes crate
+struct Module {
+    // the path of the module in the generated file
+    path: Vec<String>,
+    root: usize,
+    parent: Option<usize>,
+    items: HashMap<String, Item>,
+    children: HashMap<String, usize>,
+    uses: HashMap<String, Vec<String>>,
+    globs: Vec<Vec<String>>,
+    maps: Vec<ItemStatic>,
+}
+
+struct Crate {
+    // the name of the library, as binaries refer to it
+    name: String,
+    lib: Option<usize>,
+    modules: Vec<Module>,
+}
+
+// What a path refers to
+enum Target {
+    Item(usize, String),
+    Module(usize),
+    External,
+}
+
+impl Crate {
+    fn new(package: &str) -> Crate {
+        Crate {
+            name: package.replace('-', "_"),
+            lib: None,
+            modules: Vec::new(),
+        }
+    }
+
+    fn load_root(&mut self, file: &Path, path: Vec<String>) -> Result<usize, CommandError> {
+        let items = parse_file(file)?;
+        let dir = file.parent().unwrap().to_path_buf();
+        let index = self.modules.len();
+        self.load_module(&items, file, &dir, path, index, None)
+    }
+
+    // Adds the module with `items` and its submodules. The files of the
+    // submodules are looked up in `dir`, and `file` is where `items` are.
+    fn load_module(
+        &mut self,
+        items: &[Item],
+        file: &Path,
+        dir: &Path,
+        path: Vec<String>,
+        root: usize,
+        parent: Option<usize>,
+    ) -> Result<usize, CommandError> {
+        let index = self.modules.len();
+        self.modules.push(Module {
+            path: path.clone(),
+            root,
+            parent,
+            items: HashMap::new(),
+            children: HashMap::new(),
+            uses: HashMap::new(),
+            globs: Vec::new(),
+            maps: Vec::new(),
+        });
+
+        for item in items {
+            let name = match item {
+                Item::Struct(i) => i.ident.to_string(),
+                Item::Enum(i) => i.ident.to_string(),
+                Item::Union(i) => i.ident.to_string(),
+                Item::Type(i) => i.ident.to_string(),
+                Item::Const(i) => i.ident.to_string(),
+                Item::Static(i) => {
+                    if i.attrs.iter().any(is_map_attr) {
+                        self.modules[index].maps.push(i.clone());
+                    }
+                    continue;
+                }
+                Item::Use(i) => {
+                    let module = &mut self.modules[index];
+                    collect_uses(Vec::new(), &i.tree, &mut module.uses, &mut module.globs);
+                    continue;
+                }
+                Item::Mod(i) => {
+                    let name = i.ident.to_string();
+                    let mut child_path = path.clone();
+                    child_path.push(name.clone());
+                    let child = match &i.content {
+                        Some((_, items)) => self.load_module(
+                            items,
+                            file,
+                            &dir.join(&name),
+                            child_path,
+                            root,
+                            Some(index),
+                        )?,
+                        None => {
+                            let child_file = module_file(file, dir, i)?;
+                            let items = parse_file(&child_file)?;
+                            let child_dir = if child_file.ends_with("mod.rs") {
+                                child_file.parent().unwrap().to_path_buf()
+                            } else {
+                                child_file.with_extension("")
+                            };
+                            self.load_module(
+                                &items,
+                                &child_file,
+                                &child_dir,
+                                child_path,
+                                root,
+                                Some(index),
+                            )?
+                        }
+                    };
+                    self.modules[index].children.insert(name, child);
+                    continue;
+                }
+                _ => continue,
+            };
+            self.modules[index].items.insert(name, item.clone());
+        }
+
+        Ok(index)
+    }
+
+    // Resolves `segments` from `module` like rustc, following the `use`
+    // declarations of the crate
+    fn resolve(&self, module: usize, segments: &[String], depth: usize) -> Target {
+        if segments.is_empty() || depth > 32 {
+            return Target::External;
+        }
+        let mut current = module;
+        let mut rest = segments;
+        match segments[0].as_str() {
+            "crate" => {
+                current = self.modules[module].root;
+                rest = &segments[1..];
+            }
+            "self" => rest = &segments[1..],
+            name if name == self.name && segments.len() > 1 && self.lib.is_some() => {
+                current = self.lib.unwrap();
+                rest = &segments[1..];
+            }
+            _ => {}
+        }
+        while rest.first().map(String::as_str) == Some("super") {
+            current = match self.modules[current].parent {
+                Some(parent) => parent,
+                None => return Target::External,
+            };
+            rest = &rest[1..];
+        }
+
+        let mut target = Target::Module(current);
+        for segment in rest {
+            target = match target {
+                Target::Module(module) => match self.lookup(module, segment, depth) {
+                    Some(target) => target,
+                    None => return Target::External,
+                },
+                _ => return Target::External,
+            };
+        }
+
+        target
+    }
+
+    fn lookup(&self, module: usize, name: &str, depth: usize) -> Option<Target> {
+        let m = &self.modules[module];
+        if m.items.contains_key(name) {
+            return Some(Target::Item(module, name.to_string()));
+        }
+        if let Some(child) = m.children.get(name) {
+            return Some(Target::Module(*child));
+        }
+        if let Some(path) = m.uses.get(name) {
+            return Some(self.resolve(module, path, depth + 1));
+        }
+        for glob in m.globs.iter() {
+            if let Target::Module(glob) = self.resolve(module, glob, depth + 1) {
+                if let Some(target) = self.lookup(glob, name, depth + 1) {
+                    return Some(target);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+fn parse_file(file: &Path) -> Result<Vec<Item>, CommandError> {
+    let source = fs::read_to_string(file)?;
+    syn::parse_file(&source)
+        .map(|f| f.items)
+        .map_err(|e| CommandError::Message(format!("failed to parse {}: {}", file.display(), e)))
+}
+
+// Finds the file of the module declared with `mod name;` in `file`
+fn module_file(file: &Path, dir: &Path, item: &syn::ItemMod) -> Result<PathBuf, CommandError> {
+    for attr in item.attrs.iter().filter(|attr| attr.path.is_ident("path")) {
+        if let Ok(Meta::NameValue(meta)) = attr.parse_meta() {
+            if let Lit::Str(path) = meta.lit {
+                return Ok(file.parent().unwrap().join(path.value()));
+            }
+        }
+    }
+    let name = item.ident.to_string();
+    let candidates = [
+        dir.join(format!("{}.rs", name)),
+        dir.join(&name).join("mod.rs"),
+    ];
+    candidates
+        .iter()
+        .find(|path| path.exists())
+        .cloned()
+        .ok_or_else(|| {
+            CommandError::Message(format!(
+                "could not find the file of module `{}' declared in {}",
+                name,
+                file.display()
+            ))
+        })
+}
+
+fn collect_uses(
+    mut prefix: Vec<String>,
+    tree: &UseTree,
+    uses: &mut HashMap<String, Vec<String>>,
+    globs: &mut Vec<Vec<String>>,
+) {
+    match tree {
+        UseTree::Path(path) => {
+            prefix.push(path.ident.to_string());
+            collect_uses(prefix, &path.tree, uses, globs);
+        }
+        UseTree::Name(name) if name.ident == "self" => {
+            if let Some(last) = prefix.last() {
+                uses.insert(last.clone(), prefix.clone());
+            }
+        }
+        UseTree::Name(name) => {
+            prefix.push(name.ident.to_string());
+            uses.insert(name.ident.to_string(), prefix);
+        }
+        UseTree::Rename(rename) => {
+            if rename.ident != "self" {
+                prefix.push(rename.ident.to_string());
+            }
+            uses.insert(rename.rename.to_string(), prefix);
+        }
+        UseTree::Glob(_) => globs.push(prefix),
+        UseTree::Group(group) => {
+            for tree in group.items.iter() {
+                collect_uses(prefix.clone(), tree, uses, globs);
+            }
+        }
+    }
+}
+
+// A type or a constant of the generated file, by its module and its name
+type DefId = (Vec<String>, String);
+
+enum Ty {
+    Prim { text: String, size: Option<u64> },
+    Array(Box<Ty>, Box<Value>),
+    Ptr(bool, Box<Ty>),
+    Def(DefId),
+}
+
+enum Value {
+    Lit(String, i128),
+    Const(DefId),
+    Binary(Box<Value>, &'static str, Box<Value>),
+    Neg(Box<Value>),
+    Paren(Box<Value>),
+    Cast(Box<Value>, Ty),
+}
+
+struct Def {
+    docs: Vec<String>,
+    derives: Vec<String>,
+    repr: Vec<String>,
+    kind: DefKind,
+}
+
+enum DefKind {
+    Struct(Vec<(Option<String>, Ty)>),
+    Union(Vec<(Option<String>, Ty)>),
+    Enum(Vec<(String, Option<Value>)>),
+    Alias(Ty),
+    Const(Ty, Value),
+}
+
+impl DefKind {
+    // consts come first, then aliases and the other types
+    fn rank(&self) -> u8 {
+        match self {
+            DefKind::Const(..) => 0,
+            DefKind::Alias(_) => 1,
+            _ => 2,
+        }
+    }
+}
+
+struct SharedTypes<'a> {
+    crate_: &'a Crate,
+    defs: BTreeMap<DefId, Def>,
+    // the modules and names of the items already defined
+    done: HashMap<(usize, String), DefId>,
+    defining: HashSet<(usize, String)>,
+}
+
+impl<'a> SharedTypes<'a> {
+    fn new(crate_: &'a Crate) -> SharedTypes<'a> {
+        SharedTypes {
+            crate_,
+            defs: BTreeMap::new(),
+            done: HashMap::new(),
+            defining: HashSet::new(),
+        }
+    }
+
+    fn collect(&mut self) -> Result<(), String> {
+        for (index, module) in self.crate_.modules.iter().enumerate() {
+            for map in module.maps.iter() {
+                let user = format!("map `{}'", map_name(map));
+                for ty in generic_args(&map.ty) {
+                    // the values behind pointers stay in the kernel
+                    if let Type::Ptr(_) = ty {
+                        continue;
+                    }
+                    self.ty(index, ty, &user)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ty(&mut self, module: usize, ty: &Type, user: &str) -> Result<Ty, String> {
+        match ty {
+            Type::Paren(paren) => self.ty(module, &paren.elem, user),
+            Type::Group(group) => self.ty(module, &group.elem, user),
+            Type::Array(array) => Ok(Ty::Array(
+                Box::new(self.ty(module, &array.elem, user)?),
+                Box::new(self.value(module, &array.len, user)?),
+            )),
+            Type::Ptr(ptr) => Ok(Ty::Ptr(
+                ptr.mutability.is_some(),
+                Box::new(self.ty(module, &ptr.elem, user)?),
+            )),
+            Type::Path(path) if path.qself.is_none() => {
+                let text = path_text(&path.path);
+                if path.path.segments.iter().any(|s| !s.arguments.is_empty()) {
+                    return Err(format!(
+                        "the generic type `{}' used by {} can't be shared",
+                        text, user
+                    ));
+                }
+                let segments: Vec<String> = path
+                    .path
+                    .segments
+                    .iter()
+                    .map(|s| s.ident.to_string())
+                    .collect();
+                let last = segments.last().unwrap();
+                match self.crate_.resolve(module, &segments, 0) {
+                    Target::Item(module, name) => {
+                        let id = self.define(module, &name, user)?;
+                        match self.defs[&id].kind {
+                            DefKind::Const(..) => {
+                                Err(format!("`{}' used by {} is not a type", text, user))
+                            }
+                            _ => Ok(Ty::Def(id)),
+                        }
+                    }
+                    _ if segments.len() == 1 && prim_size(last).is_some() => Ok(Ty::Prim {
+                        text: last.clone(),
+                        size: prim_size(last),
+                    }),
+                    _ => match C_TYPES.iter().find(|(name, _)| name == last) {
+                        Some((name, size)) => Ok(Ty::Prim {
+                            text: format!("::std::os::raw::{}", name),
+                            size: *size,
+                        }),
+                        None => Err(format!(
+                            "`{}' used by {} is not defined in the package, it can't be shared",
+                            text, user
+                        )),
+                    },
+                }
+            }
+            _ => Err(format!("a type used by {} can't be shared", user)),
+        }
+    }
+
+    fn value(&mut self, module: usize, expr: &Expr, user: &str) -> Result<Value, String> {
+        match expr {
+            Expr::Lit(lit) => match &lit.lit {
+                Lit::Int(int) => {
+                    let value = int
+                        .base10_parse()
+                        .map_err(|e| format!("invalid integer in {}: {}", user, e))?;
+                    Ok(Value::Lit(int.to_string(), value))
+                }
+                _ => Err(format!("{} has a constant that isn't an integer", user)),
+            },
+            Expr::Path(path) if path.qself.is_none() => {
+                let segments: Vec<String> = path
+                    .path
+                    .segments
+                    .iter()
+                    .map(|s| s.ident.to_string())
+                    .collect();
+                let text = path_text(&path.path);
+                match self.crate_.resolve(module, &segments, 0) {
+                    Target::Item(module, name) => {
+                        let id = self.define(module, &name, user)?;
+                        match self.defs[&id].kind {
+                            DefKind::Const(..) => Ok(Value::Const(id)),
+                            _ => Err(format!("`{}' used by {} is not a constant", text, user)),
+                        }
+                    }
+                    _ => Err(format!(
+                        "the constant `{}' used by {} is not defined in the package",
+                        text, user
+                    )),
+                }
+            }
+            Expr::Binary(binary) => {
+                let op = match binary.op {
+                    BinOp::Add(_) => "+",
+                    BinOp::Sub(_) => "-",
+                    BinOp::Mul(_) => "*",
+                    BinOp::Div(_) => "/",
+                    BinOp::Rem(_) => "%",
+                    BinOp::Shl(_) => "<<",
+                    BinOp::Shr(_) => ">>",
+                    BinOp::BitAnd(_) => "&",
+                    BinOp::BitOr(_) => "|",
+                    BinOp::BitXor(_) => "^",
+                    _ => return Err(format!("{} has an unsupported constant expression", user)),
+                };
+                Ok(Value::Binary(
+                    Box::new(self.value(module, &binary.left, user)?),
+                    op,
+                    Box::new(self.value(module, &binary.right, user)?),
+                ))
+            }
+            Expr::Unary(unary) => match unary.op {
+                UnOp::Neg(_) => Ok(Value::Neg(Box::new(self.value(
+                    module,
+                    &unary.expr,
+                    user,
+                )?))),
+                _ => Err(format!("{} has an unsupported constant expression", user)),
+            },
+            Expr::Paren(paren) => Ok(Value::Paren(Box::new(self.value(
+                module,
+                &paren.expr,
+                user,
+            )?))),
+            Expr::Group(group) => self.value(module, &group.expr, user),
+            Expr::Cast(cast) => Ok(Value::Cast(
+                Box::new(self.value(module, &cast.expr, user)?),
+                self.ty(module, &cast.ty, user)?,
+            )),
+            _ => Err(format!("{} has an unsupported constant expression", user)),
+        }
+    }
+
+    // Adds the definition of the item `name` of `module`, and of the items it
+    // uses
+    fn define(&mut self, module: usize, name: &str, user: &str) -> Result<DefId, String> {
+        let key = (module, name.to_string());
+        if let Some(id) = self.done.get(&key) {
+            return Ok(id.clone());
+        }
+        if !self.defining.insert(key.clone()) {
+            return Err(format!("`{}' is defined in terms of itself", name));
+        }
+        let id = (self.crate_.modules[module].path.clone(), name.to_string());
+        if self.defs.contains_key(&id) {
+            return Err(format!(
+                "two shared items are named `{}', rename one of them",
+                item_path(&id)
+            ));
+        }
+
+        let crate_ = self.crate_;
+        let item = &crate_.modules[module].items[name];
+        let path = item_path(&id);
+        let (attrs, kind) = match item {
+            Item::Struct(s) => {
+                check_generics(&s.generics, &path)?;
+                (
+                    &s.attrs,
+                    DefKind::Struct(self.fields(module, &s.fields, &path)?),
+                )
+            }
+            Item::Union(u) => {
+                check_generics(&u.generics, &path)?;
+                let fields = Fields::Named(u.fields.clone());
+                (
+                    &u.attrs,
+                    DefKind::Union(self.fields(module, &fields, &path)?),
+                )
+            }
+            Item::Enum(e) => {
+                check_generics(&e.generics, &path)?;
+                let mut variants = Vec::new();
+                for variant in e.variants.iter() {
+                    if !variant.fields.is_empty() {
+                        return Err(format!(
+                            "the enum `{}' has variants with fields, it can't be shared",
+                            path
+                        ));
+                    }
+                    let value = match &variant.discriminant {
+                        Some((_, expr)) => {
+                            Some(self.value(module, expr, &format!("`{}'", path))?)
+                        }
+                        None => None,
+                    };
+                    variants.push((variant.ident.to_string(), value));
+                }
+                (&e.attrs, DefKind::Enum(variants))
+            }
+            Item::Type(t) => {
+                check_generics(&t.generics, &path)?;
+                let ty = self.ty(module, &t.ty, &format!("`{}'", path))?;
+                (&t.attrs, DefKind::Alias(ty))
+            }
+            Item::Const(c) => {
+                let user = format!("`{}'", path);
+                let ty = self.ty(module, &c.ty, &user)?;
+                let value = self.value(module, &c.expr, &user)?;
+                (&c.attrs, DefKind::Const(ty, value))
+            }
+            _ => unreachable!(),
+        };
+
+        let mut def = Def {
+            docs: Vec::new(),
+            derives: Vec::new(),
+            repr: Vec::new(),
+            kind,
+        };
+        for attr in attrs.iter() {
+            match attr.parse_meta() {
+                Ok(Meta::NameValue(meta)) if meta.path.is_ident("doc") => {
+                    if let Lit::Str(doc) = meta.lit {
+                        def.docs.push(doc.value());
+                    }
+                }
+                Ok(Meta::List(list)) if list.path.is_ident("derive") => {
+                    for derive in list.nested.iter() {
+                        if let NestedMeta::Meta(Meta::Path(path)) = derive {
+                            let name = path_text(path);
+                            if DERIVES.contains(&name.as_str()) {
+                                def.derives.push(name);
+                            }
+                        }
+                    }
+                }
+                Ok(Meta::List(list)) if list.path.is_ident("repr") => {
+                    for repr in list.nested.iter() {
+                        match repr {
+                            NestedMeta::Meta(Meta::Path(path)) => def.repr.push(path_text(path)),
+                            NestedMeta::Meta(Meta::List(list)) => {
+                                if let Some(NestedMeta::Lit(Lit::Int(n))) = list.nested.first() {
+                                    def.repr.push(format!("{}({})", path_text(&list.path), n));
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let has_repr = |names: &[&str]| def.repr.iter().any(|r| names.contains(&r.as_str()));
+        match def.kind {
+            DefKind::Struct(_) | DefKind::Union(_) if !has_repr(&["C", "transparent"]) => {
+                return Err(format!(
+                    "`{}' is used by {} but is not #[repr(C)], its layout could differ in userspace",
+                    path, user
+                ))
+            }
+            DefKind::Enum(_)
+                if !def.repr.iter().any(|r| r == "C" || int_size(r).is_some()) =>
+            {
+                return Err(format!(
+                    "`{}' is used by {} but has no #[repr(C)] or integer repr, its layout could differ in userspace",
+                    path, user
+                ))
+            }
+            _ => {}
+        }
+
+        self.defining.remove(&key);
+        self.done.insert(key, id.clone());
+        self.defs.insert(id.clone(), def);
+
+        Ok(id)
+    }
+
+    fn fields(
+        &mut self,
+        module: usize,
+        fields: &Fields,
+        path: &str,
+    ) -> Result<Vec<(Option<String>, Ty)>, String> {
+        let user = format!("`{}'", path);
+        fields
+            .iter()
+            .map(|field| {
+                let name = field.ident.as_ref().map(|i| i.to_string());
+                Ok((name, self.ty(module, &field.ty, &user)?))
+            })
+            .collect()
+    }
+
+    fn eval(&self, value: &Value) -> Option<i128> {
+        Some(match value {
+            Value::Lit(_, value) => *value,
+            Value::Const(id) => match &self.defs[id].kind {
+                DefKind::Const(_, value) => self.eval(value)?,
+                _ => return None,
+            },
+            Value::Binary(left, op, right) => {
+                let (left, right) = (self.eval(left)?, self.eval(right)?);
+                match *op {
+                    "+" => left.checked_add(right)?,
+                    "-" => left.checked_sub(right)?,
+                    "*" => left.checked_mul(right)?,
+                    "/" => left.checked_div(right)?,
+                    "%" => left.checked_rem(right)?,
+                    "<<" => left.checked_shl(right.try_into().ok()?)?,
+                    ">>" => left.checked_shr(right.try_into().ok()?)?,
+                    "&" => left & right,
+                    "|" => left | right,
+                    _ => left ^ right,
+                }
+            }
+            Value::Neg(value) => -self.eval(value)?,
+            Value::Paren(value) | Value::Cast(value, _) => self.eval(value)?,
+        })
+    }
+
+    // The size and alignment of `ty` in the probes
+    fn layout(&self, ty: &Ty) -> Result<(u64, u64), String> {
+        match ty {
+            Ty::Prim { text, size } => match size {
+                Some(size) => Ok((*size, cmp::min(*size, 8))),
+                None => Err(format!(
+                    "`{}' has no size, it can only be used behind a pointer",
+                    text
+                )),
+            },
+            Ty::Array(elem, len) => {
+                let (size, align) = self.layout(elem)?;
+                let len = self
+                    .eval(len)
+                    .filter(|len| *len >= 0)
+                    .ok_or_else(|| "the length of an array can't be computed".to_string())?;
+                Ok((size * len as u64, align))
+            }
+            Ty::Ptr(..) => Ok((8, 8)),
+            Ty::Def(id) => self.def_layout(id),
+        }
+    }
+
+    fn def_layout(&self, id: &DefId) -> Result<(u64, u64), String> {
+        let def = &self.defs[id];
+        let mut pack = u64::MAX;
+        let mut min_align = 1;
+        for repr in def.repr.iter() {
+            if repr == "packed" {
+                pack = 1;
+            } else if let Some(n) = repr_arg(repr, "packed") {
+                pack = n;
+            } else if let Some(n) = repr_arg(repr, "align") {
+                min_align = n;
+            }
+        }
+        let (size, align) = match &def.kind {
+            DefKind::Struct(fields) => {
+                let mut offset = 0;
+                let mut align = 1;
+                for (_, ty) in fields.iter() {
+                    let (size, field_align) = self.layout(ty)?;
+                    let field_align = cmp::min(field_align, pack);
+                    offset = round_up(offset, field_align) + size;
+                    align = cmp::max(align, field_align);
+                }
+                (offset, align)
+            }
+            DefKind::Union(fields) => {
+                let mut size = 0;
+                let mut align = 1;
+                for (_, ty) in fields.iter() {
+                    let (field_size, field_align) = self.layout(ty)?;
+                    size = cmp::max(size, field_size);
+                    align = cmp::max(align, cmp::min(field_align, pack));
+                }
+                (size, align)
+            }
+            DefKind::Enum(_) => {
+                let size = def.repr.iter().find_map(|r| int_size(r)).unwrap_or(4);
+                (size, cmp::min(size, 8))
+            }
+            DefKind::Alias(ty) => return self.layout(ty),
+            DefKind::Const(..) => unreachable!(),
+        };
+        let align = cmp::max(align, min_align);
+
+        Ok((round_up(size, align), align))
+    }
+
+    fn generate(&self, package: &str) -> Result<String, String> {
+        let mut modules: BTreeMap<&[String], Vec<(&String, &Def)>> = BTreeMap::new();
+        for ((module, name), def) in self.defs.iter() {
+            modules
+                .entry(module.as_slice())
+                .or_default()
+                .push((name, def));
+        }
+
+        let mut out = format!(
+            "// Generated by `cargo bpf share-types` from the probes of `{}', do not edit\n",
+            package
+        );
+        let mut open: Vec<String> = Vec::new();
+        for (module, mut defs) in modules {
+            while !module.starts_with(&open) {
+                open.pop();
+                writeln!(out, "{}}}", indent(open.len())).unwrap();
+            }
+            while open.len() < module.len() {
+                writeln!(
+                    out,
+                    "\n{}pub mod {} {{",
+                    indent(open.len()),
+                    module[open.len()]
+                )
+                .unwrap();
+                open.push(module[open.len()].clone());
+            }
+            defs.sort_by_key(|(name, def)| (def.kind.rank(), *name));
+            for (name, def) in defs {
+                if !out.ends_with("{\n") {
+                    out.push('\n');
+                }
+                let id = (module.to_vec(), name.clone());
+                self.write_def(&mut out, module, &id, def)
+                    .map_err(|e| format!("`{}': {}", item_path(&id), e))?;
+            }
+        }
+        while !open.is_empty() {
+            open.pop();
+            writeln!(out, "{}}}", indent(open.len())).unwrap();
+        }
+
+        Ok(out)
+    }
+
+    fn write_def(
+        &self,
+        out: &mut String,
+        module: &[String],
+        id: &DefId,
+        def: &Def,
+    ) -> Result<(), String> {
+        let i = indent(module.len());
+        let name = &id.1;
+        for doc in def.docs.iter() {
+            writeln!(out, "{}///{}", i, doc).unwrap();
+        }
+        if !def.derives.is_empty() {
+            writeln!(out, "{}#[derive({})]", i, def.derives.join(", ")).unwrap();
+        }
+        if !def.repr.is_empty() {
+            writeln!(out, "{}#[repr({})]", i, def.repr.join(", ")).unwrap();
+        }
+        match &def.kind {
+            DefKind::Const(ty, value) => {
+                let ty = self.ty_text(module, ty);
+                let value = self.value_text(module, value);
+                writeln!(out, "{}pub const {}: {} = {};", i, name, ty, value).unwrap();
+                return Ok(());
+            }
+            DefKind::Alias(ty) => {
+                writeln!(
+                    out,
+                    "{}pub type {} = {};",
+                    i,
+                    name,
+                    self.ty_text(module, ty)
+                )
+                .unwrap();
+                return Ok(());
+            }
+            DefKind::Struct(fields) if fields.is_empty() => {
+                writeln!(out, "{}pub struct {};", i, name).unwrap()
+            }
+            DefKind::Struct(fields) if fields[0].0.is_none() => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(_, ty)| format!("pub {}", self.ty_text(module, ty)))
+                    .collect();
+                writeln!(out, "{}pub struct {}({});", i, name, fields.join(", ")).unwrap();
+            }
+            DefKind::Struct(fields) | DefKind::Union(fields) => {
+                let keyword = match def.kind {
+                    DefKind::Union(_) => "union",
+                    _ => "struct",
+                };
+                writeln!(out, "{}pub {} {} {{", i, keyword, name).unwrap();
+                for (field, ty) in fields.iter() {
+                    let field = field.as_ref().unwrap();
+                    writeln!(out, "{}    pub {}: {},", i, field, self.ty_text(module, ty)).unwrap();
+                }
+                writeln!(out, "{}}}", i).unwrap();
+            }
+            DefKind::Enum(variants) => {
+                writeln!(out, "{}pub enum {} {{", i, name).unwrap();
+                for (variant, value) in variants.iter() {
+                    match value {
+                        Some(value) => writeln!(
+                            out,
+                            "{}    {} = {},",
+                            i,
+                            variant,
+                            self.value_text(module, value)
+                        ),
+                        None => writeln!(out, "{}    {},", i, variant),
+                    }
+                    .unwrap();
+                }
+                writeln!(out, "{}}}", i).unwrap();
+            }
+        }
+
+        let (size, align) = self.def_layout(id)?;
+        writeln!(
+            out,
+            "{}const _: [(); {}] = [(); ::core::mem::size_of::<{}>()];",
+            i, size, name
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{}const _: [(); {}] = [(); ::core::mem::align_of::<{}>()];",
+            i, align, name
+        )
+        .unwrap();
+
+        Ok(())
+    }
+
+    fn ty_text(&self, module: &[String], ty: &Ty) -> String {
+        match ty {
+            Ty::Prim { text, .. } => text.clone(),
+            Ty::Array(elem, len) => format!(
+                "[{}; {}]",
+                self.ty_text(module, elem),
+                self.value_text(module, len)
+            ),
+            Ty::Ptr(mutable, elem) => format!(
+                "*{} {}",
+                if *mutable { "mut" } else { "const" },
+                self.ty_text(module, elem)
+            ),
+            Ty::Def(id) => relative_path(module, id),
+        }
+    }
+
+    fn value_text(&self, module: &[String], value: &Value) -> String {
+        match value {
+            Value::Lit(text, _) => text.clone(),
+            Value::Const(id) => relative_path(module, id),
+            Value::Binary(left, op, right) => format!(
+                "{} {} {}",
+                self.value_text(module, left),
+                op,
+                self.value_text(module, right)
+            ),
+            Value::Neg(value) => format!("-{}", self.value_text(module, value)),
+            Value::Paren(value) => format!("({})", self.value_text(module, value)),
+            Value::Cast(value, ty) => format!(
+                "{} as {}",
+                self.value_text(module, value),
+                self.ty_text(module, ty)
+            ),
+        }
+    }
+}
+
+// The types of the generic arguments of the type of a map, eg. `K` and `V`
+// of `HashMap<K, V>`
+fn generic_args(ty: &Type) -> Vec<&Type> {
+    let mut args = Vec::new();
+    if let Type::Path(path) = ty {
+        for segment in path.path.segments.iter() {
+            if let PathArguments::AngleBracketed(generics) = &segment.arguments {
+                for arg in generics.args.iter() {
+                    if let GenericArgument::Type(ty) = arg {
+                        args.push(ty);
+                    }
+                }
+            }
+        }
+    }
+
+    args
+}
+
+fn is_map_attr(attr: &syn::Attribute) -> bool {
+    attr.path
+        .segments
+        .last()
+        .map(|s| s.ident == "map")
+        .unwrap_or(false)
+}
+
+fn map_name(map: &ItemStatic) -> String {
+    let attr = map.attrs.iter().find(|attr| is_map_attr(attr));
+    if let Some(Ok(Meta::List(list))) = attr.map(|attr| attr.parse_meta()) {
+        if let Some(NestedMeta::Lit(Lit::Str(name))) = list.nested.first() {
+            return name.value();
+        }
+    }
+
+    map.ident.to_string()
+}
+
+fn check_generics(generics: &syn::Generics, path: &str) -> Result<(), String> {
+    if generics.params.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("the generic type `{}' can't be shared", path))
+    }
+}
+
+fn path_text(path: &syn::Path) -> String {
+    path.segments
+        .iter()
+        .map(|s| s.ident.to_string())
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+fn item_path(id: &DefId) -> String {
+    let mut path = id.0.clone();
+    path.push(id.1.clone());
+    path.join("::")
+}
+
+// The path of `id` from `module`, so that the generated file can be a
+// module of any crate
+fn relative_path(module: &[String], id: &DefId) -> String {
+    let common = module
+        .iter()
+        .zip(id.0.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut path = vec!["super".to_string(); module.len() - common];
+    path.extend(id.0[common..].iter().cloned());
+    path.push(id.1.clone());
+    path.join("::")
+}
+
+fn prim_size(name: &str) -> Option<u64> {
+    match name {
+        "bool" => Some(1),
+        "char" | "f32" => Some(4),
+        "f64" => Some(8),
+        name => int_size(name),
+    }
+}
+
+fn int_size(name: &str) -> Option<u64> {
+    INT_TYPES
+        .iter()
+        .find(|(int, _)| *int == name)
+        .map(|(_, size)| *size)
+}
+
+// Returns `N` of reprs like `align(N)`
+fn repr_arg(repr: &str, name: &str) -> Option<u64> {
+    if repr.starts_with(name) && repr.ends_with(')') {
+        repr[name.len()..repr.len() - 1]
+            .trim_start_matches('(')
+            .parse()
+            .ok()
+    } else {
+        None
+    }
+}
+
+fn round_up(offset: u64, align: u64) -> u64 {
+    offset + (align - offset % align) % align
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+#[cfg(test)]
+mod test {
+    use super::shared_types;
+    use crate::BuildOptions;
+    use std::fs;
+    use std::path::Path;
+
+    const MANIFEST: &str = r#"
+[package]
+name = "my-probes"
+version = "0.1.0"
+edition = "2018"
+
+[[bin]]
+name = "trace"
+path = "src/trace/main.rs"
+"#;
+
+    fn write_package(dir: &Path, files: &[(&str, &str)]) {
+        fs::create_dir_all(dir.join("src/trace")).unwrap();
+        fs::write(dir.join("Cargo.toml"), MANIFEST).unwrap();
+        for (path, source) in files {
+            fs::write(dir.join(path), source).unwrap();
+        }
+    }
+
+    #[test]
+    fn generate_shared_types() {
+        let dir = tempfile::tempdir().unwrap();
+        write_package(
+            dir.path(),
+            &[
+                ("src/lib.rs", "pub mod trace;\npub type Pid = u32;\n"),
+                (
+                    "src/trace/mod.rs",
+                    r#"
+use cty::*;
+
+pub const COMM_LEN: usize = 8;
+
+pub mod kind {
+    #[derive(Clone, Copy, Debug)]
+    #[repr(u8)]
+    pub enum Kind {
+        Open = 1,
+        Close,
+    }
+}
+
+/// An event
+#[derive(Debug)]
+#[repr(C)]
+pub struct Event {
+    pub pid: crate::Pid,
+    pub kind: kind::Kind,
+    pub comm: [c_char; COMM_LEN * 2],
+    pub data: *const c_void,
+}
+
+#[repr(C, packed)]
+pub struct Packed {
+    pub a: u8,
+    pub b: u32,
+}
+
+#[repr(C)]
+pub struct Unused(u64);
+"#,
+                ),
+                (
+                    "src/trace/main.rs",
+                    r#"
+use my_probes::trace::{Event, Packed};
+
+#[repr(C)]
+pub struct Key(pub u32, pub u16);
+
+#[map("events")]
+static mut events: PerfMap<Event> = PerfMap::with_max_entries(1024);
+
+#[map]
+static mut packed: HashMap<Key, Packed> = HashMap::with_max_entries(16);
+"#,
+                ),
+            ],
+        );
+
+        let types = shared_types(&BuildOptions::new(dir.path())).unwrap();
+        assert_eq!(
+            types,
+            r#"// Generated by `cargo bpf share-types` from the probes of `my-probes', do not edit
+
+pub type Pid = u32;
+
+pub mod trace {
+    pub const COMM_LEN: usize = 8;
+
+    /// An event
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct Event {
+        pub pid: super::Pid,
+        pub kind: kind::Kind,
+        pub comm: [::std::os::raw::c_char; COMM_LEN * 2],
+        pub data: *const ::std::os::raw::c_void,
+    }
+    const _: [(); 32] = [(); ::core::mem::size_of::<Event>()];
+    const _: [(); 8] = [(); ::core::mem::align_of::<Event>()];
+
+    #[repr(C)]
+    pub struct Key(pub u32, pub u16);
+    const _: [(); 8] = [(); ::core::mem::size_of::<Key>()];
+    const _: [(); 4] = [(); ::core::mem::align_of::<Key>()];
+
+    #[repr(C, packed)]
+    pub struct Packed {
+        pub a: u8,
+        pub b: u32,
+    }
+    const _: [(); 5] = [(); ::core::mem::size_of::<Packed>()];
+    const _: [(); 1] = [(); ::core::mem::align_of::<Packed>()];
+
+    pub mod kind {
+        #[derive(Clone, Copy, Debug)]
+        #[repr(u8)]
+        pub enum Kind {
+            Open = 1,
+            Close,
+        }
+        const _: [(); 1] = [(); ::core::mem::size_of::<Kind>()];
+        const _: [(); 1] = [(); ::core::mem::align_of::<Kind>()];
+    }
+}
+"#
+        );
+    }
+
+    #[test]
+    fn shared_types_need_repr_c() {
+        let dir = tempfile::tempdir().unwrap();
+        write_package(
+            dir.path(),
+            &[(
+                "src/trace/main.rs",
+                r#"
+pub struct Value {
+    pub count: u64,
+}
+
+#[map("counts")]
+static mut counts: HashMap<u32, Value> = HashMap::with_max_entries(16);
+"#,
+            )],
+        );
+
+        let error = shared_types(&BuildOptions::new(dir.path())).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("`trace::Value' is used by map `counts'"));
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// The templates of the programs created by `cargo bpf add`. They live in
+// the templates directory, and `{{var}}` in them is replaced by the value of
+// `var`: `lib` for the name of the crate, `name` for the name of the program
+// and `map` for the name of its map.
+
+pub struct Template {
+    pub name: &'static str,
+    pub main: &'static str,
+    pub module: &'static str,
+    pub map: &'static str,
+}
+
+macro_rules! template {
+    ($name:expr, $module:expr, $map:expr) => {
+        Template {
+            name: $name,
+            main: include_str!(concat!("../templates/", $name, "/main.rs")),
+            module: include_str!(concat!("../templates/", $module, "/mod.rs")),
+            map: $map,
+        }
+    };
+}
+
+pub const DEFAULT: Template = template!("default", "default", "");
+
+pub const TEMPLATES: &[Template] = &[
+    template!("xdp", "default", "packets"),
+    template!("kprobe", "kprobe", "events"),
+    template!("kretprobe", "kretprobe", "events"),
+    template!("uprobe", "uprobe", "events"),
+    template!("socket-filter", "default", "protocols"),
+    template!("tracepoint", "tracepoint", "events"),
+];
+
+pub fn find(name: &str) -> Option<&'static Template> {
+    TEMPLATES.iter().find(|t| t.name == name)
+}
+
+pub fn names() -> Vec<&'static str> {
+    TEMPLATES.iter().map(|t| t.name).collect()
+}
+
+// Replaces the `{{var}}` placeholders of `template`, and leaves the unknown
+// ones as they are
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+        let var = &rest[2..end];
+        match vars.iter().find(|(name, _)| *name == var) {
+            Some((_, value)) => out.push_str(value),
+            None => out.push_str(&rest[..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+
+    out
+}
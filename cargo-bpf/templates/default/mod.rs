@@ -0,0 +1,12 @@
+
+use cty::*;
+
+// This is where you should define the types shared by the kernel and user
+// space, eg:
+//
+// #[repr(C)]
+// #[derive(Debug)]
+// pub struct SomeEvent {
+//     pub pid: u64,
+//     ...
+// }
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+use redbpf_probes::kprobe::prelude::*;
+use {{lib}}::{{name}}::Event;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("{{map}}")]
+static mut {{map}}: PerfMap<Event> = PerfMap::with_max_entries(1024);
+
+// The program is attached to the function named in the attribute
+#[kprobe("__x64_sys_clone")]
+pub fn {{name}}(regs: Registers) {
+    let event = Event {
+        pid: bpf_get_current_pid_tgid() >> 32,
+        arg: regs.parm1(),
+    };
+    unsafe { {{map}}.insert(regs.ctx, &event) };
+}
+
+// The events can be read from user space with redbpf:
+//
+// use futures::stream::StreamExt;
+// use redbpf::load::Loader;
+// use {{lib}}::{{name}}::Event;
+//
+// let loaded = Loader::new()
+//     .load_file("target/bpf/programs/{{name}}/{{name}}.elf")
+//     .await
+//     .expect("error loading the program");
+// let mut events = loaded.read_events::<Event>("{{map}}").unwrap();
+// while let Some(event) = events.next().await {
+//     match event {
+//         Ok(event) => println!("{:?}", event),
+//         Err(e) => eprintln!("{:?}", e),
+//     }
+// }
@@ -0,0 +1,12 @@
+use redbpf_probes::Pod;
+
+/// Sent to user space each time the probed function returns.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub pid: u64,
+    /// The return value of the function.
+    pub ret: u64,
+}
+
+unsafe impl Pod for Event {}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+use core::mem;
+use redbpf_probes::socket_filter::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// The number of IPv4 packets seen for each IP protocol
+#[map("{{map}}")]
+static mut {{map}}: HashMap<u8, u64> = HashMap::with_max_entries(256);
+
+#[socket_filter]
+pub fn {{name}}(skb: SkBuff) -> SkBuffResult {
+    let eth: ethhdr = skb.read(0)?;
+    if u16::from_be(eth.h_proto) as u32 != ETH_P_IP {
+        return Ok(SkBuffAction::Ignore);
+    }
+    let ip: iphdr = skb.read(mem::size_of::<ethhdr>())?;
+    unsafe {
+        match {{map}}.get_mut(&ip.protocol) {
+            Some(count) => *count += 1,
+            None => {{map}}.set(&ip.protocol, &1),
+        }
+    }
+
+    Ok(SkBuffAction::Ignore)
+}
+
+// The program can be attached to an interface and its map read from user
+// space with redbpf:
+//
+// use redbpf::load::{AttachParams, Loader};
+// use redbpf::HashMap;
+//
+// let loaded = Loader::new()
+//     .attach_params(
+//         "{{name}}",
+//         AttachParams::SocketFilter {
+//             interface: "eth0".to_string(),
+//         },
+//     )
+//     .load_file("target/bpf/programs/{{name}}/{{name}}.elf")
+//     .await
+//     .expect("error loading the program");
+// let counts = HashMap::<u8, u64>::new(loaded.module.map("{{map}}").unwrap()).unwrap();
+// for (protocol, count) in counts.iter() {
+//     println!("{} {}", protocol, count);
+// }
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+use redbpf_probes::tracepoint::prelude::*;
+use {{lib}}::{{name}}::Event;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("{{map}}")]
+static mut {{map}}: PerfMap<Event> = PerfMap::with_max_entries(1024);
+
+// Records of other tracepoints can be generated with `cargo bpf tracepoint`
+#[tracepoint("sock/inet_sock_set_state")]
+pub fn {{name}}(args: &InetSockSetState) {
+    if !args.is_tcp() {
+        return;
+    }
+
+    let event = Event {
+        pid: bpf_get_current_pid_tgid() >> 32,
+        old_state: args.oldstate,
+        new_state: args.newstate,
+        sport: args.sport,
+        dport: args.dport,
+        padding: 0,
+    };
+    unsafe { {{map}}.insert(args as *const _ as *mut c_void, &event) };
+}
+
+// The events can be read from user space with redbpf:
+//
+// use futures::stream::StreamExt;
+// use redbpf::load::Loader;
+// use {{lib}}::{{name}}::Event;
+//
+// let loaded = Loader::new()
+//     .load_file("target/bpf/programs/{{name}}/{{name}}.elf")
+//     .await
+//     .expect("error loading the program");
+// let mut events = loaded.read_events::<Event>("{{map}}").unwrap();
+// while let Some(event) = events.next().await {
+//     match event {
+//         Ok(event) => println!("{:?}", event),
+//         Err(e) => eprintln!("{:?}", e),
+//     }
+// }
@@ -0,0 +1,15 @@
+use redbpf_probes::Pod;
+
+/// Sent to user space each time a TCP socket changes state.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub pid: u64,
+    pub old_state: i32,
+    pub new_state: i32,
+    pub sport: u16,
+    pub dport: u16,
+    pub padding: u32,
+}
+
+unsafe impl Pod for Event {}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+use redbpf_probes::kprobe::prelude::*;
+use {{lib}}::{{name}}::Event;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("{{map}}")]
+static mut {{map}}: PerfMap<Event> = PerfMap::with_max_entries(1024);
+
+// The probed function is given when the program is attached, see below
+#[uprobe]
+pub fn {{name}}(regs: Registers) {
+    let event = Event {
+        pid: bpf_get_current_pid_tgid() >> 32,
+        arg: regs.parm1(),
+    };
+    unsafe { {{map}}.insert(regs.ctx, &event) };
+}
+
+// The program can be attached to a function of a library or executable, and
+// its events read from user space with redbpf:
+//
+// use futures::stream::StreamExt;
+// use redbpf::load::{AttachParams, Loader};
+// use {{lib}}::{{name}}::Event;
+//
+// let loaded = Loader::new()
+//     .attach_params(
+//         "{{name}}",
+//         AttachParams::Uprobe {
+//             path: "/lib/x86_64-linux-gnu/libc.so.6".to_string(),
+//             symbol: "malloc".to_string(),
+//             pid: None,
+//         },
+//     )
+//     .load_file("target/bpf/programs/{{name}}/{{name}}.elf")
+//     .await
+//     .expect("error loading the program");
+// let mut events = loaded.read_events::<Event>("{{map}}").unwrap();
+// while let Some(event) = events.next().await {
+//     match event {
+//         Ok(event) => println!("{:?}", event),
+//         Err(e) => eprintln!("{:?}", e),
+//     }
+// }
@@ -0,0 +1,12 @@
+use redbpf_probes::Pod;
+
+/// Sent to user space on each call of the probed function.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub pid: u64,
+    /// The first argument of the function.
+    pub arg: u64,
+}
+
+unsafe impl Pod for Event {}
@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+use redbpf_probes::xdp::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// The number of IPv4 packets received from each address
+#[map("{{map}}")]
+static mut {{map}}: HashMap<u32, u64> = HashMap::with_max_entries(10240);
+
+#[xdp]
+pub fn {{name}}(ctx: XdpContext) -> XdpResult {
+    let ip = unsafe { *ctx.ip()? };
+    unsafe {
+        match {{map}}.get_mut(&ip.saddr) {
+            Some(count) => *count += 1,
+            None => {{map}}.set(&ip.saddr, &1),
+        }
+    }
+
+    Ok(XdpAction::Pass)
+}
+
+// The program can be loaded and its map read from user space with redbpf:
+//
+// use redbpf::load::Loader;
+// use redbpf::{xdp, HashMap};
+// use std::net::Ipv4Addr;
+//
+// let loaded = Loader::new()
+//     .xdp(Some("eth0".to_string()), xdp::Flags::default())
+//     .load_file("target/bpf/programs/{{name}}/{{name}}.elf")
+//     .await
+//     .expect("error loading the program");
+// let counts = HashMap::<u32, u64>::new(loaded.module.map("{{map}}").unwrap()).unwrap();
+// for (addr, count) in counts.iter() {
+//     println!("{} {}", Ipv4Addr::from(u32::from_be(addr)), count);
+// }
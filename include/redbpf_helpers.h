@@ -13,5 +13,6 @@
 #include <uapi/linux/bpf.h>
 #include <net/sock.h>
 #include <net/inet_sock.h>
+#include <linux/tcp.h>
 #include "bpf_helpers.h"
 #include "xdp.h"
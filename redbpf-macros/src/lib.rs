@@ -49,7 +49,10 @@ use std::str;
 use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
 use syn::token::Comma;
-use syn::{parse_macro_input, parse_quote, parse_str, Expr, ExprLit, File, ItemFn, Lit, Result};
+use syn::{
+    parse_macro_input, parse_quote, parse_str, Expr, ExprLit, File, ItemFn, ItemStatic, Lit, Path,
+    Result, Token,
+};
 
 fn inline_string_literal(e: &Expr) -> (TokenStream2, TokenStream2) {
     let bytes = match e {
@@ -181,6 +184,167 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
     tokens.into()
 }
 
+/// Attribute macro that must be used when declaring read-only configuration
+/// globals.
+///
+/// The global can be set from userspace with `Module::set_global()` before
+/// the programs are loaded. The name defaults to the name of the static and
+/// can be overridden by passing a string literal. Always read the value with
+/// [`config::global()`](https://ingraind.org/api/redbpf_probes/config/fn.global.html),
+/// otherwise the compiler inlines the initial value.
+///
+/// # Example
+/// ```no_run
+/// # use redbpf_probes::kprobe::prelude::*;
+/// use redbpf_probes::config;
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// struct Config {
+///     pid: u32,
+/// }
+///
+/// #[config]
+/// static CONFIG: Config = Config { pid: 0 };
+///
+/// #[kprobe("__x64_sys_clone")]
+/// fn clone_enter(regs: Registers) {
+///     let pid = config::global(&CONFIG).pid;
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn config(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemStatic);
+    let name = if attrs.is_empty() {
+        item.ident.to_string()
+    } else {
+        match parse_macro_input!(attrs as Expr) {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }) => s.value(),
+            _ => panic!("expected string literal"),
+        }
+    };
+    if item.mutability.is_some() {
+        panic!("config globals can't be mutable");
+    }
+
+    let section_name = format!("globals/{}", name);
+    let tokens = quote! {
+        #[no_mangle]
+        #[link_section = #section_name]
+        #item
+    };
+
+    tokens.into()
+}
+
+// `Type, field.field`
+struct FieldPath {
+    ty: Path,
+    fields: Punctuated<Ident, Token![.]>,
+}
+
+impl Parse for FieldPath {
+    fn parse(input: ParseStream) -> Result<FieldPath> {
+        let ty = input.parse()?;
+        input.parse::<Comma>()?;
+        let fields = Punctuated::parse_separated_nonempty(input)?;
+
+        Ok(FieldPath { ty, fields })
+    }
+}
+
+// `base, Type, field.field`
+struct ReadField {
+    base: Expr,
+    path: FieldPath,
+}
+
+impl Parse for ReadField {
+    fn parse(input: ParseStream) -> Result<ReadField> {
+        let base = input.parse()?;
+        input.parse::<Comma>()?;
+        let path = input.parse()?;
+
+        Ok(ReadField { base, path })
+    }
+}
+
+// The address of a symbol of the `fields/<type>.<field>` section, replaced
+// with the offset of the field in the running kernel when the program is
+// loaded
+fn field_offset_impl(path: &FieldPath) -> TokenStream2 {
+    let ty = &path.ty;
+    let fields = path.fields.iter();
+    let type_name = match ty.segments.last() {
+        Some(segment) => segment.ident.to_string(),
+        None => panic!("expected a type"),
+    };
+    let field_names: Vec<String> = path.fields.iter().map(|f| f.to_string()).collect();
+    let section_name = format!("fields/{}.{}", type_name, field_names.join("."));
+
+    quote! {{
+        #[link_section = #section_name]
+        static FIELD: u8 = 0;
+        // the field must exist in the bindings
+        let _ = |t: #ty| t.#(#fields).*;
+        &FIELD as *const u8 as usize
+    }}
+}
+
+/// Returns the offset of a field of a kernel struct in the running kernel.
+///
+/// Takes the name of the struct, and the name of the field or a path of
+/// fields separated by dots, eg. `field_offset!(task_struct, mm)` or
+/// `field_offset!(sock, __sk_common.skc_family)`. The offset is found in the
+/// BTF of the kernel when the program is loaded, so a program built once
+/// runs on all the kernels with BTF, whatever the offsets in the bindings it
+/// was built with. Requires `redbpf` to load the program.
+///
+/// Use [`read_field!`](macro.read_field.html) to read fields.
+#[proc_macro]
+pub fn field_offset(input: TokenStream) -> TokenStream {
+    let path = parse_macro_input!(input as FieldPath);
+    field_offset_impl(&path).into()
+}
+
+/// Reads a field of a kernel struct with `bpf_probe_read`, at its offset in
+/// the running kernel.
+///
+/// Takes a pointer to the struct, the name of the struct and the name of the
+/// field or a path of fields, and returns a `Result` like
+/// `bpf_probe_read()`. The offset of the field is found like with
+/// [`field_offset!`](macro.field_offset.html). Must be called in an
+/// `unsafe` block.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+/// # #[allow(non_camel_case_types)]
+/// # pub struct task_struct { pub pid: i32 }
+///
+/// #[kprobe("wake_up_new_task")]
+/// fn wake_up_new_task(regs: Registers) {
+///     let task = regs.parm1() as *const task_struct;
+///     if let Ok(pid) = unsafe { read_field!(task, task_struct, pid) } {
+///         // pid is read at its offset in the running kernel
+///     }
+/// }
+/// ```
+#[proc_macro]
+pub fn read_field(input: TokenStream) -> TokenStream {
+    let ReadField { base, path } = parse_macro_input!(input as ReadField);
+    let ty = &path.ty;
+    let fields = path.fields.iter();
+    let offset = field_offset_impl(&path);
+    let tokens = quote! {
+        ::redbpf_probes::co_re::read_field(#base as *const #ty, #offset, |t: #ty| t.#(#fields).*)
+    };
+
+    tokens.into()
+}
+
 fn probe_impl(ty: &str, attrs: TokenStream, item: ItemFn, mut name: String) -> TokenStream {
     if !attrs.is_empty() {
         name = match parse_macro_input!(attrs as Expr) {
@@ -253,6 +417,86 @@ pub fn kretprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
     probe_impl("kretprobe", attrs, wrapper, name).into()
 }
 
+/// Attribute macro that must be used to define [`uprobes`](https://www.kernel.org/doc/Documentation/trace/uprobetracer.txt).
+///
+/// The probed function is given when the program is attached with
+/// `redbpf::Program::attach_uprobe()`.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[uprobe]
+/// fn ssl_write(regs: Registers) {
+///     // this is executed when the probed function is invoked
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn uprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let wrapper = wrap_kprobe(item);
+    probe_impl("uprobe", attrs, wrapper, name)
+}
+
+/// Attribute macro that must be used to define [`uretprobes`](https://www.kernel.org/doc/Documentation/trace/uprobetracer.txt).
+///
+/// The probed function is given when the program is attached with
+/// `redbpf::Program::attach_uretprobe()`.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[uretprobe]
+/// fn ssl_write_exit(regs: Registers) {
+///     // this is executed when the probed function returns
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn uretprobe(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let wrapper = wrap_kprobe(item);
+    probe_impl("uretprobe", attrs, wrapper, name)
+}
+
+/// Attribute macro that must be used to define [`tracepoints`](https://www.kernel.org/doc/Documentation/trace/tracepoints.txt).
+///
+/// The argument is the name of the tracepoint in the `category/name` form
+/// used by `/sys/kernel/debug/tracing/events`. The function is passed a
+/// reference to the tracepoint record, either one of the records provided by
+/// `redbpf-probes` or one generated with `cargo bpf tracepoint`.
+///
+/// See also the [`tracepoint` API provided by
+/// `redbpf-probes`](https://ingraind.org/api/redbpf_probes/tracepoint/index.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::tracepoint::prelude::*;
+///
+/// #[tracepoint("sock/inet_sock_set_state")]
+/// fn state_change(args: &InetSockSetState) {
+///     // this is executed when a socket changes state
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn tracepoint(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut c_void) -> i32 {
+            let _ = #ident(unsafe { &*(ctx as *const _) });
+            return 0;
+
+            #item
+        }
+    };
+    probe_impl("tracepoint", attrs, wrapper, name)
+}
+
 /// Attribute macro that must be used to define [`XDP` probes](https://www.iovisor.org/technology/xdp).
 ///
 /// See also the [`XDP` API provided by
@@ -325,3 +569,42 @@ pub fn socket_filter(attrs: TokenStream, item: TokenStream) -> TokenStream {
 
     probe_impl("socketfilter", attrs, wrapper, name).into()
 }
+
+/// Attribute macro that must be used to define traffic control classifier
+/// programs.
+///
+/// The programs are expected to be installed in direct-action mode. See also
+/// the [`tc` API provided by
+/// `redbpf-probes`](https://ingraind.org/api/redbpf_probes/tc/index.html).
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::tc::prelude::*;
+///
+/// #[classifier]
+/// fn probe(ctx: TcContext) -> TcResult {
+///     Ok(TcAction::Ok)
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn classifier(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(skb: *const ::redbpf_probes::bindings::__sk_buff) -> i32 {
+            let ctx = ::redbpf_probes::tc::TcContext {
+                skb: ::redbpf_probes::socket_filter::SkBuff { skb },
+            };
+            return match #ident(ctx) {
+                Ok(action) => action as i32,
+                Err(_) => ::redbpf_probes::tc::TcAction::Ok as i32
+            };
+
+            #item
+        }
+    };
+
+    probe_impl("classifier", attrs, wrapper, name)
+}
@@ -39,6 +39,11 @@ pub use {name}::*;
 }
 
 fn main() {
+    // the bindings change with the kernel headers, see bpf_sys::headers
+    println!("cargo:rerun-if-env-changed=KERNEL_SOURCE");
+    println!("cargo:rerun-if-env-changed=KERNEL_VERSION");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=include");
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let types = ["pt_regs", "s32", "bpf_.*"];
     let vars = ["BPF_.*"];
@@ -52,6 +57,7 @@ fn main() {
         "__sk_.*",
         "sk_.*",
         "inet_sock",
+        "tcp_sock",
     ];
     let xdp_vars = ["ETH_.*", "IPPROTO_.*", "SOCK_.*", "SK_FL_.*", "AF_.*"];
 
@@ -72,7 +78,9 @@ fn main() {
         .to_string();
     let accessors = bpf_bindgen::generate_read_accessors(
         &bindings,
-        &["sock", "file", "inode", "path", "dentry", "qstr"],
+        &[
+            "sock", "tcp_sock", "file", "inode", "path", "dentry", "qstr",
+        ],
     );
     bindings.push_str("use crate::helpers::bpf_probe_read;");
     bindings.push_str(&accessors);
@@ -128,6 +136,21 @@ impl Visit<'_> for RewriteBpfHelpers {
                 }
                 .to_string();
                 ty_s.push_str(&body);
+                // helpers newer than the oldest supported kernel are only
+                // available with the feature of the version adding them
+                if let Some((major, minor)) = bpf_bindgen::helper_version(call_idx) {
+                    let feature = bpf_bindgen::kernel_feature((major, minor)).unwrap();
+                    let doc = format!(
+                        "Available since Linux {}.{}, requires the `{}` feature.",
+                        major, minor, feature
+                    );
+                    ty_s = quote! {
+                        #[doc = #doc]
+                        #[cfg(feature = #feature)]
+                    }
+                    .to_string()
+                        + &ty_s;
+                }
                 let mut helper = ty_s;
                 if helper.contains("printk") {
                     helper = format!("/* {} */", helper);
@@ -0,0 +1,65 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Portable access to the fields of kernel structs.
+
+The offsets of the fields of kernel structs change between kernel versions:
+a program reading `task_struct->pid` at the offset of the bindings it was
+built with reads garbage on other kernels. [`read_field!`] and
+[`field_offset!`] leave the offset to `redbpf`, which finds it in the BTF of
+the kernel the program is loaded in. A program built once runs on all the
+kernels with BTF, Linux 5.4 and later with `CONFIG_DEBUG_INFO_BTF`. Older
+kernels need a BTF generated for them, see `ModuleSpec::relocate()` in
+`redbpf`.
+
+The struct is matched by name, ignoring the suffixes starting with `___`, so
+that bindings of the structs of older kernels can be declared as
+`task_struct___old`. Fields are matched by name, bitfields aren't supported.
+
+# Example
+```no_run
+use redbpf_probes::kprobe::prelude::*;
+# #[allow(non_camel_case_types)]
+# pub struct mm_struct { pub total_vm: u64 }
+# #[allow(non_camel_case_types)]
+# pub struct task_struct { pub pid: i32, pub mm: *const mm_struct }
+
+#[kprobe("wake_up_new_task")]
+fn wake_up_new_task(regs: Registers) {
+    let task = regs.parm1() as *const task_struct;
+    let pid = unsafe { read_field!(task, task_struct, pid) };
+    let mm = unsafe { read_field!(task, task_struct, mm) };
+    if let (Ok(pid), Ok(mm)) = (pid, mm) {
+        let total_vm = unsafe { read_field!(mm, mm_struct, total_vm) };
+    }
+}
+```
+
+[`read_field!`]: ../../redbpf_macros/macro.read_field.html
+[`field_offset!`]: ../../redbpf_macros/macro.field_offset.html
+*/
+use crate::helpers::bpf_probe_read;
+
+pub use redbpf_macros::{field_offset, read_field};
+
+/// Reads the field at `offset` from `base`. `field` is never called, it
+/// gives the type of the field.
+///
+/// Called by `read_field!`, which gets `offset` from `field_offset!`.
+///
+/// # Safety
+///
+/// `base` is read by the kernel, which fails gracefully on invalid
+/// addresses, but isn't otherwise checked.
+#[inline]
+pub unsafe fn read_field<T, F, A>(base: *const T, offset: usize, _field: A) -> Result<F, i32>
+where
+    A: FnOnce(T) -> F,
+{
+    bpf_probe_read((base as *const u8).add(offset) as *const F)
+}
@@ -0,0 +1,95 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Configuration readiness.
+
+Programs reading configuration maps populated from userspace can misbehave
+if they run before the maps are written, eg. treating an empty allowlist as
+deny-all. `redbpf` can be told to refuse attaching programs until the maps
+are initialized, and it signals that the configuration is complete by
+setting `READY_KEY` in the map named `READY_MAP`. Checking the flag from the
+program guards against the programs being attached by other means.
+
+# Example
+```no_run
+use redbpf_probes::kprobe::prelude::*;
+use redbpf_probes::config::{self, Ready};
+
+#[map("__ready")]
+static mut ready: Ready = Ready::with_max_entries(1);
+
+#[kprobe("__x64_sys_clone")]
+fn clone_enter(regs: Registers) {
+    if !config::is_ready(unsafe { &mut ready }) {
+        return;
+    }
+
+    // read the configuration maps
+}
+```
+
+# Globals
+
+Configuration that doesn't change after loading can be declared as a global
+instead, avoiding a map lookup on every event. Globals declared with the
+`#[config]` attribute are set with `Module::set_global()` before the
+programs are loaded, and are read-only for the programs. They require Linux
+5.2 or later.
+
+```no_run
+use redbpf_probes::kprobe::prelude::*;
+use redbpf_probes::config;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Config {
+    pid: u32,
+}
+
+#[config]
+static CONFIG: Config = Config { pid: 0 };
+
+#[kprobe("__x64_sys_clone")]
+fn clone_enter(regs: Registers) {
+    if bpf_get_current_pid_tgid() as u32 != config::global(&CONFIG).pid {
+        return;
+    }
+
+    // trace the process
+}
+```
+*/
+use crate::maps::HashMap;
+
+/// Name of the map signaling readiness.
+pub const READY_MAP: &str = "__ready";
+
+/// Key set once the configuration maps are initialized.
+pub const READY_KEY: u32 = 0;
+
+/// The map signaling readiness.
+pub type Ready = HashMap<u32, u32>;
+
+/// Returns `true` if userspace has finished initializing the configuration
+/// maps.
+#[inline]
+pub fn is_ready(ready: &mut Ready) -> bool {
+    match ready.get(&READY_KEY) {
+        Some(v) => *v != 0,
+        None => false,
+    }
+}
+
+/// Returns the value of the `#[config]` global `global`.
+///
+/// The global is read through a volatile load, so that the compiler doesn't
+/// replace it with its initial value.
+#[inline]
+pub fn global<T: Copy>(global: &T) -> T {
+    unsafe { core::ptr::read_volatile(global) }
+}
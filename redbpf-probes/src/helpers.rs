@@ -11,6 +11,7 @@ Wrappers for the helper functions provided by the BPF subsystem.
 See <http://man7.org/linux/man-pages/man7/bpf-helpers.7.html>.
 */
 use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use crate::bindings::*;
 use cty::*;
@@ -87,12 +88,183 @@ pub fn bpf_get_current_comm() -> [c_char; 16] {
     comm
 }
 
+/// Returns the id of the current thread, `current_task->pid`.
+#[inline]
+pub fn current_pid() -> u32 {
+    bpf_get_current_pid_tgid() as u32
+}
+
+/// Returns the id of the current process, `current_task->tgid`. This is what
+/// userspace calls the PID.
+#[inline]
+pub fn current_tgid() -> u32 {
+    (bpf_get_current_pid_tgid() >> 32) as u32
+}
+
+/// Returns the UID of the current task.
+#[inline]
+pub fn current_uid() -> u32 {
+    bpf_get_current_uid_gid() as u32
+}
+
+/// Returns the GID of the current task.
+#[inline]
+pub fn current_gid() -> u32 {
+    (bpf_get_current_uid_gid() >> 32) as u32
+}
+
+/// Returns the `comm` attribute of the current task as bytes.
+#[inline]
+pub fn current_comm() -> [u8; 16] {
+    let mut comm = [0u8; 16];
+    unsafe { gen::bpf_get_current_comm(&mut comm as *mut _ as *mut c_void, 16u32) };
+    comm
+}
+
+/// Returns the id of the cgroup v2 the current task belongs to.
+///
+/// Available since Linux 4.18.
+#[inline]
+pub fn current_cgroup_id() -> u64 {
+    unsafe { gen::bpf_get_current_cgroup_id() }
+}
+
+/// Information about the current task.
+///
+/// The struct has no padding, so it can be used as it is as a map key or
+/// perf event payload:
+///
+/// ```no_run
+/// use redbpf_probes::helpers::TaskInfo;
+///
+/// let task = TaskInfo::current().with_cgroup_id();
+/// ```
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub pid: u32,
+    pub tgid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Only set by `with_cgroup_id`, zero otherwise.
+    pub cgroup_id: u64,
+    pub comm: [u8; 16],
+}
+
+impl TaskInfo {
+    /// Returns the information about the current task, except the cgroup id.
+    #[inline]
+    pub fn current() -> TaskInfo {
+        let pid_tgid = bpf_get_current_pid_tgid();
+        let uid_gid = bpf_get_current_uid_gid();
+        TaskInfo {
+            pid: pid_tgid as u32,
+            tgid: (pid_tgid >> 32) as u32,
+            uid: uid_gid as u32,
+            gid: (uid_gid >> 32) as u32,
+            cgroup_id: 0,
+            comm: current_comm(),
+        }
+    }
+
+    /// Sets the cgroup id of the current task. Requires Linux 4.18.
+    #[inline]
+    pub fn with_cgroup_id(mut self) -> TaskInfo {
+        self.cgroup_id = current_cgroup_id();
+        self
+    }
+}
+
 /// Returns the time elapsed since system boot, in nanoseconds.
 #[inline]
 pub fn bpf_ktime_get_ns() -> u64 {
     unsafe { gen::bpf_ktime_get_ns() }
 }
 
+/// Returns the time elapsed since system boot, in nanoseconds.
+///
+/// The time spent in suspend isn't included, so differences between two
+/// timestamps measure latencies consistently across CPUs.
+#[inline]
+pub fn ktime_ns() -> u64 {
+    bpf_ktime_get_ns()
+}
+
+/// Returns a pseudo-random number.
+///
+/// The numbers aren't suitable for cryptographic purposes.
+#[inline]
+pub fn rand_u32() -> u32 {
+    unsafe { gen::bpf_get_prandom_u32() }
+}
+
+/// Returns the number of timer ticks since system boot.
+///
+/// Available since Linux 5.5, so it requires the `kernel5_5` feature.
+#[cfg(feature = "kernel5_5")]
+#[inline]
+pub fn jiffies64() -> u64 {
+    unsafe { bpf_jiffies64() }
+}
+
+/// Samples events at random.
+///
+/// Testing `rand_u32() % n == 0` only looks at the low bits of the random
+/// number, which are the weakest ones. `Sampler` instead scales the random
+/// number to the `0..n` range, which depends on its high bits. Like the
+/// modulo, some results are hit by one more random value than the others
+/// when `n` isn't a power of two.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[kprobe("tcp_sendmsg")]
+/// fn sendmsg(regs: Registers) {
+///     // only record 1 in 100 calls
+///     if !Sampler::one_in(100).sample() {
+///         return;
+///     }
+/// }
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct Sampler {
+    n: u32,
+}
+
+impl Sampler {
+    /// Creates a sampler selecting one event in `n` on average. `n` equal
+    /// to 0 never selects any event.
+    #[inline]
+    pub const fn one_in(n: u32) -> Sampler {
+        Sampler { n }
+    }
+
+    /// Returns `true` if the current event should be recorded.
+    #[inline]
+    pub fn sample(&self) -> bool {
+        self.n != 0 && scale(rand_u32(), self.n) == 0
+    }
+}
+
+// Maps `rand` to the `0..n` range using the high bits of the product, which
+// depends on the high bits of `rand` and doesn't need a division
+#[inline]
+fn scale(rand: u32, n: u32) -> u32 {
+    ((u64::from(rand) * u64::from(n)) >> 32) as u32
+}
+
+/// Atomically adds `delta` to `value`.
+///
+/// Values in maps are shared by all the CPUs, so counters in them must be
+/// incremented with this rather than `+=`. This compiles to the `XADD`
+/// instruction, which doesn't return the previous value.
+#[inline]
+pub fn atomic_add(value: &mut u64, delta: u64) {
+    let value = unsafe { &*(value as *mut u64 as *const AtomicU64) };
+    value.fetch_add(delta, Ordering::Relaxed);
+}
+
 // For tracing programs, safely attempt to read `mem::size_of::<T>()` bytes from
 // address src.
 #[inline]
@@ -110,6 +282,127 @@ pub unsafe fn bpf_probe_read<T>(src: *const T) -> Result<T, i32> {
     Ok(v.assume_init())
 }
 
+/// Reads the NUL terminated string at `src` into `dst`.
+///
+/// At most `dst.len() - 1` bytes are copied and `dst` is always NUL
+/// terminated. Returns the length of the string read, excluding the NUL
+/// byte, which can be used to size the data sent to userspace:
+///
+/// ```no_run
+/// use redbpf_probes::helpers::probe_read_str;
+///
+/// # let filename: *const u8 = core::ptr::null();
+/// let mut buf = [0u8; 256];
+/// match unsafe { probe_read_str(&mut buf, filename) } {
+///     Ok(len) => { /* send &buf[..len] */ }
+///     Err(_) => { /* the string couldn't be read */ }
+/// }
+/// ```
+///
+/// # Safety
+///
+/// `src` is read by the kernel, which fails gracefully on invalid addresses,
+/// but isn't otherwise checked.
+#[inline]
+pub unsafe fn probe_read_str(dst: &mut [u8], src: *const u8) -> Result<usize, i64> {
+    let ret = gen::bpf_probe_read_str(
+        dst.as_mut_ptr() as *mut c_void,
+        dst.len() as i32,
+        src as *const c_void,
+    );
+    str_len(ret as i64)
+}
+
+/// Reads the NUL terminated string at the user space address `src` into
+/// `dst`.
+///
+/// Uses `bpf_probe_read_user_str` when the `kernel5_5` feature is enabled,
+/// and falls back to `bpf_probe_read_str` otherwise.
+///
+/// # Safety
+///
+/// See `probe_read_str`.
+#[inline]
+pub unsafe fn probe_read_user_str(dst: &mut [u8], src: *const u8) -> Result<usize, i64> {
+    #[cfg(feature = "kernel5_5")]
+    {
+        let ret = bpf_probe_read_user_str(
+            dst.as_mut_ptr() as *mut c_void,
+            dst.len() as u32,
+            src as *const c_void,
+        );
+        str_len(i64::from(ret))
+    }
+    #[cfg(not(feature = "kernel5_5"))]
+    probe_read_str(dst, src)
+}
+
+/// Reads the NUL terminated string at the kernel address `src` into `dst`.
+///
+/// Uses `bpf_probe_read_kernel_str` when the `kernel5_5` feature is enabled,
+/// and falls back to `bpf_probe_read_str` otherwise.
+///
+/// # Safety
+///
+/// See `probe_read_str`.
+#[inline]
+pub unsafe fn probe_read_kernel_str(dst: &mut [u8], src: *const u8) -> Result<usize, i64> {
+    #[cfg(feature = "kernel5_5")]
+    {
+        let ret = bpf_probe_read_kernel_str(
+            dst.as_mut_ptr() as *mut c_void,
+            dst.len() as u32,
+            src as *const c_void,
+        );
+        str_len(i64::from(ret))
+    }
+    #[cfg(not(feature = "kernel5_5"))]
+    probe_read_str(dst, src)
+}
+
+// The helpers return the length of the string including the NUL byte
+#[inline]
+fn str_len(ret: i64) -> Result<usize, i64> {
+    if ret < 0 {
+        return Err(ret);
+    }
+
+    Ok((ret as usize).saturating_sub(1))
+}
+
+/// Sends the signal `sig` to the process of the current task.
+///
+/// Available since Linux 5.3, so it requires the `kernel5_3` feature. The
+/// signal is delivered when the task leaves the kernel, eg. `SIGKILL`
+/// terminates the process before the probed syscall returns to user space.
+/// On failure, the negative error returned by the kernel is passed on:
+/// `-EPERM` if the current task is a kernel thread or is exiting, `-EINVAL`
+/// for invalid signals and `-EBUSY` if the signal couldn't be queued.
+#[cfg(feature = "kernel5_3")]
+#[inline]
+pub fn send_signal(sig: u32) -> Result<(), i64> {
+    let ret = unsafe { bpf_send_signal(sig) };
+    if ret < 0 {
+        return Err(ret as i64);
+    }
+
+    Ok(())
+}
+
+/// Sends the signal `sig` to the current thread only.
+///
+/// Behaves like `send_signal`, but requires the `kernel5_5` feature.
+#[cfg(feature = "kernel5_5")]
+#[inline]
+pub fn send_signal_thread(sig: u32) -> Result<(), i64> {
+    let ret = unsafe { bpf_send_signal_thread(sig) };
+    if ret < 0 {
+        return Err(ret as i64);
+    }
+
+    Ok(())
+}
+
 #[inline]
 pub fn bpf_trace_printk(message: &[u8]) -> ::cty::c_int {
     unsafe {
@@ -140,4 +433,25 @@ pub fn bpf_perf_event_output(
         ) -> i32 = ::core::mem::transmute(25usize);
         f(ctx, map, flags, data, size)
     }
-}
\ No newline at end of file
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::helpers::scale;
+
+        assert_eq!(scale(0, 3), 0);
+        assert_eq!(scale(u32::MAX, 3), 2);
+        assert_eq!(scale(0x5555_5555, 3), 0);
+        assert_eq!(scale(0x5555_5556, 3), 1);
+        assert_eq!(scale(u32::MAX, 1), 0);
+
+        // each result is hit by the same number of random values, give or
+        // take one
+        let mut hits = [0u32; 3];
+        for rand in (0..=u32::MAX).step_by(1 << 16) {
+            hits[scale(rand, 3) as usize] += 1;
+        }
+        assert!(hits.iter().max().unwrap() - hits.iter().min().unwrap() <= 1);
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! `struct pt_regs` layout of aarch64.
+use crate::bindings::pt_regs;
+use crate::kprobe::stack_arg;
+
+// x0-x7 hold the parameters, but only the first six are exposed to be
+// consistent with the other architectures
+#[inline]
+pub fn parm1(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[0] }
+}
+
+#[inline]
+pub fn parm2(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[1] }
+}
+
+#[inline]
+pub fn parm3(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[2] }
+}
+
+#[inline]
+pub fn parm4(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[3] }
+}
+
+#[inline]
+pub fn parm5(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[4] }
+}
+
+#[inline]
+pub fn parm6(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[5] }
+}
+
+// AAPCS64: x0-x7 hold the first eight arguments, the others are passed on
+// the stack. The return address is in the link register, not on the stack.
+#[inline]
+pub fn arg(regs: &pt_regs, n: usize) -> Option<u64> {
+    if n < 8 {
+        Some(unsafe { regs.__bindgen_anon_1.user_regs.regs[n] })
+    } else {
+        stack_arg(unsafe { regs.__bindgen_anon_1.user_regs.sp }, n - 8)
+    }
+}
+
+// The link register, x30
+#[inline]
+pub fn ret(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[30] }
+}
+
+#[inline]
+pub fn fp(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[29] }
+}
+
+#[inline]
+pub fn rc(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.regs[0] }
+}
+
+#[inline]
+pub fn sp(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.sp }
+}
+
+#[inline]
+pub fn ip(regs: &pt_regs) -> u64 {
+    unsafe { regs.__bindgen_anon_1.user_regs.pc }
+}
@@ -32,7 +32,17 @@ pub fn enter_execve(regs: Registers) {
  */
 pub mod prelude;
 
+#[cfg(target_arch = "aarch64")]
+#[path = "aarch64.rs"]
+mod arch;
+#[cfg(target_arch = "x86_64")]
+#[path = "x86_64.rs"]
+mod arch;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+compile_error!("kprobes are only supported on aarch64 and x86_64");
+
 use crate::bindings::*;
+use crate::helpers::bpf_probe_read;
 use cty::*;
 
 #[derive(Copy, Clone)]
@@ -55,145 +65,133 @@ impl From<*mut c_void> for Registers {
 ///
 /// The only supported architectures by RedBPF are aarch64/arm64 and
 /// x86_64, therefore we only return 64bit registers, as generated by
-/// bindgen.
+/// bindgen. The register layout of each architecture is implemented in its
+/// own module.
 impl Registers {
+    #[inline]
+    fn regs(&self) -> &pt_regs {
+        unsafe { &*self.ctx }
+    }
+
     /// First parameter to the function
     #[inline]
     pub fn parm1(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).di
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[0]
-        }
+        arch::parm1(self.regs())
     }
 
     /// Second parameter to the function
     #[inline]
     pub fn parm2(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).si
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[1]
-        }
+        arch::parm2(self.regs())
     }
 
     /// Third parameter to the function
     #[inline]
     pub fn parm3(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).dx
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[2]
-        }
+        arch::parm3(self.regs())
     }
 
     /// Fourth parameter to the function
     #[inline]
     pub fn parm4(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).cx
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[3]
-        }
+        arch::parm4(self.regs())
     }
 
     /// Fifth parameter to the function
     #[inline]
     pub fn parm5(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).r8
-        }
+        arch::parm5(self.regs())
+    }
 
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[4]
-        }
+    /// Sixth parameter to the function
+    #[inline]
+    pub fn parm6(&self) -> u64 {
+        arch::parm6(self.regs())
+    }
+
+    /// Returns the function argument at `index`, counting from zero.
+    ///
+    /// The arguments that don't fit in registers are read from the stack, so
+    /// this is only correct at the entry of the function, ie. in kprobes
+    /// attached without an offset. Returns `None` if the stack can't be read.
+    #[inline]
+    pub fn arg(&self, index: usize) -> Option<u64> {
+        arch::arg(self.regs(), index)
+    }
+
+    /// Reads the value pointed to by the pointer argument at `index`.
+    ///
+    /// See `arg`.
+    #[inline]
+    pub fn arg_as<T>(&self, index: usize) -> Option<T> {
+        let ptr = self.arg(index)? as *const T;
+        unsafe { bpf_probe_read(ptr).ok() }
     }
 
     /// Procedure link pointer (return to this IP)
     #[inline]
     pub fn ret(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).sp
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[30]
-        }
+        arch::ret(self.regs())
     }
 
     /// Frame pointer
     #[inline]
     pub fn fp(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).bp
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[29]
-        }
+        arch::fp(self.regs())
     }
 
     /// Return value
     #[inline]
     pub fn rc(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).ax
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.regs[0]
-        }
+        arch::rc(self.regs())
     }
 
     /// Stack pointer
     #[inline]
     pub fn sp(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).sp
-        }
-
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.sp
-        }
+        arch::sp(self.regs())
     }
 
     /// Instruction pointer
     #[inline]
     pub fn ip(&self) -> u64 {
-        #[cfg(target_arch = "x86_64")]
-        unsafe {
-            (*self.ctx).ip
-        }
+        arch::ip(self.regs())
+    }
+}
 
-        #[cfg(target_arch = "aarch64")]
-        unsafe {
-            (*self.ctx).__bindgen_anon_1.user_regs.pc
-        }
+// Reads the `n`th stack argument, starting at `base`. Arguments are always
+// passed in 8 byte slots on 64 bit architectures.
+#[inline]
+fn stack_arg(base: u64, n: usize) -> Option<u64> {
+    let addr = base + 8 * n as u64;
+    unsafe { bpf_probe_read(addr as *const u64).ok() }
+}
+
+/// Skips the probed function, making it return `rc` instead.
+///
+/// This is only available with the `error-injection` feature, and can be
+/// used to inject faults in functions marked with `ALLOW_ERROR_INJECTION`
+/// (listed in `/sys/kernel/debug/error_injection/list`). It requires Linux
+/// 4.16 built with `CONFIG_BPF_KPROBE_OVERRIDE` and
+/// `CONFIG_FUNCTION_ERROR_INJECTION`, and only works from kprobes attached
+/// at the entry of the function by GPL programs.
+///
+/// ```no_run
+/// #![no_std]
+/// #![no_main]
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// program!(0xFFFFFFFE, "GPL");
+///
+/// #[kprobe("open_ctree")]
+/// pub fn fail_mount(mut regs: Registers) {
+///     override_return(&mut regs, -12i64 as u64);
+/// }
+/// ```
+#[cfg(feature = "error-injection")]
+#[inline]
+pub fn override_return(regs: &mut Registers, rc: u64) {
+    unsafe {
+        crate::helpers::gen::bpf_override_return(regs.ctx as *mut _, rc);
     }
 }
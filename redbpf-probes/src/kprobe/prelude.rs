@@ -13,7 +13,9 @@
 //! use redbpf_probes::kprobe::prelude::*;
 //! ```
 pub use cty::*;
-pub use redbpf_macros::{kprobe, kretprobe, map, program};
+pub use redbpf_macros::{
+    config, field_offset, kprobe, kretprobe, map, program, read_field, uprobe, uretprobe,
+};
 pub use crate::bindings::*;
 pub use crate::helpers::*;
 pub use crate::maps::*;
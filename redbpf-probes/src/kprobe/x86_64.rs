@@ -0,0 +1,81 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! `struct pt_regs` layout of x86_64.
+use crate::bindings::pt_regs;
+use crate::kprobe::stack_arg;
+
+#[inline]
+pub fn parm1(regs: &pt_regs) -> u64 {
+    regs.di
+}
+
+#[inline]
+pub fn parm2(regs: &pt_regs) -> u64 {
+    regs.si
+}
+
+#[inline]
+pub fn parm3(regs: &pt_regs) -> u64 {
+    regs.dx
+}
+
+#[inline]
+pub fn parm4(regs: &pt_regs) -> u64 {
+    regs.cx
+}
+
+#[inline]
+pub fn parm5(regs: &pt_regs) -> u64 {
+    regs.r8
+}
+
+#[inline]
+pub fn parm6(regs: &pt_regs) -> u64 {
+    regs.r9
+}
+
+// System V ABI: the arguments after the sixth are pushed on the stack, right
+// above the return address
+#[inline]
+pub fn arg(regs: &pt_regs, n: usize) -> Option<u64> {
+    match n {
+        0 => Some(parm1(regs)),
+        1 => Some(parm2(regs)),
+        2 => Some(parm3(regs)),
+        3 => Some(parm4(regs)),
+        4 => Some(parm5(regs)),
+        5 => Some(parm6(regs)),
+        _ => stack_arg(regs.sp + 8, n - 6),
+    }
+}
+
+// The return address is at the top of the stack on function entry
+#[inline]
+pub fn ret(regs: &pt_regs) -> u64 {
+    regs.sp
+}
+
+#[inline]
+pub fn fp(regs: &pt_regs) -> u64 {
+    regs.bp
+}
+
+#[inline]
+pub fn rc(regs: &pt_regs) -> u64 {
+    regs.ax
+}
+
+#[inline]
+pub fn sp(regs: &pt_regs) -> u64 {
+    regs.sp
+}
+
+#[inline]
+pub fn ip(regs: &pt_regs) -> u64 {
+    regs.ip
+}
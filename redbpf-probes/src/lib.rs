@@ -47,13 +47,52 @@ pub fn block_port_80(ctx: XdpContext) -> XdpResult {
 }
 ```
 
+# Kernel versions
+
+The BPF helpers added after Linux 4.20 are only available with the feature
+of the kernel version that introduced them, eg. `kernel5_3` or `kernel5_8`.
+Each feature includes the helpers of the previous versions, and `latest`
+includes all of them. Programs calling helpers the running kernel doesn't
+know about are rejected when they're loaded, so the features make the
+required kernel version explicit. `cargo bpf build` enables the feature
+matching the kernel headers the probes are built with.
+
+# Events
+
+The structs programs output to perf maps are usually shared with the
+userspace crate, which reads them with `redbpf::load::MapEvent::read()`.
+Marking them `Pod` asserts that any bytes are a valid value, so that they
+can be read without `unsafe`:
+
+```
+use redbpf_probes::Pod;
+
+#[repr(C)]
+pub struct Connection {
+    pub pid: u64,
+    pub saddr: u32,
+    pub daddr: u32,
+}
+
+unsafe impl Pod for Connection {}
+```
+
+`redbpf::Pod` is the same trait.
+
 */
 #![deny(clippy::all)]
 #![no_std]
 pub mod bindings;
+pub mod co_re;
+pub mod config;
 pub mod helpers;
 pub mod kprobe;
 pub mod maps;
 pub mod net;
 pub mod socket_filter;
+pub mod tc;
+pub mod tracepoint;
+pub mod usdt;
 pub mod xdp;
+
+pub use zero::Pod;
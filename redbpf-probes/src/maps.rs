@@ -15,10 +15,60 @@ kernel and user-space code.
 use core::default::Default;
 use core::marker::PhantomData;
 use core::mem;
+use core::mem::MaybeUninit;
 use cty::*;
 
 use crate::bindings::*;
 use crate::helpers::*;
+use crate::socket_filter::SkBuff;
+
+/// Flags controlling how map updates treat existing elements.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateFlags {
+    /// Create a new element or update an existing one.
+    Any,
+    /// Only create a new element, fail if it already exists.
+    NoExist,
+    /// Only update an existing element, fail if it doesn't exist.
+    Exist,
+}
+
+impl From<UpdateFlags> for u64 {
+    #[inline]
+    fn from(flags: UpdateFlags) -> u64 {
+        match flags {
+            UpdateFlags::Any => BPF_ANY.into(),
+            UpdateFlags::NoExist => BPF_NOEXIST.into(),
+            UpdateFlags::Exist => BPF_EXIST.into(),
+        }
+    }
+}
+
+/// Errors returned by map updates.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// The element exists and `UpdateFlags::NoExist` was given.
+    Exists,
+    /// The element doesn't exist and `UpdateFlags::Exist` was given.
+    NotFound,
+    /// The map is full.
+    Full,
+    /// Any other error returned by the kernel.
+    Other(c_int),
+}
+
+impl MapError {
+    #[inline]
+    fn from_ret(ret: c_int) -> MapError {
+        // errno values are the same on all the architectures supported by BPF
+        match -ret {
+            17 => MapError::Exists,
+            2 => MapError::NotFound,
+            7 => MapError::Full,
+            _ => MapError::Other(ret),
+        }
+    }
+}
 
 /// Hash table map.
 ///
@@ -33,13 +83,23 @@ pub struct HashMap<K, V> {
 impl<K, V> HashMap<K, V> {
     /// Creates a map with the specified maximum number of elements.
     pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self::with_type(bpf_map_type_BPF_MAP_TYPE_HASH, max_entries, 0)
+    }
+
+    /// Creates a map with the specified maximum number of elements and
+    /// `BPF_F_*` map flags, eg. `BPF_F_NO_PREALLOC`.
+    pub const fn with_flags(max_entries: u32, map_flags: u32) -> Self {
+        Self::with_type(bpf_map_type_BPF_MAP_TYPE_HASH, max_entries, map_flags)
+    }
+
+    const fn with_type(type_: bpf_map_type, max_entries: u32, map_flags: u32) -> Self {
         Self {
             def: bpf_map_def {
-                type_: bpf_map_type_BPF_MAP_TYPE_HASH,
+                type_,
                 key_size: mem::size_of::<K>() as u32,
                 value_size: mem::size_of::<V>() as u32,
                 max_entries,
-                map_flags: 0,
+                map_flags,
             },
             _k: PhantomData,
             _v: PhantomData,
@@ -80,19 +140,440 @@ impl<K, V> HashMap<K, V> {
     /// Set the `value` in the map for `key`
     #[inline]
     pub fn set(&mut self, key: &K, value: &V) {
-        unsafe {
+        let _ = self.set_flags(key, value, UpdateFlags::Any);
+    }
+
+    /// Set the `value` in the map for `key`, depending on whether `key`
+    /// already exists as specified by `flags`.
+    #[inline]
+    pub fn set_flags(&mut self, key: &K, value: &V, flags: UpdateFlags) -> Result<(), MapError> {
+        let ret = unsafe {
             bpf_map_update_elem(
                 &mut self.def as *mut _ as *mut c_void,
                 key as *const _ as *const c_void,
                 value as *const _ as *const c_void,
-                BPF_ANY.into(),
+                flags.into(),
+            )
+        };
+        if ret < 0 {
+            Err(MapError::from_ret(ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key,
+    /// inserting `default` first if the key doesn't exist.
+    ///
+    /// This is the equivalent of BCC's `lookup_or_init()`. Updating the value
+    /// isn't synchronized with other CPUs, see `atomic_add()` for counters.
+    ///
+    /// Returns `None` if the key didn't exist and the map is full.
+    #[inline]
+    pub fn get_or_insert(&mut self, key: &K, default: &V) -> Option<&mut V> {
+        // another CPU may insert the key in between, which is fine
+        let _ = self.set_flags(key, default, UpdateFlags::NoExist);
+        self.get_mut(key)
+    }
+
+    /// Delete the entry indexed by `key`
+    #[inline]
+    pub fn delete(&mut self, key: &K) {
+        unsafe {
+            bpf_map_delete_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
             );
         }
     }
+}
+
+/// LRU hash table map.
+///
+/// High level API for BPF_MAP_TYPE_LRU_HASH and BPF_MAP_TYPE_LRU_PERCPU_HASH
+/// maps. When the map is full, inserting a new element evicts the least
+/// recently used one instead of failing.
+#[repr(transparent)]
+pub struct LruHashMap<K, V> {
+    map: HashMap<K, V>,
+}
+
+impl<K, V> LruHashMap<K, V> {
+    /// Creates a map with the specified maximum number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self::with_flags(max_entries, 0)
+    }
+
+    /// Creates a map with the specified maximum number of elements and
+    /// `BPF_F_*` map flags.
+    ///
+    /// By default the LRU list is shared by all the CPUs. Pass
+    /// `BPF_F_NO_COMMON_LRU` to have a separate list for each CPU, which
+    /// scales better at the cost of less accurate eviction.
+    pub const fn with_flags(max_entries: u32, map_flags: u32) -> Self {
+        LruHashMap {
+            map: HashMap::with_type(bpf_map_type_BPF_MAP_TYPE_LRU_HASH, max_entries, map_flags),
+        }
+    }
+
+    /// Creates a per-CPU map with the specified maximum number of elements
+    /// and `BPF_F_*` map flags.
+    ///
+    /// Each CPU sees its own copy of the values, so they can be modified
+    /// without synchronization.
+    pub const fn per_cpu(max_entries: u32, map_flags: u32) -> Self {
+        LruHashMap {
+            map: HashMap::with_type(
+                bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH,
+                max_entries,
+                map_flags,
+            ),
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    #[inline]
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.map.get(key)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.map.get_mut(key)
+    }
+
+    /// Set the `value` in the map for `key`
+    #[inline]
+    pub fn set(&mut self, key: &K, value: &V) {
+        self.map.set(key, value)
+    }
+
+    /// Set the `value` in the map for `key` as specified by `flags`.
+    ///
+    /// See `HashMap::set_flags()`.
+    #[inline]
+    pub fn set_flags(&mut self, key: &K, value: &V, flags: UpdateFlags) -> Result<(), MapError> {
+        self.map.set_flags(key, value, flags)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key,
+    /// inserting `default` first if the key doesn't exist.
+    ///
+    /// See `HashMap::get_or_insert()`.
+    #[inline]
+    pub fn get_or_insert(&mut self, key: &K, default: &V) -> Option<&mut V> {
+        self.map.get_or_insert(key, default)
+    }
 
     /// Delete the entry indexed by `key`
     #[inline]
     pub fn delete(&mut self, key: &K) {
+        self.map.delete(key)
+    }
+}
+
+/// Array map.
+///
+/// High level API for BPF_MAP_TYPE_ARRAY maps. The elements are shared by
+/// all the CPUs, use atomic operations to modify them concurrently. A
+/// single element array is a cheap way to pass configuration that
+/// userspace can change while the programs run.
+#[repr(transparent)]
+pub struct Array<T> {
+    def: bpf_map_def,
+    _t: PhantomData<T>,
+}
+
+impl<T> Array<T> {
+    /// Creates an array with the specified number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<T>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _t: PhantomData,
+        }
+    }
+
+    /// Returns a reference to the value at `index`.
+    #[inline]
+    pub fn get(&mut self, index: u32) -> Option<&T> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&*(value as *const T))
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the value at `index`.
+    #[inline]
+    pub fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut T))
+            }
+        }
+    }
+
+    /// Set the `value` at `index`.
+    #[inline]
+    pub fn set(&mut self, index: u32, value: &T) {
+        unsafe {
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+                value as *const _ as *const c_void,
+                BPF_ANY.into(),
+            );
+        }
+    }
+}
+
+/// Per-CPU array map.
+///
+/// High level API for BPF_MAP_TYPE_PERCPU_ARRAY maps. Each CPU sees its own
+/// copy of every element, so the values can be modified without
+/// synchronization.
+#[repr(transparent)]
+pub struct PerCpuArray<T> {
+    def: bpf_map_def,
+    _t: PhantomData<T>,
+}
+
+impl<T> PerCpuArray<T> {
+    /// Creates an array with the specified number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<T>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _t: PhantomData,
+        }
+    }
+
+    /// Returns a reference to the current CPU's value at `index`.
+    #[inline]
+    pub fn get(&mut self, index: u32) -> Option<&T> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&*(value as *const T))
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the current CPU's value at `index`.
+    #[inline]
+    pub fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut T))
+            }
+        }
+    }
+
+    /// Set the current CPU's `value` at `index`.
+    #[inline]
+    pub fn set(&mut self, index: u32, value: &T) {
+        unsafe {
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+                value as *const _ as *const c_void,
+                BPF_ANY.into(),
+            );
+        }
+    }
+}
+
+/// Per-CPU scratch memory.
+///
+/// eBPF programs only get 512 bytes of stack, and going over the limit
+/// results in verifier errors that can be hard to decipher. A `StackBuffer`
+/// is a single element `PerCpuArray` used as a replacement for large stack
+/// variables: since programs can't be preempted on the CPU they run on, the
+/// element can be used as scratch space without synchronization.
+///
+/// The contents of the buffer are preserved between invocations of the
+/// program, so make sure to initialize the parts you read.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::xdp::prelude::*;
+///
+/// #[map("buffer")]
+/// static mut buffer: StackBuffer<[u8; 1024]> = StackBuffer::new();
+///
+/// #[xdp]
+/// fn probe(ctx: XdpContext) -> XdpResult {
+///     let buf = unsafe { buffer.get_mut() }.ok_or(NetworkError::Other)?;
+///     // parse the packet into the buffer
+///
+///     Ok(XdpAction::Pass)
+/// }
+/// ```
+#[repr(transparent)]
+pub struct StackBuffer<T> {
+    array: PerCpuArray<T>,
+}
+
+impl<T> StackBuffer<T> {
+    /// Creates a new buffer.
+    pub const fn new() -> Self {
+        StackBuffer {
+            array: PerCpuArray::with_max_entries(1),
+        }
+    }
+
+    /// Returns the current CPU's buffer.
+    ///
+    /// The lookup can only fail if the map wasn't loaded correctly, but the
+    /// verifier still requires the check.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.array.get_mut(0)
+    }
+}
+
+impl<T> Default for StackBuffer<T> {
+    fn default() -> Self {
+        StackBuffer::new()
+    }
+}
+
+/// Key of a `LpmTrie`.
+///
+/// `data` holds the prefix in network byte order, eg. the bytes of an IPv4
+/// address, and `prefix_len` is the number of leading bits of `data` that
+/// are significant.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct LpmKey<T> {
+    pub prefix_len: u32,
+    pub data: T,
+}
+
+impl<T> LpmKey<T> {
+    #[inline]
+    pub fn new(prefix_len: u32, data: T) -> Self {
+        LpmKey { prefix_len, data }
+    }
+}
+
+/// Longest prefix match trie.
+///
+/// High level API for BPF_MAP_TYPE_LPM_TRIE maps. Lookups return the value
+/// of the longest prefix matching the key, which makes them suitable for
+/// matching addresses against CIDR ranges.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::xdp::prelude::*;
+///
+/// #[map("blocklist")]
+/// static mut blocklist: LpmTrie<[u8; 4], u8> = LpmTrie::with_max_entries(1024);
+///
+/// #[xdp]
+/// fn probe(ctx: XdpContext) -> XdpResult {
+///     let ip = unsafe { *ctx.ip()? };
+///     let key = LpmKey::new(32, ip.saddr.to_ne_bytes());
+///     if unsafe { blocklist.lookup(&key) }.is_some() {
+///         return Ok(XdpAction::Drop);
+///     }
+///
+///     Ok(XdpAction::Pass)
+/// }
+/// ```
+#[repr(transparent)]
+pub struct LpmTrie<K, V> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> LpmTrie<K, V> {
+    /// Creates a trie with the specified maximum number of elements.
+    ///
+    /// The kernel only supports LPM tries created with `BPF_F_NO_PREALLOC`,
+    /// so the flag is always set.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_LPM_TRIE,
+                key_size: mem::size_of::<LpmKey<K>>() as u32,
+                value_size: mem::size_of::<V>() as u32,
+                max_entries,
+                map_flags: BPF_F_NO_PREALLOC,
+            },
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    /// Returns a reference to the value of the longest prefix matching
+    /// `key`.
+    #[inline]
+    pub fn lookup(&mut self, key: &LpmKey<K>) -> Option<&V> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&*(value as *const V))
+            }
+        }
+    }
+
+    /// Set the `value` in the trie for the prefix `key`
+    #[inline]
+    pub fn set(&mut self, key: &LpmKey<K>, value: &V) {
+        unsafe {
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+                value as *const _ as *const c_void,
+                BPF_ANY.into(),
+            );
+        }
+    }
+
+    /// Delete the entry for the prefix `key`
+    #[inline]
+    pub fn delete(&mut self, key: &LpmKey<K>) {
         unsafe {
             bpf_map_delete_elem(
                 &mut self.def as *mut _ as *mut c_void,
@@ -102,6 +583,505 @@ impl<K, V> HashMap<K, V> {
     }
 }
 
+/// Array of maps.
+///
+/// High level API for BPF_MAP_TYPE_ARRAY_OF_MAPS maps. Each element is a
+/// map created and stored by userspace, eg. one flow table per tenant. Looking
+/// up an index returns an `InnerMap` to look up elements in.
+///
+/// The inner maps are hash maps of `K` to `V` by default. Userspace creates
+/// them with `redbpf::ArrayOfMaps::create_inner()` so that they match the
+/// definition.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::xdp::prelude::*;
+///
+/// #[map("tenants")]
+/// static mut tenants: ArrayOfMaps<u32, u64> = ArrayOfMaps::with_max_entries(64, 10240);
+///
+/// fn count(tenant: u32, flow: u32) {
+///     if let Some(mut flows) = unsafe { tenants.get(tenant) } {
+///         if let Some(count) = flows.get_mut(&flow) {
+///             *count += 1;
+///         }
+///     }
+/// }
+/// ```
+#[repr(C)]
+pub struct ArrayOfMaps<K, V> {
+    def: bpf_map_def,
+    // read by the loader to create the outer map
+    inner: bpf_map_def,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> ArrayOfMaps<K, V> {
+    /// Creates an array of `max_entries` hash maps, each holding up to
+    /// `inner_max_entries` elements.
+    pub const fn with_max_entries(max_entries: u32, inner_max_entries: u32) -> Self {
+        Self::with_inner(
+            max_entries,
+            bpf_map_type_BPF_MAP_TYPE_HASH,
+            inner_max_entries,
+            0,
+        )
+    }
+
+    /// Creates an array of `max_entries` maps of type `inner_type`, with
+    /// the given maximum number of elements and `BPF_F_*` map flags.
+    pub const fn with_inner(
+        max_entries: u32,
+        inner_type: bpf_map_type,
+        inner_max_entries: u32,
+        inner_flags: u32,
+    ) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_ARRAY_OF_MAPS,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            inner: inner_def::<K, V>(inner_type, inner_max_entries, inner_flags),
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    /// Returns the map at `index`, or `None` if no map is stored there.
+    #[inline]
+    pub fn get(&mut self, index: u32) -> Option<InnerMap<K, V>> {
+        InnerMap::lookup(&mut self.def, &index)
+    }
+}
+
+/// Hash map of maps.
+///
+/// High level API for BPF_MAP_TYPE_HASH_OF_MAPS maps. Behaves like
+/// `ArrayOfMaps`, but the inner maps are stored under keys of type `OK`.
+#[repr(C)]
+pub struct HashOfMaps<OK, K, V> {
+    def: bpf_map_def,
+    // read by the loader to create the outer map
+    inner: bpf_map_def,
+    _ok: PhantomData<OK>,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<OK, K, V> HashOfMaps<OK, K, V> {
+    /// Creates a hash map of up to `max_entries` hash maps, each holding up
+    /// to `inner_max_entries` elements.
+    pub const fn with_max_entries(max_entries: u32, inner_max_entries: u32) -> Self {
+        Self::with_inner(
+            max_entries,
+            bpf_map_type_BPF_MAP_TYPE_HASH,
+            inner_max_entries,
+            0,
+        )
+    }
+
+    /// Creates a hash map of up to `max_entries` maps of type `inner_type`,
+    /// with the given maximum number of elements and `BPF_F_*` map flags.
+    pub const fn with_inner(
+        max_entries: u32,
+        inner_type: bpf_map_type,
+        inner_max_entries: u32,
+        inner_flags: u32,
+    ) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_HASH_OF_MAPS,
+                key_size: mem::size_of::<OK>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            inner: inner_def::<K, V>(inner_type, inner_max_entries, inner_flags),
+            _ok: PhantomData,
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    /// Returns the map stored under `key`, or `None` if there isn't one.
+    #[inline]
+    pub fn get(&mut self, key: &OK) -> Option<InnerMap<K, V>> {
+        InnerMap::lookup(&mut self.def, key)
+    }
+}
+
+const fn inner_def<K, V>(type_: bpf_map_type, max_entries: u32, map_flags: u32) -> bpf_map_def {
+    bpf_map_def {
+        type_,
+        key_size: mem::size_of::<K>() as u32,
+        value_size: mem::size_of::<V>() as u32,
+        max_entries,
+        map_flags,
+    }
+}
+
+/// A map stored in an `ArrayOfMaps` or `HashOfMaps`.
+pub struct InnerMap<K, V> {
+    map: *mut c_void,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> InnerMap<K, V> {
+    #[inline]
+    fn lookup<OK>(outer: &mut bpf_map_def, key: &OK) -> Option<Self> {
+        let map = unsafe {
+            bpf_map_lookup_elem(
+                outer as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            )
+        };
+        if map.is_null() {
+            None
+        } else {
+            Some(InnerMap {
+                map,
+                _k: PhantomData,
+                _v: PhantomData,
+            })
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    #[inline]
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        unsafe {
+            let value = bpf_map_lookup_elem(self.map, key as *const _ as *const c_void);
+            if value.is_null() {
+                None
+            } else {
+                Some(&*(value as *const V))
+            }
+        }
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        unsafe {
+            let value = bpf_map_lookup_elem(self.map, key as *const _ as *const c_void);
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut V))
+            }
+        }
+    }
+
+    /// Set the `value` in the map for `key`, depending on whether `key`
+    /// already exists as specified by `flags`.
+    #[inline]
+    pub fn set_flags(&mut self, key: &K, value: &V, flags: UpdateFlags) -> Result<(), MapError> {
+        let ret = unsafe {
+            bpf_map_update_elem(
+                self.map,
+                key as *const _ as *const c_void,
+                value as *const _ as *const c_void,
+                flags.into(),
+            )
+        };
+        if ret < 0 {
+            Err(MapError::from_ret(ret))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set the `value` in the map for `key`
+    #[inline]
+    pub fn set(&mut self, key: &K, value: &V) {
+        let _ = self.set_flags(key, value, UpdateFlags::Any);
+    }
+
+    /// Delete the entry indexed by `key`
+    #[inline]
+    pub fn delete(&mut self, key: &K) {
+        unsafe {
+            bpf_map_delete_elem(self.map, key as *const _ as *const c_void);
+        }
+    }
+}
+
+/// FIFO queue map.
+///
+/// High level API for BPF_MAP_TYPE_QUEUE maps. Queues have no keys, which
+/// makes them a cheap way to hand work items over to userspace.
+#[repr(transparent)]
+pub struct Queue<T> {
+    def: bpf_map_def,
+    _t: PhantomData<T>,
+}
+
+impl<T> Queue<T> {
+    /// Creates a queue with the specified maximum number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self::with_type(bpf_map_type_BPF_MAP_TYPE_QUEUE, max_entries)
+    }
+
+    const fn with_type(type_: bpf_map_type, max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_,
+                key_size: 0,
+                value_size: mem::size_of::<T>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _t: PhantomData,
+        }
+    }
+
+    /// Pushes `value` to the back of the queue.
+    ///
+    /// If the queue is full and `overwrite` is set, the element at the front
+    /// of the queue is dropped to make room for `value`. Otherwise the
+    /// negative error returned by the kernel is passed on.
+    #[inline]
+    pub fn push(&mut self, value: &T, overwrite: bool) -> Result<(), c_int> {
+        let flags = if overwrite { BPF_EXIST } else { 0 };
+        let ret = unsafe {
+            bpf_map_push_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                value as *const _ as *const c_void,
+                flags.into(),
+            )
+        };
+        if ret < 0 {
+            Err(ret)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Removes and returns the element at the front of the queue.
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let ret = unsafe {
+            bpf_map_pop_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                value.as_mut_ptr() as *mut c_void,
+            )
+        };
+        if ret < 0 {
+            None
+        } else {
+            Some(unsafe { value.assume_init() })
+        }
+    }
+
+    /// Returns the element at the front of the queue without removing it.
+    #[inline]
+    pub fn peek(&mut self) -> Option<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let ret = unsafe {
+            bpf_map_peek_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                value.as_mut_ptr() as *mut c_void,
+            )
+        };
+        if ret < 0 {
+            None
+        } else {
+            Some(unsafe { value.assume_init() })
+        }
+    }
+}
+
+/// LIFO stack map.
+///
+/// High level API for BPF_MAP_TYPE_STACK maps. Behaves like `Queue`, but
+/// elements are popped in reverse insertion order.
+#[repr(transparent)]
+pub struct Stack<T> {
+    queue: Queue<T>,
+}
+
+impl<T> Stack<T> {
+    /// Creates a stack with the specified maximum number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Stack {
+            queue: Queue::with_type(bpf_map_type_BPF_MAP_TYPE_STACK, max_entries),
+        }
+    }
+
+    /// Pushes `value` on top of the stack.
+    ///
+    /// If the stack is full and `overwrite` is set, the element at the
+    /// bottom of the stack is dropped to make room for `value`.
+    #[inline]
+    pub fn push(&mut self, value: &T, overwrite: bool) -> Result<(), c_int> {
+        self.queue.push(value, overwrite)
+    }
+
+    /// Removes and returns the element on top of the stack.
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    /// Returns the element on top of the stack without removing it.
+    #[inline]
+    pub fn peek(&mut self) -> Option<T> {
+        self.queue.peek()
+    }
+}
+
+/// Maximum number of frames stored for each stack trace.
+pub const PERF_MAX_STACK_DEPTH: usize = 127;
+
+/// Stack trace map.
+///
+/// High level API for BPF_MAP_TYPE_STACK_TRACE maps. Programs store the
+/// stack of the current task in the map with `stack_id()`, and userspace
+/// reads the instruction pointers back using the returned id.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[map("stacks")]
+/// static mut stacks: StackTraceMap = StackTraceMap::with_max_entries(1024);
+///
+/// #[kprobe("finish_task_switch")]
+/// fn switch(regs: Registers) {
+///     let kernel_stack = unsafe { stacks.stack_id(regs.ctx, 0) };
+///     let user_stack = unsafe { stacks.stack_id(regs.ctx, BPF_F_USER_STACK.into()) };
+///     // count the stack ids
+/// }
+/// ```
+#[repr(transparent)]
+pub struct StackTraceMap {
+    def: bpf_map_def,
+}
+
+impl StackTraceMap {
+    /// Creates a map with the specified maximum number of stacks.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        StackTraceMap {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_STACK_TRACE,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: (mem::size_of::<u64>() * PERF_MAX_STACK_DEPTH) as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Stores the current kernel stack, or the user stack if `flags`
+    /// includes `BPF_F_USER_STACK`, and returns its id.
+    ///
+    /// `flags` can also include the number of frames to skip, and
+    /// `BPF_F_FAST_STACK_CMP` or `BPF_F_REUSE_STACKID` to control how hash
+    /// collisions are handled. On failure, the negative error returned by the
+    /// kernel is passed on: `-EFAULT` if the stack couldn't be collected,
+    /// `-EEXIST` on an id collision without `BPF_F_REUSE_STACKID` and
+    /// `-ENOMEM` if the map is full.
+    #[inline]
+    pub fn stack_id<C>(&mut self, ctx: *mut C, flags: u64) -> Result<u32, c_int> {
+        let ret = unsafe {
+            bpf_get_stackid(
+                ctx as *mut _,
+                &mut self.def as *mut _ as *mut c_void,
+                flags,
+            )
+        };
+        if ret < 0 {
+            Err(ret)
+        } else {
+            Ok(ret as u32)
+        }
+    }
+}
+
+/// Perf event array holding hardware counters.
+///
+/// High level API for BPF_MAP_TYPE_PERF_EVENT_ARRAY maps used to read perf
+/// counters, eg. CPU cycles or cache misses. Unlike `PerfMap`, which sends
+/// events to userspace, the elements of this map are counters opened by
+/// userspace, one for each CPU, using
+/// `redbpf::PerfEventArray::attach_perf_counters`.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[map("cycles")]
+/// static mut cycles: PerfEventArray = PerfEventArray::with_max_entries(128);
+///
+/// #[kprobe("tcp_sendmsg")]
+/// fn sendmsg(regs: Registers) {
+///     if let Ok(value) = unsafe { cycles.read_current_cpu() } {
+///         // store value.counter and compare it on return
+///     }
+/// }
+/// ```
+#[repr(transparent)]
+pub struct PerfEventArray {
+    def: bpf_map_def,
+}
+
+impl PerfEventArray {
+    /// Creates a map with the specified maximum number of counters.
+    ///
+    /// Counters are stored by CPU id, so `max_entries` must be at least the
+    /// number of CPUs.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        PerfEventArray {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Reads the counter at `index`, along with the time it's been enabled
+    /// and running for.
+    ///
+    /// The kernel multiplexes counters when there aren't enough of them, so
+    /// `running` can be less than `enabled`, in which case the counter
+    /// should be scaled by `enabled / running`. Per-CPU counters can only
+    /// be read on the CPU they count, use `read_current_cpu()` for those.
+    /// On failure, the negative error returned by the kernel is passed on:
+    /// `-ENOENT` if there's no counter at `index` and `-EINVAL` if the
+    /// counter can't be read from the current CPU.
+    #[inline]
+    pub fn read_counter(&mut self, index: u32) -> Result<bpf_perf_event_value, c_int> {
+        let mut value = MaybeUninit::<bpf_perf_event_value>::uninit();
+        let ret = unsafe {
+            bpf_perf_event_read_value(
+                &mut self.def as *mut _ as *mut c_void,
+                u64::from(index),
+                value.as_mut_ptr() as *mut _,
+                mem::size_of::<bpf_perf_event_value>() as u32,
+            )
+        };
+        if ret < 0 {
+            Err(ret)
+        } else {
+            Ok(unsafe { value.assume_init() })
+        }
+    }
+
+    /// Reads the counter of the current CPU.
+    #[inline]
+    pub fn read_current_cpu(&mut self) -> Result<bpf_perf_event_value, c_int> {
+        self.read_counter(BPF_F_CURRENT_CPU)
+    }
+}
+
 /// Flags that can be passed to `PerfMap::insert_with_flags`.
 #[derive(Debug, Copy, Clone)]
 pub struct PerfMapFlags {
@@ -148,8 +1128,28 @@ impl PerfMapFlags {
         self.xdp_size = size;
         self
     }
+
+    /// Create flags for events carrying `size` extra bytes of socket buffer
+    /// data.
+    #[inline]
+    pub fn with_skb_size(size: u32) -> Self {
+        *PerfMapFlags::new().skb_size(size)
+    }
+
+    /// Set the number of bytes of the socket buffer data to append to the
+    /// event.
+    ///
+    /// The kernel rejects sizes larger than the packet.
+    #[inline]
+    pub fn skb_size(&mut self, size: u32) -> &mut PerfMapFlags {
+        self.xdp_size = size & MAX_PAYLOAD_SIZE;
+        self
+    }
 }
 
+// Number of bits of the payload size in the flags
+const MAX_PAYLOAD_SIZE: u32 = 0xfffff;
+
 impl From<PerfMapFlags> for u64 {
     #[inline]
     fn from(flags: PerfMapFlags) -> u64 {
@@ -208,4 +1208,22 @@ impl<T> PerfMap<T> {
             mem::size_of::<T>() as u64,
         );
     }
+
+    /// Insert an event made of `header` followed by the first `capture_len`
+    /// bytes of the packet in `skb`, keyed by the current CPU number.
+    ///
+    /// `capture_len` is capped to the length of the packet. The kernel pads
+    /// events to 8 bytes, so the header should record the length of the
+    /// payload if userspace needs it. See `redbpf::split_sample()` for the
+    /// userspace counterpart.
+    #[inline]
+    pub fn output_skb(&mut self, skb: &SkBuff, header: &T, capture_len: u32) {
+        let len = unsafe { (*skb.skb).len };
+        let capture_len = if capture_len > len { len } else { capture_len };
+        self.insert_with_flags(
+            skb.skb as *mut __sk_buff,
+            header,
+            PerfMapFlags::with_skb_size(capture_len),
+        )
+    }
 }
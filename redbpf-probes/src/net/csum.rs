@@ -0,0 +1,118 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Incremental Internet checksum updates.
+
+Programs rewriting addresses or ports need to fix the IP, TCP and UDP
+checksums. XDP programs can't use the `bpf_l3_csum_replace` and
+`bpf_l4_csum_replace` helpers, so this module implements the incremental
+update of [RFC 1624](https://tools.ietf.org/html/rfc1624).
+
+The values are expected in the byte order they're stored in the packet, so
+they can be passed without conversions.
+
+# Example
+
+Rewrite the destination address of an IPv4 packet:
+```no_run
+use redbpf_probes::net::csum;
+use redbpf_probes::xdp::prelude::*;
+
+#[xdp]
+fn rewrite(ctx: XdpContext) -> XdpResult {
+    unsafe {
+        let ip = ctx.ip()? as *mut iphdr;
+        let daddr = u32::to_be(0x0a00_0001);
+        csum::replace_u32(&mut (*ip).check, (*ip).daddr, daddr);
+        (*ip).daddr = daddr;
+    }
+
+    Ok(XdpAction::Pass)
+}
+```
+*/
+
+/// Folds a 32 bit one's complement sum into 16 bits.
+#[inline]
+pub fn fold16(sum: u32) -> u16 {
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    sum as u16
+}
+
+/// Returns the one's complement sum of `to` minus `from`, added to `seed`.
+///
+/// This is the same as the `bpf_csum_diff` helper. The result can be
+/// folded with `fold16`.
+#[inline]
+pub fn csum_diff(from: &[u32], to: &[u32], seed: u32) -> u32 {
+    let mut sum = u64::from(seed);
+    for word in from.iter() {
+        sum += u64::from(!*word);
+    }
+    for word in to.iter() {
+        sum += u64::from(*word);
+    }
+    let sum = (sum & 0xffff_ffff) + (sum >> 32);
+    let sum = (sum & 0xffff_ffff) + (sum >> 32);
+    sum as u32
+}
+
+/// Returns the checksum of `words`.
+#[inline]
+pub fn checksum(words: &[u16]) -> u16 {
+    let mut sum = 0u32;
+    for word in words.iter() {
+        sum += u32::from(*word);
+    }
+    !fold16(sum)
+}
+
+/// Updates `check` after a 16 bit field changed from `old` to `new`.
+#[inline]
+pub fn replace_u16(check: &mut u16, old: u16, new: u16) {
+    let sum = u32::from(!*check) + u32::from(!old) + u32::from(new);
+    *check = !fold16(sum);
+}
+
+/// Updates `check` after a 32 bit field changed from `old` to `new`.
+#[inline]
+pub fn replace_u32(check: &mut u16, old: u32, new: u32) {
+    let sum = csum_diff(&[old], &[new], u32::from(!*check));
+    *check = !fold16(sum);
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::net::csum::{checksum, fold16, replace_u16, replace_u32};
+
+        assert_eq!(fold16(0x0001_fffe), 0xffff);
+        assert_eq!(fold16(0x0002_ffff), 0x0002);
+
+        let mut header: [u16; 10] = [
+            0x4500, 0x0073, 0x0000, 0x4000, 0x4011, 0x0000, 0xc0a8, 0x0001, 0xc0a8, 0x00c7,
+        ];
+        assert_eq!(checksum(&header), 0xb861);
+        header[5] = 0xb861;
+        assert_eq!(checksum(&header), 0);
+
+        // daddr 192.168.0.199 -> 10.1.2.3
+        let mut check = header[5];
+        replace_u32(&mut check, 0xc0a8_00c7, 0x0a01_0203);
+        header[5] = 0;
+        header[8] = 0x0a01;
+        header[9] = 0x0203;
+        assert_eq!(check, checksum(&header));
+
+        // ttl 64 -> 63
+        replace_u16(&mut check, 0x4011, 0x3f11);
+        header[4] = 0x3f11;
+        assert_eq!(check, checksum(&header));
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+DNS message parsing.
+
+Parses the header and the questions of DNS messages carried in the payload
+of UDP packets. All the loops have fixed bounds, so that the verifier
+accepts them: names with more than `MAX_LABELS` labels and messages with
+more than `MAX_QUESTIONS` questions are rejected or truncated.
+
+# Example
+
+Drop queries for names starting with `ads`:
+```no_run
+use redbpf_probes::net::dns::{Message, NAME_LEN};
+use redbpf_probes::xdp::prelude::*;
+
+#[xdp]
+fn block_ads(ctx: XdpContext) -> XdpResult {
+    let transport = ctx.transport()?;
+    if transport.dest() != 53 {
+        return Ok(XdpAction::Pass);
+    }
+
+    let data = ctx.data()?;
+    let message = Message::parse(&data)?;
+    let mut questions = message.questions();
+    let mut name = [0u8; NAME_LEN];
+    if let Some(question) = questions.next(&mut name) {
+        let question = question?;
+        if question.name_len >= 3 && &name[..3] == b"ads" {
+            return Ok(XdpAction::Drop);
+        }
+    }
+
+    Ok(XdpAction::Pass)
+}
+```
+*/
+use crate::net::{Data, NetworkBuffer, NetworkError};
+
+/// The length of the buffers names are decoded into.
+pub const NAME_LEN: usize = 256;
+/// The maximum number of labels of a name.
+pub const MAX_LABELS: usize = 16;
+/// The maximum number of questions returned by `Questions`.
+pub const MAX_QUESTIONS: usize = 4;
+
+const HEADER_LEN: usize = 12;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Errors parsing DNS messages.
+pub enum DnsError {
+    /// The message is truncated.
+    Truncated,
+    /// The name uses compression, which isn't supported in questions.
+    Compressed,
+    /// The name is invalid or longer than supported.
+    InvalidName,
+}
+
+impl From<NetworkError> for DnsError {
+    fn from(_e: NetworkError) -> DnsError {
+        DnsError::Truncated
+    }
+}
+
+impl From<DnsError> for NetworkError {
+    fn from(_e: DnsError) -> NetworkError {
+        NetworkError::Other
+    }
+}
+
+pub type DnsResult<T> = Result<T, DnsError>;
+
+/// The header of a DNS message, in host byte order.
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub id: u16,
+    pub flags: u16,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+}
+
+impl Header {
+    /// Returns `true` if the message is a response.
+    #[inline]
+    pub fn is_response(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+
+    /// Returns the response code.
+    #[inline]
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0xf) as u8
+    }
+}
+
+/// A DNS message.
+pub struct Message<'a, T: NetworkBuffer> {
+    data: &'a Data<T>,
+    header: Header,
+}
+
+impl<'a, T: NetworkBuffer> Message<'a, T> {
+    /// Parses the header of the message in `data`.
+    #[inline]
+    pub fn parse(data: &'a Data<T>) -> DnsResult<Message<'a, T>> {
+        let header = Header {
+            id: be16(data, 0)?,
+            flags: be16(data, 2)?,
+            qdcount: be16(data, 4)?,
+            ancount: be16(data, 6)?,
+            nscount: be16(data, 8)?,
+            arcount: be16(data, 10)?,
+        };
+
+        Ok(Message { data, header })
+    }
+
+    /// Returns the header of the message.
+    #[inline]
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// Returns the questions of the message.
+    #[inline]
+    pub fn questions(&self) -> Questions<'a, T> {
+        Questions {
+            data: self.data,
+            offset: HEADER_LEN,
+            remaining: self.header.qdcount as usize,
+            returned: 0,
+        }
+    }
+}
+
+/// A question of a DNS message.
+#[derive(Clone, Copy, Debug)]
+pub struct Question {
+    /// The length of the decoded name.
+    pub name_len: usize,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// The questions of a DNS message.
+///
+/// This works like an iterator, but decodes each name into a buffer
+/// supplied by the caller.
+pub struct Questions<'a, T: NetworkBuffer> {
+    data: &'a Data<T>,
+    offset: usize,
+    remaining: usize,
+    returned: usize,
+}
+
+impl<'a, T: NetworkBuffer> Questions<'a, T> {
+    /// Decodes the next question, writing its name into `name` as a dot
+    /// separated string.
+    ///
+    /// Returns `None` after the last question, or after `MAX_QUESTIONS`
+    /// questions.
+    #[inline]
+    pub fn next(&mut self, name: &mut [u8; NAME_LEN]) -> Option<DnsResult<Question>> {
+        if self.remaining == 0 || self.returned == MAX_QUESTIONS {
+            return None;
+        }
+        self.remaining -= 1;
+        self.returned += 1;
+
+        Some(self.parse_question(name))
+    }
+
+    #[inline]
+    fn parse_question(&mut self, name: &mut [u8; NAME_LEN]) -> DnsResult<Question> {
+        let (name_len, offset) = decode_name(self.data, self.offset, name)?;
+        let question = Question {
+            name_len,
+            qtype: be16(self.data, offset)?,
+            qclass: be16(self.data, offset + 2)?,
+        };
+        self.offset = offset + 4;
+
+        Ok(question)
+    }
+}
+
+// Returns the length of the decoded name and the offset following it
+#[inline]
+fn decode_name<T: NetworkBuffer>(
+    data: &Data<T>,
+    mut offset: usize,
+    name: &mut [u8; NAME_LEN],
+) -> DnsResult<(usize, usize)> {
+    let mut len = 0;
+    for _ in 0..MAX_LABELS {
+        let label_len = byte(data, offset)? as usize;
+        offset += 1;
+        if label_len == 0 {
+            return Ok((len, offset));
+        }
+        if label_len & 0xc0 == 0xc0 {
+            return Err(DnsError::Compressed);
+        }
+        if label_len > MAX_LABEL_LEN || len + label_len + 1 >= NAME_LEN {
+            return Err(DnsError::InvalidName);
+        }
+
+        if len > 0 {
+            name[len] = b'.';
+            len += 1;
+        }
+        for i in 0..MAX_LABEL_LEN {
+            if i == label_len {
+                break;
+            }
+            name[len] = byte(data, offset + i)?;
+            len += 1;
+        }
+        offset += label_len;
+    }
+
+    Err(DnsError::InvalidName)
+}
+
+#[inline]
+fn byte<T: NetworkBuffer>(data: &Data<T>, offset: usize) -> DnsResult<u8> {
+    let addr = data.base + offset;
+    data.ctx.check_bounds(addr, addr + 1)?;
+    Ok(unsafe { *(addr as *const u8) })
+}
+
+#[inline]
+fn be16<T: NetworkBuffer>(data: &Data<T>, offset: usize) -> DnsResult<u16> {
+    Ok(u16::from(byte(data, offset)?) << 8 | u16::from(byte(data, offset + 1)?))
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::net::dns::{DnsError, Message, NAME_LEN};
+        use crate::net::{Data, NetworkBuffer};
+
+        #[derive(Clone)]
+        struct Buffer {
+            start: usize,
+            end: usize,
+        }
+        impl NetworkBuffer for Buffer {
+            fn data_start(&self) -> usize {
+                self.start
+            }
+            fn data_end(&self) -> usize {
+                self.end
+            }
+        }
+        fn buffer(bytes: &[u8]) -> Data<Buffer> {
+            let start = bytes.as_ptr() as usize;
+            Data {
+                ctx: Buffer {
+                    start,
+                    end: start + bytes.len(),
+                },
+                base: start,
+            }
+        }
+
+        let query: &[u8] = &[
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, 0x00, 0x01, 0x00, 0x01, // www.example.com A IN
+            0xc0, 0x0c, 0x00, 0x1c, 0x00, 0x01, // compressed AAAA IN
+        ];
+        let data = buffer(query);
+        let message = Message::parse(&data).ok().unwrap();
+        let header = message.header();
+        assert_eq!(header.id, 0x1234);
+        assert_eq!(header.qdcount, 2);
+        assert!(!header.is_response());
+
+        let mut questions = message.questions();
+        let mut name = [0u8; NAME_LEN];
+        let question = questions.next(&mut name).unwrap().ok().unwrap();
+        assert_eq!(&name[..question.name_len], b"www.example.com");
+        assert_eq!((question.qtype, question.qclass), (1, 1));
+        match questions.next(&mut name) {
+            Some(Err(DnsError::Compressed)) => {}
+            _ => panic!("expected compressed name"),
+        }
+        assert!(questions.next(&mut name).is_none());
+
+        // truncated in the middle of the name
+        let data = buffer(&query[..20]);
+        let message = Message::parse(&data).ok().unwrap();
+        match message.questions().next(&mut name) {
+            Some(Err(DnsError::Truncated)) => {}
+            _ => panic!("expected truncated message"),
+        }
+    }
+}
@@ -0,0 +1,486 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Types and traits for working with networking data.
+
+The main trait exported by this module is `NetworkBuffer`. It's implemented
+by
+[`XdpContext`](https://ingraind.org/api/redbpf_probes/xdp/struct.XdpContext.html)
+to provide access to the network data.
+ */
+pub mod csum;
+pub mod dns;
+
+use crate::bindings::*;
+use core::mem;
+use core::slice;
+use cty::*;
+use redbpf_macros::impl_network_buffer_array;
+
+/// The maximum number of VLAN tags skipped to find the network header.
+pub const VLAN_MAX_TAGS: usize = 2;
+
+// see `struct vlan_hdr`
+#[repr(C)]
+struct VlanHdr {
+    tci: u16,
+    encapsulated_proto: u16,
+}
+
+/// An 802.1Q or 802.1ad VLAN tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VlanTag {
+    /// The VLAN identifier.
+    pub id: u16,
+    /// The priority code point.
+    pub priority: u8,
+}
+
+impl VlanTag {
+    #[inline]
+    fn from_tci(tci: u16) -> VlanTag {
+        VlanTag {
+            id: tci & 0x0fff,
+            priority: (tci >> 13) as u8,
+        }
+    }
+}
+
+// `proto` is in network byte order
+#[inline]
+fn is_vlan(proto: u16) -> bool {
+    let proto = u16::from_be(proto) as u32;
+    proto == ETH_P_8021Q || proto == ETH_P_8021AD
+}
+
+// IPv6 extension headers, see include/net/ipv6.h
+const NEXTHDR_HOP: u8 = 0;
+const NEXTHDR_ROUTING: u8 = 43;
+const NEXTHDR_FRAGMENT: u8 = 44;
+const NEXTHDR_DEST: u8 = 60;
+
+/// The maximum number of IPv6 extension headers skipped to find the
+/// transport header.
+///
+/// The bound keeps the loop walking the headers acceptable to the verifier.
+pub const IPV6_MAX_EXT_HEADERS: usize = 4;
+
+// The hop-by-hop, routing and destination options headers share this
+// prefix. The fragment header has the same layout but a fixed length.
+#[repr(C)]
+struct Ipv6OptHdr {
+    nexthdr: u8,
+    hdrlen: u8,
+}
+
+/// The IPv6 header, see `struct ipv6hdr`.
+///
+/// Multi-byte fields are in network byte order.
+#[repr(C)]
+pub struct Ipv6Hdr {
+    pub version_class_flow: [u8; 4],
+    pub payload_len: u16,
+    pub nexthdr: u8,
+    pub hop_limit: u8,
+    pub saddr: [u8; 16],
+    pub daddr: [u8; 16],
+}
+
+impl Ipv6Hdr {
+    /// Returns the IP version, which should be 6.
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.version_class_flow[0] >> 4
+    }
+
+    /// Returns the source address.
+    #[inline]
+    pub fn source(&self) -> [u8; 16] {
+        self.saddr
+    }
+
+    /// Returns the destination address.
+    #[inline]
+    pub fn dest(&self) -> [u8; 16] {
+        self.daddr
+    }
+
+    /// Returns the length of the payload, including the extension headers,
+    /// in host byte order.
+    #[inline]
+    pub fn payload_len(&self) -> u16 {
+        u16::from_be(self.payload_len)
+    }
+}
+
+/// The packet transport header.
+///
+/// Currently only `TCP` and `UDP` transports are supported.
+pub enum Transport {
+    TCP(*const tcphdr),
+    UDP(*const udphdr),
+}
+
+impl Transport {
+    /// Returns the source port.
+    #[inline]
+    pub fn source(&self) -> u16 {
+        let source = match *self {
+            Transport::TCP(hdr) => unsafe { (*hdr).source },
+            Transport::UDP(hdr) => unsafe { (*hdr).source },
+        };
+        u16::from_be(source)
+    }
+
+    /// Returns the destination port.
+    #[inline]
+    pub fn dest(&self) -> u16 {
+        let dest = match *self {
+            Transport::TCP(hdr) => unsafe { (*hdr).dest },
+            Transport::UDP(hdr) => unsafe { (*hdr).dest },
+        };
+        u16::from_be(dest)
+    }
+
+    /// Returns the `TCP` header, if this is a `TCP` transport.
+    #[inline]
+    pub fn tcp(&self) -> Option<&tcphdr> {
+        match *self {
+            // the pointer was bounds checked by `NetworkBuffer::transport()`
+            Transport::TCP(hdr) => unsafe { hdr.as_ref() },
+            Transport::UDP(_) => None,
+        }
+    }
+
+    /// Returns the `UDP` header, if this is a `UDP` transport.
+    #[inline]
+    pub fn udp(&self) -> Option<&udphdr> {
+        match *self {
+            Transport::UDP(hdr) => unsafe { hdr.as_ref() },
+            Transport::TCP(_) => None,
+        }
+    }
+}
+
+pub enum NetworkError {
+    Other,
+    OutOfBounds,
+    NoIPHeader,
+    UnsupportedTransport(u32),
+    /// The IP header length is smaller than the minimum of 20 bytes.
+    InvalidIPHeader,
+}
+
+/// Returns the length in bytes of an IPv4 header including the options,
+/// given its `ihl` field.
+#[inline]
+pub fn ip_header_len(ihl: u8) -> NetworkResult<usize> {
+    if ihl < 5 {
+        return Err(NetworkError::InvalidIPHeader);
+    }
+
+    Ok(ihl as usize * 4)
+}
+
+pub type NetworkResult<T> = Result<T, NetworkError>;
+
+pub trait NetworkBuffer
+where
+    Self: Clone + Sized,
+{
+    fn data_start(&self) -> usize;
+    fn data_end(&self) -> usize;
+
+    /// Returns the packet length.
+    #[inline]
+    fn len(&self) -> usize {
+        self.data_end() - self.data_start()
+    }
+
+    #[inline]
+    unsafe fn ptr_at<U>(&self, addr: usize) -> NetworkResult<*const U> {
+        self.check_bounds(addr, addr + mem::size_of::<U>())?;
+
+        Ok(addr as *const U)
+    }
+
+    /// Returns a mutable pointer to the `U` at `addr`, for programs that
+    /// rewrite packets.
+    ///
+    /// # Safety
+    ///
+    /// The bounds are checked, but the pointer is invalidated by calls that
+    /// move the packet data, like `XdpContext::adjust_head`.
+    #[inline]
+    unsafe fn ptr_at_mut<U>(&self, addr: usize) -> NetworkResult<*mut U> {
+        self.ptr_at::<U>(addr).map(|ptr| ptr as *mut U)
+    }
+
+    #[inline]
+    unsafe fn ptr_after<T, U>(&self, prev: *const T) -> NetworkResult<*const U> {
+        self.ptr_at(prev as usize + mem::size_of::<T>())
+    }
+
+    #[inline]
+    fn check_bounds(&self, start: usize, end: usize) -> NetworkResult<()> {
+        if start >= end {
+            return Err(NetworkError::OutOfBounds);
+        }
+
+        if start < self.data_start() as usize {
+            return Err(NetworkError::OutOfBounds);
+        }
+
+        if end > self.data_end() as usize {
+            return Err(NetworkError::OutOfBounds);
+        }
+
+        return Ok(());
+    }
+
+    /// Returns a `slice` of `len` bytes starting `offset` bytes into the
+    /// packet.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf_probes::xdp::prelude::*;
+    ///
+    /// #[xdp]
+    /// fn drop_magic(ctx: XdpContext) -> XdpResult {
+    ///     let magic = ctx.slice(42, 4)?;
+    ///     if magic == b"\xde\xad\xbe\xef" {
+    ///         return Ok(XdpAction::Drop);
+    ///     }
+    ///
+    ///     Ok(XdpAction::Pass)
+    /// }
+    /// ```
+    #[inline]
+    fn slice(&self, offset: usize, len: usize) -> NetworkResult<&[u8]> {
+        let start = self.data_start() + offset;
+        // compare the end with data_end, which is what the verifier checks
+        self.check_bounds(start, start + len)?;
+        unsafe { Ok(slice::from_raw_parts(start as *const u8, len)) }
+    }
+
+    /// Copies the bytes starting `offset` bytes into the packet into an
+    /// array.
+    #[inline]
+    fn read<U: NetworkBufferArray>(&self, offset: usize) -> NetworkResult<U> {
+        let start = self.data_start() + offset;
+        self.check_bounds(start, start + mem::size_of::<U>())?;
+        unsafe { Ok((start as *const U).read_unaligned()) }
+    }
+
+    /// Returns the packet's `Ethernet` header if present.
+    #[inline]
+    fn eth(&self) -> NetworkResult<*const ethhdr> {
+        unsafe { self.ptr_at(self.data_start() as usize) }
+    }
+
+    /// Returns the outermost 802.1Q or 802.1ad tag of the packet, if any.
+    #[inline]
+    fn vlan(&self) -> NetworkResult<Option<VlanTag>> {
+        let eth = self.eth()?;
+        unsafe {
+            if !is_vlan((*eth).h_proto) {
+                return Ok(None);
+            }
+
+            let vlan: *const VlanHdr = self.ptr_after(eth)?;
+            Ok(Some(VlanTag::from_tci(u16::from_be((*vlan).tci))))
+        }
+    }
+
+    /// Returns the packet's `IP` header if present.
+    ///
+    /// Up to two VLAN tags are skipped.
+    #[inline]
+    fn ip(&self) -> NetworkResult<*const iphdr> {
+        unsafe {
+            let (proto, addr) = network_header(self)?;
+            if proto != ETH_P_IP as u16 {
+                return Err(NetworkError::NoIPHeader);
+            }
+
+            self.ptr_at(addr)
+        }
+    }
+
+    /// Returns the packet's `IPv6` header if present.
+    ///
+    /// Up to two VLAN tags are skipped.
+    #[inline]
+    fn ipv6(&self) -> NetworkResult<*const Ipv6Hdr> {
+        unsafe {
+            let (proto, addr) = network_header(self)?;
+            if proto != ETH_P_IPV6 as u16 {
+                return Err(NetworkError::NoIPHeader);
+            }
+
+            self.ptr_at(addr)
+        }
+    }
+
+    /// Returns the packet's transport header if present.
+    ///
+    /// Both IPv4 and IPv6 packets are supported. The transport header is
+    /// located using the length of the IPv4 header, so packets with IP
+    /// options are handled correctly, and skipping up to
+    /// `IPV6_MAX_EXT_HEADERS` IPv6 extension headers.
+    ///
+    /// # Example
+    ///
+    /// Drop SSH traffic over both IPv4 and IPv6:
+    /// ```no_run
+    /// use redbpf_probes::xdp::prelude::*;
+    ///
+    /// #[xdp]
+    /// fn block_ssh(ctx: XdpContext) -> XdpResult {
+    ///     match ctx.transport() {
+    ///         Ok(t) if t.dest() == 22 => Ok(XdpAction::Drop),
+    ///         _ => Ok(XdpAction::Pass),
+    ///     }
+    /// }
+    /// ```
+    #[inline]
+    fn transport(&self) -> NetworkResult<Transport> {
+        unsafe {
+            let (proto, _) = network_header(self)?;
+            let (protocol, addr) = if proto == ETH_P_IPV6 as u16 {
+                ipv6_transport(self)?
+            } else {
+                let ip = self.ip()?;
+                let addr = ip as usize + ip_header_len((*ip).ihl())?;
+                ((*ip).protocol, addr)
+            };
+            let transport = match protocol as u32 {
+                IPPROTO_TCP => (Transport::TCP(self.ptr_at(addr)?)),
+                IPPROTO_UDP => (Transport::UDP(self.ptr_at(addr)?)),
+                t => return Err(NetworkError::UnsupportedTransport(t)),
+            };
+
+            Ok(transport)
+        }
+    }
+
+    /// Returns the packet's data starting after the transport headers.
+    #[inline]
+    fn data(&self) -> NetworkResult<Data<Self>> {
+        use Transport::*;
+        unsafe {
+            let base: *const c_void = match self.transport()? {
+                TCP(hdr) => {
+                    let mut addr = hdr as usize + mem::size_of::<tcphdr>();
+                    let data_offset = (*hdr).doff();
+                    if data_offset > 5 {
+                        addr += ((data_offset - 5) * 4) as usize;
+                    }
+                    self.ptr_at(addr)
+                }
+                UDP(hdr) => self.ptr_after(hdr),
+            }?;
+
+            let ctx: Self = self.clone();
+            Ok(Data {
+                ctx,
+                base: base as usize,
+            })
+        }
+    }
+}
+
+// Skips the VLAN tags, returning the ethertype in host byte order and the
+// address of the network header
+#[inline]
+unsafe fn network_header<T: NetworkBuffer>(buf: &T) -> NetworkResult<(u16, usize)> {
+    let eth = buf.eth()?;
+    let mut proto = (*eth).h_proto;
+    let mut addr = eth as usize + mem::size_of::<ethhdr>();
+    for _ in 0..VLAN_MAX_TAGS {
+        if !is_vlan(proto) {
+            break;
+        }
+        let vlan: *const VlanHdr = buf.ptr_at(addr)?;
+        proto = (*vlan).encapsulated_proto;
+        addr += mem::size_of::<VlanHdr>();
+    }
+
+    Ok((u16::from_be(proto), addr))
+}
+
+// Skips the IPv6 extension headers, returning the protocol and the address
+// of the transport header
+#[inline]
+unsafe fn ipv6_transport<T: NetworkBuffer>(buf: &T) -> NetworkResult<(u8, usize)> {
+    let ip = buf.ipv6()?;
+    let mut protocol = (*ip).nexthdr;
+    let mut addr = ip as usize + mem::size_of::<Ipv6Hdr>();
+    for _ in 0..IPV6_MAX_EXT_HEADERS {
+        let len = match protocol {
+            NEXTHDR_HOP | NEXTHDR_ROUTING | NEXTHDR_DEST => {
+                let hdr: *const Ipv6OptHdr = buf.ptr_at(addr)?;
+                protocol = (*hdr).nexthdr;
+                ((*hdr).hdrlen as usize + 1) * 8
+            }
+            NEXTHDR_FRAGMENT => {
+                let hdr: *const Ipv6OptHdr = buf.ptr_at(addr)?;
+                protocol = (*hdr).nexthdr;
+                8
+            }
+            _ => break,
+        };
+        addr += len;
+    }
+
+    Ok((protocol, addr))
+}
+
+/// Data type returned by calling `NetworkBuffer::data()`
+pub struct Data<T: NetworkBuffer> {
+    ctx: T,
+    base: usize,
+}
+
+impl<T: NetworkBuffer> Data<T> {
+    /// Returns the offset from the first byte of the packet.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.base - self.ctx.data_start()
+    }
+
+    /// Returns the length of the data.
+    ///
+    /// This is equivalent to the length of the packet minus the length of the headers.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.ctx.data_end() - self.base
+    }
+
+    /// Returns a `slice` of `len` bytes from the data.
+    #[inline]
+    pub fn slice(&self, len: usize) -> NetworkResult<&[u8]> {
+        unsafe {
+            self.ctx.check_bounds(self.base, self.base + len)?;
+            let s = slice::from_raw_parts(self.base as *const u8, len);
+            Ok(s)
+        }
+    }
+
+    #[inline]
+    pub fn read<U: NetworkBufferArray>(&self) -> NetworkResult<U> {
+        unsafe {
+            let len = mem::size_of::<U>();
+            self.ctx.check_bounds(self.base, self.base + len)?;
+            Ok((self.base as *const U).read_unaligned())
+        }
+    }
+}
+
+pub trait NetworkBufferArray {}
+impl_network_buffer_array!();
@@ -39,7 +39,8 @@ fn forward_tcp(skb: SkBuff) -> SkBuffResult {
 pub mod prelude;
 
 use crate::bindings::*;
-use crate::helpers::bpf_skb_load_bytes;
+use crate::helpers::{bpf_get_socket_cookie, bpf_get_socket_uid, bpf_skb_load_bytes};
+use crate::net::{ip_header_len, NetworkBufferArray, VLAN_MAX_TAGS};
 use core::mem;
 
 pub trait FromBe {
@@ -60,6 +61,21 @@ impl_from_be!(u8);
 impl_from_be!(u16);
 impl_from_be!(u32);
 
+/// Types that can be read from the packet bytes as they are.
+///
+/// Multi-byte fields keep the network byte order.
+///
+/// # Safety
+///
+/// Implementors must be plain data valid for any bit pattern.
+pub unsafe trait PacketData {}
+
+unsafe impl PacketData for ethhdr {}
+unsafe impl PacketData for iphdr {}
+unsafe impl PacketData for tcphdr {}
+unsafe impl PacketData for udphdr {}
+unsafe impl<T: NetworkBufferArray> PacketData for T {}
+
 /// The return type for successful socket filter programs.
 pub enum SkBuffAction {
     /// Ignore the data in the buffer.
@@ -75,6 +91,10 @@ pub enum SkBuffAction {
 pub enum SkBuffError {
     /// Loading data from the socket buffer failed.
     LoadFailed,
+    /// A helper failed with the given error code.
+    Helper(i32),
+    /// The offset or length are past the end of the packet.
+    OutOfBounds,
 }
 
 /// Result type for socket filter programs.
@@ -117,17 +137,230 @@ impl SkBuff {
     pub fn load<T: FromBe>(&self, offset: usize) -> Result<T, SkBuffError> {
         unsafe {
             let mut data = mem::MaybeUninit::<T>::uninit();
-            let ret = bpf_skb_load_bytes(
-                self.skb as *const _,
-                offset as u32,
-                &mut data as *mut _ as *mut _,
-                mem::size_of::<T>() as u32,
-            );
-            if ret < 0 {
-                return Err(SkBuffError::LoadFailed);
-            }
+            self.load_raw(offset, data.as_mut_ptr() as *mut u8, mem::size_of::<T>())?;
 
             Ok(data.assume_init().from_be())
         }
     }
+
+    /// Loads a header or other `PacketData` from the socket buffer, without
+    /// converting the byte order.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf_probes::socket_filter::prelude::*;
+    ///
+    /// #[socket_filter]
+    /// fn forward_ip(skb: SkBuff) -> SkBuffResult {
+    ///     let eth: ethhdr = skb.read(0)?;
+    ///     if u16::from_be(eth.h_proto) as u32 != ETH_P_IP {
+    ///         return Ok(SkBuffAction::Ignore);
+    ///     }
+    ///     Ok(SkBuffAction::SendToUserspace)
+    /// }
+    /// ```
+    #[inline]
+    pub fn read<T: PacketData>(&self, offset: usize) -> Result<T, SkBuffError> {
+        unsafe {
+            let mut data = mem::MaybeUninit::<T>::uninit();
+            self.load_raw(offset, data.as_mut_ptr() as *mut u8, mem::size_of::<T>())?;
+
+            Ok(data.assume_init())
+        }
+    }
+
+    /// Copies `dst.len()` bytes starting at `offset` into `dst`.
+    ///
+    /// Returns the number of bytes copied.
+    #[inline]
+    pub fn load_bytes(&self, offset: usize, dst: &mut [u8]) -> Result<usize, SkBuffError> {
+        unsafe { self.load_raw(offset, dst.as_mut_ptr(), dst.len())? };
+
+        Ok(dst.len())
+    }
+
+    // Loads fail past the end of the packet, but check explicitly so that
+    // errors don't depend on the helper
+    #[inline]
+    unsafe fn load_raw(&self, offset: usize, dst: *mut u8, len: usize) -> Result<(), SkBuffError> {
+        if offset + len > self.len() as usize {
+            return Err(SkBuffError::LoadFailed);
+        }
+        let ret = bpf_skb_load_bytes(
+            self.skb as *const _,
+            offset as u32,
+            dst as *mut _,
+            len as u32,
+        );
+        if ret < 0 {
+            return Err(SkBuffError::LoadFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the length of the packet.
+    #[inline]
+    pub fn len(&self) -> u32 {
+        unsafe { (*self.skb).len }
+    }
+
+    /// Returns `true` if the packet is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the ethertype of the packet, in host byte order.
+    #[inline]
+    pub fn protocol(&self) -> u16 {
+        u16::from_be(unsafe { (*self.skb).protocol } as u16)
+    }
+
+    /// Returns the packet type, eg. `PACKET_HOST` or `PACKET_BROADCAST`.
+    #[inline]
+    pub fn pkt_type(&self) -> u32 {
+        unsafe { (*self.skb).pkt_type }
+    }
+
+    /// Returns the index of the interface the packet is going through.
+    #[inline]
+    pub fn ifindex(&self) -> u32 {
+        unsafe { (*self.skb).ifindex }
+    }
+
+    /// Returns the index of the interface the packet was received on.
+    #[inline]
+    pub fn ingress_ifindex(&self) -> u32 {
+        unsafe { (*self.skb).ingress_ifindex }
+    }
+
+    /// Returns the mark of the packet.
+    #[inline]
+    pub fn mark(&self) -> u32 {
+        unsafe { (*self.skb).mark }
+    }
+
+    /// Returns the priority of the packet.
+    #[inline]
+    pub fn priority(&self) -> u32 {
+        unsafe { (*self.skb).priority }
+    }
+
+    /// Returns the VLAN TCI of the packet, if the tag was stripped by the
+    /// driver.
+    #[inline]
+    pub fn vlan_tci(&self) -> Option<u16> {
+        unsafe {
+            if (*self.skb).vlan_present == 0 {
+                return None;
+            }
+            Some((*self.skb).vlan_tci as u16)
+        }
+    }
+
+    /// Returns the cookie of the socket the packet belongs to.
+    ///
+    /// The cookie is generated by the kernel and stays the same for the
+    /// lifetime of the socket, so it can be used to correlate packets with
+    /// the owning process. It's the value `ss -e` shows as `sk:`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf_probes::socket_filter::prelude::*;
+    ///
+    /// #[map("bytes")]
+    /// static mut bytes: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+    ///
+    /// #[socket_filter]
+    /// fn count_bytes(skb: SkBuff) -> SkBuffResult {
+    ///     let zero = 0u64;
+    ///     if let Some(count) = unsafe { bytes.get_or_insert(&skb.socket_cookie(), &zero) } {
+    ///         atomic_add(count, skb.len() as u64);
+    ///     }
+    ///
+    ///     Ok(SkBuffAction::Ignore)
+    /// }
+    /// ```
+    #[inline]
+    pub fn socket_cookie(&self) -> u64 {
+        unsafe { bpf_get_socket_cookie(self.skb as *mut _) }
+    }
+
+    /// Returns the UID of the owner of the socket the packet belongs to.
+    ///
+    /// If the packet doesn't belong to a socket, returns the overflow UID,
+    /// usually 65534.
+    #[inline]
+    pub fn socket_uid(&self) -> u32 {
+        unsafe { bpf_get_socket_uid(self.skb as *mut _) }
+    }
+
+    /// Returns the flow hash of the packet.
+    #[inline]
+    pub fn hash(&self) -> u32 {
+        unsafe { (*self.skb).hash }
+    }
+
+    /// Returns a pointer to the `T` at the start of the metadata area set by
+    /// the XDP program that processed the packet.
+    ///
+    /// Fails with `SkBuffError::OutOfBounds` if the metadata area is smaller
+    /// than `T`, which also satisfies the verifier's bounds checks. The
+    /// metadata can only be accessed by traffic control programs, see the
+    /// [`tc`](../tc/index.html) module.
+    #[inline]
+    pub fn meta<T>(&self) -> Result<*const T, SkBuffError> {
+        let (meta, data) = unsafe { ((*self.skb).data_meta as usize, (*self.skb).data as usize) };
+        if meta + mem::size_of::<T>() > data {
+            return Err(SkBuffError::OutOfBounds);
+        }
+
+        Ok(meta as *const T)
+    }
+
+    /// Returns the length of the metadata area.
+    #[inline]
+    pub fn meta_len(&self) -> usize {
+        let (meta, data) = unsafe { ((*self.skb).data_meta as usize, (*self.skb).data as usize) };
+        data.saturating_sub(meta)
+    }
+
+    /// Returns the source and destination ports of `TCP` and `UDP` packets
+    /// over IPv4, in host byte order.
+    ///
+    /// Returns `Ok(None)` for any other packet.
+    #[inline]
+    pub fn ports(&self) -> Result<Option<(u16, u16)>, SkBuffError> {
+        let mut eth_len = mem::size_of::<ethhdr>();
+        let mut eth_proto: u16 = self.load(ETH_PROTO_OFFSET)?;
+        // VLAN tags are usually stripped by the driver, but skip them if not
+        for _ in 0..VLAN_MAX_TAGS {
+            if !(eth_proto as u32 == ETH_P_8021Q || eth_proto as u32 == ETH_P_8021AD) {
+                break;
+            }
+            eth_proto = self.load(eth_len + 2)?;
+            eth_len += 4;
+        }
+        if eth_proto as u32 != ETH_P_IP {
+            return Ok(None);
+        }
+        let ip_proto: u8 = self.load(eth_len + IP_PROTOCOL_OFFSET)?;
+        if !(ip_proto as u32 == IPPROTO_TCP || ip_proto as u32 == IPPROTO_UDP) {
+            return Ok(None);
+        }
+        // the first byte of the IP header is `version << 4 | ihl`
+        let ihl: u8 = self.load(eth_len)?;
+        let ip_len = ip_header_len(ihl & 0xf).map_err(|_| SkBuffError::LoadFailed)?;
+        // both TCP and UDP headers start with the source and destination ports
+        let source: u16 = self.load(eth_len + ip_len)?;
+        let dest: u16 = self.load(eth_len + ip_len + 2)?;
+
+        Ok(Some((source, dest)))
+    }
 }
+
+// offsetof(struct ethhdr, h_proto)
+const ETH_PROTO_OFFSET: usize = 12;
+// offsetof(struct iphdr, protocol)
+const IP_PROTOCOL_OFFSET: usize = 9;
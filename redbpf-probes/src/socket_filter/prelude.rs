@@ -13,7 +13,7 @@
 //! use redbpf_probes::socket_filter::prelude::*;
 //! ```
 pub use cty::*;
-pub use redbpf_macros::{program, socket_filter};
+pub use redbpf_macros::{config, map, program, socket_filter};
 pub use crate::bindings::*;
 pub use crate::helpers::*;
 pub use crate::maps::*;
@@ -0,0 +1,302 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Traffic Control classifiers.
+
+Classifier programs run when packets go through the traffic control layer,
+after XDP on ingress. They're passed a `TcContext`, which reads packets like
+the `SkBuff` of socket filters, but can also rewrite the packets, access
+them directly and read the metadata left by XDP programs. They're expected
+to be installed in direct-action mode, where the return value of the
+program is the action taken.
+
+# Examples
+
+Packets can be non-linear, so the headers are pulled before being parsed
+directly:
+
+```no_run
+#![no_std]
+#![no_main]
+use core::mem;
+use redbpf_probes::tc::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[classifier]
+fn drop_expiring(ctx: TcContext) -> TcResult {
+    let eth_len = mem::size_of::<ethhdr>();
+    let len = eth_len + mem::size_of::<iphdr>();
+    if ctx.protocol() as u32 != ETH_P_IP || ctx.pull_data(len as u32).is_err() {
+        return Ok(TcAction::Ok);
+    }
+
+    // the pointers change when pulling, so they're read after it, and the
+    // verifier requires checking them against one another
+    let (data, data_end) = (ctx.data(), ctx.data_end());
+    if data + len > data_end {
+        return Ok(TcAction::Ok);
+    }
+    let ip = (data + eth_len) as *const iphdr;
+    if unsafe { (*ip).ttl } <= 1 {
+        return Ok(TcAction::Shot);
+    }
+
+    Ok(TcAction::Ok)
+}
+```
+
+The XDP program stores the time each packet was received in the metadata
+area, and the classifier processing the packet next reads it:
+
+```no_run
+#![no_std]
+#![no_main]
+use core::mem;
+use redbpf_probes::tc::prelude::*;
+use redbpf_probes::xdp::{XdpAction, XdpContext, XdpResult};
+use redbpf_macros::xdp;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[repr(C)]
+struct Stamp {
+    ns: u64,
+}
+
+#[xdp]
+fn stamp(mut ctx: XdpContext) -> XdpResult {
+    ctx.adjust_meta(-(mem::size_of::<Stamp>() as i32))?;
+    unsafe {
+        let stamp: *mut Stamp = ctx.meta()?;
+        (*stamp).ns = bpf_ktime_get_ns();
+    }
+
+    Ok(XdpAction::Pass)
+}
+
+#[classifier]
+fn read_stamp(ctx: TcContext) -> TcResult {
+    let stamp = unsafe { &*ctx.meta::<Stamp>()? };
+    // drop the packets that took more than 1ms to get here
+    if bpf_ktime_get_ns() - stamp.ns > 1_000_000 {
+        return Ok(TcAction::Shot);
+    }
+
+    Ok(TcAction::Ok)
+}
+```
+
+The classifier is in the `classifier/read_stamp` section, and can be
+installed with `tc filter add dev eth0 ingress bpf da obj probe.elf sec
+classifier/read_stamp`, after adding a `clsact` qdisc to the interface.
+*/
+pub mod prelude;
+
+use core::ops::Deref;
+
+use crate::helpers::{
+    bpf_clone_redirect, bpf_l3_csum_replace, bpf_l4_csum_replace, bpf_skb_change_tail,
+    bpf_skb_pull_data, bpf_skb_store_bytes,
+};
+use crate::socket_filter::{SkBuff, SkBuffError};
+
+/// The action taken by the classifier, as defined in `linux/pkt_cls.h`.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcAction {
+    /// Use the default action configured for the filter.
+    Unspec = -1,
+    /// Let the packet through.
+    Ok = 0,
+    /// Restart the classification from the first filter.
+    Reclassify = 1,
+    /// Drop the packet.
+    Shot = 2,
+    /// Continue with the next filter.
+    Pipe = 3,
+    /// Consume the packet, as done by redirections to other interfaces.
+    Stolen = 4,
+    /// Redirect the packet as requested by `bpf_redirect`.
+    Redirect = 7,
+}
+
+/// Result type for classifier programs.
+///
+/// Errors let the packet through.
+pub type TcResult = Result<TcAction, SkBuffError>;
+
+/// Context object provided to classifier programs.
+///
+/// The methods of `SkBuff` are available through `Deref`. The ones here use
+/// helpers that socket filters can't call, and return
+/// `SkBuffError::Helper` with the error of the helper when they fail.
+pub struct TcContext {
+    pub skb: SkBuff,
+}
+
+impl Deref for TcContext {
+    type Target = SkBuff;
+
+    #[inline]
+    fn deref(&self) -> &SkBuff {
+        &self.skb
+    }
+}
+
+impl TcContext {
+    /// Returns the address of the start of the packet data that can be
+    /// accessed directly, see `pull_data()`.
+    #[inline]
+    pub fn data(&self) -> usize {
+        unsafe { (*self.skb.skb).data as usize }
+    }
+
+    /// Returns the address of the end of the packet data that can be
+    /// accessed directly.
+    #[inline]
+    pub fn data_end(&self) -> usize {
+        unsafe { (*self.skb.skb).data_end as usize }
+    }
+
+    /// Overwrites the packet bytes starting at `offset` with `data`.
+    ///
+    /// `flags` can include `BPF_F_RECOMPUTE_CSUM` and
+    /// `BPF_F_INVALIDATE_HASH`.
+    ///
+    /// # Example
+    ///
+    /// Redirect DNS queries to port 5353:
+    /// ```no_run
+    /// use core::mem;
+    /// use redbpf_probes::tc::prelude::*;
+    ///
+    /// fn redirect_dns(ctx: TcContext) -> Result<(), SkBuffError> {
+    ///     let udp = mem::size_of::<ethhdr>() + mem::size_of::<iphdr>();
+    ///     // the offsets of udphdr.dest and udphdr.check
+    ///     let (dest, check) = (udp + 2, udp + 6);
+    ///     let port: u16 = ctx.load(dest)?;
+    ///     if port != 53 {
+    ///         return Ok(());
+    ///     }
+    ///
+    ///     let (old, new) = (53u16.to_be(), 5353u16.to_be());
+    ///     ctx.l4_csum_replace(check, old as u64, new as u64, 2)?;
+    ///     ctx.store_bytes(dest, &new.to_ne_bytes(), 0)
+    /// }
+    /// ```
+    #[inline]
+    pub fn store_bytes(&self, offset: usize, data: &[u8], flags: u64) -> Result<(), SkBuffError> {
+        if offset + data.len() > self.len() as usize {
+            return Err(SkBuffError::OutOfBounds);
+        }
+        let ret = unsafe {
+            bpf_skb_store_bytes(
+                self.skb.skb as *mut _,
+                offset as u32,
+                data.as_ptr() as *const _,
+                data.len() as u32,
+                flags,
+            )
+        };
+        if ret < 0 {
+            return Err(SkBuffError::Helper(ret));
+        }
+
+        Ok(())
+    }
+
+    /// Makes the first `len` bytes of the packet accessible directly.
+    ///
+    /// Packets can be non-linear, with only part of the data accessible
+    /// between `data()` and `data_end()`. Pulling the headers of interest
+    /// first makes parsing them directly possible. `data()` and `data_end()`
+    /// change and must be read again, and checked against one another, after
+    /// this returns. See the example of the [module](index.html).
+    #[inline]
+    pub fn pull_data(&self, len: u32) -> Result<(), SkBuffError> {
+        if len > self.len() {
+            return Err(SkBuffError::OutOfBounds);
+        }
+        let ret = unsafe { bpf_skb_pull_data(self.skb.skb as *mut _, len) };
+        if ret < 0 {
+            return Err(SkBuffError::Helper(ret));
+        }
+
+        Ok(())
+    }
+
+    /// Sends a copy of the packet to the interface `ifindex`.
+    ///
+    /// The copy goes to the egress path of the interface, or to its ingress
+    /// path if `flags` includes `BPF_F_INGRESS`.
+    #[inline]
+    pub fn clone_redirect(&self, ifindex: u32, flags: u64) -> Result<(), SkBuffError> {
+        let ret = unsafe { bpf_clone_redirect(self.skb.skb as *mut _, ifindex, flags) };
+        if ret < 0 {
+            return Err(SkBuffError::Helper(ret));
+        }
+
+        Ok(())
+    }
+
+    /// Grows or trims the packet to `len` bytes.
+    ///
+    /// `flags` must be 0.
+    #[inline]
+    pub fn change_tail(&self, len: u32, flags: u64) -> Result<(), SkBuffError> {
+        let ret = unsafe { bpf_skb_change_tail(self.skb.skb as *mut _, len, flags) };
+        if ret < 0 {
+            return Err(SkBuffError::Helper(ret));
+        }
+
+        Ok(())
+    }
+
+    /// Replaces `from` with `to` in the layer 3 checksum at `offset`.
+    ///
+    /// `size` is the size of the field that changed, either 2 or 4 bytes.
+    #[inline]
+    pub fn l3_csum_replace(
+        &self,
+        offset: usize,
+        from: u64,
+        to: u64,
+        size: u64,
+    ) -> Result<(), SkBuffError> {
+        let ret =
+            unsafe { bpf_l3_csum_replace(self.skb.skb as *mut _, offset as u32, from, to, size) };
+        if ret < 0 {
+            return Err(SkBuffError::Helper(ret));
+        }
+
+        Ok(())
+    }
+
+    /// Replaces `from` with `to` in the layer 4 checksum at `offset`.
+    ///
+    /// The size of the field that changed goes in the low bits of `flags`,
+    /// and `BPF_F_PSEUDO_HDR` must be set if the field is part of the pseudo
+    /// header, like the IP addresses. See `l3_csum_replace`.
+    #[inline]
+    pub fn l4_csum_replace(
+        &self,
+        offset: usize,
+        from: u64,
+        to: u64,
+        flags: u64,
+    ) -> Result<(), SkBuffError> {
+        let ret =
+            unsafe { bpf_l4_csum_replace(self.skb.skb as *mut _, offset as u32, from, to, flags) };
+        if ret < 0 {
+            return Err(SkBuffError::Helper(ret));
+        }
+
+        Ok(())
+    }
+}
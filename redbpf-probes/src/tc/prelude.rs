@@ -0,0 +1,21 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! The Traffic Control Prelude
+//!
+//! The purpose of this module is to alleviate imports of the common Traffic
+//! Control types by adding a glob import to the top of classifier programs:
+//!
+//! ```
+//! use redbpf_probes::tc::prelude::*;
+//! ```
+pub use crate::bindings::*;
+pub use crate::helpers::*;
+pub use crate::maps::*;
+pub use crate::socket_filter::{PacketData, SkBuff, SkBuffError};
+pub use crate::tc::*;
+pub use cty::*;
+pub use redbpf_macros::{classifier, config, map, program};
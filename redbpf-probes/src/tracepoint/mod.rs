@@ -0,0 +1,293 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Tracepoints
+
+Tracepoints are static hooks placed in the kernel source. Unlike kprobes,
+their arguments are passed in a record whose layout is described by the
+`format` file of the tracepoint, eg.
+`/sys/kernel/debug/tracing/events/sock/inet_sock_set_state/format`.
+
+This module provides typed records for the tracepoints supported by RedBPF.
+Because the kernel is free to change the layout of a tracepoint record,
+user-space should check the layout with `redbpf::tracepoint::Format` before
+loading programs that rely on it. Each record exposes the expected layout
+through its `FIELDS` constant.
+
+Records for other tracepoints can be generated from their `format` file with
+`cargo bpf tracepoint`, eg. `cargo bpf tracepoint sched/sched_process_exec`.
+The generated structs can be used as the argument of `#[tracepoint]`
+functions like the records defined here.
+
+# Example
+
+Trace TCP state changes.
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[tracepoint("sock/inet_sock_set_state")]
+pub fn state_change(args: &InetSockSetState) {
+    if args.is_tcp() && args.new_state() == Some(TcpState::Close) {
+        // the connection is gone
+    }
+}
+```
+ */
+pub mod prelude;
+
+use crate::bindings::*;
+use crate::helpers::probe_read_str;
+use cty::*;
+
+/// Fields common to all tracepoint records.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct TracepointCommon {
+    pub common_type: c_ushort,
+    pub common_flags: c_uchar,
+    pub common_preempt_count: c_uchar,
+    pub common_pid: c_int,
+}
+
+/// A `__data_loc` field of a tracepoint record.
+///
+/// Variable length fields, like strings, are stored after the fixed size
+/// fields of the record. The `__data_loc` field holds their offset from the
+/// start of the record in the lower 16 bits, and their length in the upper
+/// 16 bits.
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone)]
+pub struct DataLoc(pub u32);
+
+impl DataLoc {
+    /// Returns the offset of the data from the start of the record.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        (self.0 & 0xffff) as usize
+    }
+
+    /// Returns the length of the data, including the NUL byte of strings.
+    #[inline]
+    pub fn len(&self) -> usize {
+        (self.0 >> 16) as usize
+    }
+
+    /// Returns `true` if there's no data.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a pointer to the data, given the `record` containing the
+    /// field.
+    #[inline]
+    pub fn ptr<T>(&self, record: &T) -> *const u8 {
+        (record as *const T as usize + self.offset()) as *const u8
+    }
+
+    /// Reads the string stored in `record` into `dst`.
+    ///
+    /// See `probe_read_str()`.
+    #[inline]
+    pub fn read_str<T>(&self, record: &T, dst: &mut [u8]) -> Result<usize, i64> {
+        unsafe { probe_read_str(dst, self.ptr(record)) }
+    }
+}
+
+/// The kernel's TCP states, as defined in `include/net/tcp_states.h`.
+#[repr(i32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TcpState {
+    Established = 1,
+    SynSent = 2,
+    SynRecv = 3,
+    FinWait1 = 4,
+    FinWait2 = 5,
+    TimeWait = 6,
+    Close = 7,
+    CloseWait = 8,
+    LastAck = 9,
+    Listen = 10,
+    Closing = 11,
+    NewSynRecv = 12,
+}
+
+impl TcpState {
+    /// Converts the numeric state used by the kernel into a `TcpState`.
+    #[inline]
+    pub fn from_i32(state: i32) -> Option<TcpState> {
+        use TcpState::*;
+        Some(match state {
+            1 => Established,
+            2 => SynSent,
+            3 => SynRecv,
+            4 => FinWait1,
+            5 => FinWait2,
+            6 => TimeWait,
+            7 => Close,
+            8 => CloseWait,
+            9 => LastAck,
+            10 => Listen,
+            11 => Closing,
+            12 => NewSynRecv,
+            _ => return None,
+        })
+    }
+
+    /// Returns the name of the state, as printed by `ss` and bcc's tools.
+    pub fn as_str(&self) -> &'static str {
+        use TcpState::*;
+        match self {
+            Established => "ESTABLISHED",
+            SynSent => "SYN_SENT",
+            SynRecv => "SYN_RECV",
+            FinWait1 => "FIN_WAIT1",
+            FinWait2 => "FIN_WAIT2",
+            TimeWait => "TIME_WAIT",
+            Close => "CLOSE",
+            CloseWait => "CLOSE_WAIT",
+            LastAck => "LAST_ACK",
+            Listen => "LISTEN",
+            Closing => "CLOSING",
+            NewSynRecv => "NEW_SYN_RECV",
+        }
+    }
+}
+
+/// Record of the `sock/inet_sock_set_state` tracepoint.
+///
+/// The tracepoint fires every time the state of an inet socket changes. Ports
+/// are in host byte order, addresses in network byte order.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct InetSockSetState {
+    pub common: TracepointCommon,
+    pub skaddr: *const c_void,
+    pub oldstate: c_int,
+    pub newstate: c_int,
+    pub sport: u16,
+    pub dport: u16,
+    pub family: u16,
+    pub protocol: u8,
+    pub saddr: [u8; 4],
+    pub daddr: [u8; 4],
+    pub saddr_v6: [u8; 16],
+    pub daddr_v6: [u8; 16],
+}
+
+impl InetSockSetState {
+    /// The layout of the record as `(name, offset, size)` triples.
+    ///
+    /// This matches the `format` file of the tracepoint on kernels since 4.16.
+    pub const FIELDS: &'static [(&'static str, usize, usize)] = &[
+        ("skaddr", 8, 8),
+        ("oldstate", 16, 4),
+        ("newstate", 20, 4),
+        ("sport", 24, 2),
+        ("dport", 26, 2),
+        ("family", 28, 2),
+        ("protocol", 30, 1),
+        ("saddr", 31, 4),
+        ("daddr", 35, 4),
+        ("saddr_v6", 39, 16),
+        ("daddr_v6", 55, 16),
+    ];
+
+    /// Returns `true` if the state change is for a TCP socket.
+    #[inline]
+    pub fn is_tcp(&self) -> bool {
+        self.protocol as u32 == IPPROTO_TCP
+    }
+
+    /// The state the socket is leaving.
+    #[inline]
+    pub fn old_state(&self) -> Option<TcpState> {
+        TcpState::from_i32(self.oldstate)
+    }
+
+    /// The state the socket is entering.
+    #[inline]
+    pub fn new_state(&self) -> Option<TcpState> {
+        TcpState::from_i32(self.newstate)
+    }
+
+    /// The socket changing state.
+    #[inline]
+    pub fn sock(&self) -> *const sock {
+        self.skaddr as *const sock
+    }
+}
+
+/// The IPv4 addresses and ports of a socket.
+///
+/// Addresses are in network byte order, ports in host byte order.
+#[derive(Debug, Copy, Clone)]
+pub struct SockTuple {
+    pub saddr: u32,
+    pub daddr: u32,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+impl SockTuple {
+    /// Reads the 4-tuple of `sk` using the generated `sock` accessors.
+    #[inline]
+    pub fn from_sock(sk: &sock) -> Option<SockTuple> {
+        Some(SockTuple {
+            saddr: sk.skc_rcv_saddr()?,
+            daddr: sk.skc_daddr()?,
+            sport: sk.skc_num()?,
+            dport: u16::from_be(sk.skc_dport()?),
+        })
+    }
+}
+
+/// Byte counters of a TCP socket.
+#[derive(Debug, Copy, Clone)]
+pub struct TcpBytes {
+    /// Bytes received, as counted by `tcp_sock.bytes_received`.
+    pub received: u64,
+    /// Bytes sent and acknowledged by the peer, as counted by
+    /// `tcp_sock.bytes_acked`.
+    pub acked: u64,
+    /// Bytes sent, including retransmissions, as counted by
+    /// `tcp_sock.bytes_sent`.
+    pub sent: u64,
+}
+
+impl TcpBytes {
+    /// Reads the byte counters of `sk`, which must be a TCP socket.
+    #[inline]
+    pub fn from_sock(sk: &sock) -> Option<TcpBytes> {
+        let tp = unsafe { &*(sk as *const sock as *const tcp_sock) };
+        Some(TcpBytes {
+            received: tp.bytes_received()?,
+            acked: tp.bytes_acked()?,
+            sent: tp.bytes_sent()?,
+        })
+    }
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::tracepoint::DataLoc;
+
+        let record = [0u8; 32];
+        let loc = DataLoc(12 << 16 | 20);
+        assert_eq!((loc.offset(), loc.len()), (20, 12));
+        assert!(!loc.is_empty());
+        assert_eq!(loc.ptr(&record), &record[20] as *const u8);
+        assert!(DataLoc(20).is_empty());
+    }
+}
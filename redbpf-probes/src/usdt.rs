@@ -0,0 +1,139 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+USDT probe arguments.
+
+USDT probes are uprobes placed by the application on the instructions
+marked by its `DTRACE_PROBE` macros. Where the arguments of a probe are
+stored varies with each location of the probe, so user space describes them
+to the program in a `HashMap<u64, UsdtSpec>` keyed by the address of the
+location, see `redbpf::usdt::UsdtSpecs`. `UsdtContext` looks the spec of
+the current location up and reads the arguments:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::kprobe::prelude::*;
+use redbpf_probes::usdt::{UsdtContext, UsdtSpec};
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("usdt_specs")]
+static mut usdt_specs: HashMap<u64, UsdtSpec> = HashMap::with_max_entries(64);
+
+#[uprobe]
+fn query_start(regs: Registers) {
+    let ctx = match UsdtContext::new(regs, unsafe { &mut usdt_specs }) {
+        Some(ctx) => ctx,
+        None => return,
+    };
+    if let Some(query) = ctx.arg(0) {
+        // the first argument of postgresql:query__start is the query string
+    }
+}
+```
+*/
+use crate::helpers::bpf_probe_read;
+use crate::kprobe::Registers;
+use crate::maps::HashMap;
+
+/// The maximum number of arguments of a USDT probe.
+pub const USDT_MAX_ARGS: usize = 12;
+
+pub const USDT_ARG_CONST: u8 = 0;
+pub const USDT_ARG_REG: u8 = 1;
+pub const USDT_ARG_REG_DEREF: u8 = 2;
+
+/* NB: this needs to be kept in sync with redbpf::usdt::UsdtArgSpec */
+/// Where an argument is stored.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UsdtArgSpec {
+    /// The constant, or the offset from the register of memory operands
+    pub value: i64,
+    /// The offset of the register in `struct pt_regs`
+    pub reg_offset: u16,
+    pub kind: u8,
+    /// `64 - 8 * size`, to truncate and extend arguments smaller than 8
+    /// bytes
+    pub bitshift: u8,
+    pub signed: u8,
+    pub _pad: [u8; 3],
+}
+
+/* NB: this needs to be kept in sync with redbpf::usdt::UsdtSpec */
+/// The arguments of a location of a USDT probe.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct UsdtSpec {
+    pub args: [UsdtArgSpec; USDT_MAX_ARGS],
+    pub arg_count: u32,
+    pub _pad: u32,
+}
+
+/// The arguments of the USDT probe being run.
+pub struct UsdtContext<'a> {
+    regs: Registers,
+    spec: &'a UsdtSpec,
+}
+
+impl<'a> UsdtContext<'a> {
+    /// Looks up the spec of the current location in `specs`.
+    ///
+    /// Falls back to the spec at address 0, which user space uses when it
+    /// can't tell where the probed file is mapped. Returns `None` if neither
+    /// exists.
+    #[inline]
+    pub fn new(regs: Registers, specs: &'a mut HashMap<u64, UsdtSpec>) -> Option<Self> {
+        let ip = regs.ip();
+        let key = if specs.get(&ip).is_some() { ip } else { 0 };
+        let spec = specs.get(&key)?;
+
+        Some(UsdtContext { regs, spec })
+    }
+
+    #[inline]
+    pub fn arg_count(&self) -> usize {
+        self.spec.arg_count as usize
+    }
+
+    /// Returns the argument at `index`, counting from zero, sign or zero
+    /// extended to 64 bits.
+    ///
+    /// Returns `None` if the probe has no such argument, or if it can't be
+    /// read.
+    #[inline]
+    pub fn arg(&self, index: usize) -> Option<i64> {
+        if index >= self.arg_count() || index >= USDT_MAX_ARGS {
+            return None;
+        }
+        let spec = &self.spec.args[index];
+        let val = match spec.kind {
+            USDT_ARG_CONST => return Some(spec.value),
+            USDT_ARG_REG => self.reg(spec.reg_offset)?,
+            USDT_ARG_REG_DEREF => {
+                let addr = (self.reg(spec.reg_offset)? as i64 + spec.value) as *const u64;
+                unsafe { bpf_probe_read(addr).ok()? }
+            }
+            _ => return None,
+        };
+
+        let val = val << spec.bitshift;
+        if spec.signed != 0 {
+            Some((val as i64) >> spec.bitshift)
+        } else {
+            Some((val >> spec.bitshift) as i64)
+        }
+    }
+
+    #[inline]
+    fn reg(&self, offset: u16) -> Option<u64> {
+        let ptr = unsafe { (self.regs.ctx as *const u8).add(offset as usize) };
+        unsafe { bpf_probe_read(ptr as *const u64).ok() }
+    }
+}
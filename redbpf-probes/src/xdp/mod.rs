@@ -36,9 +36,13 @@ fn block_port_80(ctx: XdpContext) -> XdpResult {
  */
 pub mod prelude;
 
+use core::mem;
+use cty::*;
+
 use crate::bindings::*;
+use crate::helpers::*;
 use crate::maps::{PerfMap as PerfMapBase, PerfMapFlags};
-use crate::net::{NetworkBuffer, NetworkResult};
+use crate::net::{NetworkBuffer, NetworkError, NetworkResult};
 
 /// The result type for XDP programs.
 pub type XdpResult = NetworkResult<XdpAction>;
@@ -65,6 +69,20 @@ pub enum XdpAction {
     Redirect = xdp_action_XDP_REDIRECT,
 }
 
+impl XdpAction {
+    // `bpf_redirect_map` returns the action to take
+    #[inline]
+    fn from_redirect(ret: c_int) -> XdpAction {
+        match ret as u32 {
+            a if a == xdp_action_XDP_REDIRECT => XdpAction::Redirect,
+            a if a == xdp_action_XDP_DROP => XdpAction::Drop,
+            a if a == xdp_action_XDP_PASS => XdpAction::Pass,
+            a if a == xdp_action_XDP_TX => XdpAction::Tx,
+            _ => XdpAction::Aborted,
+        }
+    }
+}
+
 /// Context object provided to XDP programs.
 ///
 /// XDP programs are passed a `XdpContext` instance as their argument. Through
@@ -81,6 +99,106 @@ impl XdpContext {
     pub fn inner(&self) -> *mut xdp_md {
         self.ctx
     }
+
+    /// Moves the start of the packet by `delta` bytes.
+    ///
+    /// A negative `delta` makes room for new headers in front of the packet,
+    /// a positive one strips headers. All the pointers obtained before
+    /// calling this are invalid, and must be obtained again from the
+    /// context, which also redoes the bounds checks required by the verifier.
+    ///
+    /// # Example
+    ///
+    /// Insert a tag after the ethernet header and bounce the packet back:
+    /// ```no_run
+    /// use core::mem;
+    /// use redbpf_probes::xdp::prelude::*;
+    ///
+    /// #[repr(C)]
+    /// struct Tag {
+    ///     id: u32,
+    /// }
+    ///
+    /// #[xdp]
+    /// fn tag(mut ctx: XdpContext) -> XdpResult {
+    ///     let eth = unsafe { *ctx.eth()? };
+    ///     ctx.adjust_head(-(mem::size_of::<Tag>() as i32))?;
+    ///     unsafe {
+    ///         let start = ctx.data_start();
+    ///         let new_eth: *mut ethhdr = ctx.ptr_at_mut(start)?;
+    ///         (*new_eth).h_dest = eth.h_source;
+    ///         (*new_eth).h_source = eth.h_dest;
+    ///         (*new_eth).h_proto = eth.h_proto;
+    ///         let tag: *mut Tag = ctx.ptr_at_mut(start + mem::size_of::<ethhdr>())?;
+    ///         (*tag).id = 42u32.to_be();
+    ///     }
+    ///
+    ///     Ok(XdpAction::Tx)
+    /// }
+    /// ```
+    #[inline]
+    pub fn adjust_head(&mut self, delta: i32) -> NetworkResult<()> {
+        let ret = unsafe { bpf_xdp_adjust_head(self.ctx as *mut _, delta) };
+        if ret < 0 {
+            return Err(NetworkError::Other);
+        }
+
+        Ok(())
+    }
+
+    /// Moves the end of the packet by `delta` bytes.
+    ///
+    /// Only shrinking the packet, with a negative `delta`, is supported by
+    /// older kernels. Like with `adjust_head`, the pointers obtained before
+    /// calling this are invalid.
+    #[inline]
+    pub fn adjust_tail(&mut self, delta: i32) -> NetworkResult<()> {
+        let ret = unsafe { bpf_xdp_adjust_tail(self.ctx as *mut _, delta) };
+        if ret < 0 {
+            return Err(NetworkError::Other);
+        }
+
+        Ok(())
+    }
+
+    /// Moves the start of the metadata area by `delta` bytes.
+    ///
+    /// The metadata area sits in front of the packet data and is initially
+    /// empty. A negative `delta` grows it, and the metadata written there can
+    /// be read by the traffic control programs processing the packet next,
+    /// see the [`tc`](../tc/index.html) module. `delta` must be a multiple of
+    /// 4 bytes. Like with `adjust_head`, the pointers obtained before calling
+    /// this are invalid.
+    #[inline]
+    pub fn adjust_meta(&mut self, delta: i32) -> NetworkResult<()> {
+        let ret = unsafe { bpf_xdp_adjust_meta(self.ctx as *mut _, delta) };
+        if ret < 0 {
+            return Err(NetworkError::Other);
+        }
+
+        Ok(())
+    }
+
+    /// Returns a pointer to the `T` at the start of the metadata area.
+    ///
+    /// Fails with `NetworkError::OutOfBounds` if the metadata area is
+    /// smaller than `T`, which also satisfies the verifier's bounds checks.
+    #[inline]
+    pub fn meta<T>(&self) -> NetworkResult<*mut T> {
+        let (meta, data) = unsafe { ((*self.ctx).data_meta as usize, self.data_start()) };
+        if meta + mem::size_of::<T>() > data {
+            return Err(NetworkError::OutOfBounds);
+        }
+
+        Ok(meta as *mut T)
+    }
+
+    /// Returns the length of the metadata area.
+    #[inline]
+    pub fn meta_len(&self) -> usize {
+        let meta = unsafe { (*self.ctx).data_meta as usize };
+        self.data_start().saturating_sub(meta)
+    }
 }
 
 impl NetworkBuffer for XdpContext {
@@ -164,3 +282,88 @@ impl<T> PerfMap<T> {
         self.0.insert_with_flags(ctx.inner(), data, flags)
     }
 }
+
+/// Network device map.
+///
+/// High level API for BPF_MAP_TYPE_DEVMAP maps. The elements are the
+/// ifindexes of the interfaces packets can be redirected to, and are set
+/// from userspace.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::xdp::prelude::*;
+///
+/// #[map("interfaces")]
+/// static mut interfaces: DevMap = DevMap::with_max_entries(8);
+///
+/// #[xdp]
+/// fn forward(ctx: XdpContext) -> XdpResult {
+///     Ok(unsafe { interfaces.redirect(0, 0) })
+/// }
+/// ```
+#[repr(transparent)]
+pub struct DevMap {
+    def: bpf_map_def,
+}
+
+impl DevMap {
+    /// Creates a map with the specified maximum number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        DevMap {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_DEVMAP,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Redirects the packet to the interface at `index`.
+    ///
+    /// Returns `XdpAction::Redirect` on success, which must be returned by
+    /// the program for the redirection to happen. On kernels that support
+    /// it, the lower bits of `flags` hold the action returned if the
+    /// redirection fails, otherwise `XdpAction::Aborted` is returned.
+    #[inline]
+    pub fn redirect(&mut self, index: u32, flags: u64) -> XdpAction {
+        let ret = unsafe { bpf_redirect_map(&mut self.def as *mut _ as *mut c_void, index, flags) };
+        XdpAction::from_redirect(ret)
+    }
+}
+
+/// CPU map.
+///
+/// High level API for BPF_MAP_TYPE_CPUMAP maps. Packets redirected to a CPU
+/// are processed by the network stack on that CPU, which can be used to
+/// spread the load over several CPUs. The elements are the sizes of the
+/// per-CPU queues, and are set from userspace.
+#[repr(transparent)]
+pub struct CpuMap {
+    def: bpf_map_def,
+}
+
+impl CpuMap {
+    /// Creates a map with the specified maximum number of elements.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        CpuMap {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_CPUMAP,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Redirects the packet to the CPU at `index`.
+    ///
+    /// See `DevMap::redirect()`.
+    #[inline]
+    pub fn redirect(&mut self, index: u32, flags: u64) -> XdpAction {
+        let ret = unsafe { bpf_redirect_map(&mut self.def as *mut _ as *mut c_void, index, flags) };
+        XdpAction::from_redirect(ret)
+    }
+}
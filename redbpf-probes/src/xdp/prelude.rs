@@ -13,9 +13,12 @@
 //! use redbpf_probes::xdp::prelude::*;
 //! ```
 pub use cty::*;
-pub use redbpf_macros::{map, program, xdp};
+pub use redbpf_macros::{config, map, program, xdp};
 pub use crate::bindings::*;
 pub use crate::helpers::*;
-pub use crate::maps::{HashMap, PerfMapFlags};
+pub use crate::maps::{
+    Array, ArrayOfMaps, HashMap, HashOfMaps, InnerMap, LpmKey, LpmTrie, LruHashMap, MapError,
+    PerCpuArray, PerfMapFlags, Queue, Stack, StackBuffer, UpdateFlags,
+};
 pub use crate::net::*;
 pub use crate::xdp::*;
\ No newline at end of file
@@ -1,24 +1,13 @@
 use std::env;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 use cargo_bpf_lib as cargo_bpf;
 
 fn main() {
-    let cargo = PathBuf::from(env::var("CARGO").unwrap());
     let target = PathBuf::from(env::var("OUT_DIR").unwrap());
-    let probes = Path::new("probes");
-    cargo_bpf::build(
-        &cargo,
-        &probes,
-        &target.join("target"),
-        Vec::new(),
-    )
-    .expect("couldn't compile probes");
-
-    cargo_bpf::probe_files(&probes)
-        .expect("couldn't list probe files")
-        .iter()
-        .for_each(|file| {
-            println!("cargo:rerun-if-changed={}", file);
-        });
+    let mut options = cargo_bpf::BuildOptions::new("probes");
+    options.target_dir = Some(target.join("target"));
+    if let Err(e) = cargo_bpf::build_probes(options) {
+        panic!("couldn't compile probes: {}", e);
+    }
 }
@@ -0,0 +1,48 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![no_std]
+#![no_main]
+use probes::enforce::{Killed, Rule, SysEnter};
+use redbpf_probes::tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+const SIGKILL: u32 = 9;
+
+#[map("rules")]
+static mut rules: HashMap<u32, Rule> = HashMap::with_max_entries(1);
+
+#[map("killed")]
+static mut killed: PerfMap<Killed> = PerfMap::with_max_entries(1024);
+
+#[tracepoint("raw_syscalls/sys_enter")]
+fn sys_enter(args: &SysEnter) {
+    let rule = match unsafe { rules.get(&0) } {
+        Some(rule) => *rule,
+        None => return,
+    };
+    if args.id as u64 != rule.syscall || rule.arg >= 6 {
+        return;
+    }
+    let value = args.args[rule.arg as usize] as u64;
+    if value != rule.value {
+        return;
+    }
+
+    // the process is killed before the syscall returns to user space
+    if send_signal(SIGKILL).is_err() {
+        return;
+    }
+
+    let event = Killed {
+        pid: bpf_get_current_pid_tgid() >> 32,
+        syscall: rule.syscall,
+        value,
+        comm: bpf_get_current_comm(),
+    };
+    unsafe { killed.insert(args as *const _ as *mut c_void, &event) };
+}
@@ -0,0 +1,38 @@
+use cty::*;
+
+use redbpf_probes::tracepoint::TracepointCommon;
+use redbpf_probes::Pod;
+
+/// Record of the `raw_syscalls/sys_enter` tracepoint.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SysEnter {
+    pub common: TracepointCommon,
+    pub id: c_long,
+    pub args: [c_ulong; 6],
+}
+
+impl SysEnter {
+    pub const FIELDS: &'static [(&'static str, usize, usize)] = &[("id", 8, 8), ("args", 16, 48)];
+}
+
+/// Processes calling `syscall` with `value` as argument number `arg` get
+/// killed.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Rule {
+    pub syscall: u64,
+    pub arg: u64,
+    pub value: u64,
+}
+
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct Killed {
+    pub pid: u64,
+    pub syscall: u64,
+    pub value: u64,
+    pub comm: [c_char; 16],
+}
+
+unsafe impl Pod for Killed {}
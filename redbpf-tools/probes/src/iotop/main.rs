@@ -1,7 +1,7 @@
 #![no_std]
 #![no_main]
 use probes::bindings::request;
-use probes::iotop::{Counter, CounterKey, Process};
+use probes::iotop::{Counter, CounterKey, Filter, Process};
 use redbpf_probes::kprobe::prelude::*;
 
 const REQ_OP_WRITE: u32 = 1;
@@ -17,10 +17,30 @@ static mut processes: HashMap<*const request, Process> = HashMap::with_max_entri
 #[map("counts")]
 static mut counts: HashMap<CounterKey, Counter> = HashMap::with_max_entries(10240);
 
+// indexed by `write`
+#[map("totals")]
+static mut totals: PerCpuArray<Counter> = PerCpuArray::with_max_entries(2);
+
+// set by userspace
+#[map("filter")]
+static mut filter: Array<Filter> = Array::with_max_entries(1);
+
+#[inline]
+fn filtering() -> bool {
+    unsafe { filter.get(0).map(|f| f.pid != 0).unwrap_or(false) }
+}
+
 #[kprobe]
 fn blk_account_io_start(regs: Registers) {
     let comm = bpf_get_current_comm();
     let pid = bpf_get_current_pid_tgid() >> 32;
+    // the requests of the processes filtered out have no process, and are
+    // skipped when they complete
+    if let Some(f) = unsafe { filter.get(0) } {
+        if f.pid != 0 && f.pid != pid {
+            return;
+        }
+    }
     let req = regs.parm1() as *const request;
     unsafe { processes.set(&req, &Process { pid, comm }) }
 }
@@ -65,6 +85,10 @@ fn do_complete(regs: Registers) -> Option<()> {
     };
     let process = match unsafe { processes.get(&req) } {
         Some(p) => p,
+        None if filtering() => {
+            unsafe { start.delete(&req) };
+            return None;
+        }
         None => &unknown_process,
     };
     let key = CounterKey {
@@ -74,24 +98,23 @@ fn do_complete(regs: Registers) -> Option<()> {
         write,
     };
 
-    let mut counter = unsafe {
-        match counts.get_mut(&key) {
-            Some(c) => c,
-            None => {
-                let zero = Counter {
-                    bytes: 0,
-                    us: 0,
-                    io: 0,
-                };
-                counts.set(&key, &zero);
-                counts.get_mut(&key)?
-            }
-        }
+    let zero = Counter {
+        bytes: 0,
+        us: 0,
+        io: 0,
     };
-
-    counter.bytes += request.__data_len()? as u64;
-    counter.us += delta_us;
-    counter.io += 1;
+    let bytes = request.__data_len()? as u64;
+    let counter = unsafe { counts.get_or_insert(&key, &zero)? };
+    atomic_add(&mut counter.bytes, bytes);
+    atomic_add(&mut counter.us, delta_us);
+    atomic_add(&mut counter.io, 1);
+
+    // per-CPU values don't need atomic updates
+    if let Some(total) = unsafe { totals.get_mut(write as u32) } {
+        total.bytes += bytes;
+        total.us += delta_us;
+        total.io += 1;
+    }
 
     unsafe {
         start.delete(&req);
@@ -22,4 +22,12 @@ pub struct Counter {
     pub bytes: u64,
     pub us: u64,
     pub io: u64
-}
\ No newline at end of file
+}
+
+// set by userspace in the single element of the `filter` map, a `pid` of 0
+// counts the I/O of all the processes
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct Filter {
+    pub pid: u64,
+}
@@ -1,3 +1,5 @@
+use redbpf_probes::Pod;
+
 pub const MAX_SEQ_LEN: usize = 4;
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -50,9 +52,13 @@ pub struct KnockAttempt {
     pub sequence: PortSequence,
 }
 
+unsafe impl Pod for KnockAttempt {}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct Connection {
     pub source_ip: u32,
     pub allowed: u32,
 }
+
+unsafe impl Pod for Connection {}
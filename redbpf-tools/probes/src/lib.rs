@@ -1,4 +1,8 @@
 #![no_std]
 pub mod bindings;
+pub mod enforce;
 pub mod iotop;
 pub mod knock;
+pub mod offcputime;
+pub mod opensnoop;
+pub mod tcplife;
@@ -0,0 +1,72 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![no_std]
+#![no_main]
+use probes::offcputime::{SchedSwitch, StackKey, Start};
+use redbpf_probes::tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// the threads switched out, by thread id
+#[map("starts")]
+static mut starts: HashMap<u32, Start> = HashMap::with_max_entries(10240);
+
+#[map("stacks")]
+static mut stacks: StackTraceMap = StackTraceMap::with_max_entries(16384);
+
+// microseconds spent off-CPU, by stacks
+#[map("counts")]
+static mut counts: HashMap<StackKey, u64> = HashMap::with_max_entries(10240);
+
+#[tracepoint("sched/sched_switch")]
+fn sched_switch(args: &SchedSwitch) {
+    switch_out(args);
+    let _ = switch_in(args);
+}
+
+// The stacks are collected when the thread is switched out, since the
+// tracepoint runs in the context of the previous thread.
+#[inline]
+fn switch_out(args: &SchedSwitch) {
+    // the idle tasks of all the CPUs have thread id 0
+    let tid = args.prev_pid() as u32;
+    if tid == 0 {
+        return;
+    }
+    let ctx = args as *const _ as *mut SchedSwitch;
+    let kernel_stack_id = match unsafe { stacks.stack_id(ctx, 0) } {
+        Ok(id) => id as i32,
+        Err(e) => e,
+    };
+    let user_stack_id = match unsafe { stacks.stack_id(ctx, BPF_F_USER_STACK.into()) } {
+        Ok(id) => id as i32,
+        Err(e) => e,
+    };
+    let start = Start {
+        ts: bpf_ktime_get_ns(),
+        key: StackKey {
+            pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+            kernel_stack_id,
+            user_stack_id,
+            comm: bpf_get_current_comm(),
+        },
+    };
+    unsafe { starts.set(&tid, &start) };
+}
+
+#[inline]
+fn switch_in(args: &SchedSwitch) -> Option<()> {
+    let tid = args.next_pid() as u32;
+    let start = unsafe { starts.get(&tid)? }.clone();
+    unsafe { starts.delete(&tid) };
+
+    let delta_us = (bpf_ktime_get_ns() - start.ts) / 1000;
+    let total = unsafe { counts.get_or_insert(&start.key, &0)? };
+    atomic_add(total, delta_us);
+
+    Some(())
+}
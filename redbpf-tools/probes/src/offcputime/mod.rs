@@ -0,0 +1,89 @@
+use cty::*;
+
+// the start of an off-CPU period of a thread, with the stacks it was
+// switched out from
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct Start {
+    pub ts: u64,
+    pub key: StackKey,
+}
+
+// NB: this needs to be kept in sync with `redbpf::profile::StackKey`
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct StackKey {
+    pub pid: u32,
+    pub kernel_stack_id: i32,
+    pub user_stack_id: i32,
+    pub comm: [c_char; 16],
+}
+
+// generated with `cargo bpf tracepoint sched/sched_switch`
+
+/// Record of the `sched/sched_switch` tracepoint.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct SchedSwitch {
+    common: ::redbpf_probes::tracepoint::TracepointCommon,
+    prev_comm: [i8; 16],
+    prev_pid: i32,
+    prev_prio: i32,
+    prev_state: i64,
+    next_comm: [i8; 16],
+    next_pid: i32,
+    next_prio: i32,
+}
+
+impl SchedSwitch {
+    /// The layout of the record as `(name, offset, size)` triples.
+    pub const FIELDS: &'static [(&'static str, usize, usize)] = &[
+        ("prev_comm", 8, 16),
+        ("prev_pid", 24, 4),
+        ("prev_prio", 28, 4),
+        ("prev_state", 32, 8),
+        ("next_comm", 40, 16),
+        ("next_pid", 56, 4),
+        ("next_prio", 60, 4),
+    ];
+
+    #[inline]
+    pub fn common(&self) -> ::redbpf_probes::tracepoint::TracepointCommon {
+        self.common
+    }
+
+    #[inline]
+    pub fn prev_comm(&self) -> [i8; 16] {
+        self.prev_comm
+    }
+
+    #[inline]
+    pub fn prev_pid(&self) -> i32 {
+        self.prev_pid
+    }
+
+    #[inline]
+    pub fn prev_prio(&self) -> i32 {
+        self.prev_prio
+    }
+
+    #[inline]
+    pub fn prev_state(&self) -> i64 {
+        self.prev_state
+    }
+
+    #[inline]
+    pub fn next_comm(&self) -> [i8; 16] {
+        self.next_comm
+    }
+
+    #[inline]
+    pub fn next_pid(&self) -> i32 {
+        self.next_pid
+    }
+
+    #[inline]
+    pub fn next_prio(&self) -> i32 {
+        self.next_prio
+    }
+}
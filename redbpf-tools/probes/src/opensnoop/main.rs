@@ -0,0 +1,74 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![no_std]
+#![no_main]
+use probes::opensnoop::{Open, FILENAME_LEN};
+use redbpf_probes::helpers::probe_read_user_str;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// the opens in progress, by thread
+#[map("opens")]
+static mut opens: HashMap<u64, Open> = HashMap::with_max_entries(10240);
+
+#[map("events")]
+static mut events: PerfMap<Open> = PerfMap::with_max_entries(1024);
+
+// Linux 5.6 and later, where do_sys_open calls do_sys_openat2. Userspace
+// attaches either these probes or the do_sys_open ones.
+#[kprobe("do_sys_openat2")]
+fn openat2_enter(regs: Registers) {
+    // struct open_how starts with the flags
+    let flags = unsafe { bpf_probe_read(regs.parm3() as *const u64) }.unwrap_or(0);
+    enter(regs.parm2() as *const u8, flags);
+}
+
+#[kretprobe("do_sys_openat2")]
+fn openat2_exit(regs: Registers) {
+    let _ = exit(regs);
+}
+
+#[kprobe("do_sys_open")]
+fn open_enter(regs: Registers) {
+    enter(regs.parm2() as *const u8, regs.parm3() as u32 as u64);
+}
+
+#[kretprobe("do_sys_open")]
+fn open_exit(regs: Registers) {
+    let _ = exit(regs);
+}
+
+#[inline]
+fn enter(filename: *const u8, flags: u64) {
+    let id = bpf_get_current_pid_tgid();
+    let mut open = Open {
+        ts: bpf_ktime_get_ns(),
+        pid: id >> 32,
+        flags,
+        ret: 0,
+        comm: bpf_get_current_comm(),
+        filename: [0; FILENAME_LEN],
+    };
+    // the open is reported with an empty name if it can't be read
+    let _ = unsafe { probe_read_user_str(&mut open.filename, filename) };
+    unsafe { opens.set(&id, &open) };
+}
+
+#[inline]
+fn exit(regs: Registers) -> Option<()> {
+    let id = bpf_get_current_pid_tgid();
+    // sent from the map, the event doesn't fit on the stack twice
+    let open = unsafe { opens.get_mut(&id)? };
+    open.ret = regs.rc() as i64;
+    unsafe {
+        events.insert(regs.ctx, open);
+        opens.delete(&id);
+    }
+
+    Some(())
+}
@@ -0,0 +1,20 @@
+use cty::*;
+
+use redbpf_probes::Pod;
+
+pub const FILENAME_LEN: usize = 256;
+
+// an open, from the entry of the function until it returns
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct Open {
+    pub ts: u64,
+    pub pid: u64,
+    pub flags: u64,
+    // the fd, or the negated errno
+    pub ret: i64,
+    pub comm: [c_char; 16],
+    pub filename: [u8; FILENAME_LEN],
+}
+
+unsafe impl Pod for Open {}
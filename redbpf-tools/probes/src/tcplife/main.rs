@@ -0,0 +1,103 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![no_std]
+#![no_main]
+use probes::tcplife::{Connection, Owner};
+use redbpf_probes::tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// the time sockets were established
+#[map("births")]
+static mut births: HashMap<*const c_void, u64> = HashMap::with_max_entries(10240);
+
+#[map("owners")]
+static mut owners: HashMap<*const c_void, Owner> = HashMap::with_max_entries(10240);
+
+#[map("connections")]
+static mut connections: PerfMap<Connection> = PerfMap::with_max_entries(1024);
+
+#[tracepoint("sock/inet_sock_set_state")]
+fn inet_sock_set_state(args: &InetSockSetState) {
+    if !args.is_tcp() {
+        return;
+    }
+
+    let _ = match args.new_state() {
+        Some(TcpState::SynSent) => owner(args, UpdateFlags::Any),
+        Some(TcpState::FinWait1) | Some(TcpState::LastAck) => owner(args, UpdateFlags::NoExist),
+        Some(TcpState::Established) => established(args),
+        Some(TcpState::Close) => close(args),
+        _ => None,
+    };
+}
+
+// Sockets are established in softirq context, where the current task is
+// unrelated to the socket. Like bcc's tcplife, the owner is recorded by
+// connect(), which moves active sockets to SYN_SENT, and otherwise by
+// close(), which moves them to FIN_WAIT1 or LAST_ACK. Sockets reset by the
+// peer are never closed in process context, and are reported without owner.
+#[inline]
+fn owner(args: &InetSockSetState, flags: UpdateFlags) -> Option<()> {
+    let owner = Owner {
+        pid: bpf_get_current_pid_tgid() >> 32,
+        comm: bpf_get_current_comm(),
+    };
+    let _ = unsafe { owners.set_flags(&args.skaddr, &owner, flags) };
+
+    Some(())
+}
+
+#[inline]
+fn established(args: &InetSockSetState) -> Option<()> {
+    let ts = bpf_ktime_get_ns();
+    unsafe { births.set(&args.skaddr, &ts) };
+
+    Some(())
+}
+
+#[inline]
+fn close(args: &InetSockSetState) -> Option<()> {
+    // sockets closed before they were established have an owner, but no
+    // birth
+    let owner = unsafe { owners.get(&args.skaddr) }
+        .cloned()
+        .unwrap_or(Owner {
+            pid: 0,
+            comm: [0; 16],
+        });
+    unsafe { owners.delete(&args.skaddr) };
+    let ts = unsafe { *births.get(&args.skaddr)? };
+    unsafe { births.delete(&args.skaddr) };
+
+    let bytes = TcpBytes::from_sock(unsafe { &*args.sock() })?;
+    let (saddr, daddr) = if args.family as u32 == AF_INET6 {
+        (args.saddr_v6, args.daddr_v6)
+    } else {
+        let mut saddr = [0u8; 16];
+        let mut daddr = [0u8; 16];
+        saddr[..4].copy_from_slice(&args.saddr);
+        daddr[..4].copy_from_slice(&args.daddr);
+        (saddr, daddr)
+    };
+    let conn = Connection {
+        pid: owner.pid,
+        comm: owner.comm,
+        family: args.family,
+        sport: args.sport,
+        dport: args.dport,
+        padding: 0,
+        saddr,
+        daddr,
+        rx_bytes: bytes.received,
+        tx_bytes: bytes.acked,
+        duration_ns: bpf_ktime_get_ns() - ts,
+    };
+    unsafe { connections.insert(args as *const _ as *mut c_void, &conn) };
+
+    Some(())
+}
@@ -0,0 +1,31 @@
+use cty::*;
+
+pub use redbpf_probes::tracepoint::{InetSockSetState, TcpState};
+use redbpf_probes::Pod;
+
+// the process using a socket, recorded by the transitions run in process
+// context
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct Owner {
+    pub pid: u64,
+    pub comm: [c_char; 16],
+}
+
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct Connection {
+    pub pid: u64,
+    pub comm: [c_char; 16],
+    pub family: u16,
+    pub sport: u16,
+    pub dport: u16,
+    pub padding: u16,
+    pub saddr: [u8; 16],
+    pub daddr: [u8; 16],
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub duration_ns: u64,
+}
+
+unsafe impl Pod for Connection {}
@@ -0,0 +1,158 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use futures::stream::StreamExt;
+use getopts::Options;
+use redbpf::{load::Loader, tracepoint::Format, Error, HashMap};
+use std::env;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::process;
+use tokio::runtime::Runtime;
+use tokio::signal;
+
+use probes::enforce::{Killed, Rule, SysEnter};
+
+fn main() {
+    let rule = match parse_opts() {
+        Some(rule) => rule,
+        None => process::exit(1),
+    };
+
+    let format =
+        Format::load("raw_syscalls", "sys_enter").and_then(|f| f.check_layout(SysEnter::FIELDS));
+    if let Err(e) = format {
+        eprintln!("unsupported raw_syscalls:sys_enter tracepoint: {:?}", e);
+        process::exit(1);
+    }
+
+    let mut runtime = Runtime::new().unwrap();
+    let _ = runtime.block_on(async {
+        // the rule must be in place before the program is attached
+        let mut loader = Loader::new()
+            .require_initialized(&["rules"])
+            .init(move |module| {
+                let map = module
+                    .maps
+                    .iter()
+                    .find(|m| m.name == "rules")
+                    .ok_or(Error::Map)?;
+                HashMap::<u32, Rule>::new(map)?.set(0, rule);
+                Ok(())
+            })
+            .load(probe_code())
+            .await
+            .expect("error loading probe");
+
+        println!("{:6} {:16} {:>7} {:>18}", "PID", "COMM", "SYSCALL", "VALUE");
+
+        tokio::spawn(async move {
+            while let Some((_, events)) = loader.events.next().await {
+                for event in events {
+                    let killed = match event.read::<Killed>() {
+                        Ok(killed) => killed,
+                        Err(e) => {
+                            eprintln!("{:?}", e);
+                            continue;
+                        }
+                    };
+                    let comm = unsafe { CStr::from_ptr(killed.comm.as_ptr() as *const c_char) }
+                        .to_string_lossy()
+                        .into_owned();
+
+                    println!(
+                        "{:<6} {:16} {:7} {:#18x}",
+                        killed.pid, comm, killed.syscall, killed.value
+                    );
+                }
+            }
+        });
+
+        signal::ctrl_c().await
+    });
+}
+
+fn parse_opts() -> Option<Rule> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.reqopt(
+        "s",
+        "syscall",
+        "the number of the syscall to watch, see ausyscall --dump",
+        "SYSCALL",
+    );
+    opts.reqopt(
+        "a",
+        "arg",
+        "the index of the argument to check, from 0 to 5",
+        "ARG",
+    );
+    opts.reqopt(
+        "v",
+        "value",
+        "kill the processes passing this value as argument, eg. 0x10",
+        "VALUE",
+    );
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}\n", f);
+            print_usage(&program, opts);
+            return None;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(&program, opts);
+        return None;
+    }
+
+    let syscall = matches.opt_str("s").and_then(|s| parse_u64(&s));
+    let arg = matches
+        .opt_str("a")
+        .and_then(|a| parse_u64(&a))
+        .filter(|a| *a < 6);
+    let value = matches.opt_str("v").and_then(|v| parse_u64(&v));
+    match (syscall, arg, value) {
+        (Some(syscall), Some(arg), Some(value)) => Some(Rule {
+            syscall,
+            arg,
+            value,
+        }),
+        _ => {
+            print_usage(&program, opts);
+            None
+        }
+    }
+}
+
+fn parse_u64(s: &str) -> Option<u64> {
+    if s.len() > 2 && &s[..2] == "0x" {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!(
+        "Usage: {} [options]\n\nKills the processes calling a syscall with a given argument.\n\
+         Requires Linux 5.3 or newer.",
+        program
+    );
+    print!("{}", opts.usage(&brief));
+}
+
+fn probe_code() -> &'static [u8] {
+    include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/target/bpf/programs/enforce/enforce.elf"
+    ))
+}
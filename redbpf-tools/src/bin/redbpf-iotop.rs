@@ -4,79 +4,412 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use redbpf::{load::Loader, HashMap as BPFHashMap};
+use clap::{App, Arg};
+use redbpf::{load::Loader, Array, HashMap as BPFHashMap, PerCpuArray};
+use std::cmp::{Ordering, Reverse};
 use std::collections::HashMap;
 use std::ffi::CStr;
+use std::fmt::Write;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
 use std::os::raw::c_char;
-use std::time::Duration;
-use tokio;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
 use tokio::signal;
 use tokio::time::delay_for;
 
-use probes::iotop::{Counter, CounterKey};
+use probes::iotop::{Counter, CounterKey, Filter};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Copy)]
+enum Sort {
+    Bytes,
+    Iops,
+    Latency,
+}
+
+struct Options {
+    interval: Duration,
+    count: Option<u64>,
+    output: Output,
+    sort: Sort,
+    pid: Option<u64>,
+    // a substring of the command
+    comm: Option<String>,
+    disk: Option<String>,
+}
+
+// The I/O of a process on a disk during an interval
+struct Row {
+    pid: u64,
+    comm: String,
+    write: bool,
+    major: i32,
+    minor: i32,
+    disk: String,
+    io: u64,
+    kbytes: u64,
+    avg_ms: f64,
+}
 
 fn main() {
+    let options = match parse_opts() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let disks = parse_diskstats().unwrap();
+    let disk = match &options.disk {
+        Some(name) => match disks.iter().find(|(_, disk)| *disk == name) {
+            Some((&disk, _)) => Some(disk),
+            None => {
+                eprintln!("unknown disk `{}'", name);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     let mut runtime = Runtime::new().unwrap();
-    let _ = runtime.block_on(async {
+    runtime.block_on(async {
+        // the processes are filtered by the probe, and by userspace in case
+        // the filter can't be set
+        let pid = options.pid.unwrap_or(0);
         let loader = Loader::new()
+            .init(move |module| {
+                if let Some(map) = module.maps.iter().find(|m| m.name == "filter") {
+                    if let Err(e) = Array::<Filter>::new(map).and_then(|f| f.set(0, Filter { pid }))
+                    {
+                        eprintln!("error setting the filter of the probe: {:?}", e);
+                    }
+                }
+                Ok(())
+            })
             .load(probe_code())
             .await
             .expect("error loading probe");
-        tokio::spawn(async move {
-            let counts = loader
-                .module
-                .maps
-                .iter()
-                .find(|m| m.name == "counts")
-                .unwrap();
-            let counts = BPFHashMap::<CounterKey, Counter>::new(counts).unwrap();
-            let disks = parse_diskstats().unwrap();
-
-            loop {
-                delay_for(Duration::from_millis(1000)).await;
-
-                println!(
-                    "{:6} {:16} {:1} {:3} {:3} {:8} {:>5} {:>7} {:>6}",
-                    "PID", "COMM", "D", "MAJ", "MIN", "DISK", "I/O", "Kbytes", "AVGms"
-                );
-
-                let mut items: Vec<(CounterKey, Counter)> = counts.iter().collect();
-                items.sort_unstable_by(|(_, av), (_, bv)| av.bytes.cmp(&bv.bytes));
-
-                for (k, v) in items.iter().rev() {
-                    let comm = unsafe { CStr::from_ptr(k.process.comm.as_ptr() as *const c_char) }
-                        .to_string_lossy()
-                        .into_owned();
-
-                    let unknown = String::from("?");
-                    let disk_name = disks.get(&(k.major, k.minor)).unwrap_or(&unknown);
-                    let avg_ms = v.us as f64 / 1000f64 / v.io as f64;
+        let counts = loader
+            .module
+            .maps
+            .iter()
+            .find(|m| m.name == "counts")
+            .unwrap();
+        let counts = BPFHashMap::<CounterKey, Counter>::new(counts).unwrap();
+        let totals = loader
+            .module
+            .maps
+            .iter()
+            .find(|m| m.name == "totals")
+            .unwrap();
+        let totals = PerCpuArray::<Counter>::new(totals).unwrap();
 
+        if options.output == Output::Csv {
+            println!("timestamp,pid,comm,dir,major,minor,disk,io,kbytes,avg_ms");
+        }
+        let report = async {
+            let mut previous = [(0, 0), (0, 0)];
+            let mut reports = 0;
+            while reports < options.count.unwrap_or(u64::MAX) {
+                delay_for(options.interval).await;
+                reports += 1;
+
+                // draining the counts leaves only the I/O of the next interval
+                let mut rows: Vec<Row> = counts
+                    .drain()
+                    .expect("error reading the counts")
+                    .into_iter()
+                    .map(|(k, v)| row(&k, &v, &disks))
+                    .filter(|row| selected(&options, disk, row))
+                    .collect();
+                sort(&mut rows, options.sort);
+
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                match options.output {
+                    Output::Table => print_table(&rows),
+                    Output::Json => {
+                        for row in rows.iter() {
+                            println!("{}", json_row(timestamp, row));
+                        }
+                        continue;
+                    }
+                    Output::Csv => {
+                        for row in rows.iter() {
+                            println!("{}", csv_row(timestamp, row));
+                        }
+                        continue;
+                    }
+                }
+
+                // the totals are updated without atomics, so they are
+                // reported as the difference with the previous interval
+                // rather than cleared
+                for (write, cpu_totals) in totals.iter().enumerate() {
+                    let (io, bytes) = cpu_totals
+                        .iter()
+                        .fold((0, 0), |(io, bytes), t| (io + t.io, bytes + t.bytes));
+                    let (previous_io, previous_bytes) = previous[write];
+                    previous[write] = (io, bytes);
                     println!(
-                        "{:<6} {:16} {:1} {:3} {:3} {:8} {:5} {:7} {:6.2}",
-                        k.process.pid,
-                        comm,
-                        if k.write != 0 { "W" } else { "R" },
-                        k.major,
-                        k.minor,
-                        disk_name,
-                        v.io,
-                        v.bytes / 1024,
-                        avg_ms
+                        "Total {}: {} I/O, {} Kbytes",
+                        if write != 0 { "W" } else { "R" },
+                        io - previous_io,
+                        (bytes - previous_bytes) / 1024
                     );
                 }
 
-                println!("");
+                println!();
             }
-        });
+        };
 
-        signal::ctrl_c().await
+        tokio::select! {
+            _ = report => {}
+            _ = signal::ctrl_c() => {}
+        }
     });
 }
 
+fn parse_opts() -> Result<Options, String> {
+    let matches = App::new("redbpf-iotop")
+        .about("Shows the disk I/O of each process, every interval")
+        .arg(
+            Arg::with_name("INTERVAL")
+                .short("i")
+                .long("interval")
+                .value_name("SECS")
+                .default_value("1")
+                .help("Prints the I/O every SECS seconds"),
+        )
+        .arg(
+            Arg::with_name("COUNT")
+                .short("c")
+                .long("count")
+                .value_name("N")
+                .help("Exits after N intervals"),
+        )
+        .arg(
+            Arg::with_name("JSON")
+                .long("json")
+                .conflicts_with("CSV")
+                .help("Prints a JSON object per process and disk"),
+        )
+        .arg(
+            Arg::with_name("CSV")
+                .long("csv")
+                .help("Prints a CSV row per process and disk, after a header"),
+        )
+        .arg(
+            Arg::with_name("SORT")
+                .short("s")
+                .long("sort")
+                .value_name("KEY")
+                .possible_values(&["bytes", "iops", "latency"])
+                .default_value("bytes")
+                .help("Sorts the processes by bytes, I/O operations or average latency"),
+        )
+        .arg(
+            Arg::with_name("PID")
+                .short("p")
+                .long("pid")
+                .value_name("PID")
+                .help("Shows only the I/O of the process PID"),
+        )
+        .arg(
+            Arg::with_name("COMM")
+                .long("comm")
+                .value_name("TEXT")
+                .help("Shows only the I/O of the processes whose command contains TEXT"),
+        )
+        .arg(
+            Arg::with_name("DISK")
+                .short("d")
+                .long("disk")
+                .value_name("NAME")
+                .help("Shows only the I/O on the disk NAME, eg. sda or nvme0n1"),
+        )
+        .get_matches();
+
+    let interval = matches.value_of("INTERVAL").unwrap();
+    let interval = match interval.parse::<f64>() {
+        Ok(secs) if secs > 0.0 => Duration::from_secs_f64(secs),
+        _ => return Err(format!("invalid interval `{}'", interval)),
+    };
+    let count = match matches.value_of("COUNT") {
+        Some(count) => Some(
+            count
+                .parse()
+                .map_err(|_| format!("invalid count `{}'", count))?,
+        ),
+        None => None,
+    };
+    let output = if matches.is_present("JSON") {
+        Output::Json
+    } else if matches.is_present("CSV") {
+        Output::Csv
+    } else {
+        Output::Table
+    };
+    let sort = match matches.value_of("SORT").unwrap() {
+        "iops" => Sort::Iops,
+        "latency" => Sort::Latency,
+        _ => Sort::Bytes,
+    };
+
+    let pid = match matches.value_of("PID") {
+        Some(pid) => Some(pid.parse().map_err(|_| format!("invalid pid `{}'", pid))?),
+        None => None,
+    };
+
+    Ok(Options {
+        interval,
+        count,
+        output,
+        sort,
+        pid,
+        comm: matches.value_of("COMM").map(String::from),
+        disk: matches.value_of("DISK").map(String::from),
+    })
+}
+
+fn row(k: &CounterKey, v: &Counter, disks: &HashMap<(i32, i32), String>) -> Row {
+    let comm = unsafe { CStr::from_ptr(k.process.comm.as_ptr() as *const c_char) }
+        .to_string_lossy()
+        .into_owned();
+
+    Row {
+        pid: k.process.pid,
+        comm,
+        write: k.write != 0,
+        major: k.major,
+        minor: k.minor,
+        disk: disks
+            .get(&(k.major, k.minor))
+            .cloned()
+            .unwrap_or_else(|| "?".to_string()),
+        io: v.io,
+        kbytes: v.bytes / 1024,
+        avg_ms: v.us as f64 / 1000f64 / v.io as f64,
+    }
+}
+
+// Whether `row` passes the filters of `options`, and is on `disk` if set
+fn selected(options: &Options, disk: Option<(i32, i32)>, row: &Row) -> bool {
+    if let Some(disk) = disk {
+        if disk != (row.major, row.minor) {
+            return false;
+        }
+    }
+    if let Some(pid) = options.pid {
+        if pid != row.pid {
+            return false;
+        }
+    }
+    match &options.comm {
+        Some(comm) => row.comm.contains(comm.as_str()),
+        None => true,
+    }
+}
+
+// Sorts the rows in decreasing order of `key`
+fn sort(rows: &mut [Row], key: Sort) {
+    match key {
+        Sort::Bytes => rows.sort_unstable_by_key(|row| Reverse(row.kbytes)),
+        Sort::Iops => rows.sort_unstable_by_key(|row| Reverse(row.io)),
+        Sort::Latency => {
+            rows.sort_unstable_by(|a, b| b.avg_ms.partial_cmp(&a.avg_ms).unwrap_or(Ordering::Equal))
+        }
+    }
+}
+
+fn print_table(rows: &[Row]) {
+    println!(
+        "{:6} {:16} {:1} {:3} {:3} {:8} {:>5} {:>7} {:>6}",
+        "PID", "COMM", "D", "MAJ", "MIN", "DISK", "I/O", "Kbytes", "AVGms"
+    );
+    for row in rows {
+        println!(
+            "{:<6} {:16} {:1} {:3} {:3} {:8} {:5} {:7} {:6.2}",
+            row.pid,
+            row.comm,
+            if row.write { "W" } else { "R" },
+            row.major,
+            row.minor,
+            row.disk,
+            row.io,
+            row.kbytes,
+            row.avg_ms
+        );
+    }
+}
+
+fn json_row(timestamp: u64, row: &Row) -> String {
+    format!(
+        "{{\"timestamp\":{},\"pid\":{},\"comm\":{},\"dir\":\"{}\",\"major\":{},\"minor\":{},\"disk\":{},\"io\":{},\"kbytes\":{},\"avg_ms\":{:.2}}}",
+        timestamp,
+        row.pid,
+        json_string(&row.comm),
+        if row.write { "W" } else { "R" },
+        row.major,
+        row.minor,
+        json_string(&row.disk),
+        row.io,
+        row.kbytes,
+        row.avg_ms
+    )
+}
+
+fn csv_row(timestamp: u64, row: &Row) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{},{},{:.2}",
+        timestamp,
+        row.pid,
+        csv_field(&row.comm),
+        if row.write { "W" } else { "R" },
+        row.major,
+        row.minor,
+        csv_field(&row.disk),
+        row.io,
+        row.kbytes,
+        row.avg_ms
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+// Quotes the fields with separators or quotes
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
 fn parse_diskstats() -> io::Result<HashMap<(i32, i32), String>> {
     let file = File::open("/proc/diskstats")?;
     let reader = BufReader::new(file);
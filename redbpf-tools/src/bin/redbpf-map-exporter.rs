@@ -0,0 +1,54 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Prints the I/O counters of the iotop probe loaded by redbpf-map-owner, in
+// the Prometheus text format. Runs without privileges: the map is received
+// over the socket of the owner.
+use redbpf::{fdpass, HashMap};
+use std::env;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use probes::iotop::{Counter, CounterKey};
+
+const DEFAULT_SOCKET: &str = "/run/redbpf-iotop.sock";
+
+fn main() {
+    let path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_SOCKET.to_string());
+    let socket = UnixStream::connect(&path).unwrap_or_else(|e| {
+        eprintln!("error connecting to {}: {}", path, e);
+        process::exit(1);
+    });
+    let map = fdpass::recv_map(&socket).expect("error receiving map");
+    // checks the key and value sizes against the map
+    let counts = HashMap::<CounterKey, Counter>::new(&map).expect("unexpected map layout");
+
+    loop {
+        for (k, v) in counts.iter() {
+            let comm = unsafe { CStr::from_ptr(k.process.comm.as_ptr() as *const c_char) }
+                .to_string_lossy()
+                .into_owned();
+            let op = if k.write != 0 { "write" } else { "read" };
+            println!(
+                "iotop_bytes{{pid=\"{}\",comm=\"{}\",op=\"{}\"}} {}",
+                k.process.pid, comm, op, v.bytes
+            );
+            println!(
+                "iotop_ios{{pid=\"{}\",comm=\"{}\",op=\"{}\"}} {}",
+                k.process.pid, comm, op, v.io
+            );
+        }
+        println!();
+        thread::sleep(Duration::from_secs(5));
+    }
+}
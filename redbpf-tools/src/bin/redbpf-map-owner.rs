@@ -0,0 +1,66 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// Loads the iotop probe and hands its `counts` map to the processes
+// connecting to a unix socket, see redbpf-map-exporter.
+use redbpf::{fdpass, load::Loader, Map};
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::thread;
+use tokio::runtime::Runtime;
+use tokio::signal;
+
+const DEFAULT_SOCKET: &str = "/run/redbpf-iotop.sock";
+
+fn main() {
+    let path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_SOCKET.to_string());
+
+    let mut runtime = Runtime::new().unwrap();
+    let _ = runtime.block_on(async {
+        let loader = Loader::new()
+            .load(probe_code())
+            .await
+            .expect("error loading probe");
+        let counts = loader
+            .module
+            .maps
+            .iter()
+            .find(|m| m.name == "counts")
+            .unwrap();
+        // a handle the listener thread can own, sharing the fd of the map
+        let counts = Map::from_fd(counts.fd()).unwrap();
+
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).expect("error binding socket");
+        // the exporter doesn't need any privileges
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.map_err(redbpf::Error::IO);
+                if let Err(e) = result.and_then(|s| fdpass::send_map(&s, &counts)) {
+                    eprintln!("error sending map: {:?}", e);
+                }
+            }
+        });
+
+        println!("serving the counts map on {}", path);
+        let ret = signal::ctrl_c().await;
+        let _ = fs::remove_file(&path);
+        ret
+    });
+}
+
+fn probe_code() -> &'static [u8] {
+    include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/target/bpf/programs/iotop/iotop.elf"
+    ))
+}
@@ -0,0 +1,171 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use clap::{App, Arg};
+use redbpf::profile::{self, FoldedStacks, StackKey, Symbolizer};
+use redbpf::symbols::{KernelSymbols, ProcessSymbols};
+use redbpf::{load::Loader, tracepoint::Format, HashMap as BPFHashMap, StackTraceMap};
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs;
+use std::process;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::signal;
+use tokio::time::delay_for;
+
+use probes::offcputime::SchedSwitch;
+
+struct Options {
+    duration: Option<Duration>,
+    annotate: bool,
+    svg: Option<String>,
+}
+
+// The symbols of the processes with stacks. Processes that exited before
+// their symbols were read are resolved as addresses.
+struct UserSymbols {
+    processes: HashMap<u32, ProcessSymbols>,
+}
+
+impl Symbolizer for UserSymbols {
+    fn symbol(&self, pid: u32, addr: u64) -> Option<String> {
+        self.processes.get(&pid)?.symbol(pid, addr)
+    }
+}
+
+fn main() {
+    let options = match parse_opts() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // refuse to run if the tracepoint record doesn't have the layout the
+    // probe was compiled for
+    let format =
+        Format::load("sched", "sched_switch").and_then(|f| f.check_layout(SchedSwitch::FIELDS));
+    if let Err(e) = format {
+        eprintln!("unsupported sched:sched_switch tracepoint: {:?}", e);
+        process::exit(1);
+    }
+
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(async {
+        let loader = Loader::new()
+            .load(probe_code())
+            .await
+            .expect("error loading probe");
+
+        match options.duration {
+            Some(duration) => tokio::select! {
+                _ = delay_for(duration) => {}
+                _ = signal::ctrl_c() => {}
+            },
+            None => {
+                let _ = signal::ctrl_c().await;
+            }
+        }
+
+        let counts = loader
+            .module
+            .maps
+            .iter()
+            .find(|m| m.name == "counts")
+            .unwrap();
+        let counts: Vec<(StackKey, u64)> = BPFHashMap::<StackKey, u64>::new(counts)
+            .unwrap()
+            .iter()
+            .collect();
+        let stacks = loader
+            .module
+            .maps
+            .iter()
+            .find(|m| m.name == "stacks")
+            .unwrap();
+        let stacks = StackTraceMap::new(stacks).unwrap();
+
+        let kernel = KernelSymbols::load().expect("error reading the kernel symbols");
+        let mut user = UserSymbols {
+            processes: HashMap::new(),
+        };
+        for (key, _) in counts.iter() {
+            if let Entry::Vacant(entry) = user.processes.entry(key.pid) {
+                if let Ok(symbols) = ProcessSymbols::new(key.pid) {
+                    entry.insert(symbols);
+                }
+            }
+        }
+
+        // the folded lines are counted in microseconds
+        let lines = FoldedStacks::new(counts.into_iter(), &stacks, &kernel, &user)
+            .annotate(options.annotate);
+        match &options.svg {
+            Some(path) => {
+                let svg = profile::svg(lines).unwrap_or_else(|e| {
+                    eprintln!("error rendering the flamegraph: {}", e);
+                    process::exit(1);
+                });
+                if let Err(e) = fs::write(path, svg) {
+                    eprintln!("error writing `{}': {}", path, e);
+                    process::exit(1);
+                }
+            }
+            None => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+        }
+    });
+}
+
+fn parse_opts() -> Result<Options, String> {
+    let matches = App::new("redbpf-offcputime")
+        .about("Prints the time threads spend blocked, by stack, as folded stacks")
+        .arg(
+            Arg::with_name("DURATION")
+                .short("d")
+                .long("duration")
+                .value_name("SECS")
+                .help("Traces for SECS seconds instead of until interrupted"),
+        )
+        .arg(
+            Arg::with_name("ANNOTATE")
+                .short("a")
+                .long("annotate")
+                .help("Appends _[k] to kernel frames"),
+        )
+        .arg(
+            Arg::with_name("SVG")
+                .long("svg")
+                .value_name("FILE")
+                .help("Writes a flamegraph to FILE, with flamegraph.pl"),
+        )
+        .get_matches();
+
+    let duration = match matches.value_of("DURATION") {
+        Some(duration) => match duration.parse::<f64>() {
+            Ok(secs) if secs > 0.0 => Some(Duration::from_secs_f64(secs)),
+            _ => return Err(format!("invalid duration `{}'", duration)),
+        },
+        None => None,
+    };
+
+    Ok(Options {
+        duration,
+        annotate: matches.is_present("ANNOTATE"),
+        svg: matches.value_of("SVG").map(String::from),
+    })
+}
+
+fn probe_code() -> &'static [u8] {
+    include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/target/bpf/programs/offcputime/offcputime.elf"
+    ))
+}
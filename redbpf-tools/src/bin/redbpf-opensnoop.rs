@@ -0,0 +1,177 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use clap::{App, Arg};
+use futures::stream::StreamExt;
+use redbpf::{load::Loader, targets::KernelTargets};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::process;
+use tokio::runtime::Runtime;
+use tokio::signal;
+
+use probes::opensnoop::Open;
+
+struct Options {
+    pid: Option<u64>,
+    // a substring of the file name
+    name: Option<String>,
+    failed: bool,
+    timestamp: bool,
+}
+
+fn main() {
+    let options = match parse_opts() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // since Linux 5.6 do_sys_open calls do_sys_openat2, only one of them
+    // is probed so that opens aren't reported twice
+    let function = if KernelTargets::load()
+        .check_function("do_sys_openat2")
+        .is_ok()
+    {
+        "do_sys_openat2"
+    } else {
+        "do_sys_open"
+    };
+
+    let mut runtime = Runtime::new().unwrap();
+    let _ = runtime.block_on(async {
+        let mut loader = Loader::new()
+            .programs(&[function])
+            .load(probe_code())
+            .await
+            .expect("error loading probe");
+
+        if options.timestamp {
+            print!("{:<10} ", "TIME(s)");
+        }
+        println!(
+            "{:6} {:16} {:>4} {:>3} {:>8} PATH",
+            "PID", "COMM", "FD", "ERR", "FLAGS"
+        );
+
+        tokio::spawn(async move {
+            // timestamps are relative to the first open
+            let mut start = None;
+            while let Some((_, events)) = loader.events.next().await {
+                for event in events {
+                    let open = match event.read::<Open>() {
+                        Ok(open) => open,
+                        Err(e) => {
+                            eprintln!("{:?}", e);
+                            continue;
+                        }
+                    };
+                    let comm = unsafe { CStr::from_ptr(open.comm.as_ptr() as *const c_char) }
+                        .to_string_lossy()
+                        .into_owned();
+                    let len = open
+                        .filename
+                        .iter()
+                        .position(|b| *b == 0)
+                        .unwrap_or(open.filename.len());
+                    let filename = String::from_utf8_lossy(&open.filename[..len]).into_owned();
+                    if !selected(&options, &open, &filename) {
+                        continue;
+                    }
+
+                    if options.timestamp {
+                        // the events of different CPUs can arrive out of order
+                        let start = *start.get_or_insert(open.ts);
+                        print!(
+                            "{:<10.3} ",
+                            open.ts.saturating_sub(start) as f64 / 1_000_000_000f64
+                        );
+                    }
+                    let (fd, err) = if open.ret < 0 {
+                        (-1, -open.ret)
+                    } else {
+                        (open.ret, 0)
+                    };
+                    println!(
+                        "{:<6} {:16} {:4} {:3} {:08o} {}",
+                        open.pid, comm, fd, err, open.flags, filename
+                    );
+                }
+            }
+        });
+
+        signal::ctrl_c().await
+    });
+}
+
+fn parse_opts() -> Result<Options, String> {
+    let matches = App::new("redbpf-opensnoop")
+        .about("Shows the files opened by the processes")
+        .arg(
+            Arg::with_name("PID")
+                .short("p")
+                .long("pid")
+                .value_name("PID")
+                .help("Shows only the files opened by the process PID"),
+        )
+        .arg(
+            Arg::with_name("NAME")
+                .short("n")
+                .long("name")
+                .value_name("TEXT")
+                .help("Shows only the files whose name contains TEXT"),
+        )
+        .arg(
+            Arg::with_name("FAILED")
+                .short("x")
+                .long("failed")
+                .help("Shows only the opens that failed"),
+        )
+        .arg(
+            Arg::with_name("TIMESTAMP")
+                .short("T")
+                .long("timestamp")
+                .help("Prints the time of the opens, in seconds since the first one"),
+        )
+        .get_matches();
+
+    let pid = match matches.value_of("PID") {
+        Some(pid) => Some(pid.parse().map_err(|_| format!("invalid pid `{}'", pid))?),
+        None => None,
+    };
+
+    Ok(Options {
+        pid,
+        name: matches.value_of("NAME").map(String::from),
+        failed: matches.is_present("FAILED"),
+        timestamp: matches.is_present("TIMESTAMP"),
+    })
+}
+
+// Whether the open of `filename` passes the filters of `options`
+fn selected(options: &Options, open: &Open, filename: &str) -> bool {
+    if let Some(pid) = options.pid {
+        if pid != open.pid {
+            return false;
+        }
+    }
+    if options.failed && open.ret >= 0 {
+        return false;
+    }
+    match &options.name {
+        Some(name) => filename.contains(name.as_str()),
+        None => true,
+    }
+}
+
+fn probe_code() -> &'static [u8] {
+    include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/target/bpf/programs/opensnoop/opensnoop.elf"
+    ))
+}
@@ -10,7 +10,6 @@ use redbpf::{load::Loader, xdp, HashMap};
 use std::env;
 use std::net::Ipv4Addr;
 use std::process;
-use std::ptr;
 use tokio;
 use tokio::runtime::Runtime;
 use tokio::signal;
@@ -56,7 +55,13 @@ fn main() {
                 for event in events {
                     match name.as_str() {
                         "knock_attempts" => {
-                            let knock = unsafe { ptr::read(event.as_ptr() as *const KnockAttempt) };
+                            let knock = match event.read::<KnockAttempt>() {
+                                Ok(knock) => knock,
+                                Err(e) => {
+                                    eprintln!("{:?}", e);
+                                    continue;
+                                }
+                            };
                             let seq = &knock.sequence;
                             println!(
                                 "Received knock from {} sequence {}",
@@ -76,7 +81,13 @@ fn main() {
                             )
                         }
                         "connections" => {
-                            let conn = unsafe { ptr::read(event.as_ptr() as *const Connection) };
+                            let conn = match event.read::<Connection>() {
+                                Ok(conn) => conn,
+                                Err(e) => {
+                                    eprintln!("{:?}", e);
+                                    continue;
+                                }
+                            };
                             println!(
                                 "{} access from {:?}",
                                 if conn.allowed == 1 {
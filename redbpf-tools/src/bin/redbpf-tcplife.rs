@@ -0,0 +1,89 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use futures::stream::StreamExt;
+use redbpf::{load::Loader, tracepoint::Format};
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::raw::c_char;
+use std::process;
+use tokio::runtime::Runtime;
+use tokio::signal;
+
+use probes::tcplife::{Connection, InetSockSetState};
+
+const AF_INET6: u16 = 10;
+
+fn main() {
+    // refuse to run if the tracepoint record doesn't have the layout the
+    // probe was compiled for
+    let format = Format::load("sock", "inet_sock_set_state")
+        .and_then(|f| f.check_layout(InetSockSetState::FIELDS));
+    if let Err(e) = format {
+        eprintln!("unsupported sock:inet_sock_set_state tracepoint: {:?}", e);
+        process::exit(1);
+    }
+
+    let mut runtime = Runtime::new().unwrap();
+    let _ = runtime.block_on(async {
+        let mut loader = Loader::new()
+            .load(probe_code())
+            .await
+            .expect("error loading probe");
+
+        println!(
+            "{:6} {:16} {:39} {:5} {:39} {:5} {:>6} {:>6} {:>9}",
+            "PID", "COMM", "LADDR", "LPORT", "RADDR", "RPORT", "TX_KB", "RX_KB", "MS"
+        );
+
+        tokio::spawn(async move {
+            while let Some((_, events)) = loader.events.next().await {
+                for event in events {
+                    let conn = match event.read::<Connection>() {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            eprintln!("{:?}", e);
+                            continue;
+                        }
+                    };
+                    let comm = unsafe { CStr::from_ptr(conn.comm.as_ptr() as *const c_char) }
+                        .to_string_lossy()
+                        .into_owned();
+
+                    println!(
+                        "{:<6} {:16} {:39} {:<5} {:39} {:<5} {:6} {:6} {:9.2}",
+                        conn.pid,
+                        comm,
+                        addr(conn.family, &conn.saddr),
+                        conn.sport,
+                        addr(conn.family, &conn.daddr),
+                        conn.dport,
+                        conn.tx_bytes / 1024,
+                        conn.rx_bytes / 1024,
+                        conn.duration_ns as f64 / 1_000_000f64
+                    );
+                }
+            }
+        });
+
+        signal::ctrl_c().await
+    });
+}
+
+fn addr(family: u16, addr: &[u8; 16]) -> IpAddr {
+    if family == AF_INET6 {
+        IpAddr::V6(Ipv6Addr::from(*addr))
+    } else {
+        IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+    }
+}
+
+fn probe_code() -> &'static [u8] {
+    include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/target/bpf/programs/tcplife/tcplife.elf"
+    ))
+}
@@ -0,0 +1,78 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The BPF filesystem.
+//!
+//! Maps and programs are pinned by creating files for them on a BPF
+//! filesystem, usually mounted at `/sys/fs/bpf`.
+use std::ffi::CString;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use crate::{Error, Result};
+
+/// The usual mount point of the BPF filesystem.
+pub const BPFFS_PATH: &str = "/sys/fs/bpf";
+
+const BPF_FS_MAGIC: i64 = 0xcafe_4a11;
+
+/// Returns `true` if `path` is on a BPF filesystem.
+pub fn is_bpffs(path: &Path) -> Result<bool> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statfs>::zeroed();
+    if unsafe { libc::statfs(cpath.as_ptr(), stat.as_mut_ptr()) } < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    // the type of `f_type` differs between architectures and libcs
+    #[allow(clippy::unnecessary_cast)]
+    let fs_type = stat.f_type as i64;
+
+    Ok(fs_type == BPF_FS_MAGIC)
+}
+
+/// Mounts the BPF filesystem at `BPFFS_PATH`, unless it's mounted already.
+pub fn mount_bpffs() -> Result<()> {
+    let path = Path::new(BPFFS_PATH);
+    if is_bpffs(path)? {
+        return Ok(());
+    }
+    let cpath = CString::new(BPFFS_PATH)?;
+    let fstype = CString::new("bpf")?;
+    if unsafe {
+        libc::mount(
+            fstype.as_ptr(),
+            cpath.as_ptr(),
+            fstype.as_ptr(),
+            0,
+            ptr::null(),
+        )
+    } < 0
+    {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+// Fails with `Error::NotBpffs` if the directory containing `path` isn't on
+// a BPF filesystem
+pub(crate) fn check_parent(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !is_bpffs(dir)? {
+        return Err(Error::NotBpffs(dir.to_path_buf()));
+    }
+
+    Ok(())
+}
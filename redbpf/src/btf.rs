@@ -0,0 +1,978 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! BTF type information.
+//!
+//! Objects compiled with clang for libbpf describe their types in a `.BTF`
+//! section. Maps defined in the `.maps` section have no `bpf_map_def`: their
+//! type, sizes and flags are encoded in the BTF type of the map variable,
+//! and read with `Btf::map_defs()`:
+//!
+//! ```no_run
+//! use redbpf::btf::Btf;
+//!
+//! # let section: &[u8] = &[];
+//! let btf = Btf::parse(section).unwrap();
+//! for (name, def) in btf.map_defs().unwrap() {
+//!     println!("{}: {} entries", name, def.max_entries);
+//! }
+//! ```
+use bpf_sys::bpf_map_def;
+use std::cmp;
+use std::collections::HashMap as RSHashMap;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::sys::bpf::bpf_btf_load;
+use crate::{Error, Result};
+
+/// The name of the section of BTF defined maps.
+pub const MAPS_SECTION: &str = ".maps";
+
+const KERNEL_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+const EXT_HEADER_SIZE: usize = 24;
+const EXT_CORE_HEADER_SIZE: usize = 32;
+const LOG_BUF_SIZE: usize = 64 * 1024;
+const MAX_LOG_BUF_SIZE: usize = (u32::MAX >> 8) as usize;
+
+const BTF_MAGIC: u16 = 0xeb9f;
+const HEADER_SIZE: usize = 24;
+
+const KIND_INT: u32 = 1;
+const KIND_PTR: u32 = 2;
+const KIND_ARRAY: u32 = 3;
+const KIND_STRUCT: u32 = 4;
+const KIND_UNION: u32 = 5;
+const KIND_ENUM: u32 = 6;
+const KIND_FWD: u32 = 7;
+const KIND_TYPEDEF: u32 = 8;
+const KIND_VOLATILE: u32 = 9;
+const KIND_CONST: u32 = 10;
+const KIND_RESTRICT: u32 = 11;
+const KIND_FUNC: u32 = 12;
+const KIND_FUNC_PROTO: u32 = 13;
+const KIND_VAR: u32 = 14;
+const KIND_DATASEC: u32 = 15;
+const KIND_FLOAT: u32 = 16;
+const KIND_DECL_TAG: u32 = 17;
+const KIND_TYPE_TAG: u32 = 18;
+const KIND_ENUM64: u32 = 19;
+
+/// A type of a BTF section. Types refer to each other by their index, the
+/// type id. Id 0 is `void`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfType {
+    /// The name of the type, empty for anonymous types
+    pub name: String,
+    pub kind: BtfKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BtfKind {
+    Void,
+    Int {
+        size: u32,
+        encoding: u32,
+    },
+    Ptr(u32),
+    Array {
+        elem: u32,
+        index: u32,
+        nelems: u32,
+    },
+    Struct {
+        size: u32,
+        members: Vec<BtfMember>,
+    },
+    Union {
+        size: u32,
+        members: Vec<BtfMember>,
+    },
+    /// `ENUM` and `ENUM64` types
+    Enum {
+        size: u32,
+        values: Vec<(String, i64)>,
+    },
+    Fwd,
+    Typedef(u32),
+    Volatile(u32),
+    Const(u32),
+    Restrict(u32),
+    Func(u32),
+    FuncProto {
+        ret: u32,
+        params: Vec<(String, u32)>,
+    },
+    Var {
+        type_id: u32,
+        linkage: u32,
+    },
+    Datasec {
+        size: u32,
+        vars: Vec<BtfVarSecinfo>,
+    },
+    Float {
+        size: u32,
+    },
+    DeclTag {
+        type_id: u32,
+        component: i32,
+    },
+    TypeTag(u32),
+}
+
+/// A member of a struct or a union.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfMember {
+    pub name: String,
+    pub type_id: u32,
+    /// The offset from the start of the struct in bits
+    pub bit_offset: u32,
+    /// The size of bitfields in bits, 0 for other members
+    pub bitfield_size: u32,
+}
+
+/// A variable of a data section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtfVarSecinfo {
+    /// The id of the `BtfKind::Var`
+    pub type_id: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// The types of a BTF section.
+#[derive(Debug, Clone)]
+pub struct Btf {
+    types: Vec<BtfType>,
+}
+
+impl Btf {
+    /// Parses the contents of a `.BTF` section.
+    pub fn parse(bytes: &[u8]) -> Result<Btf> {
+        let (type_data, strings) = sections(bytes)?;
+
+        let mut types = vec![BtfType {
+            name: String::new(),
+            kind: BtfKind::Void,
+        }];
+        let mut offset = 0;
+        while offset < type_data.len() {
+            let (ty, size) = parse_type(&type_data[offset..], strings)?;
+            types.push(ty);
+            offset += size;
+        }
+
+        Ok(Btf { types })
+    }
+
+    /// Creates a BTF section holding `types`, with ids starting at 1 after
+    /// `void`.
+    pub fn from_types(types: Vec<BtfType>) -> Btf {
+        let mut all = vec![BtfType {
+            name: String::new(),
+            kind: BtfKind::Void,
+        }];
+        all.extend(types);
+
+        Btf { types: all }
+    }
+
+    /// Reads the BTF of the running kernel, from `/sys/kernel/btf/vmlinux`.
+    ///
+    /// Requires Linux 5.4 built with `CONFIG_DEBUG_INFO_BTF`. The BTF of
+    /// other kernels can be generated from their debug info with `pahole -J`,
+    /// and read with `parse()`.
+    pub fn from_kernel() -> Result<Btf> {
+        Btf::parse(&fs::read(KERNEL_BTF)?)
+    }
+
+    /// Returns all the types, indexed by id.
+    pub fn types(&self) -> &[BtfType] {
+        &self.types
+    }
+
+    pub fn type_by_id(&self, id: u32) -> Option<&BtfType> {
+        self.types.get(id as usize)
+    }
+
+    /// Returns the type `id` refers to, skipping typedefs and qualifiers
+    /// like `const`.
+    pub fn resolve(&self, id: u32) -> Option<&BtfType> {
+        let mut id = id;
+        // bounded, in case of loops in malformed sections
+        for _ in 0..self.types.len() {
+            let ty = self.type_by_id(id)?;
+            match ty.kind {
+                BtfKind::Typedef(t)
+                | BtfKind::Volatile(t)
+                | BtfKind::Const(t)
+                | BtfKind::Restrict(t)
+                | BtfKind::TypeTag(t) => id = t,
+                _ => return Some(ty),
+            }
+        }
+
+        None
+    }
+
+    /// Returns the size of the type `id` in bytes, `None` for types without
+    /// a size like functions.
+    pub fn size_of(&self, id: u32) -> Option<u32> {
+        match self.resolve(id)?.kind {
+            BtfKind::Int { size, .. }
+            | BtfKind::Struct { size, .. }
+            | BtfKind::Union { size, .. }
+            | BtfKind::Enum { size, .. }
+            | BtfKind::Datasec { size, .. }
+            | BtfKind::Float { size } => Some(size),
+            // BPF pointers are 64 bit on all architectures
+            BtfKind::Ptr(_) => Some(8),
+            BtfKind::Array { elem, nelems, .. } => self.size_of(elem)?.checked_mul(nelems),
+            BtfKind::Var { type_id, .. } => self.size_of(type_id),
+            _ => None,
+        }
+    }
+
+    /// Returns the definitions of the maps of the `.maps` section, by the
+    /// name of the map variable.
+    ///
+    /// Fails with `Error::Btf` if a map uses attributes that aren't
+    /// supported, like the initial `values` of maps of maps. The `pinning`
+    /// attribute is ignored.
+    pub fn map_defs(&self) -> Result<Vec<(String, bpf_map_def)>> {
+        let vars = self.types.iter().find_map(|t| match &t.kind {
+            BtfKind::Datasec { vars, .. } if t.name == MAPS_SECTION => Some(vars),
+            _ => None,
+        });
+        let vars = match vars {
+            Some(vars) => vars,
+            None => return Ok(Vec::new()),
+        };
+
+        vars.iter()
+            .map(|secinfo| match self.type_by_id(secinfo.type_id) {
+                Some(BtfType {
+                    name,
+                    kind: BtfKind::Var { type_id, .. },
+                }) => Ok((name.clone(), self.map_def(name, *type_id)?)),
+                _ => Err(Error::Btf(format!(
+                    "invalid variable {} in {}",
+                    secinfo.type_id, MAPS_SECTION
+                ))),
+            })
+            .collect()
+    }
+
+    fn map_def(&self, map: &str, type_id: u32) -> Result<bpf_map_def> {
+        let members = match self.resolve(type_id).map(|t| &t.kind) {
+            Some(BtfKind::Struct { members, .. }) => members,
+            _ => return Err(Error::Btf(format!("map {} isn't a struct", map))),
+        };
+        let mut def = bpf_map_def {
+            type_: 0,
+            key_size: 0,
+            value_size: 0,
+            max_entries: 0,
+            map_flags: 0,
+        };
+        for member in members.iter() {
+            match member.name.as_str() {
+                "type" => def.type_ = self.uint_attr(map, member)?,
+                "max_entries" => def.max_entries = self.uint_attr(map, member)?,
+                "map_flags" => def.map_flags = self.uint_attr(map, member)?,
+                "key_size" => def.key_size = self.uint_attr(map, member)?,
+                "value_size" => def.value_size = self.uint_attr(map, member)?,
+                "key" => def.key_size = self.type_attr(map, member)?,
+                "value" => def.value_size = self.type_attr(map, member)?,
+                "pinning" => {}
+                attr => {
+                    return Err(Error::Btf(format!(
+                        "map {}: unsupported attribute {}",
+                        map, attr
+                    )))
+                }
+            }
+        }
+
+        Ok(def)
+    }
+
+    // `__uint(name, value)` is declared as `int (*name)[value]`
+    fn uint_attr(&self, map: &str, member: &BtfMember) -> Result<u32> {
+        let array = match self.resolve(member.type_id).map(|t| &t.kind) {
+            Some(BtfKind::Ptr(t)) => self.resolve(*t).map(|t| &t.kind),
+            _ => None,
+        };
+        match array {
+            Some(BtfKind::Array { nelems, .. }) => Ok(*nelems),
+            _ => Err(invalid_attr(map, member)),
+        }
+    }
+
+    // `__type(name, T)` is declared as `T *name`
+    fn type_attr(&self, map: &str, member: &BtfMember) -> Result<u32> {
+        match self.resolve(member.type_id).map(|t| &t.kind) {
+            Some(BtfKind::Ptr(t)) => self.size_of(*t).ok_or_else(|| invalid_attr(map, member)),
+            _ => Err(invalid_attr(map, member)),
+        }
+    }
+}
+
+/// Loads the contents of a `.BTF` section in the kernel, and returns its
+/// file descriptor.
+///
+/// Programs loaded with the BTF of their ELF file get verifier logs
+/// annotated with their source lines. Fails with `Error::BtfLoad` if the
+/// kernel rejects the BTF, with an empty log if the kernel doesn't support
+/// BTF. Requires Linux 4.18.
+pub fn load(bytes: &[u8]) -> Result<RawFd> {
+    let mut log_buf = vec![0u8; LOG_BUF_SIZE];
+    // the log is only needed to report failures
+    let mut log_level = 0;
+    loop {
+        log_buf[0] = 0;
+        let fd = bpf_btf_load(bytes, &mut log_buf, log_level);
+        if fd >= 0 {
+            return Ok(fd);
+        }
+        let error = io::Error::last_os_error();
+        if log_level == 0 {
+            log_level = 1;
+            continue;
+        }
+        // the log didn't fit in the buffer
+        if error.raw_os_error() == Some(libc::ENOSPC) && log_buf.len() < MAX_LOG_BUF_SIZE {
+            let size = cmp::min(log_buf.len() * 4, MAX_LOG_BUF_SIZE);
+            log_buf.resize(size, 0);
+            continue;
+        }
+        let len = log_buf
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(log_buf.len());
+        let log = String::from_utf8_lossy(&log_buf[..len]).into_owned();
+
+        // kernels without BTF support fail without a log
+        return Err(Error::BtfLoad { error, log });
+    }
+}
+
+/// Fixes up the contents of a `.BTF` section emitted by a compiler, so that
+/// the kernel accepts it.
+///
+/// Compilers leave the sizes of data sections and the offsets of their
+/// variables to the loader, which gets them from `section_size` and
+/// `symbol_offset`. Names the kernel doesn't accept, like the `Option<u32>`
+/// of Rust generics, get their invalid characters replaced with
+/// underscores.
+pub(crate) fn fixup<S, O>(bytes: &[u8], section_size: S, symbol_offset: O) -> Result<Vec<u8>>
+where
+    S: Fn(&str) -> Option<u32>,
+    O: Fn(&str) -> Option<u32>,
+{
+    let (type_data, strings) = sections(bytes)?;
+    let mut parsed = Vec::new();
+    let mut offset = 0;
+    while offset < type_data.len() {
+        let (ty, size) = parse_type(&type_data[offset..], strings)?;
+        parsed.push((offset, ty));
+        offset += size;
+    }
+
+    let mut types = type_data.to_vec();
+    let mut new_strings = strings.to_vec();
+    let mut renamed = RSHashMap::new();
+    for (at, ty) in parsed.iter() {
+        let at = *at;
+        if let BtfKind::Datasec { size, vars } = &ty.kind {
+            if *size == 0 {
+                if let Some(size) = section_size(&ty.name) {
+                    put_u32(&mut types, at + 8, size);
+                }
+            }
+            for (i, var) in vars.iter().enumerate() {
+                // ids start at 1, after void
+                let name = match (var.type_id as usize).checked_sub(1) {
+                    Some(id) => parsed.get(id).map(|(_, t)| t.name.as_str()),
+                    None => None,
+                };
+                if let Some(offset) = name.and_then(&symbol_offset) {
+                    put_u32(&mut types, at + 12 + i * 12 + 4, offset);
+                }
+            }
+        }
+
+        let info = u32_at(&types, at + 4)?;
+        let kind = (info >> 24) & 0x1f;
+        let vlen = (info & 0xffff) as usize;
+        let entry_size = match kind {
+            KIND_STRUCT | KIND_UNION | KIND_ENUM64 => 12,
+            KIND_ENUM | KIND_FUNC_PROTO => 8,
+            _ => 0,
+        };
+        let mut names = vec![at];
+        if entry_size > 0 {
+            names.extend((0..vlen).map(|i| at + 12 + i * entry_size));
+        }
+        for pos in names {
+            let name = string_at(strings, u32_at(&types, pos)?)?;
+            let valid = match valid_name(&name, kind == KIND_DATASEC) {
+                Some(valid) => valid,
+                None => continue,
+            };
+            let offset = match renamed.get(&valid) {
+                Some(offset) => *offset,
+                None => {
+                    let offset = new_strings.len() as u32;
+                    new_strings.extend_from_slice(valid.as_bytes());
+                    new_strings.push(0);
+                    renamed.insert(valid, offset);
+                    offset
+                }
+            };
+            put_u32(&mut types, pos, offset);
+        }
+    }
+
+    let hdr_len = u32_at(bytes, 4)? as usize;
+    let mut ret = bytes[..hdr_len].to_vec();
+    put_u32(&mut ret, 8, 0);
+    put_u32(&mut ret, 12, types.len() as u32);
+    put_u32(&mut ret, 16, types.len() as u32);
+    put_u32(&mut ret, 20, new_strings.len() as u32);
+    ret.extend_from_slice(&types);
+    ret.extend_from_slice(&new_strings);
+
+    Ok(ret)
+}
+
+// Returns `name` with the characters the kernel doesn't accept in names
+// replaced, or `None` if it's valid. Section names can have dots.
+fn valid_name(name: &str, section: bool) -> Option<String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || (section && c == '.');
+    let starts_with_digit = matches!(name.chars().next(), Some(c) if c.is_ascii_digit());
+    if name.chars().all(valid) && !starts_with_digit {
+        return None;
+    }
+    let mut ret = String::new();
+    if starts_with_digit {
+        ret.push('_');
+    }
+    ret.extend(name.chars().map(|c| if valid(c) { c } else { '_' }));
+
+    Some(ret)
+}
+
+/// The `func_info` or `line_info` records of the programs of an ELF
+/// section, read from `.BTF.ext`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ExtInfo {
+    pub(crate) rec_size: u32,
+    /// The records, with their instruction offsets in instructions rather
+    /// than bytes like the kernel expects
+    pub(crate) records: Vec<u8>,
+}
+
+impl ExtInfo {
+    pub(crate) fn count(&self) -> u32 {
+        self.records.len() as u32 / self.rec_size
+    }
+}
+
+/// A CO-RE relocation record of `.BTF.ext`, emitted by LLVM for the
+/// accesses of `__builtin_preserve_access_index()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CoreRelo {
+    /// The offset of the instruction in instructions, not bytes
+    pub(crate) insn_off: u32,
+    /// The local type the access starts at
+    pub(crate) type_id: u32,
+    /// The indexes of the access, eg. `0:1:2`
+    pub(crate) access: String,
+    pub(crate) kind: u32,
+}
+
+/// The contents of a `.BTF.ext` section: the functions, the source lines and
+/// the CO-RE relocations of the programs, by ELF section.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BtfExt {
+    pub(crate) func_info: RSHashMap<String, ExtInfo>,
+    pub(crate) line_info: RSHashMap<String, ExtInfo>,
+    pub(crate) core_relos: RSHashMap<String, Vec<CoreRelo>>,
+}
+
+impl BtfExt {
+    /// Parses `ext`, with the section names in the strings of `btf`.
+    pub(crate) fn parse(ext: &[u8], btf: &[u8]) -> Result<BtfExt> {
+        let (_, strings) = sections(btf)?;
+        let magic = ext
+            .get(0..2)
+            .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+            .ok_or_else(|| truncated("header"))?;
+        if magic != BTF_MAGIC {
+            return Err(Error::Btf(format!("invalid .BTF.ext magic {:#x}", magic)));
+        }
+        let hdr_len = u32_at(ext, 4)? as usize;
+        if hdr_len < EXT_HEADER_SIZE {
+            return Err(truncated("header"));
+        }
+        let section = |off: usize, len: usize| {
+            let start = hdr_len + u32_at(ext, off)? as usize;
+            let end = start + u32_at(ext, len)? as usize;
+            ext.get(start..end).ok_or_else(|| truncated("section"))
+        };
+
+        // the offset and the size of the CO-RE relocations were added
+        // to the header later
+        let core_relos = if hdr_len >= EXT_CORE_HEADER_SIZE {
+            ext_infos(section(24, 28)?, strings)?
+                .into_iter()
+                .map(|(section, info)| Ok((section, core_relos(&info, strings)?)))
+                .collect::<Result<_>>()?
+        } else {
+            RSHashMap::new()
+        };
+
+        Ok(BtfExt {
+            func_info: ext_infos(section(8, 12)?, strings)?,
+            line_info: ext_infos(section(16, 20)?, strings)?,
+            core_relos,
+        })
+    }
+}
+
+fn core_relos(info: &ExtInfo, strings: &[u8]) -> Result<Vec<CoreRelo>> {
+    if info.rec_size < 16 {
+        return Err(Error::Btf(format!("invalid record size {}", info.rec_size)));
+    }
+    (0..info.count() as usize)
+        .map(|i| {
+            let at = i * info.rec_size as usize;
+            Ok(CoreRelo {
+                insn_off: u32_at(&info.records, at)?,
+                type_id: u32_at(&info.records, at + 4)?,
+                access: string_at(strings, u32_at(&info.records, at + 8)?)?,
+                kind: u32_at(&info.records, at + 12)?,
+            })
+        })
+        .collect()
+}
+
+// Parses the records of each ELF section, preceded by their size
+fn ext_infos(data: &[u8], strings: &[u8]) -> Result<RSHashMap<String, ExtInfo>> {
+    let mut infos = RSHashMap::new();
+    if data.is_empty() {
+        return Ok(infos);
+    }
+    let rec_size = u32_at(data, 0)?;
+    if rec_size < 4 {
+        return Err(Error::Btf(format!("invalid record size {}", rec_size)));
+    }
+    let mut offset = 4;
+    while offset < data.len() {
+        let section = string_at(strings, u32_at(data, offset)?)?;
+        let count = u32_at(data, offset + 4)? as usize;
+        let start = offset + 8;
+        let end = start + count * rec_size as usize;
+        let mut records = data
+            .get(start..end)
+            .ok_or_else(|| truncated("record"))?
+            .to_vec();
+        for i in 0..count {
+            let at = i * rec_size as usize;
+            let insn_off = u32_at(&records, at)?;
+            put_u32(&mut records, at, insn_off / 8);
+        }
+        infos.insert(section, ExtInfo { rec_size, records });
+        offset = end;
+    }
+
+    Ok(infos)
+}
+
+// Returns the type and the string sections of a `.BTF` section
+fn sections(bytes: &[u8]) -> Result<(&[u8], &[u8])> {
+    let magic = bytes
+        .get(0..2)
+        .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+        .ok_or_else(|| truncated("header"))?;
+    if magic != BTF_MAGIC {
+        return Err(Error::Btf(format!("invalid magic {:#x}", magic)));
+    }
+    let hdr_len = u32_at(bytes, 4)? as usize;
+    if hdr_len < HEADER_SIZE {
+        return Err(truncated("header"));
+    }
+    let section = |off: usize, len: usize| {
+        let start = hdr_len + u32_at(bytes, off)? as usize;
+        let end = start + u32_at(bytes, len)? as usize;
+        bytes.get(start..end).ok_or_else(|| truncated("section"))
+    };
+
+    Ok((section(8, 12)?, section(16, 20)?))
+}
+
+// Parses the type at the start of `data`, and returns it with its size
+fn parse_type(data: &[u8], strings: &[u8]) -> Result<(BtfType, usize)> {
+    let name = string_at(strings, u32_at(data, 0)?)?;
+    let info = u32_at(data, 4)?;
+    let size_or_type = u32_at(data, 8)?;
+    let kind = (info >> 24) & 0x1f;
+    let vlen = (info & 0xffff) as usize;
+    let kind_flag = info >> 31 == 1;
+    let rest = &data[12..];
+
+    // the entries following the type, `words` u32 each
+    let entries = |words: usize| -> Result<Vec<Vec<u32>>> {
+        (0..vlen)
+            .map(|i| {
+                (0..words)
+                    .map(|w| u32_at(rest, (i * words + w) * 4))
+                    .collect()
+            })
+            .collect()
+    };
+    let members = |count| -> Result<Vec<BtfMember>> {
+        entries(count)?
+            .into_iter()
+            .map(|m| {
+                let (bit_offset, bitfield_size) = if kind_flag {
+                    (m[2] & 0xff_ffff, m[2] >> 24)
+                } else {
+                    (m[2], 0)
+                };
+                Ok(BtfMember {
+                    name: string_at(strings, m[0])?,
+                    type_id: m[1],
+                    bit_offset,
+                    bitfield_size,
+                })
+            })
+            .collect()
+    };
+
+    let (kind, extra) = match kind {
+        KIND_INT => (
+            BtfKind::Int {
+                size: size_or_type,
+                encoding: u32_at(rest, 0)?,
+            },
+            4,
+        ),
+        KIND_PTR => (BtfKind::Ptr(size_or_type), 0),
+        KIND_ARRAY => (
+            BtfKind::Array {
+                elem: u32_at(rest, 0)?,
+                index: u32_at(rest, 4)?,
+                nelems: u32_at(rest, 8)?,
+            },
+            12,
+        ),
+        KIND_STRUCT => (
+            BtfKind::Struct {
+                size: size_or_type,
+                members: members(3)?,
+            },
+            vlen * 12,
+        ),
+        KIND_UNION => (
+            BtfKind::Union {
+                size: size_or_type,
+                members: members(3)?,
+            },
+            vlen * 12,
+        ),
+        KIND_ENUM => {
+            let values = entries(2)?
+                .into_iter()
+                .map(|e| Ok((string_at(strings, e[0])?, i64::from(e[1] as i32))))
+                .collect::<Result<_>>()?;
+            let kind = BtfKind::Enum {
+                size: size_or_type,
+                values,
+            };
+            (kind, vlen * 8)
+        }
+        KIND_ENUM64 => {
+            let values = entries(3)?
+                .into_iter()
+                .map(|e| {
+                    let value = (u64::from(e[2]) << 32 | u64::from(e[1])) as i64;
+                    Ok((string_at(strings, e[0])?, value))
+                })
+                .collect::<Result<_>>()?;
+            let kind = BtfKind::Enum {
+                size: size_or_type,
+                values,
+            };
+            (kind, vlen * 12)
+        }
+        KIND_FWD => (BtfKind::Fwd, 0),
+        KIND_TYPEDEF => (BtfKind::Typedef(size_or_type), 0),
+        KIND_VOLATILE => (BtfKind::Volatile(size_or_type), 0),
+        KIND_CONST => (BtfKind::Const(size_or_type), 0),
+        KIND_RESTRICT => (BtfKind::Restrict(size_or_type), 0),
+        KIND_FUNC => (BtfKind::Func(size_or_type), 0),
+        KIND_FUNC_PROTO => {
+            let params = entries(2)?
+                .into_iter()
+                .map(|p| Ok((string_at(strings, p[0])?, p[1])))
+                .collect::<Result<_>>()?;
+            let kind = BtfKind::FuncProto {
+                ret: size_or_type,
+                params,
+            };
+            (kind, vlen * 8)
+        }
+        KIND_VAR => (
+            BtfKind::Var {
+                type_id: size_or_type,
+                linkage: u32_at(rest, 0)?,
+            },
+            4,
+        ),
+        KIND_DATASEC => {
+            let vars = entries(3)?
+                .into_iter()
+                .map(|v| BtfVarSecinfo {
+                    type_id: v[0],
+                    offset: v[1],
+                    size: v[2],
+                })
+                .collect();
+            let kind = BtfKind::Datasec {
+                size: size_or_type,
+                vars,
+            };
+            (kind, vlen * 12)
+        }
+        KIND_FLOAT => (BtfKind::Float { size: size_or_type }, 0),
+        KIND_DECL_TAG => (
+            BtfKind::DeclTag {
+                type_id: size_or_type,
+                component: u32_at(rest, 0)? as i32,
+            },
+            4,
+        ),
+        KIND_TYPE_TAG => (BtfKind::TypeTag(size_or_type), 0),
+        kind => return Err(Error::Btf(format!("unknown type kind {}", kind))),
+    };
+
+    Ok((BtfType { name, kind }, 12 + extra))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| truncated("type"))
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn string_at(strings: &[u8], offset: u32) -> Result<String> {
+    let bytes = strings
+        .get(offset as usize..)
+        .ok_or_else(|| truncated("string"))?;
+    let end = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| truncated("string"))?;
+
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+fn truncated(what: &str) -> Error {
+    Error::Btf(format!("truncated {}", what))
+}
+
+fn invalid_attr(map: &str, member: &BtfMember) -> Error {
+    Error::Btf(format!("map {}: invalid attribute {}", map, member.name))
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::btf::{Btf, BtfKind, BTF_MAGIC, HEADER_SIZE};
+        use crate::btf::{KIND_ARRAY, KIND_DATASEC, KIND_INT, KIND_PTR, KIND_STRUCT, KIND_VAR};
+
+        // struct {
+        //     __uint(type, BPF_MAP_TYPE_HASH);
+        //     __uint(max_entries, 1024);
+        //     __type(key, u32);
+        //     __type(value, u64);
+        // } counts SEC(".maps");
+        let strings = b"\0int\0u64\0type\0max_entries\0key\0value\0counts\0.maps\0";
+        let name = |s: &str| {
+            let needle = format!("\0{}\0", s);
+            let pos = strings
+                .windows(needle.len())
+                .position(|w| w == needle.as_bytes())
+                .unwrap();
+            pos as u32 + 1
+        };
+        let info = |kind: u32, vlen: u32| kind << 24 | vlen;
+        #[rustfmt::skip]
+        let types: Vec<u32> = vec![
+            // 1: int
+            name("int"), info(KIND_INT, 0), 4, 0x0100_0020,
+            // 2: u64
+            name("u64"), info(KIND_INT, 0), 8, 64,
+            // 3: int[1], 4: int (*)[1]
+            0, info(KIND_ARRAY, 0), 0, 1, 1, 1,
+            0, info(KIND_PTR, 0), 3,
+            // 5: int[1024], 6: int (*)[1024]
+            0, info(KIND_ARRAY, 0), 0, 1, 1, 1024,
+            0, info(KIND_PTR, 0), 5,
+            // 7: int *, 8: u64 *
+            0, info(KIND_PTR, 0), 1,
+            0, info(KIND_PTR, 0), 2,
+            // 9: the definition
+            0, info(KIND_STRUCT, 4), 32,
+            name("type"), 4, 0,
+            name("max_entries"), 6, 64,
+            name("key"), 7, 128,
+            name("value"), 8, 192,
+            // 10: counts, 11: .maps
+            name("counts"), info(KIND_VAR, 0), 9, 1,
+            name(".maps"), info(KIND_DATASEC, 1), 32, 10, 0, 32,
+        ];
+        let type_bytes: Vec<u8> = types
+            .iter()
+            .flat_map(|w| w.to_ne_bytes().to_vec())
+            .collect();
+
+        let mut section = Vec::new();
+        section.extend_from_slice(&BTF_MAGIC.to_ne_bytes());
+        section.extend_from_slice(&[1, 0]);
+        for word in [
+            HEADER_SIZE as u32,
+            0,
+            type_bytes.len() as u32,
+            type_bytes.len() as u32,
+            strings.len() as u32,
+        ]
+        .iter()
+        {
+            section.extend_from_slice(&word.to_ne_bytes());
+        }
+        section.extend_from_slice(&type_bytes);
+        section.extend_from_slice(strings);
+
+        let btf = Btf::parse(&section).unwrap();
+        assert_eq!(btf.types().len(), 12);
+        assert_eq!(btf.type_by_id(0).unwrap().kind, BtfKind::Void);
+        assert_eq!(btf.size_of(5), Some(4096));
+        assert_eq!(btf.size_of(9), Some(32));
+
+        let defs = btf.map_defs().unwrap();
+        assert_eq!(defs.len(), 1);
+        let (name, def) = &defs[0];
+        assert_eq!(name, "counts");
+        assert_eq!(def.type_, 1);
+        assert_eq!(def.max_entries, 1024);
+        assert_eq!(def.key_size, 4);
+        assert_eq!(def.value_size, 8);
+
+        section[0] = 0;
+        assert!(Btf::parse(&section).is_err());
+    }
+
+    #[test]
+    fn test_ext() {
+        use crate::btf::{fixup, Btf, BtfExt, BtfKind, BTF_MAGIC, HEADER_SIZE};
+        use crate::btf::{KIND_DATASEC, KIND_INT, KIND_STRUCT, KIND_VAR};
+
+        let strings = b"\0u32\0Option<u32>\x001st\0COUNT\0maps/count\0kprobe/f\0";
+        let name = |s: &str| {
+            let needle = format!("\0{}\0", s);
+            let pos = strings
+                .windows(needle.len())
+                .position(|w| w == needle.as_bytes())
+                .unwrap();
+            pos as u32 + 1
+        };
+        let info = |kind: u32, vlen: u32| kind << 24 | vlen;
+        #[rustfmt::skip]
+        let types: Vec<u32> = vec![
+            // 1: u32
+            name("u32"), info(KIND_INT, 0), 4, 32,
+            // 2: Option<u32>
+            name("Option<u32>"), info(KIND_STRUCT, 1), 4,
+            name("1st"), 1, 0,
+            // 3: COUNT, 4: maps/count, without size and offset
+            name("COUNT"), info(KIND_VAR, 0), 1, 1,
+            name("maps/count"), info(KIND_DATASEC, 1), 0, 3, 0, 4,
+        ];
+        let words = |words: &[u32]| -> Vec<u8> {
+            words
+                .iter()
+                .flat_map(|w| w.to_ne_bytes().to_vec())
+                .collect()
+        };
+        let type_bytes = words(&types);
+        let mut section = BTF_MAGIC.to_ne_bytes().to_vec();
+        section.extend_from_slice(&[1, 0]);
+        section.extend(words(&[
+            HEADER_SIZE as u32,
+            0,
+            type_bytes.len() as u32,
+            type_bytes.len() as u32,
+            strings.len() as u32,
+        ]));
+        section.extend_from_slice(&type_bytes);
+        section.extend_from_slice(strings);
+
+        let size = |s: &str| if s == "maps/count" { Some(4) } else { None };
+        let offset = |s: &str| if s == "COUNT" { Some(8) } else { None };
+        let fixed = fixup(&section, size, offset).unwrap();
+        let btf = Btf::parse(&fixed).unwrap();
+        assert_eq!(btf.type_by_id(1).unwrap().name, "u32");
+        let option = btf.type_by_id(2).unwrap();
+        assert_eq!(option.name, "Option_u32_");
+        match &option.kind {
+            BtfKind::Struct { members, .. } => assert_eq!(members[0].name, "_1st"),
+            kind => panic!("unexpected kind: {:?}", kind),
+        }
+        let datasec = btf.type_by_id(4).unwrap();
+        assert_eq!(datasec.name, "maps_count");
+        match &datasec.kind {
+            BtfKind::Datasec { size, vars } => {
+                assert_eq!(*size, 4);
+                assert_eq!(vars[0].offset, 8);
+            }
+            kind => panic!("unexpected kind: {:?}", kind),
+        }
+
+        let func_info = words(&[8, name("kprobe/f"), 1, 0, 2]);
+        let line_info = words(&[16, name("kprobe/f"), 2, 0, 0, 0, 0, 16, 0, 0, 0]);
+        let mut ext = BTF_MAGIC.to_ne_bytes().to_vec();
+        ext.extend_from_slice(&[1, 0]);
+        ext.extend(words(&[
+            24,
+            0,
+            func_info.len() as u32,
+            func_info.len() as u32,
+            line_info.len() as u32,
+        ]));
+        ext.extend_from_slice(&func_info);
+        ext.extend_from_slice(&line_info);
+
+        let ext = BtfExt::parse(&ext, &fixed).unwrap();
+        let func_info = &ext.func_info["kprobe/f"];
+        assert_eq!((func_info.rec_size, func_info.count()), (8, 1));
+        let line_info = &ext.line_info["kprobe/f"];
+        assert_eq!(line_info.count(), 2);
+        // offsets are converted from bytes to instructions
+        assert_eq!(&line_info.records[16..20], &2u32.to_ne_bytes());
+    }
+}
@@ -7,9 +7,9 @@
 
 use crate::build::Error;
 use bpf_sys::headers::prefix_kernel_headers;
+pub use bpf_sys::headers::{kernel_headers_version, kernel_headers_version_at};
 
-#[cfg(target_arch = "x86_64")]
-pub const KERNEL_HEADERS: [&str; 7] = [
+pub const X86_64_KERNEL_HEADERS: [&str; 7] = [
     "arch/x86/include",
     "arch/x86/include/generated",
     "include",
@@ -19,8 +19,7 @@ pub const KERNEL_HEADERS: [&str; 7] = [
     "include/uapi",
 ];
 
-#[cfg(target_arch = "aarch64")]
-pub const KERNEL_HEADERS: [&str; 8] = [
+pub const AARCH64_KERNEL_HEADERS: [&str; 8] = [
     "arch/arm64/include",
     "arch/arm64/include/generated",
     "include",
@@ -31,6 +30,23 @@ pub const KERNEL_HEADERS: [&str; 8] = [
     "include/uapi",
 ];
 
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_HEADERS: [&str; 7] = X86_64_KERNEL_HEADERS;
+
+#[cfg(target_arch = "aarch64")]
+pub const KERNEL_HEADERS: [&str; 8] = AARCH64_KERNEL_HEADERS;
+
 pub fn kernel_headers() -> Result<Vec<String>, Error> {
     prefix_kernel_headers(&KERNEL_HEADERS).ok_or(Error::KernelHeadersNotFound)
 }
+
+/// Returns the kernel headers needed to build for `arch`, named like
+/// `target_arch`.
+pub fn kernel_headers_for_arch(arch: &str) -> Result<Vec<String>, Error> {
+    let headers: &[&str] = match arch {
+        "x86_64" => &X86_64_KERNEL_HEADERS,
+        "aarch64" => &AARCH64_KERNEL_HEADERS,
+        _ => return Err(Error::OSUnsupported),
+    };
+    prefix_kernel_headers(headers).ok_or(Error::KernelHeadersNotFound)
+}
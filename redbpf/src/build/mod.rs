@@ -15,6 +15,10 @@
 //! _data_[^{}]*` are picked up by bindgen. This naming convention might change
 //! in the future, but has been flexible enough.
 //!
+//! The structures implement `TryFrom<&[u8]>` to read them from the samples of
+//! perf events, failing with `redbpf::load::DecodeError::Size` if the sample
+//! is too short. The crate including the bindings must depend on `redbpf`.
+//!
 //! Because the compile + bindgen steps are fairly costly, they will slow down
 //! builds during development. The `BuildCache` struct provides a low-friction
 //! interface to only rebuild when the source files actually changed. Note that
@@ -67,9 +71,9 @@ pub mod cache;
 
 pub mod headers;
 
-#[cfg(target_arch = "x86_64")]
-pub const BUILD_FLAGS: [&str; 19] = [
+pub const X86_64_BUILD_FLAGS: [&str; 20] = [
     "-D__BPF_TRACING__",
+    "-D__TARGET_ARCH_x86",
     "-D__KERNEL__",
     "-D__ASM_SYSREG_H",
     "-Wall",
@@ -90,9 +94,9 @@ pub const BUILD_FLAGS: [&str; 19] = [
     "-c",
 ];
 
-#[cfg(target_arch = "aarch64")]
-pub const BUILD_FLAGS: [&str; 20] = [
+pub const AARCH64_BUILD_FLAGS: [&str; 21] = [
     "-D__BPF_TRACING__",
+    "-D__TARGET_ARCH_arm64",
     "-D__KERNEL__",
     "-target", "aarch64",
     "-Wall",
@@ -113,6 +117,21 @@ pub const BUILD_FLAGS: [&str; 20] = [
     "-c",
 ];
 
+#[cfg(target_arch = "x86_64")]
+pub const BUILD_FLAGS: [&str; 20] = X86_64_BUILD_FLAGS;
+
+#[cfg(target_arch = "aarch64")]
+pub const BUILD_FLAGS: [&str; 21] = AARCH64_BUILD_FLAGS;
+
+/// Returns the flags needed to build for `arch`, named like `target_arch`.
+pub fn build_flags_for_arch(arch: &str) -> Option<&'static [&'static str]> {
+    match arch {
+        "x86_64" => Some(&X86_64_BUILD_FLAGS),
+        "aarch64" => Some(&AARCH64_BUILD_FLAGS),
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     OSUnsupported,
@@ -196,9 +215,17 @@ pub fn generate_bindings(flags: &[String], out_dir: &Path, source: &Path) -> Res
     let mut code = bindings.to_string();
     for data_type in RE.captures_iter(&code.clone()) {
         let trait_impl = r"
-impl<'a> From<&'a [u8]> for ### {
-    fn from(x: &'a [u8]) -> ### {
-        unsafe { std::ptr::read(x.as_ptr() as *const ###) }
+impl<'a> std::convert::TryFrom<&'a [u8]> for ### {
+    type Error = ::redbpf::load::DecodeError;
+
+    fn try_from(x: &'a [u8]) -> std::result::Result<###, Self::Error> {
+        if x.len() < std::mem::size_of::<###>() {
+            return Err(::redbpf::load::DecodeError::Size {
+                expected: std::mem::size_of::<###>(),
+                actual: x.len(),
+            });
+        }
+        Ok(unsafe { std::ptr::read_unaligned(x.as_ptr() as *const ###) })
     }
 }
 ".replace("###", &data_type[1]);
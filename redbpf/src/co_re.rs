@@ -0,0 +1,556 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// CO-RE (compile once, run everywhere) relocations.
+//
+// The layout of kernel structs changes between kernel versions, so programs
+// reading them only work on the kernel whose headers they were built with.
+// CO-RE relocations record which field, type or enum value an instruction
+// depends on, and the instruction is patched with the value found in the
+// BTF of the kernel the program is loaded in. Relocations come from:
+//  * the records of `.BTF.ext`, emitted by LLVM for the accesses of
+//    `__builtin_preserve_access_index()` in C programs
+//  * the symbols of the `fields/<type>.<field>` sections, whose address is
+//    loaded by `read_field!()` and `field_offset!()` of `redbpf-probes`.
+//    The address is replaced with the offset of the field.
+//
+// Types are matched by name, ignoring the `___flavor` suffixes of local
+// types, and fields by name. Bitfields aren't supported.
+use bpf_sys::bpf_insn;
+use std::fmt;
+use std::mem;
+
+use crate::btf::{Btf, BtfKind, BtfMember, CoreRelo};
+use crate::{Error, Result};
+
+// `enum bpf_core_relo_kind`
+const FIELD_BYTE_OFFSET: u32 = 0;
+const FIELD_BYTE_SIZE: u32 = 1;
+const FIELD_EXISTS: u32 = 2;
+const FIELD_SIGNED: u32 = 3;
+const TYPE_ID_LOCAL: u32 = 6;
+const TYPE_EXISTS: u32 = 8;
+const TYPE_SIZE: u32 = 9;
+const ENUMVAL_EXISTS: u32 = 10;
+const ENUMVAL_VALUE: u32 = 11;
+
+// BTF_INT_SIGNED, in the encoding of ints
+const INT_SIGNED: u32 = 1 << 24;
+
+// Instruction classes
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_ST: u8 = 0x02;
+const BPF_STX: u8 = 0x03;
+const BPF_ALU: u8 = 0x04;
+const BPF_ALU64: u8 = 0x07;
+// the source of ALU instructions is a register rather than `imm`
+const BPF_X: u8 = 0x08;
+const LD_IMM64: u8 = 0x18;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Accessor {
+    Field(String),
+    Index(u32),
+}
+
+// What a relocation refers to: a field of a type, a type or an enum value
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Access {
+    // The name of the root type, without its flavor
+    type_name: String,
+    // The kind of the root type, `None` for structs and unions
+    kind: Option<mem::Discriminant<BtfKind>>,
+    // Starts with the index of the root in an array of it for fields, and
+    // is the name of the value for enum values
+    accessors: Vec<Accessor>,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.type_name)?;
+        for (i, accessor) in self.accessors.iter().enumerate() {
+            match accessor {
+                Accessor::Index(0) if i == 0 => {}
+                Accessor::Index(index) => write!(f, "[{}]", index)?,
+                Accessor::Field(name) => write!(f, ".{}", name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A field of a type, with its offset from the root of the access
+struct Field {
+    type_id: u32,
+    bit_offset: u32,
+    bitfield_size: u32,
+}
+
+/// An instruction to patch with the value of a field, a type or an enum value
+/// in the kernel BTF.
+#[derive(Debug, Clone)]
+pub(crate) struct CoreRelocation {
+    pub(crate) program: usize,
+    insn: usize,
+    kind: u32,
+    access: Access,
+    // The value for the local types, that the instruction holds. `None` for
+    // `fields/` symbols.
+    local: Option<u64>,
+}
+
+impl CoreRelocation {
+    /// The relocation of the `.BTF.ext` record `relo`, of the types of
+    /// `local`.
+    pub(crate) fn from_btf_ext(
+        local: &Btf,
+        relo: &CoreRelo,
+        program: usize,
+    ) -> Result<CoreRelocation> {
+        let invalid = || {
+            Error::Btf(format!(
+                "invalid CO-RE relocation {} of type {}",
+                relo.access, relo.type_id
+            ))
+        };
+        let indexes = relo
+            .access
+            .split(':')
+            .map(|i| i.parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let (root, rest) = indexes.split_first().ok_or_else(invalid)?;
+
+        let (root_id, accessors) = match relo.kind {
+            FIELD_BYTE_OFFSET | FIELD_BYTE_SIZE | FIELD_EXISTS | FIELD_SIGNED => {
+                let root_id = resolved_id(local, relo.type_id).ok_or_else(invalid)?;
+                let mut accessors = vec![Accessor::Index(*root)];
+                let mut id = root_id;
+                for (i, index) in rest.iter().enumerate() {
+                    match &local.resolve(id).ok_or_else(invalid)?.kind {
+                        BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => {
+                            let member = members.get(*index as usize).ok_or_else(invalid)?;
+                            // the members of anonymous members are found by
+                            // name
+                            if !member.name.is_empty() {
+                                accessors.push(Accessor::Field(member.name.clone()));
+                            } else if i == rest.len() - 1 {
+                                return Err(Error::Btf(format!(
+                                    "CO-RE relocation {} of type {} of an anonymous member",
+                                    relo.access, relo.type_id
+                                )));
+                            }
+                            id = member.type_id;
+                        }
+                        BtfKind::Array { elem, .. } => {
+                            accessors.push(Accessor::Index(*index));
+                            id = *elem;
+                        }
+                        _ => return Err(invalid()),
+                    }
+                }
+                (root_id, accessors)
+            }
+            ENUMVAL_EXISTS | ENUMVAL_VALUE => {
+                let values = match &local.type_by_id(relo.type_id).ok_or_else(invalid)?.kind {
+                    BtfKind::Enum { values, .. } => values,
+                    _ => return Err(invalid()),
+                };
+                let (name, _) = values.get(*root as usize).ok_or_else(invalid)?;
+                (relo.type_id, vec![Accessor::Field(name.clone())])
+            }
+            _ => (relo.type_id, vec![Accessor::Index(*root)]),
+        };
+
+        let root = local.type_by_id(root_id).ok_or_else(invalid)?;
+        if root.name.is_empty() {
+            return Err(Error::Btf(format!(
+                "CO-RE relocation {} of the anonymous type {}",
+                relo.access, root_id
+            )));
+        }
+        let kind = match root.kind {
+            BtfKind::Struct { .. } | BtfKind::Union { .. } => None,
+            ref kind => Some(mem::discriminant(kind)),
+        };
+        let access = Access {
+            type_name: essential_name(&root.name).to_string(),
+            kind,
+            accessors,
+        };
+        let local_value = match relo.kind {
+            TYPE_ID_LOCAL => Some(u64::from(relo.type_id)),
+            kind => value(local, root_id, kind, &access)
+                .map_err(|reason| Error::Btf(format!("{}: {}", access, reason)))?,
+        };
+
+        Ok(CoreRelocation {
+            program,
+            insn: relo.insn_off as usize,
+            kind: relo.kind,
+            access,
+            local: local_value,
+        })
+    }
+
+    /// The relocation of the symbol of the `fields/<path>` section loaded at
+    /// `insn`. `path` is the name of a struct or a union followed by the
+    /// names of the fields, eg. `task_struct.mm`.
+    pub(crate) fn field(program: usize, insn: usize, path: &str) -> CoreRelocation {
+        let mut names = path.split('.');
+        let type_name = essential_name(names.next().unwrap_or_default()).to_string();
+        let mut accessors = vec![Accessor::Index(0)];
+        accessors.extend(names.map(|name| Accessor::Field(name.to_string())));
+
+        CoreRelocation {
+            program,
+            insn,
+            kind: FIELD_BYTE_OFFSET,
+            access: Access {
+                type_name,
+                kind: None,
+                accessors,
+            },
+            local: None,
+        }
+    }
+
+    /// Patches the instruction of `code`, the code of the program `program`,
+    /// with the value of the relocation in `target`.
+    ///
+    /// Fails with `Error::CoreRelocation` if no type matches, or if several
+    /// types match with different values.
+    pub(crate) fn apply(&self, code: &mut [bpf_insn], target: &Btf, program: &str) -> Result<()> {
+        if self.insn >= code.len() {
+            return Err(Error::Reloc);
+        }
+        // the local types are the ones the program is loaded with
+        if self.kind == TYPE_ID_LOCAL {
+            return Ok(());
+        }
+        let error = |reason: String| Error::CoreRelocation {
+            program: program.to_string(),
+            access: self.access.to_string(),
+            reason,
+        };
+
+        let mut found = None;
+        for (id, ty) in target.types().iter().enumerate() {
+            if essential_name(&ty.name) != self.access.type_name || !self.matches(&ty.kind) {
+                continue;
+            }
+            match value(target, id as u32, self.kind, &self.access).map_err(error)? {
+                Some(v) if matches!(found, Some(f) if f != v) => {
+                    return Err(error(format!(
+                        "matches several types, with values {} and {}",
+                        found.unwrap_or_default(),
+                        v
+                    )))
+                }
+                Some(v) => found = Some(v),
+                None => {}
+            }
+        }
+        let value = match (found, self.kind) {
+            (Some(value), _) => value,
+            (None, FIELD_EXISTS) | (None, TYPE_EXISTS) | (None, ENUMVAL_EXISTS) => 0,
+            (None, _) => return Err(error("not found in the kernel BTF".to_string())),
+        };
+
+        patch(code, self.insn, value, self.local).map_err(error)
+    }
+
+    fn matches(&self, kind: &BtfKind) -> bool {
+        match (self.access.kind, kind) {
+            (None, BtfKind::Struct { .. }) | (None, BtfKind::Union { .. }) => true,
+            (Some(local), kind) => local == mem::discriminant(kind),
+            _ => false,
+        }
+    }
+}
+
+// The value of the relocation for the type `id` of `btf`, `None` if the
+// field or the enum value doesn't exist
+fn value(
+    btf: &Btf,
+    id: u32,
+    kind: u32,
+    access: &Access,
+) -> std::result::Result<Option<u64>, String> {
+    let value = match kind {
+        FIELD_BYTE_OFFSET | FIELD_BYTE_SIZE | FIELD_EXISTS | FIELD_SIGNED => {
+            let field = match field(btf, id, &access.accessors) {
+                Some(field) => field,
+                None => return Ok(None),
+            };
+            if kind != FIELD_EXISTS && (field.bitfield_size != 0 || field.bit_offset % 8 != 0) {
+                return Err("bitfields aren't supported".to_string());
+            }
+            match kind {
+                FIELD_BYTE_OFFSET => field.bit_offset / 8,
+                FIELD_BYTE_SIZE => btf
+                    .size_of(field.type_id)
+                    .ok_or_else(|| "the field has no size".to_string())?,
+                FIELD_EXISTS => 1,
+                _ => match btf.resolve(field.type_id).map(|t| &t.kind) {
+                    Some(BtfKind::Int { encoding, .. }) => ((encoding & INT_SIGNED) != 0) as u32,
+                    Some(BtfKind::Enum { values, .. }) => values.iter().any(|(_, v)| *v < 0) as u32,
+                    _ => 0,
+                },
+            }
+        }
+        TYPE_EXISTS => 1,
+        TYPE_SIZE => btf
+            .size_of(id)
+            .ok_or_else(|| "the type has no size".to_string())?,
+        ENUMVAL_EXISTS | ENUMVAL_VALUE => {
+            let values = match btf.type_by_id(id).map(|t| &t.kind) {
+                Some(BtfKind::Enum { values, .. }) => values,
+                _ => return Ok(None),
+            };
+            let value = values
+                .iter()
+                .find(|(name, _)| match access.accessors.first() {
+                    Some(Accessor::Field(field)) => name == field,
+                    _ => false,
+                });
+            return match value {
+                Some(_) if kind == ENUMVAL_EXISTS => Ok(Some(1)),
+                Some((_, value)) => Ok(Some(*value as u64)),
+                None => Ok(None),
+            };
+        }
+        kind => return Err(format!("relocations of kind {} aren't supported", kind)),
+    };
+
+    Ok(Some(u64::from(value)))
+}
+
+// Walks the accessors from the type `root`
+fn field(btf: &Btf, root: u32, accessors: &[Accessor]) -> Option<Field> {
+    let (first, rest) = accessors.split_first()?;
+    let bit_offset = match first {
+        Accessor::Index(index) => index.checked_mul(btf.size_of(root)?)?.checked_mul(8)?,
+        Accessor::Field(_) => return None,
+    };
+    let mut field = Field {
+        type_id: root,
+        bit_offset,
+        bitfield_size: 0,
+    };
+    for accessor in rest {
+        match accessor {
+            Accessor::Field(name) => {
+                let (offset, member) = find_member(btf, field.type_id, name)?;
+                field = Field {
+                    type_id: member.type_id,
+                    bit_offset: field.bit_offset.checked_add(offset)?,
+                    bitfield_size: member.bitfield_size,
+                };
+            }
+            Accessor::Index(index) => {
+                let (elem, nelems) = match btf.resolve(field.type_id)?.kind {
+                    BtfKind::Array { elem, nelems, .. } => (elem, nelems),
+                    _ => return None,
+                };
+                // arrays of 0 elements are flexible
+                if nelems != 0 && *index >= nelems {
+                    return None;
+                }
+                let offset = index.checked_mul(btf.size_of(elem)?)?.checked_mul(8)?;
+                field = Field {
+                    type_id: elem,
+                    bit_offset: field.bit_offset.checked_add(offset)?,
+                    bitfield_size: 0,
+                };
+            }
+        }
+    }
+
+    Some(field)
+}
+
+// Finds the member `name` of the struct or union `id`, looking into its
+// anonymous members, and returns its offset in bits
+fn find_member<'b>(btf: &'b Btf, id: u32, name: &str) -> Option<(u32, &'b BtfMember)> {
+    let members = match &btf.resolve(id)?.kind {
+        BtfKind::Struct { members, .. } | BtfKind::Union { members, .. } => members,
+        _ => return None,
+    };
+    for member in members.iter() {
+        if member.name == name {
+            return Some((member.bit_offset, member));
+        }
+        if member.name.is_empty() {
+            if let Some((offset, inner)) = find_member(btf, member.type_id, name) {
+                return Some((member.bit_offset + offset, inner));
+            }
+        }
+    }
+
+    None
+}
+
+// The id of the type `id` refers to, skipping typedefs and qualifiers
+fn resolved_id(btf: &Btf, id: u32) -> Option<u32> {
+    let mut id = id;
+    for _ in 0..btf.types().len() {
+        match btf.type_by_id(id)?.kind {
+            BtfKind::Typedef(t)
+            | BtfKind::Volatile(t)
+            | BtfKind::Const(t)
+            | BtfKind::Restrict(t)
+            | BtfKind::TypeTag(t) => id = t,
+            _ => return Some(id),
+        }
+    }
+
+    None
+}
+
+// `task_struct___old` is a flavor of `task_struct`, declaring the fields of
+// older kernels
+fn essential_name(name: &str) -> &str {
+    match name.find("___") {
+        Some(pos) => &name[..pos],
+        None => name,
+    }
+}
+
+// Writes `value` in the immediate or the offset of the instruction at `at`
+fn patch(
+    code: &mut [bpf_insn],
+    at: usize,
+    value: u64,
+    local: Option<u64>,
+) -> std::result::Result<(), String> {
+    let insn = &code[at];
+    let (current, max) = match insn.code & 0x07 {
+        BPF_ALU | BPF_ALU64 if insn.code & BPF_X == 0 => {
+            (u64::from(insn.imm as u32), i32::MAX as u64)
+        }
+        BPF_LDX | BPF_ST | BPF_STX => (insn.off as u64, i16::MAX as u64),
+        BPF_LD if insn.code == LD_IMM64 && at + 1 < code.len() => {
+            let high = u64::from(code[at + 1].imm as u32) << 32;
+            (high | u64::from(insn.imm as u32), u64::MAX)
+        }
+        _ => return Err(format!("unexpected instruction {:#x}", insn.code)),
+    };
+    if let Some(local) = local.filter(|local| *local != current) {
+        return Err(format!(
+            "the instruction holds {} instead of the local value {}",
+            current, local
+        ));
+    }
+    if value > max {
+        return Err(format!("{} doesn't fit in the instruction", value));
+    }
+
+    let insn = &mut code[at];
+    match insn.code & 0x07 {
+        BPF_LDX | BPF_ST | BPF_STX => insn.off = value as i16,
+        BPF_LD => {
+            // the address of a `fields/` symbol becomes a constant
+            insn.set_src_reg(0);
+            insn.imm = value as i32;
+            code[at + 1].imm = (value >> 32) as i32;
+        }
+        _ => insn.imm = value as i32,
+    }
+
+    Ok(())
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::btf::{Btf, CoreRelo};
+        use crate::co_re::{patch, CoreRelocation};
+        use bpf_sys::bpf_insn;
+
+        // the pid of the kernel's task_struct is after an anonymous union
+        let strings = b"\0int\0state\0task_struct\0pid\0";
+        #[rustfmt::skip]
+        let types: Vec<u32> = vec![
+            // 1: int
+            1, 1 << 24, 4, 1 << 24 | 32,
+            // 2: union { int state; }
+            0, 5 << 24 | 1, 8,
+            5, 1, 0,
+            // 3: struct task_struct { union { .. }; int pid; }
+            11, 4 << 24 | 2, 24,
+            0, 2, 0,
+            23, 1, 128,
+        ];
+        let mut kernel = 0xeb9fu16.to_ne_bytes().to_vec();
+        kernel.extend_from_slice(&[1, 0]);
+        let types_len = types.len() as u32 * 4;
+        for word in [24, 0, types_len, types_len, strings.len() as u32]
+            .iter()
+            .chain(types.iter())
+        {
+            kernel.extend_from_slice(&word.to_ne_bytes());
+        }
+        kernel.extend_from_slice(strings);
+        let kernel = Btf::parse(&kernel).unwrap();
+
+        // r1 = &fields/task_struct.pid ll
+        let mut bytes = [0u8; 24];
+        bytes[8] = 0x18;
+        let mut code: Vec<bpf_insn> = zero::read_array(&bytes).to_vec();
+        let reloc = CoreRelocation::field(0, 1, "task_struct___old.pid");
+        reloc.apply(&mut code, &kernel, "probe").unwrap();
+        assert_eq!((code[1].imm, code[2].imm), (16, 0));
+
+        let reloc = CoreRelocation::field(0, 1, "task_struct.state");
+        reloc.apply(&mut code, &kernel, "probe").unwrap();
+        assert_eq!(code[1].imm, 0);
+
+        let reloc = CoreRelocation::field(0, 1, "task_struct.tgid");
+        let e = reloc.apply(&mut code, &kernel, "probe").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "program probe: can't relocate task_struct.tgid: not found in the kernel BTF"
+        );
+
+        // r1 += 16, relocated with the same types
+        let mut bytes = [0u8; 8];
+        bytes[0] = 0x07;
+        bytes[4] = 16;
+        let mut code: Vec<bpf_insn> = zero::read_array(&bytes).to_vec();
+        let relo = CoreRelo {
+            insn_off: 0,
+            type_id: 3,
+            access: "0:1".to_string(),
+            kind: 0,
+        };
+        let reloc = CoreRelocation::from_btf_ext(&kernel, &relo, 0).unwrap();
+        assert_eq!(reloc.access.to_string(), "task_struct.pid");
+        reloc.apply(&mut code, &kernel, "probe").unwrap();
+        assert_eq!(code[0].imm, 16);
+        let relo = CoreRelo {
+            access: "0:0:0".to_string(),
+            ..relo
+        };
+        let reloc = CoreRelocation::from_btf_ext(&kernel, &relo, 0).unwrap();
+        assert_eq!(reloc.access.to_string(), "task_struct.state");
+        // the instruction holds 16, not the offset of state
+        assert!(reloc.apply(&mut code, &kernel, "probe").is_err());
+
+        // r2 = *(u32 *)(r1 + 4)
+        let mut bytes = [0u8; 8];
+        bytes[0] = 0x61;
+        bytes[2] = 4;
+        let mut code: Vec<bpf_insn> = zero::read_array(&bytes).to_vec();
+        patch(&mut code, 0, 16, Some(4)).unwrap();
+        assert_eq!(code[0].off, 16);
+        assert!(patch(&mut code, 0, 8, Some(4)).is_err());
+        assert!(patch(&mut code, 0, 1 << 16, None).is_err());
+    }
+}
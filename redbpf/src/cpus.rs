@@ -10,6 +10,7 @@ use std::io::Error;
 use std::str::FromStr;
 
 const SYS_CPU_ONLINE: &str = "/sys/devices/system/cpu/online";
+const SYS_CPU_POSSIBLE: &str = "/sys/devices/system/cpu/possible";
 
 pub type CpuId = i32;
 
@@ -27,6 +28,75 @@ pub fn get_online() -> Result<Vec<CpuId>, Error> {
     Ok(list_from_string(&cpus.trim()))
 }
 
+/// Returns a list of possible CPU IDs.
+///
+/// Per-CPU maps hold one value for each possible CPU, including the ones
+/// that are currently offline.
+///
+/// The only time an error is reported is when
+/// `/sys/devices/system/cpu/possible` can't be opened.
+pub fn get_possible() -> Result<Vec<CpuId>, Error> {
+    let cpus = unsafe { String::from_utf8_unchecked(read(SYS_CPU_POSSIBLE)?) };
+    Ok(list_from_string(cpus.trim()))
+}
+
+/// A change in the set of online CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuEvent {
+    Online(CpuId),
+    Offline(CpuId),
+}
+
+/// Tracks CPUs going online and offline.
+///
+/// The kernel doesn't notify userspace of CPU hotplug through sysfs, so the
+/// list of online CPUs has to be polled. `poll()` returns the changes since
+/// the previous call.
+pub struct OnlineWatcher<F> {
+    online: Vec<CpuId>,
+    read: F,
+}
+
+impl OnlineWatcher<fn() -> Result<Vec<CpuId>, Error>> {
+    /// Creates a watcher reading `/sys/devices/system/cpu/online`.
+    pub fn new() -> Result<Self, Error> {
+        OnlineWatcher::with_reader(get_online)
+    }
+}
+
+impl<F: FnMut() -> Result<Vec<CpuId>, Error>> OnlineWatcher<F> {
+    /// Creates a watcher getting the list of online CPUs from `read`.
+    pub fn with_reader(mut read: F) -> Result<Self, Error> {
+        let online = read()?;
+        Ok(OnlineWatcher { online, read })
+    }
+
+    /// Returns the CPUs that were online at the last poll.
+    pub fn online(&self) -> &[CpuId] {
+        &self.online
+    }
+
+    /// Returns the CPUs that went online or offline since the last poll.
+    pub fn poll(&mut self) -> Result<Vec<CpuEvent>, Error> {
+        let online = (self.read)()?;
+        let mut events: Vec<CpuEvent> = self
+            .online
+            .iter()
+            .filter(|cpu| !online.contains(cpu))
+            .map(|cpu| CpuEvent::Offline(*cpu))
+            .collect();
+        events.extend(
+            online
+                .iter()
+                .filter(|cpu| !self.online.contains(cpu))
+                .map(|cpu| CpuEvent::Online(*cpu)),
+        );
+        self.online = online;
+
+        Ok(events)
+    }
+}
+
 fn list_from_string(cpus: &str) -> Vec<CpuId> {
     let cpu_list = cpus.split(',').flat_map(|group| {
         let mut split = group.split('-');
@@ -53,4 +123,34 @@ mod test {
         assert_eq!(list_from_string("0-4"), vec![0, 1, 2, 3, 4]);
         assert_eq!(list_from_string("0-2,5-6"), vec![0, 1, 2, 5, 6]);
     }
+
+    // Hotplug can be tested manually on a VM with at least two CPUs:
+    //
+    // 1. start a tool using perf events, eg. `redbpf-tcplife`
+    // 2. `echo 0 > /sys/devices/system/cpu/cpu1/online`
+    // 3. generate events pinned to the remaining CPUs, eg. with
+    //    `taskset -c 0 curl ...`, and check that they are still reported
+    // 4. `echo 1 > /sys/devices/system/cpu/cpu1/online`
+    // 5. generate events with `taskset -c 1 curl ...` and check that they
+    //    are reported as well
+    #[test]
+    fn test_hotplug() {
+        use crate::cpus::{list_from_string, CpuEvent, OnlineWatcher};
+
+        let mut states = vec!["0-1", "0", "0", "0-3"].into_iter();
+        let mut watcher =
+            OnlineWatcher::with_reader(|| Ok(list_from_string(states.next().unwrap()))).unwrap();
+        assert_eq!(watcher.online(), &[0, 1]);
+        assert_eq!(watcher.poll().unwrap(), vec![CpuEvent::Offline(1)]);
+        assert_eq!(watcher.poll().unwrap(), vec![]);
+        assert_eq!(
+            watcher.poll().unwrap(),
+            vec![
+                CpuEvent::Online(1),
+                CpuEvent::Online(2),
+                CpuEvent::Online(3)
+            ]
+        );
+        assert_eq!(watcher.online(), &[0, 1, 2, 3]);
+    }
 }
@@ -5,24 +5,345 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::fmt;
+use std::io;
+
 #[derive(Debug)]
 pub enum Error {
     StringConversion,
     BPF,
     Map,
     Section(String),
-    Parse(::goblin::error::Error),
+    /// The ELF file is malformed
+    ElfParse(::goblin::error::Error),
     KernelRelease(String),
-    IO(::std::io::Error),
+    IO(io::Error),
     Uname,
     Reloc,
+    TracepointFormat(String),
+    /// The kernel refused to create the named map. `memlock_limit` is the
+    /// `RLIMIT_MEMLOCK` at the time of `EPERM` failures, see
+    /// `sys::bump_memlock_rlimit()`.
+    MapCreation {
+        name: String,
+        error: io::Error,
+        memlock_limit: Option<u64>,
+    },
+    /// The named program must be loaded first
+    NotLoaded(String),
+    /// The named program is already loaded
+    AlreadyLoaded(String),
+    /// The program has a type redbpf doesn't support, eg. when opened from
+    /// a pin
+    ProgramType(u32),
+    /// The named program isn't attached through a BPF link, which is
+    /// required to pin the attachment
+    NotBpfLink(String),
+    /// The kernel refused to attach the program to the target, eg. a
+    /// function or a tracepoint
+    Attach {
+        program: String,
+        target: String,
+        error: io::Error,
+    },
+    /// All the attempts to attach the named program failed
+    AttachRetries(String, Vec<Error>),
+    /// The feature requires a newer kernel. Versions are encoded like
+    /// `LINUX_VERSION_CODE`.
+    UnsupportedKernel {
+        needed: u32,
+        found: u32,
+    },
+    /// The named maps haven't been initialized
+    Uninitialized(Vec<String>),
+    /// The map element already exists
+    ElementExists,
+    /// The map element doesn't exist
+    ElementNotFound,
+    /// The perf counter isn't provided by any PMU, eg. when running in a
+    /// virtual machine
+    CounterUnavailable(crate::PerfEventAttr),
+    /// The named perf event array has fewer entries than needed to index
+    /// it by the id of every possible CPU
+    CpuEntries {
+        map: String,
+        entries: u32,
+        needed: u32,
+    },
+    /// The named global doesn't exist, or its size doesn't match the value
+    Global(String),
+    /// The named global can't be set after the programs are loaded
+    GlobalAfterLoad(String),
+    /// The directory isn't on a BPF filesystem. See `bpffs::mount_bpffs()`.
+    NotBpffs(::std::path::PathBuf),
+    /// Another XDP program is attached to the interface
+    XdpAttached,
+    /// The driver doesn't support the requested XDP mode, eg. native XDP.
+    /// SKB mode works with all the drivers.
+    XdpModeUnsupported,
+    /// The kernel can't probe the named function at the offset, eg. because
+    /// it isn't on an instruction boundary
+    ProbeOffset(String, u64),
+    /// The ELF file has no symbol tables, eg. because it's stripped
+    NoSymbols(::std::path::PathBuf),
+    /// The ELF file doesn't define the named function or USDT probe
+    SymbolNotFound(::std::path::PathBuf, String),
+    /// The semaphore of the USDT probe can't be set. On kernels older than
+    /// 4.20 it's set in the memory of the traced process, which requires
+    /// attaching the probe to a given process.
+    UsdtSemaphore,
+    /// The configuration of the named map can't be overridden because the
+    /// module has no such map. Lists the maps of the module.
+    UnknownMap(String, Vec<String>),
+    /// The kprobe function or the tracepoint doesn't exist in the running
+    /// kernel. Lists similarly named ones, see the `targets` module.
+    TargetNotFound(String, Vec<String>),
+    /// The targets of the programs are invalid, see
+    /// `Module::validate_targets()`
+    InvalidTargets(Vec<Error>),
+    /// The kernel rejected the program, see `Error::verifier_log()`
+    ProgramLoad(ProgramLoadError),
+    /// Programs of the kind can't be run with `Program::test_run()`
+    TestRunUnsupported(crate::ProgramKind),
+    /// The BTF section of the ELF file is malformed, or defines maps in a
+    /// way that isn't supported
+    Btf(String),
+    /// The kernel rejected the BTF of the ELF file, see
+    /// `Error::verifier_log()`. The log is empty if the kernel doesn't
+    /// support BTF.
+    BtfLoad {
+        error: io::Error,
+        log: String,
+    },
+    /// The named program accesses a field, a type or an enum value that
+    /// isn't found in the kernel BTF, see `ModuleSpec::relocate()`
+    CoreRelocation {
+        program: String,
+        access: String,
+        reason: String,
+    },
+    /// The named map doesn't have the type or the key and value sizes
+    /// expected by the typed wrapper it's used with
+    MapTypeMismatch {
+        map: String,
+        expected: crate::MapLayout,
+        found: crate::MapLayout,
+    },
+}
+
+/// The error returned when the kernel refuses to load a program.
+///
+/// The verifier log can be thousands of lines long, so it's left out of
+/// the `Debug` and `Display` output. Read it with `verifier_log()`.
+pub struct ProgramLoadError {
+    pub program: String,
+    pub error: io::Error,
+    pub(crate) log: String,
+}
+
+impl ProgramLoadError {
+    /// Returns the output of the verifier, empty if the program was
+    /// rejected before being verified, eg. for lack of permissions.
+    pub fn verifier_log(&self) -> &str {
+        &self.log
+    }
+}
+
+impl fmt::Debug for ProgramLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgramLoadError")
+            .field("program", &self.program)
+            .field("error", &self.error)
+            .field("log_lines", &self.log.lines().count())
+            .finish()
+    }
+}
+
+impl fmt::Display for ProgramLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to load program {}: {}", self.program, self.error)?;
+        // the last line of the log usually says why
+        if let Some(reason) = self.log.lines().rev().find(|l| !l.trim().is_empty()) {
+            write!(f, " ({})", reason.trim())?;
+        }
+
+        Ok(())
+    }
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            StringConversion => write!(f, "string contains a nul byte"),
+            BPF => write!(f, "BPF operation failed"),
+            Map => write!(f, "invalid map"),
+            Section(s) => write!(f, "invalid section: {}", s),
+            ElfParse(e) => write!(f, "failed to parse the ELF file: {}", e),
+            KernelRelease(s) => write!(f, "invalid kernel release: {}", s),
+            IO(e) => write!(f, "{}", e),
+            Uname => write!(f, "uname failed"),
+            Reloc => write!(f, "invalid relocation"),
+            TracepointFormat(s) => write!(f, "invalid tracepoint format: {}", s),
+            MapCreation {
+                name,
+                error,
+                memlock_limit,
+            } => {
+                write!(f, "failed to create map {}: {}", name, error)?;
+                if let Some(limit) = memlock_limit {
+                    write!(
+                        f,
+                        " (RLIMIT_MEMLOCK is {} bytes, raise it with `ulimit -l` or \
+                         redbpf::sys::bump_memlock_rlimit())",
+                        limit
+                    )?;
+                }
+                Ok(())
+            }
+            NotLoaded(p) => write!(f, "program {} isn't loaded", p),
+            AlreadyLoaded(p) => write!(f, "program {} is already loaded", p),
+            ProgramType(t) => write!(f, "unsupported program type {}", t),
+            NotBpfLink(p) => write!(f, "program {} isn't attached through a BPF link", p),
+            Attach {
+                program,
+                target,
+                error,
+            } => write!(f, "failed to attach {} to {}: {}", program, target, error),
+            AttachRetries(p, errors) => match errors.last() {
+                Some(e) => write!(f, "{} attempts to attach {} failed: {}", errors.len(), p, e),
+                None => write!(f, "failed to attach {}", p),
+            },
+            UnsupportedKernel { needed, found } => write!(
+                f,
+                "requires Linux {}, running {}",
+                KernelVersion(*needed),
+                KernelVersion(*found)
+            ),
+            Uninitialized(maps) => write!(f, "maps not initialized: {}", maps.join(", ")),
+            ElementExists => write!(f, "map element exists"),
+            ElementNotFound => write!(f, "map element not found"),
+            CounterUnavailable(_) => write!(f, "perf counter unavailable"),
+            CpuEntries {
+                map,
+                entries,
+                needed,
+            } => write!(
+                f,
+                "map {} has {} entries, the possible CPUs need {}",
+                map, entries, needed
+            ),
+            Global(g) => write!(f, "invalid global: {}", g),
+            GlobalAfterLoad(g) => write!(f, "global {} set after loading", g),
+            NotBpffs(p) => write!(f, "{} isn't on a BPF filesystem", p.display()),
+            XdpAttached => write!(f, "another XDP program is attached"),
+            XdpModeUnsupported => write!(f, "XDP mode not supported by the driver"),
+            ProbeOffset(func, offset) => write!(f, "can't probe {}+{:#x}", func, offset),
+            NoSymbols(p) => write!(f, "{} has no symbols", p.display()),
+            SymbolNotFound(p, sym) => write!(f, "{} not found in {}", sym, p.display()),
+            UsdtSemaphore => write!(f, "USDT semaphores require a pid on this kernel"),
+            Btf(s) => write!(f, "invalid BTF: {}", s),
+            BtfLoad { error, log } => {
+                write!(f, "failed to load BTF: {}", error)?;
+                match log.lines().rev().find(|l| !l.trim().is_empty()) {
+                    Some(reason) => write!(f, " ({})", reason.trim()),
+                    None => Ok(()),
+                }
+            }
+            CoreRelocation {
+                program,
+                access,
+                reason,
+            } => write!(
+                f,
+                "program {}: can't relocate {}: {}",
+                program, access, reason
+            ),
+            TestRunUnsupported(k) => write!(f, "{:?} programs can't be test run", k),
+            UnknownMap(m, maps) => write!(f, "no map {}, expected one of: {}", m, maps.join(", ")),
+            TargetNotFound(t, similar) if similar.is_empty() => {
+                write!(f, "{} isn't available in the running kernel", t)
+            }
+            TargetNotFound(t, similar) => write!(
+                f,
+                "{} isn't available in the running kernel, similar: {}",
+                t,
+                similar.join(", ")
+            ),
+            InvalidTargets(errors) => {
+                write!(f, "invalid targets:")?;
+                for e in errors {
+                    write!(f, "\n  {}", e)?;
+                }
+                Ok(())
+            }
+            ProgramLoad(e) => write!(f, "{}", e),
+            MapTypeMismatch {
+                map,
+                expected,
+                found,
+            } => write!(f, "map {}: expected {}, found {}", map, expected, found),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ElfParse(e) => Some(e),
+            Error::IO(e) => Some(e),
+            Error::MapCreation { error, .. } => Some(error),
+            Error::Attach { error, .. } => Some(error),
+            Error::AttachRetries(_, errors) => errors.last().map(|e| e as _),
+            Error::ProgramLoad(e) => Some(e),
+            Error::BtfLoad { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for ProgramLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Formats a `LINUX_VERSION_CODE`
+struct KernelVersion(u32);
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = self.0;
+        write!(f, "{}.{}.{}", v >> 16, (v >> 8) & 0xff, v & 0xff)
+    }
+}
+
+impl Error {
+    /// Returns the verifier log of `Error::ProgramLoad` and
+    /// `Error::BtfLoad` errors.
+    pub fn verifier_log(&self) -> Option<&str> {
+        match self {
+            Error::ProgramLoad(e) => Some(e.verifier_log()),
+            Error::BtfLoad { log, .. } => Some(log),
+            _ => None,
+        }
+    }
+
+    // Translates the errno set by failed map operations
+    pub(crate) fn from_map_errno(errno: i32) -> Error {
+        match errno {
+            libc::EEXIST => Error::ElementExists,
+            libc::ENOENT => Error::ElementNotFound,
+            e => Error::IO(::std::io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
 impl From<::goblin::error::Error> for Error {
     fn from(e: ::goblin::error::Error) -> Error {
-        Error::Parse(e)
+        Error::ElfParse(e)
     }
 }
 
@@ -32,8 +353,59 @@ impl From<::std::ffi::NulError> for Error {
     }
 }
 
-impl From<::std::io::Error> for Error {
-    fn from(e: ::std::io::Error) -> Error {
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
         Error::IO(e)
     }
 }
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::error::ProgramLoadError;
+        use crate::Error;
+        use std::io;
+
+        match Error::from_map_errno(libc::EEXIST) {
+            Error::ElementExists => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        match Error::from_map_errno(libc::ENOENT) {
+            Error::ElementNotFound => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        match Error::from_map_errno(libc::E2BIG) {
+            Error::IO(e) => assert_eq!(e.raw_os_error(), Some(libc::E2BIG)),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let e = ProgramLoadError {
+            program: "block".to_string(),
+            error: io::Error::from_raw_os_error(libc::EACCES),
+            log: "0: (b7) r0 = 0\nR0 !read_ok\n".to_string(),
+        };
+        assert!(e.to_string().starts_with("failed to load program block: "));
+        assert!(e.to_string().ends_with(" (R0 !read_ok)"));
+        assert!(format!("{:?}", e).contains("log_lines: 2"));
+        let e = Error::ProgramLoad(e);
+        assert_eq!(e.verifier_log(), Some("0: (b7) r0 = 0\nR0 !read_ok\n"));
+        assert_eq!(Error::BPF.verifier_log(), None);
+        assert_eq!(
+            Error::NotBpfLink("block".to_string()).to_string(),
+            "program block isn't attached through a BPF link"
+        );
+
+        let e = Error::UnsupportedKernel {
+            needed: 0x05_08_00,
+            found: 0x04_13_0a,
+        };
+        assert_eq!(e.to_string(), "requires Linux 5.8.0, running 4.19.10");
+
+        let e = Error::MapCreation {
+            name: "flows".to_string(),
+            error: io::Error::from_raw_os_error(libc::EPERM),
+            memlock_limit: Some(65536),
+        };
+        assert!(e.to_string().contains("RLIMIT_MEMLOCK is 65536 bytes"));
+    }
+}
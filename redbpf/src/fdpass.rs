@@ -0,0 +1,172 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Passing maps between processes over unix sockets.
+//!
+//! A process with the privileges to load programs can hand their maps to
+//! unprivileged processes, without pinning them. The file descriptor of the
+//! map is sent with `SCM_RIGHTS`, and the receiver rebuilds the `Map` from
+//! it with `Map::from_fd()`:
+//!
+//! ```no_run
+//! use std::os::unix::net::UnixStream;
+//! use redbpf::{fdpass, HashMap};
+//!
+//! let socket = UnixStream::connect("/run/loader.sock").unwrap();
+//! let map = fdpass::recv_map(&socket).unwrap();
+//! // fails if the sizes of the key and value don't match the map
+//! let counts = HashMap::<u32, u64>::new(&map).unwrap();
+//! for (pid, count) in counts.iter() {
+//!     println!("{} {}", pid, count);
+//! }
+//! ```
+//!
+//! The loader sends the map with `send_map()`. Anyone holding the file
+//! descriptor can read and write the map, whatever their privileges.
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use crate::{Error, Map, Result};
+
+/// Sends `map` and its name over `socket`.
+pub fn send_map(socket: &UnixStream, map: &Map) -> Result<()> {
+    let name = map.name.as_bytes();
+    if name.len() > u16::MAX as usize {
+        return Err(Error::IO(io::Error::from_raw_os_error(libc::ENAMETOOLONG)));
+    }
+    let mut data = (name.len() as u16).to_ne_bytes().to_vec();
+    data.extend_from_slice(name);
+
+    send_fd(socket, map.as_raw_fd(), &data).map_err(Error::IO)
+}
+
+/// Receives a map sent with `send_map()` from `socket`.
+///
+/// The type and sizes of the map are read from the kernel with
+/// `Map::from_fd()`.
+pub fn recv_map(socket: &UnixStream) -> Result<Map> {
+    let mut len = [0u8; 2];
+    let fd = recv_fd(socket, &mut len)?;
+    let mut name = vec![0u8; u16::from_ne_bytes(len) as usize];
+    let map = (&*socket)
+        .read_exact(&mut name)
+        .map_err(Error::IO)
+        .and_then(|_| Map::from_fd(fd));
+    let mut map = match map {
+        Ok(map) => map,
+        Err(e) => {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+    };
+    map.name = String::from_utf8_lossy(&name).into_owned();
+
+    Ok(map)
+}
+
+// Sends `data` with `fd` attached to its first byte
+fn send_fd(socket: &UnixStream, fd: RawFd, data: &[u8]) -> io::Result<()> {
+    let mut cmsg_buf =
+        vec![0u8; unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+    msg.msg_controllen = cmsg_buf.len() as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // the fd went with the first byte, the rest is plain data
+    (&*socket).write_all(&data[sent as usize..])
+}
+
+// Fills `data` and returns the fd attached to it
+fn recv_fd(socket: &UnixStream, data: &mut [u8]) -> io::Result<RawFd> {
+    let mut cmsg_buf =
+        vec![0u8; unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut _;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    let fd = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::from_raw_os_error(libc::EBADMSG));
+        }
+        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd)
+    };
+    if let Err(e) = (&*socket).read_exact(&mut data[received as usize..]) {
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+
+    Ok(fd)
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::fdpass::{recv_fd, send_fd};
+        use std::os::unix::net::UnixStream;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut pipe = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+
+        send_fd(&a, pipe[1], b"\x05\x00hello").unwrap();
+        let mut data = [0u8; 7];
+        let fd = recv_fd(&b, &mut data).unwrap();
+        assert_eq!(&data, b"\x05\x00hello");
+        assert_ne!(fd, pipe[1]);
+
+        // the received fd is the write end of the pipe
+        assert_eq!(unsafe { libc::write(fd, b"x".as_ptr() as *const _, 1) }, 1);
+        let mut buf = [0u8; 1];
+        assert_eq!(
+            unsafe { libc::read(pipe[0], buf.as_mut_ptr() as *mut _, 1) },
+            1
+        );
+        assert_eq!(&buf, b"x");
+        unsafe {
+            libc::close(fd);
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+    }
+}
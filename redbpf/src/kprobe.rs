@@ -0,0 +1,383 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Kprobe creation.
+//!
+//! Kprobes and kretprobes are created either through the kprobe PMU of
+//! `perf_event_open(2)`, available since Linux 4.17, or by adding an entry
+//! to the `kprobe_events` file of tracefs, which older and some locked-down
+//! kernels only support. Probes of the PMU are removed by the kernel when
+//! their perf event is closed, entries of `kprobe_events` outlive the
+//! process that added them.
+//!
+//! `redbpf` names its entries `redbpf_<pid>_<n>_<function>` and removes them
+//! when the program is detached, or when the `Link` is dropped. Entries left
+//! by processes that crashed are removed when the next process adds one.
+//!
+//! The PMU is used when the kernel has one, `Program::set_kprobe_mechanism()`
+//! overrides it:
+//!
+//! ```no_run
+//! use redbpf::kprobe::KprobeMechanism;
+//! use redbpf::Module;
+//!
+//! let mut module = Module::parse(&std::fs::read("probe.elf").unwrap()).unwrap();
+//! for prog in module.programs.iter_mut() {
+//!     prog.set_kprobe_mechanism(KprobeMechanism::Tracefs);
+//!     prog.load(module.version, module.license.clone()).unwrap();
+//!     prog.attach_probe().unwrap();
+//! }
+//! ```
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Once;
+
+use libc::{close, syscall, SYS_perf_event_open};
+
+use crate::link::attach_perf_event;
+use crate::sys::perf::*;
+use crate::tracepoint::TRACEFS_PATHS;
+
+const KPROBE_PMU_TYPE: &str = "/sys/bus/event_source/devices/kprobe/type";
+const KPROBE_PMU_RETPROBE: &str = "/sys/bus/event_source/devices/kprobe/format/retprobe";
+const EVENT_GROUP: &str = "kprobes";
+const EVENT_PREFIX: &str = "redbpf_";
+// the kernel rejects longer event names
+const MAX_EVENT_NAME_LEN: usize = 64;
+// the number of names tried before giving up on adding an entry
+const MAX_NAME_ATTEMPTS: usize = 8;
+
+// numbers the entries added by the process, so that names don't collide
+static NEXT_EVENT: AtomicUsize = AtomicUsize::new(0);
+static REMOVE_STALE: Once = Once::new();
+
+/// How kprobes and kretprobes are created.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KprobeMechanism {
+    /// The kprobe PMU, or `kprobe_events` if the kernel has no kprobe PMU
+    /// or for kretprobes with a `maxactive`, which the PMU doesn't support
+    Auto,
+    /// The kprobe PMU of `perf_event_open(2)`. The `maxactive` of kretprobes
+    /// is ignored.
+    Perf,
+    /// The `kprobe_events` file of tracefs
+    Tracefs,
+}
+
+/// An entry of `kprobe_events` added by `redbpf`, removed when it's dropped.
+#[derive(Debug)]
+pub(crate) struct KprobeEvent {
+    tracefs: PathBuf,
+    name: String,
+    removed: AtomicBool,
+}
+
+impl KprobeEvent {
+    fn add(
+        retprobe: bool,
+        function: &str,
+        offset: u64,
+        maxactive: Option<usize>,
+    ) -> io::Result<KprobeEvent> {
+        let tracefs = TRACEFS_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.join("kprobe_events").exists())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "kprobe_events can't be found, is tracefs mounted?",
+                )
+            })?;
+        REMOVE_STALE.call_once(|| remove_stale_events(&tracefs));
+
+        let probe = match (retprobe, maxactive) {
+            (false, _) => "p".to_string(),
+            (true, Some(maxactive)) if maxactive > 0 => format!("r{}", maxactive),
+            (true, _) => "r".to_string(),
+        };
+        let target = if offset == 0 {
+            function.to_string()
+        } else {
+            format!("{}+{}", function, offset)
+        };
+        let mut attempts = 0;
+        loop {
+            let name = event_name(
+                process::id(),
+                NEXT_EVENT.fetch_add(1, Ordering::Relaxed),
+                function,
+            );
+            let definition = format!("{}:{}/{} {}", probe, EVENT_GROUP, name, target);
+            match write_kprobe_events(&tracefs, &definition) {
+                Ok(()) => {
+                    return Ok(KprobeEvent {
+                        tracefs,
+                        name,
+                        removed: AtomicBool::new(false),
+                    })
+                }
+                // the entry was left by a crashed process that had the same
+                // pid, remove it and try another name in case it's in use
+                Err(e)
+                    if matches!(e.raw_os_error(), Some(libc::EEXIST) | Some(libc::EBUSY))
+                        && attempts < MAX_NAME_ATTEMPTS =>
+                {
+                    let _ = remove_event(&tracefs, &name);
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Reads the id of the tracepoint of the entry
+    fn id(&self) -> io::Result<u64> {
+        let path = self
+            .tracefs
+            .join("events")
+            .join(EVENT_GROUP)
+            .join(&self.name)
+            .join("id");
+        fs::read_to_string(path)?
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    /// Removes the entry. The kernel fails with `EBUSY` while a perf event
+    /// of the entry is open.
+    pub(crate) fn remove(&self) -> io::Result<()> {
+        if self.removed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        remove_event(&self.tracefs, &self.name)
+    }
+}
+
+impl Drop for KprobeEvent {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+// Attaches the program `prog_fd` to a new kprobe or kretprobe of `function`.
+// Returns the perf event, the BPF link attaching the program to it if any,
+// and the entry of `kprobe_events` to remove once the event is closed.
+pub(crate) fn open_kprobe(
+    prog_fd: RawFd,
+    mechanism: KprobeMechanism,
+    retprobe: bool,
+    function: &str,
+    offset: u64,
+    maxactive: Option<usize>,
+) -> io::Result<(RawFd, Option<RawFd>, Option<KprobeEvent>)> {
+    let tracefs = match mechanism {
+        KprobeMechanism::Perf => false,
+        KprobeMechanism::Tracefs => true,
+        KprobeMechanism::Auto => {
+            (retprobe && matches!(maxactive, Some(n) if n > 0))
+                || !Path::new(KPROBE_PMU_TYPE).exists()
+        }
+    };
+    if !tracefs {
+        match unsafe { open_pmu_kprobe(prog_fd, retprobe, function, offset) } {
+            Err(e)
+                if mechanism == KprobeMechanism::Auto
+                    && e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+            ret => return ret.map(|(pfd, link)| (pfd, link, None)),
+        }
+    }
+
+    // the entry is removed on errors when it's dropped
+    let event = KprobeEvent::add(retprobe, function, offset, maxactive)?;
+    let (pfd, link) = unsafe { open_tracepoint(prog_fd, event.id()?)? };
+
+    Ok((pfd, link, Some(event)))
+}
+
+unsafe fn open_pmu_kprobe(
+    prog_fd: RawFd,
+    retprobe: bool,
+    function: &str,
+    offset: u64,
+) -> io::Result<(RawFd, Option<RawFd>)> {
+    let pmu_type = fs::read_to_string(KPROBE_PMU_TYPE)?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let cfunction = CString::new(function)?;
+    let mut attr = mem::zeroed::<perf_event_attr>();
+
+    attr.type_ = pmu_type;
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+    if retprobe {
+        let format = fs::read_to_string(KPROBE_PMU_RETPROBE)?;
+        let bit =
+            parse_config_bit(&format).ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        attr.config |= 1 << bit;
+    }
+    attr.__bindgen_anon_3.config1 = cfunction.as_ptr() as u64;
+    attr.__bindgen_anon_4.config2 = offset;
+
+    open_perf_event(prog_fd, &attr)
+}
+
+// Opens the tracepoint `id` and attaches the program `prog_fd` to it
+pub(crate) unsafe fn open_tracepoint(
+    prog_fd: RawFd,
+    id: u64,
+) -> io::Result<(RawFd, Option<RawFd>)> {
+    let mut attr = mem::zeroed::<perf_event_attr>();
+
+    attr.type_ = perf_type_id_PERF_TYPE_TRACEPOINT;
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+    attr.config = id;
+
+    open_perf_event(prog_fd, &attr)
+}
+
+// Opens the event for all the processes and attaches the program `prog_fd`
+// to it, through a BPF link if the kernel supports them
+unsafe fn open_perf_event(
+    prog_fd: RawFd,
+    attr: &perf_event_attr,
+) -> io::Result<(RawFd, Option<RawFd>)> {
+    let mut attr = *attr;
+    attr.__bindgen_anon_1.sample_period = 1;
+    attr.__bindgen_anon_2.wakeup_events = 1;
+
+    let pfd = syscall(
+        SYS_perf_event_open,
+        &attr as *const perf_event_attr,
+        -1,
+        0,
+        -1,
+        PERF_FLAG_FD_CLOEXEC,
+    );
+    if pfd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let pfd = pfd as RawFd;
+    match attach_perf_event(pfd, prog_fd) {
+        Ok(link) => Ok((pfd, link)),
+        Err(e) => {
+            close(pfd);
+            Err(e)
+        }
+    }
+}
+
+fn write_kprobe_events(tracefs: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(tracefs.join("kprobe_events"))?;
+    file.write_all(line.as_bytes())
+}
+
+fn remove_event(tracefs: &Path, name: &str) -> io::Result<()> {
+    write_kprobe_events(tracefs, &format!("-:{}/{}", EVENT_GROUP, name))
+}
+
+// Removes the entries added by processes that aren't running anymore.
+// Entries that are still in use, eg. by `perf`, fail to be removed.
+fn remove_stale_events(tracefs: &Path) {
+    let events = match fs::read_to_string(tracefs.join("kprobe_events")) {
+        Ok(events) => events,
+        Err(_) => return,
+    };
+    for name in stale_events(&events, is_running) {
+        let _ = remove_event(tracefs, &name);
+    }
+}
+
+fn is_running(pid: u32) -> bool {
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Returns the names of the entries of `redbpf` in `kprobe_events` whose
+// process isn't running
+fn stale_events(events: &str, is_running: impl Fn(u32) -> bool) -> Vec<String> {
+    events
+        .lines()
+        .filter_map(|line| {
+            // eg. "p:kprobes/redbpf_42_0_tcp_connect tcp_connect"
+            let definition = line.split_whitespace().next()?;
+            let event = &definition[definition.find(':')? + 1..];
+            let slash = event.find('/')?;
+            let (group, name) = (&event[..slash], &event[slash + 1..]);
+            if group != EVENT_GROUP || !name.starts_with(EVENT_PREFIX) {
+                return None;
+            }
+            let pid = name[EVENT_PREFIX.len()..].split('_').next()?.parse().ok()?;
+            if is_running(pid) {
+                return None;
+            }
+            Some(name.to_string())
+        })
+        .collect()
+}
+
+// Event names only allow alphanumeric characters and underscores, and
+// functions like `tcp_v4_connect.cold` have dots
+fn event_name(pid: u32, n: usize, function: &str) -> String {
+    let mut name = format!("{}{}_{}_", EVENT_PREFIX, pid, n);
+    name.extend(
+        function
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .take(MAX_EVENT_NAME_LEN.saturating_sub(name.len())),
+    );
+    name
+}
+
+// Parses the bit of a PMU format, eg. "config:0"
+fn parse_config_bit(format: &str) -> Option<u32> {
+    let format = format.trim();
+    if !format.starts_with("config:") {
+        return None;
+    }
+    format["config:".len()..].parse().ok()
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::kprobe::{event_name, parse_config_bit, stale_events, MAX_EVENT_NAME_LEN};
+
+        assert_eq!(
+            event_name(42, 3, "tcp_v4_connect.cold"),
+            "redbpf_42_3_tcp_v4_connect_cold"
+        );
+        let long = "a".repeat(100);
+        assert_eq!(event_name(42, 3, &long).len(), MAX_EVENT_NAME_LEN);
+
+        assert_eq!(parse_config_bit("config:0\n"), Some(0));
+        assert_eq!(parse_config_bit("config1:0-63"), None);
+
+        let events = "p:kprobes/redbpf_42_0_tcp_connect tcp_connect
+r16:kprobes/redbpf_43_1_tcp_close tcp_close
+p:kprobes/redbpf_44_0_vfs_read vfs_read
+p:kprobes/myprobe_42 vfs_write
+p:other/redbpf_42_0_vfs_write vfs_write
+";
+        assert_eq!(
+            stale_events(events, |pid| pid == 44),
+            vec![
+                "redbpf_42_0_tcp_connect".to_string(),
+                "redbpf_43_1_tcp_close".to_string()
+            ]
+        );
+    }
+}
@@ -26,10 +26,20 @@
 //!
 //! The ELF sections loaded by RedBPF should follow the following naming convention:
 //!  * `maps/name` for maps
+//!  * `globals/name` for read-only globals, see `Module::set_global()`
 //!  * `kprobe/function_name` for entry probes for `function_name`
 //!  * `kretprobe/function_name` for return probes for `function_name`
+//!  * `uprobe/name` and `uretprobe/name` for userspace entry and return
+//!    probes. Names can be anything, the function is given when attaching.
 //!  * `xdp/name` for XDP probes. Names can be anything.
 //!  * `socketfilter/name` for socket filters. Names can be anything.
+//!  * `classifier/name` for traffic control classifiers. Names can be anything.
+//!  * `tracepoint/category/name` for tracepoints, eg. `tracepoint/sock/inet_sock_set_state`
+//!  * `perf_event/name` for programs run on perf event samples, see
+//!    `Program::attach_sampling()`. Names can be anything.
+//!  * `fields/type.field` for the symbols whose address is replaced with the
+//!    offset of the field in the running kernel, see `read_field!()` in
+//!    `redbpf-probes`
 //!
 //! Additionally, as per convention, the following sections should be present in
 //! the ELF object:
@@ -44,37 +54,102 @@
 //! The magic version number is compatible with GoBPF's convention: during
 //! loading it is replaced with the currently running kernel's internal version,
 //! as returned by `uname()`.
+//!
+//! ### libbpf objects
+//!
+//! Objects compiled with clang for libbpf are loaded too:
+//!  * the maps of the `maps` section, and the BTF defined maps of `.maps`,
+//!    are named after their variables
+//!  * `.rodata`, `.data` and `.bss` are loaded as globals
+//!  * `socket` (and `socket1` and so on), `xdp`, `classifier`, `tc` and
+//!    `perf_event` programs are named after their function
+//!  * `tp/category/name` is an alias of `tracepoint/category/name`
+//!  * the CO-RE relocations of `.BTF.ext` are applied with the BTF of the
+//!    running kernel, see `spec::ModuleSpec::relocate()`
+//!
+//! Each program section must hold a single program, and calls to other
+//! functions aren't supported.
 #![deny(clippy::all)]
 
 #[cfg(feature = "build")]
 #[macro_use]
 extern crate lazy_static;
 
+pub mod bpffs;
+pub mod btf;
 #[cfg(feature = "build")]
 pub mod build;
+mod co_re;
 pub mod cpus;
 mod error;
-#[cfg(feature = "load")]
+pub mod fdpass;
+pub mod kprobe;
+mod link;
 pub mod load;
+pub mod netns;
 mod perf;
+mod perf_consumer;
+pub mod profile;
+pub mod query;
+pub mod retry;
+pub mod socket_filter;
+pub mod spec;
+mod stats;
+pub mod symbols;
 pub mod sys;
+pub mod targets;
+pub mod tc;
+pub mod test_run;
+pub mod tracepoint;
+pub mod uprobe;
+pub mod usdt;
 pub mod xdp;
 pub use bpf_sys::uname;
+/// Types that are valid for any bytes, which can be read from events with
+/// `load::MapEvent::read()`. It's the same trait as `redbpf_probes::Pod`.
+pub use zero::Pod;
 
 use bpf_sys::{bpf_insn, bpf_map_def};
-use goblin::elf::{reloc::RelocSection, section_header as hdr, Elf, SectionHeader, Sym};
 
-use std::collections::HashMap as RSHashMap;
-use std::ffi::CString;
+use std::cmp;
+use std::collections::{HashMap as RSHashMap, HashSet as RSHashSet};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
 use std::io;
 use std::marker::PhantomData;
 use std::mem;
 use std::mem::MaybeUninit;
-use std::os::unix::io::RawFd;
-
-pub use crate::error::{Error, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::vec;
+
+use crate::btf::{Btf, ExtInfo};
+pub use crate::error::{Error, ProgramLoadError, Result};
+use crate::kprobe::KprobeMechanism;
+pub use crate::link::Link;
+use crate::link::{LinkKind, ProbeEvent};
+use crate::netns::NetNs;
 pub use crate::perf::*;
-use crate::uname::get_kernel_internal_version;
+pub use crate::perf_consumer::{CpuPolicy, PerfConsumer, PerfStopHandle};
+use crate::spec::{MapSpec, ModuleSpec, ProgramSpec, RelocationTarget};
+pub use crate::stats::{enable_stats, ProgramStats, StatsHandle};
+use crate::symbols::KernelSymbols;
+use crate::targets::{AttachReport, KernelTargets};
+use crate::test_run::TestRunResult;
+use crate::uname::{get_kernel_internal_version, kernel_version_code};
+use crate::usdt::UsdtProbe;
+
+// The initial size of the verifier log buffer, and the largest size
+// accepted by all the kernels
+const LOG_BUF_SIZE: usize = 64 * 1024;
+const MAX_LOG_BUF_SIZE: usize = (u32::MAX >> 8) as usize;
 
 #[cfg(target_arch = "aarch64")]
 pub type DataPtr = *const u8;
@@ -89,8 +164,14 @@ pub type MutDataPtr = *mut i8;
 pub struct Module {
     pub programs: Vec<Program>,
     pub maps: Vec<Map>,
+    /// The single-entry array maps backing the globals.
+    pub globals: Vec<Map>,
     pub license: String,
     pub version: u32,
+    /// Why the kernel rejected the BTF of the module, in which case the
+    /// programs are loaded without it. `None` on kernels without BTF
+    /// support.
+    pub btf_error: Option<Error>,
 }
 
 /// You can load an eBPF module, and all the programs in it like so:
@@ -145,21 +226,76 @@ pub struct Module {
 /// }
 /// ```
 pub struct Program {
-    pfd: Option<RawFd>,
     fd: Option<RawFd>,
+    links: Vec<Link>,
     pub kind: ProgramKind,
     pub name: String,
     code: Vec<bpf_insn>,
     code_bytes: i32,
+    verifier_log: String,
+    btf: Option<ProgramBtf>,
+    kprobe_mechanism: KprobeMechanism,
+}
+
+// The BTF of the module of a program, and the records of the program in it
+#[derive(Debug, Clone)]
+struct ProgramBtf {
+    fd: Arc<BtfFd>,
+    func_info: Option<ExtInfo>,
+    line_info: Option<ExtInfo>,
+}
+
+// The BTF of a module loaded in the kernel, closed with the last program of
+// the module. The loaded programs keep their own reference to it.
+#[derive(Debug)]
+struct BtfFd(RawFd);
+
+impl Drop for BtfFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Information about a program loaded in the kernel, eg. by another
+/// process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramInfo {
+    pub id: u32,
+    pub name: String,
+    /// `None` for program types not supported by `redbpf`
+    pub kind: Option<ProgramKind>,
+    pub tag: [u8; 8],
+    /// The time the program was loaded, in nanoseconds since boot
+    pub load_time_ns: u64,
+    /// The uid of the process that loaded the program
+    pub created_by_uid: u32,
+    /// The ids of the maps used by the program
+    pub map_ids: Vec<u32>,
+}
+
+/// Information about a map created in the kernel, eg. by another process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapInfo {
+    pub id: u32,
+    pub name: String,
+    pub map_type: bpf_sys::bpf_map_type,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProgramKind {
     Kprobe,
     Kretprobe,
+    Uprobe,
+    Uretprobe,
     XDP,
     SocketFilter,
     Tracepoint,
+    Classifier,
+    PerfEvent,
 }
 
 pub struct Map {
@@ -167,6 +303,67 @@ pub struct Map {
     pub kind: u32,
     fd: RawFd,
     config: bpf_map_def,
+    // template of the inner maps of maps of maps
+    inner_config: Option<bpf_map_def>,
+    initialized: AtomicBool,
+}
+
+/// The type and the key and value sizes of a map, see
+/// `Error::MapTypeMismatch`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MapLayout {
+    pub map_type: bpf_sys::bpf_map_type,
+    pub key_size: u32,
+    pub value_size: u32,
+}
+
+impl fmt::Display for MapLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match spec::map_type_name(self.map_type) {
+            Some(name) => write!(f, "{} map", name)?,
+            None => write!(f, "map of type {}", self.map_type)?,
+        }
+        write!(
+            f,
+            " with {} byte keys and {} byte values",
+            self.key_size, self.value_size
+        )
+    }
+}
+
+/// Overrides the definition of a map in the ELF file, see
+/// `Module::parse_with_config()`.
+///
+/// The fields left to `None` keep the values set by the probe.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MapConfig {
+    pub max_entries: Option<u32>,
+    /// Replaces the flags of the map, eg. with `BPF_F_NO_PREALLOC`
+    pub map_flags: Option<u32>,
+}
+
+impl MapConfig {
+    fn apply(&self, def: &mut bpf_map_def) {
+        if let Some(max_entries) = self.max_entries {
+            def.max_entries = max_entries;
+        }
+        if let Some(map_flags) = self.map_flags {
+            def.map_flags = map_flags;
+        }
+    }
+}
+
+/// Flags controlling how map updates treat existing elements.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateFlags {
+    /// Create a new element or update an existing one.
+    Any,
+    /// Only create a new element, fail with `Error::ElementExists` if it
+    /// already exists.
+    NoExist,
+    /// Only update an existing element, fail with `Error::ElementNotFound`
+    /// if it doesn't exist.
+    Exist,
 }
 
 pub struct HashMap<'a, K: Clone, V: Clone> {
@@ -175,33 +372,231 @@ pub struct HashMap<'a, K: Clone, V: Clone> {
     _v: PhantomData<V>,
 }
 
-#[allow(dead_code)]
-pub struct Rel {
-    shndx: usize,
-    target: usize,
-    offset: u64,
-    sym: usize,
+/// An LRU hash map.
+///
+/// Behaves like `HashMap`, but only accepts maps of type
+/// `BPF_MAP_TYPE_LRU_HASH`. Per-CPU LRU maps hold one value per CPU and
+/// can't be accessed through this type.
+pub struct LruHashMap<'a, K: Clone, V: Clone> {
+    map: HashMap<'a, K, V>,
+}
+
+/// Key of a `LpmTrie`.
+///
+/// `data` holds the prefix in network byte order and `prefix_len` is the
+/// number of leading bits of `data` that are significant. Keys for IP
+/// networks can be created from `(address, prefix_len)` pairs.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LpmKey<T> {
+    pub prefix_len: u32,
+    pub data: T,
+}
+
+/// A longest prefix match trie.
+///
+/// Lookups from probes return the value of the longest prefix matching the
+/// key. Only maps of type `BPF_MAP_TYPE_LPM_TRIE` are accepted.
+///
+/// ```no_run
+/// use redbpf::{LpmKey, LpmTrie, Module};
+/// use std::net::Ipv4Addr;
+///
+/// let code = std::fs::read("bpf.elf").unwrap();
+/// let module = Module::parse(&code).unwrap();
+/// let map = module.maps.iter().find(|m| m.name == "blocklist").unwrap();
+/// let trie = LpmTrie::<[u8; 4], u8>::new(map).unwrap();
+/// trie.insert(LpmKey::from((Ipv4Addr::new(10, 0, 0, 0), 8)), 1);
+/// ```
+pub struct LpmTrie<'a, K: Clone, V: Clone> {
+    map: HashMap<'a, LpmKey<K>, V>,
+}
+
+/// An array map.
+///
+/// Arrays have a fixed number of elements, all initialized to zero, indexed
+/// from 0 to `len() - 1`.
+///
+/// # Example
+/// ```no_run
+/// use redbpf::{Array, Module};
+///
+/// let code = std::fs::read("bpf.elf").unwrap();
+/// let module = Module::parse(&code).unwrap();
+/// let map = module.maps.iter().find(|m| m.name == "counters").unwrap();
+/// let counters = Array::<u64>::new(map).unwrap();
+/// counters.set(0, 42).unwrap();
+/// for (i, count) in counters.iter().enumerate() {
+///     println!("{}: {}", i, count);
+/// }
+/// ```
+pub struct Array<'a, T: Clone> {
+    base: &'a Map,
+    _t: PhantomData<T>,
+}
+
+/// A per-CPU array map.
+///
+/// Values stored in per-CPU maps are replicated on every possible CPU, so
+/// lookups return one value for each CPU.
+pub struct PerCpuArray<'a, T: Clone> {
+    base: &'a Map,
+    _t: PhantomData<T>,
+}
+
+/// A stack trace map.
+///
+/// Holds the stacks collected with `bpf_get_stackid()`. Each element is an
+/// array of instruction pointers, innermost frame first.
+pub struct StackTraceMap<'a> {
+    base: &'a Map,
+}
+
+/// A perf event array holding hardware counters.
+///
+/// Probes read the counters stored in the array with
+/// `PerfEventArray::read_counter()`. The counters are opened for each
+/// online CPU with `attach_perf_counters()`, and closed when the array is
+/// dropped.
+///
+/// ```no_run
+/// use redbpf::{HardwareCounter, Module, PerfEventArray, PerfEventAttr};
+///
+/// let code = std::fs::read("bpf.elf").unwrap();
+/// let module = Module::parse(&code).unwrap();
+/// let map = module.maps.iter().find(|m| m.name == "cycles").unwrap();
+/// let mut cycles = PerfEventArray::new(map).unwrap();
+/// cycles
+///     .attach_perf_counters(PerfEventAttr::hardware(HardwareCounter::CpuCycles))
+///     .unwrap();
+/// ```
+pub struct PerfEventArray<'a> {
+    base: &'a Map,
+    counters: Vec<RawFd>,
+}
+
+/// Reason why `bpf_get_stackid()` failed to return a stack id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackIdError {
+    /// The stack couldn't be collected, eg. user stacks of kernel threads.
+    NotCollected,
+    /// The stack hashed to the id of a different stack.
+    Collision,
+    /// The map is full.
+    Full,
+    Other(i32),
+}
+
+/// A FIFO queue map.
+///
+/// Queues have no keys, they are useful to pass work items from probes to
+/// userspace without the overhead of perf events. Nothing notifies
+/// userspace of new items, so the queue is polled periodically. Async code
+/// should wait with the timer of its runtime instead of sleeping, eg.
+/// `tokio::time::interval()`.
+///
+/// ```no_run
+/// use redbpf::{Module, Queue};
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let code = std::fs::read("bpf.elf").unwrap();
+/// let module = Module::parse(&code).unwrap();
+/// let map = module.maps.iter().find(|m| m.name == "work").unwrap();
+/// let queue = Queue::<u64>::new(map).unwrap();
+/// loop {
+///     while let Some(item) = queue.pop() {
+///         // process the item
+///     }
+///     thread::sleep(Duration::from_millis(100));
+/// }
+/// ```
+pub struct Queue<'a, T: Clone> {
+    base: &'a Map,
+    _t: PhantomData<T>,
+}
+
+/// A LIFO stack map.
+///
+/// Behaves like `Queue`, but elements are popped in reverse insertion
+/// order.
+pub struct Stack<'a, T: Clone> {
+    base: &'a Map,
+    _t: PhantomData<T>,
+}
+
+/// An array of maps.
+///
+/// Each element is another map, created at runtime with `create_inner()`.
+/// Probes look up the inner map for an index and then look up elements in
+/// it. All inner maps have the type and sizes given by the definition of
+/// the array in the probe.
+///
+/// ```no_run
+/// use redbpf::{ArrayOfMaps, HashMap, Module};
+///
+/// let code = std::fs::read("bpf.elf").unwrap();
+/// let module = Module::parse(&code).unwrap();
+/// let map = module.maps.iter().find(|m| m.name == "tenants").unwrap();
+/// let tenants = ArrayOfMaps::new(map).unwrap();
+/// let flows = tenants.create_inner("flows_1").unwrap();
+/// tenants.set_inner(1, &flows).unwrap();
+/// HashMap::<u32, u64>::new(&flows).unwrap().set(42, 0);
+/// ```
+pub struct ArrayOfMaps<'a> {
+    base: &'a Map,
 }
 
+/// A hash map of maps.
+///
+/// Behaves like `ArrayOfMaps`, but inner maps are stored under keys of
+/// type `K`.
+pub struct HashOfMaps<'a, K: Clone> {
+    base: &'a Map,
+    _k: PhantomData<K>,
+}
+
+// Not included in the bundled headers, available since Linux 5.2
+const BPF_PSEUDO_MAP_VALUE: u8 = 2;
+const BPF_F_RDONLY_PROG: u32 = 1 << 7;
+
 impl ProgramKind {
     pub fn to_prog_type(&self) -> bpf_sys::bpf_prog_type {
         use crate::ProgramKind::*;
         match self {
-            Kprobe | Kretprobe => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE,
+            Kprobe | Kretprobe | Uprobe | Uretprobe => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE,
             XDP => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_XDP,
             SocketFilter => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCKET_FILTER,
             Tracepoint => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_TRACEPOINT,
+            Classifier => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SCHED_CLS,
+            PerfEvent => bpf_sys::bpf_prog_type_BPF_PROG_TYPE_PERF_EVENT,
         }
     }
 
     pub fn to_attach_type(&self) -> bpf_sys::bpf_probe_attach_type {
         use crate::ProgramKind::*;
         match self {
-            Kprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_ENTRY,
-            Kretprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_RETURN,
+            Kprobe | Uprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_ENTRY,
+            Kretprobe | Uretprobe => bpf_sys::bpf_probe_attach_type_BPF_PROBE_RETURN,
             a @ Tracepoint => panic!("Program type cannot be used with attach(): {:?}", a),
             a @ SocketFilter => panic!("Program type cannot be used with attach(): {:?}", a),
             a @ XDP => panic!("Program type cannot be used with attach(): {:?}", a),
+            a @ Classifier => panic!("Program type cannot be used with attach(): {:?}", a),
+            a @ PerfEvent => panic!("Program type cannot be used with attach(): {:?}", a),
+        }
+    }
+
+    // Kretprobes have the same program type as kprobes
+    fn from_prog_type(prog_type: bpf_sys::bpf_prog_type) -> Option<ProgramKind> {
+        use ProgramKind::*;
+        match prog_type {
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_KPROBE => Some(Kprobe),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_XDP => Some(XDP),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SOCKET_FILTER => Some(SocketFilter),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_TRACEPOINT => Some(Tracepoint),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_SCHED_CLS => Some(Classifier),
+            bpf_sys::bpf_prog_type_BPF_PROG_TYPE_PERF_EVENT => Some(PerfEvent),
+            _ => None,
         }
     }
 
@@ -210,15 +605,33 @@ impl ProgramKind {
         match section {
             "kretprobe" => Ok(Kretprobe),
             "kprobe" => Ok(Kprobe),
+            "uretprobe" => Ok(Uretprobe),
+            "uprobe" => Ok(Uprobe),
             "xdp" => Ok(XDP),
             "socketfilter" => Ok(SocketFilter),
             "tracepoint" => Ok(Tracepoint),
+            "classifier" => Ok(Classifier),
+            "perf_event" => Ok(PerfEvent),
             sec => Err(Error::Section(sec.to_string())),
         }
     }
 }
 
 impl Program {
+    fn from_spec(spec: &ProgramSpec) -> Program {
+        Program {
+            fd: None,
+            links: Vec::new(),
+            kind: spec.kind,
+            name: spec.name.clone(),
+            code: spec.code.clone(),
+            code_bytes: (spec.code.len() * mem::size_of::<bpf_insn>()) as i32,
+            verifier_log: String::new(),
+            btf: None,
+            kprobe_mechanism: KprobeMechanism::Auto,
+        }
+    }
+
     pub fn new(kind: &str, name: &str, code: &[u8]) -> Result<Program> {
         let code_bytes = code.len() as i32;
         let code = zero::read_array(code).to_vec();
@@ -226,12 +639,106 @@ impl Program {
         let kind = ProgramKind::from_section(kind)?;
 
         Ok(Program {
-            pfd: None,
             fd: None,
+            links: Vec::new(),
             kind,
             name,
             code,
             code_bytes,
+            verifier_log: String::new(),
+            btf: None,
+            kprobe_mechanism: KprobeMechanism::Auto,
+        })
+    }
+
+    /// Opens the program pinned at `path`.
+    ///
+    /// The program is already loaded and can be attached again, eg. by a
+    /// new version of the process that loaded it. Kretprobes are opened as
+    /// `ProgramKind::Kprobe`, set `kind` to `Kretprobe` before attaching
+    /// them.
+    pub fn from_pinned<P: AsRef<Path>>(path: P) -> Result<Program> {
+        let path = path.as_ref();
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { bpf_sys::bpf_obj_get(cpath.as_ptr()) };
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+
+        let mut info = bpf_sys::bpf_prog_info::default();
+        let mut info_len = mem::size_of::<bpf_sys::bpf_prog_info>() as u32;
+        let ret =
+            unsafe { bpf_sys::bpf_obj_get_info(fd, &mut info as *mut _ as *mut _, &mut info_len) };
+        let kind = if ret < 0 {
+            Err(Error::IO(io::Error::last_os_error()))
+        } else {
+            ProgramKind::from_prog_type(info.type_).ok_or(Error::ProgramType(info.type_))
+        };
+        let kind = match kind {
+            Ok(kind) => kind,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::new(),
+        };
+        Ok(Program {
+            fd: Some(fd),
+            links: Vec::new(),
+            kind,
+            name,
+            code: Vec::new(),
+            code_bytes: 0,
+            verifier_log: String::new(),
+            btf: None,
+            kprobe_mechanism: KprobeMechanism::Auto,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> ProgramKind {
+        self.kind
+    }
+
+    /// Returns the file descriptor of the program, `None` until it's
+    /// loaded.
+    ///
+    /// The descriptor is owned by the program, don't close it.
+    pub fn fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    /// Returns the id the kernel assigned to the program, as listed by
+    /// `bpftool prog`.
+    ///
+    /// Fails with `Error::NotLoaded` if the program isn't loaded.
+    pub fn id(&self) -> Result<u32> {
+        Ok(self.info()?.id)
+    }
+
+    /// Returns what the kernel knows about the program.
+    ///
+    /// Fails with `Error::NotLoaded` if the program isn't loaded.
+    pub fn info(&self) -> Result<ProgramInfo> {
+        ProgramInfo::from_fd(self.loaded_fd()?)
+    }
+
+    /// Returns the run time statistics of the program.
+    ///
+    /// The kernel only counts runs while statistics are enabled, see
+    /// `enable_stats()`.
+    pub fn stats(&self) -> Result<ProgramStats> {
+        let info: bpf_sys::bpf_prog_info = obj_info(self.loaded_fd()?)?;
+        Ok(ProgramStats {
+            run_count: info.run_cnt,
+            run_time_ns: info.run_time_ns,
         })
     }
 
@@ -239,374 +746,3164 @@ impl Program {
         self.fd.is_some()
     }
 
+    fn loaded_fd(&self) -> Result<RawFd> {
+        self.fd.ok_or_else(|| Error::NotLoaded(self.name.clone()))
+    }
+
     pub fn is_attached(&self) -> bool {
-        self.pfd.is_some()
+        !self.links.is_empty()
     }
 
-    pub fn load(&mut self, kernel_version: u32, license: String) -> Result<RawFd> {
-        let clicense = CString::new(license)?;
-        let cname = CString::new(self.name.clone())?;
-        let log_buffer: MutDataPtr =
-            unsafe { libc::malloc(mem::size_of::<i8>() * 16 * 65535) as MutDataPtr };
-        let buf_size = 64 * 65535 as u32;
+    /// Returns the links attaching the program.
+    pub fn links(&self) -> &[Link] {
+        &self.links
+    }
 
-        let fd = unsafe {
-            bpf_sys::bcc_prog_load(
-                self.kind.to_prog_type(),
-                cname.as_ptr() as DataPtr,
-                self.code.as_ptr(),
-                self.code_bytes,
-                clicense.as_ptr() as DataPtr,
-                kernel_version as u32,
-                0 as i32,
-                log_buffer,
-                buf_size,
-            )
-        };
+    /// Removes the links attaching the program from it and returns them, eg.
+    /// to detach or pin them individually.
+    pub fn take_links(&mut self) -> Vec<Link> {
+        mem::take(&mut self.links)
+    }
 
-        if fd < 0 {
-            Err(Error::BPF)
-        } else {
-            self.fd = Some(fd);
-            Ok(fd)
+    /// Detaches the program from all its attach points.
+    ///
+    /// Returns the first error, after trying to detach all the links.
+    pub fn detach(&mut self) -> Result<()> {
+        let mut ret = Ok(());
+        for link in self.take_links() {
+            let res = link.detach();
+            if ret.is_ok() {
+                ret = res;
+            }
         }
+        ret
     }
 
-    pub fn attach_probe(&mut self) -> Result<RawFd> {
-        self.attach_probe_to_name(&self.name.clone())
+    /// Detaches the program and closes its file descriptor.
+    ///
+    /// The kernel frees the program once it's neither open nor pinned
+    /// anywhere. The program can be loaded again with `load()`. Returns
+    /// the first error detaching it, the program is unloaded anyway.
+    pub fn unload(&mut self) -> Result<()> {
+        let ret = self.detach();
+        if let Some(fd) = self.fd.take() {
+            unsafe { libc::close(fd) };
+        }
+        ret
     }
 
-    pub fn attach_probe_to_name(&mut self, name: &str) -> Result<RawFd> {
-        let ev_name = CString::new(format!("{}{}", name, self.kind.to_attach_type())).unwrap();
-        let cname = CString::new(name).unwrap();
-        let pfd = unsafe {
-            bpf_sys::bpf_attach_kprobe(
-                self.fd.unwrap(),
-                self.kind.to_attach_type(),
-                ev_name.as_ptr(),
-                cname.as_ptr(),
-                0,
-                0,
-            )
-        };
+    /// Pins the loaded program at `path`, so that it outlives the process
+    /// and can be opened with `Program::from_pinned()`.
+    ///
+    /// Pinning a program doesn't keep its attachments alive, see
+    /// `pin_link()`.
+    pub fn pin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        pin_fd(self.loaded_fd()?, path.as_ref())
+    }
 
-        if pfd < 0 {
-            Err(Error::BPF)
-        } else {
-            self.pfd = Some(pfd);
-            Ok(pfd)
+    /// Pins the link attaching the program at `path`, so that the program
+    /// stays attached after the process exits.
+    ///
+    /// Pins the first BPF link of the program, see `Link::pin()`. Fails with
+    /// `Error::NotBpfLink` if the program isn't attached through a BPF link.
+    pub fn pin_link<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        match self.links.iter().find(|l| l.is_bpf_link()) {
+            Some(link) => link.pin(path),
+            None => Err(Error::NotBpfLink(self.name.clone())),
         }
     }
 
-    pub fn attach_tracepoint(&mut self, category: &str, name: &str) -> Result<RawFd> {
-        let category = CString::new(category)?;
-        let name = CString::new(name)?;
-        let res = unsafe {
-            bpf_sys::bpf_attach_tracepoint(
-                self.fd.unwrap(),
-                category.as_c_str().as_ptr(),
-                name.as_c_str().as_ptr(),
-            )
-        };
+    /// Loads the program in the kernel.
+    ///
+    /// Fails with `Error::ProgramLoad` if the kernel rejects the program,
+    /// with the output of the verifier.
+    pub fn load(&mut self, kernel_version: u32, license: String) -> Result<RawFd> {
+        self.load_with_log_level(kernel_version, license, 0)
+    }
 
-        if res < 0 {
-            Err(Error::BPF)
-        } else {
-            Ok(res)
+    /// Loads the program like `load()`, with the verifier logging at
+    /// `log_level`.
+    ///
+    /// Level 1 logs the instructions as they are verified, level 2 also
+    /// logs the state of the registers. With a level above 0 the log of
+    /// successful loads is kept, see `verifier_log()`.
+    ///
+    /// Programs of modules with BTF are loaded with their functions and
+    /// source lines, so that the log shows the lines being verified. They
+    /// are loaded again without BTF if the kernel rejects them, eg. because
+    /// it's too old. The error returned is then the one of the first load.
+    pub fn load_with_log_level(
+        &mut self,
+        kernel_version: u32,
+        license: String,
+        log_level: u32,
+    ) -> Result<RawFd> {
+        let clicense = CString::new(license)?;
+        let cname = CString::new(self.name.clone())?;
+        let btf = match self.btf.clone() {
+            Some(btf) => btf,
+            None => return self.load_without_btf(kernel_version, &clicense, &cname, log_level),
+        };
+        match self.load_with_btf(&btf, kernel_version, &clicense, &cname, log_level) {
+            Ok(fd) => Ok(fd),
+            Err(e) => self
+                .load_without_btf(kernel_version, &clicense, &cname, log_level)
+                .map_err(|_| e),
         }
     }
 
-    pub fn attach_xdp(&mut self, iface: &str, flags: xdp::Flags) -> Result<()> {
-        let ciface = CString::new(iface).unwrap();
-        let res =
-            unsafe { bpf_sys::bpf_attach_xdp(ciface.as_ptr(), self.fd.unwrap(), flags as u32) };
+    fn load_with_btf(
+        &mut self,
+        btf: &ProgramBtf,
+        kernel_version: u32,
+        license: &CStr,
+        name: &CStr,
+        log_level: u32,
+    ) -> Result<RawFd> {
+        let mut attr = sys::bpf::bpf_prog_load_attr {
+            prog_type: self.kind.to_prog_type(),
+            insn_cnt: self.code.len() as u32,
+            insns: self.code.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            kern_version: kernel_version,
+            prog_btf_fd: btf.fd.0 as u32,
+            ..Default::default()
+        };
+        let name = name.to_bytes();
+        let len = cmp::min(name.len(), attr.prog_name.len() - 1);
+        attr.prog_name[..len].copy_from_slice(&name[..len]);
+        if let Some(info) = &btf.func_info {
+            attr.func_info_rec_size = info.rec_size;
+            attr.func_info = info.records.as_ptr() as u64;
+            attr.func_info_cnt = info.count();
+        }
+        if let Some(info) = &btf.line_info {
+            attr.line_info_rec_size = info.rec_size;
+            attr.line_info = info.records.as_ptr() as u64;
+            attr.line_info_cnt = info.count();
+        }
+        let mut log_buf = vec![0u8; LOG_BUF_SIZE];
+        let mut level = log_level;
+
+        loop {
+            log_buf[0] = 0;
+            if level > 0 {
+                attr.log_level = level;
+                attr.log_buf = log_buf.as_mut_ptr() as u64;
+                attr.log_size = log_buf.len() as u32;
+            }
+            let fd = unsafe { sys::bpf::bpf_prog_load(&mut attr) };
+            let error = io::Error::last_os_error();
+            let len = log_buf
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(log_buf.len());
+            let log = String::from_utf8_lossy(&log_buf[..len]).into_owned();
+
+            if fd >= 0 {
+                self.fd = Some(fd);
+                self.verifier_log = log;
+                return Ok(fd);
+            }
+            // like bcc_prog_load(), load again with a log to report why
+            if level == 0 {
+                level = 1;
+                continue;
+            }
+            // the log didn't fit in the buffer
+            if error.raw_os_error() == Some(libc::ENOSPC) && log_buf.len() < MAX_LOG_BUF_SIZE {
+                let size = cmp::min(log_buf.len() * 4, MAX_LOG_BUF_SIZE);
+                log_buf.resize(size, 0);
+                continue;
+            }
 
-        if res < 0 {
-            Err(Error::BPF)
-        } else {
-            Ok(())
+            return Err(Error::ProgramLoad(ProgramLoadError {
+                program: self.name.clone(),
+                error,
+                log,
+            }));
         }
     }
 
-    pub fn attach_socketfilter(&mut self, iface: &str) -> Result<RawFd> {
-        let ciface = CString::new(iface).unwrap();
-        let sfd = unsafe { bpf_sys::bpf_open_raw_sock(ciface.as_ptr()) };
+    fn load_without_btf(
+        &mut self,
+        kernel_version: u32,
+        clicense: &CStr,
+        cname: &CStr,
+        log_level: u32,
+    ) -> Result<RawFd> {
+        let mut log_buf = vec![0u8; LOG_BUF_SIZE];
+
+        loop {
+            log_buf[0] = 0;
+            let fd = unsafe {
+                bpf_sys::bcc_prog_load(
+                    self.kind.to_prog_type(),
+                    cname.as_ptr() as DataPtr,
+                    self.code.as_ptr(),
+                    self.code_bytes,
+                    clicense.as_ptr() as DataPtr,
+                    kernel_version,
+                    log_level as i32,
+                    log_buf.as_mut_ptr() as MutDataPtr,
+                    log_buf.len() as u32,
+                )
+            };
+            let error = io::Error::last_os_error();
+            let len = log_buf
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(log_buf.len());
+            let log = String::from_utf8_lossy(&log_buf[..len]).into_owned();
+
+            if fd >= 0 {
+                self.fd = Some(fd);
+                self.verifier_log = log;
+                return Ok(fd);
+            }
+            // the log didn't fit in the buffer
+            if error.raw_os_error() == Some(libc::ENOSPC) && log_buf.len() < MAX_LOG_BUF_SIZE {
+                let size = cmp::min(log_buf.len() * 4, MAX_LOG_BUF_SIZE);
+                log_buf.resize(size, 0);
+                continue;
+            }
 
-        if sfd < 0 {
-            return Err(Error::IO(io::Error::last_os_error()));
+            return Err(Error::ProgramLoad(ProgramLoadError {
+                program: self.name.clone(),
+                error,
+                log,
+            }));
         }
+    }
 
-        match unsafe { bpf_sys::bpf_attach_socket(sfd, self.fd.ok_or(Error::BPF)?) } {
-            0 => {
-                self.pfd = Some(sfd);
-                Ok(sfd)
-            }
-            _ => Err(Error::IO(io::Error::last_os_error())),
-        }
+    /// Returns the verifier log of the last load with a log level above 0.
+    pub fn verifier_log(&self) -> &str {
+        &self.verifier_log
     }
-}
 
-impl Module {
-    pub fn parse(bytes: &[u8]) -> Result<Module> {
-        let object = Elf::parse(&bytes[..])?;
-        let symtab = object.syms.to_vec();
-        let shdr_relocs = &object.shdr_relocs;
+    /// Runs the program through the verifier without keeping it loaded.
+    ///
+    /// The program is loaded like with `load_with_log_level()` and its file
+    /// descriptor is closed right away, so it's never attached. Returns the
+    /// verifier log, empty at log level 0, or `Error::ProgramLoad` with the
+    /// log if the kernel rejects the program, and `Error::AlreadyLoaded` if
+    /// the program is already loaded. None of the program kinds need an expected
+    /// attach type to load.
+    pub fn verify(
+        &mut self,
+        kernel_version: u32,
+        license: String,
+        log_level: u32,
+    ) -> Result<String> {
+        if self.is_loaded() {
+            return Err(Error::AlreadyLoaded(self.name.clone()));
+        }
+        self.load_with_log_level(kernel_version, license, log_level)?;
+        if let Some(fd) = self.fd.take() {
+            unsafe { libc::close(fd) };
+        }
 
-        let mut rels = vec![];
-        let mut programs = RSHashMap::new();
-        let mut maps = RSHashMap::new();
+        Ok(mem::take(&mut self.verifier_log))
+    }
 
-        let mut license = String::new();
-        let mut version = 0u32;
+    /// Sets how the kprobes and kretprobes of the program are created.
+    /// Defaults to `KprobeMechanism::Auto`.
+    pub fn set_kprobe_mechanism(&mut self, mechanism: KprobeMechanism) {
+        self.kprobe_mechanism = mechanism;
+    }
 
-        for (shndx, shdr) in object.section_headers.iter().enumerate() {
-            let (kind, name) = get_split_section_name(&object, &shdr, shndx)?;
+    pub fn attach_probe(&mut self) -> Result<RawFd> {
+        self.attach_probe_to_name(&self.name.clone())
+    }
 
-            let section_type = shdr.sh_type;
-            let content = data(&bytes, &shdr);
+    pub fn attach_probe_to_name(&mut self, name: &str) -> Result<RawFd> {
+        self.attach_probe_at(name, 0, None)
+    }
 
-            match (section_type, kind, name) {
-                (hdr::SHT_REL, _, _) => add_rel(&mut rels, shndx, &shdr, shdr_relocs),
-                (hdr::SHT_PROGBITS, Some("version"), _) => version = get_version(&content),
-                (hdr::SHT_PROGBITS, Some("license"), _) => {
-                    license = zero::read_str(content).to_string()
-                }
-                (hdr::SHT_PROGBITS, Some("maps"), Some(name)) => {
-                    // Maps are immediately bcc_create_map'd
-                    maps.insert(shndx, Map::load(name, &content)?);
-                }
-                (hdr::SHT_PROGBITS, Some(kind @ "kprobe"), Some(name))
-                | (hdr::SHT_PROGBITS, Some(kind @ "kretprobe"), Some(name))
-                | (hdr::SHT_PROGBITS, Some(kind @ "xdp"), Some(name))
-                | (hdr::SHT_PROGBITS, Some(kind @ "socketfilter"), Some(name)) => {
-                    programs.insert(shndx, Program::new(kind, name, &content)?);
+    /// Attaches the kprobe or kretprobe to the function `name`.
+    ///
+    /// Kprobes are attached at `offset` bytes into the function, which must
+    /// fall on an instruction boundary. Fails with `Error::ProbeOffset` if the
+    /// kernel rejects it. Kretprobes must be attached at offset 0.
+    ///
+    /// `maxactive` is the number of concurrent calls of the function a
+    /// kretprobe can track, returns of the calls beyond that are missed. The
+    /// kernel picks a default based on the number of CPUs when it's `None`,
+    /// which can be too low for recursive functions. It's ignored for
+    /// kprobes. Only `kprobe_events` supports it, see
+    /// `kprobe::KprobeMechanism`.
+    pub fn attach_probe_at(
+        &mut self,
+        name: &str,
+        offset: u64,
+        maxactive: Option<usize>,
+    ) -> Result<RawFd> {
+        let fd = self.loaded_fd()?;
+        self.attach_kprobe(fd, name, offset, maxactive)
+            .or_else(|error| {
+                // kprobes can only be placed on instruction boundaries
+                if offset != 0 && error.raw_os_error() == Some(libc::EILSEQ) {
+                    return Err(Error::ProbeOffset(name.to_string(), offset));
                 }
-                _ => {}
-            }
+                KernelTargets::load().check_function(name)?;
+                Err(self.attach_error(name.to_string(), error))
+            })
+    }
+
+    /// Attaches the kprobe or kretprobe to all the functions of
+    /// `available_filter_functions` matching the glob `pattern`, eg. `tcp_*`.
+    ///
+    /// The program is attached to at most `targets::DEFAULT_MATCH_LIMIT`
+    /// functions, see `attach_kprobe_matching_with_limit()`. Failing to
+    /// attach to some functions isn't an error, the report lists them with
+    /// the errors. Fails with `Error::TargetNotFound` if no function
+    /// matches. `detach()` detaches the program from all the functions.
+    ///
+    /// The probe can tell which function it runs for by its
+    /// `Registers::ip()`, see `AttachReport::function()`.
+    pub fn attach_kprobe_matching(&mut self, pattern: &str) -> Result<AttachReport> {
+        self.attach_kprobe_matching_with_limit(pattern, targets::DEFAULT_MATCH_LIMIT)
+    }
+
+    /// Attaches the kprobe or kretprobe like `attach_kprobe_matching()`, to
+    /// at most `limit` functions in alphabetical order.
+    pub fn attach_kprobe_matching_with_limit(
+        &mut self,
+        pattern: &str,
+        limit: usize,
+    ) -> Result<AttachReport> {
+        let fd = self.loaded_fd()?;
+        let functions = targets::matching_functions(pattern)?;
+        if functions.is_empty() {
+            return Err(Error::TargetNotFound(pattern.to_string(), Vec::new()));
         }
 
-        // Rewrite programs with relocation data
-        for rel in rels.iter() {
-            if programs.contains_key(&rel.target) {
-                rel.apply(&mut programs, &maps, &symtab)?;
+        let mut report = AttachReport::default();
+        report.skipped = functions.len().saturating_sub(limit);
+        for function in functions.into_iter().take(limit) {
+            match self.attach_kprobe(fd, &function, 0, None) {
+                Ok(_) => report.attached.push(function),
+                Err(error) => {
+                    let e = self.attach_error(function.clone(), error);
+                    report.failed.push((function, e));
+                }
             }
         }
+        // without the addresses the functions can't be told apart
+        if let Ok(symbols) = KernelSymbols::load() {
+            report.resolve_addresses(&symbols);
+        }
 
-        let programs = programs.drain().map(|(_, v)| v).collect();
-        let maps = maps.drain().map(|(_, v)| v).collect();
-        Ok(Module {
-            programs,
-            maps,
-            license,
-            version,
-        })
+        Ok(report)
     }
-}
 
-#[inline]
-fn get_split_section_name<'o>(
-    object: &'o Elf<'_>,
-    shdr: &'o SectionHeader,
-    shndx: usize,
-) -> Result<(Option<&'o str>, Option<&'o str>)> {
-    let name = object
-        .shdr_strtab
-        .get_unsafe(shdr.sh_name)
-        .ok_or_else(|| Error::Section(format!("Section name not found: {}", shndx)))?;
+    // Attaches the kprobe or kretprobe with the mechanism of the program,
+    // failing with the error of the kernel
+    fn attach_kprobe(
+        &mut self,
+        fd: RawFd,
+        name: &str,
+        offset: u64,
+        maxactive: Option<usize>,
+    ) -> io::Result<RawFd> {
+        let retprobe = self.kind == ProgramKind::Kretprobe;
+        let maxactive = maxactive.filter(|_| retprobe);
+        let (pfd, link, event) =
+            kprobe::open_kprobe(fd, self.kprobe_mechanism, retprobe, name, offset, maxactive)?;
+        let kind = LinkKind::Perf {
+            fd: pfd,
+            link,
+            probe: event.map(ProbeEvent::Kprobe),
+        };
+        self.links.push(Link::new(&self.name, kind));
 
-    let mut names = name.splitn(2, '/');
+        Ok(pfd)
+    }
 
-    let kind = names.next();
-    let name = names.next();
+    /// Attaches the uprobe to the function `symbol` in the executable or
+    /// shared library at `path`.
+    ///
+    /// `path` can also be a library name like `libssl`, which is looked up
+    /// with `uprobe::find_library()`. The probe runs for all the processes
+    /// using the file, or only for `pid`.
+    pub fn attach_uprobe(
+        &mut self,
+        path: &str,
+        symbol: &str,
+        pid: Option<libc::pid_t>,
+    ) -> Result<RawFd> {
+        let attach_type = bpf_sys::bpf_probe_attach_type_BPF_PROBE_ENTRY;
+        self.attach_uprobe_type(attach_type, path, symbol, pid)
+    }
 
-    Ok((kind, name))
-}
+    /// Attaches the uretprobe to the function `symbol` in the executable or
+    /// shared library at `path`. See `attach_uprobe()`.
+    pub fn attach_uretprobe(
+        &mut self,
+        path: &str,
+        symbol: &str,
+        pid: Option<libc::pid_t>,
+    ) -> Result<RawFd> {
+        let attach_type = bpf_sys::bpf_probe_attach_type_BPF_PROBE_RETURN;
+        self.attach_uprobe_type(attach_type, path, symbol, pid)
+    }
 
-impl Rel {
-    #[inline]
-    pub fn apply(
-        &self,
-        programs: &mut RSHashMap<usize, Program>,
-        maps: &RSHashMap<usize, Map>,
-        symtab: &[Sym],
-    ) -> Result<()> {
-        let prog = programs.get_mut(&self.target).ok_or(Error::Reloc)?;
-        let map = maps
-            .get(&symtab[self.sym].st_shndx)
-            .ok_or(Error::Reloc)?;
-        let insn_idx = (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize;
+    fn attach_uprobe_type(
+        &mut self,
+        attach_type: bpf_sys::bpf_probe_attach_type,
+        path: &str,
+        symbol: &str,
+        pid: Option<libc::pid_t>,
+    ) -> Result<RawFd> {
+        let path = uprobe::find_library(path).ok_or_else(|| {
+            Error::IO(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("library not found: {}", path),
+            ))
+        })?;
+        let offset = uprobe::resolve_symbol(&path, symbol)?;
+        self.attach_uprobe_offset(attach_type, &path, offset, pid)
+    }
 
-        prog.code[insn_idx].set_src_reg(bpf_sys::BPF_PSEUDO_MAP_FD as u8);
-        prog.code[insn_idx].imm = map.fd;
+    fn attach_uprobe_offset(
+        &mut self,
+        attach_type: bpf_sys::bpf_probe_attach_type,
+        path: &Path,
+        offset: u64,
+        pid: Option<libc::pid_t>,
+    ) -> Result<RawFd> {
+        // event names can only contain letters, digits and underscores
+        let target: String = path
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let pid = pid.unwrap_or(-1);
+        let ev_name = format!("{}{}_0x{:x}_{}", attach_type, target, offset, pid.max(0));
+        let ev_name = CString::new(ev_name)?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let pfd = unsafe {
+            bpf_sys::bpf_attach_uprobe(
+                self.loaded_fd()?,
+                attach_type,
+                ev_name.as_ptr(),
+                cpath.as_ptr(),
+                offset,
+                pid,
+            )
+        };
 
-        Ok(())
+        if pfd < 0 {
+            let target = format!("{}+{:#x}", path.display(), offset);
+            Err(self.attach_error(target, io::Error::last_os_error()))
+        } else {
+            let kind = LinkKind::Perf {
+                fd: pfd,
+                link: None,
+                probe: Some(ProbeEvent::Uprobe(ev_name)),
+            };
+            self.links.push(Link::new(&self.name, kind));
+            Ok(pfd)
+        }
     }
-}
 
-impl Map {
-    pub fn load(name: &str, code: &[u8]) -> Result<Map> {
-        let config: bpf_map_def = *zero::read(code);
-        let cname = CString::new(name.to_owned())?;
-        let fd = unsafe {
-            bpf_sys::bcc_create_map(
-                config.type_,
-                cname.as_ptr(),
-                config.key_size as i32,
-                config.value_size as i32,
-                config.max_entries as i32,
-                config.map_flags as i32,
-            )
+    /// Attaches the uprobe to all the locations of the USDT `probe`, for all
+    /// the processes using the file defining it, or only for `pid`.
+    ///
+    /// The semaphore of the probe, if any, is incremented while the program
+    /// is attached. See the `usdt` module.
+    pub fn attach_usdt(&mut self, probe: &UsdtProbe, pid: Option<libc::pid_t>) -> Result<()> {
+        // the kernel increments semaphores since 4.20
+        let ref_ctr_offset = match probe.semaphore_offset {
+            Some(offset) if get_kernel_internal_version().unwrap_or(0) >= 0x04_14_00 => {
+                Some(offset)
+            }
+            _ => None,
         };
-        if fd < 0 {
-            return Err(Error::Map);
+        if probe.semaphore_offset.is_some() && ref_ctr_offset.is_none() {
+            let semaphore = usdt::Semaphore::increment(probe, pid.ok_or(Error::UsdtSemaphore)?)?;
+            self.links
+                .push(Link::new(&self.name, LinkKind::UsdtSemaphore(semaphore)));
         }
 
-        Ok(Map {
-            name: name.to_string(),
-            kind: config.type_,
-            fd,
+        let entry = bpf_sys::bpf_probe_attach_type_BPF_PROBE_ENTRY;
+        for location in probe.locations.iter() {
+            match ref_ctr_offset {
+                Some(ref_ctr_offset) => {
+                    let path = CString::new(probe.path.as_os_str().as_bytes())?;
+                    let pfd = unsafe {
+                        perf::open_uprobe(
+                            self.loaded_fd()?,
+                            &path,
+                            location.offset,
+                            ref_ctr_offset,
+                            pid.unwrap_or(-1),
+                        )?
+                    };
+                    let kind = LinkKind::Perf {
+                        fd: pfd,
+                        link: None,
+                        probe: None,
+                    };
+                    self.links.push(Link::new(&self.name, kind));
+                }
+                None => {
+                    self.attach_uprobe_offset(entry, &probe.path, location.offset, pid)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn attach_tracepoint(&mut self, category: &str, name: &str) -> Result<RawFd> {
+        let fd = self.loaded_fd()?;
+        let res = tracepoint::id(category, name)
+            .and_then(|id| unsafe { kprobe::open_tracepoint(fd, id) });
+
+        match res {
+            Ok((pfd, link)) => {
+                let kind = LinkKind::Perf {
+                    fd: pfd,
+                    link,
+                    probe: None,
+                };
+                self.links.push(Link::new(&self.name, kind));
+                Ok(pfd)
+            }
+            Err(error) => {
+                KernelTargets::load().check_tracepoint(category, name)?;
+                let target = format!("{}/{}", category, name);
+                Err(self.attach_error(target, error))
+            }
+        }
+    }
+
+    fn attach_error(&self, target: String, error: io::Error) -> Error {
+        Error::Attach {
+            program: self.name.clone(),
+            target,
+            error,
+        }
+    }
+
+    /// Runs the perf event program on the samples of the event in `spec`,
+    /// on every online CPU.
+    ///
+    /// Returns the perf events, one for each CPU online at the time of the
+    /// call. Events are disabled when the program is detached. CPUs going
+    /// online later can be watched for with `cpus::OnlineWatcher` and passed to
+    /// `attach_sampling_cpu()`.
+    pub fn attach_sampling(&mut self, spec: SampleSpec) -> Result<Vec<RawFd>> {
+        cpus::get_online()?
+            .into_iter()
+            .map(|cpu| self.attach_sampling_cpu(spec, cpu))
+            .collect()
+    }
+
+    /// Runs the perf event program on the samples of the event in `spec` on
+    /// `cpu`.
+    pub fn attach_sampling_cpu(&mut self, spec: SampleSpec, cpu: cpus::CpuId) -> Result<RawFd> {
+        let pfd = unsafe { perf::open_sampling_event(self.loaded_fd()?, &spec, cpu)? };
+        let kind = LinkKind::Perf {
+            fd: pfd,
+            link: None,
+            probe: None,
+        };
+        self.links.push(Link::new(&self.name, kind));
+
+        Ok(pfd)
+    }
+
+    /// Attaches the XDP program to `iface` through netlink.
+    ///
+    /// The program stays attached until it's replaced or detached with
+    /// `xdp::detach_xdp()`, even after the process exits. Fails with
+    /// `Error::XdpModeUnsupported` if the driver doesn't support the mode in
+    /// `flags`, so callers can retry with `xdp::Flags::SKB_MODE`, and with
+    /// `Error::XdpAttached` if `flags` include `UPDATE_IF_NOEXIST` and
+    /// another program is attached.
+    pub fn attach_xdp(&mut self, iface: &str, flags: xdp::Flags) -> Result<()> {
+        self.attach_xdp_in(None, iface, flags)
+    }
+
+    /// Attaches the XDP program to `iface` in the network namespace
+    /// `netns` through netlink, like `attach_xdp()` does.
+    pub fn attach_xdp_netns(
+        &mut self,
+        netns: &NetNs,
+        iface: &str,
+        flags: xdp::Flags,
+    ) -> Result<()> {
+        self.attach_xdp_in(Some(netns), iface, flags)
+    }
+
+    fn attach_xdp_in(
+        &mut self,
+        netns: Option<&NetNs>,
+        iface: &str,
+        flags: xdp::Flags,
+    ) -> Result<()> {
+        let fd = self.loaded_fd()?;
+        let name = iface.to_string();
+        netns::run_in(netns, move || xdp::attach(&name, fd, flags))?;
+        let kind = LinkKind::Xdp {
+            iface: iface.to_string(),
+            flags,
+            netns: netns.cloned(),
+        };
+        self.links.push(Link::new(&self.name, kind));
+
+        Ok(())
+    }
+
+    /// Attaches the XDP program to `iface` in place of `old`, atomically.
+    ///
+    /// `old` must be attached to `iface` through netlink, eg. by
+    /// `attach_xdp()`, so that packets are processed by either program
+    /// while they are swapped. The link of `old` is then dropped without
+    /// detaching anything, and the program gets a new one with the same
+    /// flags. Fails with `Error::XdpAttached` if `old` isn't attached to
+    /// `iface`, or another program replaced it in the meantime.
+    ///
+    /// Kernels before 5.7 can't check the program being replaced, the one
+    /// attached to `iface` is then replaced whichever it is.
+    pub fn replace_xdp(&mut self, iface: &str, old: &mut Program) -> Result<()> {
+        let fd = self.loaded_fd()?;
+        let index = old
+            .links
+            .iter()
+            .position(|l| l.xdp_interface().map(|(i, _)| i) == Some(iface))
+            .ok_or(Error::XdpAttached)?;
+        let (_, flags) = old.links[index].xdp_interface().unwrap();
+        xdp::replace(iface, fd, old.loaded_fd()?, flags)?;
+        old.links.remove(index).forget();
+        let kind = LinkKind::Xdp {
+            iface: iface.to_string(),
+            flags,
+            netns: None,
+        };
+        self.links.push(Link::new(&self.name, kind));
+
+        Ok(())
+    }
+
+    /// Attaches the XDP program to all the interfaces whose name is
+    /// `matching`, through netlink.
+    ///
+    /// The program stays attached to the other interfaces if attaching to
+    /// some fail, see `xdp::XdpGroup::failures()`. The group keeps the links,
+    /// and can attach to and detach from interfaces later.
+    pub fn attach_xdp_all(
+        &self,
+        matching: fn(&str) -> bool,
+        flags: xdp::Flags,
+    ) -> Result<xdp::XdpGroup> {
+        xdp::XdpGroup::new(&self.name, self.loaded_fd()?, flags, matching)
+    }
+
+    /// Attaches the XDP program to `iface` through a BPF link.
+    ///
+    /// Unlike `attach_xdp()`, the program is detached when the link is
+    /// closed, which happens when the process exits unless the link has
+    /// been pinned with `Link::pin()`. Only the mode flags apply to links.
+    ///
+    /// BPF links for XDP require Linux 5.9. On older kernels the program is
+    /// attached through netlink like `attach_xdp()` does.
+    pub fn attach_xdp_link(&mut self, iface: &str, flags: xdp::Flags) -> Result<()> {
+        if !link::xdp_links_supported() {
+            return self.attach_xdp(iface, flags);
+        }
+        let link = sys::bpf::bpf_link_create(
+            self.loaded_fd()?,
+            xdp::ifindex(iface)? as i32,
+            sys::bpf::BPF_XDP,
+            flags.bits() & bpf_sys::XDP_FLAGS_MODES,
+        );
+        if link < 0 {
+            let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            return Err(xdp::attach_error(errno));
+        }
+        self.links.push(Link::new(&self.name, LinkKind::Bpf(link)));
+
+        Ok(())
+    }
+
+    /// Attaches the classifier program to `iface` for the packets going in
+    /// `direction`, as a filter with `priority` and `handle`.
+    ///
+    /// Creates the `clsact` qdisc if the interface doesn't have one yet. An
+    /// existing filter with the same priority and handle is replaced.
+    /// `priority` and `handle` must not be zero. See the `tc` module.
+    pub fn attach_tc(
+        &mut self,
+        iface: &str,
+        direction: tc::Direction,
+        priority: u16,
+        handle: u32,
+    ) -> Result<()> {
+        self.attach_tc_in(None, iface, direction, priority, handle)
+    }
+
+    /// Attaches the classifier program to `iface` in the network namespace
+    /// `netns`, like `attach_tc()` does.
+    pub fn attach_tc_netns(
+        &mut self,
+        netns: &NetNs,
+        iface: &str,
+        direction: tc::Direction,
+        priority: u16,
+        handle: u32,
+    ) -> Result<()> {
+        self.attach_tc_in(Some(netns), iface, direction, priority, handle)
+    }
+
+    fn attach_tc_in(
+        &mut self,
+        netns: Option<&NetNs>,
+        iface: &str,
+        direction: tc::Direction,
+        priority: u16,
+        handle: u32,
+    ) -> Result<()> {
+        let fd = self.loaded_fd()?;
+        let (name, program) = (iface.to_string(), self.name.clone());
+        netns::run_in(netns, move || {
+            tc::attach(&name, direction, priority, handle, fd, &program)
+        })?;
+        let kind = LinkKind::Tc {
+            iface: iface.to_string(),
+            direction,
+            priority,
+            handle,
+            netns: netns.cloned(),
+        };
+        self.links.push(Link::new(&self.name, kind));
+
+        Ok(())
+    }
+
+    /// Opens a raw socket bound to `iface` with
+    /// `socket_filter::open_raw_socket()` and attaches the socket filter
+    /// program to it.
+    ///
+    /// The socket is owned by the program and closed when it's detached.
+    pub fn attach_socketfilter(&mut self, iface: &str) -> Result<RawFd> {
+        self.attach_socketfilter_in(None, iface)
+    }
+
+    /// Opens a raw socket bound to `iface` in the network namespace `netns`
+    /// and attaches the socket filter program to it, like
+    /// `attach_socketfilter()` does.
+    ///
+    /// The socket stays in `netns`, and receives the packets of its
+    /// interfaces.
+    pub fn attach_socketfilter_netns(&mut self, netns: &NetNs, iface: &str) -> Result<RawFd> {
+        self.attach_socketfilter_in(Some(netns), iface)
+    }
+
+    fn attach_socketfilter_in(&mut self, netns: Option<&NetNs>, iface: &str) -> Result<RawFd> {
+        let prog_fd = self.loaded_fd()?;
+        let name = iface.to_string();
+        let sfd = netns::run_in(netns, move || socket_filter::open_raw_socket(&name))?;
+        if let Err(e) = socket_filter::attach(sfd, prog_fd) {
+            unsafe { libc::close(sfd) };
+            return Err(e);
+        }
+        self.links
+            .push(Link::new(&self.name, LinkKind::Socket(sfd)));
+
+        Ok(sfd)
+    }
+
+    /// Attaches the socket filter program to the socket `fd`.
+    ///
+    /// The socket stays owned by the caller. Detaching the program removes
+    /// the filter from the socket, so the socket must stay open until then.
+    pub fn attach_socket_filter_fd(&mut self, fd: RawFd) -> Result<()> {
+        socket_filter::attach(fd, self.loaded_fd()?)?;
+        self.links
+            .push(Link::new(&self.name, LinkKind::SocketFilter(fd)));
+
+        Ok(())
+    }
+
+    /// Runs the loaded program `repeat` times on the packet `input`, without
+    /// attaching it, and returns the result of the last run.
+    ///
+    /// Supports XDP, socket filter and classifier programs. The packet
+    /// starts with the Ethernet header, see `test_run::PacketBuilder`.
+    /// Requires Linux 4.12, and root.
+    pub fn test_run(&self, input: &[u8], repeat: u32) -> Result<TestRunResult> {
+        match self.kind {
+            ProgramKind::XDP | ProgramKind::SocketFilter | ProgramKind::Classifier => {}
+            kind => return Err(Error::TestRunUnsupported(kind)),
+        }
+        let fd = self.loaded_fd()?;
+
+        // programs can grow the packet, eg. with bpf_xdp_adjust_head()
+        let mut output = vec![0u8; input.len() + test_run::OUTPUT_HEADROOM];
+        loop {
+            let mut attr = sys::bpf::bpf_prog_test_run_attr {
+                prog_fd: fd as u32,
+                data_size_in: input.len() as u32,
+                data_size_out: output.len() as u32,
+                data_in: input.as_ptr() as u64,
+                data_out: output.as_mut_ptr() as u64,
+                repeat,
+                ..Default::default()
+            };
+            if unsafe { sys::bpf::bpf_prog_test_run(&mut attr) } < 0 {
+                let error = io::Error::last_os_error();
+                // the kernel reports the size of the whole output
+                let size = attr.data_size_out as usize;
+                if error.raw_os_error() == Some(libc::ENOSPC) && size > output.len() {
+                    output.resize(size, 0);
+                    continue;
+                }
+                return Err(Error::IO(error));
+            }
+
+            output.truncate(attr.data_size_out as usize);
+            return Ok(TestRunResult {
+                return_value: attr.retval,
+                output,
+                duration_ns: attr.duration,
+            });
+        }
+    }
+}
+
+impl ProgramInfo {
+    pub(crate) fn from_id(id: u32) -> Result<ProgramInfo> {
+        let fd = sys::bpf::bpf_prog_get_fd_by_id(id);
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        let info = ProgramInfo::from_fd(fd);
+        unsafe { libc::close(fd) };
+
+        info
+    }
+
+    fn from_fd(fd: RawFd) -> Result<ProgramInfo> {
+        let info: bpf_sys::bpf_prog_info = obj_info(fd)?;
+        let name = unsafe { CStr::from_ptr(info.name.as_ptr()) };
+
+        // the ids are only copied to a buffer passed in a second call
+        let mut map_ids = vec![0u32; info.nr_map_ids as usize];
+        if !map_ids.is_empty() {
+            let mut ids_info = bpf_sys::bpf_prog_info {
+                nr_map_ids: map_ids.len() as u32,
+                map_ids: map_ids.as_mut_ptr() as u64,
+                ..Default::default()
+            };
+            let mut info_len = mem::size_of::<bpf_sys::bpf_prog_info>() as u32;
+            let ret = unsafe {
+                bpf_sys::bpf_obj_get_info(fd, &mut ids_info as *mut _ as *mut _, &mut info_len)
+            };
+            if ret < 0 {
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
+            map_ids.truncate(ids_info.nr_map_ids as usize);
+        }
+
+        Ok(ProgramInfo {
+            id: info.id,
+            name: name.to_string_lossy().into_owned(),
+            kind: ProgramKind::from_prog_type(info.type_),
+            tag: info.tag,
+            load_time_ns: info.load_time,
+            created_by_uid: info.created_by_uid,
+            map_ids,
+        })
+    }
+}
+
+impl MapInfo {
+    pub(crate) fn from_id(id: u32) -> Result<MapInfo> {
+        let fd = sys::bpf::bpf_map_get_fd_by_id(id);
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        let info = MapInfo::from_fd(fd);
+        unsafe { libc::close(fd) };
+
+        info
+    }
+
+    fn from_fd(fd: RawFd) -> Result<MapInfo> {
+        let info: bpf_sys::bpf_map_info = obj_info(fd)?;
+        let name = unsafe { CStr::from_ptr(info.name.as_ptr()) };
+        Ok(MapInfo {
+            id: info.id,
+            name: name.to_string_lossy().into_owned(),
+            map_type: info.type_,
+            key_size: info.key_size,
+            value_size: info.value_size,
+            max_entries: info.max_entries,
+            map_flags: info.map_flags,
+        })
+    }
+}
+
+impl Module {
+    pub fn programs(&self) -> impl Iterator<Item = &Program> {
+        self.programs.iter()
+    }
+
+    /// Returns the program called `name`.
+    pub fn program(&self, name: &str) -> Option<&Program> {
+        self.programs.iter().find(|p| p.name == name)
+    }
+
+    /// Returns the map called `name`.
+    pub fn map(&self, name: &str) -> Option<&Map> {
+        self.maps.iter().find(|m| m.name == name)
+    }
+
+    /// Returns the links attaching the programs of the module.
+    pub fn links(&self) -> impl Iterator<Item = &Link> {
+        self.programs.iter().flat_map(|p| p.links.iter())
+    }
+
+    /// Detaches the program called `name` from all its attach points.
+    ///
+    /// Fails with `Error::Section` if there is no such program.
+    pub fn detach(&mut self, name: &str) -> Result<()> {
+        match self.programs.iter_mut().find(|p| p.name == name) {
+            Some(prog) => prog.detach(),
+            None => Err(Error::Section(name.to_string())),
+        }
+    }
+
+    /// Checks that the functions of the kprobes and the events of the
+    /// tracepoints exist in the running kernel, before attaching anything.
+    ///
+    /// The programs are expected to be attached to the targets they are
+    /// named after. Fails with `Error::InvalidTargets` listing the
+    /// `Error::TargetNotFound` errors of all the programs.
+    pub fn validate_targets(&self) -> Result<()> {
+        let targets = KernelTargets::load();
+        let errors: Vec<Error> = self
+            .programs
+            .iter()
+            .filter_map(|prog| {
+                let res = match prog.kind {
+                    ProgramKind::Kprobe | ProgramKind::Kretprobe => {
+                        targets.check_function(&prog.name)
+                    }
+                    ProgramKind::Tracepoint => {
+                        let mut parts = prog.name.splitn(2, '/');
+                        match (parts.next(), parts.next()) {
+                            (Some(category), Some(name)) => {
+                                targets.check_tracepoint(category, name)
+                            }
+                            _ => Err(Error::TargetNotFound(prog.name.clone(), Vec::new())),
+                        }
+                    }
+                    _ => Ok(()),
+                };
+                res.err()
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidTargets(errors))
+        }
+    }
+
+    /// Pins all the maps of the module in `dir`, each named after the map.
+    ///
+    /// `dir` is created if it doesn't exist, and must be on a BPF
+    /// filesystem. Use `Map::from_pinned()` to open the maps again, eg.
+    /// after restarting or from another process.
+    pub fn pin_maps<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        for map in self.maps.iter() {
+            map.pin(dir.join(&map.name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that all the maps in `names` have been initialized.
+    ///
+    /// Returns `Error::Uninitialized` listing the maps that are missing or
+    /// haven't been written to yet. Use this before attaching programs that
+    /// depend on configuration provided by userspace.
+    pub fn check_initialized(&self, names: &[&str]) -> Result<()> {
+        let uninitialized: Vec<String> = names
+            .iter()
+            .filter(|name| {
+                !self
+                    .maps
+                    .iter()
+                    .any(|m| m.name == **name && m.is_initialized())
+            })
+            .map(|name| name.to_string())
+            .collect();
+        if uninitialized.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Uninitialized(uninitialized))
+        }
+    }
+
+    /// Sets the value of the global `name`.
+    ///
+    /// Globals are declared in the programs with the `#[config]` attribute,
+    /// and are stored in single-entry array maps, which requires Linux 5.2 or
+    /// later. Older kernels fail to parse the module with
+    /// `Error::UnsupportedKernel`: the programs read globals through
+    /// pointers, so the loads can't be rewritten into constants. The
+    /// programs can only read them, so they must be set before the programs
+    /// are loaded: this returns `Error::GlobalAfterLoad` afterwards. Returns
+    /// `Error::Global` if there's no global `name`, or if its size doesn't
+    /// match `T`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use redbpf::Module;
+    ///
+    /// #[repr(C)]
+    /// struct Config {
+    ///     pid: u32,
+    /// }
+    ///
+    /// let code = std::fs::read("bpf.elf").unwrap();
+    /// let mut module = Module::parse(&code).unwrap();
+    /// module.set_global("CONFIG", &Config { pid: 42 }).unwrap();
+    /// for prog in module.programs.iter_mut() {
+    ///     prog.load(module.version, module.license.clone()).unwrap();
+    /// }
+    /// ```
+    pub fn set_global<T>(&mut self, name: &str, value: &T) -> Result<()> {
+        let data = unsafe {
+            std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        };
+        self.set_global_bytes(name, data)
+    }
+
+    pub(crate) fn set_global_bytes(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        if self.programs.iter().any(Program::is_loaded) {
+            return Err(Error::GlobalAfterLoad(name.to_string()));
+        }
+        let global = self
+            .globals
+            .iter()
+            .find(|g| g.name == name)
+            .ok_or_else(|| Error::Global(name.to_string()))?;
+        if data.len() != global.config.value_size as usize {
+            return Err(Error::Global(name.to_string()));
+        }
+
+        global.set_global_value(data)
+    }
+
+    /// Parses `bytes` and loads all the programs of the module, without
+    /// attaching them.
+    ///
+    /// This is a blocking call that doesn't need an async runtime. Use
+    /// `load::Loader::load_module()` to also initialize the maps and attach
+    /// the programs. `RLIMIT_MEMLOCK` is raised first if the process is
+    /// allowed to, see `sys::bump_memlock_rlimit()`.
+    pub fn load_bytes(bytes: &[u8]) -> Result<Module> {
+        // without CAP_SYS_RESOURCE the maps may still fit in the limit
+        let _ = sys::bump_memlock_rlimit();
+        let mut module = Module::parse(bytes)?;
+        for prog in module.programs.iter_mut() {
+            prog.load(module.version, module.license.clone())?;
+        }
+
+        Ok(module)
+    }
+
+    /// Runs each program through the verifier with `Program::verify()`,
+    /// and returns the results in the order of `programs`.
+    ///
+    /// Unlike `load_bytes()`, the programs that fail don't stop the others
+    /// from being verified, and none of them stays loaded.
+    pub fn verify(&mut self, log_level: u32) -> Vec<Result<String>> {
+        let version = self.version;
+        let license = self.license.clone();
+        self.programs
+            .iter_mut()
+            .map(|prog| prog.verify(version, license.clone(), log_level))
+            .collect()
+    }
+
+    /// Reads the ELF file at `path` and loads it with `load_bytes()`.
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Module> {
+        Module::load_bytes(&fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Module> {
+        Module::parse_with_config(bytes, &RSHashMap::new())
+    }
+
+    /// Parses the ELF file like `parse()`, creating the maps named in
+    /// `configs` with their overrides applied.
+    ///
+    /// Fails with `Error::UnknownMap` before creating any map if the module
+    /// has no map with one of the names.
+    pub fn parse_with_config(
+        bytes: &[u8],
+        configs: &RSHashMap<String, MapConfig>,
+    ) -> Result<Module> {
+        Module::from_spec_with_config(&ModuleSpec::parse(bytes)?, configs)
+    }
+
+    /// Creates the maps and the globals of `spec`, and relocates its
+    /// programs. The programs aren't loaded.
+    ///
+    /// The CO-RE relocations that weren't applied with
+    /// `ModuleSpec::relocate()` are applied with the BTF of the running
+    /// kernel.
+    pub fn from_spec(spec: &ModuleSpec) -> Result<Module> {
+        Module::from_spec_with_config(spec, &RSHashMap::new())
+    }
+
+    /// Like `from_spec()`, with the overrides of `configs` applied to the
+    /// maps named by its keys.
+    ///
+    /// Fails with `Error::UnknownMap` before creating any map if the module
+    /// has no map with one of the names.
+    pub fn from_spec_with_config(
+        spec: &ModuleSpec,
+        configs: &RSHashMap<String, MapConfig>,
+    ) -> Result<Module> {
+        Module::from_spec_reusing_maps(spec, configs, Vec::new())
+    }
+
+    /// Like `from_spec_with_config()`, using the maps of `reused` instead of
+    /// creating the maps named like them, eg. the maps pinned by an earlier
+    /// instance of the module. The programs then share their state with the
+    /// other programs using the maps.
+    ///
+    /// Fails with `Error::MapTypeMismatch` if one of the maps doesn't have
+    /// the type and the key and value sizes of its definition.
+    pub fn from_spec_reusing_maps(
+        spec: &ModuleSpec,
+        configs: &RSHashMap<String, MapConfig>,
+        mut reused: Vec<Map>,
+    ) -> Result<Module> {
+        if let Some(name) = configs.keys().find(|n| spec.map(n).is_none()) {
+            let mut map_names: Vec<String> = spec.maps.iter().map(|m| m.name.clone()).collect();
+            map_names.sort();
+            return Err(Error::UnknownMap(name.clone(), map_names));
+        }
+        let version = resolve_version(spec.version)?;
+
+        let mut programs: Vec<Program> = spec.programs.iter().map(Program::from_spec).collect();
+        if !spec.core_relocations.is_empty() {
+            let target = Btf::from_kernel().map_err(|e| {
+                Error::Btf(format!(
+                    "the kernel BTF is needed for CO-RE relocations: {}",
+                    e
+                ))
+            })?;
+            for reloc in spec.core_relocations.iter() {
+                let prog = &mut programs[reloc.program];
+                reloc.apply(&mut prog.code, &target, &prog.name)?;
+            }
+        }
+
+        // Maps are immediately bcc_create_map'd
+        let maps = spec
+            .maps
+            .iter()
+            .map(|m| match reused.iter().position(|r| r.name == m.name) {
+                Some(i) => {
+                    let map = reused.swap_remove(i);
+                    let expected = MapLayout {
+                        map_type: m.def.type_,
+                        key_size: m.def.key_size,
+                        value_size: m.def.value_size,
+                    };
+                    if map.layout() != expected {
+                        return Err(Error::MapTypeMismatch {
+                            map: m.name.clone(),
+                            expected,
+                            found: map.layout(),
+                        });
+                    }
+                    Ok(map)
+                }
+                None => Map::from_spec(m, configs.get(&m.name).cloned().unwrap_or_default()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let globals = spec
+            .globals
+            .iter()
+            .map(|g| {
+                let map_flags = if g.read_only { BPF_F_RDONLY_PROG } else { 0 };
+                Map::global_data(&g.name, &g.data, map_flags)
+                    .map_err(|e| global_data_error(e, get_kernel_internal_version()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // the programs load without BTF on kernels that don't support it
+        let mut btf_error = None;
+        let btf_fd = match spec.btf.as_ref().map(|btf| btf::load(btf)) {
+            Some(Ok(fd)) => Some(Arc::new(BtfFd(fd))),
+            Some(Err(Error::BtfLoad { ref log, .. })) if log.is_empty() => None,
+            Some(Err(e)) => {
+                btf_error = Some(e);
+                None
+            }
+            None => None,
+        };
+        if let Some(fd) = btf_fd {
+            for (prog, spec) in programs.iter_mut().zip(spec.programs.iter()) {
+                prog.btf = Some(ProgramBtf {
+                    fd: fd.clone(),
+                    func_info: spec.func_info.clone(),
+                    line_info: spec.line_info.clone(),
+                });
+            }
+        }
+
+        // Rewrite programs with relocation data
+        for reloc in spec.relocations.iter() {
+            let prog = &mut programs[reloc.program];
+            match reloc.target {
+                RelocationTarget::Map(map) => {
+                    prog.code[reloc.insn].set_src_reg(bpf_sys::BPF_PSEUDO_MAP_FD as u8);
+                    prog.code[reloc.insn].imm = maps[map].fd;
+                }
+                RelocationTarget::Global(global, offset) => {
+                    prog.code[reloc.insn].set_src_reg(BPF_PSEUDO_MAP_VALUE);
+                    prog.code[reloc.insn].imm = globals[global].fd;
+                    prog.code[reloc.insn + 1].imm = offset;
+                }
+            }
+        }
+
+        Ok(Module {
+            programs,
+            maps,
+            globals,
+            license: spec.license.clone(),
+            version,
+            btf_error,
+        })
+    }
+}
+
+impl Map {
+    pub fn load(name: &str, code: &[u8]) -> Result<Map> {
+        Map::load_with_config(name, code, MapConfig::default())
+    }
+
+    /// Creates the map defined by `code`, with the overrides in `map_config`
+    /// applied.
+    pub fn load_with_config(name: &str, code: &[u8], map_config: MapConfig) -> Result<Map> {
+        Map::from_spec(&MapSpec::from_elf(name, code)?, map_config)
+    }
+
+    fn from_spec(spec: &MapSpec, map_config: MapConfig) -> Result<Map> {
+        let mut config = spec.def;
+        map_config.apply(&mut config);
+        match spec.inner_def {
+            Some(inner) if is_map_of_maps(config.type_) => {
+                Map::create_map_of_maps(&spec.name, config, inner)
+            }
+            _ => Map::create(&spec.name, config),
+        }
+    }
+
+    // The kernel needs an inner map to check the maps stored in a map of
+    // maps against. A placeholder matching the inner definition is created
+    // for it, and closed once the outer map exists.
+    fn create_map_of_maps(name: &str, config: bpf_map_def, inner: bpf_map_def) -> Result<Map> {
+        let placeholder = Map::create(&format!("{}_inner", name), inner)?;
+        let fd = sys::bpf::bpf_create_map_in_map(
+            config.type_,
+            name,
+            config.key_size,
+            placeholder.fd,
+            config.max_entries,
+            config.map_flags,
+        );
+        let error = io::Error::last_os_error();
+        unsafe { libc::close(placeholder.fd) };
+        if fd < 0 {
+            return Err(map_creation_error(name, error));
+        }
+
+        Ok(Map {
+            name: name.to_string(),
+            kind: config.type_,
+            fd,
             config,
+            inner_config: Some(inner),
+            initialized: AtomicBool::new(false),
+        })
+    }
+
+    // Creates the read-only array map backing a global, initialized with
+    // the contents of its section
+    fn global_data(name: &str, data: &[u8], map_flags: u32) -> Result<Map> {
+        let config = bpf_map_def {
+            type_: bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY,
+            key_size: mem::size_of::<u32>() as u32,
+            value_size: data.len() as u32,
+            max_entries: 1,
+            map_flags,
+        };
+        let map = Map::create(name, config)?;
+        map.set_global_value(data)?;
+
+        Ok(map)
+    }
+
+    fn set_global_value(&self, data: &[u8]) -> Result<()> {
+        let mut key = 0u32;
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.fd,
+                &mut key as *mut _ as *mut _,
+                data.as_ptr() as *mut _,
+                bpf_sys::BPF_ANY.into(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    fn create(name: &str, config: bpf_map_def) -> Result<Map> {
+        let cname = CString::new(name.to_owned())?;
+        let fd = unsafe {
+            bpf_sys::bcc_create_map(
+                config.type_,
+                cname.as_ptr(),
+                config.key_size as i32,
+                config.value_size as i32,
+                config.max_entries as i32,
+                config.map_flags as i32,
+            )
+        };
+        if fd < 0 {
+            return Err(map_creation_error(name, io::Error::last_os_error()));
+        }
+
+        Ok(Map {
+            name: name.to_string(),
+            kind: config.type_,
+            fd,
+            config,
+            inner_config: None,
+            initialized: AtomicBool::new(false),
+        })
+    }
+
+    /// Opens the map pinned at `path`.
+    ///
+    /// The type and sizes of the map are read from the kernel, so the typed
+    /// wrappers validate against them as usual. Pinned maps are considered
+    /// initialized.
+    pub fn from_pinned<P: AsRef<Path>>(path: P) -> Result<Map> {
+        let path = path.as_ref();
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { bpf_sys::bpf_obj_get(cpath.as_ptr()) };
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+
+        let mut map = match Map::from_fd(fd) {
+            Ok(map) => map,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+        map.name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::new(),
+        };
+        Ok(map)
+    }
+
+    /// Wraps the map `fd`, eg. received from another process with
+    /// `fdpass::recv_map()`.
+    ///
+    /// The name, type and sizes of the map are read from the kernel, so
+    /// the typed wrappers validate against them as usual. The map is
+    /// considered initialized. Like the other maps, it doesn't close `fd`.
+    pub fn from_fd(fd: RawFd) -> Result<Map> {
+        let info: bpf_sys::bpf_map_info = obj_info(fd)?;
+        let name = unsafe { CStr::from_ptr(info.name.as_ptr()) };
+        Ok(Map {
+            name: name.to_string_lossy().into_owned(),
+            kind: info.type_,
+            fd,
+            config: bpf_map_def {
+                type_: info.type_,
+                key_size: info.key_size,
+                value_size: info.value_size,
+                max_entries: info.max_entries,
+                map_flags: info.map_flags,
+            },
+            inner_config: None,
+            initialized: AtomicBool::new(true),
+        })
+    }
+
+    /// Pins the map at `path`, so that it outlives the process and can be
+    /// opened with `Map::from_pinned()`.
+    ///
+    /// Fails with `Error::NotBpffs` if the directory containing `path`
+    /// isn't on a BPF filesystem, and with `Error::IO` if `path` exists.
+    pub fn pin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        pin_fd(self.fd, path.as_ref())
+    }
+
+    /// Removes the pin at `path`.
+    ///
+    /// The map is freed once it's no longer pinned, used by programs or
+    /// open in any process.
+    pub fn unpin<P: AsRef<Path>>(path: P) -> Result<()> {
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    // Returns a `Map` referring to the same kernel map. Maps don't own their
+    // file descriptor, so the alias can be used independently.
+    pub(crate) fn alias(&self) -> Map {
+        Map {
+            name: self.name.clone(),
+            kind: self.kind,
+            fd: self.fd,
+            config: self.config,
+            inner_config: self.inner_config,
+            initialized: AtomicBool::new(self.is_initialized()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the file descriptor of the map.
+    ///
+    /// The descriptor is shared by all the handles of the map, don't close
+    /// it.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Returns the id the kernel assigned to the map, as listed by
+    /// `bpftool map`.
+    pub fn id(&self) -> Result<u32> {
+        let info: bpf_sys::bpf_map_info = obj_info(self.fd)?;
+        Ok(info.id)
+    }
+
+    /// Returns the type of the map, one of the `bpf_map_type` constants.
+    pub fn map_type(&self) -> bpf_sys::bpf_map_type {
+        self.config.type_
+    }
+
+    /// Returns the type and the key and value sizes of the map.
+    ///
+    /// They are read from the kernel for maps opened with `from_fd()` and
+    /// `from_pinned()`, and match the definition in the ELF file for the
+    /// maps of modules.
+    pub fn layout(&self) -> MapLayout {
+        MapLayout {
+            map_type: self.config.type_,
+            key_size: self.config.key_size,
+            value_size: self.config.value_size,
+        }
+    }
+
+    pub fn key_size(&self) -> u32 {
+        self.config.key_size
+    }
+
+    pub fn value_size(&self) -> u32 {
+        self.config.value_size
+    }
+
+    pub fn max_entries(&self) -> u32 {
+        self.config.max_entries
+    }
+
+    /// Returns `true` if the map has been written to through `redbpf`, or
+    /// marked as initialized with `mark_initialized()`.
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::SeqCst)
+    }
+
+    /// Marks the map as initialized, eg. after writing to it through its
+    /// file descriptor.
+    pub fn mark_initialized(&self) {
+        self.initialized.store(true, Ordering::SeqCst)
+    }
+}
+
+impl AsRawFd for Map {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
+    /// Returns a `HashMap` reading and writing the map `base`.
+    ///
+    /// Fails with `Error::MapTypeMismatch` if the sizes of the keys and
+    /// values of `base` don't match `K` and `V`.
+    pub fn new<'a>(base: &'a Map) -> Result<HashMap<'a, K, V>> {
+        check_layout(base, base.kind, mem::size_of::<K>(), mem::size_of::<V>())?;
+
+        Ok(HashMap {
+            base,
+            _k: PhantomData,
+            _v: PhantomData,
+        })
+    }
+
+    pub fn set(&self, key: K, value: V) {
+        let _ = self.set_flags(key, value, UpdateFlags::Any);
+    }
+
+    /// Sets the `value` for `key`, depending on whether `key` already exists
+    /// as specified by `flags`.
+    pub fn set_flags(&self, mut key: K, mut value: V, flags: UpdateFlags) -> Result<()> {
+        let flags = match flags {
+            UpdateFlags::Any => bpf_sys::BPF_ANY,
+            UpdateFlags::NoExist => bpf_sys::BPF_NOEXIST,
+            UpdateFlags::Exist => bpf_sys::BPF_EXIST,
+        };
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.base.fd,
+                &mut key as *mut _ as *mut _,
+                &mut value as *mut _ as *mut _,
+                flags.into(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::from_map_errno(
+                io::Error::last_os_error().raw_os_error().unwrap_or(0),
+            ));
+        }
+
+        self.base.mark_initialized();
+        Ok(())
+    }
+
+    pub fn get(&self, mut key: K) -> Option<V> {
+        let mut value = MaybeUninit::zeroed();
+        if unsafe {
+            bpf_sys::bpf_lookup_elem(
+                self.base.fd,
+                &mut key as *mut _ as *mut _,
+                &mut value as *mut _ as *mut _,
+            )
+        } < 0
+        {
+            return None;
+        }
+        Some(unsafe { value.assume_init() })
+    }
+
+    /// Returns `true` if the map contains a value for `key`.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key.clone()).is_some()
+    }
+
+    /// Deletes the value for `key`.
+    ///
+    /// Fails with `Error::ElementNotFound` if the map doesn't contain `key`.
+    pub fn delete(&self, key: &K) -> Result<()> {
+        let mut key = key.clone();
+        if unsafe { bpf_sys::bpf_delete_elem(self.base.fd, &mut key as *mut _ as *mut _) } < 0 {
+            return Err(Error::from_map_errno(
+                io::Error::last_os_error().raw_os_error().unwrap_or(0),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the elements of the map.
+    ///
+    /// The keys are collected when iteration starts and each value is read
+    /// when its key is reached, skipping keys deleted in between. If probes
+    /// update the map concurrently the result is a best-effort snapshot:
+    /// every key is returned at most once, but keys inserted or deleted
+    /// while collecting them may be missed.
+    pub fn iter<'a>(&'a self) -> MapIter<'a, 'base, K, V> {
+        MapIter {
+            map: self,
+            keys: None,
+        }
+    }
+
+    /// Returns an iterator over the keys of the map.
+    ///
+    /// See `iter()` for how concurrent updates are handled.
+    pub fn keys(&self) -> impl Iterator<Item = K> {
+        self.collect_keys().into_iter()
+    }
+
+    /// Returns an iterator over the values of the map.
+    ///
+    /// See `iter()` for how concurrent updates are handled.
+    pub fn values<'a>(&'a self) -> impl Iterator<Item = V> + 'a {
+        self.iter().map(|(_, value)| value)
+    }
+
+    // When the key passed to `bpf_get_next_key` has been deleted, the
+    // kernel starts over from the first key. Keys seen before are skipped,
+    // and the walk is bounded so that it ends even if probes keep deleting
+    // the current key.
+    fn collect_keys(&self) -> Vec<K> {
+        let max_entries = self.base.config.max_entries as usize;
+        let mut keys = Vec::new();
+        let mut seen = RSHashSet::new();
+        let mut key = self.first_key();
+        let mut steps = 0;
+        while let Some(k) = key {
+            if keys.len() >= max_entries || steps >= 2 * max_entries {
+                break;
+            }
+            steps += 1;
+            let bytes =
+                unsafe { slice::from_raw_parts(&k as *const K as *const u8, mem::size_of::<K>()) };
+            if seen.insert(bytes.to_vec()) {
+                keys.push(k.clone());
+            }
+            key = self.next_key(k);
+        }
+        keys
+    }
+
+    fn first_key(&self) -> Option<K> {
+        let mut key = MaybeUninit::<K>::zeroed();
+        if unsafe {
+            bpf_sys::bpf_get_first_key(
+                self.base.fd,
+                &mut key as *mut _ as *mut _,
+                self.base.config.key_size as usize,
+            )
+        } < 0
+        {
+            return None;
+        }
+        Some(unsafe { key.assume_init() })
+    }
+
+    fn next_key(&self, mut key: K) -> Option<K> {
+        let mut next_key = MaybeUninit::<K>::zeroed();
+        if unsafe {
+            bpf_sys::bpf_get_next_key(
+                self.base.fd,
+                &mut key as *mut _ as *mut _,
+                &mut next_key as *mut _ as *mut _,
+            )
+        } < 0
+        {
+            return None;
+        }
+        Some(unsafe { next_key.assume_init() })
+    }
+
+    /// Returns an iterator over chunks of up to `chunk_size` elements.
+    ///
+    /// Each chunk is read with a single `BPF_MAP_LOOKUP_BATCH` call, which
+    /// is much faster than `iter()` for large maps. On kernels without
+    /// batch operations (before 5.6) the chunks are read element by element.
+    /// A failed call ends the iteration with its error.
+    pub fn lookup_batch<'a>(&'a self, chunk_size: u32) -> BatchIter<'a, 'base, K, V> {
+        BatchIter {
+            map: self,
+            chunk_size: cmp::max(chunk_size, 1),
+            token: None,
+            fallback: None,
+            done: false,
+        }
+    }
+
+    /// Removes all the elements from the map and returns them.
+    ///
+    /// Uses `BPF_MAP_LOOKUP_AND_DELETE_BATCH`, so every element is returned
+    /// exactly once even if probes keep updating the map. On kernels
+    /// without batch operations the elements are read and then deleted one
+    /// by one, and updates made in between are lost.
+    ///
+    /// Elements deleted by probes while draining aren't errors. On other
+    /// failures the error is returned, and the elements removed before it
+    /// are lost.
+    pub fn drain(&self) -> Result<Vec<(K, V)>> {
+        let mut items = Vec::new();
+        let mut token = None;
+        loop {
+            match self.batch(
+                sys::bpf::BPF_MAP_LOOKUP_AND_DELETE_BATCH,
+                &mut token,
+                self.base.config.max_entries,
+            ) {
+                Ok((chunk, more)) => {
+                    items.extend(chunk);
+                    if !more {
+                        return Ok(items);
+                    }
+                }
+                Err(e) if token.is_none() && batch_unsupported(&e) => break,
+                Err(e) => return Err(Error::IO(e)),
+            }
+        }
+
+        let items: Vec<(K, V)> = self.iter().collect();
+        for (key, _) in items.iter() {
+            match self.delete(key) {
+                Ok(()) | Err(Error::ElementNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(items)
+    }
+
+    /// Sets the values of multiple keys.
+    ///
+    /// Uses a single `BPF_MAP_UPDATE_BATCH` call when supported by the
+    /// kernel, and falls back to updating the elements one by one.
+    pub fn update_batch(&self, items: &[(K, V)]) -> Result<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let key_size = mem::size_of::<K>();
+        let value_size = mem::size_of::<V>();
+        let mut keys = vec![0u8; key_size * items.len()];
+        let mut values = vec![0u8; value_size * items.len()];
+        for (i, (key, value)) in items.iter().enumerate() {
+            unsafe {
+                ptr::write_unaligned(keys[i * key_size..].as_mut_ptr() as *mut K, key.clone());
+                ptr::write_unaligned(
+                    values[i * value_size..].as_mut_ptr() as *mut V,
+                    value.clone(),
+                );
+            }
+        }
+        let mut count = items.len() as u32;
+        let ret = unsafe {
+            sys::bpf::bpf_map_batch(
+                sys::bpf::BPF_MAP_UPDATE_BATCH,
+                self.base.fd,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                keys.as_mut_ptr() as *mut _,
+                values.as_mut_ptr() as *mut _,
+                &mut count,
+                bpf_sys::BPF_ANY.into(),
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if !batch_unsupported(&e) {
+                return Err(Error::from_map_errno(e.raw_os_error().unwrap_or(0)));
+            }
+            for (key, value) in items.iter() {
+                self.set_flags(key.clone(), value.clone(), UpdateFlags::Any)?;
+            }
+        }
+
+        self.base.mark_initialized();
+        Ok(())
+    }
+
+    // Runs a lookup batch command, continuing from `token` and storing the
+    // position to continue from after it. Returns the elements read and
+    // whether there are more to read.
+    fn batch(
+        &self,
+        cmd: libc::c_int,
+        token: &mut Option<Vec<u8>>,
+        mut chunk_size: u32,
+    ) -> io::Result<(Vec<(K, V)>, bool)> {
+        let key_size = mem::size_of::<K>();
+        let value_size = mem::size_of::<V>();
+        // hash maps use a u32 bucket index as token, other maps a key
+        let token_size = cmp::max(key_size, mem::size_of::<u32>());
+        loop {
+            let mut keys = vec![0u8; key_size * chunk_size as usize];
+            let mut values = vec![0u8; value_size * chunk_size as usize];
+            let mut out_batch = vec![0u8; token_size];
+            let in_batch = match token {
+                Some(token) => token.as_mut_ptr() as *mut _,
+                None => ptr::null_mut(),
+            };
+            let mut count = chunk_size;
+            let ret = unsafe {
+                sys::bpf::bpf_map_batch(
+                    cmd,
+                    self.base.fd,
+                    in_batch,
+                    out_batch.as_mut_ptr() as *mut _,
+                    keys.as_mut_ptr() as *mut _,
+                    values.as_mut_ptr() as *mut _,
+                    &mut count,
+                    0,
+                )
+            };
+            let more = if ret < 0 {
+                let e = io::Error::last_os_error();
+                match batch_step(&e) {
+                    BatchStep::Last => false,
+                    BatchStep::Grow => {
+                        chunk_size *= 2;
+                        continue;
+                    }
+                    BatchStep::Fail => return Err(e),
+                }
+            } else {
+                true
+            };
+            *token = Some(out_batch);
+
+            let items = (0..count as usize)
+                .map(|i| unsafe {
+                    (
+                        ptr::read_unaligned(keys[i * key_size..].as_ptr() as *const K),
+                        ptr::read_unaligned(values[i * value_size..].as_ptr() as *const V),
+                    )
+                })
+                .collect();
+            return Ok((items, more));
+        }
+    }
+}
+
+impl<'base, K: Clone, V: Clone> LruHashMap<'base, K, V> {
+    pub fn new<'a>(base: &'a Map) -> Result<LruHashMap<'a, K, V>> {
+        check_layout(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_LRU_HASH,
+            mem::size_of::<K>(),
+            mem::size_of::<V>(),
+        )?;
+
+        Ok(LruHashMap {
+            map: HashMap::new(base)?,
+        })
+    }
+
+    pub fn set(&self, key: K, value: V) {
+        self.map.set(key, value)
+    }
+
+    pub fn set_flags(&self, key: K, value: V, flags: UpdateFlags) -> Result<()> {
+        self.map.set_flags(key, value, flags)
+    }
+
+    pub fn get(&self, key: K) -> Option<V> {
+        self.map.get(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn delete(&self, key: &K) -> Result<()> {
+        self.map.delete(key)
+    }
+
+    pub fn iter<'a>(&'a self) -> MapIter<'a, '_, K, V> {
+        self.map.iter()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = K> {
+        self.map.keys()
+    }
+
+    pub fn values<'a>(&'a self) -> impl Iterator<Item = V> + 'a {
+        self.map.values()
+    }
+
+    pub fn lookup_batch<'a>(&'a self, chunk_size: u32) -> BatchIter<'a, 'base, K, V> {
+        self.map.lookup_batch(chunk_size)
+    }
+
+    pub fn drain(&self) -> Result<Vec<(K, V)>> {
+        self.map.drain()
+    }
+
+    pub fn update_batch(&self, items: &[(K, V)]) -> Result<()> {
+        self.map.update_batch(items)
+    }
+}
+
+impl<T> LpmKey<T> {
+    pub fn new(prefix_len: u32, data: T) -> Self {
+        LpmKey { prefix_len, data }
+    }
+}
+
+impl From<(Ipv4Addr, u32)> for LpmKey<[u8; 4]> {
+    fn from((addr, prefix_len): (Ipv4Addr, u32)) -> Self {
+        LpmKey::new(prefix_len, addr.octets())
+    }
+}
+
+impl From<(Ipv6Addr, u32)> for LpmKey<[u8; 16]> {
+    fn from((addr, prefix_len): (Ipv6Addr, u32)) -> Self {
+        LpmKey::new(prefix_len, addr.octets())
+    }
+}
+
+impl<'base, K: Clone, V: Clone> LpmTrie<'base, K, V> {
+    pub fn new<'a>(base: &'a Map) -> Result<LpmTrie<'a, K, V>> {
+        check_layout(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_LPM_TRIE,
+            mem::size_of::<LpmKey<K>>(),
+            mem::size_of::<V>(),
+        )?;
+
+        Ok(LpmTrie {
+            map: HashMap::new(base)?,
         })
     }
-}
 
-impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
-    pub fn new<'a>(base: &'a Map) -> Result<HashMap<'a, K, V>> {
-        if mem::size_of::<K>() != base.config.key_size as usize
-            || mem::size_of::<V>() != base.config.value_size as usize
+    pub fn insert(&self, key: LpmKey<K>, value: V) {
+        self.map.set(key, value)
+    }
+
+    /// Returns the value of the longest prefix matching `key`.
+    pub fn get(&self, key: LpmKey<K>) -> Option<V> {
+        self.map.get(key)
+    }
+
+    pub fn remove(&self, key: LpmKey<K>) {
+        let _ = self.map.delete(&key);
+    }
+
+    pub fn iter<'a>(&'a self) -> MapIter<'a, '_, LpmKey<K>, V> {
+        self.map.iter()
+    }
+}
+
+impl<'base, T: Clone> Array<'base, T> {
+    /// Returns an `Array` reading and writing the array map `base`.
+    ///
+    /// Fails with `Error::MapTypeMismatch` if `base` isn't an array, or if
+    /// the size of its values doesn't match `T`.
+    pub fn new<'a>(base: &'a Map) -> Result<Array<'a, T>> {
+        check_array(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY,
+            mem::size_of::<T>(),
+        )?;
+        Ok(Array {
+            base,
+            _t: PhantomData,
+        })
+    }
+
+    /// Returns the value at `index`, or `None` if `index` is out of bounds.
+    pub fn get(&self, mut index: u32) -> Option<T> {
+        let mut value = MaybeUninit::zeroed();
+        if unsafe {
+            bpf_sys::bpf_lookup_elem(
+                self.base.fd,
+                &mut index as *mut _ as *mut _,
+                &mut value as *mut _ as *mut _,
+            )
+        } < 0
+        {
+            return None;
+        }
+        Some(unsafe { value.assume_init() })
+    }
+
+    /// Sets the value at `index`.
+    ///
+    /// Fails with `Error::ElementNotFound` if `index` is out of bounds.
+    pub fn set(&self, mut index: u32, mut value: T) -> Result<()> {
+        if index >= self.len() {
+            return Err(Error::ElementNotFound);
+        }
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.base.fd,
+                &mut index as *mut _ as *mut _,
+                &mut value as *mut _ as *mut _,
+                bpf_sys::BPF_ANY.into(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::from_map_errno(
+                io::Error::last_os_error().raw_os_error().unwrap_or(0),
+            ));
+        }
+
+        self.base.mark_initialized();
+        Ok(())
+    }
+
+    /// Returns the number of elements of the array.
+    pub fn len(&self) -> u32 {
+        self.base.config.max_entries
+    }
+
+    /// Returns `true` if the array has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the values of the array, in index order.
+    pub fn iter<'a>(&'a self) -> ArrayIter<'a, 'base, T> {
+        ArrayIter {
+            array: self,
+            index: 0,
+        }
+    }
+}
+
+impl<'base, T: Clone> PerCpuArray<'base, T> {
+    /// Returns a `PerCpuArray` reading and writing the per-CPU array map
+    /// `base`.
+    ///
+    /// Fails with `Error::MapTypeMismatch` if `base` isn't a per-CPU array,
+    /// or if the size of its values doesn't match `T`. Sizes are compared
+    /// after rounding them up to 8 bytes, like the kernel does for the
+    /// values of each CPU.
+    pub fn new<'a>(base: &'a Map) -> Result<PerCpuArray<'a, T>> {
+        check_array(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY,
+            mem::size_of::<T>(),
+        )?;
+        Ok(PerCpuArray {
+            base,
+            _t: PhantomData,
+        })
+    }
+
+    /// Sets the values at `index`, one for each possible CPU, indexed by
+    /// CPU id.
+    ///
+    /// Fails with `Error::Map` if the number of values doesn't match the
+    /// number of possible CPUs, and with `Error::ElementNotFound` if `index`
+    /// is out of bounds.
+    pub fn set(&self, mut index: u32, values: &[T]) -> Result<()> {
+        if values.len() != cpus::get_possible()?.len() {
+            return Err(Error::Map);
+        }
+        if index >= self.len() {
+            return Err(Error::ElementNotFound);
+        }
+        let mut buf = per_cpu_bytes(values);
+        let ret = unsafe {
+            bpf_sys::bpf_update_elem(
+                self.base.fd,
+                &mut index as *mut _ as *mut _,
+                buf.as_mut_ptr() as *mut _,
+                bpf_sys::BPF_ANY.into(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::from_map_errno(
+                io::Error::last_os_error().raw_os_error().unwrap_or(0),
+            ));
+        }
+
+        self.base.mark_initialized();
+        Ok(())
+    }
+
+    /// Returns the number of elements of the array.
+    pub fn len(&self) -> u32 {
+        self.base.config.max_entries
+    }
+
+    /// Returns `true` if the array has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the per-CPU values of the array, in index
+    /// order.
+    pub fn iter<'a>(&'a self) -> PerCpuArrayIter<'a, 'base, T> {
+        PerCpuArrayIter {
+            array: self,
+            index: 0,
+        }
+    }
+
+    /// Returns the values at `index` for all the possible CPUs, indexed by
+    /// CPU id.
+    pub fn get(&self, mut index: u32) -> Option<Vec<T>> {
+        let cpus = cpus::get_possible().ok()?.len();
+        let value_size = per_cpu_value_size::<T>();
+        let mut values = vec![0u8; value_size * cpus];
+        if unsafe {
+            bpf_sys::bpf_lookup_elem(
+                self.base.fd,
+                &mut index as *mut _ as *mut _,
+                values.as_mut_ptr() as *mut _,
+            )
+        } < 0
         {
-            return Err(Error::Map);
+            return None;
         }
 
-        Ok(HashMap {
+        Some(per_cpu_values(&values))
+    }
+}
+
+impl<'base, T: Clone> Queue<'base, T> {
+    pub fn new<'a>(base: &'a Map) -> Result<Queue<'a, T>> {
+        check_key_less(base, bpf_sys::bpf_map_type_BPF_MAP_TYPE_QUEUE, mem::size_of::<T>())?;
+        Ok(Queue {
+            base,
+            _t: PhantomData,
+        })
+    }
+
+    /// Pushes `value` to the back of the queue.
+    ///
+    /// If the queue is full and `overwrite` is set, the element at the
+    /// front of the queue is dropped to make room for `value`.
+    pub fn push(&self, value: T, overwrite: bool) -> Result<()> {
+        push_elem(self.base, value, overwrite)
+    }
+
+    /// Removes and returns the element at the front of the queue.
+    pub fn pop(&self) -> Option<T> {
+        pop_elem(self.base)
+    }
+
+    /// Returns the element at the front of the queue without removing it.
+    pub fn peek(&self) -> Option<T> {
+        peek_elem(self.base)
+    }
+}
+
+impl<'base, T: Clone> Stack<'base, T> {
+    pub fn new<'a>(base: &'a Map) -> Result<Stack<'a, T>> {
+        check_key_less(base, bpf_sys::bpf_map_type_BPF_MAP_TYPE_STACK, mem::size_of::<T>())?;
+        Ok(Stack {
+            base,
+            _t: PhantomData,
+        })
+    }
+
+    /// Pushes `value` on top of the stack.
+    ///
+    /// If the stack is full and `overwrite` is set, the element at the
+    /// bottom of the stack is dropped to make room for `value`.
+    pub fn push(&self, value: T, overwrite: bool) -> Result<()> {
+        push_elem(self.base, value, overwrite)
+    }
+
+    /// Removes and returns the element on top of the stack.
+    pub fn pop(&self) -> Option<T> {
+        pop_elem(self.base)
+    }
+
+    /// Returns the element on top of the stack without removing it.
+    pub fn peek(&self) -> Option<T> {
+        peek_elem(self.base)
+    }
+}
+
+impl<'base> ArrayOfMaps<'base> {
+    pub fn new<'a>(base: &'a Map) -> Result<ArrayOfMaps<'a>> {
+        check_layout(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY_OF_MAPS,
+            mem::size_of::<u32>(),
+            mem::size_of::<u32>(),
+        )?;
+
+        Ok(ArrayOfMaps { base })
+    }
+
+    /// Creates a map that can be stored in the array.
+    pub fn create_inner(&self, name: &str) -> Result<Map> {
+        create_inner(self.base, name)
+    }
+
+    /// Stores `map` at `index`.
+    ///
+    /// The array holds a reference to `map`, so it stays alive until it's
+    /// replaced or removed, even when the process that created it exits.
+    pub fn set_inner(&self, index: u32, map: &Map) -> Result<()> {
+        set_inner(self.base, index, map)
+    }
+
+    /// Removes the map at `index`.
+    pub fn remove(&self, mut index: u32) -> Result<()> {
+        delete_elem(self.base, &mut index as *mut _ as *mut _)
+    }
+}
+
+impl<'base, K: Clone> HashOfMaps<'base, K> {
+    pub fn new<'a>(base: &'a Map) -> Result<HashOfMaps<'a, K>> {
+        check_layout(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH_OF_MAPS,
+            mem::size_of::<K>(),
+            mem::size_of::<u32>(),
+        )?;
+
+        Ok(HashOfMaps {
             base,
             _k: PhantomData,
-            _v: PhantomData,
         })
     }
 
-    pub fn set(&self, mut key: K, mut value: V) {
-        unsafe {
-            bpf_sys::bpf_update_elem(
-                self.base.fd,
-                &mut key as *mut _ as *mut _,
-                &mut value as *mut _ as *mut _,
-                0,
-            );
-        }
+    /// Creates a map that can be stored in the hash map.
+    pub fn create_inner(&self, name: &str) -> Result<Map> {
+        create_inner(self.base, name)
     }
 
-    pub fn get(&self, mut key: K) -> Option<V> {
-        let mut value = MaybeUninit::zeroed();
+    /// Stores `map` under `key`.
+    ///
+    /// The hash map holds a reference to `map`, so it stays alive until it's
+    /// replaced or removed, even when the process that created it exits.
+    pub fn set_inner(&self, key: K, map: &Map) -> Result<()> {
+        set_inner(self.base, key, map)
+    }
+
+    /// Removes the map stored under `key`.
+    pub fn remove(&self, key: &K) -> Result<()> {
+        let mut key = key.clone();
+        delete_elem(self.base, &mut key as *mut _ as *mut _)
+    }
+}
+
+impl<'base> StackTraceMap<'base> {
+    pub fn new<'a>(base: &'a Map) -> Result<StackTraceMap<'a>> {
+        // the values hold up to a configurable number of frames
+        let value_size = (base.config.value_size as usize + 7) & !7;
+        check_layout(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_STACK_TRACE,
+            mem::size_of::<u32>(),
+            value_size,
+        )?;
+
+        Ok(StackTraceMap { base })
+    }
+
+    /// Returns the instruction pointers of the stack `id`, innermost frame
+    /// first.
+    ///
+    /// Returns `None` if there's no stack with that id, eg. because it was
+    /// deleted.
+    pub fn get(&self, mut id: u32) -> Option<Vec<u64>> {
+        let mut ips = vec![0u64; self.base.config.value_size as usize / mem::size_of::<u64>()];
         if unsafe {
             bpf_sys::bpf_lookup_elem(
                 self.base.fd,
-                &mut key as *mut _ as *mut _,
-                &mut value as *mut _ as *mut _,
+                &mut id as *mut _ as *mut _,
+                ips.as_mut_ptr() as *mut _,
             )
         } < 0
         {
             return None;
         }
-        Some(unsafe { value.assume_init() })
+
+        let depth = ips.iter().position(|ip| *ip == 0).unwrap_or(ips.len());
+        ips.truncate(depth);
+        Some(ips)
     }
 
-    pub fn delete(&self, mut key: K) {
+    /// Deletes the stack `id`, so that it can be reused.
+    pub fn delete(&self, mut id: u32) {
         unsafe {
-            bpf_sys::bpf_delete_elem(self.base.fd, &mut key as *mut _ as *mut _);
+            bpf_sys::bpf_delete_elem(self.base.fd, &mut id as *mut _ as *mut _);
         }
     }
+}
 
-    pub fn iter<'a>(&'a self) -> MapIter<'a, '_, K, V> {
-        MapIter {
-            map: self,
-            key: None,
+impl<'base> PerfEventArray<'base> {
+    pub fn new<'a>(base: &'a Map) -> Result<PerfEventArray<'a>> {
+        check_layout(
+            base,
+            bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+            mem::size_of::<u32>(),
+            mem::size_of::<u32>(),
+        )?;
+
+        Ok(PerfEventArray {
+            base,
+            counters: Vec::new(),
+        })
+    }
+
+    /// Opens the counter described by `attr` on every online CPU, and
+    /// stores them in the array indexed by CPU id.
+    ///
+    /// Fails with `Error::CounterUnavailable` if the counter isn't
+    /// supported, which is common in virtual machines, and with
+    /// `Error::CpuEntries` if the array can't be indexed by every possible
+    /// CPU.
+    pub fn attach_perf_counters(&mut self, attr: PerfEventAttr) -> Result<()> {
+        check_cpu_entries(self.base)?;
+        for mut cpu in cpus::get_online()? {
+            let mut fd = unsafe { perf::open_perf_counter(&attr, cpu)? };
+            self.counters.push(fd);
+            let ret = unsafe {
+                bpf_sys::bpf_update_elem(
+                    self.base.fd,
+                    &mut cpu as *mut _ as *mut _,
+                    &mut fd as *mut _ as *mut _,
+                    bpf_sys::BPF_ANY.into(),
+                )
+            };
+            if ret < 0 {
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
         }
+
+        self.base.mark_initialized();
+        Ok(())
     }
 }
 
-pub struct MapIter<'a, 'b, K: Clone, V: Clone> {
+impl Drop for PerfEventArray<'_> {
+    fn drop(&mut self) {
+        for fd in self.counters.drain(..) {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+impl profile::StackTraces for StackTraceMap<'_> {
+    fn stack(&self, id: i32) -> Option<Vec<u64>> {
+        if id < 0 {
+            return None;
+        }
+        self.get(id as u32)
+    }
+}
+
+impl StackIdError {
+    /// Returns the error corresponding to a stack id returned by
+    /// `bpf_get_stackid()`, or `None` if the id is valid.
+    pub fn from_id(id: i64) -> Option<StackIdError> {
+        if id >= 0 {
+            return None;
+        }
+
+        Some(match -id as i32 {
+            libc::EFAULT => StackIdError::NotCollected,
+            libc::EEXIST => StackIdError::Collision,
+            libc::ENOMEM => StackIdError::Full,
+            e => StackIdError::Other(e),
+        })
+    }
+}
+
+fn is_map_of_maps(kind: u32) -> bool {
+    kind == bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY_OF_MAPS
+        || kind == bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH_OF_MAPS
+}
+
+fn create_inner(outer: &Map, name: &str) -> Result<Map> {
+    let config = outer.inner_config.ok_or(Error::Map)?;
+    Map::create(name, config)
+}
+
+// Maps of maps store the file descriptor of the inner map on update, and
+// return its id on lookup
+fn set_inner<K>(outer: &Map, mut key: K, map: &Map) -> Result<()> {
+    let mut fd = map.fd as u32;
+    let ret = unsafe {
+        bpf_sys::bpf_update_elem(
+            outer.fd,
+            &mut key as *mut _ as *mut _,
+            &mut fd as *mut _ as *mut _,
+            bpf_sys::BPF_ANY.into(),
+        )
+    };
+    if ret < 0 {
+        return Err(Error::from_map_errno(
+            io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        ));
+    }
+
+    outer.mark_initialized();
+    Ok(())
+}
+
+fn delete_elem(base: &Map, key: *mut libc::c_void) -> Result<()> {
+    if unsafe { bpf_sys::bpf_delete_elem(base.fd, key) } < 0 {
+        return Err(Error::from_map_errno(
+            io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_array(base: &Map, kind: u32, value_size: usize) -> Result<()> {
+    check_layout(base, kind, mem::size_of::<u32>(), value_size)
+}
+
+// Perf event arrays are indexed by CPU id, so they need an entry for every
+// possible CPU, including the ones going online later
+pub(crate) fn check_cpu_entries(base: &Map) -> Result<()> {
+    let needed = cpu_entries(&cpus::get_possible()?);
+    if base.max_entries() < needed {
+        return Err(Error::CpuEntries {
+            map: base.name.clone(),
+            entries: base.max_entries(),
+            needed,
+        });
+    }
+
+    Ok(())
+}
+
+fn cpu_entries(possible: &[cpus::CpuId]) -> u32 {
+    possible.iter().max().map_or(0, |cpu| *cpu as u32 + 1)
+}
+
+// Fails with `Error::MapTypeMismatch` if `base` doesn't have the type
+// `map_type`, or keys and values of the given sizes
+pub(crate) fn check_layout(
+    base: &Map,
+    map_type: bpf_sys::bpf_map_type,
+    key_size: usize,
+    value_size: usize,
+) -> Result<()> {
+    let expected = MapLayout {
+        map_type,
+        key_size: key_size as u32,
+        value_size: value_size as u32,
+    };
+    let found = base.layout();
+    let matches = if is_per_cpu(map_type) {
+        let round = |size: u32| (size + 7) & !7;
+        found.map_type == expected.map_type
+            && found.key_size == expected.key_size
+            && round(found.value_size) == round(expected.value_size)
+    } else {
+        found == expected
+    };
+    if !matches {
+        return Err(Error::MapTypeMismatch {
+            map: base.name.clone(),
+            expected,
+            found,
+        });
+    }
+
+    Ok(())
+}
+
+fn is_per_cpu(map_type: bpf_sys::bpf_map_type) -> bool {
+    map_type == bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY
+        || map_type == bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_HASH
+        || map_type == bpf_sys::bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH
+}
+
+// The kernel rounds up each value of per-CPU maps to 8 bytes
+fn per_cpu_value_size<T>() -> usize {
+    (mem::size_of::<T>() + 7) & !7
+}
+
+// Lays out the values of each CPU like the kernel, each one padded to 8
+// bytes
+fn per_cpu_bytes<T: Clone>(values: &[T]) -> Vec<u8> {
+    let value_size = per_cpu_value_size::<T>();
+    let mut buf = vec![0u8; value_size * values.len()];
+    for (value, chunk) in values.iter().zip(buf.chunks_exact_mut(value_size)) {
+        unsafe { ptr::write_unaligned(chunk.as_mut_ptr() as *mut T, value.clone()) };
+    }
+    buf
+}
+
+fn per_cpu_values<T>(buf: &[u8]) -> Vec<T> {
+    buf.chunks_exact(per_cpu_value_size::<T>())
+        .map(|value| unsafe { ptr::read_unaligned(value.as_ptr() as *const T) })
+        .collect()
+}
+
+// Queue and stack maps have a key size of 0, and the kernel requires a null
+// key for all the operations on them
+fn check_key_less(base: &Map, kind: u32, value_size: usize) -> Result<()> {
+    check_layout(base, kind, 0, value_size)
+}
+
+fn push_elem<T>(base: &Map, mut value: T, overwrite: bool) -> Result<()> {
+    let flags = if overwrite { bpf_sys::BPF_EXIST } else { 0 };
+    let ret = unsafe {
+        bpf_sys::bpf_update_elem(
+            base.fd,
+            ptr::null_mut(),
+            &mut value as *mut _ as *mut _,
+            flags.into(),
+        )
+    };
+    if ret < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    base.mark_initialized();
+    Ok(())
+}
+
+fn pop_elem<T>(base: &Map) -> Option<T> {
+    let mut value = MaybeUninit::zeroed();
+    if unsafe {
+        sys::bpf::bpf_lookup_and_delete_elem(
+            base.fd,
+            ptr::null_mut(),
+            &mut value as *mut _ as *mut _,
+        )
+    } < 0
+    {
+        return None;
+    }
+    Some(unsafe { value.assume_init() })
+}
+
+fn peek_elem<T>(base: &Map) -> Option<T> {
+    let mut value = MaybeUninit::zeroed();
+    if unsafe {
+        bpf_sys::bpf_lookup_elem(base.fd, ptr::null_mut(), &mut value as *mut _ as *mut _)
+    } < 0
+    {
+        return None;
+    }
+    Some(unsafe { value.assume_init() })
+}
+
+/// Iterator over the values of an `Array`.
+pub struct ArrayIter<'a, 'b, T: Clone> {
+    array: &'a Array<'b, T>,
+    index: u32,
+}
+
+impl<T: Clone> Iterator for ArrayIter<'_, '_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.index >= self.array.len() {
+            return None;
+        }
+        let value = self.array.get(self.index);
+        self.index += 1;
+        value
+    }
+}
+
+/// Iterator over the per-CPU values of a `PerCpuArray`.
+pub struct PerCpuArrayIter<'a, 'b, T: Clone> {
+    array: &'a PerCpuArray<'b, T>,
+    index: u32,
+}
+
+impl<T: Clone> Iterator for PerCpuArrayIter<'_, '_, T> {
+    type Item = Vec<T>;
+
+    fn next(&mut self) -> Option<Vec<T>> {
+        if self.index >= self.array.len() {
+            return None;
+        }
+        let values = self.array.get(self.index);
+        self.index += 1;
+        values
+    }
+}
+
+/// Iterator over chunks of the elements of a `HashMap`.
+pub struct BatchIter<'a, 'b, K: Clone, V: Clone> {
     map: &'a HashMap<'b, K, V>,
-    key: Option<K>,
+    chunk_size: u32,
+    token: Option<Vec<u8>>,
+    fallback: Option<MapIter<'a, 'b, K, V>>,
+    done: bool,
 }
 
-impl<K: Clone, V: Clone> Iterator for MapIter<'_, '_, K, V> {
-    type Item = (K, V);
+impl<K: Clone, V: Clone> Iterator for BatchIter<'_, '_, K, V> {
+    type Item = Result<Vec<(K, V)>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let key = self.key.take();
-        self.key = match key {
-            Some(mut key) => {
-                let mut next_key = MaybeUninit::<K>::zeroed();
-                let ret = unsafe {
-                    bpf_sys::bpf_get_next_key(
-                        self.map.base.fd,
-                        &mut key as *mut _ as *mut _,
-                        &mut next_key as *mut _ as *mut _,
-                    )
-                };
-                if ret < 0 {
-                    None
-                } else {
-                    Some(unsafe { next_key.assume_init() })
+        if self.done {
+            return None;
+        }
+        if let Some(iter) = self.fallback.as_mut() {
+            let chunk: Vec<_> = iter.take(self.chunk_size as usize).collect();
+            self.done = chunk.len() < self.chunk_size as usize;
+            return if chunk.is_empty() {
+                None
+            } else {
+                Some(Ok(chunk))
+            };
+        }
+
+        match self.map.batch(
+            sys::bpf::BPF_MAP_LOOKUP_BATCH,
+            &mut self.token,
+            self.chunk_size,
+        ) {
+            Ok((chunk, more)) => {
+                self.done = !more;
+                if chunk.is_empty() {
+                    return self.next();
                 }
+                Some(Ok(chunk))
             }
-            None => {
-                let mut key = MaybeUninit::<K>::zeroed();
-                if unsafe {
-                    bpf_sys::bpf_get_first_key(
-                        self.map.base.fd,
-                        &mut key as *mut _ as *mut _,
-                        self.map.base.config.key_size as usize,
-                    )
-                } < 0
-                {
-                    None
-                } else {
-                    Some(unsafe { key.assume_init() })
-                }
+            Err(e) if self.token.is_none() && batch_unsupported(&e) => {
+                self.fallback = Some(self.map.iter());
+                self.next()
             }
-        };
+            Err(e) => {
+                self.done = true;
+                Some(Err(Error::IO(e)))
+            }
+        }
+    }
+}
 
-        if self.key.is_none() {
-            return None;
+// How a batch call failing with `e` is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BatchStep {
+    // the chunk holds the last elements of the map
+    Last,
+    // a hash bucket holds more elements than fit in a chunk
+    Grow,
+    Fail,
+}
+
+fn batch_step(e: &io::Error) -> BatchStep {
+    match e.raw_os_error() {
+        Some(libc::ENOENT) => BatchStep::Last,
+        Some(libc::ENOSPC) => BatchStep::Grow,
+        _ => BatchStep::Fail,
+    }
+}
+
+// Kernels before 5.6 don't know the batch commands, and some map types
+// don't implement them
+fn batch_unsupported(e: &io::Error) -> bool {
+    const ENOTSUPP: i32 = 524;
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) | Some(ENOTSUPP)
+    )
+}
+
+pub struct MapIter<'a, 'b, K: Clone, V: Clone> {
+    map: &'a HashMap<'b, K, V>,
+    keys: Option<vec::IntoIter<K>>,
+}
+
+impl<K: Clone, V: Clone> Iterator for MapIter<'_, '_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let map = self.map;
+        let keys = self
+            .keys
+            .get_or_insert_with(|| map.collect_keys().into_iter());
+        for key in keys {
+            if let Some(value) = map.get(key.clone()) {
+                return Some((key, value));
+            }
         }
+        None
+    }
+}
 
-        let key = self.key.clone().unwrap();
-        Some((key.clone(), self.map.get(key).unwrap()))
+fn pin_fd(fd: RawFd, path: &Path) -> Result<()> {
+    bpffs::check_parent(path)?;
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { bpf_sys::bpf_obj_pin(fd, cpath.as_ptr()) } < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
     }
+
+    Ok(())
 }
 
-#[inline]
-fn add_rel(
-    rels: &mut Vec<Rel>,
-    shndx: usize,
-    shdr: &SectionHeader,
-    shdr_relocs: &[(usize, RelocSection<'_>)],
-) {
-    // if unwrap blows up, something's really bad
-    let section_rels = &shdr_relocs.iter().find(|(idx, _)| idx == &shndx).unwrap().1;
-    rels.extend(section_rels.iter().map(|rel| Rel {
-        shndx,
-        target: shdr.sh_info as usize,
-        sym: rel.r_sym,
-        offset: rel.r_offset,
-    }));
+// EPERM usually means the memlock limit is too low on kernels older than
+// 5.11, which charge maps to it
+fn map_creation_error(name: &str, error: io::Error) -> Error {
+    let memlock_limit = match error.raw_os_error() {
+        Some(libc::EPERM) => sys::memlock_rlimit().ok().and_then(|limit| limit),
+        _ => None,
+    };
+
+    Error::MapCreation {
+        name: name.to_string(),
+        error,
+        memlock_limit,
+    }
+}
+
+// Kernels before 5.2 don't support global data. They don't know the flag
+// of read-only globals, so creating their map fails with EINVAL.
+fn global_data_error(error: Error, version: Option<u32>) -> Error {
+    let needed = kernel_version_code(5, 2, 0);
+    match (&error, version) {
+        (Error::MapCreation { error: e, .. }, Some(found))
+            if e.raw_os_error() == Some(libc::EINVAL) && found < needed =>
+        {
+            Error::UnsupportedKernel { needed, found }
+        }
+        _ => error,
+    }
+}
+
+// Reads the `bpf_prog_info` or `bpf_map_info` of `fd`
+fn obj_info<T: Default>(fd: RawFd) -> Result<T> {
+    let mut info = T::default();
+    let mut info_len = mem::size_of::<T>() as u32;
+    if unsafe { bpf_sys::bpf_obj_get_info(fd, &mut info as *mut _ as *mut _, &mut info_len) } < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    Ok(info)
 }
 
 #[inline]
-fn get_version(bytes: &[u8]) -> u32 {
-    let version = zero::read::<u32>(bytes);
+fn resolve_version(version: u32) -> Result<u32> {
     match version {
-        0xFFFF_FFFE => get_kernel_internal_version().unwrap(),
-        _ => *version,
+        // kernels before 5.0 reject kprobes whose version doesn't match the
+        // running kernel exactly
+        0xFFFF_FFFE => get_kernel_internal_version().ok_or_else(|| {
+            Error::KernelRelease("can't determine the running kernel version".to_string())
+        }),
+        _ => Ok(version),
     }
 }
 
-#[inline]
-fn data<'d>(bytes: &'d [u8], shdr: &SectionHeader) -> &'d [u8] {
-    let offset = shdr.sh_offset as usize;
-    let end = (shdr.sh_offset + shdr.sh_size) as usize;
+mod test {
+    #[test]
+    fn test() {
+        use crate::{
+            batch_step, batch_unsupported, check_layout, cpu_entries, BatchStep, Error, Map,
+            MapLayout,
+        };
+        use bpf_sys::bpf_map_def;
+        use std::io;
+        use std::sync::atomic::AtomicBool;
+
+        let map = |type_, key_size, value_size| Map {
+            name: "counts".to_string(),
+            kind: type_,
+            fd: -1,
+            config: bpf_map_def {
+                type_,
+                key_size,
+                value_size,
+                max_entries: 1,
+                map_flags: 0,
+            },
+            inner_config: None,
+            initialized: AtomicBool::new(false),
+        };
+        let hash = bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH;
+        let per_cpu = bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY;
+
+        assert!(check_layout(&map(hash, 4, 8), hash, 4, 8).is_ok());
+        match check_layout(&map(hash, 4, 8), hash, 4, 16) {
+            Err(Error::MapTypeMismatch {
+                map,
+                expected,
+                found,
+            }) => {
+                assert_eq!(map, "counts");
+                assert_eq!(expected.value_size, 16);
+                assert_eq!(
+                    found,
+                    MapLayout {
+                        map_type: hash,
+                        key_size: 4,
+                        value_size: 8
+                    }
+                );
+                assert_eq!(
+                    found.to_string(),
+                    "HASH map with 4 byte keys and 8 byte values"
+                );
+            }
+            _ => panic!("unexpected result"),
+        }
+        assert!(check_layout(&map(per_cpu, 4, 8), hash, 4, 8).is_err());
+
+        // per-CPU values are padded to 8 bytes
+        assert!(check_layout(&map(per_cpu, 4, 12), per_cpu, 4, 12).is_ok());
+        assert!(check_layout(&map(per_cpu, 4, 12), per_cpu, 4, 16).is_ok());
+        assert!(check_layout(&map(per_cpu, 4, 12), per_cpu, 4, 24).is_err());
+
+        // perf event arrays are indexed by CPU id, possible CPUs can have
+        // holes
+        assert_eq!(cpu_entries(&[0, 1, 2, 3]), 4);
+        assert_eq!(cpu_entries(&[0, 1, 4, 5]), 6);
+        assert_eq!(cpu_entries(&[]), 0);
+
+        let errno = io::Error::from_raw_os_error;
+        assert_eq!(batch_step(&errno(libc::ENOENT)), BatchStep::Last);
+        assert_eq!(batch_step(&errno(libc::ENOSPC)), BatchStep::Grow);
+        assert_eq!(batch_step(&errno(libc::EFAULT)), BatchStep::Fail);
+        assert_eq!(batch_step(&errno(libc::EPERM)), BatchStep::Fail);
+        assert!(batch_unsupported(&errno(libc::EINVAL)));
+        assert!(batch_unsupported(&errno(524)));
+        assert!(!batch_unsupported(&errno(libc::EPERM)));
+        assert!(!batch_unsupported(&errno(libc::ENOENT)));
+    }
+
+    #[test]
+    fn test_arrays() {
+        use crate::{
+            cpus, per_cpu_bytes, per_cpu_value_size, per_cpu_values, Array, Error, Map, PerCpuArray,
+        };
+        use bpf_sys::bpf_map_def;
+        use std::sync::atomic::AtomicBool;
+
+        // never created, the bounds are checked before the syscalls
+        let map = |type_, value_size| Map {
+            name: "array".to_string(),
+            kind: type_,
+            fd: -1,
+            config: bpf_map_def {
+                type_,
+                key_size: 4,
+                value_size,
+                max_entries: 4,
+                map_flags: 0,
+            },
+            inner_config: None,
+            initialized: AtomicBool::new(false),
+        };
+        let array_map = map(bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY, 8);
+        let array = Array::<u64>::new(&array_map).unwrap();
+        assert_eq!(array.len(), 4);
+        assert!(matches!(array.set(4, 1), Err(Error::ElementNotFound)));
+        assert!(matches!(
+            array.set(u32::MAX, 1),
+            Err(Error::ElementNotFound)
+        ));
+        assert!(Array::<u32>::new(&array_map).is_err());
+
+        let per_cpu_map = map(bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY, 4);
+        let per_cpu = PerCpuArray::<u32>::new(&per_cpu_map).unwrap();
+        let cpus = cpus::get_possible().unwrap().len();
+        assert!(matches!(
+            per_cpu.set(0, &vec![1; cpus + 1]),
+            Err(Error::Map)
+        ));
+        assert!(matches!(
+            per_cpu.set(4, &vec![1; cpus]),
+            Err(Error::ElementNotFound)
+        ));
+        assert!(Array::<u32>::new(&per_cpu_map).is_err());
+
+        // the value of each possible CPU is padded to 8 bytes
+        assert_eq!(per_cpu_value_size::<u32>(), 8);
+        assert_eq!(per_cpu_value_size::<u64>(), 8);
+        assert_eq!(per_cpu_value_size::<[u8; 12]>(), 16);
+        let values: Vec<u32> = (0..cpus as u32).map(|cpu| 0x0102_0304 + cpu).collect();
+        let buf = per_cpu_bytes(&values);
+        assert_eq!(buf.len(), 8 * cpus);
+        for (cpu, chunk) in buf.chunks(8).enumerate() {
+            let value = (0x0102_0304 + cpu as u32).to_ne_bytes();
+            assert_eq!(&chunk[..4], &value);
+            assert_eq!(&chunk[4..], &[0; 4]);
+        }
+        assert_eq!(per_cpu_values::<u32>(&buf), values);
+
+        #[derive(Clone, Debug, PartialEq)]
+        #[repr(C)]
+        struct Counts {
+            packets: u64,
+            drops: u32,
+        }
+        let values = vec![
+            Counts {
+                packets: 1,
+                drops: 2,
+            },
+            Counts {
+                packets: 3,
+                drops: 4,
+            },
+        ];
+        let buf = per_cpu_bytes(&values);
+        assert_eq!(buf.len(), 32);
+        assert_eq!(&buf[16..24], &3u64.to_ne_bytes());
+        assert_eq!(per_cpu_values::<Counts>(&buf), values);
+    }
+
+    #[test]
+    fn test_global_data() {
+        use crate::{global_data_error, map_creation_error, Error};
+        use std::io;
 
-    &bytes[offset..end]
+        let einval = || map_creation_error("CONFIG", io::Error::from_raw_os_error(libc::EINVAL));
+        match global_data_error(einval(), Some(0x04_13_00)) {
+            Error::UnsupportedKernel { needed, found } => {
+                assert_eq!((needed, found), (0x05_02_00, 0x04_13_00))
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+        // newer kernels reject the map for other reasons
+        assert!(matches!(
+            global_data_error(einval(), Some(0x05_02_00)),
+            Error::MapCreation { .. }
+        ));
+        // the version can't be read
+        assert!(matches!(
+            global_data_error(einval(), None),
+            Error::MapCreation { .. }
+        ));
+        let eperm = map_creation_error("CONFIG", io::Error::from_raw_os_error(libc::EPERM));
+        assert!(matches!(
+            global_data_error(eperm, Some(0x04_13_00)),
+            Error::MapCreation { .. }
+        ));
+    }
+
+    // Needs root. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_batch() {
+        use crate::{HashMap, Map};
+        use bpf_sys::bpf_map_def;
+
+        let config = bpf_map_def {
+            type_: bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 1024,
+            map_flags: 0,
+        };
+        let map = Map::create("batch_test", config).unwrap();
+        let map = HashMap::<u32, u64>::new(&map).unwrap();
+        let items: Vec<(u32, u64)> = (0..1000).map(|k| (k, u64::from(k) * 2)).collect();
+        map.update_batch(&items).unwrap();
+
+        // chunks smaller than the map
+        let mut read: Vec<(u32, u64)> = map
+            .lookup_batch(7)
+            .flat_map(|chunk| {
+                let chunk = chunk.unwrap();
+                assert!(!chunk.is_empty());
+                chunk
+            })
+            .collect();
+        read.sort();
+        assert_eq!(read, items);
+
+        let mut drained = map.drain().unwrap();
+        drained.sort();
+        assert_eq!(drained, items);
+        assert_eq!(map.iter().count(), 0);
+        assert!(map.drain().unwrap().is_empty());
+        assert_eq!(map.lookup_batch(7).count(), 0);
+    }
+
+    // Needs root. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_array_maps() {
+        use crate::{cpus, Array, Error, Map, PerCpuArray};
+        use bpf_sys::bpf_map_def;
+
+        let config = |type_, value_size, max_entries| bpf_map_def {
+            type_,
+            key_size: 4,
+            value_size,
+            max_entries,
+            map_flags: 0,
+        };
+        let map = Map::create(
+            "array_test",
+            config(bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY, 8, 4),
+        )
+        .unwrap();
+        let array = Array::<u64>::new(&map).unwrap();
+        assert_eq!(array.iter().collect::<Vec<_>>(), vec![0; 4]);
+        for i in 0..4 {
+            array.set(i, u64::from(i) * 10).unwrap();
+        }
+        assert_eq!(array.get(3), Some(30));
+        assert_eq!(array.get(4), None);
+        assert!(matches!(array.set(4, 40), Err(Error::ElementNotFound)));
+        assert_eq!(array.iter().collect::<Vec<_>>(), vec![0, 10, 20, 30]);
+
+        let map = Map::create(
+            "per_cpu_test",
+            config(bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY, 4, 2),
+        )
+        .unwrap();
+        let per_cpu = PerCpuArray::<u32>::new(&map).unwrap();
+        let possible = cpus::get_possible().unwrap();
+        let values: Vec<u32> = possible.iter().map(|cpu| *cpu as u32 + 1).collect();
+        per_cpu.set(1, &values).unwrap();
+        assert_eq!(per_cpu.get(0), Some(vec![0; possible.len()]));
+        assert_eq!(per_cpu.get(1), Some(values.clone()));
+        assert_eq!(per_cpu.get(2), None);
+        assert_eq!(
+            per_cpu.iter().collect::<Vec<_>>(),
+            vec![vec![0; possible.len()], values]
+        );
+    }
+
+    // Iterates while another thread keeps inserting and deleting keys, like
+    // a busy probe would. Needs root. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_iter_churn() {
+        use crate::{HashMap, Map};
+        use bpf_sys::bpf_map_def;
+        use std::collections::HashSet;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        const MAX_ENTRIES: u32 = 256;
+        let config = bpf_map_def {
+            type_: bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH,
+            key_size: 4,
+            value_size: 8,
+            max_entries: MAX_ENTRIES,
+            map_flags: 0,
+        };
+        let map = Arc::new(Map::create("churn_test", config).unwrap());
+        let stable: Vec<u32> = (0..64).collect();
+        {
+            let map = HashMap::<u32, u64>::new(&map).unwrap();
+            for key in stable.iter() {
+                map.set(*key, u64::from(*key) * 2);
+            }
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let churn = {
+            let map = map.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let map = HashMap::<u32, u64>::new(&map).unwrap();
+                let mut rounds = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    for key in 1000..1150 {
+                        map.set(key, u64::from(key) * 2);
+                    }
+                    for key in 1000..1150 {
+                        let _ = map.delete(&key);
+                    }
+                    rounds += 1;
+                }
+                rounds
+            })
+        };
+
+        let hash_map = HashMap::<u32, u64>::new(&map).unwrap();
+        for _ in 0..2000 {
+            let mut seen = HashSet::new();
+            for (key, value) in hash_map.iter() {
+                assert!(seen.insert(key), "key {} returned twice", key);
+                assert_eq!(value, u64::from(key) * 2);
+            }
+            assert!(seen.len() <= MAX_ENTRIES as usize);
+
+            let keys: Vec<u32> = hash_map.keys().collect();
+            let unique: HashSet<u32> = keys.iter().cloned().collect();
+            assert_eq!(keys.len(), unique.len());
+            assert!(stable.iter().all(|key| hash_map.contains_key(key)));
+        }
+        stop.store(true, Ordering::Relaxed);
+        assert!(churn.join().unwrap() > 0);
+
+        // once the churn stops the snapshot is exact
+        let mut keys: Vec<u32> = hash_map.keys().collect();
+        keys.sort();
+        assert_eq!(keys, stable);
+        let mut values: Vec<u64> = hash_map.values().collect();
+        values.sort();
+        assert_eq!(
+            values,
+            stable.iter().map(|k| u64::from(*k) * 2).collect::<Vec<_>>()
+        );
+    }
+
+    // A child process loads an XDP program, attaches it to lo through a BPF
+    // link, pins both and exits, then the program is opened again from the
+    // pin. Needs root, a mounted bpffs and Linux 5.9 or later for XDP links.
+    // Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_pin() {
+        use crate::{bpffs, xdp, Program, ProgramKind};
+        use std::path::Path;
+        use std::process::{self, Command};
+        use std::time::Duration;
+        use std::{env, fs, thread};
+
+        let dir = Path::new(bpffs::BPFFS_PATH).join(format!("redbpf-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let status = Command::new(env::current_exe().unwrap())
+            .args(&["--ignored", "--exact", "test::test_pin_child"])
+            .env("REDBPF_PIN_DIR", &dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let attached = xdp::query_xdp("lo").unwrap().expect("not attached");
+        let mut prog = Program::from_pinned(dir.join("prog")).unwrap();
+        assert_eq!(prog.kind(), ProgramKind::XDP);
+        assert_eq!(prog.id().unwrap(), attached.id);
+
+        // the pinned link is the last reference to the attachment
+        fs::remove_file(dir.join("link")).unwrap();
+        let mut detached = false;
+        for _ in 0..50 {
+            detached = xdp::query_xdp("lo").unwrap().is_none();
+            if detached {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(detached);
+
+        // the program opened from the pin can be attached again
+        prog.attach_xdp_link("lo", xdp::Flags::default()).unwrap();
+        let attached = xdp::query_xdp("lo").unwrap().expect("not attached");
+        assert_eq!(prog.id().unwrap(), attached.id);
+        prog.detach().unwrap();
+
+        fs::remove_file(dir.join("prog")).unwrap();
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    // Run by test_pin in a child process
+    #[test]
+    #[ignore]
+    fn test_pin_child() {
+        use crate::{xdp, Program};
+        use std::env;
+        use std::path::PathBuf;
+        use std::slice;
+
+        let dir = match env::var_os("REDBPF_PIN_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => return,
+        };
+        // r0 = XDP_PASS; exit. Little endian, and aligned like instructions.
+        let insns: [u64; 2] = [0x0000_0002_0000_00b7, 0x95];
+        let code = unsafe { slice::from_raw_parts(insns.as_ptr() as *const u8, 16) };
+        let mut prog = Program::new("xdp", "pin_test", code).unwrap();
+        prog.load(0, "GPL".to_string()).unwrap();
+        prog.attach_xdp_link("lo", xdp::Flags::default()).unwrap();
+        prog.pin(dir.join("prog")).unwrap();
+        prog.pin_link(dir.join("link")).unwrap();
+    }
 }
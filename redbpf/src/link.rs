@@ -0,0 +1,314 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+
+use crate::kprobe::KprobeEvent;
+use crate::netns::{self, NetNs};
+use crate::sys::bpf::{bpf_link_create, BPF_PERF_EVENT};
+use crate::sys::perf::{PERF_EVENT_IOC_ENABLE, PERF_EVENT_IOC_SET_BPF};
+use crate::uname::get_kernel_internal_version;
+use crate::{pin_fd, socket_filter, tc, usdt, xdp, Error, Result};
+
+// BPF links of XDP programs, since Linux 5.9
+const XDP_LINKS_SINCE: u32 = 0x05_09_00;
+// BPF links of perf events, ie. kprobes and tracepoints, since Linux 5.15
+const PERF_LINKS_SINCE: u32 = 0x05_0f_00;
+
+lazy_static! {
+    // BPF_LINK_CREATE fails on the invalid program with EBADF, or with EINVAL
+    // on kernels before 5.7 that don't know the command
+    static ref LINK_CREATE: bool = link_create_supported(
+        match bpf_link_create(-1, -1, 0, 0) {
+            ret if ret >= 0 => {
+                unsafe { libc::close(ret) };
+                0
+            }
+            _ => io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        }
+    );
+    static ref KERNEL_VERSION: Option<u32> = get_kernel_internal_version();
+}
+
+/// An attachment of a program.
+///
+/// Links are created by the `attach_*()` methods of `Program` and kept by
+/// the program, see `Program::links()`. Depending on the attach point and
+/// the kernel, a link is either a BPF link or uses the legacy mechanism for
+/// the attach point: a perf event for kprobes, uprobes and tracepoints, netlink for
+/// XDP and classifiers, and the socket for socket filters.
+///
+/// Kprobes and tracepoints are attached to their perf event through a BPF
+/// link since Linux 5.15, and XDP programs attached with
+/// `Program::attach_xdp_link()` since Linux 5.9. Which one is used is
+/// decided once per process, from the kernel version and whether the kernel
+/// knows `BPF_LINK_CREATE`.
+///
+/// # Drop behavior
+///
+/// Dropping a link detaches the program, ignoring errors. Use `detach()` to
+/// handle them, and `forget()` to keep the program attached instead. Links
+/// whose file descriptor is closed when the process exits detach the program
+/// then, unless they are BPF links pinned with `pin()`. XDP programs and
+/// classifiers attached through netlink stay attached until they are
+/// detached explicitly.
+#[derive(Debug)]
+pub struct Link {
+    program: String,
+    kind: LinkKind,
+    attached: bool,
+}
+
+#[derive(Debug)]
+pub(crate) enum LinkKind {
+    Bpf(RawFd),
+    Perf {
+        fd: RawFd,
+        // the BPF link attaching the program to the event, if the kernel
+        // supports it
+        link: Option<RawFd>,
+        // the probe event to remove on detach
+        probe: Option<ProbeEvent>,
+    },
+    Xdp {
+        iface: String,
+        flags: xdp::Flags,
+        // the namespace of the interface, if not the current one
+        netns: Option<NetNs>,
+    },
+    Tc {
+        iface: String,
+        direction: tc::Direction,
+        priority: u16,
+        handle: u32,
+        netns: Option<NetNs>,
+    },
+    // a socket opened by redbpf
+    Socket(RawFd),
+    // a socket owned by the caller
+    SocketFilter(RawFd),
+    // the semaphore of a USDT probe, set in the memory of the traced process
+    UsdtSemaphore(usdt::Semaphore),
+}
+
+#[derive(Debug)]
+pub(crate) enum ProbeEvent {
+    Kprobe(KprobeEvent),
+    Uprobe(CString),
+}
+
+impl Link {
+    pub(crate) fn new(program: &str, kind: LinkKind) -> Link {
+        Link {
+            program: program.to_string(),
+            kind,
+            attached: true,
+        }
+    }
+
+    /// Returns the name of the attached program.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Returns `true` if the link is a BPF link, which can be pinned.
+    pub fn is_bpf_link(&self) -> bool {
+        matches!(
+            self.kind,
+            LinkKind::Bpf(_) | LinkKind::Perf { link: Some(_), .. }
+        )
+    }
+
+    /// Returns the file descriptor of the link, the perf event or the
+    /// socket, or `None` for programs attached through netlink.
+    pub fn fd(&self) -> Option<RawFd> {
+        match self.kind {
+            LinkKind::Bpf(fd)
+            | LinkKind::Perf { fd, .. }
+            | LinkKind::Socket(fd)
+            | LinkKind::SocketFilter(fd) => Some(fd),
+            LinkKind::Xdp { .. } | LinkKind::Tc { .. } | LinkKind::UsdtSemaphore(_) => None,
+        }
+    }
+
+    // The interface and flags of XDP programs attached through netlink in the
+    // current namespace
+    pub(crate) fn xdp_interface(&self) -> Option<(&str, xdp::Flags)> {
+        match &self.kind {
+            LinkKind::Xdp {
+                iface,
+                flags,
+                netns: None,
+            } => Some((iface, *flags)),
+            _ => None,
+        }
+    }
+
+    /// Detaches the program.
+    pub fn detach(mut self) -> Result<()> {
+        self.attached = false;
+        self.detach_kind()
+    }
+
+    /// Pins the link at `path`, so that the program stays attached after the
+    /// process exits. Remove the pin with `Map::unpin()` to detach it.
+    ///
+    /// Only BPF links can be pinned, fails with `Error::NotBpfLink`
+    /// otherwise.
+    pub fn pin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        match self.kind {
+            LinkKind::Bpf(fd) | LinkKind::Perf { link: Some(fd), .. } => pin_fd(fd, path.as_ref()),
+            _ => Err(Error::NotBpfLink(self.program.clone())),
+        }
+    }
+
+    /// Drops the link without detaching the program.
+    ///
+    /// The file descriptor of the link stays open until the process exits.
+    pub fn forget(mut self) {
+        self.attached = false;
+    }
+
+    fn detach_kind(&self) -> Result<()> {
+        match &self.kind {
+            LinkKind::Bpf(fd) | LinkKind::Socket(fd) => close(*fd),
+            LinkKind::Perf { fd, link, probe } => {
+                if let Some(link) = link {
+                    close(*link)?;
+                }
+                if unsafe { bpf_sys::bpf_close_perf_event_fd(*fd) } < 0 {
+                    return Err(Error::IO(io::Error::last_os_error()));
+                }
+                match probe {
+                    Some(ProbeEvent::Kprobe(event)) => event.remove().map_err(Error::IO),
+                    Some(ProbeEvent::Uprobe(ev_name)) => {
+                        if unsafe { bpf_sys::bpf_detach_uprobe(ev_name.as_ptr()) } < 0 {
+                            return Err(Error::IO(io::Error::last_os_error()));
+                        }
+                        Ok(())
+                    }
+                    None => Ok(()),
+                }
+            }
+            LinkKind::Xdp {
+                iface,
+                flags,
+                netns,
+            } => {
+                let (iface, flags) = (iface.clone(), *flags);
+                netns::run_in(netns.as_ref(), move || xdp::detach_xdp(&iface, flags))
+            }
+            LinkKind::Tc {
+                iface,
+                direction,
+                priority,
+                handle,
+                netns,
+            } => {
+                let (iface, direction, priority, handle) =
+                    (iface.clone(), *direction, *priority, *handle);
+                netns::run_in(netns.as_ref(), move || {
+                    tc::detach_tc(&iface, direction, priority, handle)
+                })
+            }
+            LinkKind::SocketFilter(fd) => socket_filter::detach_socket_filter(*fd),
+            LinkKind::UsdtSemaphore(semaphore) => semaphore.decrement(),
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        if self.attached {
+            let _ = self.detach_kind();
+        }
+    }
+}
+
+fn close(fd: RawFd) -> Result<()> {
+    if unsafe { libc::close(fd) } < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+// Returns `true` if the kernel supports BPF links of XDP programs
+pub(crate) fn xdp_links_supported() -> bool {
+    links_supported(*LINK_CREATE, *KERNEL_VERSION, XDP_LINKS_SINCE)
+}
+
+// Returns `true` if the kernel supports BPF links of perf events
+pub(crate) fn perf_links_supported() -> bool {
+    links_supported(*LINK_CREATE, *KERNEL_VERSION, PERF_LINKS_SINCE)
+}
+
+// The kernel version is only trusted when it knows BPF_LINK_CREATE, and
+// links aren't used when the version is unknown
+fn links_supported(link_create: bool, version: Option<u32>, since: u32) -> bool {
+    link_create && version.map(|v| v >= since).unwrap_or(false)
+}
+
+fn link_create_supported(errno: i32) -> bool {
+    errno != libc::EINVAL
+}
+
+// Attaches the program `prog_fd` to the perf event `pfd` and enables it.
+// Returns the BPF link attaching it if the kernel supports them, the
+// program is attached with an ioctl otherwise.
+pub(crate) fn attach_perf_event(pfd: RawFd, prog_fd: RawFd) -> io::Result<Option<RawFd>> {
+    let link = if perf_links_supported() {
+        let link = bpf_link_create(prog_fd, pfd, BPF_PERF_EVENT, 0);
+        if link < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Some(link)
+    } else {
+        if unsafe { libc::ioctl(pfd, PERF_EVENT_IOC_SET_BPF, prog_fd) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        None
+    };
+    if unsafe { libc::ioctl(pfd, PERF_EVENT_IOC_ENABLE, 0) } != 0 {
+        let e = io::Error::last_os_error();
+        if let Some(link) = link {
+            unsafe { libc::close(link) };
+        }
+        return Err(e);
+    }
+
+    Ok(link)
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::link::{link_create_supported, links_supported};
+        use crate::link::{PERF_LINKS_SINCE, XDP_LINKS_SINCE};
+
+        // the invalid program is rejected by kernels knowing the command
+        assert!(link_create_supported(libc::EBADF));
+        assert!(!link_create_supported(libc::EINVAL));
+
+        // 5.4 falls back to netlink and ioctls
+        assert!(!links_supported(true, Some(0x05_04_00), XDP_LINKS_SINCE));
+        assert!(!links_supported(true, Some(0x05_04_00), PERF_LINKS_SINCE));
+        // 5.10 has XDP links but not perf links
+        assert!(links_supported(true, Some(0x05_0a_00), XDP_LINKS_SINCE));
+        assert!(!links_supported(true, Some(0x05_0a_00), PERF_LINKS_SINCE));
+        assert!(links_supported(true, Some(0x05_0f_00), PERF_LINKS_SINCE));
+        // kernels without BPF_LINK_CREATE, eg. with a backported version
+        // string, and unknown versions use the legacy mechanisms
+        assert!(!links_supported(false, Some(0x06_01_00), XDP_LINKS_SINCE));
+        assert!(!links_supported(true, None, XDP_LINKS_SINCE));
+    }
+}
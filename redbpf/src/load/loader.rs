@@ -5,18 +5,45 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use bpf_sys;
-use futures::channel::mpsc;
+#[cfg(feature = "load")]
+use futures::channel::{mpsc, oneshot};
+#[cfg(feature = "load")]
+use futures::future::{AbortHandle, Either, Shared};
+#[cfg(feature = "load")]
 use futures::prelude::*;
-use std::ffi::CString;
+#[cfg(feature = "load")]
+use log::warn;
+use std::collections::HashMap as RSHashMap;
+use std::fmt;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::mem;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "load")]
+use std::pin::Pin;
+#[cfg(feature = "load")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "load")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "load")]
+use std::task::{Context, Poll};
+#[cfg(feature = "load")]
+use std::thread;
+use std::time::Duration;
 
-use crate::cpus;
+#[cfg(feature = "load")]
+use crate::cpus::{self, CpuEvent, CpuId};
+#[cfg(feature = "load")]
 use crate::load::map_io::PerfMessageStream;
+use crate::load::poller::PerfPoller;
+#[cfg(feature = "load")]
+use crate::load::poller::{lost_count, DecodeError, MapEvent, MapEvents};
+use crate::retry::AttachRetry;
+use crate::spec::ModuleSpec;
 use crate::ProgramKind::*;
-use crate::{xdp, Error, Module, PerfMap};
+#[cfg(feature = "load")]
+use crate::{check_cpu_entries, PerfMap, Pod};
+use crate::{sys, tc, xdp, Error, HashMap, Map, MapConfig, Module, PerfBufferConfig, Program};
 
 #[derive(Debug)]
 pub enum LoaderError {
@@ -25,11 +52,122 @@ pub enum LoaderError {
     LoadError(String, Error),
     XdpError(String, Error),
     KprobeError(String, Error),
+    TracepointError(String, Error),
+    /// Attaching a uprobe, classifier or socket filter failed
+    AttachError(String, Error),
+    /// The attach parameters of the program don't match its kind
+    InvalidAttachParams(String),
+    InitError(Error),
+    /// Loading the ELF file at the path failed
+    InFile(PathBuf, Box<LoaderError>),
 }
 
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LoaderError::*;
+        match self {
+            FileError(e) => write!(f, "failed to read the ELF file: {}", e),
+            ParseError(e) => write!(f, "{}", e),
+            LoadError(p, e) => write!(f, "failed to load {}: {}", p, e),
+            XdpError(p, e) | KprobeError(p, e) | TracepointError(p, e) | AttachError(p, e) => {
+                write!(f, "failed to attach {}: {}", p, e)
+            }
+            InvalidAttachParams(p) => write!(f, "invalid attach parameters for {}", p),
+            InitError(e) => write!(f, "failed to initialize the maps: {}", e),
+            InFile(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use LoaderError::*;
+        match self {
+            FileError(e) => Some(e),
+            ParseError(e) | InitError(e) => Some(e),
+            LoadError(_, e) | XdpError(_, e) | KprobeError(_, e) | TracepointError(_, e) => Some(e),
+            AttachError(_, e) => Some(e),
+            InvalidAttachParams(_) => None,
+            InFile(_, e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// Where to attach a program, see `Loader::attach_params()`.
+#[derive(Debug, Clone)]
+pub enum AttachParams {
+    Xdp {
+        interface: String,
+        flags: xdp::Flags,
+    },
+    /// Attaches kprobes and kretprobes to `function` instead of the function
+    /// they are named after.
+    Kprobe {
+        function: String,
+    },
+    /// Attaches uprobes and uretprobes, see `Program::attach_uprobe()`.
+    Uprobe {
+        path: String,
+        symbol: String,
+        pid: Option<libc::pid_t>,
+    },
+    Tracepoint {
+        category: String,
+        name: String,
+    },
+    Classifier {
+        interface: String,
+        direction: tc::Direction,
+        priority: u16,
+        handle: u32,
+    },
+    SocketFilter {
+        interface: String,
+    },
+}
+
+/// Name of the map signaling that the configuration maps are initialized.
+///
+/// If a module contains a map with this name, the loader sets the
+/// `READY_KEY` element to 1 once the maps passed to
+/// `Loader::require_initialized()` have been written, before attaching any
+/// program.
+pub const READY_MAP: &str = "__ready";
+
+/// Key of the element set in `READY_MAP`.
+pub const READY_KEY: u32 = 0;
+
+// How often the list of online CPUs is checked for changes
+pub(crate) const CPU_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type InitFn = Box<dyn Fn(&Module) -> Result<(), Error> + Send + Sync>;
+
 /// High level API to load bpf programs.
 pub struct Loader {
     xdp: XdpConfig,
+    retry: AttachRetry,
+    required: Vec<String>,
+    init: Option<InitFn>,
+    globals: Vec<(String, Vec<u8>)>,
+    programs: Option<Vec<String>>,
+    skip_attach: bool,
+    attach_params: RSHashMap<String, AttachParams>,
+    strict: bool,
+    perf_buffers: PerfBufferConfig,
+    map_perf_buffers: RSHashMap<String, PerfBufferConfig>,
+    map_configs: RSHashMap<String, MapConfig>,
+    pinned_maps: Option<PathBuf>,
+    log_level: u32,
+    bump_memlock: bool,
+    channel_capacity: Option<usize>,
+}
+
+/// The module returned by `load_module()`.
+pub struct LoadedModule {
+    pub module: Module,
+    /// The errors of the programs that failed to attach. Always empty if
+    /// the loader is strict.
+    pub attach_errors: Vec<LoaderError>,
 }
 
 impl Loader {
@@ -37,104 +175,919 @@ impl Loader {
     pub fn new() -> Self {
         Loader {
             xdp: XdpConfig::default(),
+            retry: AttachRetry::default(),
+            required: Vec::new(),
+            init: None,
+            globals: Vec::new(),
+            programs: None,
+            skip_attach: false,
+            attach_params: RSHashMap::new(),
+            strict: false,
+            perf_buffers: PerfBufferConfig::default(),
+            map_perf_buffers: RSHashMap::new(),
+            map_configs: RSHashMap::new(),
+            pinned_maps: None,
+            log_level: 0,
+            bump_memlock: true,
+            channel_capacity: None,
         }
     }
 
+    /// Only loads and attaches the programs called `names`.
+    ///
+    /// The other programs are removed from the module. Loading fails with
+    /// `LoaderError::LoadError` if the module has no program with one of the
+    /// names.
+    pub fn programs(&mut self, names: &[&str]) -> &mut Self {
+        self.programs = Some(names.iter().map(|n| n.to_string()).collect());
+        self
+    }
+
+    /// Loads the programs without attaching them.
+    pub fn skip_attach(&mut self) -> &mut Self {
+        self.skip_attach = true;
+        self
+    }
+
+    /// Sets where the program called `name` is attached.
+    ///
+    /// Without parameters, XDP programs are attached to the interface set
+    /// with `xdp()`, kprobes to the function and tracepoints to the event
+    /// they are named after. Uprobes, classifiers and socket filters
+    /// are only attached when they have parameters.
+    pub fn attach_params(&mut self, name: &str, params: AttachParams) -> &mut Self {
+        self.attach_params.insert(name.to_string(), params);
+        self
+    }
+
+    /// Fails loading when a program can't be attached.
+    ///
+    /// By default the programs that fail to attach are reported in
+    /// `attach_errors`, and the others stay attached.
+    pub fn strict(&mut self) -> &mut Self {
+        self.strict = true;
+        self
+    }
+
+    /// Sets the log level of the verifier, 0 to 2.
+    ///
+    /// The verifier log of rejected programs is always returned, see
+    /// `Error::verifier_log()`. Above 0, the log of the programs that load
+    /// is kept too and can be read with `Program::verifier_log()`.
+    pub fn verifier_log_level(&mut self, level: u32) -> &mut Self {
+        self.log_level = level;
+        self
+    }
+
+    /// Sets whether `RLIMIT_MEMLOCK` is raised before the maps are created,
+    /// see `sys::bump_memlock_rlimit()`. Enabled by default.
+    ///
+    /// Failing to raise the limit isn't an error, the maps may fit in the
+    /// current one. Map creation failures caused by the limit are reported
+    /// in `Error::MapCreation`.
+    pub fn bump_memlock(&mut self, bump: bool) -> &mut Self {
+        self.bump_memlock = bump;
+        self
+    }
+
     /// Sets the network interface and flags for XDP programs.
     pub fn xdp(&mut self, interface: Option<String>, flags: xdp::Flags) -> &mut Self {
         self.xdp = XdpConfig { interface, flags };
         self
     }
 
-    /// Loads the programs included in `data`.
+    /// Sets the retry policy used when attaching programs.
+    pub fn attach_retry(&mut self, retry: AttachRetry) -> &mut Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the maps that must be initialized before the programs are
+    /// attached.
     ///
-    /// This will parse `data` with `Module::parse()` and load all the programs
-    /// present in the module.
-    pub async fn load(&self, data: &[u8]) -> Result<Loaded, LoaderError> {
-        let mut module = Module::parse(&data).map_err(|e| LoaderError::ParseError(e))?;
+    /// The maps can be populated from the function set with `init()`, which
+    /// runs after the programs are loaded but before they're attached.
+    /// Loading fails with `Error::Uninitialized` naming the maps that haven't
+    /// been written to.
+    pub fn require_initialized(&mut self, maps: &[&str]) -> &mut Self {
+        self.required = maps.iter().map(|m| m.to_string()).collect();
+        self
+    }
+
+    /// Sets a function that initializes the maps before the programs are
+    /// attached.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use redbpf::load::Loader;
+    /// use redbpf::{Error, HashMap};
+    /// # async {
+    /// let loaded = Loader::new()
+    ///     .require_initialized(&["config"])
+    ///     .init(|module| {
+    ///         let map = module.maps.iter().find(|m| m.name == "config").ok_or(Error::Map)?;
+    ///         HashMap::<u32, u64>::new(map)?.set(0, 1000);
+    ///         Ok(())
+    ///     })
+    ///     .load_file(&Path::new("probe.elf"))
+    ///     .await
+    ///     .unwrap();
+    /// # };
+    /// ```
+    pub fn init<F>(&mut self, init: F) -> &mut Self
+    where
+        F: Fn(&Module) -> Result<(), Error> + Send + Sync + 'static,
+    {
+        self.init = Some(Box::new(init));
+        self
+    }
+
+    /// Sets the value of the global `name` before the programs are loaded.
+    ///
+    /// See `Module::set_global()`.
+    pub fn global<T>(&mut self, name: &str, value: &T) -> &mut Self {
+        let data = unsafe {
+            std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        };
+        self.globals.push((name.to_string(), data.to_vec()));
+        self
+    }
+
+    /// Overrides the definition of the map called `name`, eg. to size it
+    /// for the machine:
+    ///
+    /// ```no_run
+    /// use redbpf::load::Loader;
+    /// use redbpf::MapConfig;
+    ///
+    /// let config = MapConfig {
+    ///     max_entries: Some(4_000_000),
+    ///     ..Default::default()
+    /// };
+    /// let loaded = Loader::new()
+    ///     .map_config("flows", config)
+    ///     .load_module(&std::fs::read("probe.elf").unwrap())
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Loading fails with `LoaderError::ParseError(Error::UnknownMap(..))`
+    /// if the module has no such map.
+    pub fn map_config(&mut self, name: &str, config: MapConfig) -> &mut Self {
+        self.map_configs.insert(name.to_string(), config);
+        self
+    }
+
+    /// Uses the maps pinned in `dir` under their name, eg. by an earlier run
+    /// of the programs, instead of creating them. The maps not pinned in
+    /// `dir` are created, see `Module::from_spec_reusing_maps()`.
+    pub fn reuse_pinned_maps<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.pinned_maps = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the configuration of the perf buffers opened for the perf event
+    /// arrays of the module.
+    ///
+    /// Defaults to 16 pages per CPU, waking up the reader on every sample.
+    pub fn perf_buffers(&mut self, config: PerfBufferConfig) -> &mut Self {
+        self.perf_buffers = config;
+        self
+    }
+
+    /// Sets the configuration of the perf buffers of the map called `name`,
+    /// overriding the one set with `perf_buffers()`.
+    pub fn map_perf_buffers(&mut self, name: &str, config: PerfBufferConfig) -> &mut Self {
+        self.map_perf_buffers.insert(name.to_string(), config);
+        self
+    }
+
+    /// Bounds the number of batches of events queued in `Loaded::events`
+    /// and in the streams returned by `Loaded::raw_events()` to about
+    /// `capacity`.
+    ///
+    /// By default the queues are unbounded, and grow as long as the
+    /// consumer is slower than the programs. When a bounded queue is full,
+    /// the perf buffers aren't read until the consumer catches up, so the
+    /// kernel drops the new samples instead and reports them as
+    /// `MapEvent::Lost`.
+    pub fn event_channel_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.channel_capacity = Some(capacity);
+        self
+    }
+
+    /// Opens a `PerfPoller` for the perf event arrays of `module`, with the
+    /// perf buffers configured with `perf_buffers()` and
+    /// `map_perf_buffers()`.
+    pub fn perf_poller(&self, module: &Module) -> Result<PerfPoller, Error> {
+        PerfPoller::with_config(module, |name| self.perf_buffer_config(name))
+    }
+
+    fn perf_buffer_config(&self, map: &str) -> PerfBufferConfig {
+        self.map_perf_buffers
+            .get(map)
+            .cloned()
+            .unwrap_or(self.perf_buffers)
+    }
+
+    /// Loads the programs included in `data`, initializes the maps and
+    /// attaches the programs.
+    ///
+    /// This is the blocking part of `load()`, which doesn't need an async
+    /// runtime. The perf events of the module can be read with
+    /// `perf_poller()`.
+    pub fn load_module(&self, data: &[u8]) -> Result<LoadedModule, LoaderError> {
+        let loaded = self.load_module_prefixed(data, None)?;
+        if let Some(names) = &self.programs {
+            check_programs(&loaded.module, names)?;
+        }
+
+        Ok(loaded)
+    }
+
+    // Loads the module like `load_module()`. With a `prefix`, the names given
+    // to the loader are `prefix/name`, the names of the module without it
+    // are ignored, and the programs and maps get the prefix once attached.
+    fn load_module_prefixed(
+        &self,
+        data: &[u8],
+        prefix: Option<&str>,
+    ) -> Result<LoadedModule, LoaderError> {
+        if self.bump_memlock {
+            let _ = sys::bump_memlock_rlimit();
+        }
+        let full_name = |name: &str| match prefix {
+            Some(prefix) => format!("{}/{}", prefix, name),
+            None => name.to_string(),
+        };
+        let map_configs = self
+            .map_configs
+            .iter()
+            .filter_map(|(name, config)| {
+                local_name(prefix, name).map(|name| (name.to_string(), *config))
+            })
+            .collect();
+        let spec = ModuleSpec::parse(data).map_err(LoaderError::ParseError)?;
+        let mut reused = Vec::new();
+        if let Some(dir) = &self.pinned_maps {
+            for map in spec.maps.iter() {
+                let path = dir.join(full_name(&map.name));
+                if path.exists() {
+                    let mut pinned = Map::from_pinned(&path).map_err(LoaderError::ParseError)?;
+                    pinned.name = map.name.clone();
+                    reused.push(pinned);
+                }
+            }
+        }
+        let mut module = Module::from_spec_reusing_maps(&spec, &map_configs, reused)
+            .map_err(LoaderError::ParseError)?;
+        if let Some(names) = &self.programs {
+            module
+                .programs
+                .retain(|p| names.contains(&full_name(&p.name)));
+        }
+        for (name, data) in self.globals.iter() {
+            if let Some(name) = local_name(prefix, name) {
+                module
+                    .set_global_bytes(name, data)
+                    .map_err(LoaderError::InitError)?;
+            }
+        }
         for prog in module.programs.iter_mut() {
-            prog.load(module.version, module.license.clone())
+            prog.load_with_log_level(module.version, module.license.clone(), self.log_level)
                 .map_err(|e| LoaderError::LoadError(prog.name.clone(), e))?;
         }
 
-        if let Some(interface) = &self.xdp.interface {
-            for prog in module.programs.iter_mut().filter(|p| p.kind == XDP) {
-                println!("Loaded: {}, {:?}", prog.name, prog.kind);
-                prog.attach_xdp(&interface, self.xdp.flags)
-                    .map_err(|e| LoaderError::XdpError(prog.name.clone(), e))?;
+        if let Some(init) = &self.init {
+            init(&module).map_err(LoaderError::InitError)?;
+        }
+        let required: Vec<&str> = self
+            .required
+            .iter()
+            .filter_map(|name| local_name(prefix, name))
+            .collect();
+        module
+            .check_initialized(&required)
+            .map_err(LoaderError::InitError)?;
+        if let Some(ready) = module.maps.iter().find(|m| m.name == READY_MAP) {
+            HashMap::<u32, u32>::new(ready)
+                .map_err(LoaderError::InitError)?
+                .set(READY_KEY, 1);
+        }
+
+        let mut attach_errors = Vec::new();
+        if !self.skip_attach {
+            for prog in module.programs.iter_mut() {
+                match self.attach(prog, &full_name(&prog.name)) {
+                    Ok(()) => {}
+                    Err(e) if self.strict => return Err(e),
+                    Err(e) => attach_errors.push(e),
+                }
             }
         }
+        if prefix.is_some() {
+            for prog in module.programs.iter_mut() {
+                prog.name = full_name(&prog.name);
+            }
+            for map in module.maps.iter_mut().chain(module.globals.iter_mut()) {
+                map.name = full_name(&map.name);
+            }
+        }
+
+        Ok(LoadedModule {
+            module,
+            attach_errors,
+        })
+    }
+
+    // Attaches `prog` where the parameters of `name` say, or where it's named
+    // after
+    fn attach(&self, prog: &mut Program, name: &str) -> Result<(), LoaderError> {
+        let name = name.to_string();
+        let section = prog.name.clone();
+        let retry = &self.retry;
+        let attached = match (prog.kind, self.attach_params.get(&name)) {
+            (XDP, Some(AttachParams::Xdp { interface, flags })) => retry
+                .run(&name, || prog.attach_xdp(interface, *flags))
+                .map_err(|e| LoaderError::XdpError(name.clone(), e)),
+            (XDP, None) => match &self.xdp.interface {
+                Some(interface) => retry
+                    .run(&name, || prog.attach_xdp(interface, self.xdp.flags))
+                    .map_err(|e| LoaderError::XdpError(name.clone(), e)),
+                None => return Ok(()),
+            },
+            (Kprobe, Some(AttachParams::Kprobe { function }))
+            | (Kretprobe, Some(AttachParams::Kprobe { function })) => retry
+                .run(&name, || prog.attach_probe_to_name(function))
+                .map(|_| ())
+                .map_err(|e| LoaderError::KprobeError(name.clone(), e)),
+            (Kprobe, None) | (Kretprobe, None) => retry
+                .run(&name, || prog.attach_probe())
+                .map(|_| ())
+                .map_err(|e| LoaderError::KprobeError(name.clone(), e)),
+            (Uprobe, Some(AttachParams::Uprobe { path, symbol, pid })) => retry
+                .run(&name, || prog.attach_uprobe(path, symbol, *pid))
+                .map(|_| ())
+                .map_err(|e| LoaderError::AttachError(name.clone(), e)),
+            (Uretprobe, Some(AttachParams::Uprobe { path, symbol, pid })) => retry
+                .run(&name, || prog.attach_uretprobe(path, symbol, *pid))
+                .map(|_| ())
+                .map_err(|e| LoaderError::AttachError(name.clone(), e)),
+            (Tracepoint, Some(AttachParams::Tracepoint { category, name: tp })) => retry
+                .run(&name, || prog.attach_tracepoint(category, tp))
+                .map(|_| ())
+                .map_err(|e| LoaderError::TracepointError(name.clone(), e)),
+            (Tracepoint, None) => {
+                let mut names = section.splitn(2, '/');
+                let (category, tp) = match (names.next(), names.next()) {
+                    (Some(category), Some(tp)) => (category, tp),
+                    _ => {
+                        return Err(LoaderError::TracepointError(
+                            name.clone(),
+                            Error::Section(name.clone()),
+                        ))
+                    }
+                };
+                retry
+                    .run(&name, || prog.attach_tracepoint(category, tp))
+                    .map(|_| ())
+                    .map_err(|e| LoaderError::TracepointError(name.clone(), e))
+            }
+            (
+                Classifier,
+                Some(AttachParams::Classifier {
+                    interface,
+                    direction,
+                    priority,
+                    handle,
+                }),
+            ) => retry
+                .run(&name, || {
+                    prog.attach_tc(interface, *direction, *priority, *handle)
+                })
+                .map_err(|e| LoaderError::AttachError(name.clone(), e)),
+            (SocketFilter, Some(AttachParams::SocketFilter { interface })) => retry
+                .run(&name, || prog.attach_socketfilter(interface))
+                .map(|_| ())
+                .map_err(|e| LoaderError::AttachError(name.clone(), e)),
+            (_, Some(_)) => return Err(LoaderError::InvalidAttachParams(name)),
+            (_, None) => return Ok(()),
+        };
+        attached?;
+        println!("Loaded: {}, {:?}", prog.name, prog.kind);
+
+        Ok(())
+    }
+
+    /// Loads the BPF programs included in `file`.
+    ///
+    /// See `load_module()`. Errors are `LoaderError::InFile` with the path
+    /// of the file.
+    pub fn load_module_file<P: AsRef<Path>>(&self, file: P) -> Result<LoadedModule, LoaderError> {
+        let file = file.as_ref();
+        fs::read(file)
+            .map_err(LoaderError::FileError)
+            .and_then(|data| self.load_module(&data))
+            .map_err(|e| LoaderError::InFile(file.to_path_buf(), Box::new(e)))
+    }
 
-        for prog in module
-            .programs
-            .iter_mut()
-            .filter(|p| p.kind == Kprobe || p.kind == Kretprobe)
-        {
-            prog.attach_probe()
-                .map_err(|e| LoaderError::KprobeError(prog.name.clone(), e))?;
-            println!("Loaded: {}, {:?}", prog.name, prog.kind);
+    /// Loads the BPF programs of all the `*.elf` files in `dir` and its
+    /// subdirectories, like the probes built by `cargo bpf build` in
+    /// `target/bpf/programs`, into a single module.
+    ///
+    /// The programs and maps are named `stem/name` after the stem of their
+    /// file, eg. `iotop/vfs_read`, and so are the names given to the loader:
+    /// `programs(&["iotop/vfs_read"])` only loads that program of
+    /// `iotop.elf`. `init()` runs for the module of each file, before the
+    /// names are prefixed. The `license` and `version` of the module are
+    /// those of the first file in alphabetical order.
+    ///
+    /// Errors are `LoaderError::InFile` with the path of the file, the
+    /// programs of the files loaded before are detached. Fails if two files
+    /// have the same stem or if there's no `*.elf` file.
+    pub fn load_module_dir<P: AsRef<Path>>(&self, dir: P) -> Result<LoadedModule, LoaderError> {
+        let dir = dir.as_ref();
+        let in_file = |path: &Path, e| LoaderError::InFile(path.to_path_buf(), Box::new(e));
+        let files = elf_files(dir).map_err(|e| in_file(dir, LoaderError::FileError(e)))?;
+        if files.is_empty() {
+            let e = io::Error::new(io::ErrorKind::NotFound, "no ELF files");
+            return Err(in_file(dir, LoaderError::FileError(e)));
         }
-        let online_cpus = cpus::get_online().unwrap();
-        let (sender, receiver) = mpsc::unbounded();
-        for m in module.maps.iter_mut().filter(|m| m.kind == 4) {
-            for cpuid in online_cpus.iter() {
-                let name = m.name.clone();
-                let map = PerfMap::bind(m, -1, *cpuid, 16, -1, 0).unwrap();
-                let stream = PerfMessageStream::new(name.clone(), map);
-                let mut s = sender.clone();
-                let fut = stream.for_each(move |events| {
-                    s.start_send((name.clone(), events)).unwrap();
-                    future::ready(())
-                });
-                tokio::spawn(fut);
+
+        let mut loaded: Option<LoadedModule> = None;
+        let mut stems = RSHashMap::new();
+        for file in files.iter() {
+            let stem = file
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if let Some(other) = stems.insert(stem.clone(), file) {
+                let e = io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} has the same name", other.display()),
+                );
+                return Err(in_file(file, LoaderError::FileError(e)));
+            }
+            let LoadedModule {
+                mut module,
+                mut attach_errors,
+            } = fs::read(file)
+                .map_err(LoaderError::FileError)
+                .and_then(|data| self.load_module_prefixed(&data, Some(&stem)))
+                .map_err(|e| in_file(file, e))?;
+            let attach_errors = attach_errors
+                .drain(..)
+                .map(|e| in_file(file, e))
+                .collect::<Vec<_>>();
+            match &mut loaded {
+                Some(loaded) => {
+                    loaded.module.programs.append(&mut module.programs);
+                    loaded.module.maps.append(&mut module.maps);
+                    loaded.module.globals.append(&mut module.globals);
+                    loaded.attach_errors.extend(attach_errors);
+                }
+                None => {
+                    loaded = Some(LoadedModule {
+                        module,
+                        attach_errors,
+                    })
+                }
             }
         }
+        let loaded = loaded.unwrap();
+        if let Some(names) = &self.programs {
+            check_programs(&loaded.module, names).map_err(|e| in_file(dir, e))?;
+        }
+
+        Ok(loaded)
+    }
+
+    /// Loads the programs included in `data`.
+    ///
+    /// This will parse `data` with `Module::parse()` and load all the programs
+    /// present in the module, as `load_module()` does, and then stream the
+    /// events of the perf maps of the module.
+    #[cfg(feature = "load")]
+    pub async fn load(&self, data: &[u8]) -> Result<Loaded, LoaderError> {
+        self.stream(self.load_module(data)?)
+    }
+
+    /// Loads the BPF programs included in `file`.
+    ///
+    /// See `load()` and `load_module_file()`.
+    #[cfg(feature = "load")]
+    pub async fn load_file<P: AsRef<Path>>(&self, file: P) -> Result<Loaded, LoaderError> {
+        self.stream(self.load_module_file(file)?)
+    }
+
+    /// Loads the BPF programs of all the `*.elf` files in `dir`.
+    ///
+    /// See `load()` and `load_module_dir()`. The events are streamed with
+    /// the prefixed names of their maps.
+    #[cfg(feature = "load")]
+    pub async fn load_dir<P: AsRef<Path>>(&self, dir: P) -> Result<Loaded, LoaderError> {
+        self.stream(self.load_module_dir(dir)?)
+    }
+
+    // Starts streaming the events of the perf maps of the module
+    #[cfg(feature = "load")]
+    fn stream(&self, loaded: LoadedModule) -> Result<Loaded, LoaderError> {
+        let LoadedModule {
+            module,
+            attach_errors,
+        } = loaded;
+
+        let watcher =
+            cpus::OnlineWatcher::new().map_err(|e| LoaderError::InitError(Error::IO(e)))?;
+        let (sender, receiver) = EventSender::channel(self.channel_capacity);
+        let (stop_sender, stop) = oneshot::channel();
+        let stop = stop.shared();
+        // each task reading perf buffers holds a sender, so `close()` knows
+        // when they're all done
+        let (running, done) = mpsc::channel(0);
+        let mut buffers = Vec::new();
+        let mut maps = RSHashMap::new();
+        for m in module.maps.iter().filter(|m| m.kind == 4) {
+            check_cpu_entries(m).map_err(LoaderError::InitError)?;
+            let state = Arc::new(MapState::default());
+            maps.insert(m.name.clone(), state.clone());
+            let mut perf_buffers = PerfBuffers {
+                map: m.alias(),
+                config: self.perf_buffer_config(&m.name),
+                sender: sender.clone(),
+                state,
+                streams: RSHashMap::new(),
+                stop: stop.clone(),
+                running: running.clone(),
+            };
+            for cpuid in watcher.online() {
+                perf_buffers.open(*cpuid).map_err(LoaderError::InitError)?;
+            }
+            buffers.push(perf_buffers);
+        }
+        if !buffers.is_empty() {
+            watch_cpus(watcher, buffers, sender, stop);
+        }
 
         Ok(Loaded {
             module,
-            xdp: self.xdp.clone(),
+            attach_errors,
+            persist: false,
             events: receiver,
+            maps,
+            channel_capacity: self.channel_capacity,
+            stop: Some(stop_sender),
+            done,
         })
     }
+}
 
-    /// Loads the BPF programs included in `file`.
-    ///
-    /// See `load()`.
-    pub async fn load_file(&self, file: &Path) -> Result<Loaded, LoaderError> {
-        self.load(&fs::read(file).map_err(|e| LoaderError::FileError(e))?)
-            .await
+// Fails with `LoaderError::LoadError` if one of `names` isn't a program of
+// `module`
+fn check_programs(module: &Module, names: &[String]) -> Result<(), LoaderError> {
+    match names
+        .iter()
+        .find(|n| !module.programs.iter().any(|p| &p.name == *n))
+    {
+        Some(name) => Err(LoaderError::LoadError(
+            name.clone(),
+            Error::Section(name.clone()),
+        )),
+        None => Ok(()),
     }
 }
 
+// Returns the name in the module of `name` given to the loader, or `None` if
+// it's meant for another module
+fn local_name<'a>(prefix: Option<&str>, name: &'a str) -> Option<&'a str> {
+    match prefix {
+        None => Some(name),
+        Some(prefix) if name.starts_with(prefix) && name[prefix.len()..].starts_with('/') => {
+            Some(&name[prefix.len() + 1..])
+        }
+        Some(_) => None,
+    }
+}
+
+// Returns the `*.elf` files in `dir` and its subdirectories, sorted
+fn elf_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(elf_files(&path)?);
+        } else if path.extension().map(|ext| ext == "elf").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
 /// The `Loaded` object returned by `load()`.
+#[cfg(feature = "load")]
 pub struct Loaded {
     pub module: Module,
-    xdp: XdpConfig,
-    /// The stream of events emitted by the BPF programs.
+    /// The errors of the programs that failed to attach. Always empty if
+    /// the loader is strict.
+    pub attach_errors: Vec<LoaderError>,
+    persist: bool,
+    /// The stream of events emitted by the BPF programs, except those of the
+    /// maps read with `events()` or `raw_events()`.
     ///
     /// # Example
     ///
     /// ```no_run
     /// use std::path::Path;
     /// use futures::stream::StreamExt;
-    /// use redbpf::load::Loader;
+    /// use redbpf::load::{Loader, MapEvent};
     /// # async {
     /// let mut loader = Loader::new().load_file(&Path::new("probe.elf")).await.unwrap();
     /// while let Some((map_name, events)) = loader.events.next().await {
     ///     for event in events {
-    ///         // ...
+    ///         match event {
+    ///             MapEvent::Sample { data, .. } => {
+    ///                 // ...
+    ///             }
+    ///             MapEvent::Lost { count, cpu } => {
+    ///                 eprintln!("lost {} samples on CPU {}", count, cpu)
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// # };
+    /// ```
+    pub events: EventStream<MapEvents>,
+    maps: RSHashMap<String, Arc<MapState>>,
+    channel_capacity: Option<usize>,
+    // dropped to stop the tasks reading the perf buffers
+    stop: Option<oneshot::Sender<()>>,
+    done: mpsc::Receiver<()>,
+}
+
+// The state of the perf buffers of a map, shared with their tasks
+#[cfg(feature = "load")]
+#[derive(Default)]
+struct MapState {
+    lost: AtomicU64,
+    // the streams returned by `raw_events()`
+    subscribers: Mutex<Vec<EventSender<Vec<MapEvent>>>>,
+}
+
+/// A stream of events, see `Loaded::events`.
+///
+/// The stream is unbounded, unless a capacity is set with
+/// `Loader::event_channel_capacity()`.
+#[cfg(feature = "load")]
+pub struct EventStream<T> {
+    receiver: Either<mpsc::UnboundedReceiver<T>, mpsc::Receiver<T>>,
+}
+
+#[cfg(feature = "load")]
+impl<T> Stream for EventStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        match &mut self.receiver {
+            Either::Left(receiver) => receiver.poll_next_unpin(cx),
+            Either::Right(receiver) => receiver.poll_next_unpin(cx),
+        }
+    }
+}
+
+#[cfg(feature = "load")]
+impl<T> stream::FusedStream for EventStream<T> {
+    fn is_terminated(&self) -> bool {
+        match &self.receiver {
+            Either::Left(receiver) => receiver.is_terminated(),
+            Either::Right(receiver) => receiver.is_terminated(),
+        }
+    }
+}
+
+// The sending half of an `EventStream`
+#[cfg(feature = "load")]
+enum EventSender<T> {
+    Unbounded(mpsc::UnboundedSender<T>),
+    Bounded(mpsc::Sender<T>),
+}
+
+#[cfg(feature = "load")]
+impl<T> Clone for EventSender<T> {
+    fn clone(&self) -> Self {
+        match self {
+            EventSender::Unbounded(sender) => EventSender::Unbounded(sender.clone()),
+            EventSender::Bounded(sender) => EventSender::Bounded(sender.clone()),
+        }
+    }
+}
+
+#[cfg(feature = "load")]
+impl<T> EventSender<T> {
+    fn channel(capacity: Option<usize>) -> (EventSender<T>, EventStream<T>) {
+        match capacity {
+            Some(capacity) => {
+                let (sender, receiver) = mpsc::channel(capacity);
+                let receiver = Either::Right(receiver);
+                (EventSender::Bounded(sender), EventStream { receiver })
+            }
+            None => {
+                let (sender, receiver) = mpsc::unbounded();
+                let receiver = Either::Left(receiver);
+                (EventSender::Unbounded(sender), EventStream { receiver })
+            }
+        }
+    }
+
+    // Waits for room in bounded channels. Fails if the receiver is dropped.
+    async fn send(&mut self, item: T) -> Result<(), ()> {
+        match self {
+            EventSender::Unbounded(sender) => sender.unbounded_send(item).map_err(|_| ()),
+            EventSender::Bounded(sender) => sender.send(item).await.map_err(|_| ()),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            EventSender::Unbounded(sender) => sender.is_closed(),
+            EventSender::Bounded(sender) => sender.is_closed(),
+        }
+    }
+}
+
+#[cfg(feature = "load")]
+impl Loaded {
+    /// Keeps the XDP and classifier programs attached when `Loaded` is
+    /// dropped.
+    ///
+    /// By default they are detached from their interfaces on drop. After
+    /// calling `persist()` they keep running once the process exits, eg. so
+    /// that a new version of it can take over their pinned maps without
+    /// dropping packets in between.
+    pub fn persist(&mut self) {
+        self.persist = true;
+    }
+
+    /// Returns the number of samples of `map` the kernel dropped so far,
+    /// because its perf buffers were full.
+    pub fn lost_samples(&self, map: &str) -> u64 {
+        match self.maps.get(map) {
+            Some(state) => state.lost.load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
+    /// Returns the stream of the events of the perf map `name`, read as
+    /// `T`s with `MapEvent::read()`.
+    ///
+    /// Lost samples are reported as `DecodeError::Lost`. See `raw_events()`.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use futures::stream::StreamExt;
+    /// use redbpf::load::Loader;
+    /// use redbpf::Pod;
+    ///
+    /// #[repr(C)]
+    /// struct Query {
+    ///     id: u16,
+    ///     len: u16,
+    /// }
+    /// unsafe impl Pod for Query {}
+    /// # async {
+    /// let mut loaded = Loader::new().load_file(&Path::new("dns.elf")).await.unwrap();
+    /// let mut queries = loaded.read_events::<Query>("dns_queries").unwrap();
+    /// while let Some(query) = queries.next().await {
+    ///     match query {
+    ///         Ok(query) => println!("query {}", query.id),
+    ///         Err(e) => eprintln!("{:?}", e),
     ///     }
     /// }
     /// # };
     /// ```
-    pub events: mpsc::UnboundedReceiver<(String, <PerfMessageStream as Stream>::Item)>,
+    pub fn read_events<T: Pod>(
+        &self,
+        name: &str,
+    ) -> Result<impl Stream<Item = Result<T, DecodeError>>, Error> {
+        Ok(self.raw_events(name)?.map(|e| e.read()))
+    }
+
+    /// Returns the stream of the events of the perf map `name`, decoded as
+    /// `T`s like `read_events()` does, for types that aren't `Pod`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the samples of the map hold valid `T`s, see
+    /// `MapEvent::decode()`.
+    pub unsafe fn events<T>(
+        &self,
+        name: &str,
+    ) -> Result<impl Stream<Item = Result<T, DecodeError>>, Error> {
+        Ok(self.raw_events(name)?.map(|e| e.decode()))
+    }
+
+    /// Returns the stream of the events of the perf map `name`.
+    ///
+    /// The events of the map are delivered to the streams returned for it
+    /// instead of `events`, until they are all dropped. Fails with
+    /// `Error::Map` if the module has no perf map called `name`.
+    pub fn raw_events(&self, name: &str) -> Result<impl Stream<Item = MapEvent>, Error> {
+        let state = self.maps.get(name).ok_or(Error::Map)?;
+        let (sender, receiver) = EventSender::channel(self.channel_capacity);
+        state.subscribers.lock().unwrap().push(sender);
+
+        Ok(receiver.map(stream::iter).flatten())
+    }
+
+    /// Stops reading the perf buffers, and detaches and unloads the
+    /// programs.
+    ///
+    /// The events left in the perf buffers are read before the buffers are
+    /// unmapped, and returned along with the events still queued in
+    /// `events`. The streams returned by `raw_events()` end, and the events
+    /// of their maps are returned too. The programs are then detached and
+    /// unloaded in the reverse order of `module.programs`, except the
+    /// programs kept attached by `persist()`.
+    ///
+    /// Returns the first error detaching a program, after trying to detach
+    /// all of them.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    /// use futures::stream::StreamExt;
+    /// use redbpf::load::Loader;
+    /// use tokio::signal::unix::{signal, SignalKind};
+    /// # async {
+    /// let mut loaded = Loader::new().load_file(&Path::new("probe.elf")).await.unwrap();
+    /// let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    /// loop {
+    ///     tokio::select! {
+    ///         Some((map_name, events)) = loaded.events.next() => {
+    ///             // ...
+    ///         }
+    ///         _ = sigterm.recv() => break,
+    ///     }
+    /// }
+    /// for (map_name, events) in loaded.close().await.unwrap() {
+    ///     // ...
+    /// }
+    /// # };
+    /// ```
+    pub async fn close(mut self) -> Result<Vec<MapEvents>, Error> {
+        for state in self.maps.values() {
+            state.subscribers.lock().unwrap().clear();
+        }
+        self.stop.take();
+
+        // the tasks may wait for room in `events` while draining
+        let mut flushed = Vec::new();
+        loop {
+            match future::select(self.done.next(), self.events.next()).await {
+                Either::Left((None, _)) => break,
+                Either::Left((Some(()), _)) => {}
+                Either::Right((Some(events), _)) => flushed.push(events),
+                Either::Right((None, _)) => break,
+            }
+        }
+        while let Some(Some(events)) = self.events.next().now_or_never() {
+            flushed.push(events);
+        }
+
+        let mut ret = Ok(());
+        for prog in self.module.programs.iter_mut().rev() {
+            let res = if self.persist && matches!(prog.kind, XDP | Classifier) {
+                prog.take_links().into_iter().for_each(|link| link.forget());
+                Ok(())
+            } else {
+                prog.unload()
+            };
+            if ret.is_ok() {
+                ret = res;
+            }
+        }
+
+        ret.map(|_| flushed)
+    }
 }
 
+// The programs are detached when their links are dropped
+#[cfg(feature = "load")]
 impl Drop for Loaded {
     fn drop(&mut self) {
-        if let Some(interface) = &self.xdp.interface {
-            let ciface = CString::new(interface.as_bytes()).unwrap();
-            let _ = unsafe { bpf_sys::bpf_attach_xdp(ciface.as_ptr(), -1, 0) };
+        if !self.persist {
+            return;
+        }
+        let programs = self.module.programs.iter_mut();
+        for prog in programs.filter(|p| matches!(p.kind, XDP | Classifier)) {
+            for link in prog.take_links() {
+                link.forget();
+            }
         }
     }
 }
@@ -153,3 +1106,127 @@ impl Default for XdpConfig {
         }
     }
 }
+
+// Resolves when `Loaded` is closed or dropped
+#[cfg(feature = "load")]
+type Stop = Shared<oneshot::Receiver<()>>;
+
+// The perf buffers of a perf event array, one for each online CPU
+#[cfg(feature = "load")]
+struct PerfBuffers {
+    map: Map,
+    config: PerfBufferConfig,
+    sender: EventSender<MapEvents>,
+    state: Arc<MapState>,
+    streams: RSHashMap<CpuId, AbortHandle>,
+    stop: Stop,
+    running: mpsc::Sender<()>,
+}
+
+#[cfg(feature = "load")]
+impl PerfBuffers {
+    fn open(&mut self, cpu: CpuId) -> Result<(), Error> {
+        let name = self.map.name.clone();
+        let map = PerfMap::bind_with(&self.map, cpu, self.config)?;
+        let mut stream = PerfMessageStream::new(map, cpu).take_until(self.stop.clone());
+        let mut sender = self.sender.clone();
+        let state = self.state.clone();
+        let running = self.running.clone();
+        let (fut, handle) = future::abortable(async move {
+            while let Some(events) = stream.next().await {
+                deliver(&name, &state, &mut sender, events).await;
+            }
+            // read what's left before the buffer is unmapped
+            let events = stream.into_inner().read_messages();
+            if !events.is_empty() {
+                deliver(&name, &state, &mut sender, events).await;
+            }
+            drop(running);
+        });
+        tokio::spawn(fut);
+        self.streams.insert(cpu, handle);
+
+        Ok(())
+    }
+
+    fn close(&mut self, cpu: CpuId) {
+        if let Some(handle) = self.streams.remove(&cpu) {
+            handle.abort();
+        }
+        if let Ok(map) = HashMap::<CpuId, i32>::new(&self.map) {
+            let _ = map.delete(&cpu);
+        }
+    }
+}
+
+// Sends the events of the map `name` to the streams of `raw_events()`, or
+// to `Loaded::events` if there are none
+#[cfg(feature = "load")]
+async fn deliver(
+    name: &str,
+    state: &MapState,
+    sender: &mut EventSender<MapEvents>,
+    events: Vec<MapEvent>,
+) {
+    state.lost.fetch_add(lost_count(&events), Ordering::Relaxed);
+    // the lock can't be held while waiting for room in the channels
+    let subscribers = state.subscribers.lock().unwrap().clone();
+    if subscribers.is_empty() {
+        let _ = sender.send((name.to_string(), events)).await;
+        return;
+    }
+    for mut subscriber in subscribers {
+        let _ = subscriber.send(events.clone()).await;
+    }
+    state
+        .subscribers
+        .lock()
+        .unwrap()
+        .retain(|sub| !sub.is_closed());
+}
+
+// Opens perf buffers for CPUs going online and closes the ones of CPUs going
+// offline, until the receiver of the events is dropped or `stop` resolves
+#[cfg(feature = "load")]
+fn watch_cpus(
+    mut watcher: cpus::OnlineWatcher<fn() -> io::Result<Vec<CpuId>>>,
+    mut buffers: Vec<PerfBuffers>,
+    sender: EventSender<MapEvents>,
+    stop: Stop,
+) {
+    let (tx, rx) = mpsc::unbounded();
+    thread::spawn(move || {
+        while !sender.is_closed() && !tx.is_closed() {
+            thread::sleep(CPU_POLL_INTERVAL);
+            match watcher.poll() {
+                Ok(events) if events.is_empty() => {}
+                Ok(events) => {
+                    if tx.unbounded_send(events).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("error reading online CPUs: {}", e),
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut rx = rx.take_until(stop);
+        while let Some(events) = rx.next().await {
+            for event in events {
+                for perf_buffers in buffers.iter_mut() {
+                    match event {
+                        CpuEvent::Online(cpu) => {
+                            if let Err(e) = perf_buffers.open(cpu) {
+                                warn!(
+                                    "error opening perf buffer for {} on CPU {}: {}",
+                                    perf_buffers.map.name, cpu, e
+                                );
+                            }
+                        }
+                        CpuEvent::Offline(cpu) => perf_buffers.close(cpu),
+                    }
+                }
+            }
+        }
+    });
+}
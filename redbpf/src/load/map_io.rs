@@ -11,11 +11,12 @@ use mio::{Evented, PollOpt, Ready, Token};
 use std::io;
 use std::os::unix::io::RawFd;
 use std::pin::Pin;
-use std::slice;
 use std::task::{Context, Poll};
 use tokio::io::PollEvented;
 
-use crate::{Event, PerfMap};
+use crate::cpus::CpuId;
+use crate::load::poller::{read_events, MapEvent};
+use crate::PerfMap;
 
 pub struct MapIo(RawFd);
 
@@ -48,40 +49,25 @@ impl Evented for MapIo {
 pub struct PerfMessageStream {
     poll: PollEvented<MapIo>,
     map: PerfMap,
-    name: String,
+    cpu: CpuId,
 }
 
 impl PerfMessageStream {
-    pub fn new(name: String, map: PerfMap) -> Self {
+    /// Creates a stream of the events of `map`, the perf buffer of `cpu`.
+    pub fn new(map: PerfMap, cpu: CpuId) -> Self {
         let io = MapIo(map.fd);
         let poll = PollEvented::new(io).unwrap();
-        PerfMessageStream { poll, map, name }
+        PerfMessageStream { poll, map, cpu }
     }
 
-    fn read_messages(&mut self) -> Vec<Box<[u8]>> {
-        let mut ret = Vec::new();
-        while let Some(ev) = self.map.read() {
-            match ev {
-                Event::Lost(lost) => {
-                    eprintln!("Possibly lost {} samples for {}", lost.count, &self.name);
-                }
-                Event::Sample(sample) => {
-                    let msg = unsafe {
-                        slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize)
-                            .to_vec()
-                            .into_boxed_slice()
-                    };
-                    ret.push(msg);
-                }
-            };
-        }
-
-        ret
+    // Reads all the events available in the buffer
+    pub(crate) fn read_messages(&mut self) -> Vec<MapEvent> {
+        read_events(&self.map, self.cpu)
     }
 }
 
 impl Stream for PerfMessageStream {
-    type Item = Vec<Box<[u8]>>;
+    type Item = Vec<MapEvent>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let ready = Ready::readable();
@@ -1,4 +1,18 @@
+//! High level API to load and attach BPF modules.
+//!
+//! `Loader::load_module()` and `PerfPoller` are blocking and don't need an
+//! async runtime. The async loader, `Loader::load()`, streams the perf
+//! events of the module and requires the `load` feature, which pulls in
+//! tokio. It must be called from a tokio runtime, which runs the tasks
+//! reading the perf buffers.
+//!
+//! With other runtimes, like async-std or smol, the module is loaded with
+//! `Loader::load_module()` and its events are read by a `PerfPoller`
+//! registered with the reactor of the runtime, see `PerfPoller::try_poll()`.
+#[cfg(feature = "load")]
 pub mod map_io;
 mod loader;
+mod poller;
 
-pub use loader::*;
\ No newline at end of file
+pub use loader::*;
+pub use poller::*;
@@ -0,0 +1,524 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap as RSHashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use crate::cpus::{self, CpuEvent, CpuId};
+use crate::load::loader::CPU_POLL_INTERVAL;
+use crate::{Error, Event, HashMap, Map, Module, PerfBufferConfig, PerfMap, Pod, Result};
+
+const MAX_EVENTS: usize = 64;
+
+/// An event read from the perf buffers of a map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapEvent {
+    /// The data output by a program
+    Sample {
+        /// The CPU the program ran on
+        cpu: CpuId,
+        /// The time of the sample in nanoseconds, if the buffers of the map
+        /// record timestamps
+        timestamp_ns: Option<u64>,
+        data: Box<[u8]>,
+    },
+    /// The kernel dropped `count` samples because the buffer of `cpu` was
+    /// full
+    Lost { count: u64, cpu: CpuId },
+}
+
+/// Reason why an event can't be decoded with `MapEvent::read()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The sample is shorter than the type it's decoded to
+    Size { expected: usize, actual: usize },
+    /// The event is a `MapEvent::Lost` notification
+    Lost { count: u64, cpu: CpuId },
+}
+
+impl MapEvent {
+    /// Returns the data of a sample, `None` for lost samples.
+    pub fn into_sample(self) -> Option<Box<[u8]>> {
+        match self {
+            MapEvent::Sample { data, .. } => Some(data),
+            MapEvent::Lost { .. } => None,
+        }
+    }
+
+    /// Reads a `T` from the start of the sample.
+    ///
+    /// Samples are padded by the kernel, so they can be longer than `T`.
+    /// Fails with `DecodeError::Size` if the sample is shorter. The sample is
+    /// copied, so it doesn't need to be aligned for `T`.
+    ///
+    /// ```
+    /// use redbpf::load::MapEvent;
+    /// use redbpf::Pod;
+    ///
+    /// #[repr(C)]
+    /// struct Exec {
+    ///     pid: u32,
+    ///     uid: u32,
+    /// }
+    /// unsafe impl Pod for Exec {}
+    ///
+    /// let event = MapEvent::Sample {
+    ///     cpu: 0,
+    ///     timestamp_ns: None,
+    ///     data: vec![42, 0, 0, 0, 0xe8, 3, 0, 0].into_boxed_slice(),
+    /// };
+    /// let exec = event.read::<Exec>().unwrap();
+    /// assert_eq!((exec.pid, exec.uid), (42, 1000));
+    /// assert!(event.read::<u128>().is_err());
+    /// ```
+    pub fn read<T: Pod>(&self) -> std::result::Result<T, DecodeError> {
+        unsafe { self.decode() }
+    }
+
+    /// Reads a `T` from the start of the sample like `read()`, and returns
+    /// it with the data following it, eg. the packet following its header.
+    ///
+    /// The data includes the padding of the sample, so `T` should hold its
+    /// length.
+    pub fn read_with_payload<T: Pod>(&self) -> std::result::Result<(T, &[u8]), DecodeError> {
+        let header = self.read()?;
+        match self {
+            MapEvent::Sample { data, .. } => Ok((header, &data[mem::size_of::<T>()..])),
+            MapEvent::Lost { .. } => unreachable!(),
+        }
+    }
+
+    /// Reads a `T` from the start of the sample like `read()`, for types that
+    /// aren't `Pod`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure the samples hold valid `T`s, eg. because `T`
+    /// is the type the program outputs to the map.
+    pub unsafe fn decode<T>(&self) -> std::result::Result<T, DecodeError> {
+        match self {
+            MapEvent::Sample { data, .. } if data.len() < mem::size_of::<T>() => {
+                Err(DecodeError::Size {
+                    expected: mem::size_of::<T>(),
+                    actual: data.len(),
+                })
+            }
+            MapEvent::Sample { data, .. } => Ok(ptr::read_unaligned(data.as_ptr() as *const T)),
+            MapEvent::Lost { count, cpu } => Err(DecodeError::Lost {
+                count: *count,
+                cpu: *cpu,
+            }),
+        }
+    }
+}
+
+/// Decodes `events` as `T`s, see `MapEvent::decode()`.
+///
+/// # Safety
+///
+/// The caller must ensure the samples hold valid `T`s.
+pub unsafe fn decode_events<T>(
+    events: Vec<MapEvent>,
+) -> impl Iterator<Item = std::result::Result<T, DecodeError>> {
+    events.into_iter().map(|e| e.decode())
+}
+
+/// The events read from a perf buffer, paired with the name of the map.
+pub type MapEvents = (String, Vec<MapEvent>);
+
+/// A blocking reader of the perf events of a module.
+///
+/// Opens the perf buffers of all the perf event arrays of the module, one
+/// for each online CPU, and waits for events with epoll. Buffers are opened
+/// and closed as CPUs go online and offline. Lost samples are returned as
+/// `MapEvent::Lost` and counted by `lost_samples()`. Failing to read the
+/// online CPUs or to open the buffers of a CPU going online is returned by
+/// the next `poll()` or `try_poll()`; the buffers of that CPU aren't
+/// retried.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use redbpf::load::PerfPoller;
+/// use redbpf::Module;
+///
+/// let module = Module::load_file("probe.elf").unwrap();
+/// let mut poller = PerfPoller::new(&module).unwrap();
+/// loop {
+///     for (map_name, events) in poller.poll(Some(Duration::from_secs(1))).unwrap() {
+///         for event in events {
+///             // ...
+///         }
+///     }
+/// }
+/// ```
+///
+/// # Async runtimes
+///
+/// The file descriptor of the poller, see `as_raw_fd()`, is readable when
+/// one of the perf buffers has events. Registered with the reactor of an
+/// async runtime, the events are read with `try_poll()` once it's readable,
+/// without blocking the runtime. With async-std and smol,
+/// `async_io::Async::new(poller)` registers it, and
+/// `read_with_mut(|p| p.try_poll())` waits for the events. This is what the
+/// reactors do:
+///
+/// ```no_run
+/// use std::io;
+/// use std::os::unix::io::AsRawFd;
+/// use redbpf::load::PerfPoller;
+/// use redbpf::Module;
+///
+/// let module = Module::load_file("probe.elf").unwrap();
+/// let mut poller = PerfPoller::new(&module).unwrap();
+/// let mut fds = [libc::pollfd {
+///     fd: poller.as_raw_fd(),
+///     events: libc::POLLIN,
+///     revents: 0,
+/// }];
+/// loop {
+///     unsafe { libc::poll(fds.as_mut_ptr(), 1, 1000) };
+///     match poller.try_poll() {
+///         Ok(events) => {
+///             for (map_name, events) in events {
+///                 // ...
+///             }
+///         }
+///         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+///         Err(e) => panic!("{}", e),
+///     }
+/// }
+/// ```
+pub struct PerfPoller {
+    epoll: EpollFd,
+    maps: Vec<(Map, PerfBufferConfig)>,
+    buffers: RSHashMap<u64, PerfBuffer>,
+    lost: RSHashMap<String, u64>,
+    next_token: u64,
+    watcher: cpus::OnlineWatcher<fn() -> io::Result<Vec<CpuId>>>,
+    last_cpu_poll: Instant,
+}
+
+struct PerfBuffer {
+    map: PerfMap,
+    name: String,
+    cpu: CpuId,
+}
+
+// Closes the epoll file descriptor when dropped, including when creating
+// the poller fails
+#[derive(Debug)]
+struct EpollFd(RawFd);
+
+impl EpollFd {
+    fn new() -> io::Result<EpollFd> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(EpollFd(fd))
+    }
+}
+
+impl Drop for EpollFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl PerfPoller {
+    pub fn new(module: &Module) -> Result<PerfPoller> {
+        PerfPoller::with_config(module, |_| PerfBufferConfig::default())
+    }
+
+    /// Opens the perf buffers of each map with the configuration returned by
+    /// `config` for the name of the map.
+    pub fn with_config<F>(module: &Module, config: F) -> Result<PerfPoller>
+    where
+        F: Fn(&str) -> PerfBufferConfig,
+    {
+        let epoll = EpollFd::new()?;
+        let watcher = cpus::OnlineWatcher::new()?;
+        let maps = module
+            .maps
+            .iter()
+            .filter(|m| m.kind == bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY)
+            .map(|m| (m.alias(), config(&m.name)))
+            .collect();
+        let mut poller = PerfPoller {
+            epoll,
+            maps,
+            buffers: RSHashMap::new(),
+            lost: RSHashMap::new(),
+            next_token: 0,
+            watcher,
+            last_cpu_poll: Instant::now(),
+        };
+        for cpu in poller.watcher.online().to_vec() {
+            poller.open(cpu)?;
+        }
+
+        Ok(poller)
+    }
+
+    /// Waits for events for up to `timeout`, or until there are events if
+    /// `timeout` is `None`.
+    ///
+    /// Returns the events read from each perf buffer. The list is empty if
+    /// the timeout expired.
+    ///
+    /// Fails if the online CPUs can't be read, or if the perf buffers of a
+    /// CPU going online can't be opened.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<MapEvents>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            // wake up regularly to check for new CPUs
+            let wait = match deadline {
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(CPU_POLL_INTERVAL),
+                None => CPU_POLL_INTERVAL,
+            };
+            let ready = self.read_ready(wait)?;
+
+            match deadline {
+                _ if !ready.is_empty() => return Ok(ready),
+                Some(deadline) if Instant::now() >= deadline => return Ok(ready),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the events of the perf buffers that have some, without
+    /// waiting.
+    ///
+    /// Fails with `io::ErrorKind::WouldBlock` if there are none, like
+    /// non-blocking reads of sockets do, so that reactors wait for the file
+    /// descriptor of the poller to be readable again. Perf buffers of CPUs
+    /// going online are only opened by the calls, so it should be called at
+    /// least every second, eg. with a timeout on the reactor.
+    pub fn try_poll(&mut self) -> io::Result<Vec<MapEvents>> {
+        let ready = self
+            .read_ready(Duration::from_secs(0))
+            .map_err(|e| match e {
+                Error::IO(e) => e,
+                e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+            })?;
+        if ready.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+
+        Ok(ready)
+    }
+
+    /// Returns the number of samples of `map` the kernel dropped so far.
+    pub fn lost_samples(&self, map: &str) -> u64 {
+        self.lost.get(map).cloned().unwrap_or(0)
+    }
+
+    // Waits for up to `timeout` and reads the buffers that have events
+    fn read_ready(&mut self, timeout: Duration) -> Result<Vec<MapEvents>> {
+        if self.last_cpu_poll.elapsed() >= CPU_POLL_INTERVAL {
+            self.watch_cpus()?;
+        }
+
+        let mut ready = Vec::new();
+        for token in self.wait(timeout)? {
+            if let Some(buffer) = self.buffers.get(&token) {
+                let events = read_events(&buffer.map, buffer.cpu);
+                if events.is_empty() {
+                    continue;
+                }
+                *self.lost.entry(buffer.name.clone()).or_insert(0) += lost_count(&events);
+                ready.push((buffer.name.clone(), events));
+            }
+        }
+
+        Ok(ready)
+    }
+
+    fn wait(&self, timeout: Duration) -> io::Result<Vec<u64>> {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let ret = unsafe {
+            libc::epoll_wait(
+                self.epoll.0,
+                events.as_mut_ptr(),
+                MAX_EVENTS as i32,
+                timeout.as_millis() as i32,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+
+        Ok(events[..ret as usize].iter().map(|e| e.u64).collect())
+    }
+
+    fn open(&mut self, cpu: CpuId) -> Result<()> {
+        for (map, config) in self.maps.iter() {
+            let perf_map = PerfMap::bind_with(map, cpu, *config)?;
+            let token = self.next_token;
+            self.next_token += 1;
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: token,
+            };
+            let ret = unsafe {
+                libc::epoll_ctl(self.epoll.0, libc::EPOLL_CTL_ADD, perf_map.fd, &mut event)
+            };
+            if ret < 0 {
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
+            let buffer = PerfBuffer {
+                map: perf_map,
+                name: map.name.clone(),
+                cpu,
+            };
+            self.buffers.insert(token, buffer);
+        }
+
+        Ok(())
+    }
+
+    // Closing the perf events removes them from the epoll set
+    fn close(&mut self, cpu: CpuId) {
+        self.buffers.retain(|_, b| b.cpu != cpu);
+        for (map, _) in self.maps.iter() {
+            if let Ok(map) = HashMap::<CpuId, i32>::new(map) {
+                let _ = map.delete(&cpu);
+            }
+        }
+    }
+
+    // Opens and closes the buffers of the CPUs that went online and offline.
+    // All the changes are applied before returning the first error.
+    fn watch_cpus(&mut self) -> Result<()> {
+        self.last_cpu_poll = Instant::now();
+        let mut ret = Ok(());
+        for event in self.watcher.poll()? {
+            match event {
+                CpuEvent::Online(cpu) => {
+                    if let Err(e) = self.open(cpu) {
+                        if ret.is_ok() {
+                            ret = Err(e);
+                        }
+                    }
+                }
+                CpuEvent::Offline(cpu) => self.close(cpu),
+            }
+        }
+
+        ret
+    }
+}
+
+impl AsRawFd for PerfPoller {
+    /// Returns the epoll file descriptor of the perf buffers, readable when
+    /// one of them has events.
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.0
+    }
+}
+
+// Reads all the events available in the buffer of `cpu`
+pub(crate) fn read_events(map: &PerfMap, cpu: CpuId) -> Vec<MapEvent> {
+    let mut ret = Vec::new();
+    while let Some(ev) = map.read() {
+        let event = match ev {
+            Event::Lost(lost) => MapEvent::Lost {
+                count: lost.count,
+                cpu,
+            },
+            Event::Sample(sample) => MapEvent::Sample {
+                cpu,
+                timestamp_ns: None,
+                data: sample.raw_data().into(),
+            },
+            Event::TimedSample(sample) => MapEvent::Sample {
+                cpu,
+                timestamp_ns: Some(sample.time),
+                data: sample.raw_data().into(),
+            },
+        };
+        ret.push(event);
+    }
+
+    ret
+}
+
+pub(crate) fn lost_count(events: &[MapEvent]) -> u64 {
+    events
+        .iter()
+        .map(|e| match e {
+            MapEvent::Lost { count, .. } => *count,
+            MapEvent::Sample { .. } => 0,
+        })
+        .sum()
+}
+
+mod test {
+    #[test]
+    fn test_epoll_closed() {
+        use crate::load::poller::EpollFd;
+        use std::io;
+
+        EpollFd::new().unwrap();
+
+        // writing to the pipe fails once its read end is closed
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        drop(EpollFd(fds[0]));
+        let ret = unsafe { libc::write(fds[1], [0u8].as_ptr() as *const _, 1) };
+        assert_eq!(ret, -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPIPE));
+        unsafe { libc::close(fds[1]) };
+    }
+
+    #[test]
+    fn test_online_error() {
+        use crate::cpus::{CpuId, OnlineWatcher};
+        use crate::load::loader::CPU_POLL_INTERVAL;
+        use crate::load::poller::{EpollFd, PerfPoller};
+        use std::io;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Instant;
+
+        static READS: AtomicUsize = AtomicUsize::new(0);
+        fn read() -> io::Result<Vec<CpuId>> {
+            match READS.fetch_add(1, Ordering::SeqCst) {
+                1 => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+                _ => Ok(vec![0, 1]),
+            }
+        }
+
+        let mut poller = PerfPoller {
+            epoll: EpollFd::new().unwrap(),
+            maps: Vec::new(),
+            buffers: Default::default(),
+            lost: Default::default(),
+            next_token: 0,
+            watcher: OnlineWatcher::with_reader(read as fn() -> io::Result<Vec<CpuId>>).unwrap(),
+            last_cpu_poll: Instant::now() - CPU_POLL_INTERVAL,
+        };
+        let e = poller.try_poll().unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+        // the error is returned once, the next polls read the CPUs again
+        poller.last_cpu_poll = Instant::now() - CPU_POLL_INTERVAL;
+        let e = poller.try_poll().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(READS.load(Ordering::SeqCst), 3);
+        assert_eq!(poller.lost_samples("events"), 0);
+    }
+}
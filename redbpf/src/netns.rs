@@ -0,0 +1,164 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Network namespaces.
+//!
+//! Interfaces are only visible in their network namespace, and their
+//! indexes are local to it: index 2 is a different interface in each
+//! container. `NetNs::run()` runs a closure on a thread that has joined the
+//! namespace, so that interface names are resolved and netlink requests
+//! are handled there. The file descriptors of programs, maps and sockets
+//! stay valid across namespaces, and sockets opened in a namespace stay in
+//! it.
+//!
+//! `Program::attach_xdp_netns()`, `Program::attach_tc_netns()` and
+//! `Program::attach_socketfilter_netns()` attach programs to the
+//! interfaces of a namespace:
+//!
+//! ```no_run
+//! use redbpf::netns::NetNs;
+//! use redbpf::{xdp, Module};
+//!
+//! let mut module = Module::parse(&std::fs::read("probe.elf").unwrap()).unwrap();
+//! let netns = NetNs::from_pid(4242).unwrap();
+//! for prog in module.programs.iter_mut() {
+//!     prog.load(module.version, module.license.clone()).unwrap();
+//!     prog.attach_xdp_netns(&netns, "eth0", xdp::Flags::default())
+//!         .unwrap();
+//! }
+//! ```
+//!
+//! Joining a network namespace requires `CAP_SYS_ADMIN`.
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::panic;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use crate::{xdp, Error, Result};
+
+// Where `ip netns add` bind mounts the namespaces it creates
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+/// An open network namespace.
+///
+/// The namespace stays alive while it's open, even after the processes in
+/// it exit. Clones share the file descriptor.
+#[derive(Debug, Clone)]
+pub struct NetNs {
+    fd: Arc<NsFd>,
+}
+
+#[derive(Debug)]
+struct NsFd(RawFd);
+
+impl Drop for NsFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl NetNs {
+    /// Opens the namespace at `path`, eg. `/proc/<pid>/ns/net`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<NetNs> {
+        let cpath = CString::new(path.as_ref().as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+
+        Ok(NetNs {
+            fd: Arc::new(NsFd(fd)),
+        })
+    }
+
+    /// Opens the namespace of the process `pid`.
+    pub fn from_pid(pid: libc::pid_t) -> Result<NetNs> {
+        NetNs::open(format!("/proc/{}/ns/net", pid))
+    }
+
+    /// Opens the namespace `name` created with `ip netns add`.
+    pub fn named(name: &str) -> Result<NetNs> {
+        NetNs::open(Path::new(NETNS_RUN_DIR).join(name))
+    }
+
+    /// Runs `f` on a new thread that joined the namespace, and returns its
+    /// result.
+    ///
+    /// The thread exits when `f` returns, so the calling thread and the
+    /// rest of the process stay in their namespace.
+    pub fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let fd = self.fd.clone();
+        let thread = thread::spawn(move || {
+            if unsafe { libc::setns(fd.0, libc::CLONE_NEWNET) } < 0 {
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
+            f()
+        });
+
+        match thread.join() {
+            Ok(ret) => ret,
+            Err(e) => panic::resume_unwind(e),
+        }
+    }
+
+    /// Returns the index of `iface` in the namespace.
+    pub fn ifindex(&self, iface: &str) -> Result<u32> {
+        let iface = iface.to_string();
+        self.run(move || xdp::ifindex(&iface))
+    }
+}
+
+// Runs `f` in `netns`, or on the calling thread without one
+pub(crate) fn run_in<F, T>(netns: Option<&NetNs>, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match netns {
+        Some(netns) => netns.run(f),
+        None => f(),
+    }
+}
+
+mod test {
+    // Needs root and iproute2. Run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_netns() {
+        use crate::netns::NetNs;
+        use crate::xdp;
+        use std::path::Path;
+        use std::process::Command;
+
+        let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("../scripts/netns-test.sh");
+        let run = |cmd: &str| {
+            let status = Command::new(&script).arg(cmd).status().unwrap();
+            assert!(status.success(), "netns-test.sh {} failed", cmd);
+        };
+        run("setup");
+
+        let netns = NetNs::named("redbpf-test").unwrap();
+        let inside = netns.run(xdp::interfaces);
+        let ifindex = netns.ifindex("rbt1");
+        let outside = xdp::ifindex("rbt1");
+        run("teardown");
+
+        let inside = inside.unwrap();
+        assert!(inside.contains(&"rbt1".to_string()));
+        assert!(!inside.contains(&"rbt0".to_string()));
+        assert!(ifindex.unwrap() > 0);
+        assert!(outside.is_err());
+    }
+}
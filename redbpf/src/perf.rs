@@ -29,6 +29,7 @@
 //!         Event::Lost(lost) => {
 //!             println!("Possibly lost {} samples for {}", lost.count, name);
 //!         }
+//!         Event::TimedSample(_) => unreachable!("timestamps are disabled"),
 //!         Event::Sample(sample) => {
 //!             let sample = unsafe {
 //!                 slice::from_raw_parts(
@@ -50,12 +51,14 @@
 #![allow(clippy::cast_lossless)]
 #![allow(clippy::cast_ptr_alignment)]
 
-use crate::{Error, Map, HashMap, Result};
+use crate::{Error, HashMap, Map, Result, UpdateFlags};
 use std::cell::RefCell;
+use std::ffi::CStr;
+use std::fs;
 use std::io;
 use std::mem;
-use std::os::unix::io::RawFd;
-use std::ptr::null_mut;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{self, null_mut};
 use std::slice;
 use std::sync::atomic::{self, AtomicPtr, Ordering};
 
@@ -66,16 +69,73 @@ use libc::{
 
 use crate::sys::perf::*;
 
-unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Result<RawFd> {
-    let mut attr = mem::zeroed::<perf_event_attr>();
+/// When the reader of a perf buffer is woken up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wakeup {
+    /// After the given number of samples
+    Events(u32),
+    /// When the buffer holds at least the given number of bytes
+    Watermark(u32),
+}
+
+/// Configuration of the perf buffers bound with `PerfMap::bind_with()`.
+///
+/// Larger buffers lose fewer samples under load, and waking up the reader
+/// less often lowers the overhead of busy probes at the cost of latency.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PerfBufferConfig {
+    /// The size of the buffer in pages, must be a power of two
+    pub page_count: usize,
+    pub wakeup: Wakeup,
+    /// Records the time of the samples with `CLOCK_MONOTONIC`, see
+    /// `TimedSample`. Every sample grows by 8 bytes.
+    pub timestamps: bool,
+}
+
+impl Default for PerfBufferConfig {
+    fn default() -> Self {
+        PerfBufferConfig {
+            page_count: 16,
+            wakeup: Wakeup::Events(1),
+            timestamps: false,
+        }
+    }
+}
+
+fn perf_buffer_attr(config: &PerfBufferConfig) -> perf_event_attr {
+    let mut attr = unsafe { mem::zeroed::<perf_event_attr>() };
 
     attr.config = perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT as u64;
     attr.size = mem::size_of::<perf_event_attr>() as u32;
     attr.type_ = perf_type_id_PERF_TYPE_SOFTWARE;
     attr.sample_type = perf_event_sample_format_PERF_SAMPLE_RAW as u64;
+    if config.timestamps {
+        attr.sample_type |= perf_event_sample_format_PERF_SAMPLE_TIME as u64;
+        // perf events default to the scheduler clock, bpf_ktime_get_ns()
+        // reads CLOCK_MONOTONIC
+        attr.set_use_clockid(1);
+        attr.clockid = libc::CLOCK_MONOTONIC;
+    }
     attr.__bindgen_anon_1.sample_period = 1;
-    attr.__bindgen_anon_2.wakeup_events = 1;
+    match config.wakeup {
+        Wakeup::Events(events) => attr.__bindgen_anon_2.wakeup_events = events,
+        Wakeup::Watermark(bytes) => {
+            attr.set_watermark(1);
+            attr.__bindgen_anon_2.wakeup_watermark = bytes;
+        }
+    }
+
+    attr
+}
 
+unsafe fn open_perf_buffer(
+    pid: i32,
+    cpu: i32,
+    group: RawFd,
+    flags: u32,
+    config: &PerfBufferConfig,
+) -> Result<RawFd> {
+    let attr = perf_buffer_attr(config);
     let pfd = syscall(
         SYS_perf_event_open,
         &attr as *const perf_event_attr,
@@ -91,6 +151,230 @@ unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Resu
     }
 }
 
+/// Hardware counters that can be opened with `PerfEventAttr::hardware()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HardwareCounter {
+    CpuCycles,
+    Instructions,
+    CacheReferences,
+    CacheMisses,
+    BranchInstructions,
+    BranchMisses,
+}
+
+/// Configuration of the counters opened by
+/// `PerfEventArray::attach_perf_counters()`.
+///
+/// `type_` and `config` are the fields of the same name of
+/// `perf_event_attr`, see `perf_event_open(2)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PerfEventAttr {
+    pub type_: u32,
+    pub config: u64,
+}
+
+impl PerfEventAttr {
+    pub fn new(type_: u32, config: u64) -> Self {
+        PerfEventAttr { type_, config }
+    }
+
+    /// Returns the configuration of a generic hardware counter.
+    pub fn hardware(counter: HardwareCounter) -> Self {
+        let config = match counter {
+            HardwareCounter::CpuCycles => perf_hw_id_PERF_COUNT_HW_CPU_CYCLES,
+            HardwareCounter::Instructions => perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS,
+            HardwareCounter::CacheReferences => perf_hw_id_PERF_COUNT_HW_CACHE_REFERENCES,
+            HardwareCounter::CacheMisses => perf_hw_id_PERF_COUNT_HW_CACHE_MISSES,
+            HardwareCounter::BranchInstructions => perf_hw_id_PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
+            HardwareCounter::BranchMisses => perf_hw_id_PERF_COUNT_HW_BRANCH_MISSES,
+        };
+        PerfEventAttr::new(perf_type_id_PERF_TYPE_HARDWARE, config as u64)
+    }
+
+    /// Returns the configuration of a software event.
+    pub fn software(event: SoftwareEvent) -> Self {
+        let config = match event {
+            SoftwareEvent::CpuClock => perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK,
+            SoftwareEvent::TaskClock => perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK,
+            SoftwareEvent::PageFaults => perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS,
+            SoftwareEvent::ContextSwitches => perf_sw_ids_PERF_COUNT_SW_CONTEXT_SWITCHES,
+            SoftwareEvent::CpuMigrations => perf_sw_ids_PERF_COUNT_SW_CPU_MIGRATIONS,
+        };
+        PerfEventAttr::new(perf_type_id_PERF_TYPE_SOFTWARE, config as u64)
+    }
+}
+
+/// Software events that can be opened with `PerfEventAttr::software()`.
+///
+/// Unlike hardware counters, they are available in virtual machines.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SoftwareEvent {
+    CpuClock,
+    TaskClock,
+    PageFaults,
+    ContextSwitches,
+    CpuMigrations,
+}
+
+/// How often a sampled event runs the program.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SampleRate {
+    /// Samples per second. The kernel adjusts the period to reach it.
+    Frequency(u64),
+    /// Number of occurrences of the event between samples, eg. CPU cycles.
+    Period(u64),
+}
+
+/// Configuration of the events sampled by `Program::attach_sampling()`.
+///
+/// Profilers usually sample the CPU clock at an odd frequency, so that
+/// samples don't line up with timers:
+///
+/// ```
+/// use redbpf::{PerfEventAttr, SampleRate, SampleSpec, SoftwareEvent};
+///
+/// let spec = SampleSpec {
+///     event: PerfEventAttr::software(SoftwareEvent::CpuClock),
+///     rate: SampleRate::Frequency(99),
+/// };
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SampleSpec {
+    pub event: PerfEventAttr,
+    pub rate: SampleRate,
+}
+
+// Opens a counter for all the tasks running on `cpu`. The kernel fails with
+// ENOENT or EOPNOTSUPP when there's no PMU providing the counter, which is
+// common in virtual machines.
+pub(crate) unsafe fn open_perf_counter(config: &PerfEventAttr, cpu: i32) -> Result<RawFd> {
+    let mut attr = mem::zeroed::<perf_event_attr>();
+
+    attr.type_ = config.type_;
+    attr.config = config.config;
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+
+    let pfd = syscall(
+        SYS_perf_event_open,
+        &attr as *const perf_event_attr,
+        -1,
+        cpu,
+        -1,
+        PERF_FLAG_FD_CLOEXEC,
+    );
+    if pfd < 0 {
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ENOENT) | Some(libc::EOPNOTSUPP) | Some(libc::ENODEV) => {
+                Err(Error::CounterUnavailable(*config))
+            }
+            _ => Err(Error::IO(e)),
+        }
+    } else {
+        Ok(pfd as RawFd)
+    }
+}
+
+// Opens the sampled event of `spec` for all the tasks running on `cpu`, and
+// attaches the program `prog_fd` to it.
+pub(crate) unsafe fn open_sampling_event(
+    prog_fd: RawFd,
+    spec: &SampleSpec,
+    cpu: i32,
+) -> Result<RawFd> {
+    let mut attr = mem::zeroed::<perf_event_attr>();
+
+    attr.type_ = spec.event.type_;
+    attr.config = spec.event.config;
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+    match spec.rate {
+        SampleRate::Frequency(freq) => {
+            attr.set_freq(1);
+            attr.__bindgen_anon_1.sample_freq = freq;
+        }
+        SampleRate::Period(period) => attr.__bindgen_anon_1.sample_period = period,
+    }
+
+    let pfd = syscall(
+        SYS_perf_event_open,
+        &attr as *const perf_event_attr,
+        -1,
+        cpu,
+        -1,
+        PERF_FLAG_FD_CLOEXEC,
+    );
+    if pfd < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOENT) | Some(libc::EOPNOTSUPP) | Some(libc::ENODEV) => {
+                Err(Error::CounterUnavailable(spec.event))
+            }
+            _ => Err(Error::IO(e)),
+        };
+    }
+    let pfd = pfd as RawFd;
+    if ioctl(pfd, PERF_EVENT_IOC_SET_BPF, prog_fd) != 0 || ioctl(pfd, PERF_EVENT_IOC_ENABLE, 0) != 0
+    {
+        let e = io::Error::last_os_error();
+        close(pfd);
+        return Err(Error::IO(e));
+    }
+
+    Ok(pfd)
+}
+
+const UPROBE_PMU_TYPE: &str = "/sys/bus/event_source/devices/uprobe/type";
+
+// Opens an entry uprobe at `offset` in `path` through the uprobe PMU, with
+// the reference counter at `ref_ctr_offset` incremented by the kernel while
+// it's attached, and attaches the program `prog_fd` to it. Reference
+// counters require Linux 4.20.
+pub(crate) unsafe fn open_uprobe(
+    prog_fd: RawFd,
+    path: &CStr,
+    offset: u64,
+    ref_ctr_offset: u64,
+    pid: i32,
+) -> Result<RawFd> {
+    let pmu_type = fs::read_to_string(UPROBE_PMU_TYPE)?;
+    let pmu_type = pmu_type
+        .trim()
+        .parse()
+        .map_err(|_| Error::IO(io::Error::from_raw_os_error(libc::EINVAL)))?;
+    let mut attr = mem::zeroed::<perf_event_attr>();
+
+    attr.type_ = pmu_type;
+    attr.size = mem::size_of::<perf_event_attr>() as u32;
+    attr.config = ref_ctr_offset << 32;
+    attr.__bindgen_anon_3.config1 = path.as_ptr() as u64;
+    attr.__bindgen_anon_4.config2 = offset;
+    attr.__bindgen_anon_1.sample_period = 1;
+    attr.__bindgen_anon_2.wakeup_events = 1;
+
+    // probes of all the processes are opened on any CPU, like bcc does
+    let cpu = if pid == -1 { 0 } else { -1 };
+    let pfd = syscall(
+        SYS_perf_event_open,
+        &attr as *const perf_event_attr,
+        pid,
+        cpu,
+        -1,
+        PERF_FLAG_FD_CLOEXEC,
+    );
+    if pfd < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+    let pfd = pfd as RawFd;
+    if ioctl(pfd, PERF_EVENT_IOC_SET_BPF, prog_fd) != 0 || ioctl(pfd, PERF_EVENT_IOC_ENABLE, 0) != 0
+    {
+        let e = io::Error::last_os_error();
+        close(pfd);
+        return Err(Error::IO(e));
+    }
+
+    Ok(pfd)
+}
+
 #[repr(C)]
 pub struct Sample {
     header: perf_event_header,
@@ -105,8 +389,56 @@ pub struct LostSamples {
     pub count: u64,
 }
 
+/// A sample of a perf buffer recording timestamps, see
+/// `PerfBufferConfig::timestamps`.
+#[repr(C)]
+pub struct TimedSample {
+    header: perf_event_header,
+    /// The time of the sample in nanoseconds, from `CLOCK_MONOTONIC` like
+    /// `bpf_ktime_get_ns()`
+    pub time: u64,
+    pub size: u32,
+    pub data: [u8; 0],
+}
+
+impl TimedSample {
+    /// Returns the data of the sample. See `Sample::raw_data()`.
+    pub fn raw_data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data.as_ptr(), self.size as usize) }
+    }
+}
+
+impl Sample {
+    /// Returns the data of the sample.
+    ///
+    /// The kernel pads samples to 8 bytes, so the data can be longer than
+    /// what the program output.
+    pub fn raw_data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data.as_ptr(), self.size as usize) }
+    }
+}
+
+/// Splits the data of a sample into a header of type `T` and the variable
+/// length payload following it.
+///
+/// Returns `None` if `data` is shorter than `T`.
+///
+/// # Safety
+///
+/// The caller must ensure the sample starts with a valid `T`.
+pub unsafe fn split_sample<T>(data: &[u8]) -> Option<(T, &[u8])> {
+    if data.len() < mem::size_of::<T>() {
+        return None;
+    }
+
+    let header = ptr::read_unaligned(data.as_ptr() as *const T);
+    Some((header, &data[mem::size_of::<T>()..]))
+}
+
 pub enum Event<'a> {
     Sample(&'a Sample),
+    /// A sample of a buffer bound with `PerfBufferConfig::timestamps` set
+    TimedSample(&'a TimedSample),
     Lost(&'a LostSamples),
 }
 
@@ -116,20 +448,43 @@ pub struct PerfMap {
     page_size: usize,
     mmap_size: usize,
     buf: RefCell<Vec<u8>>,
+    timestamps: bool,
     pub fd: RawFd,
 }
 
 impl PerfMap {
     pub fn bind(
-        map: &mut Map,
+        map: &Map,
         pid: i32,
         cpu: i32,
         page_cnt: usize,
         group: RawFd,
         flags: u32,
     ) -> Result<PerfMap> {
+        let config = PerfBufferConfig {
+            page_count: page_cnt,
+            ..PerfBufferConfig::default()
+        };
+        PerfMap::bind_config(map, pid, cpu, group, flags, config)
+    }
+
+    /// Binds a perf buffer for all the processes running on `cpu` to `map`,
+    /// configured with `config`.
+    pub fn bind_with(map: &Map, cpu: i32, config: PerfBufferConfig) -> Result<PerfMap> {
+        PerfMap::bind_config(map, -1, cpu, -1, 0, config)
+    }
+
+    fn bind_config(
+        map: &Map,
+        pid: i32,
+        cpu: i32,
+        group: RawFd,
+        flags: u32,
+        config: PerfBufferConfig,
+    ) -> Result<PerfMap> {
+        let page_cnt = config.page_count;
         unsafe {
-            let fd = open_perf_buffer(pid, cpu, group, flags)?;
+            let fd = open_perf_buffer(pid, cpu, group, flags, &config)?;
             let page_size = sysconf(_SC_PAGESIZE) as usize;
             let mmap_size = page_size * (page_cnt + 1);
             let base_ptr = mmap(
@@ -142,73 +497,138 @@ impl PerfMap {
             );
 
             if base_ptr == MAP_FAILED {
-                return Err(Error::IO(io::Error::last_os_error()));
-            }
-
-            if ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) != 0 {
-                return Err(Error::IO(io::Error::last_os_error()));
+                let e = io::Error::last_os_error();
+                close(fd);
+                return Err(Error::IO(e));
             }
-
-            let tm = HashMap::<i32, i32>::new(map).unwrap();
-            tm.set(cpu, fd);
-
-            Ok(PerfMap {
+            // unmapped and closed on errors from here on
+            let perf_map = PerfMap {
                 base_ptr: AtomicPtr::new(base_ptr as *mut perf_event_mmap_page),
                 buf: RefCell::new(vec![]),
                 page_cnt,
                 page_size,
                 mmap_size,
+                timestamps: config.timestamps,
                 fd,
-            })
+            };
+
+            if ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) != 0 {
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
+
+            // fails if the array has no entry for the CPU, whose events
+            // would be lost
+            HashMap::<i32, i32>::new(map)?.set_flags(cpu, fd, UpdateFlags::Any)?;
+
+            Ok(perf_map)
         }
     }
 
+    /// Returns the next sample or lost samples record, skipping the other
+    /// records.
     pub fn read(&self) -> Option<Event<'_>> {
+        loop {
+            unsafe {
+                let header = self.base_ptr.load(Ordering::SeqCst);
+                let data_head = (*header).data_head;
+                let data_tail = (*header).data_tail;
+                let raw_size = (self.page_cnt * self.page_size) as u64;
+                let base = (header as *const u8).add(self.page_size);
+
+                if data_tail == data_head {
+                    return None;
+                }
+
+                let start = (data_tail % raw_size) as usize;
+                let event = base.add(start) as *const perf_event_header;
+                let end = ((data_tail + (*event).size as u64) % raw_size) as usize;
+
+                let mut buf = self.buf.borrow_mut();
+                buf.clear();
+
+                if end < start {
+                    let len = (raw_size as usize - start) as usize;
+                    let ptr = base.add(start);
+                    buf.extend_from_slice(slice::from_raw_parts(ptr, len));
+
+                    let len = (*event).size as usize - len;
+                    let ptr = base;
+                    buf.extend_from_slice(slice::from_raw_parts(ptr, len));
+                } else {
+                    let ptr = base.add(start);
+                    let len = (*event).size as usize;
+                    buf.extend_from_slice(slice::from_raw_parts(ptr, len));
+                }
+
+                // the record can be overwritten once the tail moves past it
+                let event_type = (*event).type_;
+                atomic::fence(Ordering::SeqCst);
+                (*header).data_tail += (*event).size as u64;
+
+                match event_type {
+                    perf_event_type_PERF_RECORD_SAMPLE if self.timestamps => {
+                        return Some(Event::TimedSample(&*(buf.as_ptr() as *const TimedSample)))
+                    }
+                    perf_event_type_PERF_RECORD_SAMPLE => {
+                        return Some(Event::Sample(&*(buf.as_ptr() as *const Sample)))
+                    }
+                    perf_event_type_PERF_RECORD_LOST => {
+                        return Some(Event::Lost(&*(buf.as_ptr() as *const LostSamples)))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Calls `f` with the type and the bytes of each record. Records are
+    // passed in place, unless they wrap around the end of the buffer and get
+    // copied to `buf`. The tail moves past a record once `f` returns.
+    pub(crate) fn consume_records<F>(&self, buf: &mut Vec<u8>, mut f: F)
+    where
+        F: FnMut(u32, &[u8]),
+    {
         unsafe {
             let header = self.base_ptr.load(Ordering::SeqCst);
-            let data_head = (*header).data_head;
-            let data_tail = (*header).data_tail;
             let raw_size = (self.page_cnt * self.page_size) as u64;
             let base = (header as *const u8).add(self.page_size);
+            loop {
+                let data_head = ptr::read_volatile(&(*header).data_head);
+                atomic::fence(Ordering::Acquire);
+                let data_tail = (*header).data_tail;
+                if data_tail == data_head {
+                    return;
+                }
 
-            if data_tail == data_head {
-                return None;
-            }
+                let start = (data_tail % raw_size) as usize;
+                let event = base.add(start) as *const perf_event_header;
+                let size = (*event).size as usize;
+                if start + size > raw_size as usize {
+                    let len = raw_size as usize - start;
+                    buf.clear();
+                    buf.extend_from_slice(slice::from_raw_parts(base.add(start), len));
+                    buf.extend_from_slice(slice::from_raw_parts(base, size - len));
+                    f((*event).type_, buf);
+                } else {
+                    f((*event).type_, slice::from_raw_parts(base.add(start), size));
+                }
 
-            let start = (data_tail % raw_size) as usize;
-            let event = base.add(start) as *const perf_event_header;
-            let end = ((data_tail + (*event).size as u64) % raw_size) as usize;
-
-            let mut buf = self.buf.borrow_mut();
-            buf.clear();
-
-            if end < start {
-                let len = (raw_size as usize - start) as usize;
-                let ptr = base.add(start);
-                buf.extend_from_slice(slice::from_raw_parts(ptr, len));
-
-                let len = (*event).size as usize - len;
-                let ptr = base;
-                buf.extend_from_slice(slice::from_raw_parts(ptr, len));
-            } else {
-                let ptr = base.add(start);
-                let len = (*event).size as usize;
-                buf.extend_from_slice(slice::from_raw_parts(ptr, len));
+                atomic::fence(Ordering::SeqCst);
+                (*header).data_tail += size as u64;
             }
+        }
+    }
 
-            atomic::fence(Ordering::SeqCst);
-            (*header).data_tail += (*event).size as u64;
+    pub(crate) fn timestamps(&self) -> bool {
+        self.timestamps
+    }
+}
 
-            match (*event).type_ {
-                perf_event_type_PERF_RECORD_SAMPLE => {
-                    Some(Event::Sample(&*(buf.as_ptr() as *const Sample)))
-                }
-                perf_event_type_PERF_RECORD_LOST => {
-                    Some(Event::Lost(&*(buf.as_ptr() as *const LostSamples)))
-                }
-                _ => None,
-            }
-        }
+impl AsRawFd for PerfMap {
+    /// Returns the file descriptor of the perf event, readable when the
+    /// buffer has events.
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
     }
 }
 
@@ -224,3 +644,42 @@ impl Drop for PerfMap {
         }
     }
 }
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::perf::split_sample;
+
+        #[repr(C)]
+        struct Header {
+            len: u32,
+            proto: u16,
+        }
+
+        let mut data = vec![6u8, 0, 0, 0, 17, 0, 0, 0];
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0, 0]);
+        let (header, payload) = unsafe { split_sample::<Header>(&data) }.unwrap();
+        assert_eq!((header.len, header.proto), (6, 17));
+        assert_eq!(&payload[..header.len as usize], &[1, 2, 3, 4, 5, 6]);
+        assert!(unsafe { split_sample::<Header>(&data[..4]) }.is_none());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        use crate::perf::{perf_buffer_attr, PerfBufferConfig};
+        use crate::sys::perf::perf_event_sample_format_PERF_SAMPLE_TIME;
+
+        let time = perf_event_sample_format_PERF_SAMPLE_TIME as u64;
+        let attr = perf_buffer_attr(&PerfBufferConfig::default());
+        assert_eq!(attr.sample_type & time, 0);
+        assert_eq!(attr.use_clockid(), 0);
+
+        let attr = perf_buffer_attr(&PerfBufferConfig {
+            timestamps: true,
+            ..Default::default()
+        });
+        assert_eq!(attr.sample_type & time, time);
+        assert_eq!(attr.use_clockid(), 1);
+        assert_eq!(attr.clockid, libc::CLOCK_MONOTONIC);
+    }
+}
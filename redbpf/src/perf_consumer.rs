@@ -0,0 +1,431 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Consuming perf buffers with one thread per CPU.
+//!
+//! At high event rates a single reader of all the perf buffers falls
+//! behind and the kernel drops samples. `PerfMap::consume_per_cpu()` reads
+//! the buffer of each online CPU on its own thread, optionally pinned to
+//! that CPU, and calls back with the data of each sample while it's still
+//! in the buffer:
+//!
+//! ```no_run
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//! use std::sync::Arc;
+//! use std::thread;
+//! use std::time::Duration;
+//! use redbpf::{CpuPolicy, Module, PerfBufferConfig, PerfMap};
+//!
+//! let module = Module::load_file("probe.elf").unwrap();
+//! let map = module.map("events").unwrap();
+//! let bytes = Arc::new(AtomicU64::new(0));
+//! let counter = bytes.clone();
+//! let consumer = PerfMap::consume_per_cpu(
+//!     map,
+//!     PerfBufferConfig::default(),
+//!     CpuPolicy::Pinned,
+//!     move |_cpu, data| {
+//!         counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+//!     },
+//! )
+//! .unwrap();
+//! let stop = consumer.stop_handle();
+//! thread::spawn(move || {
+//!     thread::sleep(Duration::from_secs(10));
+//!     stop.stop();
+//! });
+//! // returns once stopped, and resumes panics of the callback
+//! consumer.join().unwrap();
+//! println!("{} bytes", bytes.load(Ordering::Relaxed));
+//! ```
+//!
+//! The callback runs on the worker threads, so it must be cheap: while it
+//! runs, the buffer of its CPU isn't read. Buffers are only opened for the
+//! CPUs online when the consumer starts.
+#![allow(non_upper_case_globals)]
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::panic;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+
+use crate::cpus::{self, CpuId};
+use crate::sys::perf::{perf_event_type_PERF_RECORD_LOST, perf_event_type_PERF_RECORD_SAMPLE};
+use crate::{check_cpu_entries, Error, Map, PerfBufferConfig, PerfMap, Result};
+
+// The size of `struct perf_event_header`
+const HEADER_SIZE: usize = 8;
+
+/// Where the threads of `PerfMap::consume_per_cpu()` run.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuPolicy {
+    /// Each thread runs on the CPU whose buffer it reads, so the samples
+    /// are still in its cache. The thread competes with the traced
+    /// workload of the CPU.
+    Pinned,
+    /// The scheduler moves the threads across CPUs.
+    Unpinned,
+}
+
+/// The threads reading perf buffers, returned by
+/// `PerfMap::consume_per_cpu()`.
+///
+/// Dropping the consumer stops the threads and waits for them, discarding
+/// their panics. Use `join()` to get them.
+pub struct PerfConsumer {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<io::Result<()>>>,
+}
+
+/// Stops a `PerfConsumer` from another thread, or from a signal handler
+/// thread.
+#[derive(Clone)]
+pub struct PerfStopHandle {
+    shared: Arc<Shared>,
+}
+
+// The state shared by the threads of a consumer
+struct Shared {
+    stopped: AtomicBool,
+    // an eventfd made readable to wake up the threads when stopping
+    wakeup: RawFd,
+    lost: AtomicU64,
+}
+
+impl Shared {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let one = 1u64;
+        unsafe {
+            libc::write(
+                self.wakeup,
+                &one as *const u64 as *const _,
+                mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.wakeup) };
+    }
+}
+
+// Closes the epoll fd of a thread, and stops all the threads when it
+// panics
+struct ThreadGuard {
+    shared: Arc<Shared>,
+    epoll: RawFd,
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epoll) };
+        if thread::panicking() {
+            self.shared.stop();
+        }
+    }
+}
+
+impl PerfMap {
+    /// Reads the perf buffers of `map` with one thread for each online
+    /// CPU, and calls `callback` with the CPU and the data of each sample.
+    ///
+    /// The data isn't copied out of the buffer, unless the sample wraps
+    /// around its end. Samples the kernel dropped are counted by
+    /// `PerfConsumer::lost_samples()`. Works without an async runtime.
+    ///
+    /// Fails if a buffer can't be opened, if a thread can't be pinned to its
+    /// CPU with `CpuPolicy::Pinned`, or with `Error::CpuEntries` if `map`
+    /// can't be indexed by every possible CPU.
+    pub fn consume_per_cpu<F>(
+        map: &Map,
+        config: PerfBufferConfig,
+        policy: CpuPolicy,
+        callback: F,
+    ) -> Result<PerfConsumer>
+    where
+        F: Fn(CpuId, &[u8]) + Send + Sync + 'static,
+    {
+        check_cpu_entries(map)?;
+        let wakeup = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wakeup < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        let shared = Arc::new(Shared {
+            stopped: AtomicBool::new(false),
+            wakeup,
+            lost: AtomicU64::new(0),
+        });
+        let mut consumer = PerfConsumer {
+            shared,
+            threads: Vec::new(),
+        };
+        let callback = Arc::new(callback);
+        let (started, results) = mpsc::channel();
+        for cpu in cpus::get_online()? {
+            let perf_map = PerfMap::bind_with(map, cpu, config)?;
+            let shared = consumer.shared.clone();
+            let callback = callback.clone();
+            let started = started.clone();
+            let thread = thread::Builder::new()
+                .name(format!("perf-cpu{}", cpu))
+                .spawn(move || {
+                    let pinned = match policy {
+                        CpuPolicy::Pinned => pin_to(cpu),
+                        CpuPolicy::Unpinned => Ok(()),
+                    };
+                    let failed = pinned.is_err();
+                    let _ = started.send(pinned);
+                    if failed {
+                        return Ok(());
+                    }
+                    consume(perf_map, cpu, shared, &*callback)
+                })?;
+            consumer.threads.push(thread);
+        }
+        drop(started);
+
+        // the consumer is dropped on errors, which stops the threads
+        for _ in 0..consumer.threads.len() {
+            if let Ok(Err(e)) = results.recv() {
+                return Err(Error::IO(e));
+            }
+        }
+
+        Ok(consumer)
+    }
+}
+
+impl PerfConsumer {
+    /// Returns a handle that stops the consumer, see `stop()`.
+    pub fn stop_handle(&self) -> PerfStopHandle {
+        PerfStopHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Stops the threads once they've read the samples left in their
+    /// buffers. Doesn't wait for them, see `join()`.
+    pub fn stop(&self) {
+        self.shared.stop();
+    }
+
+    /// Returns the number of samples the kernel dropped so far.
+    pub fn lost_samples(&self) -> u64 {
+        self.shared.lost.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the threads to exit, after `stop()` was called or the
+    /// callback panicked.
+    ///
+    /// A panic of the callback stops all the threads, and is resumed on
+    /// the calling thread once they exited. Otherwise returns the first
+    /// error waiting for samples.
+    pub fn join(mut self) -> Result<()> {
+        let mut ret = Ok(());
+        let mut panicked = None;
+        for thread in self.threads.drain(..) {
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    if ret.is_ok() {
+                        ret = Err(Error::IO(e));
+                    }
+                }
+                Err(payload) => {
+                    if panicked.is_none() {
+                        panicked = Some(payload);
+                    }
+                }
+            }
+        }
+        if let Some(payload) = panicked {
+            panic::resume_unwind(payload);
+        }
+
+        ret
+    }
+}
+
+impl Drop for PerfConsumer {
+    fn drop(&mut self) {
+        self.shared.stop();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl PerfStopHandle {
+    /// Stops the consumer, see `PerfConsumer::stop()`.
+    pub fn stop(&self) {
+        self.shared.stop();
+    }
+}
+
+fn pin_to(cpu: CpuId) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu as usize, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+// Reads the buffer of `cpu` until the consumer is stopped
+fn consume<F>(map: PerfMap, cpu: CpuId, shared: Arc<Shared>, callback: &F) -> io::Result<()>
+where
+    F: Fn(CpuId, &[u8]),
+{
+    let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if epoll < 0 {
+        let e = io::Error::last_os_error();
+        shared.stop();
+        return Err(e);
+    }
+    let _guard = ThreadGuard {
+        shared: shared.clone(),
+        epoll,
+    };
+    let ret = wait_loop(epoll, &map, cpu, &shared, callback);
+    if ret.is_err() {
+        shared.stop();
+    }
+
+    ret
+}
+
+fn wait_loop<F>(
+    epoll: RawFd,
+    map: &PerfMap,
+    cpu: CpuId,
+    shared: &Shared,
+    callback: &F,
+) -> io::Result<()>
+where
+    F: Fn(CpuId, &[u8]),
+{
+    for fd in [map.fd, shared.wakeup].iter() {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: *fd as u64,
+        };
+        if unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, *fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let timestamps = map.timestamps();
+    let mut buf = Vec::new();
+    let mut read = || {
+        map.consume_records(&mut buf, |type_, record| {
+            match record_data(type_, record, timestamps) {
+                Some(Record::Sample(data)) => callback(cpu, data),
+                Some(Record::Lost(count)) => {
+                    shared.lost.fetch_add(count, Ordering::Relaxed);
+                }
+                None => {}
+            }
+        })
+    };
+    let mut events = [libc::epoll_event { events: 0, u64: 0 }; 2];
+    // the wakeup eventfd stays readable once stopped, so checking the flag
+    // before waiting can't miss it
+    while !shared.stopped.load(Ordering::SeqCst) {
+        read();
+        let ret = unsafe { libc::epoll_wait(epoll, events.as_mut_ptr(), 2, -1) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+    // drain what's left before the buffer is unmapped
+    read();
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Record<'a> {
+    Sample(&'a [u8]),
+    Lost(u64),
+}
+
+// Parses a record of a perf buffer. Samples are the header, the time if
+// `timestamps`, the size as a u32 and the data. Lost records are the
+// header, an id and the count.
+fn record_data(type_: u32, record: &[u8], timestamps: bool) -> Option<Record<'_>> {
+    let read_u32 = |at: usize| {
+        record
+            .get(at..at + 4)
+            .map(|b| unsafe { ptr::read_unaligned(b.as_ptr() as *const u32) })
+    };
+    let read_u64 = |at: usize| {
+        record
+            .get(at..at + 8)
+            .map(|b| unsafe { ptr::read_unaligned(b.as_ptr() as *const u64) })
+    };
+    match type_ {
+        perf_event_type_PERF_RECORD_SAMPLE => {
+            let at = HEADER_SIZE + if timestamps { 8 } else { 0 };
+            let size = read_u32(at)? as usize;
+            record.get(at + 4..at + 4 + size).map(Record::Sample)
+        }
+        perf_event_type_PERF_RECORD_LOST => read_u64(HEADER_SIZE + 8).map(Record::Lost),
+        _ => None,
+    }
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::perf_consumer::{record_data, Record};
+        use crate::sys::perf::{
+            perf_event_type_PERF_RECORD_LOST, perf_event_type_PERF_RECORD_SAMPLE,
+        };
+
+        let mut sample = vec![0u8; 8];
+        sample.extend_from_slice(&3u32.to_ne_bytes());
+        sample.extend_from_slice(&[1, 2, 3, 0]);
+        assert_eq!(
+            record_data(perf_event_type_PERF_RECORD_SAMPLE, &sample, false),
+            Some(Record::Sample(&[1, 2, 3]))
+        );
+
+        let mut timed = vec![0u8; 8];
+        timed.extend_from_slice(&42u64.to_ne_bytes());
+        timed.extend_from_slice(&sample[8..]);
+        assert_eq!(
+            record_data(perf_event_type_PERF_RECORD_SAMPLE, &timed, true),
+            Some(Record::Sample(&[1, 2, 3]))
+        );
+        // a size past the end of the record
+        assert_eq!(
+            record_data(perf_event_type_PERF_RECORD_SAMPLE, &timed, false),
+            None
+        );
+
+        let mut lost = vec![0u8; 16];
+        lost.extend_from_slice(&7u64.to_ne_bytes());
+        assert_eq!(
+            record_data(perf_event_type_PERF_RECORD_LOST, &lost, false),
+            Some(Record::Lost(7))
+        );
+        assert_eq!(record_data(0, &lost, false), None);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Folding of sampled stack traces.
+//!
+//! Profilers usually count samples in a map keyed by `StackKey`, and store
+//! the stacks themselves in a stack trace map. `FoldedStacks` joins the two
+//! and produces the folded format understood by flamegraph tools, one
+//! `comm;frame1;frame2 count` line per key:
+//!
+//! ```no_run
+//! use redbpf::profile::{Addresses, FoldedStacks, StackKey, StackTraces};
+//! # struct Stacks;
+//! # impl StackTraces for Stacks {
+//! #     fn stack(&self, id: i32) -> Option<Vec<u64>> { None }
+//! # }
+//! # let stacks = Stacks;
+//! # let counts: Vec<(StackKey, u64)> = vec![];
+//! for line in FoldedStacks::new(counts.into_iter(), &stacks, &Addresses, &Addresses)
+//!     .annotate(true)
+//!     .collapse_idle(true)
+//! {
+//!     println!("{}", line);
+//! }
+//! ```
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// Key of the map counting samples.
+///
+/// The stack ids are the values returned by `bpf_get_stackid()`, negative
+/// values mean the stack couldn't be collected.
+///
+/// NB: this needs to be kept in sync with the key used by the probes.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct StackKey {
+    pub pid: u32,
+    pub kernel_stack_id: i32,
+    pub user_stack_id: i32,
+    pub comm: [u8; 16],
+}
+
+impl StackKey {
+    /// Returns the command name of the sampled task.
+    pub fn comm(&self) -> String {
+        let len = self.comm.iter().position(|c| *c == 0).unwrap_or(16);
+        String::from_utf8_lossy(&self.comm[..len]).into_owned()
+    }
+}
+
+/// Source of stack traces.
+pub trait StackTraces {
+    /// Returns the instruction pointers of the stack `id`, innermost frame
+    /// first.
+    fn stack(&self, id: i32) -> Option<Vec<u64>>;
+}
+
+/// Maps addresses to symbol names.
+pub trait Symbolizer {
+    /// Returns the name of the symbol containing `addr` in the address space
+    /// of `pid`.
+    fn symbol(&self, pid: u32, addr: u64) -> Option<String>;
+}
+
+/// Symbolizer formatting addresses as hex numbers.
+pub struct Addresses;
+
+impl Symbolizer for Addresses {
+    fn symbol(&self, _pid: u32, addr: u64) -> Option<String> {
+        Some(format!("0x{:x}", addr))
+    }
+}
+
+/// Iterator producing folded stack lines.
+///
+/// Lines are produced as the counts are read, so keys sharing the same
+/// stacks can result in duplicate lines. Flamegraph tools add them up.
+pub struct FoldedStacks<'a, I> {
+    counts: I,
+    stacks: &'a dyn StackTraces,
+    kernel: &'a dyn Symbolizer,
+    user: &'a dyn Symbolizer,
+    annotate: bool,
+    collapse_idle: bool,
+}
+
+impl<'a, I: Iterator<Item = (StackKey, u64)>> FoldedStacks<'a, I> {
+    /// Creates an iterator folding the `(key, count)` pairs in `counts`,
+    /// resolving frames with the `kernel` and `user` symbolizers.
+    pub fn new(
+        counts: I,
+        stacks: &'a dyn StackTraces,
+        kernel: &'a dyn Symbolizer,
+        user: &'a dyn Symbolizer,
+    ) -> Self {
+        FoldedStacks {
+            counts,
+            stacks,
+            kernel,
+            user,
+            annotate: false,
+            collapse_idle: false,
+        }
+    }
+
+    /// Appends `_[k]` to kernel frames, so that flamegraph tools can color
+    /// them differently.
+    pub fn annotate(mut self, annotate: bool) -> Self {
+        self.annotate = annotate;
+        self
+    }
+
+    /// Folds the samples of the idle tasks into a single `swapper` frame.
+    pub fn collapse_idle(mut self, collapse: bool) -> Self {
+        self.collapse_idle = collapse;
+        self
+    }
+
+    fn fold_line(&self, key: &StackKey, count: u64) -> String {
+        if self.collapse_idle && key.pid == 0 {
+            return format!("swapper {}", count);
+        }
+
+        let mut frames = vec![key.comm()];
+        if key.user_stack_id >= 0 || key.kernel_stack_id < 0 {
+            self.push_frames(&mut frames, key.pid, key.user_stack_id, false);
+        }
+        if key.kernel_stack_id >= 0 || key.user_stack_id < 0 {
+            self.push_frames(&mut frames, 0, key.kernel_stack_id, true);
+        }
+
+        format!("{} {}", frames.join(";"), count)
+    }
+
+    fn push_frames(&self, frames: &mut Vec<String>, pid: u32, id: i32, kernel: bool) {
+        let (kind, symbolizer, suffix) = match (kernel, self.annotate) {
+            (true, true) => ("kernel", self.kernel, "_[k]"),
+            (true, false) => ("kernel", self.kernel, ""),
+            (false, _) => ("user", self.user, ""),
+        };
+        let stack = if id >= 0 { self.stacks.stack(id) } else { None };
+        let stack = match stack {
+            Some(stack) => stack,
+            None => {
+                frames.push(format!("[missed {} stack]", kind));
+                return;
+            }
+        };
+        // folded stacks start from the outermost frame
+        for addr in stack.iter().rev() {
+            let symbol = symbolizer
+                .symbol(pid, *addr)
+                .unwrap_or_else(|| format!("0x{:x}", addr));
+            frames.push(format!("{}{}", symbol, suffix));
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = (StackKey, u64)>> Iterator for FoldedStacks<'a, I> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let (key, count) = self.counts.next()?;
+        Some(self.fold_line(&key, count))
+    }
+}
+
+/// Renders folded stack lines as a flamegraph SVG.
+///
+/// This runs `flamegraph.pl`, which must be installed and in `$PATH`.
+pub fn svg<I: Iterator<Item = String>>(lines: I) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("flamegraph.pl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        for line in lines {
+            writeln!(stdin, "{}", line)?;
+        }
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("flamegraph.pl failed: {}", output.status),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::profile::{Addresses, FoldedStacks, StackKey, StackTraces, Symbolizer};
+
+        struct Stacks;
+        impl StackTraces for Stacks {
+            fn stack(&self, id: i32) -> Option<Vec<u64>> {
+                match id {
+                    1 => Some(vec![0x10, 0x20]),
+                    2 => Some(vec![0x30]),
+                    _ => None,
+                }
+            }
+        }
+        struct Kernel;
+        impl Symbolizer for Kernel {
+            fn symbol(&self, _pid: u32, addr: u64) -> Option<String> {
+                match addr {
+                    0x10 => Some("schedule".to_string()),
+                    _ => None,
+                }
+            }
+        }
+
+        fn key(pid: u32, kernel_stack_id: i32, user_stack_id: i32, comm: &str) -> StackKey {
+            let mut key = StackKey {
+                pid,
+                kernel_stack_id,
+                user_stack_id,
+                comm: [0; 16],
+            };
+            key.comm[..comm.len()].copy_from_slice(comm.as_bytes());
+            key
+        }
+
+        let counts = vec![
+            (key(42, 1, 2, "curl"), 3),
+            (key(42, 1, -14, "curl"), 1),
+            (key(0, 1, -14, "swapper/1"), 7),
+        ];
+        let lines: Vec<String> =
+            FoldedStacks::new(counts.clone().into_iter(), &Stacks, &Kernel, &Addresses)
+                .annotate(true)
+                .collapse_idle(true)
+                .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "curl;0x30;0x20_[k];schedule_[k] 3",
+                "curl;0x20_[k];schedule_[k] 1",
+                "swapper 7",
+            ]
+        );
+
+        let lines: Vec<String> =
+            FoldedStacks::new(counts.into_iter().skip(1), &Stacks, &Kernel, &Addresses).collect();
+        assert_eq!(
+            lines,
+            vec!["curl;0x20;schedule 1", "swapper/1;0x20;schedule 7"]
+        );
+
+        let lines: Vec<String> = FoldedStacks::new(
+            vec![(key(42, -17, -14, "curl"), 2)].into_iter(),
+            &Stacks,
+            &Kernel,
+            &Addresses,
+        )
+        .collect();
+        assert_eq!(
+            lines,
+            vec!["curl;[missed user stack];[missed kernel stack] 2"]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Programs and maps loaded by all the processes of the system.
+//!
+//! Like `bpftool prog` and `bpftool map`, `programs()` and `maps()` list
+//! everything loaded in the kernel, not just the objects of a `Module`:
+//!
+//! ```no_run
+//! use redbpf::query;
+//!
+//! for info in query::programs() {
+//!     match info {
+//!         Ok(info) => println!("{} {} maps: {:?}", info.id, info.name, info.map_ids),
+//!         // listing requires CAP_SYS_ADMIN
+//!         Err(e) => eprintln!("{:?}", e),
+//!     }
+//! }
+//! ```
+//!
+//! Listing requires `CAP_SYS_ADMIN`. Without it the iterators return a
+//! single `Error::IO` of kind `io::ErrorKind::PermissionDenied`, so a
+//! failure to list is never mistaken for nothing being loaded.
+use std::ffi::CString;
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::sys::bpf::{
+    bpf_obj_get_next_id, bpf_prog_query, BPF_MAP_GET_NEXT_ID, BPF_PROG_GET_NEXT_ID,
+};
+use crate::uname::get_kernel_internal_version;
+use crate::{Error, MapInfo, ProgramInfo, Result};
+
+// BPF_CGROUP_MAX_PROGS, the most programs of an attach type a cgroup can have
+const MAX_CGROUP_PROGRAMS: usize = 64;
+
+// The attach types of cgroup programs, `enum bpf_attach_type`, with the
+// kernel version that introduced them
+const CGROUP_ATTACH_TYPES: &[(u32, &str, u32)] = &[
+    (0, "ingress", 0x04_0a_00),
+    (1, "egress", 0x04_0a_00),
+    (2, "sock_create", 0x04_0a_00),
+    (3, "sock_ops", 0x04_0d_00),
+    (6, "device", 0x04_0f_00),
+    (8, "bind4", 0x04_11_00),
+    (9, "bind6", 0x04_11_00),
+    (10, "connect4", 0x04_11_00),
+    (11, "connect6", 0x04_11_00),
+    (12, "post_bind4", 0x04_11_00),
+    (13, "post_bind6", 0x04_11_00),
+    (14, "sendmsg4", 0x04_12_00),
+    (15, "sendmsg6", 0x04_12_00),
+    (18, "sysctl", 0x05_02_00),
+    (19, "recvmsg4", 0x05_02_00),
+    (20, "recvmsg6", 0x05_02_00),
+    (21, "getsockopt", 0x05_03_00),
+    (22, "setsockopt", 0x05_03_00),
+];
+
+/// A program attached to a cgroup, returned by `cgroup()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgroupProgram {
+    /// The attach type in the format of `bpftool cgroup`, eg. `ingress`
+    pub attach_type: &'static str,
+    pub program: ProgramInfo,
+}
+
+/// Returns an iterator over all the programs loaded in the kernel.
+pub fn programs() -> Programs {
+    Programs {
+        ids: Ids::new(BPF_PROG_GET_NEXT_ID),
+    }
+}
+
+/// Returns an iterator over all the maps created in the kernel.
+pub fn maps() -> Maps {
+    Maps {
+        ids: Ids::new(BPF_MAP_GET_NEXT_ID),
+    }
+}
+
+/// Returns the XDP program attached to `iface`, or `None` if there isn't
+/// one.
+pub fn xdp(iface: &str) -> Result<Option<ProgramInfo>> {
+    crate::xdp::query_xdp(iface)
+}
+
+/// Returns the programs attached to the cgroup at `path`, eg.
+/// `/sys/fs/cgroup/unified/user.slice`.
+///
+/// Programs attached to parent cgroups aren't included. Attach types the
+/// kernel doesn't know are skipped.
+pub fn cgroup<P: AsRef<Path>>(path: P) -> Result<Vec<CgroupProgram>> {
+    let cpath = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY) };
+    if fd < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+    let programs = cgroup_programs(fd);
+    unsafe { libc::close(fd) };
+
+    programs
+}
+
+fn cgroup_programs(fd: c_int) -> Result<Vec<CgroupProgram>> {
+    let version = get_kernel_internal_version();
+    let mut programs = Vec::new();
+    for (attach_type, name, since) in CGROUP_ATTACH_TYPES.iter() {
+        let mut ids = [0u32; MAX_CGROUP_PROGRAMS];
+        let mut count = 0;
+        if bpf_prog_query(fd, *attach_type, &mut ids, &mut count) < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EINVAL) && !attach_type_known(version, *since) {
+                continue;
+            }
+            return Err(Error::IO(e));
+        }
+        for id in ids.iter().take(count as usize) {
+            match ProgramInfo::from_id(*id) {
+                Ok(program) => programs.push(CgroupProgram {
+                    attach_type: name,
+                    program,
+                }),
+                Err(e) if vanished(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(programs)
+}
+
+/// Iterator over the programs loaded in the kernel, returned by
+/// `programs()`.
+pub struct Programs {
+    ids: Ids,
+}
+
+impl Iterator for Programs {
+    type Item = Result<ProgramInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.ids.next_info(ProgramInfo::from_id)
+    }
+}
+
+/// Iterator over the maps created in the kernel, returned by `maps()`.
+pub struct Maps {
+    ids: Ids,
+}
+
+impl Iterator for Maps {
+    type Item = Result<MapInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.ids.next_info(MapInfo::from_id)
+    }
+}
+
+struct Ids {
+    cmd: c_int,
+    current: u32,
+    done: bool,
+}
+
+impl Ids {
+    fn new(cmd: c_int) -> Ids {
+        Ids {
+            cmd,
+            current: 0,
+            done: false,
+        }
+    }
+
+    fn next_id(&mut self) -> Option<Result<u32>> {
+        if self.done {
+            return None;
+        }
+        let mut next = 0;
+        if bpf_obj_get_next_id(self.cmd, self.current, &mut next) < 0 {
+            self.done = true;
+            let e = io::Error::last_os_error();
+            // the end of the list
+            if e.raw_os_error() == Some(libc::ENOENT) {
+                return None;
+            }
+            return Some(Err(Error::IO(e)));
+        }
+        self.current = next;
+
+        Some(Ok(next))
+    }
+
+    // Objects released between getting their id and opening them are
+    // skipped
+    fn next_info<T, F>(&mut self, info: F) -> Option<Result<T>>
+    where
+        F: Fn(u32) -> Result<T>,
+    {
+        loop {
+            let id = match self.next_id()? {
+                Ok(id) => id,
+                Err(e) => return Some(Err(e)),
+            };
+            match info(id) {
+                Err(e) if vanished(&e) => continue,
+                info => return Some(info),
+            }
+        }
+    }
+}
+
+// The kernel fails queries of attach types it doesn't know with EINVAL. If
+// the version can't be read, any EINVAL is taken to mean that.
+fn attach_type_known(version: Option<u32>, since: u32) -> bool {
+    version.map_or(false, |version| version >= since)
+}
+
+fn vanished(e: &Error) -> bool {
+    match e {
+        Error::IO(e) => e.raw_os_error() == Some(libc::ENOENT),
+        _ => false,
+    }
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::query::{attach_type_known, vanished, CGROUP_ATTACH_TYPES};
+        use crate::sys::bpf::bpf_prog_query_attr;
+        use crate::Error;
+        use std::io;
+        use std::mem;
+
+        // the `query` member of `union bpf_attr`
+        let attr = bpf_prog_query_attr::default();
+        let base = &attr as *const _ as usize;
+        assert_eq!(mem::size_of::<bpf_prog_query_attr>(), 32);
+        assert_eq!(&attr.target_fd as *const _ as usize - base, 0);
+        assert_eq!(&attr.attach_type as *const _ as usize - base, 4);
+        assert_eq!(&attr.query_flags as *const _ as usize - base, 8);
+        assert_eq!(&attr.attach_flags as *const _ as usize - base, 12);
+        assert_eq!(&attr.prog_ids as *const _ as usize - base, 16);
+        assert_eq!(&attr.prog_cnt as *const _ as usize - base, 24);
+        assert_eq!(&attr._pad as *const _ as usize - base, 28);
+
+        let mut types: Vec<u32> = CGROUP_ATTACH_TYPES.iter().map(|(t, _, _)| *t).collect();
+        types.dedup();
+        assert_eq!(types.len(), CGROUP_ATTACH_TYPES.len());
+        assert!(types.windows(2).all(|w| w[0] < w[1]));
+
+        assert!(attach_type_known(Some(0x05_03_00), 0x05_03_00));
+        assert!(attach_type_known(Some(0x05_0a_00), 0x04_0a_00));
+        assert!(!attach_type_known(Some(0x05_02_00), 0x05_03_00));
+        assert!(!attach_type_known(None, 0x04_0a_00));
+
+        assert!(vanished(&Error::IO(io::Error::from_raw_os_error(
+            libc::ENOENT
+        ))));
+        assert!(!vanished(&Error::IO(io::Error::from_raw_os_error(
+            libc::EPERM
+        ))));
+    }
+}
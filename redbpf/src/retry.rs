@@ -0,0 +1,279 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Retry policy for attaching programs.
+//!
+//! Attaching can fail transiently, eg. when a driver briefly returns `EBUSY`
+//! while an interface is being reconfigured, or when another tool holds
+//! tracefs. `AttachRetry` retries the attach calls that fail with one of a
+//! configured set of errors, waiting with jittered exponential backoff
+//! between attempts:
+//!
+//! ```no_run
+//! use redbpf::retry::AttachRetry;
+//! use redbpf::Module;
+//! use std::time::Duration;
+//!
+//! let code = std::fs::read("bpf.elf").unwrap();
+//! let mut module = Module::parse(&code).unwrap();
+//! let retry = AttachRetry::new(5, Duration::from_millis(100));
+//! for prog in module.programs.iter_mut() {
+//!     prog.load(module.version, module.license.clone()).unwrap();
+//!     retry.run(&prog.name.clone(), || prog.attach_probe()).unwrap();
+//! }
+//! ```
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::{Error, Result};
+
+/// An `errno` value, eg. `libc::EBUSY`.
+pub type Errno = i32;
+
+/// Errors retried by default.
+pub const DEFAULT_RETRY_ON: [Errno; 3] = [libc::EBUSY, libc::EAGAIN, libc::ENOENT];
+
+/// Retry policy for attach calls.
+///
+/// The default policy makes a single attempt.
+#[derive(Debug, Clone)]
+pub struct AttachRetry {
+    /// Maximum number of attempts, including the first one.
+    pub attempts: u32,
+    /// Wait before the first retry. The wait doubles after each attempt.
+    pub backoff: Duration,
+    /// Errors that trigger a retry. Any other error is returned immediately.
+    pub retry_on: Vec<Errno>,
+}
+
+impl Default for AttachRetry {
+    fn default() -> Self {
+        AttachRetry {
+            attempts: 1,
+            backoff: Duration::from_millis(0),
+            retry_on: DEFAULT_RETRY_ON.to_vec(),
+        }
+    }
+}
+
+impl AttachRetry {
+    /// Creates a policy making up to `attempts` attempts, retrying on
+    /// `DEFAULT_RETRY_ON`.
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        AttachRetry {
+            attempts,
+            backoff,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the errors that trigger a retry.
+    pub fn retry_on(mut self, errors: &[Errno]) -> Self {
+        self.retry_on = errors.to_vec();
+        self
+    }
+
+    /// Runs `attach` until it succeeds, fails with an error that can't be
+    /// retried or the attempts are exhausted.
+    ///
+    /// `name` identifies the program in the warnings logged with the `log`
+    /// crate for each retry.
+    /// If more than one attempt was made, the error returned is
+    /// `Error::AttachRetries` carrying the errors of all the attempts.
+    pub fn run<T, F>(&self, name: &str, attach: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        self.run_with(name, attach, thread::sleep)
+    }
+
+    fn run_with<T, F, S>(&self, name: &str, mut attach: F, mut sleep: S) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+        S: FnMut(Duration),
+    {
+        let mut errors = Vec::new();
+        let mut backoff = self.backoff;
+        loop {
+            let error = match attach() {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            let errno = errno(&error);
+            errors.push(error);
+
+            let attempt = errors.len() as u32;
+            let errno = match errno {
+                Some(e) if self.retry_on.contains(&e) && attempt < self.attempts => e,
+                _ => break,
+            };
+
+            let wait = backoff + jitter(backoff);
+            warn!(
+                "attach {}: attempt {}/{} failed: {}, retrying in {:?}",
+                name,
+                attempt,
+                self.attempts,
+                io::Error::from_raw_os_error(errno),
+                wait
+            );
+            sleep(wait);
+            backoff *= 2;
+        }
+
+        if errors.len() == 1 {
+            Err(errors.pop().unwrap())
+        } else {
+            Err(Error::AttachRetries(name.to_string(), errors))
+        }
+    }
+}
+
+// The errno is captured in the error right after the failed call, errors
+// without one aren't retried
+fn errno(error: &Error) -> Option<Errno> {
+    match error {
+        Error::IO(e) => e.raw_os_error(),
+        Error::Attach { error, .. } => error.raw_os_error(),
+        _ => None,
+    }
+}
+
+// Up to half of the backoff, so that tools started together don't retry in
+// lockstep
+fn jitter(backoff: Duration) -> Duration {
+    let max = backoff.as_nanos() as u64 / 2;
+    if max == 0 {
+        return Duration::from_nanos(0);
+    }
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| u64::from(d.subsec_nanos()))
+        .unwrap_or(0);
+    Duration::from_nanos(seed % max)
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::retry::AttachRetry;
+        use crate::sys::mock::Syscalls;
+        use crate::Error;
+        use std::io;
+        use std::time::Duration;
+
+        // fails like the attach paths, with the errno of the call
+        fn attach(sys: &Syscalls) -> Result<i32, Error> {
+            let fd = sys.call();
+            if fd < 0 {
+                return Err(Error::Attach {
+                    program: "test".to_string(),
+                    target: "eth0".to_string(),
+                    error: io::Error::last_os_error(),
+                });
+            }
+            Ok(fd)
+        }
+
+        fn errno(error: &Error) -> Option<i32> {
+            match error {
+                Error::Attach { error, .. } => error.raw_os_error(),
+                _ => None,
+            }
+        }
+
+        // EBUSY is retried with growing waits
+        let retry = AttachRetry::new(5, Duration::from_millis(10));
+        let sys = Syscalls::new(vec![Err(libc::EBUSY), Err(libc::EBUSY), Ok(3)]);
+        let mut waits = Vec::new();
+        let res = retry.run_with("test", || attach(&sys), |d| waits.push(d));
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(sys.calls(), 3);
+        assert_eq!(waits.len(), 2);
+        assert!(waits[0] >= Duration::from_millis(10) && waits[0] < Duration::from_millis(15));
+        assert!(waits[1] >= Duration::from_millis(20) && waits[1] < Duration::from_millis(30));
+
+        // the backoff keeps doubling until the attempts are exhausted, and
+        // the final error includes all the attempts
+        let sys = Syscalls::new(vec![
+            Err(libc::EAGAIN),
+            Err(libc::EBUSY),
+            Err(libc::ENOENT),
+            Err(libc::EBUSY),
+            Err(libc::EBUSY),
+        ]);
+        let mut waits = Vec::new();
+        let res = retry.run_with("test", || attach(&sys), |d| waits.push(d));
+        assert_eq!(sys.calls(), 5);
+        assert_eq!(waits.len(), 4);
+        for (i, wait) in waits.iter().enumerate() {
+            let backoff = Duration::from_millis(10) * 2u32.pow(i as u32);
+            assert!(*wait >= backoff && *wait < backoff + backoff / 2);
+        }
+        match res {
+            Err(Error::AttachRetries(name, errors)) => {
+                assert_eq!(name, "test");
+                let errnos: Vec<_> = errors.iter().map(errno).collect();
+                assert_eq!(
+                    errnos,
+                    vec![
+                        Some(libc::EAGAIN),
+                        Some(libc::EBUSY),
+                        Some(libc::ENOENT),
+                        Some(libc::EBUSY),
+                        Some(libc::EBUSY)
+                    ]
+                );
+            }
+            _ => panic!("unexpected result"),
+        }
+
+        // EPERM bypasses retries
+        let sys = Syscalls::new(vec![Err(libc::EPERM)]);
+        let res = retry.run_with("test", || attach(&sys), |_| panic!("no retries expected"));
+        assert_eq!(sys.calls(), 1);
+        match res {
+            Err(ref e) => assert_eq!(errno(e), Some(libc::EPERM)),
+            _ => panic!("unexpected result"),
+        }
+
+        // so do errors without an errno, eg. verifier failures
+        let mut calls = 0;
+        let res: Result<(), _> = retry.run_with(
+            "test",
+            || {
+                calls += 1;
+                Err(Error::NotLoaded("test".to_string()))
+            },
+            |_| panic!("no retries expected"),
+        );
+        assert_eq!(calls, 1);
+        assert!(matches!(res, Err(Error::NotLoaded(_))));
+
+        // errors retried by the default policy aren't retried after a
+        // different retry_on
+        let retry = AttachRetry::new(5, Duration::from_millis(10)).retry_on(&[libc::EAGAIN]);
+        let sys = Syscalls::new(vec![Err(libc::EAGAIN), Err(libc::EBUSY)]);
+        let mut waits = 0;
+        let res = retry.run_with("test", || attach(&sys), |_| waits += 1);
+        assert_eq!((sys.calls(), waits), (2, 1));
+        assert!(matches!(res, Err(Error::AttachRetries(_, errors)) if errors.len() == 2));
+
+        // the default policy makes a single attempt
+        let sys = Syscalls::new(vec![Err(libc::EBUSY)]);
+        let res = AttachRetry::default().run_with(
+            "test",
+            || attach(&sys),
+            |_| panic!("no retries expected"),
+        );
+        assert_eq!(sys.calls(), 1);
+        assert!(matches!(res, Err(Error::Attach { .. })));
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Sockets for socket filter programs.
+//!
+//! `Program::attach_socketfilter()` opens a raw socket bound to an interface
+//! and attaches the program to it. To configure the socket first, eg. to join
+//! a fanout group, open it with `open_raw_socket()` or any other way and
+//! attach the program with `Program::attach_socket_filter_fd()`.
+//!
+//! The sockets returned by `open_raw_socket()` are non-blocking, so they can be
+//! read with `tokio::io::unix::AsyncFd` or similar.
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use crate::xdp::ifindex;
+use crate::{Error, Result};
+
+// not in libc for linux, the values are the same on x86_64 and aarch64
+const SO_ATTACH_BPF: libc::c_int = 50;
+const SO_DETACH_BPF: libc::c_int = 27;
+
+/// Opens an `AF_PACKET` raw socket receiving all the packets of `iface`.
+///
+/// The socket is non-blocking and close-on-exec. The caller owns it and is
+/// responsible for closing it.
+pub fn open_raw_socket(iface: &str) -> Result<RawFd> {
+    let ifindex = ifindex(iface)?;
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            protocol as libc::c_int,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = ifindex as i32;
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(Error::IO(err));
+    }
+
+    Ok(fd)
+}
+
+/// Detaches the socket filter program attached to the socket `fd`.
+pub fn detach_socket_filter(fd: RawFd) -> Result<()> {
+    let ret = unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, SO_DETACH_BPF, std::ptr::null(), 0) };
+    if ret < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn attach(fd: RawFd, prog_fd: RawFd) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            SO_ATTACH_BPF,
+            &prog_fd as *const _ as *const libc::c_void,
+            mem::size_of::<RawFd>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
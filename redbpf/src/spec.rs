@@ -0,0 +1,692 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Inspection of ELF files without loading them.
+//!
+//! `ModuleSpec::parse()` reads the programs, maps and globals of an ELF file
+//! without making any syscalls, so builds can be checked on machines that
+//! can't load BPF programs. `Module::from_spec()` loads them:
+//!
+//! ```no_run
+//! use redbpf::spec::ModuleSpec;
+//!
+//! let spec = ModuleSpec::parse(&std::fs::read("probe.elf").unwrap()).unwrap();
+//! for prog in spec.programs.iter() {
+//!     println!("{} {:?}: {} instructions", prog.name, prog.kind, prog.instruction_count());
+//! }
+//! assert!(spec.map("counts").is_some());
+//! ```
+use bpf_sys::{bpf_insn, bpf_map_def, bpf_map_type};
+use goblin::elf::{section_header as hdr, sym, Elf, SectionHeader, Sym};
+use std::mem;
+
+use crate::btf::{self, Btf, BtfExt, ExtInfo};
+use crate::co_re::CoreRelocation;
+use crate::{is_map_of_maps, Error, ProgramKind, Result};
+
+/// The programs, maps and globals defined by an ELF file.
+#[derive(Debug, Clone)]
+pub struct ModuleSpec {
+    pub programs: Vec<ProgramSpec>,
+    pub maps: Vec<MapSpec>,
+    pub globals: Vec<GlobalSpec>,
+    pub license: String,
+    /// The version of the `version` section, 0 if there's none. The magic
+    /// `0xFFFFFFFE` is replaced with the version of the running kernel when
+    /// the module is loaded.
+    pub version: u32,
+    pub(crate) relocations: Vec<Relocation>,
+    /// The CO-RE relocations not applied yet, see `relocate()`
+    pub(crate) core_relocations: Vec<CoreRelocation>,
+    /// The `.BTF` section, fixed up to be loaded in the kernel
+    pub(crate) btf: Option<Vec<u8>>,
+}
+
+/// A program defined by an ELF file.
+#[derive(Debug, Clone)]
+pub struct ProgramSpec {
+    pub name: String,
+    pub kind: ProgramKind,
+    /// The name of the ELF section of the program, eg. `kprobe/do_sys_open`
+    pub section: String,
+    pub(crate) code: Vec<bpf_insn>,
+    /// The functions and the source lines of the program, from `.BTF.ext`
+    pub(crate) func_info: Option<ExtInfo>,
+    pub(crate) line_info: Option<ExtInfo>,
+}
+
+/// A map defined by an ELF file.
+#[derive(Debug, Clone)]
+pub struct MapSpec {
+    pub name: String,
+    pub def: bpf_map_def,
+    /// The definition of the inner maps of maps of maps
+    pub inner_def: Option<bpf_map_def>,
+}
+
+/// A global defined by an ELF file, see `Module::set_global()`.
+#[derive(Debug, Clone)]
+pub struct GlobalSpec {
+    pub name: String,
+    /// The initial value
+    pub data: Vec<u8>,
+    /// Whether the programs can't change the value, eg. for `.rodata`
+    pub read_only: bool,
+}
+
+// An instruction to rewrite with the fd of a map or a global once it's
+// created
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Relocation {
+    pub(crate) program: usize,
+    pub(crate) insn: usize,
+    pub(crate) target: RelocationTarget,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RelocationTarget {
+    Map(usize),
+    /// A global and the offset of the value in it
+    Global(usize, i32),
+}
+
+const MAP_TYPE_NAMES: &[(bpf_map_type, &str)] = &[
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH, "HASH"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY, "ARRAY"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_PROG_ARRAY, "PROG_ARRAY"),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+        "PERF_EVENT_ARRAY",
+    ),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_HASH,
+        "PERCPU_HASH",
+    ),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY,
+        "PERCPU_ARRAY",
+    ),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_STACK_TRACE,
+        "STACK_TRACE",
+    ),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_CGROUP_ARRAY,
+        "CGROUP_ARRAY",
+    ),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_LRU_HASH, "LRU_HASH"),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH,
+        "LRU_PERCPU_HASH",
+    ),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_LPM_TRIE, "LPM_TRIE"),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_ARRAY_OF_MAPS,
+        "ARRAY_OF_MAPS",
+    ),
+    (
+        bpf_sys::bpf_map_type_BPF_MAP_TYPE_HASH_OF_MAPS,
+        "HASH_OF_MAPS",
+    ),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_DEVMAP, "DEVMAP"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_SOCKMAP, "SOCKMAP"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_CPUMAP, "CPUMAP"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_XSKMAP, "XSKMAP"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_SOCKHASH, "SOCKHASH"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_QUEUE, "QUEUE"),
+    (bpf_sys::bpf_map_type_BPF_MAP_TYPE_STACK, "STACK"),
+];
+
+// The name of `map_type` as used by `bpftool`, in upper case
+pub(crate) fn map_type_name(map_type: bpf_map_type) -> Option<&'static str> {
+    MAP_TYPE_NAMES
+        .iter()
+        .find(|(t, _)| *t == map_type)
+        .map(|(_, name)| *name)
+}
+
+// A relocation entry of the ELF file
+struct Rel {
+    target: usize,
+    offset: u64,
+    sym: usize,
+}
+
+impl ModuleSpec {
+    /// Parses the ELF file in `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<ModuleSpec> {
+        let object = Elf::parse(bytes)?;
+        let symtab = object.syms.to_vec();
+
+        let mut rels = vec![];
+        let mut programs = Vec::new();
+        let mut maps = Vec::new();
+        let mut globals = Vec::new();
+        let mut fields = Vec::new();
+
+        let mut license = String::new();
+        let mut version = 0u32;
+
+        for (shndx, offset, map) in map_definitions(&object, bytes, &symtab)? {
+            maps.push(((shndx, offset), map));
+        }
+
+        for (shndx, shdr) in object.section_headers.iter().enumerate() {
+            let (kind, name) = get_split_section_name(&object, shdr, shndx)?;
+
+            let section_type = shdr.sh_type;
+            let content = data(bytes, shdr);
+            let global = |name: &str, data: &[u8], read_only| GlobalSpec {
+                name: name.to_string(),
+                data: data.to_vec(),
+                read_only,
+            };
+
+            match (section_type, kind, name) {
+                (hdr::SHT_REL, _, _) => add_rel(&mut rels, shndx, shdr, &object)?,
+                (hdr::SHT_PROGBITS, Some("version"), _) => {
+                    version = *zero::read::<u32>(content);
+                }
+                (hdr::SHT_PROGBITS, Some("license"), _) => {
+                    license = zero::read_str(content).to_string()
+                }
+                (hdr::SHT_PROGBITS, Some("globals"), Some(name)) => {
+                    globals.push((shndx, global(name, content, true)));
+                }
+                (_, Some("fields"), Some(path)) => fields.push((shndx, path)),
+                // the global variables of libbpf objects, only `.rodata` is
+                // read-only
+                (hdr::SHT_PROGBITS, Some(name @ ".rodata"), None) if !content.is_empty() => {
+                    globals.push((shndx, global(name, content, true)));
+                }
+                (hdr::SHT_PROGBITS, Some(name @ ".data"), None) if !content.is_empty() => {
+                    globals.push((shndx, global(name, content, false)));
+                }
+                (hdr::SHT_NOBITS, Some(name @ ".bss"), None) if shdr.sh_size > 0 => {
+                    let zeroes = vec![0u8; shdr.sh_size as usize];
+                    globals.push((shndx, global(name, &zeroes, false)));
+                }
+                (hdr::SHT_PROGBITS, Some(kind @ "kprobe"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "kretprobe"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "uprobe"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "uretprobe"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "xdp"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "socketfilter"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "tracepoint"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "classifier"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "perf_event"), Some(name)) => {
+                    let section = format!("{}/{}", kind, name);
+                    let prog = ProgramSpec::new(kind, name, section, content)?;
+                    programs.push((shndx, prog));
+                }
+                (hdr::SHT_PROGBITS, Some(section), target) => {
+                    if let Some((kind, target)) = libbpf_program_section(section, target) {
+                        let name = match target {
+                            Some(target) => target.to_string(),
+                            None => program_function_name(&object, &symtab, shndx)?,
+                        };
+                        let section = section_name(&object, shdr, shndx)?.to_string();
+                        programs.push((shndx, ProgramSpec::new(kind, &name, section, content)?));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let btf = match elf_section(&object, bytes, ".BTF") {
+            Some(data) => {
+                let section_size = |name: &str| {
+                    object
+                        .section_headers
+                        .iter()
+                        .find(|shdr| object.shdr_strtab.get_unsafe(shdr.sh_name) == Some(name))
+                        .map(|shdr| shdr.sh_size as u32)
+                };
+                let symbol_offset = |name: &str| {
+                    symtab
+                        .iter()
+                        .find(|sym| object.strtab.get_unsafe(sym.st_name) == Some(name))
+                        .map(|sym| sym.st_value as u32)
+                };
+                Some(btf::fixup(data, section_size, symbol_offset)?)
+            }
+            None => None,
+        };
+        let mut core_relocations = Vec::new();
+        if let (Some(btf), Some(ext)) = (&btf, elf_section(&object, bytes, ".BTF.ext")) {
+            let ext = BtfExt::parse(ext, btf)?;
+            let local = if ext.core_relos.is_empty() {
+                None
+            } else {
+                Some(Btf::parse(btf)?)
+            };
+            for (i, (_, prog)) in programs.iter_mut().enumerate() {
+                prog.func_info = ext.func_info.get(&prog.section).cloned();
+                prog.line_info = ext.line_info.get(&prog.section).cloned();
+                if let (Some(local), Some(relos)) = (&local, ext.core_relos.get(&prog.section)) {
+                    for relo in relos.iter() {
+                        core_relocations.push(CoreRelocation::from_btf_ext(local, relo, i)?);
+                    }
+                }
+            }
+        }
+
+        let mut relocations = Vec::new();
+        for rel in rels.iter() {
+            if let Some(program) = programs.iter().position(|(shndx, _)| *shndx == rel.target) {
+                let code = &programs[program].1.code;
+                match rel.field(program, &fields, &symtab) {
+                    Some(reloc) => core_relocations.push(reloc),
+                    None => relocations.push(rel.resolve(program, code, &maps, &globals, &symtab)?),
+                }
+            }
+        }
+
+        Ok(ModuleSpec {
+            programs: programs.into_iter().map(|(_, p)| p).collect(),
+            maps: maps.into_iter().map(|(_, m)| m).collect(),
+            globals: globals.into_iter().map(|(_, g)| g).collect(),
+            license,
+            version,
+            relocations,
+            core_relocations,
+            btf,
+        })
+    }
+
+    /// Applies the CO-RE relocations of the programs with the types of
+    /// `target`, the BTF of the kernel the programs are loaded in.
+    ///
+    /// `Module::from_spec()` applies them with the BTF of the running
+    /// kernel, read with `Btf::from_kernel()`. Kernels older than 5.4 don't
+    /// provide their BTF: relocating the programs with a BTF generated for
+    /// the kernel, eg. with `pahole -J`, lets them load anyway.
+    ///
+    /// Fails with `Error::CoreRelocation`, naming the field that doesn't
+    /// match, without changing the programs.
+    ///
+    /// ```no_run
+    /// use redbpf::btf::Btf;
+    /// use redbpf::spec::ModuleSpec;
+    /// use redbpf::Module;
+    ///
+    /// let mut spec = ModuleSpec::parse(&std::fs::read("probe.elf").unwrap()).unwrap();
+    /// let btf = Btf::parse(&std::fs::read("4.19.0-17-amd64.btf").unwrap()).unwrap();
+    /// spec.relocate(&btf).unwrap();
+    /// let module = Module::from_spec(&spec).unwrap();
+    /// ```
+    pub fn relocate(&mut self, target: &Btf) -> Result<()> {
+        let mut programs = self.programs.clone();
+        for reloc in self.core_relocations.iter() {
+            let prog = &mut programs[reloc.program];
+            reloc.apply(&mut prog.code, target, &prog.name)?;
+        }
+        self.programs = programs;
+        self.core_relocations.clear();
+
+        Ok(())
+    }
+
+    pub fn program(&self, name: &str) -> Option<&ProgramSpec> {
+        self.programs.iter().find(|p| p.name == name)
+    }
+
+    pub fn map(&self, name: &str) -> Option<&MapSpec> {
+        self.maps.iter().find(|m| m.name == name)
+    }
+
+    /// Returns whether the ELF file has a `.BTF` section.
+    pub fn has_btf(&self) -> bool {
+        self.btf.is_some()
+    }
+
+    /// Returns the maps used by the program `name`, with the index of the
+    /// instruction loading each of them.
+    pub fn map_references(&self, name: &str) -> Vec<(usize, &MapSpec)> {
+        let program = match self.programs.iter().position(|p| p.name == name) {
+            Some(program) => program,
+            None => return Vec::new(),
+        };
+        self.relocations
+            .iter()
+            .filter(|reloc| reloc.program == program)
+            .filter_map(|reloc| match reloc.target {
+                RelocationTarget::Map(map) => Some((reloc.insn, &self.maps[map])),
+                RelocationTarget::Global(..) => None,
+            })
+            .collect()
+    }
+}
+
+impl ProgramSpec {
+    fn new(kind: &str, name: &str, section: String, code: &[u8]) -> Result<ProgramSpec> {
+        Ok(ProgramSpec {
+            name: name.to_string(),
+            kind: ProgramKind::from_section(kind)?,
+            section,
+            code: zero::read_array(code).to_vec(),
+            func_info: None,
+            line_info: None,
+        })
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Returns whether `.BTF.ext` has the functions or the source lines of
+    /// the program.
+    pub fn has_btf_ext(&self) -> bool {
+        self.func_info.is_some() || self.line_info.is_some()
+    }
+
+    /// Returns the instructions of the program, before relocations are
+    /// applied.
+    pub fn instructions(&self) -> &[bpf_insn] {
+        &self.code
+    }
+}
+
+impl MapSpec {
+    /// Returns the name of the type of the map, eg. `HASH` for
+    /// `BPF_MAP_TYPE_HASH`, `None` for types unknown to `redbpf`.
+    pub fn type_name(&self) -> Option<&'static str> {
+        map_type_name(self.def.type_)
+    }
+
+    /// Reads the `bpf_map_def` at the start of `code`, followed by the
+    /// definition of the inner maps for maps of maps.
+    pub(crate) fn from_elf(name: &str, code: &[u8]) -> Result<MapSpec> {
+        let size = mem::size_of::<bpf_map_def>();
+        if code.len() < size {
+            return Err(Error::Map);
+        }
+        let def: bpf_map_def = *zero::read(code);
+        let inner_def = if is_map_of_maps(def.type_) {
+            if code.len() < 2 * size {
+                return Err(Error::Map);
+            }
+            Some(*zero::read(&code[size..]))
+        } else {
+            None
+        };
+
+        Ok(MapSpec {
+            name: name.to_string(),
+            def,
+            inner_def,
+        })
+    }
+}
+
+impl Rel {
+    // The CO-RE relocation of the instruction, if it loads the address of a
+    // symbol of a `fields/` section
+    fn field(
+        &self,
+        program: usize,
+        fields: &[(usize, &str)],
+        symtab: &[Sym],
+    ) -> Option<CoreRelocation> {
+        let sym = symtab.get(self.sym)?;
+        let (_, path) = fields.iter().find(|(shndx, _)| *shndx == sym.st_shndx)?;
+        let insn = (self.offset / mem::size_of::<bpf_insn>() as u64) as usize;
+
+        Some(CoreRelocation::field(program, insn, path))
+    }
+
+    // Finds the map or the global the instruction refers to
+    fn resolve(
+        &self,
+        program: usize,
+        code: &[bpf_insn],
+        maps: &[((usize, u64), MapSpec)],
+        globals: &[(usize, GlobalSpec)],
+        symtab: &[Sym],
+    ) -> Result<Relocation> {
+        let sym = symtab.get(self.sym).ok_or(Error::Reloc)?;
+        let insn = (self.offset / mem::size_of::<bpf_insn>() as u64) as usize;
+        if insn >= code.len() {
+            return Err(Error::Reloc);
+        }
+
+        let location = (sym.st_shndx, sym.st_value);
+        let target = if let Some(map) = maps.iter().position(|(l, _)| *l == location) {
+            RelocationTarget::Map(map)
+        } else {
+            // globals are loaded with a 16 byte ld_imm64 instruction: the
+            // first half gets the map, the second one the offset of the value
+            let global = globals
+                .iter()
+                .position(|(shndx, _)| *shndx == sym.st_shndx)
+                .ok_or(Error::Reloc)?;
+            if insn + 1 >= code.len() {
+                return Err(Error::Reloc);
+            }
+            RelocationTarget::Global(global, sym.st_value as i32 + code[insn].imm)
+        };
+
+        Ok(Relocation {
+            program,
+            insn,
+            target,
+        })
+    }
+}
+
+// redbpf puts each map in its own `maps/<name>` section. libbpf objects put
+// all the maps in the `maps` section, or define them with BTF in `.maps`,
+// named by their symbols. Returns the section and the offset of each map.
+fn map_definitions(
+    object: &Elf<'_>,
+    bytes: &[u8],
+    symtab: &[Sym],
+) -> Result<Vec<(usize, u64, MapSpec)>> {
+    let mut defs = Vec::new();
+    for (shndx, shdr) in object.section_headers.iter().enumerate() {
+        let content = data(bytes, shdr);
+        match get_split_section_name(object, shdr, shndx)? {
+            (Some("maps"), Some(name)) => defs.push((shndx, 0, MapSpec::from_elf(name, content)?)),
+            (Some("maps"), None) => {
+                let symbols = section_symbols(object, symtab, shndx, sym::STT_OBJECT);
+                if symbols.is_empty() {
+                    continue;
+                }
+                // libbpf allows definitions longer than `bpf_map_def`, all
+                // of the same size
+                let size = content.len() / symbols.len();
+                if size < mem::size_of::<bpf_map_def>() {
+                    return Err(Error::Section("maps".to_string()));
+                }
+                for (offset, name) in symbols {
+                    let start = offset as usize;
+                    let code = content.get(start..start + size).ok_or(Error::Map)?;
+                    defs.push((shndx, offset, MapSpec::from_elf(&name, code)?));
+                }
+            }
+            (Some(btf::MAPS_SECTION), None) => {
+                let btf_defs = elf_btf(object, bytes)?.map_defs()?;
+                for (offset, name) in section_symbols(object, symtab, shndx, sym::STT_OBJECT) {
+                    let def = btf_defs
+                        .iter()
+                        .find(|(n, _)| n == &name)
+                        .map(|(_, def)| *def)
+                        .ok_or_else(|| Error::Btf(format!("no definition of map {}", name)))?;
+                    let map = MapSpec {
+                        name,
+                        def,
+                        inner_def: None,
+                    };
+                    defs.push((shndx, offset, map));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(defs)
+}
+
+fn elf_btf(object: &Elf<'_>, bytes: &[u8]) -> Result<Btf> {
+    let section = elf_section(object, bytes, ".BTF")
+        .ok_or_else(|| Error::Btf("no .BTF section".to_string()))?;
+
+    Btf::parse(section)
+}
+
+fn elf_section<'d>(object: &Elf<'_>, bytes: &'d [u8], name: &str) -> Option<&'d [u8]> {
+    object
+        .section_headers
+        .iter()
+        .find(|shdr| object.shdr_strtab.get_unsafe(shdr.sh_name) == Some(name))
+        .map(|shdr| data(bytes, shdr))
+}
+
+// The offsets and names of the symbols of type `st_type` in section `shndx`
+fn section_symbols(
+    object: &Elf<'_>,
+    symtab: &[Sym],
+    shndx: usize,
+    st_type: u8,
+) -> Vec<(u64, String)> {
+    let mut symbols: Vec<(u64, String)> = symtab
+        .iter()
+        .filter(|s| s.st_shndx == shndx && s.st_type() == st_type)
+        .filter_map(|s| {
+            let name = object.strtab.get_unsafe(s.st_name)?;
+            Some((s.st_value, name.to_string()))
+        })
+        .collect();
+    symbols.sort();
+
+    symbols
+}
+
+// Maps the section names used by libbpf to the program kinds of redbpf.
+// Returns the target of the program if the section names one, eg.
+// `syscalls/sys_enter_write` for `tp/syscalls/sys_enter_write`.
+fn libbpf_program_section<'s>(
+    kind: &str,
+    target: Option<&'s str>,
+) -> Option<(&'static str, Option<&'s str>)> {
+    match (kind, target) {
+        ("tp", Some(_)) => Some(("tracepoint", target)),
+        ("xdp", None) => Some(("xdp", None)),
+        ("classifier", None) | ("tc", _) => Some(("classifier", None)),
+        ("perf_event", None) => Some(("perf_event", None)),
+        // `socket`, and `socket1` and so on in the kernel samples
+        (kind, _) if kind.starts_with("socket") => Some(("socketfilter", None)),
+        _ => None,
+    }
+}
+
+// Programs of the sections without a target are named after their function
+fn program_function_name(object: &Elf<'_>, symtab: &[Sym], shndx: usize) -> Result<String> {
+    let mut functions = section_symbols(object, symtab, shndx, sym::STT_FUNC);
+    match functions.len() {
+        1 => Ok(functions.remove(0).1),
+        count => {
+            let section = section_name(object, &object.section_headers[shndx], shndx)?;
+            Err(Error::Section(format!(
+                "{}: expected one program, found {}",
+                section, count
+            )))
+        }
+    }
+}
+
+#[inline]
+fn section_name<'o>(object: &'o Elf<'_>, shdr: &SectionHeader, shndx: usize) -> Result<&'o str> {
+    object
+        .shdr_strtab
+        .get_unsafe(shdr.sh_name)
+        .ok_or_else(|| Error::Section(format!("Section name not found: {}", shndx)))
+}
+
+#[inline]
+fn get_split_section_name<'o>(
+    object: &'o Elf<'_>,
+    shdr: &SectionHeader,
+    shndx: usize,
+) -> Result<(Option<&'o str>, Option<&'o str>)> {
+    let name = section_name(object, shdr, shndx)?;
+
+    let mut names = name.splitn(2, '/');
+
+    let kind = names.next();
+    let name = names.next();
+
+    Ok((kind, name))
+}
+
+#[inline]
+fn add_rel(
+    rels: &mut Vec<Rel>,
+    shndx: usize,
+    shdr: &SectionHeader,
+    object: &Elf<'_>,
+) -> Result<()> {
+    let section_rels = &object
+        .shdr_relocs
+        .iter()
+        .find(|(idx, _)| idx == &shndx)
+        .ok_or(Error::Reloc)?
+        .1;
+    rels.extend(section_rels.iter().map(|rel| Rel {
+        target: shdr.sh_info as usize,
+        sym: rel.r_sym,
+        offset: rel.r_offset,
+    }));
+
+    Ok(())
+}
+
+#[inline]
+fn data<'d>(bytes: &'d [u8], shdr: &SectionHeader) -> &'d [u8] {
+    // sections like `.bss` take no space in the file
+    if shdr.sh_type == hdr::SHT_NOBITS {
+        return &[];
+    }
+    let offset = shdr.sh_offset as usize;
+    let end = (shdr.sh_offset + shdr.sh_size) as usize;
+
+    &bytes[offset..end]
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::spec::{libbpf_program_section, MapSpec};
+        use std::mem;
+
+        assert_eq!(
+            libbpf_program_section("tp", Some("syscalls/sys_enter_write")),
+            Some(("tracepoint", Some("syscalls/sys_enter_write")))
+        );
+        assert_eq!(
+            libbpf_program_section("socket1", None),
+            Some(("socketfilter", None))
+        );
+        assert_eq!(
+            libbpf_program_section("tc", Some("ingress")),
+            Some(("classifier", None))
+        );
+        assert_eq!(libbpf_program_section(".text", None), None);
+
+        // struct bpf_map_def { HASH, u32, u64, 1024, 0 }
+        let words: [u32; 5] = [1, 4, 8, 1024, 0];
+        let code = unsafe {
+            std::slice::from_raw_parts(words.as_ptr() as *const u8, mem::size_of_val(&words))
+        };
+        let map = MapSpec::from_elf("counts", code).unwrap();
+        assert_eq!(map.type_name(), Some("HASH"));
+        assert_eq!(map.def.value_size, 8);
+        assert_eq!(map.def.max_entries, 1024);
+        assert!(map.inner_def.is_none());
+        assert!(MapSpec::from_elf("counts", &code[..12]).is_err());
+    }
+}
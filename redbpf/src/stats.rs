@@ -0,0 +1,174 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Run time statistics of programs.
+//!
+//! The kernel only counts the runs of programs while statistics are enabled,
+//! which slows every program down slightly. `enable_stats()` turns them on
+//! until the returned handle is dropped:
+//!
+//! ```no_run
+//! use redbpf::{enable_stats, Module};
+//!
+//! let module = Module::load_file("probe.elf").unwrap();
+//! let _stats = enable_stats().unwrap();
+//! // ...
+//! for prog in module.programs() {
+//!     let stats = prog.stats().unwrap();
+//!     println!("{}: {} runs, {} ns", prog.name(), stats.run_count, stats.run_time_ns);
+//! }
+//! ```
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::sys::bpf::{bpf_enable_stats, BPF_STATS_RUN_TIME};
+use crate::uname::{get_kernel_internal_version, kernel_version_code};
+use crate::{Error, Result};
+
+const STATS_SYSCTL: &str = "/proc/sys/kernel/bpf_stats_enabled";
+
+/// The run time statistics of a program, see `Program::stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProgramStats {
+    /// The number of runs while statistics were enabled
+    pub run_count: u64,
+    /// The total run time while statistics were enabled
+    pub run_time_ns: u64,
+}
+
+impl ProgramStats {
+    /// Returns the average run time in nanoseconds, 0 if the program didn't
+    /// run.
+    pub fn average_ns(&self) -> u64 {
+        self.run_time_ns.checked_div(self.run_count).unwrap_or(0)
+    }
+
+    /// Returns the runs since the `earlier` statistics of the program.
+    pub fn since(&self, earlier: &ProgramStats) -> ProgramStats {
+        ProgramStats {
+            run_count: self.run_count.saturating_sub(earlier.run_count),
+            run_time_ns: self.run_time_ns.saturating_sub(earlier.run_time_ns),
+        }
+    }
+}
+
+/// Keeps run time statistics enabled until it's dropped or `restore()` is
+/// called.
+pub struct StatsHandle {
+    // None once restored
+    kind: Option<HandleKind>,
+}
+
+enum HandleKind {
+    Fd(RawFd),
+    // the value of the sysctl before it was enabled
+    Sysctl(String),
+}
+
+/// Enables run time statistics for all programs.
+///
+/// Uses `BPF_ENABLE_STATS` on Linux 5.8 and newer, and the
+/// `kernel.bpf_stats_enabled` sysctl on older kernels. The sysctl is set
+/// back to its previous value by `StatsHandle::restore()`, or when the
+/// handle is dropped, ignoring errors. Fails with
+/// `Error::UnsupportedKernel` before Linux 5.1.
+pub fn enable_stats() -> Result<StatsHandle> {
+    let fd = bpf_enable_stats(BPF_STATS_RUN_TIME);
+    if fd >= 0 {
+        return Ok(StatsHandle {
+            kind: Some(HandleKind::Fd(fd)),
+        });
+    }
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() != Some(libc::EINVAL) {
+        return Err(Error::IO(error));
+    }
+
+    // older kernels don't know the command
+    let previous = match fs::read_to_string(STATS_SYSCTL) {
+        Ok(previous) => previous,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::UnsupportedKernel {
+                needed: kernel_version_code(5, 1, 0),
+                found: get_kernel_internal_version().unwrap_or(0),
+            })
+        }
+        Err(e) => return Err(Error::IO(e)),
+    };
+    fs::write(STATS_SYSCTL, "1")?;
+
+    Ok(StatsHandle {
+        kind: Some(HandleKind::Sysctl(previous.trim().to_string())),
+    })
+}
+
+impl StatsHandle {
+    /// Disables the statistics, or sets the sysctl back to its previous
+    /// value on older kernels.
+    ///
+    /// Unlike dropping the handle, returns the error of writing the sysctl.
+    pub fn restore(mut self) -> Result<()> {
+        self.disable()
+    }
+
+    fn disable(&mut self) -> Result<()> {
+        match self.kind.take() {
+            Some(HandleKind::Fd(fd)) => {
+                unsafe { libc::close(fd) };
+                Ok(())
+            }
+            Some(HandleKind::Sysctl(previous)) => Ok(fs::write(STATS_SYSCTL, previous)?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for StatsHandle {
+    fn drop(&mut self) {
+        let _ = self.disable();
+    }
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::stats::ProgramStats;
+
+        let earlier = ProgramStats {
+            run_count: 10,
+            run_time_ns: 1000,
+        };
+        let now = ProgramStats {
+            run_count: 14,
+            run_time_ns: 1800,
+        };
+        let delta = now.since(&earlier);
+        assert_eq!(delta.run_count, 4);
+        assert_eq!(delta.average_ns(), 200);
+        assert_eq!(earlier.since(&now), ProgramStats::default());
+        assert_eq!(ProgramStats::default().average_ns(), 0);
+    }
+
+    #[test]
+    fn test_restore() {
+        use crate::stats::{HandleKind, StatsHandle};
+        use std::io;
+
+        // writing to the pipe fails once its read end is closed
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let handle = StatsHandle {
+            kind: Some(HandleKind::Fd(fds[0])),
+        };
+        handle.restore().unwrap();
+        let ret = unsafe { libc::write(fds[1], [0u8].as_ptr() as *const _, 1) };
+        assert_eq!(ret, -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPIPE));
+        unsafe { libc::close(fds[1]) };
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Symbolization of stack addresses.
+//!
+//! `KernelSymbols` maps kernel addresses, eg. the ones read from a
+//! `StackTraceMap`, to the names of the functions containing them:
+//!
+//! ```no_run
+//! use redbpf::symbols::KernelSymbols;
+//!
+//! let symbols = KernelSymbols::load().unwrap();
+//! println!("{}", symbols.format(0xffff_ffff_8100_0000));
+//! ```
+//!
+//! `ProcessSymbols` does the same for the user space addresses of a
+//! process, with the symbol tables of the executable and libraries it maps:
+//!
+//! ```no_run
+//! use redbpf::symbols::ProcessSymbols;
+//!
+//! let mut symbols = ProcessSymbols::new(1234).unwrap();
+//! // the process may have loaded new libraries
+//! symbols.refresh().unwrap();
+//! if let Some(symbol) = symbols.resolve(0x7f2c_4e3a_1000) {
+//!     println!("{}", symbol);
+//! }
+//! ```
+use goblin::elf::{program_header, Elf};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::profile::Symbolizer;
+
+const KALLSYMS: &str = "/proc/kallsyms";
+
+/// A resolved symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// Offset of the address from the start of the symbol.
+    pub offset: u64,
+    /// The kernel module defining the symbol, if any, or the file name of
+    /// the executable or library defining a user space symbol.
+    pub module: Option<String>,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+0x{:x}", self.name, self.offset)?;
+        if let Some(module) = &self.module {
+            write!(f, " [{}]", module)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The kernel symbol table.
+pub struct KernelSymbols {
+    // sorted by address
+    symbols: Vec<(u64, String, Option<String>)>,
+}
+
+impl KernelSymbols {
+    /// Loads the symbols from `/proc/kallsyms`.
+    ///
+    /// Warns if the addresses are hidden by `kernel.kptr_restrict`, in which
+    /// case no address can be resolved.
+    pub fn load() -> io::Result<KernelSymbols> {
+        let kallsyms = fs::read_to_string(KALLSYMS)?;
+        let symbols = KernelSymbols::parse(&kallsyms);
+        if symbols.is_empty() && !kallsyms.is_empty() {
+            eprintln!(
+                "warning: kernel addresses are hidden by kernel.kptr_restrict, \
+                 kernel stacks can't be symbolized"
+            );
+        }
+
+        Ok(symbols)
+    }
+
+    /// Parses symbols in the `/proc/kallsyms` format.
+    pub fn parse(kallsyms: &str) -> KernelSymbols {
+        let mut symbols: Vec<_> = kallsyms
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let _kind = fields.next()?;
+                let name = fields.next()?.to_string();
+                let module = fields
+                    .next()
+                    .map(|m| m.trim_start_matches('[').trim_end_matches(']').to_string());
+                Some((addr, name, module))
+            })
+            .filter(|(addr, _, _)| *addr != 0)
+            .collect();
+        symbols.sort_by_key(|(addr, _, _)| *addr);
+
+        KernelSymbols { symbols }
+    }
+
+    /// Returns `true` if no symbols with addresses were found.
+    ///
+    /// Unprivileged users see all the addresses as 0 when
+    /// `kernel.kptr_restrict` is set.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Returns the symbol containing `addr`.
+    pub fn resolve(&self, addr: u64) -> Option<Symbol> {
+        let idx = match self.symbols.binary_search_by_key(&addr, |(a, _, _)| *a) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let (start, name, module) = &self.symbols[idx];
+
+        Some(Symbol {
+            name: name.clone(),
+            offset: addr - start,
+            module: module.clone(),
+        })
+    }
+
+    // The addresses and the names of the symbols
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.symbols
+            .iter()
+            .map(|(addr, name, _)| (*addr, name.as_str()))
+    }
+
+    /// Formats `addr` as `symbol+offset`, or as a hex number if it can't be
+    /// resolved.
+    pub fn format(&self, addr: u64) -> String {
+        match self.resolve(addr) {
+            Some(symbol) => symbol.to_string(),
+            None => format!("0x{:x}", addr),
+        }
+    }
+}
+
+impl Symbolizer for KernelSymbols {
+    fn symbol(&self, _pid: u32, addr: u64) -> Option<String> {
+        self.resolve(addr).map(|symbol| symbol.name)
+    }
+}
+
+/// The symbols of the executable and the libraries mapped by a process.
+///
+/// Only the symbol tables are read, so the symbols of stripped files can't
+/// be resolved. The mappings are read once, call `refresh()` after the
+/// process execs or loads libraries.
+pub struct ProcessSymbols {
+    pid: u32,
+    maps: String,
+    // sorted by start address
+    mappings: Vec<Mapping>,
+    objects: HashMap<PathBuf, Rc<ObjectSymbols>>,
+}
+
+struct Mapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    object: Option<Rc<ObjectSymbols>>,
+}
+
+struct ObjectSymbols {
+    name: String,
+    // (vaddr, file offset, file size) of the loadable segments
+    segments: Vec<(u64, u64, u64)>,
+    // (vaddr, size, name) sorted by address
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl ProcessSymbols {
+    /// Reads the mappings of `pid` and the symbols of the mapped files.
+    pub fn new(pid: u32) -> io::Result<ProcessSymbols> {
+        let mut symbols = ProcessSymbols {
+            pid,
+            maps: String::new(),
+            mappings: Vec::new(),
+            objects: HashMap::new(),
+        };
+        symbols.refresh()?;
+
+        Ok(symbols)
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Reads the mappings of the process again.
+    ///
+    /// Returns `true` if they changed, eg. because the process loaded a
+    /// library. The symbols of files mapped before are kept.
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        let maps = fs::read_to_string(format!("/proc/{}/maps", self.pid))?;
+        if maps == self.maps {
+            return Ok(false);
+        }
+
+        let root = PathBuf::from(format!("/proc/{}/root", self.pid));
+        let mut objects = HashMap::new();
+        let mut mappings = Vec::new();
+        for (start, end, offset, path) in parse_maps(&maps) {
+            let object = match objects.get(path) {
+                Some(object) => Some(Rc::clone(object)),
+                None => {
+                    let object = self.objects.get(path).cloned().or_else(|| {
+                        // read through the root of the process, which may
+                        // be in a container
+                        let path = root.join(path.strip_prefix("/").unwrap_or(path));
+                        ObjectSymbols::load(&path).map(Rc::new)
+                    });
+                    if let Some(object) = &object {
+                        objects.insert(path.to_path_buf(), Rc::clone(object));
+                    }
+                    object
+                }
+            };
+            mappings.push(Mapping {
+                start,
+                end,
+                offset,
+                object,
+            });
+        }
+        mappings.sort_by_key(|m| m.start);
+
+        self.maps = maps;
+        self.mappings = mappings;
+        self.objects = objects;
+        Ok(true)
+    }
+
+    /// Returns the symbol containing the user space address `addr`.
+    pub fn resolve(&self, addr: u64) -> Option<Symbol> {
+        let idx = match self.mappings.binary_search_by_key(&addr, |m| m.start) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let mapping = &self.mappings[idx];
+        if addr >= mapping.end {
+            return None;
+        }
+        let object = mapping.object.as_ref()?;
+
+        object.resolve(addr - mapping.start + mapping.offset)
+    }
+}
+
+impl Symbolizer for ProcessSymbols {
+    fn symbol(&self, pid: u32, addr: u64) -> Option<String> {
+        if pid != self.pid {
+            return None;
+        }
+        self.resolve(addr).map(|symbol| symbol.name)
+    }
+}
+
+impl ObjectSymbols {
+    fn load(path: &Path) -> Option<ObjectSymbols> {
+        let bytes = fs::read(path).ok()?;
+        let elf = Elf::parse(&bytes).ok()?;
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == program_header::PT_LOAD)
+            .map(|ph| (ph.p_vaddr, ph.p_offset, ph.p_filesz))
+            .collect();
+        let mut symbols = Vec::new();
+        for (syms, strtab) in [(&elf.syms, &elf.strtab), (&elf.dynsyms, &elf.dynstrtab)].iter() {
+            for sym in syms.iter() {
+                if sym.st_value == 0 || !sym.is_function() {
+                    continue;
+                }
+                if let Some(Ok(name)) = strtab.get(sym.st_name) {
+                    symbols.push((sym.st_value, sym.st_size, name.to_string()));
+                }
+            }
+        }
+        symbols.sort();
+        symbols.dedup_by_key(|(addr, _, _)| *addr);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Some(ObjectSymbols {
+            name,
+            segments,
+            symbols,
+        })
+    }
+
+    // Mappings map file offsets, symbols have virtual addresses
+    fn resolve(&self, offset: u64) -> Option<Symbol> {
+        let (vaddr, seg_offset, _) = self
+            .segments
+            .iter()
+            .find(|(_, seg_offset, size)| *seg_offset <= offset && offset < seg_offset + size)?;
+        let vaddr = offset - seg_offset + vaddr;
+        let idx = match self.symbols.binary_search_by_key(&vaddr, |(a, _, _)| *a) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let (start, size, name) = &self.symbols[idx];
+        if *size != 0 && vaddr >= start + size {
+            return None;
+        }
+
+        Some(Symbol {
+            name: name.clone(),
+            offset: vaddr - start,
+            module: Some(self.name.clone()),
+        })
+    }
+}
+
+// The executable mappings of files, as `(start, end, offset, path)`
+fn parse_maps(maps: &str) -> impl Iterator<Item = (u64, u64, u64, &Path)> {
+    maps.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mut range = fields.next()?.splitn(2, '-');
+        let start = u64::from_str_radix(range.next()?, 16).ok()?;
+        let end = u64::from_str_radix(range.next()?, 16).ok()?;
+        let perms = fields.next()?;
+        let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let path = fields.nth(2)?;
+        if !perms.contains('x') || !path.starts_with('/') {
+            return None;
+        }
+
+        Some((start, end, offset, Path::new(path)))
+    })
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::symbols::{parse_maps, KernelSymbols, ProcessSymbols, Symbol};
+        use std::path::Path;
+
+        let symbols = KernelSymbols::parse(
+            "0000000000000000 A fixed_percpu_data
+ffffffff81000000 T _stext
+ffffffff81000100 T do_one_initcall
+ffffffffc0002000 t xt_init\t[x_tables]
+",
+        );
+        assert!(symbols.resolve(0xffff_ffff_8000_0000).is_none());
+        assert_eq!(
+            symbols.resolve(0xffff_ffff_8100_0110),
+            Some(Symbol {
+                name: "do_one_initcall".to_string(),
+                offset: 0x10,
+                module: None
+            })
+        );
+        assert_eq!(symbols.format(0xffff_ffff_8100_0000), "_stext+0x0");
+        assert_eq!(
+            symbols.format(0xffff_ffff_c000_2004),
+            "xt_init+0x4 [x_tables]"
+        );
+        assert!(KernelSymbols::parse("0000000000000000 T _stext").is_empty());
+
+        let maps: Vec<_> = parse_maps(
+            "55d4a8a00000-55d4a8a22000 r--p 00000000 08:01 1234 /usr/bin/bash
+55d4a8a22000-55d4a8b00000 r-xp 00022000 08:01 1234 /usr/bin/bash
+7ffd2a1e0000-7ffd2a202000 rw-p 00000000 00:00 0 [stack]
+7ffd2a3f0000-7ffd2a3f2000 r-xp 00000000 00:00 0 [vdso]
+",
+        )
+        .collect();
+        assert_eq!(
+            maps,
+            vec![(
+                0x55d4_a8a2_2000,
+                0x55d4_a8b0_0000,
+                0x22000,
+                Path::new("/usr/bin/bash")
+            )]
+        );
+
+        // the test binary isn't stripped
+        let symbols = ProcessSymbols::new(std::process::id()).unwrap();
+        let symbol = symbols.resolve(test as fn() as usize as u64).unwrap();
+        assert!(symbol.name.contains("symbols4test4test"));
+    }
+}
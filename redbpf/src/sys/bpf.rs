@@ -0,0 +1,399 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! `bpf()` commands not wrapped by `bpf-sys`.
+//!
+//! The attribute structs only include the fields used by each command. The
+//! kernel zero-fills the rest of `union bpf_attr`.
+#![allow(non_camel_case_types)]
+
+use libc::{c_int, c_void};
+use std::cmp;
+use std::io;
+use std::mem;
+
+pub const BPF_MAP_LOOKUP_AND_DELETE_ELEM: c_int = 21;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_map_elem_attr {
+    pub map_fd: u32,
+    pub _pad: u32,
+    pub key: u64,
+    pub value: u64,
+    pub flags: u64,
+}
+
+/// Calls `bpf(cmd, attr, sizeof(*attr))`.
+///
+/// # Safety
+///
+/// `attr` must be valid for `cmd`, including any pointers it contains.
+pub unsafe fn bpf<T>(cmd: c_int, attr: &mut T) -> c_int {
+    libc::syscall(
+        libc::SYS_bpf,
+        cmd,
+        attr as *mut T as *mut c_void,
+        mem::size_of::<T>() as u32,
+    ) as c_int
+}
+
+/// Looks up the element at `key` and deletes it from the map.
+///
+/// For queue and stack maps, `key` must be null.
+///
+/// # Safety
+///
+/// `key` and `value` must point to buffers of the map's key and value size.
+pub unsafe fn bpf_lookup_and_delete_elem(fd: c_int, key: *mut c_void, value: *mut c_void) -> c_int {
+    let mut attr = bpf_map_elem_attr {
+        map_fd: fd as u32,
+        key: key as u64,
+        value: value as u64,
+        ..Default::default()
+    };
+    bpf(BPF_MAP_LOOKUP_AND_DELETE_ELEM, &mut attr)
+}
+
+pub const BPF_MAP_LOOKUP_BATCH: c_int = 24;
+pub const BPF_MAP_LOOKUP_AND_DELETE_BATCH: c_int = 25;
+pub const BPF_MAP_UPDATE_BATCH: c_int = 26;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_map_batch_attr {
+    pub in_batch: u64,
+    pub out_batch: u64,
+    pub keys: u64,
+    pub values: u64,
+    pub count: u32,
+    pub map_fd: u32,
+    pub elem_flags: u64,
+    pub flags: u64,
+}
+
+/// Runs the batch command `cmd` on up to `*count` elements.
+///
+/// On return `*count` holds the number of elements processed, which can be
+/// non-zero even if the call fails with `ENOENT` at the end of the map.
+///
+/// # Safety
+///
+/// `keys` and `values` must point to buffers of `*count` keys and values.
+/// `in_batch` must be null or point to the `out_batch` token of the
+/// previous call, and `out_batch` must be large enough for a token.
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn bpf_map_batch(
+    cmd: c_int,
+    fd: c_int,
+    in_batch: *mut c_void,
+    out_batch: *mut c_void,
+    keys: *mut c_void,
+    values: *mut c_void,
+    count: &mut u32,
+    elem_flags: u64,
+) -> c_int {
+    let mut attr = bpf_map_batch_attr {
+        in_batch: in_batch as u64,
+        out_batch: out_batch as u64,
+        keys: keys as u64,
+        values: values as u64,
+        count: *count,
+        map_fd: fd as u32,
+        elem_flags,
+        ..Default::default()
+    };
+    let ret = bpf(cmd, &mut attr);
+    *count = attr.count;
+    ret
+}
+
+pub const BPF_LINK_CREATE: c_int = 28;
+pub const BPF_XDP: u32 = 37;
+pub const BPF_PERF_EVENT: u32 = 41;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_link_create_attr {
+    pub prog_fd: u32,
+    pub target_fd: u32,
+    pub attach_type: u32,
+    pub flags: u32,
+}
+
+/// Attaches the program `prog_fd` to `target_fd`, or to the interface
+/// `target_fd` for XDP, and returns the file descriptor of the link.
+///
+/// The program stays attached until the link is closed and unpinned.
+/// Requires Linux 5.7, 5.9 for XDP and 5.15 for perf events.
+pub fn bpf_link_create(prog_fd: c_int, target_fd: c_int, attach_type: u32, flags: u32) -> c_int {
+    let mut attr = bpf_link_create_attr {
+        prog_fd: prog_fd as u32,
+        target_fd: target_fd as u32,
+        attach_type,
+        flags,
+    };
+    unsafe { bpf(BPF_LINK_CREATE, &mut attr) }
+}
+
+pub const BPF_MAP_CREATE: c_int = 0;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_map_create_attr {
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub inner_map_fd: u32,
+    pub numa_node: u32,
+    pub map_name: [u8; 16],
+}
+
+/// Creates a map of maps, and returns its file descriptor.
+///
+/// `inner_map_fd` is a map of the same type and sizes as the maps that will
+/// be stored in the new map. The name is truncated to 15 bytes, and left
+/// out on kernels that don't support map names.
+pub fn bpf_create_map_in_map(
+    map_type: u32,
+    name: &str,
+    key_size: u32,
+    inner_map_fd: c_int,
+    max_entries: u32,
+    map_flags: u32,
+) -> c_int {
+    let mut attr = bpf_map_create_attr {
+        map_type,
+        key_size,
+        value_size: mem::size_of::<u32>() as u32,
+        max_entries,
+        map_flags,
+        inner_map_fd: inner_map_fd as u32,
+        ..Default::default()
+    };
+    let len = cmp::min(name.len(), attr.map_name.len() - 1);
+    attr.map_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    let fd = unsafe { bpf(BPF_MAP_CREATE, &mut attr) };
+    if fd < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+        attr.map_name = [0; 16];
+        return unsafe { bpf(BPF_MAP_CREATE, &mut attr) };
+    }
+    fd
+}
+
+pub const BPF_PROG_GET_FD_BY_ID: c_int = 13;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_get_fd_by_id_attr {
+    pub id: u32,
+    pub next_id: u32,
+    pub open_flags: u32,
+}
+
+/// Returns a file descriptor for the program with the given id.
+pub fn bpf_prog_get_fd_by_id(id: u32) -> c_int {
+    let mut attr = bpf_get_fd_by_id_attr {
+        id,
+        ..Default::default()
+    };
+    unsafe { bpf(BPF_PROG_GET_FD_BY_ID, &mut attr) }
+}
+
+pub const BPF_PROG_TEST_RUN: c_int = 10;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_prog_test_run_attr {
+    pub prog_fd: u32,
+    pub retval: u32,
+    pub data_size_in: u32,
+    pub data_size_out: u32,
+    pub data_in: u64,
+    pub data_out: u64,
+    pub repeat: u32,
+    pub duration: u32,
+}
+
+/// Runs the program `prog_fd` `repeat` times on `data_in`.
+///
+/// On return `retval` holds the return value of the program, `duration`
+/// the average run time in nanoseconds and `data_size_out` the size of the
+/// output. The call fails with `ENOSPC` if the output doesn't fit in
+/// `data_out`.
+///
+/// # Safety
+///
+/// `data_in` and `data_out` must point to buffers of `data_size_in` and
+/// `data_size_out` bytes.
+pub unsafe fn bpf_prog_test_run(attr: &mut bpf_prog_test_run_attr) -> c_int {
+    bpf(BPF_PROG_TEST_RUN, attr)
+}
+
+pub const BPF_ENABLE_STATS: c_int = 32;
+pub const BPF_STATS_RUN_TIME: u32 = 0;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_enable_stats_attr {
+    pub type_: u32,
+}
+
+/// Enables the statistics of type `type_` for all programs, and returns a
+/// file descriptor that keeps them enabled until it's closed.
+///
+/// Requires Linux 5.8.
+pub fn bpf_enable_stats(type_: u32) -> c_int {
+    let mut attr = bpf_enable_stats_attr { type_ };
+    unsafe { bpf(BPF_ENABLE_STATS, &mut attr) }
+}
+
+pub const BPF_PROG_GET_NEXT_ID: c_int = 11;
+pub const BPF_MAP_GET_NEXT_ID: c_int = 12;
+pub const BPF_MAP_GET_FD_BY_ID: c_int = 14;
+
+/// Stores the id following `start_id` of the programs or maps in `next_id`,
+/// depending on `cmd`. Fails with `ENOENT` after the last one.
+pub fn bpf_obj_get_next_id(cmd: c_int, start_id: u32, next_id: &mut u32) -> c_int {
+    let mut attr = bpf_get_fd_by_id_attr {
+        id: start_id,
+        ..Default::default()
+    };
+    let ret = unsafe { bpf(cmd, &mut attr) };
+    *next_id = attr.next_id;
+    ret
+}
+
+/// Returns a file descriptor for the map with the given id.
+pub fn bpf_map_get_fd_by_id(id: u32) -> c_int {
+    let mut attr = bpf_get_fd_by_id_attr {
+        id,
+        ..Default::default()
+    };
+    unsafe { bpf(BPF_MAP_GET_FD_BY_ID, &mut attr) }
+}
+
+pub const BPF_PROG_QUERY: c_int = 16;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_prog_query_attr {
+    pub target_fd: u32,
+    pub attach_type: u32,
+    pub query_flags: u32,
+    pub attach_flags: u32,
+    pub prog_ids: u64,
+    pub prog_cnt: u32,
+    // the tail padding of the union in the uapi, which must be zero
+    pub _pad: u32,
+}
+
+/// Stores the ids of the programs attached to `target_fd` with
+/// `attach_type` in `prog_ids`, and returns the number of programs in
+/// `*prog_cnt`.
+///
+/// Fails with `ENOSPC` if there are more programs than `prog_ids` holds.
+pub fn bpf_prog_query(
+    target_fd: c_int,
+    attach_type: u32,
+    prog_ids: &mut [u32],
+    prog_cnt: &mut u32,
+) -> c_int {
+    let mut attr = bpf_prog_query_attr {
+        target_fd: target_fd as u32,
+        attach_type,
+        query_flags: 0,
+        attach_flags: 0,
+        prog_ids: prog_ids.as_mut_ptr() as u64,
+        prog_cnt: prog_ids.len() as u32,
+        _pad: 0,
+    };
+    let ret = unsafe { bpf(BPF_PROG_QUERY, &mut attr) };
+    *prog_cnt = attr.prog_cnt;
+    ret
+}
+
+pub const BPF_PROG_LOAD: c_int = 5;
+pub const BPF_BTF_LOAD: c_int = 18;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_prog_load_attr {
+    pub prog_type: u32,
+    pub insn_cnt: u32,
+    pub insns: u64,
+    pub license: u64,
+    pub log_level: u32,
+    pub log_size: u32,
+    pub log_buf: u64,
+    pub kern_version: u32,
+    pub prog_flags: u32,
+    pub prog_name: [u8; 16],
+    pub prog_ifindex: u32,
+    pub expected_attach_type: u32,
+    pub prog_btf_fd: u32,
+    pub func_info_rec_size: u32,
+    pub func_info: u64,
+    pub func_info_cnt: u32,
+    pub line_info_rec_size: u32,
+    pub line_info: u64,
+    pub line_info_cnt: u32,
+    // keeps the struct free of padding, which the kernel would read as
+    // this field
+    pub attach_btf_id: u32,
+}
+
+/// Loads a program, and returns its file descriptor.
+///
+/// Unlike `bcc_prog_load()`, the program can be loaded with the BTF
+/// describing its functions and source lines, see `bpf_btf_load()`.
+///
+/// # Safety
+///
+/// The pointers of `attr` must point to buffers of the given sizes and
+/// numbers of records.
+pub unsafe fn bpf_prog_load(attr: &mut bpf_prog_load_attr) -> c_int {
+    bpf(BPF_PROG_LOAD, attr)
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bpf_btf_load_attr {
+    pub btf: u64,
+    pub btf_log_buf: u64,
+    pub btf_size: u32,
+    pub btf_log_size: u32,
+    pub btf_log_level: u32,
+    // set by newer kernels, and keeps the struct free of padding
+    pub btf_log_true_size: u32,
+}
+
+/// Loads the BTF in `data`, and returns its file descriptor.
+///
+/// The verifier logs at `log_level` to `log_buf`. Requires Linux 4.18.
+pub fn bpf_btf_load(data: &[u8], log_buf: &mut [u8], log_level: u32) -> c_int {
+    let mut attr = bpf_btf_load_attr {
+        btf: data.as_ptr() as u64,
+        btf_size: data.len() as u32,
+        ..Default::default()
+    };
+    if log_level > 0 {
+        attr.btf_log_buf = log_buf.as_mut_ptr() as u64;
+        attr.btf_log_size = log_buf.len() as u32;
+        attr.btf_log_level = log_level;
+    }
+    unsafe { bpf(BPF_BTF_LOAD, &mut attr) }
+}
+
+// Compiled into bpf-sys as part of the bundled libbpf, but not included in
+// its bindings. Both return a negative errno on failure.
+extern "C" {
+    pub fn bpf_set_link_xdp_fd(ifindex: c_int, fd: c_int, flags: u32) -> c_int;
+    pub fn bpf_get_link_xdp_id(ifindex: c_int, prog_id: *mut u32, flags: u32) -> c_int;
+}
@@ -0,0 +1,50 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Scripted syscalls for unit tests.
+//!
+//! `Syscalls` returns the results it's given in order, with the libc
+//! convention: failures return -1 and set `errno`, so that the code under
+//! test reads the error with `io::Error::last_os_error()` like after a real
+//! call.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use crate::retry::Errno;
+
+pub(crate) struct Syscalls {
+    results: RefCell<VecDeque<Result<i32, Errno>>>,
+    calls: Cell<usize>,
+}
+
+impl Syscalls {
+    pub(crate) fn new(results: Vec<Result<i32, Errno>>) -> Syscalls {
+        Syscalls {
+            results: RefCell::new(results.into()),
+            calls: Cell::new(0),
+        }
+    }
+
+    /// Returns the next result. Panics when there are none left.
+    pub(crate) fn call(&self) -> i32 {
+        self.calls.set(self.calls.get() + 1);
+        match self.results.borrow_mut().pop_front() {
+            Some(Ok(ret)) => ret,
+            Some(Err(errno)) => {
+                unsafe { *libc::__errno_location() = errno };
+                -1
+            }
+            None => panic!("unexpected syscall #{}", self.calls.get()),
+        }
+    }
+
+    /// Returns the number of calls made so far.
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.get()
+    }
+}
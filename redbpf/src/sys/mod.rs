@@ -5,4 +5,44 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::io;
+
+pub mod bpf;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod netlink;
 pub mod perf;
+
+/// Raises `RLIMIT_MEMLOCK` to infinity.
+///
+/// Kernels older than 5.11 charge maps and programs to the locked memory of
+/// the user, and fail to create them with `EPERM` when the limit is reached.
+/// Raising the limit requires `CAP_SYS_RESOURCE`.
+pub fn bump_memlock_rlimit() -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Returns the current `RLIMIT_MEMLOCK` in bytes, `None` if it's unlimited.
+pub fn memlock_rlimit() -> io::Result<Option<u64>> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        Ok(None)
+    } else {
+        Ok(Some(limit.rlim_cur))
+    }
+}
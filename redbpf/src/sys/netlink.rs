@@ -0,0 +1,244 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A minimal rtnetlink client.
+//!
+//! Only covers what's needed to manage TC qdiscs and filters: building
+//! requests with (nested) attributes, waiting for their acknowledgement and
+//! collecting the replies of dump requests.
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+pub const NLMSG_ERROR: u16 = 2;
+pub const NLMSG_DONE: u16 = 3;
+
+pub const NLM_F_REQUEST: u16 = 0x1;
+pub const NLM_F_ACK: u16 = 0x4;
+pub const NLM_F_DUMP: u16 = 0x300;
+pub const NLM_F_REPLACE: u16 = 0x100;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+
+const NLA_F_NESTED: u16 = 0x8000;
+
+const NLMSG_HDRLEN: usize = 16;
+const RTA_HDRLEN: usize = 4;
+
+const RECV_BUF_SIZE: usize = 32 * 1024;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A netlink request.
+pub struct Message {
+    buf: Vec<u8>,
+    // offsets of the nested attributes being built
+    nested: Vec<usize>,
+}
+
+impl Message {
+    /// Creates a request of type `msg_type`, followed by the family specific
+    /// `header`, eg. a `struct tcmsg`.
+    pub fn new(msg_type: u16, flags: u16, header: &[u8]) -> Message {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        buf.extend_from_slice(header);
+        buf.resize(align(buf.len()), 0);
+
+        Message {
+            buf,
+            nested: Vec::new(),
+        }
+    }
+
+    pub fn put_attr(&mut self, attr_type: u16, data: &[u8]) {
+        let len = (RTA_HDRLEN + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    pub fn put_u32(&mut self, attr_type: u16, value: u32) {
+        self.put_attr(attr_type, &value.to_ne_bytes());
+    }
+
+    /// Adds the NUL terminated string `value`.
+    pub fn put_str(&mut self, attr_type: u16, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.put_attr(attr_type, &data);
+    }
+
+    /// Starts a nested attribute. The attributes added until the matching
+    /// `end_nested()` are its payload.
+    pub fn begin_nested(&mut self, attr_type: u16) {
+        self.nested.push(self.buf.len());
+        self.put_attr(attr_type | NLA_F_NESTED, &[]);
+    }
+
+    pub fn end_nested(&mut self) {
+        let start = self.nested.pop().expect("no nested attribute to end");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    /// Returns the encoded request with the sequence number `seq`.
+    pub fn finish(mut self, seq: u32) -> Vec<u8> {
+        assert!(self.nested.is_empty(), "unterminated nested attribute");
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Splits `buf` into `(type, payload)` attribute pairs.
+pub fn parse_attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while buf.len() >= RTA_HDRLEN {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([buf[2], buf[3]]) & !NLA_F_NESTED;
+        if len < RTA_HDRLEN || len > buf.len() {
+            break;
+        }
+        attrs.push((attr_type, &buf[RTA_HDRLEN..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    attrs
+}
+
+/// A `NETLINK_ROUTE` socket.
+pub struct NetlinkSocket {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    pub fn open() -> io::Result<NetlinkSocket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = NetlinkSocket { fd, seq: 0 };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(sock)
+    }
+
+    /// Sends `msg` and waits for its acknowledgement. `msg` must have the
+    /// `NLM_F_ACK` flag.
+    pub fn request(&mut self, msg: Message) -> io::Result<()> {
+        let seq = self.send(msg)?;
+        loop {
+            for (msg_type, msg_seq, payload) in self.recv()? {
+                if msg_seq != seq {
+                    continue;
+                }
+                if msg_type == NLMSG_ERROR {
+                    return check_error(payload);
+                }
+            }
+        }
+    }
+
+    /// Sends the dump request `msg` and returns the payloads of the replies
+    /// of type `reply_type`.
+    pub fn dump(&mut self, msg: Message, reply_type: u16) -> io::Result<Vec<Vec<u8>>> {
+        let seq = self.send(msg)?;
+        let mut replies = Vec::new();
+        loop {
+            for (msg_type, msg_seq, payload) in self.recv()? {
+                if msg_seq != seq {
+                    continue;
+                }
+                match msg_type {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => check_error(payload)?,
+                    t if t == reply_type => replies.push(payload),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, msg: Message) -> io::Result<u32> {
+        self.seq += 1;
+        let buf = msg.finish(self.seq);
+        let ret = unsafe { libc::send(self.fd, buf.as_ptr() as *const _, buf.len(), 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(self.seq)
+    }
+
+    // Returns the (type, seq, payload) of the messages in the next datagram
+    fn recv(&mut self) -> io::Result<Vec<(u16, u32, Vec<u8>)>> {
+        let mut buf = vec![0u8; RECV_BUF_SIZE];
+        let len = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut msgs = Vec::new();
+        let mut buf = &buf[..len as usize];
+        while buf.len() >= NLMSG_HDRLEN {
+            let msg_len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if msg_len < NLMSG_HDRLEN || msg_len > buf.len() {
+                break;
+            }
+            let msg_type = u16::from_ne_bytes([buf[4], buf[5]]);
+            let seq = u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]);
+            msgs.push((msg_type, seq, buf[NLMSG_HDRLEN..msg_len].to_vec()));
+            buf = &buf[align(msg_len).min(buf.len())..];
+        }
+
+        Ok(msgs)
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// NLMSG_ERROR messages start with the negated errno, 0 for acknowledgements
+fn check_error(payload: Vec<u8>) -> io::Result<()> {
+    if payload.len() < 4 {
+        return Err(io::Error::from_raw_os_error(libc::EPROTO));
+    }
+    let err = i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(-err));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,377 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Validation of kprobe and tracepoint targets.
+//!
+//! Kernel functions get renamed, inlined or compiled out between versions
+//! and configurations, and attaching to a missing one fails with a generic
+//! error. `KernelTargets` checks targets against the functions and events
+//! of the running kernel, and suggests similarly named ones:
+//!
+//! ```no_run
+//! use redbpf::targets::KernelTargets;
+//!
+//! let targets = KernelTargets::load();
+//! if let Err(e) = targets.check_function("sys_clone") {
+//!     // "sys_clone isn't available in the running kernel, similar: __x64_sys_clone"
+//!     eprintln!("{}", e);
+//! }
+//! ```
+//!
+//! `Module::validate_targets()` checks all the kprobes and tracepoints of a
+//! module before anything is attached.
+//!
+//! `matching_functions()` lists the functions matching a glob, which
+//! `Program::attach_kprobe_matching()` attaches a kprobe to. The probe tells
+//! which function it runs for by its instruction pointer:
+//!
+//! ```no_run
+//! use redbpf::{HashMap, Module};
+//!
+//! let mut module = Module::parse(&std::fs::read("probe.elf").unwrap()).unwrap();
+//! let prog = module
+//!     .programs
+//!     .iter_mut()
+//!     .find(|p| p.name == "count_calls")
+//!     .unwrap();
+//! prog.load(module.version, module.license.clone()).unwrap();
+//! let report = prog.attach_kprobe_matching("tcp_*").unwrap();
+//! for (function, e) in report.failed.iter() {
+//!     eprintln!("{}: {}", function, e);
+//! }
+//!
+//! // the probe counts the calls by `Registers::ip()`
+//! let calls = HashMap::<u64, u64>::new(module.map("calls").unwrap()).unwrap();
+//! for (ip, count) in calls.iter() {
+//!     println!("{}: {}", report.function(ip).unwrap_or("?"), count);
+//! }
+//! ```
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::symbols::KernelSymbols;
+use crate::tracepoint::TRACEFS_PATHS;
+use crate::{Error, Result};
+
+const KALLSYMS: &str = "/proc/kallsyms";
+const MAX_SUGGESTIONS: usize = 5;
+// shorter names match too many functions to be useful suggestions
+const MIN_SUGGESTION_LEN: usize = 4;
+// The instruction pointer of kprobes is past the start of the function by
+// the size of the breakpoint, or of the instructions before the ftrace call
+const MAX_PROBE_IP_OFFSET: u64 = 16;
+
+/// The number of functions `Program::attach_kprobe_matching()` attaches to
+/// at most.
+pub const DEFAULT_MATCH_LIMIT: usize = 256;
+
+/// The functions that can be probed and the tracepoints of the running
+/// kernel.
+pub struct KernelTargets {
+    functions: Option<HashSet<String>>,
+    events: Option<HashSet<String>>,
+}
+
+impl KernelTargets {
+    /// Reads the functions from `available_filter_functions`, and the
+    /// tracepoints from `available_events`.
+    ///
+    /// `available_filter_functions` lists the functions that can be probed.
+    /// Only when it can't be read, eg. when tracefs isn't mounted, the text
+    /// symbols of `/proc/kallsyms` are used instead, which include
+    /// functions the kernel refuses to probe. Targets of a kind are not
+    /// checked if none of its sources can be read.
+    pub fn load() -> KernelTargets {
+        let functions = probeable_functions(read_tracefs("available_filter_functions"), || {
+            fs::read_to_string(KALLSYMS).ok()
+        });
+        let events = read_tracefs("available_events").map(|events| parse_events(&events).collect());
+
+        KernelTargets { functions, events }
+    }
+
+    /// Fails with `Error::TargetNotFound` if the kernel has no function
+    /// `name` that can be probed.
+    pub fn check_function(&self, name: &str) -> Result<()> {
+        match &self.functions {
+            Some(functions) if !functions.contains(name) => Err(Error::TargetNotFound(
+                name.to_string(),
+                suggestions(name, functions.iter()),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `Error::TargetNotFound` if the kernel has no tracepoint
+    /// `category/name`.
+    pub fn check_tracepoint(&self, category: &str, name: &str) -> Result<()> {
+        let event = format!("{}/{}", category, name);
+        match &self.events {
+            Some(events) if !events.contains(&event) => Err(Error::TargetNotFound(
+                event,
+                suggestions(name, events.iter()),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The functions a kprobe was attached to by
+/// `Program::attach_kprobe_matching()`.
+#[derive(Debug, Default)]
+pub struct AttachReport {
+    /// The functions the program is attached to
+    pub attached: Vec<String>,
+    /// The functions the program couldn't be attached to, eg. because the
+    /// kernel doesn't allow probing them
+    pub failed: Vec<(String, Error)>,
+    /// The number of matching functions skipped because of the limit
+    pub skipped: usize,
+    // the addresses of the attached functions, sorted
+    addresses: Vec<(u64, usize)>,
+}
+
+impl AttachReport {
+    /// Returns the attached function containing `ip`, the `Registers::ip()`
+    /// of the probe when it runs.
+    ///
+    /// Returns `None` for kretprobes, whose instruction pointer is the
+    /// return address, and if the addresses of `/proc/kallsyms` are hidden
+    /// by `kernel.kptr_restrict`.
+    pub fn function(&self, ip: u64) -> Option<&str> {
+        let idx = match self.addresses.binary_search_by_key(&ip, |(a, _)| *a) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let (addr, function) = self.addresses[idx];
+        if ip - addr > MAX_PROBE_IP_OFFSET {
+            return None;
+        }
+
+        Some(&self.attached[function])
+    }
+
+    pub(crate) fn resolve_addresses(&mut self, symbols: &KernelSymbols) {
+        let mut addresses: Vec<(u64, usize)> = symbols
+            .iter()
+            .filter_map(|(addr, name)| {
+                self.attached
+                    .binary_search_by(|f| f.as_str().cmp(name))
+                    .ok()
+                    .map(|function| (addr, function))
+            })
+            .collect();
+        addresses.sort();
+        self.addresses = addresses;
+    }
+}
+
+/// Returns the functions of `available_filter_functions` matching the glob
+/// `pattern`, sorted and without duplicates.
+///
+/// `*` matches any characters and `?` matches one character, eg. `tcp_*`
+/// or `vfs_?????`. Fails with `Error::IO` if tracefs isn't mounted.
+pub fn matching_functions(pattern: &str) -> Result<Vec<String>> {
+    let functions = read_tracefs("available_filter_functions").ok_or_else(|| {
+        Error::IO(io::Error::new(
+            io::ErrorKind::NotFound,
+            "available_filter_functions can't be read, is tracefs mounted?",
+        ))
+    })?;
+    let mut matches: Vec<String> = parse_filter_functions(&functions)
+        .into_iter()
+        .filter(|f| glob_match(pattern, f))
+        .collect();
+    matches.sort();
+    matches.dedup();
+
+    Ok(matches)
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    // the positions after the last `*` and in the name where it started
+    // matching, to backtrack to when the rest doesn't match
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, n));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn read_tracefs(file: &str) -> Option<String> {
+    TRACEFS_PATHS
+        .iter()
+        .find_map(|base| fs::read_to_string(Path::new(base).join(file)).ok())
+}
+
+// The functions of `available_filter_functions`, or the text symbols of
+// kallsyms if it can't be read
+fn probeable_functions<F>(filter_functions: Option<String>, kallsyms: F) -> Option<HashSet<String>>
+where
+    F: FnOnce() -> Option<String>,
+{
+    let functions = match filter_functions {
+        Some(functions) => parse_filter_functions(&functions),
+        None => parse_kallsyms(&kallsyms()?),
+    };
+
+    Some(functions.into_iter().collect())
+}
+
+// `do_sys_open` or `xt_init [x_tables]`
+fn parse_filter_functions(functions: &str) -> Vec<String> {
+    functions
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+// Only text symbols can be probed
+fn parse_kallsyms(kallsyms: &str) -> Vec<String> {
+    kallsyms
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            match (fields.next(), fields.next()) {
+                (Some("t"), Some(name)) | (Some("T"), Some(name)) => Some(name.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// `sock:inet_sock_set_state` is returned as `sock/inet_sock_set_state`, the
+// format of tracepoint program names
+fn parse_events<'a>(events: &'a str) -> impl Iterator<Item = String> + 'a {
+    events.lines().map(|line| line.trim().replacen(':', "/", 1))
+}
+
+// Targets containing `name` or contained in it, eg. `__x64_sys_clone` for
+// `sys_clone`, with the closest in length first
+fn suggestions<'a, I>(name: &str, targets: I) -> Vec<String>
+where
+    I: Iterator<Item = &'a String>,
+{
+    let mut matches: Vec<&String> = targets
+        .filter(|t| {
+            let target = t.rsplit('/').next().unwrap_or(t);
+            let shorter = target.len().min(name.len());
+            shorter >= MIN_SUGGESTION_LEN && (target.contains(name) || name.contains(target))
+        })
+        .collect();
+    matches.sort_by_key(|t| ((t.len() as isize - name.len() as isize).abs(), *t));
+
+    matches.into_iter().take(MAX_SUGGESTIONS).cloned().collect()
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::symbols::KernelSymbols;
+        use crate::targets::{glob_match, parse_events, parse_filter_functions, parse_kallsyms};
+        use crate::targets::{probeable_functions, suggestions, AttachReport, KernelTargets};
+        use crate::Error;
+
+        let functions = parse_filter_functions("do_sys_open\nxt_init [x_tables]\n");
+        assert_eq!(functions, vec!["do_sys_open", "xt_init"]);
+        let functions = parse_kallsyms(
+            "ffffffff81000000 T __x64_sys_clone
+ffffffff81000100 t tcp_v4_connect.cold
+ffffffff81000200 D init_task
+",
+        );
+        assert_eq!(functions, vec!["__x64_sys_clone", "tcp_v4_connect.cold"]);
+        let kallsyms = || Some("ffffffff81000000 T __x64_sys_clone\n".to_string());
+        let functions = probeable_functions(Some("do_sys_open\n".to_string()), kallsyms).unwrap();
+        assert!(functions.contains("do_sys_open"));
+        assert!(!functions.contains("__x64_sys_clone"));
+        let functions = probeable_functions(None, kallsyms).unwrap();
+        assert!(functions.contains("__x64_sys_clone"));
+        assert!(probeable_functions(None, || None).is_none());
+        let events: Vec<_> = parse_events("sock:inet_sock_set_state\n").collect();
+        assert_eq!(events, vec!["sock/inet_sock_set_state"]);
+
+        let names: Vec<String> = vec!["tcp_v4_connect.cold", "__x64_sys_clone", "tcp"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            suggestions("sys_clone", names.iter()),
+            vec!["__x64_sys_clone"]
+        );
+        assert_eq!(
+            suggestions("tcp_v4_connect", names.iter()),
+            vec!["tcp_v4_connect.cold"]
+        );
+        assert!(suggestions("vfs_read", names.iter()).is_empty());
+
+        let targets = KernelTargets {
+            functions: Some(names.into_iter().collect()),
+            events: None,
+        };
+        assert!(targets.check_function("__x64_sys_clone").is_ok());
+        match targets.check_function("sys_clone") {
+            Err(Error::TargetNotFound(name, similar)) => {
+                assert_eq!(name, "sys_clone");
+                assert_eq!(similar, vec!["__x64_sys_clone"]);
+            }
+            _ => panic!("unexpected result"),
+        }
+        // events can't be checked
+        assert!(targets
+            .check_tracepoint("sock", "inet_sock_set_state")
+            .is_ok());
+
+        assert!(glob_match("tcp_*", "tcp_v4_connect"));
+        assert!(glob_match("*_connect", "tcp_v4_connect"));
+        assert!(glob_match("tcp_*_*", "tcp_v4_connect"));
+        assert!(glob_match("tcp_v?_connect", "tcp_v6_connect"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("tcp_*", "udp_sendmsg"));
+        assert!(!glob_match("tcp_v?_connect", "tcp_v4_connect.cold"));
+        assert!(!glob_match("*_connect", "tcp_v4_connect.cold"));
+
+        let mut report = AttachReport {
+            attached: vec!["tcp_close".to_string(), "tcp_connect".to_string()],
+            ..Default::default()
+        };
+        report.resolve_addresses(&KernelSymbols::parse(
+            "ffffffff81000000 T tcp_close
+ffffffff81000100 T tcp_connect
+ffffffff81000200 T udp_sendmsg
+",
+        ));
+        assert_eq!(report.function(0xffff_ffff_8100_0001), Some("tcp_close"));
+        assert_eq!(report.function(0xffff_ffff_8100_0101), Some("tcp_connect"));
+        assert_eq!(report.function(0xffff_ffff_8100_0080), None);
+        assert_eq!(report.function(0xffff_ffff_8100_0201), None);
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Traffic Control classifiers.
+//!
+//! Classifier programs are attached with `Program::attach_tc()`, which
+//! installs them as `bpf` filters in direct-action mode on the `clsact`
+//! qdisc of the interface, creating the qdisc if needed. This is what
+//! `tc filter add dev <iface> <direction> prio <priority> handle <handle>
+//! bpf da` does, without depending on the `tc` binary.
+//!
+//! Filters are identified by their direction, priority and handle. Attaching
+//! a program where a filter is already installed replaces it, so tools can
+//! be restarted without detaching their programs first.
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::sys::netlink::{
+    parse_attrs, Message, NetlinkSocket, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL,
+    NLM_F_REPLACE, NLM_F_REQUEST,
+};
+use crate::xdp::ifindex;
+use crate::{Error, Result};
+
+const RTM_NEWQDISC: u16 = 36;
+const RTM_NEWTFILTER: u16 = 44;
+const RTM_DELTFILTER: u16 = 45;
+const RTM_GETTFILTER: u16 = 46;
+
+const TCA_KIND: u16 = 1;
+const TCA_OPTIONS: u16 = 2;
+
+const TCA_BPF_FD: u16 = 6;
+const TCA_BPF_NAME: u16 = 7;
+const TCA_BPF_FLAGS: u16 = 8;
+const TCA_BPF_ID: u16 = 11;
+
+const TCA_BPF_FLAG_ACT_DIRECT: u32 = 1;
+
+const TC_H_CLSACT: u32 = 0xffff_fff1;
+const TC_H_MIN_INGRESS: u32 = 0xfff2;
+const TC_H_MIN_EGRESS: u32 = 0xfff3;
+
+/// The direction of the packets a classifier is run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+impl Direction {
+    fn parent(self) -> u32 {
+        let minor = match self {
+            Direction::Ingress => TC_H_MIN_INGRESS,
+            Direction::Egress => TC_H_MIN_EGRESS,
+        };
+
+        TC_H_CLSACT & 0xffff_0000 | minor
+    }
+}
+
+/// A `bpf` filter installed on an interface, as returned by `query_tc()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcFilter {
+    pub priority: u16,
+    pub handle: u32,
+    /// The name passed when the filter was installed. `attach_tc()` uses the
+    /// name of the program.
+    pub name: String,
+    /// The id of the program, `None` on kernels older than 4.13
+    pub id: Option<u32>,
+    pub direct_action: bool,
+}
+
+/// Detaches the classifier installed with `priority` and `handle`.
+///
+/// The `clsact` qdisc is left in place since other filters may use it.
+pub fn detach_tc(iface: &str, direction: Direction, priority: u16, handle: u32) -> Result<()> {
+    let ifindex = ifindex(iface)?;
+    let header = tcmsg(ifindex, handle, direction.parent(), filter_info(priority));
+    let mut msg = Message::new(RTM_DELTFILTER, NLM_F_REQUEST | NLM_F_ACK, &header);
+    msg.put_str(TCA_KIND, "bpf");
+
+    NetlinkSocket::open()?.request(msg).map_err(Error::IO)
+}
+
+/// Returns the `bpf` filters installed on `iface` for `direction`.
+///
+/// Returns an empty list if the interface doesn't have a `clsact` qdisc.
+pub fn query_tc(iface: &str, direction: Direction) -> Result<Vec<TcFilter>> {
+    let ifindex = ifindex(iface)?;
+    let header = tcmsg(ifindex, 0, direction.parent(), 0);
+    let msg = Message::new(RTM_GETTFILTER, NLM_F_REQUEST | NLM_F_DUMP, &header);
+    let replies = match NetlinkSocket::open()?.dump(msg, RTM_NEWTFILTER) {
+        Ok(replies) => replies,
+        Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(Vec::new()),
+        Err(e) => return Err(Error::IO(e)),
+    };
+
+    Ok(replies.iter().filter_map(|r| parse_filter(r)).collect())
+}
+
+pub(crate) fn attach(
+    iface: &str,
+    direction: Direction,
+    priority: u16,
+    handle: u32,
+    fd: RawFd,
+    name: &str,
+) -> Result<()> {
+    if priority == 0 || handle == 0 {
+        return Err(Error::IO(io::Error::from_raw_os_error(libc::EINVAL)));
+    }
+    let ifindex = ifindex(iface)?;
+    let mut sock = NetlinkSocket::open()?;
+
+    let header = tcmsg(ifindex, TC_H_CLSACT & 0xffff_0000, TC_H_CLSACT, 0);
+    let flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL;
+    let mut msg = Message::new(RTM_NEWQDISC, flags, &header);
+    msg.put_str(TCA_KIND, "clsact");
+    match sock.request(msg) {
+        Ok(()) => {}
+        Err(ref e) if e.raw_os_error() == Some(libc::EEXIST) => {}
+        Err(e) => return Err(Error::IO(e)),
+    }
+
+    let header = tcmsg(ifindex, handle, direction.parent(), filter_info(priority));
+    let flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE;
+    let mut msg = Message::new(RTM_NEWTFILTER, flags, &header);
+    msg.put_str(TCA_KIND, "bpf");
+    msg.begin_nested(TCA_OPTIONS);
+    msg.put_u32(TCA_BPF_FD, fd as u32);
+    msg.put_str(TCA_BPF_NAME, name);
+    msg.put_u32(TCA_BPF_FLAGS, TCA_BPF_FLAG_ACT_DIRECT);
+    msg.end_nested();
+
+    sock.request(msg).map_err(Error::IO)
+}
+
+// Filters match all the protocols
+fn filter_info(priority: u16) -> u32 {
+    (priority as u32) << 16 | (libc::ETH_P_ALL as u16).to_be() as u32
+}
+
+// struct tcmsg
+fn tcmsg(ifindex: u32, handle: u32, parent: u32, info: u32) -> Vec<u8> {
+    let mut header = vec![0u8; 4];
+    header[0] = libc::AF_UNSPEC as u8;
+    header.extend_from_slice(&(ifindex as i32).to_ne_bytes());
+    header.extend_from_slice(&handle.to_ne_bytes());
+    header.extend_from_slice(&parent.to_ne_bytes());
+    header.extend_from_slice(&info.to_ne_bytes());
+
+    header
+}
+
+fn parse_filter(reply: &[u8]) -> Option<TcFilter> {
+    if reply.len() < 20 {
+        return None;
+    }
+    let u32_at =
+        |buf: &[u8], i: usize| u32::from_ne_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+    let handle = u32_at(reply, 8);
+    let info = u32_at(reply, 16);
+    // the kernel also dumps an entry without handle for each priority
+    if handle == 0 {
+        return None;
+    }
+
+    let attrs = parse_attrs(&reply[20..]);
+    let kind = attrs.iter().find(|(t, _)| *t == TCA_KIND)?.1;
+    if kind != b"bpf\0" {
+        return None;
+    }
+    let mut filter = TcFilter {
+        priority: (info >> 16) as u16,
+        handle,
+        name: String::new(),
+        id: None,
+        direct_action: false,
+    };
+    let options = attrs.iter().find(|(t, _)| *t == TCA_OPTIONS)?.1;
+    for (attr_type, data) in parse_attrs(options) {
+        match attr_type {
+            TCA_BPF_NAME => {
+                let name = data.split(|b| *b == 0).next().unwrap_or(&[]);
+                filter.name = String::from_utf8_lossy(name).into_owned();
+            }
+            TCA_BPF_ID if data.len() >= 4 => filter.id = Some(u32_at(data, 0)),
+            TCA_BPF_FLAGS if data.len() >= 4 => {
+                filter.direct_action = u32_at(data, 0) & TCA_BPF_FLAG_ACT_DIRECT != 0
+            }
+            _ => {}
+        }
+    }
+
+    Some(filter)
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::sys::netlink::Message;
+        use crate::tc::{parse_filter, tcmsg, Direction, TCA_BPF_ID, TCA_BPF_NAME, TCA_KIND};
+        use crate::tc::{TCA_BPF_FLAGS, TCA_BPF_FLAG_ACT_DIRECT, TCA_OPTIONS};
+
+        assert_eq!(Direction::Ingress.parent(), 0xffff_fff2);
+        assert_eq!(Direction::Egress.parent(), 0xffff_fff3);
+
+        // a dumped filter is a tcmsg followed by the attributes
+        let build = |handle| {
+            let mut msg = Message::new(0, 0, &tcmsg(1, handle, 0, 5 << 16));
+            msg.put_str(TCA_KIND, "bpf");
+            msg.begin_nested(TCA_OPTIONS);
+            msg.put_u32(TCA_BPF_ID, 42);
+            msg.put_str(TCA_BPF_NAME, "block");
+            msg.put_u32(TCA_BPF_FLAGS, TCA_BPF_FLAG_ACT_DIRECT);
+            msg.end_nested();
+            msg.finish(0)[16..].to_vec()
+        };
+        let filter = parse_filter(&build(1)).unwrap();
+        assert_eq!(filter.priority, 5);
+        assert_eq!(filter.handle, 1);
+        assert_eq!(filter.name, "block");
+        assert_eq!(filter.id, Some(42));
+        assert!(filter.direct_action);
+        assert!(parse_filter(&build(0)).is_none());
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Running programs on crafted packets.
+//!
+//! `Program::test_run()` runs XDP, socket filter and classifier programs on
+//! a packet with `BPF_PROG_TEST_RUN`, without attaching them to an
+//! interface, so they can be tested with `cargo test`. `PacketBuilder`
+//! builds the packets:
+//!
+//! ```no_run
+//! use redbpf::test_run::PacketBuilder;
+//! use redbpf::Module;
+//!
+//! let mut module = Module::parse(&std::fs::read("block_http.elf").unwrap()).unwrap();
+//! let prog = module
+//!     .programs
+//!     .iter_mut()
+//!     .find(|p| p.name == "block_port_80")
+//!     .unwrap();
+//! prog.load(module.version, module.license.clone()).unwrap();
+//!
+//! let packet = PacketBuilder::new()
+//!     .ipv4([10, 0, 0, 1].into(), [10, 0, 0, 2].into())
+//!     .tcp(41000, 80, 0x02)
+//!     .build();
+//! let result = prog.test_run(&packet, 1).unwrap();
+//! // XDP_DROP
+//! assert_eq!(result.return_value, 1);
+//! ```
+use std::net::Ipv4Addr;
+
+// The room the kernel leaves in front of the packets of XDP programs
+pub(crate) const OUTPUT_HEADROOM: usize = 256;
+
+const ETH_HLEN: usize = 14;
+const ETH_P_IP: u16 = 0x0800;
+const IPV4_HLEN: usize = 20;
+const UDP_HLEN: usize = 8;
+const TCP_HLEN: usize = 20;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// The result of `Program::test_run()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestRunResult {
+    /// The return value of the program, eg. an `XdpAction`
+    pub return_value: u32,
+    /// The packet after the program ran, including any changes it made
+    pub output: Vec<u8>,
+    /// The average duration of a run in nanoseconds, as measured by the
+    /// kernel
+    pub duration_ns: u32,
+}
+
+/// Builds Ethernet frames carrying IPv4 with a UDP or TCP header.
+///
+/// Lengths and checksums are filled in by `build()`. Frames without an IP
+/// header carry only the payload.
+#[derive(Debug, Clone)]
+pub struct PacketBuilder {
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+    ipv4: Option<(Ipv4Addr, Ipv4Addr)>,
+    ttl: u8,
+    transport: Option<Transport>,
+    payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Udp(u16, u16),
+    Tcp(u16, u16, u8),
+}
+
+impl PacketBuilder {
+    pub fn new() -> PacketBuilder {
+        PacketBuilder {
+            src_mac: [0; 6],
+            dst_mac: [0; 6],
+            ipv4: None,
+            ttl: 64,
+            transport: None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Sets the MAC addresses, zero by default.
+    pub fn eth(&mut self, src: [u8; 6], dst: [u8; 6]) -> &mut Self {
+        self.src_mac = src;
+        self.dst_mac = dst;
+        self
+    }
+
+    pub fn ipv4(&mut self, src: Ipv4Addr, dst: Ipv4Addr) -> &mut Self {
+        self.ipv4 = Some((src, dst));
+        self
+    }
+
+    /// Sets the TTL of the IPv4 header, 64 by default.
+    pub fn ttl(&mut self, ttl: u8) -> &mut Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Adds a UDP header. Requires an IPv4 header.
+    pub fn udp(&mut self, src_port: u16, dst_port: u16) -> &mut Self {
+        self.transport = Some(Transport::Udp(src_port, dst_port));
+        self
+    }
+
+    /// Adds a TCP header with `flags`, eg. `0x02` for SYN. Requires an IPv4
+    /// header.
+    pub fn tcp(&mut self, src_port: u16, dst_port: u16, flags: u8) -> &mut Self {
+        self.transport = Some(Transport::Tcp(src_port, dst_port, flags));
+        self
+    }
+
+    pub fn payload(&mut self, payload: &[u8]) -> &mut Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(ETH_HLEN + IPV4_HLEN + TCP_HLEN + self.payload.len());
+        packet.extend_from_slice(&self.dst_mac);
+        packet.extend_from_slice(&self.src_mac);
+        let (src, dst) = match self.ipv4 {
+            Some(addrs) => addrs,
+            None => {
+                packet.extend_from_slice(&[0, 0]);
+                packet.extend_from_slice(&self.payload);
+                return packet;
+            }
+        };
+        packet.extend_from_slice(&ETH_P_IP.to_be_bytes());
+
+        let transport = self.transport_header(src, dst);
+        let protocol = match self.transport {
+            Some(Transport::Udp(..)) => IPPROTO_UDP,
+            Some(Transport::Tcp(..)) => IPPROTO_TCP,
+            None => 0,
+        };
+        let total_len = (IPV4_HLEN + transport.len() + self.payload.len()) as u16;
+        let mut ip = vec![0x45, 0];
+        ip.extend_from_slice(&total_len.to_be_bytes());
+        // id, flags and fragment offset
+        ip.extend_from_slice(&[0, 0, 0x40, 0]);
+        ip.extend_from_slice(&[self.ttl, protocol, 0, 0]);
+        ip.extend_from_slice(&src.octets());
+        ip.extend_from_slice(&dst.octets());
+        let csum = checksum(&ip);
+        ip[10..12].copy_from_slice(&csum.to_be_bytes());
+
+        packet.extend_from_slice(&ip);
+        packet.extend_from_slice(&transport);
+        packet.extend_from_slice(&self.payload);
+        packet
+    }
+
+    fn transport_header(&self, src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+        let (mut header, protocol, csum_offset) = match self.transport {
+            Some(Transport::Udp(src_port, dst_port)) => {
+                let len = (UDP_HLEN + self.payload.len()) as u16;
+                let mut header = Vec::new();
+                header.extend_from_slice(&src_port.to_be_bytes());
+                header.extend_from_slice(&dst_port.to_be_bytes());
+                header.extend_from_slice(&len.to_be_bytes());
+                header.extend_from_slice(&[0, 0]);
+                (header, IPPROTO_UDP, 6)
+            }
+            Some(Transport::Tcp(src_port, dst_port, flags)) => {
+                let mut header = Vec::new();
+                header.extend_from_slice(&src_port.to_be_bytes());
+                header.extend_from_slice(&dst_port.to_be_bytes());
+                // sequence and acknowledgment numbers
+                header.extend_from_slice(&[0; 8]);
+                header.extend_from_slice(&[(TCP_HLEN as u8 / 4) << 4, flags]);
+                // window, checksum and urgent pointer
+                header.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+                (header, IPPROTO_TCP, 16)
+            }
+            None => return Vec::new(),
+        };
+
+        // the checksum covers a pseudo header with the addresses
+        let len = (header.len() + self.payload.len()) as u16;
+        let mut pseudo = Vec::new();
+        pseudo.extend_from_slice(&src.octets());
+        pseudo.extend_from_slice(&dst.octets());
+        pseudo.extend_from_slice(&[0, protocol]);
+        pseudo.extend_from_slice(&len.to_be_bytes());
+        pseudo.extend_from_slice(&header);
+        pseudo.extend_from_slice(&self.payload);
+        let csum = match checksum(&pseudo) {
+            // 0 means no checksum for UDP
+            0 if protocol == IPPROTO_UDP => 0xffff,
+            csum => csum,
+        };
+        header[csum_offset..csum_offset + 2].copy_from_slice(&csum.to_be_bytes());
+
+        header
+    }
+}
+
+impl Default for PacketBuilder {
+    fn default() -> PacketBuilder {
+        PacketBuilder::new()
+    }
+}
+
+// The internet checksum of `data`, RFC 1071
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).fold(0u32, |sum, chunk| {
+        let word = u16::from_be_bytes([chunk[0], chunk.get(1).cloned().unwrap_or(0)]);
+        sum + u32::from(word)
+    });
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::test_run::{checksum, PacketBuilder, ETH_HLEN, IPV4_HLEN, UDP_HLEN};
+
+        let packet = PacketBuilder::new()
+            .eth([1, 2, 3, 4, 5, 6], [6, 5, 4, 3, 2, 1])
+            .ipv4([10, 0, 0, 1].into(), [10, 0, 0, 2].into())
+            .udp(5353, 53)
+            .payload(b"query")
+            .build();
+        assert_eq!(packet.len(), ETH_HLEN + IPV4_HLEN + UDP_HLEN + 5);
+        assert_eq!(&packet[0..6], &[6, 5, 4, 3, 2, 1]);
+        assert_eq!(&packet[12..14], &[0x08, 0x00]);
+
+        let ip = &packet[ETH_HLEN..ETH_HLEN + IPV4_HLEN];
+        assert_eq!(u16::from_be_bytes([ip[2], ip[3]]), 33);
+        assert_eq!(ip[9], 17);
+        // a valid header sums to 0xffff, which complements to 0
+        assert_eq!(checksum(ip), 0);
+
+        let udp = &packet[ETH_HLEN + IPV4_HLEN..];
+        assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), 53);
+        assert_eq!(u16::from_be_bytes([udp[4], udp[5]]), 13);
+        let mut pseudo = vec![10, 0, 0, 1, 10, 0, 0, 2, 0, 17, 0, 13];
+        pseudo.extend_from_slice(udp);
+        assert_eq!(checksum(&pseudo), 0);
+
+        let packet = PacketBuilder::new()
+            .ipv4([10, 0, 0, 1].into(), [10, 0, 0, 2].into())
+            .tcp(41000, 80, 0x02)
+            .build();
+        assert_eq!(packet.len(), ETH_HLEN + IPV4_HLEN + 20);
+        assert_eq!(packet[ETH_HLEN + IPV4_HLEN + 13], 0x02);
+
+        assert_eq!(
+            PacketBuilder::new().payload(b"raw").build().len(),
+            ETH_HLEN + 3
+        );
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2019-2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Tracepoint record layouts.
+//!
+//! The kernel describes the record passed to tracepoint programs in the
+//! `format` file of each tracepoint. Programs compiled against a fixed layout
+//! read garbage when the running kernel uses a different one, so the layout
+//! should be checked before the programs are attached:
+//!
+//! ```no_run
+//! use redbpf::tracepoint::Format;
+//!
+//! let format = Format::load("sock", "inet_sock_set_state").unwrap();
+//! format.check_layout(&[("skaddr", 8, 8), ("oldstate", 16, 4)]).unwrap();
+//! ```
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::{Error, Result};
+
+pub(crate) const TRACEFS_PATHS: [&str; 2] = ["/sys/kernel/debug/tracing", "/sys/kernel/tracing"];
+
+// Reads the id of the `category/name` tracepoint, which perf events of the
+// tracepoint are opened with
+pub(crate) fn id(category: &str, name: &str) -> io::Result<u64> {
+    let path = TRACEFS_PATHS
+        .iter()
+        .map(|base| {
+            PathBuf::from(base)
+                .join("events")
+                .join(category)
+                .join(name)
+                .join("id")
+        })
+        .find(|path| path.exists())
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+/// A field of a tracepoint record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    /// The C declaration of the field, eg. `__u8 saddr[4]`.
+    pub decl: String,
+    pub offset: usize,
+    pub size: usize,
+    pub signed: bool,
+}
+
+impl Field {
+    /// Returns `true` for `__data_loc` fields, which store the offset and
+    /// length of variable length data placed after the fixed size fields.
+    pub fn is_data_loc(&self) -> bool {
+        self.decl.starts_with("__data_loc")
+    }
+
+    /// Returns `true` if the field is a pointer.
+    pub fn is_pointer(&self) -> bool {
+        !self.is_data_loc() && self.decl.contains('*')
+    }
+
+    /// Returns the number of elements of fixed size array fields.
+    pub fn array_len(&self) -> Option<usize> {
+        if self.is_data_loc() {
+            return None;
+        }
+        let start = self.decl.find('[')?;
+        let end = self.decl[start..].find(']')? + start;
+        self.decl[start + 1..end].trim().parse().ok()
+    }
+}
+
+/// The parsed `format` file of a tracepoint.
+#[derive(Debug, Clone)]
+pub struct Format {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+impl Format {
+    /// Reads the format of the `category/name` tracepoint from tracefs.
+    pub fn load(category: &str, name: &str) -> Result<Format> {
+        let path = TRACEFS_PATHS
+            .iter()
+            .map(|base| {
+                PathBuf::from(base)
+                    .join("events")
+                    .join(category)
+                    .join(name)
+                    .join("format")
+            })
+            .find(|path| path.exists())
+            .ok_or_else(|| {
+                Error::TracepointFormat(format!("tracepoint not found: {}/{}", category, name))
+            })?;
+
+        Format::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the contents of a tracepoint `format` file.
+    pub fn parse(format: &str) -> Result<Format> {
+        let mut name = String::new();
+        let mut fields = Vec::new();
+        for line in format.lines().map(str::trim) {
+            if line.starts_with("name:") {
+                name = line.trim_start_matches("name:").trim().to_string();
+                continue;
+            }
+            if !line.starts_with("field:") {
+                continue;
+            }
+
+            let mut decl = None;
+            let mut offset = None;
+            let mut size = None;
+            let mut signed = false;
+            for attr in line.split(';').map(str::trim) {
+                let mut kv = attr.splitn(2, ':');
+                match (kv.next(), kv.next()) {
+                    (Some("field"), Some(v)) => decl = Some(v),
+                    (Some("offset"), Some(v)) => offset = v.parse().ok(),
+                    (Some("size"), Some(v)) => size = v.parse().ok(),
+                    (Some("signed"), Some(v)) => signed = v == "1",
+                    _ => {}
+                }
+            }
+
+            match (decl, decl.and_then(field_name), offset, size) {
+                (Some(decl), Some(name), Some(offset), Some(size)) => fields.push(Field {
+                    name: name.to_string(),
+                    decl: decl.trim().to_string(),
+                    offset,
+                    size,
+                    signed,
+                }),
+                _ => {
+                    return Err(Error::TracepointFormat(format!(
+                        "invalid field definition: {}",
+                        line
+                    )))
+                }
+            }
+        }
+
+        Ok(Format { name, fields })
+    }
+
+    /// Returns the field called `name`.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Checks that the record contains the `(name, offset, size)` fields in
+    /// `expected`.
+    ///
+    /// Returns `Error::TracepointFormat` describing the first mismatching
+    /// field.
+    pub fn check_layout(&self, expected: &[(&str, usize, usize)]) -> Result<()> {
+        for (name, offset, size) in expected.iter() {
+            match self.field(name) {
+                Some(f) if f.offset == *offset && f.size == *size => {}
+                Some(f) => {
+                    return Err(Error::TracepointFormat(format!(
+                        "{}: field `{}` has offset {} and size {}, expected offset {} and size {}",
+                        self.name, name, f.offset, f.size, offset, size
+                    )))
+                }
+                None => {
+                    return Err(Error::TracepointFormat(format!(
+                        "{}: field `{}` not found",
+                        self.name, name
+                    )))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Extracts `saddr` from declarations like `__u8 saddr[4]`
+fn field_name(decl: &str) -> Option<&str> {
+    let name = decl
+        .trim()
+        .rsplit(|c: char| c.is_whitespace() || c == '*')
+        .next()?;
+    let name = match name.find('[') {
+        Some(idx) => &name[..idx],
+        None => name,
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::tracepoint::Format;
+
+        let format = Format::parse(
+            "name: inet_sock_set_state
+ID: 1342
+format:
+	field:unsigned short common_type;	offset:0;	size:2;	signed:0;
+	field:int common_pid;	offset:4;	size:4;	signed:1;
+
+	field:const void * skaddr;	offset:8;	size:8;	signed:0;
+	field:int oldstate;	offset:16;	size:4;	signed:1;
+	field:__u8 saddr[4];	offset:31;	size:4;	signed:0;
+	field:__data_loc char[] name;	offset:36;	size:4;	signed:1;
+
+print fmt: \"family=%s\"",
+        )
+        .unwrap();
+        assert_eq!(format.name, "inet_sock_set_state");
+        assert_eq!(format.fields.len(), 6);
+        let pid = format.field("common_pid").unwrap();
+        assert_eq!((pid.offset, pid.size, pid.signed), (4, 4, true));
+        let skaddr = format.field("skaddr").unwrap();
+        assert!(skaddr.is_pointer());
+        assert_eq!(skaddr.array_len(), None);
+        let saddr = format.field("saddr").unwrap();
+        assert_eq!(saddr.decl, "__u8 saddr[4]");
+        assert_eq!(saddr.array_len(), Some(4));
+        let name = format.field("name").unwrap();
+        assert!(name.is_data_loc() && !name.is_pointer());
+        assert_eq!(name.array_len(), None);
+        assert!(format
+            .check_layout(&[("skaddr", 8, 8), ("oldstate", 16, 4), ("saddr", 31, 4)])
+            .is_ok());
+        assert!(format.check_layout(&[("oldstate", 20, 4)]).is_err());
+        assert!(format.check_layout(&[("newstate", 20, 4)]).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Uprobe targets.
+//!
+//! Uprobes are attached to a file offset in an executable or shared
+//! library. `Program::attach_uprobe()` computes the offset of a symbol with
+//! `resolve_symbol()`, and looks up bare library names like `libssl` with
+//! `find_library()`, so tools don't need to hard-code distribution specific
+//! paths:
+//!
+//! ```no_run
+//! use redbpf::Module;
+//!
+//! let mut module = Module::parse(&std::fs::read("ssl.elf").unwrap()).unwrap();
+//! for prog in module.programs.iter_mut() {
+//!     prog.load(module.version, module.license.clone()).unwrap();
+//!     prog.attach_uprobe("libssl", "SSL_write", None).unwrap();
+//! }
+//! ```
+use goblin::elf::{program_header, Elf};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, Result};
+
+const LD_SO_CACHE: &str = "/etc/ld.so.cache";
+const CACHE_MAGIC_NEW: &[u8] = b"glibc-ld.so.cache1.1";
+const CACHE_HEADER_SIZE: usize = 48;
+const CACHE_ENTRY_SIZE: usize = 24;
+
+// the type and the architecture of the libraries in ld.so.cache
+const FLAG_ELF_LIBC6: u32 = 0x0003;
+#[cfg(target_arch = "x86_64")]
+const FLAG_ARCH: u32 = 0x0300;
+#[cfg(target_arch = "aarch64")]
+const FLAG_ARCH: u32 = 0x0a00;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+const FLAG_ARCH: u32 = 0;
+
+const LIBRARY_PATHS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib", "/usr/local/lib"];
+#[cfg(target_arch = "x86_64")]
+const ARCH_LIBRARY_PATHS: &[&str] = &["/lib/x86_64-linux-gnu", "/usr/lib/x86_64-linux-gnu"];
+#[cfg(target_arch = "aarch64")]
+const ARCH_LIBRARY_PATHS: &[&str] = &["/lib/aarch64-linux-gnu", "/usr/lib/aarch64-linux-gnu"];
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+const ARCH_LIBRARY_PATHS: &[&str] = &[];
+
+/// Returns the file offset of `symbol` in the ELF file at `path`.
+///
+/// Looks `symbol` up in the symbol table and then the dynamic symbol table.
+/// Fails with `Error::NoSymbols` if the file has neither, eg. when it's
+/// stripped, and with `Error::SymbolNotFound` if it's not defined.
+pub fn resolve_symbol<P: AsRef<Path>>(path: P, symbol: &str) -> Result<u64> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    let elf = Elf::parse(&bytes)?;
+    if elf.syms.is_empty() && elf.dynsyms.is_empty() {
+        return Err(Error::NoSymbols(path.to_path_buf()));
+    }
+
+    let find = |syms: &goblin::elf::Symtab, strtab: &goblin::strtab::Strtab| {
+        syms.iter()
+            .find(|sym| {
+                sym.st_value != 0
+                    && sym.is_function()
+                    && strtab.get_unsafe(sym.st_name) == Some(symbol)
+            })
+            .map(|sym| sym.st_value)
+    };
+    let addr = find(&elf.syms, &elf.strtab)
+        .or_else(|| find(&elf.dynsyms, &elf.dynstrtab))
+        .ok_or_else(|| Error::SymbolNotFound(path.to_path_buf(), symbol.to_string()))?;
+
+    Ok(file_offset(&elf, addr))
+}
+
+// Uprobes take file offsets, not virtual addresses
+pub(crate) fn file_offset(elf: &Elf, addr: u64) -> u64 {
+    elf.program_headers
+        .iter()
+        .find(|ph| {
+            ph.p_type == program_header::PT_LOAD
+                && ph.p_vaddr <= addr
+                && addr < ph.p_vaddr + ph.p_memsz
+        })
+        .map(|ph| addr - ph.p_vaddr + ph.p_offset)
+        .unwrap_or(addr)
+}
+
+/// Returns the path of the shared library `name`, eg. `libssl` or
+/// `libssl.so.1.1`.
+///
+/// Paths are returned as they are. Libraries are looked up in
+/// `/etc/ld.so.cache`, and then in the standard library directories.
+pub fn find_library(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name));
+    }
+
+    if let Ok(cache) = fs::read(LD_SO_CACHE) {
+        if let Some(path) = parse_ld_so_cache(&cache, name) {
+            return Some(path);
+        }
+    }
+
+    let mut dirs = ARCH_LIBRARY_PATHS.iter().chain(LIBRARY_PATHS);
+    dirs.find_map(|dir| {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| match p.file_name().and_then(|f| f.to_str()) {
+                Some(file_name) => matches_library(file_name, name),
+                None => false,
+            })
+            .collect();
+        entries.sort();
+        entries.into_iter().next()
+    })
+}
+
+// `libssl` matches `libssl.so` and `libssl.so.1.1`
+fn matches_library(file_name: &str, name: &str) -> bool {
+    let so_name = if name.contains(".so") {
+        name.to_string()
+    } else {
+        format!("{}.so", name)
+    };
+
+    file_name == so_name || file_name.starts_with(&format!("{}.", so_name))
+}
+
+// Looks `name` up in the glibc ld.so.cache format. The new format follows
+// the entries of the old one in older caches.
+fn parse_ld_so_cache(cache: &[u8], name: &str) -> Option<PathBuf> {
+    let start = cache
+        .windows(CACHE_MAGIC_NEW.len())
+        .position(|w| w == CACHE_MAGIC_NEW)?;
+    let data = &cache[start..];
+    let u32_at = |i: usize| {
+        data.get(i..i + 4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let string_at = |i: usize| {
+        let bytes = data.get(i..)?;
+        let end = bytes.iter().position(|b| *b == 0)?;
+        std::str::from_utf8(&bytes[..end]).ok()
+    };
+
+    let nlibs = u32_at(CACHE_MAGIC_NEW.len())? as usize;
+    (0..nlibs).find_map(|i| {
+        let entry = CACHE_HEADER_SIZE + i * CACHE_ENTRY_SIZE;
+        let flags = u32_at(entry)?;
+        if flags & 0xff != FLAG_ELF_LIBC6 || flags & 0xff00 != FLAG_ARCH {
+            return None;
+        }
+        let key = string_at(u32_at(entry + 4)? as usize)?;
+        if !matches_library(key, name) {
+            return None;
+        }
+
+        string_at(u32_at(entry + 8)? as usize).map(PathBuf::from)
+    })
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::uprobe::{find_library, matches_library, parse_ld_so_cache};
+        use crate::uprobe::{CACHE_ENTRY_SIZE, CACHE_HEADER_SIZE, CACHE_MAGIC_NEW};
+        use crate::uprobe::{FLAG_ARCH, FLAG_ELF_LIBC6};
+        use std::path::PathBuf;
+
+        assert!(matches_library("libssl.so", "libssl"));
+        assert!(matches_library("libssl.so.1.1", "libssl"));
+        assert!(matches_library("libssl.so.1.1", "libssl.so.1.1"));
+        assert!(!matches_library("libssl3.so", "libssl"));
+        assert_eq!(
+            find_library("/opt/libssl.so"),
+            Some(PathBuf::from("/opt/libssl.so"))
+        );
+
+        let entries = [
+            ("libcrypto.so.1.1", "/lib/libcrypto.so.1.1"),
+            ("libssl.so.1.1", "/lib/libssl.so.1.1"),
+        ];
+        let mut cache = CACHE_MAGIC_NEW.to_vec();
+        cache.extend_from_slice(&(entries.len() as u32).to_ne_bytes());
+        cache.resize(CACHE_HEADER_SIZE, 0);
+        let mut strings = Vec::new();
+        let strings_start = CACHE_HEADER_SIZE + entries.len() * CACHE_ENTRY_SIZE;
+        for (key, value) in entries.iter() {
+            let key_offset = strings_start + strings.len();
+            strings.extend_from_slice(key.as_bytes());
+            strings.push(0);
+            let value_offset = strings_start + strings.len();
+            strings.extend_from_slice(value.as_bytes());
+            strings.push(0);
+
+            let mut entry = vec![0u8; CACHE_ENTRY_SIZE];
+            entry[0..4].copy_from_slice(&(FLAG_ELF_LIBC6 | FLAG_ARCH).to_ne_bytes());
+            entry[4..8].copy_from_slice(&(key_offset as u32).to_ne_bytes());
+            entry[8..12].copy_from_slice(&(value_offset as u32).to_ne_bytes());
+            cache.extend_from_slice(&entry);
+        }
+        cache.extend_from_slice(&strings);
+
+        assert_eq!(
+            parse_ld_so_cache(&cache, "libssl"),
+            Some(PathBuf::from("/lib/libssl.so.1.1"))
+        );
+        assert_eq!(parse_ld_so_cache(&cache, "libz"), None);
+    }
+}
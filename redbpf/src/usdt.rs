@@ -0,0 +1,624 @@
+// Copyright 2020 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! USDT probes.
+//!
+//! Applications built with SystemTap's `sys/sdt.h`, eg. PostgreSQL, Node or
+//! the JVM, describe their USDT probes in `.note.stapsdt` ELF notes: where
+//! each probe is placed, the semaphore telling the application whether the
+//! probe is enabled, and where the arguments are stored. `UsdtProbe::find()`
+//! reads them, `Program::attach_usdt()` attaches a uprobe program to the
+//! locations of the probe and enables it, and `UsdtSpecs` passes the
+//! argument specs to the program, which reads the arguments with
+//! `redbpf_probes::usdt::UsdtContext`:
+//!
+//! ```no_run
+//! use redbpf::usdt::{UsdtProbe, UsdtSpecs};
+//! use redbpf::Module;
+//!
+//! let mut module = Module::parse(&std::fs::read("pg.elf").unwrap()).unwrap();
+//! let probe = UsdtProbe::find("/usr/bin/postgres", "postgresql", "query__start").unwrap();
+//! let map = module.maps.iter().find(|m| m.name == "usdt_specs").unwrap();
+//! UsdtSpecs::new(map).unwrap().register(&probe, None).unwrap();
+//! for prog in module.programs.iter_mut() {
+//!     prog.load(module.version, module.license.clone()).unwrap();
+//!     prog.attach_usdt(&probe, None).unwrap();
+//! }
+//! ```
+//!
+//! Semaphores are incremented by the kernel on Linux 4.20 and later. On older
+//! kernels they're incremented in the memory of the traced process, so
+//! probes with a semaphore can only be attached to a given process.
+use goblin::elf::{header, Elf};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::uprobe::{file_offset, find_library};
+use crate::{Error, HashMap, Map, Result};
+
+const STAPSDT_NOTE_NAME: &str = "stapsdt";
+const STAPSDT_NOTE_TYPE: u32 = 3;
+const STAPSDT_BASE_SECTION: &str = ".stapsdt.base";
+const STAPSDT_NOTE_SECTION: &str = ".note.stapsdt";
+
+/// The maximum number of arguments of a USDT probe.
+pub const USDT_MAX_ARGS: usize = 12;
+
+const USDT_ARG_CONST: u8 = 0;
+const USDT_ARG_REG: u8 = 1;
+const USDT_ARG_REG_DEREF: u8 = 2;
+const USDT_ARG_UNSUPPORTED: u8 = 0xff;
+
+/// A USDT probe, with all its locations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsdtProbe {
+    /// The executable or shared library defining the probe
+    pub path: PathBuf,
+    pub provider: String,
+    pub name: String,
+    /// The address of the semaphore, `None` if the probe doesn't have one
+    pub semaphore: Option<u64>,
+    /// The file offset of the semaphore
+    pub semaphore_offset: Option<u64>,
+    pub locations: Vec<UsdtLocation>,
+    // non-PIE executables are mapped at the addresses of their headers
+    absolute: bool,
+}
+
+/// A location of a USDT probe. Probes used in several places, eg. in
+/// inlined functions, have several locations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsdtLocation {
+    pub address: u64,
+    /// The file offset of the probe, which uprobes are attached to
+    pub offset: u64,
+    pub args: Vec<UsdtArg>,
+}
+
+/// An argument of a USDT probe at a given location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsdtArg {
+    /// The size of the argument in bytes
+    pub size: u8,
+    pub signed: bool,
+    pub kind: UsdtArgKind,
+}
+
+/// Where an argument is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsdtArgKind {
+    Constant(i64),
+    Register(String),
+    /// The memory at `offset` from the address in `register`
+    Memory {
+        register: String,
+        offset: i64,
+    },
+    /// An argument spec `UsdtContext` can't read, eg. a RIP relative or an
+    /// indexed one
+    Unsupported(String),
+}
+
+/* NB: this needs to be kept in sync with redbpf_probes::usdt::UsdtArgSpec */
+/// The encoding of `UsdtArg` read by BPF programs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UsdtArgSpec {
+    pub value: i64,
+    pub reg_offset: u16,
+    pub kind: u8,
+    pub bitshift: u8,
+    pub signed: u8,
+    pub _pad: [u8; 3],
+}
+
+/* NB: this needs to be kept in sync with redbpf_probes::usdt::UsdtSpec */
+/// The encoding of the arguments of a `UsdtLocation` read by BPF programs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UsdtSpec {
+    pub args: [UsdtArgSpec; USDT_MAX_ARGS],
+    pub arg_count: u32,
+    pub _pad: u32,
+}
+
+impl UsdtProbe {
+    /// Finds the probe `provider:name` defined by the executable or shared
+    /// library at `path`.
+    ///
+    /// `path` can also be a library name, see `uprobe::find_library()`.
+    /// Fails with `Error::SymbolNotFound` if the probe isn't defined.
+    pub fn find<P: AsRef<Path>>(path: P, provider: &str, name: &str) -> Result<UsdtProbe> {
+        let path = path.as_ref();
+        UsdtProbe::all(path)?
+            .into_iter()
+            .find(|p| p.provider == provider && p.name == name)
+            .ok_or_else(|| {
+                Error::SymbolNotFound(path.to_path_buf(), format!("{}:{}", provider, name))
+            })
+    }
+
+    /// Returns all the probes defined by the executable or shared library at
+    /// `path`.
+    pub fn all<P: AsRef<Path>>(path: P) -> Result<Vec<UsdtProbe>> {
+        let path = path.as_ref();
+        let path = path
+            .to_str()
+            .and_then(find_library)
+            .unwrap_or_else(|| path.to_path_buf());
+        let bytes = fs::read(&path)?;
+        let elf = Elf::parse(&bytes)?;
+        let absolute = elf.header.e_type == header::ET_EXEC;
+
+        // prelinking moves the code, .stapsdt.base tells by how much
+        let base = elf.section_headers.iter().find_map(|sh| {
+            match elf.shdr_strtab.get_unsafe(sh.sh_name) {
+                Some(STAPSDT_BASE_SECTION) => Some(sh.sh_addr),
+                _ => None,
+            }
+        });
+
+        let mut probes: BTreeMap<(String, String), UsdtProbe> = BTreeMap::new();
+        let notes = match elf.iter_note_sections(&bytes, Some(STAPSDT_NOTE_SECTION)) {
+            Some(notes) => notes,
+            None => return Ok(Vec::new()),
+        };
+        for note in notes {
+            let note = note?;
+            if note.n_type != STAPSDT_NOTE_TYPE
+                || note.name.trim_end_matches('\0') != STAPSDT_NOTE_NAME
+            {
+                continue;
+            }
+            let note = match parse_note(note.desc, elf.is_64) {
+                Some(note) => note,
+                None => continue,
+            };
+
+            let mut address = note.pc;
+            if let Some(base) = base {
+                address = address.wrapping_add(base).wrapping_sub(note.base);
+            }
+            let semaphore = match note.semaphore {
+                0 => None,
+                sem => Some(sem),
+            };
+            let location = UsdtLocation {
+                address,
+                offset: file_offset(&elf, address),
+                args: note.args.split_whitespace().map(parse_arg).collect(),
+            };
+
+            let key = (note.provider.clone(), note.name.clone());
+            let probe = probes.entry(key).or_insert_with(|| UsdtProbe {
+                path: path.clone(),
+                provider: note.provider,
+                name: note.name,
+                semaphore,
+                semaphore_offset: semaphore.map(|s| file_offset(&elf, s)),
+                locations: Vec::new(),
+                absolute,
+            });
+            probe.locations.push(location);
+        }
+
+        Ok(probes.values().cloned().collect())
+    }
+
+    // Returns the address `offset` is mapped at in `pid`, or the address of
+    // the location in the file if it's always mapped there.
+    fn address(&self, location: &UsdtLocation, pid: Option<libc::pid_t>) -> Option<u64> {
+        match pid {
+            Some(pid) => runtime_address(pid, &self.path, location.offset),
+            None if self.absolute => Some(location.address),
+            None => None,
+        }
+    }
+}
+
+impl UsdtLocation {
+    /// Returns the encoding of the arguments read by BPF programs.
+    pub fn spec(&self) -> UsdtSpec {
+        let mut spec = UsdtSpec {
+            args: [UsdtArgSpec::default(); USDT_MAX_ARGS],
+            arg_count: self.args.len().min(USDT_MAX_ARGS) as u32,
+            _pad: 0,
+        };
+        for (arg, arg_spec) in self.args.iter().zip(spec.args.iter_mut()) {
+            *arg_spec = arg.spec();
+        }
+
+        spec
+    }
+}
+
+impl UsdtArg {
+    fn spec(&self) -> UsdtArgSpec {
+        let mut spec = UsdtArgSpec {
+            bitshift: 64 - 8 * self.size,
+            signed: self.signed as u8,
+            ..Default::default()
+        };
+        let reg = match &self.kind {
+            UsdtArgKind::Constant(value) => {
+                spec.kind = USDT_ARG_CONST;
+                spec.value = *value;
+                None
+            }
+            UsdtArgKind::Register(register) => {
+                spec.kind = USDT_ARG_REG;
+                Some(register)
+            }
+            UsdtArgKind::Memory { register, offset } => {
+                spec.kind = USDT_ARG_REG_DEREF;
+                spec.value = *offset;
+                Some(register)
+            }
+            UsdtArgKind::Unsupported(_) => {
+                spec.kind = USDT_ARG_UNSUPPORTED;
+                None
+            }
+        };
+        if let Some(reg) = reg {
+            match reg_offset(reg) {
+                Some(offset) => spec.reg_offset = offset,
+                None => spec.kind = USDT_ARG_UNSUPPORTED,
+            }
+        }
+
+        spec
+    }
+}
+
+/// The argument specs of USDT probes, read by
+/// `redbpf_probes::usdt::UsdtContext`.
+pub struct UsdtSpecs<'a> {
+    specs: HashMap<'a, u64, UsdtSpec>,
+}
+
+impl<'a> UsdtSpecs<'a> {
+    pub fn new(base: &'a Map) -> Result<UsdtSpecs<'a>> {
+        Ok(UsdtSpecs {
+            specs: HashMap::new(base)?,
+        })
+    }
+
+    /// Stores the argument specs of the locations of `probe`, as attached to
+    /// `pid` or to all the processes.
+    ///
+    /// Specs are keyed by the address of the location in the traced
+    /// process. When it can't be known, ie. when tracing all the processes
+    /// using a shared library or a position independent executable, the
+    /// spec of the first location is stored at address 0, which is only
+    /// right for probes whose locations store their arguments the same way.
+    pub fn register(&self, probe: &UsdtProbe, pid: Option<libc::pid_t>) -> Result<()> {
+        for location in probe.locations.iter() {
+            match probe.address(location, pid) {
+                Some(address) => self.specs.set(address, location.spec()),
+                None => {
+                    self.specs.set(0, location.spec());
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Increments the semaphore of a probe in the memory of a process, for
+// kernels that can't do it
+#[derive(Debug)]
+pub(crate) struct Semaphore {
+    pid: libc::pid_t,
+    address: u64,
+}
+
+impl Semaphore {
+    pub(crate) fn increment(probe: &UsdtProbe, pid: libc::pid_t) -> Result<Semaphore> {
+        let offset = probe.semaphore_offset.ok_or(Error::UsdtSemaphore)?;
+        let address = runtime_address(pid, &probe.path, offset).ok_or(Error::UsdtSemaphore)?;
+        let semaphore = Semaphore { pid, address };
+        semaphore.add(1)?;
+
+        Ok(semaphore)
+    }
+
+    pub(crate) fn decrement(&self) -> Result<()> {
+        self.add(-1)
+    }
+
+    // sys/sdt.h semaphores are unsigned shorts
+    fn add(&self, n: i16) -> Result<()> {
+        let mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{}/mem", self.pid))?;
+        let mut buf = [0u8; 2];
+        mem.read_exact_at(&mut buf, self.address)?;
+        let value = u16::from_ne_bytes(buf).wrapping_add(n as u16);
+        mem.write_all_at(&value.to_ne_bytes(), self.address)?;
+
+        Ok(())
+    }
+}
+
+// Returns the address `path` at `offset` is mapped at in `pid`
+fn runtime_address(pid: libc::pid_t, path: &Path, offset: u64) -> Option<u64> {
+    let path = fs::canonicalize(path).ok()?;
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).ok()?;
+    parse_maps(&maps, &path, offset)
+}
+
+fn parse_maps(maps: &str, path: &Path, offset: u64) -> Option<u64> {
+    maps.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let range = fields.next()?;
+        let _perms = fields.next()?;
+        let map_offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        let _dev = fields.next()?;
+        let _inode = fields.next()?;
+        if Path::new(fields.next()?) != path {
+            return None;
+        }
+        let mut range = range.split('-');
+        let start = u64::from_str_radix(range.next()?, 16).ok()?;
+        let end = u64::from_str_radix(range.next()?, 16).ok()?;
+        if offset < map_offset || offset >= map_offset + (end - start) {
+            return None;
+        }
+
+        Some(start + offset - map_offset)
+    })
+}
+
+struct Note {
+    pc: u64,
+    base: u64,
+    semaphore: u64,
+    provider: String,
+    name: String,
+    args: String,
+}
+
+// The note holds the addresses of the probe, of .stapsdt.base and of the
+// semaphore, followed by the provider, the name and the argument specs
+fn parse_note(desc: &[u8], is_64: bool) -> Option<Note> {
+    let word = if is_64 { 8 } else { 4 };
+    let addr = |i: usize| -> Option<u64> {
+        let bytes = desc.get(i * word..(i + 1) * word)?;
+        let mut buf = [0u8; 8];
+        buf[..word].copy_from_slice(bytes);
+        Some(u64::from_ne_bytes(buf))
+    };
+    let pc = addr(0)?;
+    let base = addr(1)?;
+    let semaphore = addr(2)?;
+
+    let mut strings = desc.get(3 * word..)?.split(|b| *b == 0);
+    let mut string = || {
+        strings
+            .next()
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+    };
+
+    Some(Note {
+        pc,
+        base,
+        semaphore,
+        provider: string()?,
+        name: string()?,
+        args: string().unwrap_or_default(),
+    })
+}
+
+// Parses argument specs like `-4@%edi`, `8@-16(%rbp)` and `4@$5` on x86_64,
+// or `-4@x0`, `8@[sp, 16]` and `4@5` on aarch64
+fn parse_arg(spec: &str) -> UsdtArg {
+    let unsupported = || UsdtArg {
+        size: 8,
+        signed: false,
+        kind: UsdtArgKind::Unsupported(spec.to_string()),
+    };
+    let mut parts = spec.splitn(2, '@');
+    let (size, location) = match (parts.next(), parts.next()) {
+        (Some(size), Some(location)) => (size, location),
+        _ => return unsupported(),
+    };
+    let size = match size.parse::<i8>() {
+        Ok(size) if [1, 2, 4, 8].contains(&size.abs()) => size,
+        _ => return unsupported(),
+    };
+
+    let kind = if location.starts_with('$') {
+        location
+            .trim_start_matches('$')
+            .parse()
+            .ok()
+            .map(UsdtArgKind::Constant)
+    } else if location.starts_with('%') {
+        let register = location.trim_start_matches('%');
+        Some(UsdtArgKind::Register(register.to_string()))
+    } else if location.starts_with('[') && location.ends_with(']') {
+        let operands = location.trim_start_matches('[').trim_end_matches(']');
+        let mut operands = operands.split(',').map(str::trim);
+        let register = operands.next().unwrap_or("").to_string();
+        match operands.next().map(str::parse) {
+            None => Some(UsdtArgKind::Memory {
+                register,
+                offset: 0,
+            }),
+            Some(Ok(offset)) if operands.next().is_none() => {
+                Some(UsdtArgKind::Memory { register, offset })
+            }
+            _ => None,
+        }
+    } else if let Some(paren) = location.find('(') {
+        let register = match location[paren + 1..].strip_suffix(')') {
+            Some(register) => register,
+            None => return unsupported(),
+        };
+        let offset = &location[..paren];
+        let offset = if offset.is_empty() {
+            Ok(0)
+        } else {
+            offset.parse()
+        };
+        match offset {
+            Ok(offset) if register.starts_with('%') => {
+                if register.contains(',') {
+                    None
+                } else {
+                    Some(UsdtArgKind::Memory {
+                        register: register.trim_start_matches('%').to_string(),
+                        offset,
+                    })
+                }
+            }
+            _ => None,
+        }
+    } else if let Ok(value) = location.parse() {
+        Some(UsdtArgKind::Constant(value))
+    } else {
+        Some(UsdtArgKind::Register(location.to_string()))
+    };
+
+    match kind {
+        Some(kind) => UsdtArg {
+            size: if size < 0 { -size } else { size } as u8,
+            signed: size < 0,
+            kind,
+        },
+        None => unsupported(),
+    }
+}
+
+// The offset of a register in `struct pt_regs`. Sub-registers like `eax`
+// are read through the full register and truncated to the argument size.
+#[cfg(target_arch = "x86_64")]
+fn reg_offset(reg: &str) -> Option<u16> {
+    let offset = match reg {
+        "r15" | "r15d" | "r15w" | "r15b" => 0,
+        "r14" | "r14d" | "r14w" | "r14b" => 8,
+        "r13" | "r13d" | "r13w" | "r13b" => 16,
+        "r12" | "r12d" | "r12w" | "r12b" => 24,
+        "rbp" | "ebp" | "bp" | "bpl" => 32,
+        "rbx" | "ebx" | "bx" | "bl" => 40,
+        "r11" | "r11d" | "r11w" | "r11b" => 48,
+        "r10" | "r10d" | "r10w" | "r10b" => 56,
+        "r9" | "r9d" | "r9w" | "r9b" => 64,
+        "r8" | "r8d" | "r8w" | "r8b" => 72,
+        "rax" | "eax" | "ax" | "al" => 80,
+        "rcx" | "ecx" | "cx" | "cl" => 88,
+        "rdx" | "edx" | "dx" | "dl" => 96,
+        "rsi" | "esi" | "si" | "sil" => 104,
+        "rdi" | "edi" | "di" | "dil" => 112,
+        "rip" => 128,
+        "rsp" | "esp" | "sp" | "spl" => 152,
+        _ => return None,
+    };
+
+    Some(offset)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn reg_offset(reg: &str) -> Option<u16> {
+    match reg {
+        "sp" => Some(31 * 8),
+        "pc" => Some(32 * 8),
+        _ if reg.starts_with('x') || reg.starts_with('w') => match reg[1..].parse::<u16>() {
+            Ok(n) if n <= 30 => Some(n * 8),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+fn reg_offset(_reg: &str) -> Option<u16> {
+    None
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::usdt::{parse_arg, parse_maps, parse_note, UsdtArg, UsdtArgKind};
+        use std::path::Path;
+
+        let arg = |size, signed, kind| UsdtArg { size, signed, kind };
+        assert_eq!(
+            parse_arg("-4@%edi"),
+            arg(4, true, UsdtArgKind::Register("edi".to_string()))
+        );
+        assert_eq!(
+            parse_arg("8@-16(%rbp)"),
+            arg(
+                8,
+                false,
+                UsdtArgKind::Memory {
+                    register: "rbp".to_string(),
+                    offset: -16
+                }
+            )
+        );
+        assert_eq!(parse_arg("4@$5"), arg(4, false, UsdtArgKind::Constant(5)));
+        assert_eq!(
+            parse_arg("8@[sp, 16]"),
+            arg(
+                8,
+                false,
+                UsdtArgKind::Memory {
+                    register: "sp".to_string(),
+                    offset: 16
+                }
+            )
+        );
+        assert_eq!(
+            parse_arg("-2@x1"),
+            arg(2, true, UsdtArgKind::Register("x1".to_string()))
+        );
+        match parse_arg("8@foo(%rip)").kind {
+            UsdtArgKind::Unsupported(_) => {}
+            k => panic!("unexpected kind: {:?}", k),
+        }
+        match parse_arg("8@(%rax,%rbx,8)").kind {
+            UsdtArgKind::Unsupported(_) => {}
+            k => panic!("unexpected kind: {:?}", k),
+        }
+        for spec in &["8@-4(", "8@(", "8@-4(%rbp"] {
+            match parse_arg(spec).kind {
+                UsdtArgKind::Unsupported(_) => {}
+                k => panic!("unexpected kind: {:?}", k),
+            }
+        }
+
+        let mut desc = Vec::new();
+        for addr in &[0x1234u64, 0x2000, 0x4010] {
+            desc.extend_from_slice(&addr.to_ne_bytes());
+        }
+        desc.extend_from_slice(b"postgresql\0query__start\08@%rdi\0");
+        let note = parse_note(&desc, true).unwrap();
+        assert_eq!(note.pc, 0x1234);
+        assert_eq!(note.base, 0x2000);
+        assert_eq!(note.semaphore, 0x4010);
+        assert_eq!(note.provider, "postgresql");
+        assert_eq!(note.name, "query__start");
+        assert_eq!(note.args, "8@%rdi");
+
+        let maps = "\
+55d0c0a00000-55d0c0a40000 r--p 00000000 fd:01 42 /usr/bin/postgres
+55d0c0a40000-55d0c0e00000 r-xp 00040000 fd:01 42 /usr/bin/postgres
+7f0000000000-7f0000001000 rw-p 00000000 00:00 0
+";
+        let path = Path::new("/usr/bin/postgres");
+        assert_eq!(parse_maps(maps, path, 0x41234), Some(0x55d0_c0a4_1234));
+        assert_eq!(parse_maps(maps, path, 0x1234), Some(0x55d0_c0a0_1234));
+        assert_eq!(parse_maps(maps, Path::new("/usr/bin/other"), 0x1234), None);
+    }
+}
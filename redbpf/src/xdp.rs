@@ -1,26 +1,386 @@
 use std::slice;
+use std::collections::BTreeMap;
 use std::default::Default;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ops::BitOr;
+use std::os::unix::io::RawFd;
 
 use bpf_sys::{XDP_FLAGS_UPDATE_IF_NOEXIST, XDP_FLAGS_SKB_MODE,
               XDP_FLAGS_DRV_MODE, XDP_FLAGS_HW_MODE, XDP_FLAGS_MODES, XDP_FLAGS_MASK};
-use crate::Sample;
+use crate::link::LinkKind;
+use crate::sys::bpf::{bpf_get_link_xdp_id, bpf_set_link_xdp_fd};
+use crate::sys::netlink::{
+    parse_attrs, Message, NetlinkSocket, NLM_F_ACK, NLM_F_DUMP, NLM_F_REQUEST,
+};
+use crate::uname::get_kernel_internal_version;
+use crate::{check_layout, Error, Link, Map, ProgramInfo, Result, Sample};
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u32)]
-pub enum Flags {
-    Unset = 0,
-    UpdateIfNoExist = XDP_FLAGS_UPDATE_IF_NOEXIST,
-    SkbMode = XDP_FLAGS_SKB_MODE,
-    DrvMode = XDP_FLAGS_DRV_MODE,
-    HwMode = XDP_FLAGS_HW_MODE,
-    Modes = XDP_FLAGS_MODES,
-    Mask = XDP_FLAGS_MASK
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_XDP: u16 = 43;
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_EXPECTED_FD: u16 = 8;
+// replace the program only if it's the one of IFLA_XDP_EXPECTED_FD
+const XDP_FLAGS_REPLACE: u32 = 1 << 4;
+// struct ifinfomsg
+const IFINFOMSG_LEN: usize = 16;
+
+/// Flags controlling how XDP programs are attached.
+///
+/// Flags can be combined, eg. `Flags::DRV_MODE | Flags::UPDATE_IF_NOEXIST`.
+/// Without a mode flag the kernel uses native mode if the driver supports
+/// it, and falls back to SKB mode otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u32);
+
+#[allow(non_upper_case_globals)]
+impl Flags {
+    pub const UNSET: Flags = Flags(0);
+    /// Fail with `Error::XdpAttached` instead of replacing the program
+    /// attached to the interface.
+    pub const UPDATE_IF_NOEXIST: Flags = Flags(XDP_FLAGS_UPDATE_IF_NOEXIST);
+    /// Generic XDP, run by the kernel after the socket buffer is allocated.
+    /// Slower than the other modes, but works with all the drivers.
+    pub const SKB_MODE: Flags = Flags(XDP_FLAGS_SKB_MODE);
+    /// Native XDP, run by the driver.
+    pub const DRV_MODE: Flags = Flags(XDP_FLAGS_DRV_MODE);
+    /// Offloaded XDP, run by the NIC.
+    pub const HW_MODE: Flags = Flags(XDP_FLAGS_HW_MODE);
+
+    #[doc(hidden)]
+    pub const Unset: Flags = Flags::UNSET;
+    #[doc(hidden)]
+    pub const UpdateIfNoExist: Flags = Flags::UPDATE_IF_NOEXIST;
+    #[doc(hidden)]
+    pub const SkbMode: Flags = Flags::SKB_MODE;
+    #[doc(hidden)]
+    pub const DrvMode: Flags = Flags::DRV_MODE;
+    #[doc(hidden)]
+    pub const HwMode: Flags = Flags::HW_MODE;
+    #[doc(hidden)]
+    pub const Modes: Flags = Flags(XDP_FLAGS_MODES);
+    #[doc(hidden)]
+    pub const Mask: Flags = Flags(XDP_FLAGS_MASK);
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the flags with the mode replaced by `mode`.
+    pub fn with_mode(self, mode: Flags) -> Flags {
+        Flags(self.0 & !XDP_FLAGS_MODES | mode.0 & XDP_FLAGS_MODES)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
 }
 
 impl Default for Flags {
     fn default() -> Self {
-        Flags::Unset
+        Flags::UNSET
+    }
+}
+
+/// Detaches the XDP program attached to `iface`.
+///
+/// `flags` must include the mode the program was attached with, if any.
+pub fn detach_xdp(iface: &str, flags: Flags) -> Result<()> {
+    attach(iface, -1, Flags(flags.0 & XDP_FLAGS_MODES))
+}
+
+/// Returns the XDP program attached to `iface`, or `None` if there isn't
+/// one.
+pub fn query_xdp(iface: &str) -> Result<Option<ProgramInfo>> {
+    let ifindex = ifindex(iface)?;
+    let mut id = 0u32;
+    let ret = unsafe { bpf_get_link_xdp_id(ifindex as i32, &mut id, 0) };
+    if ret < 0 {
+        return Err(Error::IO(io::Error::from_raw_os_error(-ret)));
+    }
+    if id == 0 {
+        return Ok(None);
+    }
+
+    ProgramInfo::from_id(id).map(Some)
+}
+
+/// Returns the names of the network interfaces, as listed by `ip link`.
+pub fn interfaces() -> Result<Vec<String>> {
+    let mut sock = NetlinkSocket::open()?;
+    let msg = Message::new(
+        RTM_GETLINK,
+        NLM_F_REQUEST | NLM_F_DUMP,
+        &[0u8; IFINFOMSG_LEN],
+    );
+    let replies = sock.dump(msg, RTM_NEWLINK)?;
+
+    Ok(replies.iter().filter_map(|r| parse_ifname(r)).collect())
+}
+
+fn parse_ifname(reply: &[u8]) -> Option<String> {
+    if reply.len() < IFINFOMSG_LEN {
+        return None;
+    }
+    parse_attrs(&reply[IFINFOMSG_LEN..])
+        .into_iter()
+        .find(|(attr_type, _)| *attr_type == IFLA_IFNAME)
+        .map(|(_, name)| {
+            let name = name.split(|b| *b == 0).next().unwrap_or(name);
+            String::from_utf8_lossy(name).into_owned()
+        })
+}
+
+/// An XDP program attached to a set of interfaces, returned by
+/// `Program::attach_xdp_all()`.
+///
+/// The program is attached through netlink to each interface, and keeps
+/// one `Link` per interface. Interfaces can be added and removed with
+/// `attach()` and `detach()`, and `sync()` follows the interfaces going and
+/// coming:
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+/// use redbpf::{xdp, Module};
+///
+/// let module = Module::load_file("probe.elf").unwrap();
+/// let prog = module.program("filter").unwrap();
+/// let mut group = prog
+///     .attach_xdp_all(|iface| iface.starts_with("eth"), xdp::Flags::default())
+///     .unwrap();
+/// for (iface, error) in group.failures() {
+///     eprintln!("error attaching to {}: {}", iface, error);
+/// }
+/// loop {
+///     thread::sleep(Duration::from_secs(1));
+///     group.sync().unwrap();
+/// }
+/// ```
+///
+/// Dropping the group detaches the program from all the interfaces.
+pub struct XdpGroup {
+    program: String,
+    // a duplicate of the file descriptor of the program, so the group
+    // doesn't borrow it
+    fd: RawFd,
+    flags: Flags,
+    matching: fn(&str) -> bool,
+    links: BTreeMap<String, Link>,
+    failures: Vec<(String, Error)>,
+}
+
+impl XdpGroup {
+    pub(crate) fn new(
+        program: &str,
+        fd: RawFd,
+        flags: Flags,
+        matching: fn(&str) -> bool,
+    ) -> Result<XdpGroup> {
+        let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        let mut group = XdpGroup {
+            program: program.to_string(),
+            fd,
+            flags,
+            matching,
+            links: BTreeMap::new(),
+            failures: Vec::new(),
+        };
+        group.sync()?;
+
+        Ok(group)
+    }
+
+    /// Returns the interfaces the program is attached to.
+    pub fn interfaces(&self) -> impl Iterator<Item = &str> {
+        self.links.keys().map(|iface| iface.as_str())
+    }
+
+    /// Returns the link attaching the program to `iface`, if any.
+    pub fn link(&self, iface: &str) -> Option<&Link> {
+        self.links.get(iface)
+    }
+
+    /// Returns the interfaces the program couldn't be attached to by the
+    /// last `sync()`, with the errors.
+    ///
+    /// Failures don't detach the program from the other interfaces, see
+    /// `all_or_nothing()`.
+    pub fn failures(&self) -> &[(String, Error)] {
+        &self.failures
+    }
+
+    /// Detaches the program from all the interfaces and returns the first
+    /// failure, if there were any.
+    pub fn all_or_nothing(mut self) -> Result<XdpGroup> {
+        if self.failures.is_empty() {
+            return Ok(self);
+        }
+        let _ = self.detach_all();
+
+        Err(self.failures.remove(0).1)
+    }
+
+    /// Attaches the program to `iface`, whether or not it matches.
+    ///
+    /// Does nothing if the program is already attached to `iface`.
+    pub fn attach(&mut self, iface: &str) -> Result<()> {
+        if self.links.contains_key(iface) {
+            return Ok(());
+        }
+        attach(iface, self.fd, self.flags)?;
+        let kind = LinkKind::Xdp {
+            iface: iface.to_string(),
+            flags: self.flags,
+            netns: None,
+        };
+        self.links
+            .insert(iface.to_string(), Link::new(&self.program, kind));
+
+        Ok(())
+    }
+
+    /// Detaches the program from `iface`.
+    ///
+    /// Does nothing if the program isn't attached to `iface`. Matching
+    /// interfaces are attached again by the next `sync()`.
+    pub fn detach(&mut self, iface: &str) -> Result<()> {
+        match self.links.remove(iface) {
+            Some(link) => link.detach(),
+            None => Ok(()),
+        }
+    }
+
+    /// Detaches the program from all the interfaces.
+    ///
+    /// Returns the first error, after trying to detach from all the
+    /// interfaces.
+    pub fn detach_all(&mut self) -> Result<()> {
+        let mut ret = Ok(());
+        for (_, link) in mem::take(&mut self.links) {
+            if let Err(e) = link.detach() {
+                if ret.is_ok() {
+                    ret = Err(e);
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Attaches the program to the matching interfaces that appeared since
+    /// the last call, and forgets the interfaces that disappeared.
+    ///
+    /// Errors attaching to an interface are returned by `failures()`, the
+    /// interface is retried by the next call.
+    pub fn sync(&mut self) -> Result<()> {
+        let current = interfaces()?;
+        let gone: Vec<String> = self
+            .links
+            .keys()
+            .filter(|iface| !current.contains(iface))
+            .cloned()
+            .collect();
+        // the kernel detached the program with the interface
+        for iface in gone {
+            if let Some(link) = self.links.remove(&iface) {
+                link.forget();
+            }
+        }
+
+        self.failures.clear();
+        let matching = self.matching;
+        for iface in current.iter().filter(|iface| matching(iface)) {
+            if let Err(e) = self.attach(iface) {
+                self.failures.push((iface.clone(), e));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for XdpGroup {
+    fn drop(&mut self) {
+        self.links.clear();
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// Attaches the program `fd` to `iface` through netlink, or detaches the
+// current program if `fd` is -1
+pub(crate) fn attach(iface: &str, fd: i32, flags: Flags) -> Result<()> {
+    let ifindex = ifindex(iface)?;
+    let ret = unsafe { bpf_set_link_xdp_fd(ifindex as i32, fd, flags.bits()) };
+    if ret < 0 {
+        return Err(attach_error(-ret));
+    }
+
+    Ok(())
+}
+
+// Attaches `fd` to `iface` in place of `old_fd`, atomically. Kernels before
+// 5.7 don't support XDP_FLAGS_REPLACE, the program attached is then replaced
+// without checking it's `old_fd`.
+pub(crate) fn replace(iface: &str, fd: i32, old_fd: i32, flags: Flags) -> Result<()> {
+    if !replace_supported(get_kernel_internal_version()) {
+        return attach(iface, fd, flags);
     }
+    let ifindex = ifindex(iface)?;
+    let mut header = vec![0u8; IFINFOMSG_LEN];
+    header[0] = libc::AF_UNSPEC as u8;
+    header[4..8].copy_from_slice(&(ifindex as i32).to_ne_bytes());
+    let mut msg = Message::new(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, &header);
+    msg.begin_nested(IFLA_XDP);
+    msg.put_u32(IFLA_XDP_FD, fd as u32);
+    msg.put_u32(IFLA_XDP_FLAGS, flags.bits() | XDP_FLAGS_REPLACE);
+    msg.put_u32(IFLA_XDP_EXPECTED_FD, old_fd as u32);
+    msg.end_nested();
+
+    let mut sock = NetlinkSocket::open()?;
+    sock.request(msg)
+        .map_err(|e| attach_error(e.raw_os_error().unwrap_or(0)))
+}
+
+// XDP_FLAGS_REPLACE is supported since Linux 5.7. It's tried when the
+// version is unknown, and the errors are returned.
+fn replace_supported(version: Option<u32>) -> bool {
+    version.map(|v| v >= 0x05_07_00).unwrap_or(true)
+}
+
+pub(crate) fn attach_error(errno: i32) -> Error {
+    match errno {
+        // with UPDATE_IF_NOEXIST, or when attaching a link
+        libc::EBUSY | libc::EEXIST => Error::XdpAttached,
+        libc::EOPNOTSUPP => Error::XdpModeUnsupported,
+        e => Error::IO(io::Error::from_raw_os_error(e)),
+    }
+}
+
+pub(crate) fn ifindex(iface: &str) -> Result<u32> {
+    let cname = CString::new(iface)?;
+    let ifindex = unsafe { libc::if_nametoindex(cname.as_ptr()) };
+    if ifindex == 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    Ok(ifindex)
 }
 
 /* NB: this needs to be kept in sync with redbpf_probes::xdp::MapData */
@@ -53,3 +413,122 @@ impl<T> MapData<T> {
         }
     }
 }
+
+/// A network device map.
+///
+/// XDP programs can redirect packets to the interfaces in the map with
+/// `redbpf_probes::xdp::DevMap::redirect()`.
+pub struct DevMap<'a> {
+    base: &'a Map,
+}
+
+impl<'base> DevMap<'base> {
+    pub fn new<'a>(base: &'a Map) -> Result<DevMap<'a>> {
+        check_redirect_map(base, bpf_sys::bpf_map_type_BPF_MAP_TYPE_DEVMAP)?;
+        Ok(DevMap { base })
+    }
+
+    /// Sets the interface at `index` to `ifindex`.
+    pub fn set(&self, index: u32, ifindex: u32) -> Result<()> {
+        set_elem(self.base, index, ifindex)
+    }
+
+    /// Sets the interface at `index` to the interface called `name`.
+    pub fn set_interface(&self, index: u32, name: &str) -> Result<()> {
+        self.set(index, ifindex(name)?)
+    }
+
+    /// Removes the interface at `index`.
+    pub fn delete(&self, index: u32) -> Result<()> {
+        delete_elem(self.base, index)
+    }
+}
+
+/// A CPU map.
+///
+/// XDP programs can redirect packets to the CPUs in the map with
+/// `redbpf_probes::xdp::CpuMap::redirect()`.
+pub struct CpuMap<'a> {
+    base: &'a Map,
+}
+
+impl<'base> CpuMap<'base> {
+    pub fn new<'a>(base: &'a Map) -> Result<CpuMap<'a>> {
+        check_redirect_map(base, bpf_sys::bpf_map_type_BPF_MAP_TYPE_CPUMAP)?;
+        Ok(CpuMap { base })
+    }
+
+    /// Enables redirection to `cpu`, queueing up to `queue_size` packets.
+    pub fn set(&self, cpu: u32, queue_size: u32) -> Result<()> {
+        set_elem(self.base, cpu, queue_size)
+    }
+
+    /// Disables redirection to `cpu`.
+    pub fn delete(&self, cpu: u32) -> Result<()> {
+        delete_elem(self.base, cpu)
+    }
+}
+
+fn check_redirect_map(base: &Map, kind: u32) -> Result<()> {
+    check_layout(base, kind, mem::size_of::<u32>(), mem::size_of::<u32>())
+}
+
+fn set_elem(base: &Map, mut key: u32, mut value: u32) -> Result<()> {
+    let ret = unsafe {
+        bpf_sys::bpf_update_elem(
+            base.fd,
+            &mut key as *mut _ as *mut _,
+            &mut value as *mut _ as *mut _,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    base.mark_initialized();
+    Ok(())
+}
+
+fn delete_elem(base: &Map, mut key: u32) -> Result<()> {
+    if unsafe { bpf_sys::bpf_delete_elem(base.fd, &mut key as *mut _ as *mut _) } < 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+mod test {
+    #[test]
+    fn test() {
+        use crate::xdp::{parse_ifname, replace_supported, Flags, IFINFOMSG_LEN};
+        use bpf_sys::{XDP_FLAGS_DRV_MODE, XDP_FLAGS_SKB_MODE, XDP_FLAGS_UPDATE_IF_NOEXIST};
+
+        let flags = Flags::SKB_MODE | Flags::UPDATE_IF_NOEXIST;
+        assert_eq!(
+            flags.bits(),
+            XDP_FLAGS_SKB_MODE | XDP_FLAGS_UPDATE_IF_NOEXIST
+        );
+        assert!(flags.contains(Flags::UPDATE_IF_NOEXIST));
+        assert!(!flags.contains(Flags::DRV_MODE));
+
+        let flags = flags.with_mode(Flags::DRV_MODE);
+        assert_eq!(
+            flags.bits(),
+            XDP_FLAGS_DRV_MODE | XDP_FLAGS_UPDATE_IF_NOEXIST
+        );
+        assert_eq!(Flags::default(), Flags::Unset);
+
+        let mut reply = vec![0u8; IFINFOMSG_LEN];
+        // IFLA_MTU, then IFLA_IFNAME
+        reply.extend_from_slice(&[8, 0, 4, 0, 0xdc, 0x05, 0, 0]);
+        reply.extend_from_slice(&[9, 0, 3, 0, b'e', b't', b'h', b'0', 0, 0, 0, 0]);
+        assert_eq!(parse_ifname(&reply), Some("eth0".to_string()));
+        assert_eq!(parse_ifname(&reply[..IFINFOMSG_LEN + 8]), None);
+        assert_eq!(parse_ifname(&[]), None);
+
+        assert!(!replace_supported(Some(0x05_06_13)));
+        assert!(replace_supported(Some(0x05_07_00)));
+        assert!(replace_supported(None));
+    }
+}
@@ -0,0 +1,24 @@
+#!/bin/bash
+# Creates the network namespace used by the ignored netns test of redbpf: a
+# veth pair with rbt0 in the current namespace and rbt1 in redbpf-test.
+set -euo pipefail
+
+NETNS="redbpf-test"
+
+case "${1}" in
+    setup)
+        ip netns add $NETNS
+        ip link add rbt0 type veth peer name rbt1
+        ip link set rbt1 netns $NETNS
+        ip link set rbt0 up
+        ip -n $NETNS link set rbt1 up
+        ;;
+    teardown)
+        ip link del rbt0 || true
+        ip netns del $NETNS
+        ;;
+    *)
+        echo "usage: ${0} setup|teardown" >&2
+        exit 1
+        ;;
+esac